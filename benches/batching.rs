@@ -0,0 +1,66 @@
+//! Batched vs unbatched frame writes over loopback TCP.
+//!
+//! Run with `cargo bench --bench batching`.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pipproto::batch::{BatchConfig, BatchingWriter};
+use pipproto::codec::write_prefixed;
+use pipproto::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+const FRAMES: u64 = 200_000;
+
+fn frame(counter: u64) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Event,
+            flags: Flags::new(0).unwrap(),
+            device_id: *b"DEV00001",
+            counter,
+        },
+        body: vec![0; 4].into(),
+    }
+}
+
+/// Connected socket whose peer drains and discards everything.
+fn sink() -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut s, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 64 * 1024];
+        while s.read(&mut buf).unwrap_or(0) > 0 {}
+    });
+    let s = TcpStream::connect(addr).unwrap();
+    s.set_nodelay(true).unwrap();
+    s
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let rate = FRAMES as f64 / elapsed.as_secs_f64();
+    println!("{name:>10}: {elapsed:>10.2?}  {rate:>12.0} frames/s");
+}
+
+fn main() {
+    let mut s = sink();
+    let start = Instant::now();
+    let mut buf = Vec::new();
+    for c in 0..FRAMES {
+        buf.clear();
+        write_prefixed(&frame(c), &mut buf);
+        s.write_all(&buf).unwrap();
+    }
+    report("unbatched", start.elapsed());
+
+    let mut w = BatchingWriter::new(sink(), BatchConfig::default());
+    let start = Instant::now();
+    for c in 0..FRAMES {
+        w.write_frame(&frame(c)).unwrap();
+    }
+    w.close().unwrap();
+    report("batched", start.elapsed());
+}
@@ -0,0 +1,112 @@
+//! Body copies on the receive path, owned bodies vs shared `Bytes` ones:
+//! decoding a stream of frames and handing each to two consumers (the
+//! dispatcher and the journal, say), and writing frames back out.
+//!
+//! Run with `cargo bench --bench bodies`. Besides the time, each line
+//! shows the bytes allocated per frame, which is what the body copies
+//! cost.
+
+use std::hint::black_box;
+use std::io;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use pipproto::codec::{FrameCodec, SharedFrameCodec, write_prefixed, write_prefixed_vectored};
+use pipproto::{Flags, FrameHeaderV1, FrameV1, FrameV1B, MsgType, VERSION_V1};
+use tokio_util::codec::{Decoder, Encoder};
+
+#[path = "common/alloc.rs"]
+mod alloc;
+
+const FRAMES: u64 = 20_000;
+const BODY_LENS: [usize; 4] = [16, 256, 4096, 60_000];
+
+fn frame(counter: u64, body_len: usize) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Event,
+            flags: Flags::new(0).unwrap(),
+            device_id: *b"DEV00001",
+            counter,
+        },
+        body: vec![counter as u8; body_len].into(),
+    }
+}
+
+/// `FRAMES` frames as read off a stream.
+fn stream(body_len: usize) -> BytesMut {
+    let mut codec = FrameCodec::new(usize::MAX);
+    let mut buf = BytesMut::new();
+    for c in 0..FRAMES {
+        codec.encode(frame(c, body_len), &mut buf).unwrap();
+    }
+    buf
+}
+
+/// Time `run`, and the bytes it allocated.
+fn measure(run: impl FnOnce()) -> (Duration, u64) {
+    let start = Instant::now();
+    let ((), counts) = alloc::counted(run);
+    (start.elapsed(), counts.bytes)
+}
+
+fn report(name: &str, body_len: usize, (elapsed, allocated): (Duration, u64)) {
+    let per_frame = elapsed / FRAMES as u32;
+    let bytes = allocated / FRAMES;
+    println!("{name:>14} {body_len:>6}B: {per_frame:>10.2?}/frame {bytes:>8} B allocated/frame");
+}
+
+/// Decode, and clone for two consumers.
+fn receive<D, B>(mut codec: D, mut buf: BytesMut)
+where
+    D: Decoder<Item = Result<FrameV1<B>, pipproto::DecodeError>>,
+    D::Error: std::fmt::Debug,
+    B: Clone + AsRef<[u8]>,
+{
+    while let Some(frame) = codec.decode(&mut buf).unwrap() {
+        let frame = frame.unwrap();
+        let dispatched = frame.clone();
+        let journaled = frame.clone();
+        black_box((frame, dispatched, journaled));
+    }
+}
+
+fn main() {
+    for body_len in BODY_LENS {
+        let max = FrameCodec::new(usize::MAX);
+        let buf = stream(body_len);
+        let input = buf.clone();
+        report("receive owned", body_len, measure(|| receive(max, input)));
+        let input = buf.clone();
+        report(
+            "receive bytes",
+            body_len,
+            measure(|| receive(SharedFrameCodec(max), input)),
+        );
+
+        let frames: Vec<FrameV1> = (0..FRAMES).map(|c| frame(c, body_len)).collect();
+        let mut flat = Vec::new();
+        report(
+            "send flat",
+            body_len,
+            measure(|| {
+                for f in &frames {
+                    flat.clear();
+                    write_prefixed(f, &mut flat);
+                    io::Write::write_all(&mut io::sink(), &flat).unwrap();
+                }
+            }),
+        );
+        let frames: Vec<FrameV1B> = frames.into_iter().map(FrameV1B::from).collect();
+        report(
+            "send vectored",
+            body_len,
+            measure(|| {
+                for f in &frames {
+                    write_prefixed_vectored(f, &mut io::sink()).unwrap();
+                }
+            }),
+        );
+    }
+}
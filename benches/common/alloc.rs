@@ -0,0 +1,58 @@
+//! A global allocator counting, per thread, the allocations made and the
+//! bytes they asked for. The benches and tests that report or check
+//! allocations include it with `#[path]`; including it installs it.
+
+// each includer reads only some of the counts
+#![allow(dead_code)]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// What was allocated on one thread.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub allocations: u64,
+    pub bytes: u64,
+}
+
+thread_local! {
+    static COUNTS: Cell<Counts> = const {
+        Cell::new(Counts {
+            allocations: 0,
+            bytes: 0,
+        })
+    };
+}
+
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        COUNTS.with(|counts| {
+            let mut n = counts.get();
+            n.allocations += 1;
+            n.bytes += layout.size() as u64;
+            counts.set(n);
+        });
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Run `run`, returning its result and what it allocated on this thread.
+pub fn counted<T>(run: impl FnOnce() -> T) -> (T, Counts) {
+    let before = COUNTS.with(Cell::get);
+    let out = run();
+    let after = COUNTS.with(Cell::get);
+    let counts = Counts {
+        allocations: after.allocations - before.allocations,
+        bytes: after.bytes - before.bytes,
+    };
+    (out, counts)
+}
@@ -0,0 +1,94 @@
+//! Decoding a buffer of hundreds of length-prefixed frames: a loop over
+//! `FrameV1::decode` doing its own offset math, vs `decode_all`, vs
+//! `decode_all_shared` and `decode_each`, leaving the bodies in the
+//! buffer.
+//!
+//! Run with `cargo bench --bench decode_all`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use pipproto::codec::{DecodeOptions, decode_all, decode_all_shared, decode_each, write_prefixed};
+use pipproto::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+const FRAMES: u64 = 500;
+const ROUNDS: u32 = 2_000;
+
+fn buffer(body_len: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for counter in 0..FRAMES {
+        let frame = FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Event,
+                flags: Flags::new(0).unwrap(),
+                device_id: *b"DEV00001",
+                counter,
+            },
+            body: vec![counter as u8; body_len].into(),
+        };
+        write_prefixed(&frame, &mut buf);
+    }
+    buf
+}
+
+fn naive(buf: &[u8]) -> Vec<FrameV1> {
+    let mut frames = Vec::new();
+    let mut at = 0;
+    while at + 4 <= buf.len() {
+        let len = u32::from_be_bytes(buf[at..at + 4].try_into().unwrap()) as usize;
+        let Some(bytes) = buf.get(at + 4..at + 4 + len) else {
+            break;
+        };
+        match FrameV1::decode(bytes) {
+            Ok(frame) => frames.push(frame),
+            Err(_) => break,
+        }
+        at += 4 + len;
+    }
+    frames
+}
+
+fn time(name: &str, body_len: usize, mut run: impl FnMut() -> usize) -> Duration {
+    assert_eq!(run(), FRAMES as usize);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(run());
+    }
+    let per_buffer = start.elapsed() / ROUNDS;
+    let per_frame = per_buffer.as_nanos() as f64 / FRAMES as f64;
+    println!("{name:>12} {body_len:>5}B: {per_buffer:>10.2?}/buffer {per_frame:>7.1}ns/frame");
+    per_buffer
+}
+
+fn main() {
+    let options = DecodeOptions::default();
+    for body_len in [8, 64, 512] {
+        let buf = buffer(body_len);
+        let naive = time("naive loop", body_len, || naive(black_box(&buf)).len());
+        let all = time("decode_all", body_len, || {
+            decode_all(black_box(&buf), &options).0.len()
+        });
+        let bytes = Bytes::from(buf.clone());
+        let shared = time("shared", body_len, || {
+            decode_all_shared(black_box(&bytes), &options).0.len()
+        });
+        let each = time("decode_each", body_len, || {
+            let mut n = 0;
+            decode_each(black_box(&buf), &options, |_, header, body| {
+                black_box((header, body));
+                n += 1;
+            });
+            n
+        });
+        let speedup = |d: Duration| naive.as_secs_f64() / d.as_secs_f64();
+        println!(
+            "{:>20}  decode_all {:.2}x, shared {:.2}x, decode_each {:.2}x",
+            "",
+            speedup(all),
+            speedup(shared),
+            speedup(each)
+        );
+    }
+}
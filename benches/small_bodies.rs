@@ -0,0 +1,59 @@
+//! Owned decodes of frames with short bodies, kept inline in the frame, vs
+//! copied into a `Vec` as they used to be, around `INLINE_BODY` bytes.
+//!
+//! Run with `cargo bench --bench small_bodies`. Besides the time, each line
+//! shows the allocations per frame.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use pipproto::{
+    Body, Flags, FrameHeaderV1, FrameV1, HEADER_LEN_V1, INLINE_BODY, MsgType, VERSION_V1,
+};
+
+#[path = "common/alloc.rs"]
+mod alloc;
+
+const FRAMES: u64 = 1_000_000;
+
+fn encoded(body_len: usize) -> Vec<u8> {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Event,
+            flags: Flags::new(0).unwrap(),
+            device_id: *b"DEV00001",
+            counter: 7,
+        },
+        body: Body::from(vec![0xab; body_len]),
+    }
+    .encode()
+}
+
+fn report(name: &str, body_len: usize, run: impl Fn()) {
+    let start = Instant::now();
+    let ((), counts) = alloc::counted(|| {
+        for _ in 0..FRAMES {
+            run();
+        }
+    });
+    let per_frame: Duration = start.elapsed() / FRAMES as u32;
+    let allocated = counts.allocations as f64 / FRAMES as f64;
+    println!(
+        "{name:>7} {body_len:>4}B: {per_frame:>8.2?}/frame {allocated:>4.1} allocations/frame"
+    );
+}
+
+fn main() {
+    for body_len in [0, 8, 32, INLINE_BODY, INLINE_BODY + 1, 256] {
+        let input = encoded(body_len);
+        report("inline", body_len, || {
+            black_box(FrameV1::decode(black_box(&input)).unwrap());
+        });
+        report("vec", body_len, || {
+            let input = black_box(&input);
+            let header = FrameHeaderV1::decode(input).unwrap();
+            black_box((header, input[HEADER_LEN_V1..].to_vec()));
+        });
+    }
+}
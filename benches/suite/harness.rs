@@ -0,0 +1,204 @@
+//! A small runner in the manner of criterion's: benchmarks in named
+//! groups, each timed over many samples, its median compared with the one
+//! a saved baseline has.
+//!
+//! `cargo bench --bench suite -- [FILTER] [--quick] [--save-baseline NAME]
+//! [--baseline NAME]` runs the benchmarks whose id contains `FILTER`.
+//! Baselines are kept in `target/bench-baselines/NAME.tsv`, one id and
+//! median in nanoseconds per line.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::hint::black_box;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Changes smaller than this, or than twice the noise, are not called
+/// out.
+const THRESHOLD: f64 = 0.05;
+
+struct Options {
+    filter: Option<String>,
+    quick: bool,
+    save: Option<String>,
+    baseline: Option<String>,
+}
+
+impl Options {
+    fn from_args() -> Self {
+        let mut options = Options {
+            filter: None,
+            quick: false,
+            save: None,
+            baseline: None,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                // passed by `cargo bench`
+                "--bench" => {}
+                "--quick" => options.quick = true,
+                "--save-baseline" => options.save = args.next(),
+                "--baseline" => options.baseline = args.next(),
+                flag if flag.starts_with("--") => {
+                    eprintln!("unknown option {flag:?}");
+                    std::process::exit(2);
+                }
+                _ => options.filter = Some(arg),
+            }
+        }
+        options
+    }
+
+    fn samples(&self) -> (usize, Duration) {
+        if self.quick {
+            (10, Duration::from_millis(1))
+        } else {
+            (30, Duration::from_millis(5))
+        }
+    }
+}
+
+fn baseline_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("target/bench-baselines")
+        .join(format!("{name}.tsv"))
+}
+
+fn load(name: &str) -> HashMap<String, f64> {
+    let path = baseline_path(name);
+    let Ok(text) = fs::read_to_string(&path) else {
+        eprintln!("no baseline {name:?} at {}", path.display());
+        std::process::exit(2);
+    };
+    text.lines()
+        .filter_map(|line| {
+            let (id, ns) = line.split_once('\t')?;
+            Some((id.to_string(), ns.parse().ok()?))
+        })
+        .collect()
+}
+
+pub struct Runner {
+    options: Options,
+    baseline: HashMap<String, f64>,
+    results: Vec<(String, f64)>,
+}
+
+impl Runner {
+    pub fn from_args() -> Self {
+        let options = Options::from_args();
+        let baseline = options.baseline.as_deref().map(load).unwrap_or_default();
+        Runner {
+            options,
+            baseline,
+            results: Vec::new(),
+        }
+    }
+
+    pub fn group(&mut self, name: &str) -> Group<'_> {
+        Group {
+            runner: self,
+            name: name.to_string(),
+            bytes: None,
+        }
+    }
+
+    /// Save the medians, if asked to.
+    pub fn finish(self) {
+        let Some(name) = &self.options.save else {
+            return;
+        };
+        let path = baseline_path(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let text: String = self
+            .results
+            .iter()
+            .map(|(id, ns)| format!("{id}\t{ns}\n"))
+            .collect();
+        fs::write(&path, text).unwrap();
+        println!("saved baseline {name:?} to {}", path.display());
+    }
+}
+
+pub struct Group<'a> {
+    runner: &'a mut Runner,
+    name: String,
+    bytes: Option<usize>,
+}
+
+impl Group<'_> {
+    /// Bytes each call to a routine handles, for a rate in GB/s.
+    pub fn throughput(&mut self, bytes: usize) -> &mut Self {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    /// Time `routine` as `{group}/{id}`.
+    pub fn bench<T>(&mut self, id: impl Display, mut routine: impl FnMut() -> T) -> &mut Self {
+        let id = format!("{}/{id}", self.name);
+        let options = &self.runner.options;
+        if options.filter.as_ref().is_some_and(|f| !id.contains(f)) {
+            return self;
+        }
+        let (samples, sample_time) = options.samples();
+
+        // warm up, and find how many calls fill a sample
+        let mut calls = 1u64;
+        let per_call = loop {
+            let start = Instant::now();
+            for _ in 0..calls {
+                black_box(routine());
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= sample_time / 4 {
+                break elapsed / calls as u32;
+            }
+            calls *= 2;
+        };
+        let calls = (sample_time.as_nanos() / per_call.as_nanos().max(1)).max(1) as u64;
+
+        let mut times: Vec<f64> = (0..samples)
+            .map(|_| {
+                let start = Instant::now();
+                for _ in 0..calls {
+                    black_box(routine());
+                }
+                start.elapsed().as_nanos() as f64 / calls as f64
+            })
+            .collect();
+        times.sort_by(f64::total_cmp);
+        let median = times[samples / 2];
+        // the median absolute deviation, as a share of the median
+        let mut deviations: Vec<f64> = times.iter().map(|t| (t - median).abs()).collect();
+        deviations.sort_by(f64::total_cmp);
+        let noise = deviations[samples / 2] / median;
+
+        let rate = self
+            .bytes
+            .map(|b| format!("{:>8.2} GB/s", b as f64 / median))
+            .unwrap_or_default();
+        let change = match self.runner.baseline.get(&id) {
+            Some(&before) => {
+                let change = median / before - 1.0;
+                let verdict = if change.abs() < THRESHOLD.max(2.0 * noise) {
+                    ""
+                } else if change > 0.0 {
+                    " regressed"
+                } else {
+                    " improved"
+                };
+                format!("  {:>+7.1}%{verdict}", change * 100.0)
+            }
+            None => String::new(),
+        };
+        println!(
+            "{id:<36} {:>12} ±{:>4.1}%{rate}{change}",
+            format!("{:.1?}", Duration::from_secs_f64(median / 1e9)),
+            noise * 100.0,
+        );
+        self.runner.results.push((id, median));
+        self
+    }
+}
@@ -0,0 +1,164 @@
+//! The benchmark suite: each encode and decode path at each of
+//! `workload::BODY_LENS`, on the workloads the tests use.
+//!
+//! Run with `cargo bench --bench suite`; `harness.rs` lists the options,
+//! and the README how to compare two runs. Ids are
+//! `{group}/{path}/{body length}`, plus the chunk size for `stream`.
+//!
+//! A new decode path is one more entry in [`DECODERS`],
+//! [`STREAM_DECODERS`] or [`BATCH_DECODERS`].
+
+mod harness;
+
+use std::hint::black_box;
+
+use bytes::{Bytes, BytesMut};
+use pipproto::codec::{
+    DecodeOptions, FrameCodec, SharedFrameCodec, decode_all, decode_all_shared, decode_each,
+};
+use pipproto::crc::{crc32, verify_many};
+use pipproto::workload::{self, BODY_LENS};
+use pipproto::{FrameHeaderV1, FrameV1, FrameV1B, HEADER_LEN_V1};
+use tokio_util::codec::Decoder;
+
+use harness::Runner;
+
+/// Frames per buffer for the stream, batch and crc groups.
+const FRAMES: usize = 32;
+/// Sizes of the reads a stream decoder is fed: a small serial read, an
+/// Ethernet MTU, a large socket read.
+const CHUNKS: [usize; 3] = [16, 1500, 64 << 10];
+
+/// Decodes a frame or a buffer of frames, returning a length or count
+/// to keep the work from being optimized away.
+type Decode = fn(&Bytes) -> usize;
+/// Decodes a stream read so many bytes at a time.
+type StreamDecode = fn(&[u8], usize) -> usize;
+
+/// Ways of decoding one frame from its encoding, returning its body
+/// length.
+const DECODERS: &[(&str, Decode)] = &[
+    ("owned", |b| FrameV1::decode(b).unwrap().body.len()),
+    ("borrowed", |b| {
+        let frame = FrameV1 {
+            header: FrameHeaderV1::decode(b).unwrap(),
+            body: &b[HEADER_LEN_V1..],
+        };
+        black_box(frame).body.len()
+    }),
+    ("shared", |b| {
+        FrameV1B::decode_bytes(b.clone()).unwrap().body.len()
+    }),
+];
+
+/// Ways of decoding a stream read `chunk` bytes at a time, returning the
+/// frames decoded.
+const STREAM_DECODERS: &[(&str, StreamDecode)] = &[
+    ("owned", |s, chunk| {
+        feed(FrameCodec::new(usize::MAX), s, chunk)
+    }),
+    ("shared", |s, chunk| {
+        feed(SharedFrameCodec(FrameCodec::new(usize::MAX)), s, chunk)
+    }),
+];
+
+/// Ways of decoding a buffer of length-prefixed frames, returning the
+/// frames decoded.
+const BATCH_DECODERS: &[(&str, Decode)] = &[
+    ("owned", |b| {
+        decode_all(b, &DecodeOptions::default()).0.len()
+    }),
+    ("shared", |b| {
+        decode_all_shared(b, &DecodeOptions::default()).0.len()
+    }),
+    ("each", |b| {
+        let mut n = 0;
+        decode_each(b, &DecodeOptions::default(), |_, h, body| {
+            black_box((h, body));
+            n += 1;
+        });
+        n
+    }),
+];
+
+fn feed<D>(mut codec: D, stream: &[u8], chunk: usize) -> usize
+where
+    D: Decoder,
+    D::Error: std::fmt::Debug,
+{
+    let mut buf = BytesMut::new();
+    let mut frames = 0;
+    for read in stream.chunks(chunk) {
+        buf.extend_from_slice(read);
+        while let Some(frame) = codec.decode(&mut buf).unwrap() {
+            black_box(frame);
+            frames += 1;
+        }
+    }
+    frames
+}
+
+fn main() {
+    let mut runner = Runner::from_args();
+    for len in BODY_LENS {
+        let frame = workload::frame(1, len);
+        let encoded = Bytes::from(frame.encode());
+        let frames = workload::frames(FRAMES, len);
+        let stream = workload::stream(&frames);
+        let batch = Bytes::from(stream.clone());
+        let records = workload::records(&frames);
+
+        runner
+            .group("header")
+            .bench(format_args!("encode/{len}"), || frame.header.encode())
+            .bench(format_args!("to_bytes/{len}"), || frame.header.to_bytes())
+            .bench(format_args!("decode/{len}"), || {
+                FrameHeaderV1::decode(&encoded).unwrap()
+            });
+
+        let mut group = runner.group("decode");
+        group.throughput(encoded.len());
+        for (name, decode) in DECODERS {
+            group.bench(format_args!("{name}/{len}"), || decode(&encoded));
+        }
+
+        let mut out = Vec::new();
+        runner
+            .group("encode")
+            .throughput(encoded.len())
+            .bench(format_args!("encode/{len}"), || frame.encode())
+            .bench(format_args!("encode_into/{len}"), || {
+                out.clear();
+                frame.encode_into(&mut out);
+                out.len()
+            });
+
+        let mut group = runner.group("stream");
+        group.throughput(stream.len());
+        for (name, decode) in STREAM_DECODERS {
+            for chunk in CHUNKS {
+                group.bench(format_args!("{name}/{len}/{chunk}"), || {
+                    decode(&stream, chunk)
+                });
+            }
+        }
+
+        let mut group = runner.group("batch");
+        group.throughput(batch.len());
+        for (name, decode) in BATCH_DECODERS {
+            group.bench(format_args!("{name}/{len}"), || decode(&batch));
+        }
+
+        let bytes = records.iter().map(|(r, _)| r.len()).sum();
+        runner
+            .group("crc")
+            .throughput(bytes)
+            .bench(format_args!("crc32/{len}"), || {
+                records.iter().map(|(r, _)| crc32(r)).fold(0, |a, c| a ^ c)
+            })
+            .bench(format_args!("verify_many/{len}"), || {
+                verify_many(records.iter().map(|(r, c)| (&r[..], *c)))
+            });
+    }
+    runner.finish();
+}
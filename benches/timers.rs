@@ -0,0 +1,114 @@
+//! Ack deadlines kept in a timer wheel vs scanned on every poll, as the
+//! ack tracker used to.
+//!
+//! Run with `cargo bench --bench timers`.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use pipproto::wheel::TimerWheel;
+
+const TIMERS: u64 = 100_000;
+const TICKS: u32 = 1_000;
+const STEP: Duration = Duration::from_millis(10);
+
+/// The deadlines, spread over the run, and which are acked before then.
+fn workload() -> Vec<(Duration, bool)> {
+    let mut x = 0x9e37_79b9_7f4a_7c15u64;
+    (0..TIMERS)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let at = Duration::from_micros(x % (STEP * TICKS).as_micros() as u64);
+            (at, x.is_multiple_of(2))
+        })
+        .collect()
+}
+
+trait Timers {
+    fn insert(&mut self, key: u64, at: Instant);
+    fn cancel(&mut self, key: u64);
+    fn tick(&mut self, now: Instant) -> usize;
+    fn next_deadline(&self) -> Option<Instant>;
+}
+
+#[derive(Default)]
+struct Scan(HashMap<u64, Instant>);
+
+impl Timers for Scan {
+    fn insert(&mut self, key: u64, at: Instant) {
+        self.0.insert(key, at);
+    }
+
+    fn cancel(&mut self, key: u64) {
+        self.0.remove(&key);
+    }
+
+    fn tick(&mut self, now: Instant) -> usize {
+        let before = self.0.len();
+        self.0.retain(|_, &mut at| at > now);
+        before - self.0.len()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.0.values().min().copied()
+    }
+}
+
+impl Timers for TimerWheel<u64> {
+    fn insert(&mut self, key: u64, at: Instant) {
+        TimerWheel::insert(self, key, at);
+    }
+
+    fn cancel(&mut self, key: u64) {
+        TimerWheel::cancel(self, &key);
+    }
+
+    fn tick(&mut self, now: Instant) -> usize {
+        TimerWheel::tick(self, now).len()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        TimerWheel::next_deadline(self)
+    }
+}
+
+/// Set every timer, then step through the run, cancelling the acked
+/// ones halfway to their deadline.
+fn run(name: &str, timers: &mut impl Timers, start: Instant, work: &[(Duration, bool)]) {
+    let mut acks = vec![Vec::new(); TICKS as usize + 1];
+    for (key, &(at, acked)) in work.iter().enumerate() {
+        if acked {
+            let tick = (at / 2).as_micros() / STEP.as_micros();
+            acks[tick as usize + 1].push(key as u64);
+        }
+    }
+    let begin = Instant::now();
+    for (key, &(at, _)) in work.iter().enumerate() {
+        timers.insert(key as u64, start + at);
+    }
+    let inserted = begin.elapsed();
+    let mut fired = 0;
+    for tick in 1..=TICKS {
+        for &key in &acks[tick as usize] {
+            timers.cancel(key);
+        }
+        fired += timers.tick(start + STEP * tick);
+        std::hint::black_box(timers.next_deadline());
+    }
+    let total = begin.elapsed();
+    println!("{name:>6}: insert {inserted:>10.2?}  total {total:>10.2?}  fired {fired}");
+}
+
+fn main() {
+    let work = workload();
+    let start = Instant::now();
+    run("scan", &mut Scan::default(), start, &work);
+    run(
+        "wheel",
+        &mut TimerWheel::new(start, Duration::from_millis(1)),
+        start,
+        &work,
+    );
+}
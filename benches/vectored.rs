@@ -0,0 +1,82 @@
+//! Writing large frames: encoding each into a new buffer and writing that,
+//! vs writing the header and the body as they are with one vectored write.
+//!
+//! Run with `cargo bench --bench vectored`. The writer copies what it is
+//! given into a buffer of its own, as a socket's send buffer would, so
+//! both ways copy the body once there; encoding first copies it once
+//! more, and allocates for it.
+
+use std::hint::black_box;
+use std::io::{self, IoSlice, Write};
+use std::time::{Duration, Instant};
+
+use pipproto::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+#[path = "common/alloc.rs"]
+mod alloc;
+
+const BODY_LENS: [usize; 4] = [4 << 10, 64 << 10, 1 << 20, 8 << 20];
+const BYTES_PER_RUN: usize = 512 << 20;
+
+/// A send buffer, emptied as it is written to.
+struct Wire(Vec<u8>);
+
+impl Write for Wire {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.0.clear();
+        let n = self.0.write_vectored(bufs)?;
+        black_box(&self.0);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn frame(body_len: usize) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Event,
+            flags: Flags::new(0).unwrap(),
+            device_id: *b"DEV00001",
+            counter: 1,
+        },
+        body: vec![7; body_len].into(),
+    }
+}
+
+fn report(name: &str, body_len: usize, frames: usize, run: impl FnOnce()) {
+    let start = Instant::now();
+    let ((), counts) = alloc::counted(run);
+    let elapsed: Duration = start.elapsed();
+    let bytes = counts.bytes / frames as u64;
+    let per_frame = elapsed / frames as u32;
+    let gbps = (frames * body_len) as f64 / elapsed.as_secs_f64() / 1e9;
+    println!(
+        "{name:>9} {body_len:>8}B: {per_frame:>10.2?}/frame {gbps:>6.2} GB/s {bytes:>8} B allocated/frame"
+    );
+}
+
+fn main() {
+    for body_len in BODY_LENS {
+        let f = frame(body_len);
+        let frames = BYTES_PER_RUN / body_len;
+        let mut wire = Wire(Vec::with_capacity(body_len + 64));
+        report("encode", body_len, frames, || {
+            for _ in 0..frames {
+                wire.write_all(&f.encode()).unwrap();
+            }
+        });
+        report("vectored", body_len, frames, || {
+            for _ in 0..frames {
+                f.write_vectored_to(&mut wire).unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,163 @@
+-- PiProto dissector for Wireshark, generated by pipproto 0.1.0 (`pipproto dissector`).
+-- Do not edit: regenerate it from the crate instead.
+
+local pipproto = Proto("pipproto", "PiProto")
+
+local MAGIC = "PP"
+local PACK_MAGIC = "PK"
+local VERSION_V1 = 1
+local HEADER_LEN_V1 = 21
+local ACK_REQUIRED = 0x01
+local RESERVED_FLAGS = 0xfe
+
+local msg_types = {
+    [0x01] = "event",
+    [0x02] = "command",
+    [0x03] = "ack",
+    [0x04] = "error",
+}
+
+local f = pipproto.fields
+f.magic = ProtoField.string("pipproto.magic", "Magic")
+f.version = ProtoField.uint8("pipproto.version", "Version", base.DEC)
+f.type = ProtoField.uint8("pipproto.type", "Type", base.HEX, msg_types)
+f.flags = ProtoField.uint8("pipproto.flags", "Flags", base.HEX)
+f.device_id = ProtoField.bytes("pipproto.device_id", "Device ID")
+f.counter = ProtoField.uint64("pipproto.counter", "Counter", base.DEC)
+f.ack_required = ProtoField.bool("pipproto.flags.ack_required", "ACK_REQUIRED", 8, nil, ACK_REQUIRED)
+f.reserved = ProtoField.uint8("pipproto.flags.reserved", "Reserved", base.HEX, nil, RESERVED_FLAGS)
+f.body = ProtoField.bytes("pipproto.body", "Body")
+f.entry_len = ProtoField.uint16("pipproto.entry_len", "Entry length", base.DEC)
+
+local e = pipproto.experts
+e.truncated = ProtoExpert.new("pipproto.truncated", "Frame ends inside the header", expert.group.MALFORMED, expert.severity.ERROR)
+e.bad_magic = ProtoExpert.new("pipproto.bad_magic", "Not a PiProto frame", expert.group.MALFORMED, expert.severity.ERROR)
+e.unknown_version = ProtoExpert.new("pipproto.unknown_version", "Unknown protocol version", expert.group.PROTOCOL, expert.severity.ERROR)
+e.unknown_type = ProtoExpert.new("pipproto.unknown_type", "Unknown message type", expert.group.PROTOCOL, expert.severity.ERROR)
+e.reserved_flags = ProtoExpert.new("pipproto.reserved_flags", "Reserved flag bits set", expert.group.PROTOCOL, expert.severity.ERROR)
+e.bad_entry = ProtoExpert.new("pipproto.bad_entry", "Packed entry runs past the datagram", expert.group.MALFORMED, expert.severity.ERROR)
+
+pipproto.prefs.port = Pref.uint("UDP port", 4000, "UDP port to decode as PiProto")
+
+-- Header fields: field, offset, length.
+local header_v1 = {
+    { f.magic, 0, 2 },
+    { f.version, 2, 1 },
+    { f.type, 3, 1 },
+    { f.flags, 4, 1 },
+    { f.device_id, 5, 8 },
+    { f.counter, 13, 8 },
+}
+
+local function dissect_v1(buf, pinfo, item)
+    for _, field in ipairs(header_v1) do
+        local at, len = field[2], field[3]
+        if buf:len() < at + len then
+            item:add_proto_expert_info(e.truncated)
+            return
+        end
+        local added = item:add(field[1], buf(at, len))
+        if field[1] == f.flags then
+            added:add(f.ack_required, buf(at, len))
+            added:add(f.reserved, buf(at, len))
+        end
+    end
+    if buf:len() > HEADER_LEN_V1 then
+        item:add(f.body, buf(HEADER_LEN_V1))
+    end
+
+    local msg_type = buf(3, 1):uint()
+    local name = msg_types[msg_type]
+    if name == nil then
+        item:add_proto_expert_info(e.unknown_type)
+        name = string.format("0x%02x", msg_type)
+    end
+    if bit.band(buf(4, 1):uint(), RESERVED_FLAGS) ~= 0 then
+        item:add_proto_expert_info(e.reserved_flags)
+    end
+    pinfo.cols.info:append(string.format(
+        " %s device=%s counter=%s len=%d",
+        name,
+        buf(5, 8):bytes():tohex(),
+        tostring(buf(13, 8):uint64()),
+        buf:len() - HEADER_LEN_V1
+    ))
+end
+
+local versions = {
+    [VERSION_V1] = dissect_v1,
+}
+
+local function dissect_frame(buf, pinfo, tree)
+    local item = tree:add(pipproto, buf())
+    if buf:len() < 3 then
+        item:add_proto_expert_info(e.truncated)
+        return
+    end
+    if buf(0, 2):string() ~= MAGIC then
+        item:add_proto_expert_info(e.bad_magic)
+        return
+    end
+    local dissect = versions[buf(2, 1):uint()]
+    if dissect == nil then
+        item:add(f.magic, buf(0, 2))
+        item:add(f.version, buf(2, 1))
+        item:add_proto_expert_info(e.unknown_version)
+        return
+    end
+    dissect(buf, pinfo, item)
+end
+
+function pipproto.dissector(buf, pinfo, tree)
+    if buf:len() < 2 then
+        return 0
+    end
+    local magic = buf(0, 2):string()
+    if magic ~= MAGIC and magic ~= PACK_MAGIC then
+        return 0
+    end
+    pinfo.cols.protocol = "PIPPROTO"
+    pinfo.cols.info = ""
+    if magic == MAGIC then
+        dissect_frame(buf, pinfo, tree)
+        return buf:len()
+    end
+
+    local packed = tree:add(pipproto, buf(), "PiProto packed datagram")
+    local at = 2
+    while at < buf:len() do
+        if buf:len() < at + 2 then
+            packed:add_proto_expert_info(e.bad_entry)
+            break
+        end
+        local len = buf(at, 2):uint()
+        if buf:len() < at + 2 + len then
+            packed:add(f.entry_len, buf(at, 2))
+            packed:add_proto_expert_info(e.bad_entry)
+            break
+        end
+        packed:add(f.entry_len, buf(at, 2))
+        if len > 0 then
+            dissect_frame(buf(at + 2, len):tvb(), pinfo, packed)
+        end
+        at = at + 2 + len
+    end
+    return buf:len()
+end
+
+local registered = nil
+
+local function register()
+    local udp = DissectorTable.get("udp.port")
+    if registered ~= nil then
+        udp:remove(registered, pipproto)
+    end
+    registered = pipproto.prefs.port
+    udp:add(registered, pipproto)
+end
+
+function pipproto.prefs_changed()
+    register()
+end
+
+register()
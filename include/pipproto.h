@@ -0,0 +1,124 @@
+/* pipproto C bindings: generated by cbindgen from src/ffi.rs, do not edit. */
+
+#ifndef PIPPROTO_H
+#define PIPPROTO_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ Length of an encoded header; an encoded frame is this plus its body.
+ */
+#define PP_HEADER_LEN 21
+
+/*
+ What a call returns: `Ok`, a [`DecodeError`] kind, or a misuse.
+ */
+enum PpError
+#if defined(__cplusplus) || __STDC_VERSION__ >= 202311L
+  : int32_t
+#endif // defined(__cplusplus) || __STDC_VERSION__ >= 202311L
+ {
+  PP_ERROR_OK = 0,
+  PP_ERROR_TOO_SHORT = 1,
+  PP_ERROR_BAD_MAGIC = 2,
+  PP_ERROR_BAD_VERSION = 3,
+  PP_ERROR_UNKNOWN_MSG_TYPE = 4,
+  PP_ERROR_RESERVED_FLAGS = 5,
+  PP_ERROR_BAD_DEVICE_ID_BYTES = 6,
+  PP_ERROR_BAD_COUNTER_BYTES = 7,
+  /*
+   A pointer that must not be NULL was.
+   */
+  PP_ERROR_NULL_POINTER = 8,
+  /*
+   The output buffer is smaller than the encoded frame.
+   */
+  PP_ERROR_BUFFER_TOO_SMALL = 9,
+  /*
+   The library panicked; the call had no effect.
+   */
+  PP_ERROR_PANIC = 10,
+};
+#ifndef __cplusplus
+#if __STDC_VERSION__ >= 202311L
+typedef enum PpError PpError;
+#else
+typedef int32_t PpError;
+#endif // __STDC_VERSION__ >= 202311L
+#endif // __cplusplus
+
+/*
+ A frame's header fields and body.
+
+ `body` is NULL when `body_len` is 0.
+ */
+typedef struct PpFrame {
+  uint8_t version;
+  uint8_t msg_type;
+  uint8_t flags;
+  uint8_t device_id[8];
+  uint64_t counter;
+  uint8_t *body;
+  size_t body_len;
+} PpFrame;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Decode the `len` bytes at `input` into `*out`, its body a copy the
+ library allocates.
+
+ `*out` is overwritten, not freed; on error it holds no body. Release
+ a decoded frame with [`pp_frame_free`].
+
+ # Safety
+
+ `input` must point to `len` readable bytes (or may be NULL if `len`
+ is 0), and `out` to a writable `PpFrame`.
+ */
+PpError pp_frame_decode(const uint8_t *input, size_t len, struct PpFrame *out);
+
+/*
+ Encode `*frame` into the `cap` bytes at `out`, setting `*written` to
+ the encoded length.
+
+ If `cap` is too small nothing is written to `out` and the call returns
+ `BufferTooSmall`, `*written` still set to the length needed, so
+ passing a NULL `out` and a `cap` of 0 asks for that length.
+
+ # Safety
+
+ `frame` must point to a valid `PpFrame` whose `body` points to
+ `body_len` readable bytes; `out` to `cap` writable bytes, not
+ overlapping the body; and `written` to a writable `size_t`.
+ */
+PpError pp_frame_encode(const struct PpFrame *frame, uint8_t *out, size_t cap, size_t *written);
+
+/*
+ Release the body of a frame [`pp_frame_decode`] filled in, leaving
+ `*frame` with none. NULL, or a frame with no body, is a no-op, so
+ freeing twice is harmless.
+
+ # Safety
+
+ `frame` must be NULL or point to a `PpFrame` whose body, if any, came
+ from [`pp_frame_decode`], never one the caller set.
+ */
+void pp_frame_free(struct PpFrame *frame);
+
+/*
+ A static, NUL-terminated description of `code`, for logs.
+ */
+const char *pp_error_message(int32_t code);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* PIPPROTO_H */
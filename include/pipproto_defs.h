@@ -0,0 +1,61 @@
+/* PiProto v1 constants, generated by pipproto 0.1.0 (`pipproto c-defs`).
+ * Do not edit: regenerate it from the crate instead. */
+
+#ifndef PIPPROTO_DEFS_H
+#define PIPPROTO_DEFS_H
+
+/* What frames, and packed datagrams (PP_PACK_MAGIC), start with. */
+#define PP_MAGIC_0 0x50u                     /* 'P' */
+#define PP_MAGIC_1 0x50u                     /* 'P' */
+#define PP_PACK_MAGIC_0 0x50u                /* 'P' */
+#define PP_PACK_MAGIC_1 0x4bu                /* 'K' */
+#define PP_MAGIC_LEN 2u
+
+#define PP_VERSION_V1 0x01u
+#define PP_HEADER_LEN_V1 21u                 /* a frame is this plus its body */
+
+/* Header fields: byte offset and size. Integers are big-endian. */
+#define PP_MAGIC_OFFSET 0u
+#define PP_MAGIC_SIZE 2u
+#define PP_VERSION_OFFSET 2u
+#define PP_VERSION_SIZE 1u
+#define PP_MSG_TYPE_OFFSET 3u
+#define PP_MSG_TYPE_SIZE 1u
+#define PP_FLAGS_OFFSET 4u
+#define PP_FLAGS_SIZE 1u
+#define PP_DEVICE_ID_OFFSET 5u
+#define PP_DEVICE_ID_SIZE 8u
+#define PP_COUNTER_OFFSET 13u
+#define PP_COUNTER_SIZE 8u
+#define PP_BODY_OFFSET 21u
+
+/* msg_type values; any other is an error. */
+#define PP_MSG_TYPE_EVENT 0x01u
+#define PP_MSG_TYPE_COMMAND 0x02u
+#define PP_MSG_TYPE_ACK 0x03u
+#define PP_MSG_TYPE_ERROR 0x04u
+
+/* Flag bits; reserved bits must be zero. */
+#define PP_FLAG_ACK_REQUIRED 0x01u
+#define PP_FLAGS_RESERVED 0xfeu
+
+/* Why a frame doesn't decode, and the offset of the field at fault. */
+#define PP_ERR_OK 0
+#define PP_ERR_TOO_SHORT 1                   /* input too short */
+#define PP_ERR_BAD_MAGIC 2                   /* bad magic, at offset 0 */
+#define PP_ERR_BAD_VERSION 3                 /* unsupported version, at offset 2 */
+#define PP_ERR_UNKNOWN_MSG_TYPE 4            /* unknown msg_type, at offset 3 */
+#define PP_ERR_RESERVED_FLAGS 5              /* reserved flag bits set, at offset 4 */
+#define PP_ERR_BAD_DEVICE_ID_BYTES 6         /* bad device_id bytes, at offset 5 */
+#define PP_ERR_BAD_COUNTER_BYTES 7           /* bad counter bytes, at offset 13 */
+
+/* Length limits, in bytes. */
+#define PP_MIN_FRAME_LEN 21u                 /* an empty body */
+#define PP_STREAM_PREFIX_LEN 4u              /* big-endian, before each frame */
+#define PP_STREAM_MAX_FRAME_LEN 65536u       /* a stream decoder's default */
+#define PP_MAX_DATAGRAM 65507u               /* largest UDP payload over IPv4 */
+#define PP_MAX_DATAGRAM_BODY 65486u          /* in one datagram */
+#define PP_DEFAULT_MTU 1200u                 /* payload budget on unknown paths */
+#define PP_PACK_ENTRY_PREFIX_LEN 2u          /* big-endian, before each packed frame */
+
+#endif /* PIPPROTO_DEFS_H */
@@ -0,0 +1,30 @@
+/** A frame, in the serde JSON representation the HTTP gateway uses. */
+export interface Frame {
+  /** 1 if left out. */
+  version?: number
+  msg_type: 'event' | 'command' | 'ack' | 'error'
+  flags?: FrameFlags
+  /** 16 hex digits. */
+  device_id: string
+  /** A `bigint` above `u32::MAX`. */
+  counter: number | bigint
+  /** Base64. */
+  body?: string
+  /** The body as hex instead, for `encodeFrame`. */
+  body_hex?: string
+}
+
+export interface FrameFlags {
+  ack_required?: boolean
+}
+
+/** What every binding throws. */
+export interface PipprotoError extends Error {
+  name: 'PipprotoError'
+  /** A `DecodeError` kind, `invalid_frame`, `too_large` or `truncated`. */
+  kind: string
+  /** The offending field's offset, if a decode error has one. */
+  offset: number | null
+  /** From `FrameDecoder.feed`: the frames the chunk decoded before it. */
+  frames?: Frame[]
+}
@@ -0,0 +1,55 @@
+/* tslint:disable */
+/* eslint-disable */
+
+/* auto-generated by NAPI-RS */
+
+/** A frame, in the serde JSON representation the HTTP gateway uses. */
+export interface Frame {
+  /** 1 if left out. */
+  version?: number
+  msg_type: 'event' | 'command' | 'ack' | 'error'
+  flags?: FrameFlags
+  /** 16 hex digits. */
+  device_id: string
+  /** A `bigint` above `u32::MAX`. */
+  counter: number | bigint
+  /** Base64. */
+  body?: string
+  /** The body as hex instead, for `encodeFrame`. */
+  body_hex?: string
+}
+
+export interface FrameFlags {
+  ack_required?: boolean
+}
+
+/** What every binding throws. */
+export interface PipprotoError extends Error {
+  name: 'PipprotoError'
+  /** A `DecodeError` kind, `invalid_frame`, `too_large` or `truncated`. */
+  kind: string
+  /** The offending field's offset, if a decode error has one. */
+  offset: number | null
+  /** From `FrameDecoder.feed`: the frames the chunk decoded before it. */
+  frames?: Frame[]
+}
+
+/** Encode a frame object. */
+export declare function encodeFrame(frame: Frame): Buffer
+/** Decode one frame, without a length prefix, into an object. */
+export declare function decodeFrame(bytes: Buffer): Frame
+/** Length-prefixed frames out of a stream, fed a chunk at a time. */
+export declare class FrameDecoder {
+  constructor(maxFrameLen?: number | undefined | null)
+  /**
+   * The frames `chunk` completes. A frame that doesn't decode throws,
+   * with the frames before it as `error.frames`; the next call, even
+   * with an empty chunk, carries on after it. A `too_large` error
+   * leaves the stream out of step.
+   */
+  feed(chunk: Buffer): Frame[]
+  /** Throw a `truncated` error if the stream ended inside a frame. */
+  finish(): void
+  /** Bytes fed but not yet decoded. */
+  get buffered(): number
+}
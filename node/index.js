@@ -0,0 +1,2 @@
+// The addon `npm run build` builds; its types are in index.d.ts.
+module.exports = require(process.env.PIPPROTO_ADDON || './pipproto.node')
@@ -0,0 +1,121 @@
+//! Node.js bindings for pipproto, built by napi-rs into `pipproto.node`;
+//! `index.d.ts` has their types.
+//!
+//! Frames are objects in the `serde` representation, so they are the
+//! documents the HTTP gateway takes and returns. Errors are thrown as
+//! `PipprotoError`s with the `kind` and `offset` the gateway reports.
+//! A `counter` is a number, or a `BigInt` above `u32::MAX`.
+
+use bytes::BytesMut;
+use napi::bindgen_prelude::{Buffer, Error, Result, Status};
+use napi::{Env, JsObject, JsUnknown};
+use napi_derive::napi;
+use tokio_util::codec::Decoder;
+
+use pipproto::FrameV1;
+use pipproto::codec::{DEFAULT_MAX_FRAME_LEN, FrameCodec};
+use pipproto::transport::{ProtocolError, TransportError};
+
+/// A JS `Error` named `PipprotoError`, with `kind` and `offset`.
+fn error(env: &Env, kind: &str, message: String, offset: Option<usize>) -> Result<JsObject> {
+    let mut error = env.create_error(Error::new(Status::GenericFailure, message))?;
+    error.set_named_property("name", env.create_string("PipprotoError")?)?;
+    error.set_named_property("kind", env.create_string(kind)?)?;
+    match offset {
+        Some(o) => error.set_named_property("offset", env.create_uint32(o as u32)?)?,
+        None => error.set_named_property("offset", env.get_null()?)?,
+    }
+    Ok(error)
+}
+
+/// Throw `error`, for returning from a binding.
+fn throw(env: &Env, error: Result<JsObject>) -> Error {
+    match error.and_then(|e| env.throw(e)) {
+        Ok(()) => Error::from_status(Status::PendingException),
+        Err(e) => e,
+    }
+}
+
+/// Encode a frame object.
+#[napi(js_name = "encodeFrame", ts_args_type = "frame: Frame")]
+pub fn encode_frame(env: Env, frame: JsUnknown) -> Result<Buffer> {
+    let frame: FrameV1 = env
+        .from_js_value(frame)
+        .map_err(|e| throw(&env, error(&env, "invalid_frame", e.reason, None)))?;
+    Ok(frame.encode().into())
+}
+
+/// Decode one frame, without a length prefix, into an object.
+#[napi(js_name = "decodeFrame", ts_return_type = "Frame")]
+pub fn decode_frame(env: Env, bytes: Buffer) -> Result<JsUnknown> {
+    let frame = FrameV1::decode(&bytes)
+        .map_err(|e| throw(&env, error(&env, e.kind(), e.to_string(), e.offset())))?;
+    env.to_js_value(&frame)
+}
+
+/// Length-prefixed frames out of a stream, fed a chunk at a time.
+#[napi]
+pub struct FrameDecoder {
+    codec: FrameCodec,
+    buf: BytesMut,
+}
+
+#[napi]
+impl FrameDecoder {
+    #[napi(constructor)]
+    pub fn new(max_frame_len: Option<u32>) -> Self {
+        let max_frame_len = max_frame_len.map_or(DEFAULT_MAX_FRAME_LEN, |n| n as usize);
+        FrameDecoder {
+            codec: FrameCodec::new(max_frame_len),
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// The frames `chunk` completes. A frame that doesn't decode throws,
+    /// with the frames before it as `error.frames`; the next call, even
+    /// with an empty chunk, carries on after it. A `too_large` error
+    /// leaves the stream out of step.
+    #[napi(ts_return_type = "Frame[]")]
+    pub fn feed(&mut self, env: Env, chunk: Buffer) -> Result<JsUnknown> {
+        self.buf.extend_from_slice(&chunk);
+        let mut frames = Vec::new();
+        loop {
+            let thrown = match self.codec.decode(&mut self.buf) {
+                Ok(Some(Ok(frame))) => {
+                    frames.push(frame);
+                    continue;
+                }
+                Ok(None) => return env.to_js_value(&frames),
+                Ok(Some(Err(e))) => error(&env, e.kind(), e.to_string(), e.offset()),
+                Err(TransportError::Protocol(ProtocolError::TooLarge(n))) => error(
+                    &env,
+                    "too_large",
+                    format!("frame too large: {n} bytes"),
+                    None,
+                ),
+                Err(e) => return Err(Error::from_reason(e.to_string())),
+            };
+            let thrown = thrown.and_then(|mut e| {
+                e.set_named_property("frames", env.to_js_value(&frames)?)?;
+                Ok(e)
+            });
+            return Err(throw(&env, thrown));
+        }
+    }
+
+    /// Throw a `truncated` error if the stream ended inside a frame.
+    #[napi]
+    pub fn finish(&self, env: Env) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let message = "stream ends inside a frame".to_string();
+        Err(throw(&env, error(&env, "truncated", message, None)))
+    }
+
+    /// Bytes fed but not yet decoded.
+    #[napi(getter)]
+    pub fn buffered(&self) -> u32 {
+        self.buf.len() as u32
+    }
+}
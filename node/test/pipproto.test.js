@@ -0,0 +1,157 @@
+// The addon as Node code uses it. Run with `npm test` after
+// `npm run build`; `cargo test -p pipproto-node` builds it and runs
+// these too.
+
+const assert = require('node:assert/strict')
+const fs = require('node:fs')
+const path = require('node:path')
+const test = require('node:test')
+
+const pipproto = require('..')
+const { decodeFrame, encodeFrame, FrameDecoder } = pipproto
+
+const HEADER_LEN = 21
+
+function sample(changes = {}) {
+  return {
+    version: 1,
+    msg_type: 'command',
+    flags: { ack_required: true },
+    device_id: 'deadbeef00000001',
+    counter: 7,
+    body: Buffer.from('hello').toString('base64'),
+    ...changes,
+  }
+}
+
+function prefixed(bytes) {
+  const len = Buffer.alloc(4)
+  len.writeUInt32BE(bytes.length)
+  return Buffer.concat([len, bytes])
+}
+
+function assertPipprotoError(fn, kind, offset) {
+  let caught
+  assert.throws(fn, (e) => {
+    caught = e
+    return e instanceof Error && e.name === 'PipprotoError'
+  })
+  assert.equal(caught.kind, kind)
+  assert.equal(caught.offset, offset)
+  return caught
+}
+
+test('frames round-trip', () => {
+  const bytes = encodeFrame(sample())
+  assert.ok(Buffer.isBuffer(bytes))
+  assert.equal(bytes.length, HEADER_LEN + 5)
+  assert.deepEqual([...bytes.subarray(0, 5)], [0x50, 0x50, 1, 2, 1])
+  assert.deepEqual(decodeFrame(bytes), sample())
+})
+
+test('documents are the HTTP gateway\'s', () => {
+  // as `POST /frames` takes it: defaults left out, the body as hex
+  const doc = JSON.parse(
+    '{"msg_type":"ack","device_id":"0000000000000002","counter":5,"body_hex":"6869"}',
+  )
+  const frame = decodeFrame(encodeFrame(doc))
+  assert.deepEqual(frame, {
+    version: 1,
+    msg_type: 'ack',
+    flags: { ack_required: false },
+    device_id: '0000000000000002',
+    counter: 5,
+    body: 'aGk=',
+  })
+  assert.deepEqual(JSON.parse(JSON.stringify(frame)), frame)
+})
+
+test('counters above u32::MAX are bigints', () => {
+  const max = 2n ** 64n - 1n
+  const frame = decodeFrame(encodeFrame(sample({ counter: max })))
+  assert.equal(frame.counter, max)
+  assert.equal(decodeFrame(encodeFrame(sample({ counter: 2 ** 32 - 1 }))).counter, 2 ** 32 - 1)
+})
+
+test('decode errors carry kind and offset', () => {
+  const good = encodeFrame(sample())
+  const at = (offset, byte) => {
+    const bytes = Buffer.from(good)
+    bytes[offset] = byte
+    return bytes
+  }
+  assertPipprotoError(() => decodeFrame(good.subarray(0, 20)), 'too_short', null)
+  assertPipprotoError(() => decodeFrame(at(0, 0x58)), 'bad_magic', 0)
+  assertPipprotoError(() => decodeFrame(at(2, 9)), 'bad_version', 2)
+  assertPipprotoError(() => decodeFrame(at(3, 0x7f)), 'unknown_msg_type', 3)
+  assertPipprotoError(() => decodeFrame(at(4, 0x80)), 'reserved_flags', 4)
+})
+
+test('bad documents are invalid_frame', () => {
+  for (const doc of [
+    sample({ version: 2 }),
+    sample({ msg_type: 'nope' }),
+    sample({ device_id: 'dead' }),
+    sample({ body: '!' }),
+    sample({ extra: 1 }),
+    sample({ counter: -1 }),
+  ]) {
+    assertPipprotoError(() => encodeFrame(doc), 'invalid_frame', null)
+  }
+})
+
+test('a stream decodes fed in chunks', () => {
+  const frames = Array.from({ length: 50 }, (_, n) =>
+    sample({ counter: n, body: Buffer.alloc(n, n).toString('base64') }),
+  )
+  const stream = Buffer.concat(frames.map((f) => prefixed(encodeFrame(f))))
+  for (const size of [1, 7, 1500, stream.length]) {
+    const decoder = new FrameDecoder()
+    const got = []
+    for (let start = 0; start < stream.length; start += size) {
+      got.push(...decoder.feed(stream.subarray(start, start + size)))
+    }
+    decoder.finish()
+    assert.deepEqual(got, frames)
+    assert.equal(decoder.buffered, 0)
+  }
+})
+
+test('a truncated stream fails finish', () => {
+  const decoder = new FrameDecoder()
+  const bytes = prefixed(encodeFrame(sample()))
+  assert.deepEqual(decoder.feed(bytes.subarray(0, -3)), [])
+  assert.equal(decoder.buffered, bytes.length - 3)
+  assertPipprotoError(() => decoder.finish(), 'truncated', null)
+})
+
+test('a bad frame throws with the frames before it', () => {
+  const bad = encodeFrame(sample())
+  bad[0] = 0x58
+  const good = (counter) => prefixed(encodeFrame(sample({ counter })))
+  const decoder = new FrameDecoder()
+  const e = assertPipprotoError(
+    () => decoder.feed(Buffer.concat([good(1), prefixed(bad), good(2)])),
+    'bad_magic',
+    0,
+  )
+  assert.deepEqual(e.frames.map((f) => f.counter), [1])
+  assert.deepEqual(decoder.feed(Buffer.alloc(0)).map((f) => f.counter), [2])
+  decoder.finish()
+})
+
+test('a frame over maxFrameLen is too_large', () => {
+  const decoder = new FrameDecoder(100)
+  const big = prefixed(encodeFrame(sample({ body: Buffer.alloc(200).toString('base64') })))
+  const e = assertPipprotoError(() => decoder.feed(big), 'too_large', null)
+  assert.deepEqual(e.frames, [])
+})
+
+test('index.d.ts declares every export', () => {
+  const dts = fs.readFileSync(path.join(__dirname, '..', 'index.d.ts'), 'utf8')
+  for (const name of Object.keys(pipproto)) {
+    assert.match(dts, new RegExp(`export declare (function|class) ${name}\\b`))
+  }
+  const header = fs.readFileSync(path.join(__dirname, '..', 'header.d.ts'), 'utf8')
+  assert.ok(dts.includes(header), 'index.d.ts is missing header.d.ts')
+})
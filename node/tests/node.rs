@@ -0,0 +1,43 @@
+//! The JS tests in `test/`, against the addon as `napi build` would
+//! build it.
+
+#![cfg(target_os = "linux")]
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn node_tests_pass() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // this test is target/{profile}/deps/node-*
+    let exe = std::env::current_exe().unwrap();
+    let dir = exe.parent().unwrap().parent().unwrap();
+    let profile = match dir.file_name().unwrap().to_str().unwrap() {
+        "debug" => "dev",
+        other => other,
+    };
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--profile", profile])
+        .arg("--manifest-path")
+        .arg(root.join("Cargo.toml"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // node loads addons by their `.node` name
+    let tmp = tempfile::tempdir().unwrap();
+    let addon = tmp.path().join("pipproto.node");
+    std::fs::copy(dir.join("libpipproto_node.so"), &addon).unwrap();
+
+    let run = Command::new("node")
+        .args(["--test", "test/"])
+        .current_dir(root)
+        .env("PIPPROTO_ADDON", &addon)
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stdout)
+    );
+}
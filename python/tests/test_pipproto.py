@@ -0,0 +1,171 @@
+"""The pipproto extension module, as Python code uses it.
+
+Run after `maturin develop` with `python -m unittest discover python/tests`
+(or pytest); `cargo test --features python` builds the module and runs
+them too.
+"""
+
+import io
+import struct
+import unittest
+
+import pipproto
+from pipproto import Decoder, Frame, MsgType
+
+
+def sample(**changes):
+    fields = dict(
+        msg_type=MsgType.COMMAND,
+        device_id=b"DEV00042",
+        counter=7,
+        body=b"hello",
+        flags=1,
+    )
+    fields.update(changes)
+    return Frame(**fields)
+
+
+def prefixed(data):
+    return struct.pack(">I", len(data)) + data
+
+
+class FrameTest(unittest.TestCase):
+    def test_round_trip(self):
+        frame = sample()
+        data = frame.encode()
+        self.assertEqual(len(data), pipproto.HEADER_LEN + 5)
+        self.assertEqual(data[:5], b"PP\x01\x02\x01")
+        back = Frame.decode(data)
+        self.assertEqual(back, frame)
+        self.assertEqual(back.version, 1)
+        self.assertEqual(back.msg_type, MsgType.COMMAND)
+        self.assertEqual(back.flags, 1)
+        self.assertTrue(back.ack_required)
+        self.assertEqual(back.device_id, b"DEV00042")
+        self.assertEqual(back.counter, 7)
+        self.assertEqual(back.body, b"hello")
+
+    def test_defaults_and_wire_values(self):
+        frame = Frame(3, b"\x00" * 8, 2**64 - 1)
+        self.assertEqual(frame.msg_type, MsgType.ACK)
+        self.assertEqual(int(MsgType.ACK), 3)
+        self.assertEqual(frame.body, b"")
+        self.assertEqual(frame.flags, 0)
+        self.assertFalse(frame.ack_required)
+        self.assertEqual(Frame.decode(frame.encode()).counter, 2**64 - 1)
+        self.assertIn("counter=18446744073709551615", repr(frame))
+
+    def test_bad_fields_raise(self):
+        with self.assertRaises(ValueError):
+            sample(device_id=b"short")
+        cases = [
+            (dict(flags=0b10), pipproto.ReservedFlagsError, "reserved_flags", 4),
+            (dict(version=2), pipproto.BadVersionError, "bad_version", 2),
+            (dict(msg_type=9), pipproto.UnknownMsgTypeError, "unknown_msg_type", 3),
+        ]
+        for changes, error, kind, offset in cases:
+            with self.subTest(kind=kind):
+                with self.assertRaises(error) as caught:
+                    sample(**changes)
+                self.assertEqual(caught.exception.kind, kind)
+                self.assertEqual(caught.exception.offset, offset)
+
+    def test_decode_errors(self):
+        good = sample().encode()
+
+        def at(offset, byte):
+            return good[:offset] + bytes([byte]) + good[offset + 1 :]
+
+        cases = [
+            (good[:20], pipproto.TooShortError, "too_short", None),
+            (at(0, ord("X")), pipproto.BadMagicError, "bad_magic", 0),
+            (at(2, 9), pipproto.BadVersionError, "bad_version", 2),
+            (at(3, 0x7F), pipproto.UnknownMsgTypeError, "unknown_msg_type", 3),
+            (at(4, 0x80), pipproto.ReservedFlagsError, "reserved_flags", 4),
+        ]
+        for data, error, kind, offset in cases:
+            with self.subTest(kind=kind):
+                with self.assertRaises(error) as caught:
+                    Frame.decode(data)
+                self.assertIsInstance(caught.exception, pipproto.PipprotoError)
+                self.assertEqual(caught.exception.kind, kind)
+                self.assertEqual(caught.exception.offset, offset)
+
+    def test_every_error_is_a_pipproto_error(self):
+        for name in [
+            "TooShortError",
+            "BadMagicError",
+            "BadVersionError",
+            "UnknownMsgTypeError",
+            "ReservedFlagsError",
+            "BadDeviceIdBytesError",
+            "BadCounterBytesError",
+            "TruncatedError",
+            "FrameTooLargeError",
+        ]:
+            self.assertTrue(issubclass(getattr(pipproto, name), pipproto.PipprotoError))
+        self.assertTrue(issubclass(pipproto.PipprotoError, Exception))
+
+
+class DecoderTest(unittest.TestCase):
+    def frames(self):
+        return [sample(counter=n, body=bytes([n]) * n) for n in range(50)]
+
+    def stream(self):
+        return b"".join(prefixed(f.encode()) for f in self.frames())
+
+    def test_fed_in_chunks(self):
+        stream = self.stream()
+        for size in [1, 7, 1500, len(stream)]:
+            with self.subTest(size=size):
+                decoder = Decoder()
+                got = []
+                for start in range(0, len(stream), size):
+                    decoder.feed(stream[start : start + size])
+                    got.extend(decoder)
+                decoder.finish()
+                self.assertEqual(got, self.frames())
+                self.assertEqual(decoder.position, len(stream))
+                self.assertEqual(decoder.buffered, 0)
+
+    def test_reads_a_file(self):
+        frames = list(Decoder(io.BytesIO(self.stream())))
+        self.assertEqual(frames, self.frames())
+
+    def test_truncated_stream(self):
+        stream = self.stream()[:-3]
+        decoder = Decoder(io.BytesIO(stream))
+        with self.assertRaises(pipproto.TruncatedError) as caught:
+            list(decoder)
+        self.assertEqual(caught.exception.kind, "truncated")
+        self.assertEqual(caught.exception.offset, None)
+
+        decoder = Decoder()
+        decoder.feed(stream)
+        self.assertEqual(len(list(decoder)), 49)
+        with self.assertRaises(pipproto.TruncatedError):
+            decoder.finish()
+
+    def test_bad_frame_is_skipped(self):
+        bad = bytearray(sample().encode())
+        bad[0] = ord("X")
+        good = prefixed(sample(counter=1).encode())
+        decoder = Decoder(io.BytesIO(good + prefixed(bytes(bad)) + good))
+        self.assertEqual(next(decoder).counter, 1)
+        with self.assertRaises(pipproto.BadMagicError) as caught:
+            next(decoder)
+        self.assertEqual(caught.exception.offset, 0)
+        self.assertEqual(decoder.position, 2 * len(good))
+        self.assertEqual([f.counter for f in decoder], [1])
+
+    def test_frame_too_large(self):
+        decoder = Decoder(max_frame_len=100)
+        decoder.feed(prefixed(sample(body=bytes(200)).encode()))
+        with self.assertRaises(pipproto.FrameTooLargeError) as caught:
+            next(decoder)
+        self.assertEqual(caught.exception.kind, "too_large")
+        self.assertEqual(decoder.position, 0)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,1073 @@
+//! Waiting for acks to frames sent with ACK_REQUIRED.
+//!
+//! An ACK frame with an empty body acknowledges the frame whose device id
+//! and counter its header repeats; one with a [`AckBody::Cumulative`] body
+//! acknowledges every counter of the device up to a point, and one with
+//! an [`AckBody::Selective`] body adds the counters received past that
+//! point, so only the holes are resent. Register each
+//! ack-required frame as it is sent, feed received acks to
+//! [`AckTracker::on_ack`], and call [`AckTracker::poll`] at least by
+//! [`AckTracker::next_deadline`] to learn what to resend and what to give
+//! up on. When to resend is up to a [`RetryPolicy`], chosen for all
+//! frames, per message type or per frame. Deadlines are kept in a
+//! [`TimerWheel`], so a poll costs the frames due rather than all those
+//! waiting. A tracker given the sender's [`SendWindow`] gives back the
+//! slot of each frame it resolves or gives up on.
+//!
+//! On the receiving side, [`CumulativeAcker`] decides when to send
+//! cumulative or selective acks.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::retry::{FixedInterval, RetryPolicy};
+use crate::snapshot::{PendingAck, Rebase};
+use crate::wheel::TimerWheel;
+use crate::window::SendWindow;
+use crate::{DecodeError, Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+const CUMULATIVE: u8 = 0x01;
+const SELECTIVE: u8 = 0x02;
+const NACK: u8 = 0x03;
+const REPLY: u8 = 0x04;
+
+/// Longest selective ack bitmap, covering 256 counters past the base.
+pub const MAX_SACK_BYTES: usize = 32;
+
+/// What an ACK frame's body acknowledges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AckBody {
+    /// Empty body: the frame the header names.
+    Single,
+    /// `01` then `up_to` as a big-endian u64: every counter of the
+    /// header's device up to and including `up_to`.
+    Cumulative { up_to: u64 },
+    /// `02`, `base` as a big-endian u64, then up to [`MAX_SACK_BYTES`] of
+    /// bitmap: every counter up to and including `base`, plus
+    /// `base + 1 + 8 * i + b` for each bit `b` (least significant first)
+    /// set in byte `i`.
+    Selective { base: u64, bitmap: Vec<u8> },
+    /// `03` then a UTF-8 reason: the named frame arrived but was refused.
+    Nack { reason: String },
+    /// `04` then application bytes: the named frame was handled, and this
+    /// is the answer.
+    Reply(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AckBodyError {
+    UnknownKind(u8),
+    BadLength { kind: u8, len: usize },
+}
+
+impl fmt::Display for AckBodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckBodyError::UnknownKind(k) => write!(f, "unknown ack kind {k:#04x}"),
+            AckBodyError::BadLength { kind, len } => {
+                write!(f, "ack kind {kind:#04x} with {len}-byte body")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AckBodyError {}
+
+impl AckBody {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            AckBody::Single => Vec::new(),
+            AckBody::Cumulative { up_to } => {
+                let mut body = vec![CUMULATIVE];
+                body.extend_from_slice(&up_to.to_be_bytes());
+                body
+            }
+            AckBody::Selective { base, bitmap } => {
+                let mut body = vec![SELECTIVE];
+                body.extend_from_slice(&base.to_be_bytes());
+                body.extend_from_slice(bitmap);
+                body
+            }
+            AckBody::Nack { reason } => [&[NACK], reason.as_bytes()].concat(),
+            AckBody::Reply(data) => [&[REPLY], data.as_slice()].concat(),
+        }
+    }
+
+    /// Whether this body acknowledges `counter`; `named` is the counter in
+    /// the ack's header.
+    pub fn covers(&self, named: u64, counter: u64) -> bool {
+        match self {
+            AckBody::Single | AckBody::Nack { .. } | AckBody::Reply(_) => counter == named,
+            AckBody::Cumulative { up_to } => counter <= *up_to,
+            AckBody::Selective { base, bitmap } => {
+                let Some(ahead) = counter.checked_sub(*base) else {
+                    return true;
+                };
+                let Some(bit) = ahead.checked_sub(1) else {
+                    return true;
+                };
+                usize::try_from(bit / 8)
+                    .ok()
+                    .and_then(|i| bitmap.get(i))
+                    .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+            }
+        }
+    }
+
+    pub fn decode(body: &[u8]) -> Result<AckBody, AckBodyError> {
+        let Some((&kind, rest)) = body.split_first() else {
+            return Ok(AckBody::Single);
+        };
+        match kind {
+            CUMULATIVE => {
+                let up_to = <[u8; 8]>::try_from(rest).map_err(|_| AckBodyError::BadLength {
+                    kind,
+                    len: body.len(),
+                })?;
+                Ok(AckBody::Cumulative {
+                    up_to: u64::from_be_bytes(up_to),
+                })
+            }
+            SELECTIVE if (8..=8 + MAX_SACK_BYTES).contains(&rest.len()) => {
+                let (base, bitmap) = rest.split_at(8);
+                Ok(AckBody::Selective {
+                    base: u64::from_be_bytes(base.try_into().unwrap()),
+                    bitmap: bitmap.to_vec(),
+                })
+            }
+            SELECTIVE => Err(AckBodyError::BadLength {
+                kind,
+                len: body.len(),
+            }),
+            NACK => Ok(AckBody::Nack {
+                reason: String::from_utf8_lossy(rest).into_owned(),
+            }),
+            REPLY => Ok(AckBody::Reply(rest.to_vec())),
+            _ => Err(AckBodyError::UnknownKind(kind)),
+        }
+    }
+}
+
+/// An ACK for every counter of `device_id` up to and including `up_to`.
+/// The header repeats `up_to`, so a peer that only knows single acks
+/// still resolves that one frame.
+pub fn cumulative_ack(device_id: [u8; 8], up_to: u64) -> FrameV1 {
+    ack_frame(device_id, up_to, AckBody::Cumulative { up_to })
+}
+
+/// A selective ACK, its header naming `base` like [`cumulative_ack`].
+pub fn selective_ack(device_id: [u8; 8], base: u64, bitmap: Vec<u8>) -> FrameV1 {
+    ack_frame(device_id, base, AckBody::Selective { base, bitmap })
+}
+
+pub(crate) fn ack_frame(device_id: [u8; 8], counter: u64, body: AckBody) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Ack,
+            flags: Flags::new(0).unwrap(),
+            device_id,
+            counter,
+        },
+        body: body.encode().into(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AckConfig {
+    /// Time after the latest send before resending under the default
+    /// policy, and before giving up once a frame's policy stops resending.
+    pub timeout: Duration,
+    /// Sends per frame under the default policy, the first included.
+    pub max_attempts: u32,
+    pub max_outstanding: usize,
+    /// Tick of the timer wheel. Deadlines are met exactly whatever it is;
+    /// it trades empty slots passed against timers looked at early.
+    pub resolution: Duration,
+}
+
+impl Default for AckConfig {
+    fn default() -> Self {
+        AckConfig {
+            timeout: Duration::from_secs(1),
+            max_attempts: 3,
+            max_outstanding: 256,
+            resolution: Duration::from_millis(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AckError {
+    /// `max_outstanding` frames are already waiting.
+    Full,
+    /// A frame with this device id and counter is already waiting.
+    Duplicate,
+}
+
+impl fmt::Display for AckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckError::Full => write!(f, "too many frames awaiting ack"),
+            AckError::Duplicate => write!(f, "frame is already awaiting ack"),
+        }
+    }
+}
+
+impl std::error::Error for AckError {}
+
+/// A frame waiting for its ack.
+#[derive(Debug, Clone)]
+pub struct Outstanding {
+    pub frame: FrameV1,
+    pub attempts: u32,
+    pub first_sent: Instant,
+    pub last_sent: Instant,
+    /// When it is next resent; `None` once its policy has stopped.
+    pub retry_at: Option<Instant>,
+    pub policy: Arc<dyn RetryPolicy>,
+}
+
+impl Outstanding {
+    /// When [`AckTracker::poll`] next acts on it, given the tracker's
+    /// `timeout`.
+    fn deadline(&self, timeout: Duration) -> Instant {
+        self.retry_at.unwrap_or(self.last_sent + timeout)
+    }
+}
+
+impl PartialEq for Outstanding {
+    fn eq(&self, other: &Self) -> bool {
+        self.frame == other.frame
+            && self.attempts == other.attempts
+            && self.first_sent == other.first_sent
+            && self.last_sent == other.last_sent
+            && self.retry_at == other.retry_at
+            && Arc::ptr_eq(&self.policy, &other.policy)
+    }
+}
+
+impl Eq for Outstanding {}
+
+/// What [`AckTracker::poll`] found due.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Due {
+    /// Send these again; their attempt counts already include it.
+    pub retransmit: Vec<FrameV1>,
+    /// Sent as often as their policy allows without an ack, and no longer
+    /// tracked.
+    pub expired: Vec<Outstanding>,
+}
+
+type Key = ([u8; 8], u64);
+
+pub struct AckTracker {
+    clock: Arc<dyn Clock>,
+    config: AckConfig,
+    outstanding: HashMap<Key, Outstanding>,
+    timers: TimerWheel<Key>,
+    policy: Arc<dyn RetryPolicy>,
+    by_type: HashMap<MsgType, Arc<dyn RetryPolicy>>,
+    window: Option<SendWindow>,
+}
+
+impl AckTracker {
+    /// Resends every `timeout`, up to `max_attempts` sends, unless told
+    /// otherwise.
+    pub fn new(config: AckConfig, clock: Arc<dyn Clock>) -> Self {
+        let policy = Arc::new(FixedInterval {
+            interval: config.timeout,
+            max_attempts: config.max_attempts,
+        });
+        AckTracker {
+            timers: TimerWheel::new(clock.now(), config.resolution),
+            clock,
+            config,
+            outstanding: HashMap::new(),
+            policy,
+            by_type: HashMap::new(),
+            window: None,
+        }
+    }
+
+    /// Release the slots of `window` that acked and expired frames hold.
+    pub fn with_window(mut self, window: SendWindow) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Resend by `policy` frames of types without their own.
+    pub fn with_policy(mut self, policy: impl RetryPolicy + 'static) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Resend frames of `msg_type` by `policy`.
+    pub fn with_policy_for(
+        mut self,
+        msg_type: MsgType,
+        policy: impl RetryPolicy + 'static,
+    ) -> Self {
+        self.by_type.insert(msg_type, Arc::new(policy));
+        self
+    }
+
+    /// Start waiting for `frame`'s ack, counting this as its first send.
+    pub fn register(&mut self, frame: &FrameV1) -> Result<(), AckError> {
+        let policy = self.policy_for(frame.header.msg_type);
+        self.register_with(frame, policy)
+    }
+
+    fn policy_for(&self, msg_type: MsgType) -> Arc<dyn RetryPolicy> {
+        match self.by_type.get(&msg_type) {
+            Some(policy) => policy.clone(),
+            None => self.policy.clone(),
+        }
+    }
+
+    /// [`AckTracker::register`], resending by `policy` whatever the
+    /// frame's type.
+    pub fn register_with(
+        &mut self,
+        frame: &FrameV1,
+        policy: Arc<dyn RetryPolicy>,
+    ) -> Result<(), AckError> {
+        let key = (frame.header.device_id, frame.header.counter);
+        if self.outstanding.contains_key(&key) {
+            return Err(AckError::Duplicate);
+        }
+        if self.outstanding.len() >= self.config.max_outstanding {
+            return Err(AckError::Full);
+        }
+        let now = self.clock.now();
+        let o = Outstanding {
+            frame: frame.clone(),
+            attempts: 1,
+            first_sent: now,
+            last_sent: now,
+            retry_at: policy.next_delay(1).map(|d| now + d),
+            policy,
+        };
+        self.timers.insert(key, o.deadline(self.config.timeout));
+        self.outstanding.insert(key, o);
+        Ok(())
+    }
+
+    /// Resolve the waiting frames `ack` acknowledges, in counter order.
+    /// Acks that are not ACK frames or have a malformed body resolve
+    /// nothing. A late cumulative ack below one already seen is harmless:
+    /// everything it covers is already resolved.
+    ///
+    /// With the `metrics` feature, the round trip is recorded for frames
+    /// acked on their first attempt; after a resend it is ambiguous which
+    /// send the ack answers.
+    pub fn on_ack(&mut self, ack: &FrameV1) -> Vec<Outstanding> {
+        if ack.header.msg_type != MsgType::Ack {
+            return Vec::new();
+        }
+        let device = ack.header.device_id;
+        let named = ack.header.counter;
+        let keys: Vec<Key> = match AckBody::decode(&ack.body) {
+            Ok(AckBody::Single | AckBody::Nack { .. } | AckBody::Reply(_)) => {
+                vec![(device, named)]
+            }
+            Ok(body) => self
+                .outstanding
+                .keys()
+                .filter(|&&(d, c)| d == device && body.covers(named, c))
+                .copied()
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        let mut done: Vec<Outstanding> = keys
+            .iter()
+            .filter_map(|k| {
+                self.timers.cancel(k);
+                self.outstanding.remove(k)
+            })
+            .collect();
+        done.sort_by_key(|o| o.frame.header.counter);
+        if let Some(w) = &self.window {
+            w.release_all(&done);
+        }
+        #[cfg(feature = "metrics")]
+        for o in done.iter().filter(|o| o.attempts == 1) {
+            crate::metrics::global().observe_ack_rtt(self.clock.now() - o.first_sent);
+        }
+        done
+    }
+
+    /// Frames due to be resent or given up on at `now`.
+    pub fn poll(&mut self, now: Instant) -> Due {
+        let mut due = Due::default();
+        for (key, _) in self.timers.tick(now) {
+            let Some(o) = self.outstanding.get_mut(&key) else {
+                continue;
+            };
+            if o.retry_at.is_none() {
+                due.expired.extend(self.outstanding.remove(&key));
+                continue;
+            }
+            o.attempts += 1;
+            o.last_sent = now;
+            o.retry_at = o.policy.next_delay(o.attempts).map(|d| now + d);
+            due.retransmit.push(o.frame.clone());
+            self.timers.insert(key, o.deadline(self.config.timeout));
+        }
+        due.retransmit.sort_by_key(|f| f.header.counter);
+        due.expired.sort_by_key(|o| o.frame.header.counter);
+        if let Some(w) = &self.window {
+            w.release_all(&due.expired);
+        }
+        due
+    }
+
+    /// Every waiting frame, to send again at once, as over a new link
+    /// after the old one dropped. Each counts as a send, and is resent
+    /// or given up on from `now` as its policy says.
+    pub fn resend_all(&mut self, now: Instant) -> Vec<FrameV1> {
+        let mut frames = Vec::with_capacity(self.outstanding.len());
+        for (key, o) in &mut self.outstanding {
+            o.attempts += 1;
+            o.last_sent = now;
+            o.retry_at = o.policy.next_delay(o.attempts).map(|d| now + d);
+            self.timers.insert(*key, o.deadline(self.config.timeout));
+            frames.push(o.frame.clone());
+        }
+        frames.sort_by_key(|f| (f.header.device_id, f.header.counter));
+        frames
+    }
+
+    /// When the next frame falls due, if any are waiting.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+
+    pub fn state(&self, device_id: [u8; 8], counter: u64) -> Option<&Outstanding> {
+        self.outstanding.get(&(device_id, counter))
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub(crate) fn export(&self, now: Instant) -> Vec<PendingAck> {
+        let mut pending: Vec<_> = self.outstanding.values().collect();
+        pending.sort_by_key(|o| (o.frame.header.device_id, o.frame.header.counter));
+        pending
+            .into_iter()
+            .map(|o| PendingAck {
+                frame: o.frame.encode(),
+                attempts: o.attempts,
+                first_sent_ago: now.saturating_duration_since(o.first_sent),
+                last_sent_ago: now.saturating_duration_since(o.last_sent),
+                retry_in: o.retry_at.map(|at| at.saturating_duration_since(now)),
+            })
+            .collect()
+    }
+
+    /// Take in frames from a snapshot, leaving out those that would have
+    /// been given up on by `rebase.now` had the tracker kept running.
+    pub(crate) fn import(
+        &mut self,
+        pending: Vec<PendingAck>,
+        rebase: Rebase,
+    ) -> Result<(), DecodeError> {
+        for p in pending {
+            let frame = FrameV1::decode(&p.frame)?;
+            let o = Outstanding {
+                policy: self.policy_for(frame.header.msg_type),
+                frame,
+                attempts: p.attempts,
+                first_sent: rebase.ago(p.first_sent_ago),
+                last_sent: rebase.ago(p.last_sent_ago),
+                retry_at: p.retry_in.map(|d| rebase.after(d)),
+            };
+            let key = (o.frame.header.device_id, o.frame.header.counter);
+            if self.given_up(&o, rebase.now)
+                || (!self.outstanding.contains_key(&key)
+                    && self.outstanding.len() >= self.config.max_outstanding)
+            {
+                continue;
+            }
+            self.timers.insert(key, o.deadline(self.config.timeout));
+            self.outstanding.insert(key, o);
+        }
+        Ok(())
+    }
+
+    /// Whether polls up to `now` would have given up on `o`.
+    fn given_up(&self, o: &Outstanding, now: Instant) -> bool {
+        let (mut attempts, mut last, mut next) = (o.attempts, o.last_sent, o.retry_at);
+        while let Some(at) = next {
+            if at > now {
+                return false;
+            }
+            attempts += 1;
+            last = at;
+            next = o.policy.next_delay(attempts).map(|d| at + d);
+        }
+        last + self.config.timeout <= now
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CumulativeAckConfig {
+    /// Ack once this many frames from a device await one.
+    pub every_frames: u32,
+    /// Ack no later than this after the first frame awaiting one.
+    pub max_delay: Duration,
+    /// Send [`AckBody::Selective`] acks while frames past the cumulative
+    /// point have arrived.
+    pub selective: bool,
+}
+
+impl Default for CumulativeAckConfig {
+    fn default() -> Self {
+        CumulativeAckConfig {
+            every_frames: 16,
+            max_delay: Duration::from_millis(200),
+            selective: false,
+        }
+    }
+}
+
+/// Counters further than this past a device's cumulative point are not
+/// remembered; the sender will resend them.
+const MAX_HELD: u64 = 1024;
+
+#[derive(Default)]
+struct Progress {
+    /// Every counter up to this one has arrived.
+    point: u64,
+    /// Arrived counters above `point + 1`.
+    held: BTreeSet<u64>,
+    unacked: u32,
+    since: Option<Instant>,
+}
+
+/// Receiver side of cumulative and selective acks: tracks, per device,
+/// the highest counter below which nothing is missing, and says when to
+/// ack it. In selective mode the ack also reports what arrived in the
+/// first [`MAX_SACK_BYTES`] * 8 counters past that point.
+///
+/// Counting starts at 0, so this suits senders that number each device's
+/// frames 1, 2, 3, ... as [`CounterMap`](crate::sender::CounterMap) does.
+/// A frame at or below the point, such as a retransmission whose ack was
+/// lost, leaves the point alone but still earns an ack.
+pub struct CumulativeAcker {
+    clock: Arc<dyn Clock>,
+    config: CumulativeAckConfig,
+    devices: HashMap<[u8; 8], Progress>,
+}
+
+impl CumulativeAcker {
+    pub fn new(config: CumulativeAckConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: CumulativeAckConfig, clock: Arc<dyn Clock>) -> Self {
+        CumulativeAcker {
+            clock,
+            config,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Record a received frame that wants an ack, returning the ack to
+    /// send if `every_frames` are now waiting.
+    pub fn on_frame(&mut self, header: &FrameHeaderV1) -> Option<FrameV1> {
+        let now = self.clock.now();
+        let p = self.devices.entry(header.device_id).or_default();
+        let counter = header.counter;
+        if counter > p.point {
+            if counter == p.point + 1 {
+                p.point = counter;
+                while p.held.remove(&(p.point + 1)) {
+                    p.point += 1;
+                }
+            } else if counter - p.point <= MAX_HELD {
+                p.held.insert(counter);
+            }
+        }
+        p.unacked += 1;
+        p.since.get_or_insert(now);
+        if p.unacked >= self.config.every_frames {
+            return take_ack(header.device_id, p, self.config.selective);
+        }
+        None
+    }
+
+    /// Acks for devices whose oldest waiting frame is `max_delay` old.
+    pub fn poll(&mut self, now: Instant) -> Vec<FrameV1> {
+        let delay = self.config.max_delay;
+        let selective = self.config.selective;
+        let mut acks: Vec<FrameV1> = self
+            .devices
+            .iter_mut()
+            .filter(|(_, p)| p.since.is_some_and(|t| now >= t + delay))
+            .filter_map(|(&d, p)| take_ack(d, p, selective))
+            .collect();
+        acks.sort_by_key(|a| a.header.device_id);
+        acks
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.devices
+            .values()
+            .filter_map(|p| p.since)
+            .min()
+            .map(|t| t + self.config.max_delay)
+    }
+
+    /// The device's cumulative point, if any of its frames arrived.
+    pub fn point(&self, device_id: [u8; 8]) -> Option<u64> {
+        self.devices.get(&device_id).map(|p| p.point)
+    }
+}
+
+/// Nothing is sent while nothing has arrived that an ack could cover.
+fn take_ack(device_id: [u8; 8], p: &mut Progress, selective: bool) -> Option<FrameV1> {
+    p.unacked = 0;
+    p.since = None;
+    if selective && !p.held.is_empty() {
+        let mut bitmap = vec![0u8; MAX_SACK_BYTES];
+        for bit in p.held.iter().map(|&c| c - p.point - 1) {
+            if let Some(byte) = bitmap.get_mut((bit / 8) as usize) {
+                *byte |= 1 << (bit % 8);
+            }
+        }
+        let used = bitmap.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        bitmap.truncate(used);
+        return Some(selective_ack(device_id, p.point, bitmap));
+    }
+    (p.point > 0).then(|| cumulative_ack(device_id, p.point))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::transport::tests::frame;
+    use crate::transport::{Fault, FaultyTransport, Transport, loopback_pair};
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn tracker() -> (AckTracker, ManualClock) {
+        let clock = ManualClock::new();
+        let config = AckConfig {
+            timeout: 100 * MS,
+            max_attempts: 3,
+            max_outstanding: 2,
+            ..AckConfig::default()
+        };
+        (AckTracker::new(config, Arc::new(clock.clone())), clock)
+    }
+
+    fn needs_ack(counter: u64) -> FrameV1 {
+        let mut f = frame(counter);
+        f.header.flags = Flags::new(Flags::ACK_REQUIRED).unwrap();
+        f
+    }
+
+    fn ack_for(f: &FrameV1) -> FrameV1 {
+        let mut ack = f.clone();
+        ack.header.msg_type = MsgType::Ack;
+        ack.header.flags = Flags::new(0).unwrap();
+        ack.body.clear();
+        ack
+    }
+
+    #[test]
+    fn ack_before_timeout() {
+        let (mut t, clock) = tracker();
+        let f = needs_ack(1);
+        t.register(&f).unwrap();
+        clock.advance(40 * MS);
+        assert_eq!(t.on_ack(&f), [], "not an ack");
+
+        let done = t.on_ack(&ack_for(&f));
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].attempts, 1);
+        assert!(t.is_empty());
+        assert_eq!(t.poll(clock.now() + 200 * MS), Due::default());
+    }
+
+    #[test]
+    fn acked_and_expired_frames_release_their_slots() {
+        use crate::window::{Priority, WindowConfig};
+        let window = SendWindow::new(WindowConfig {
+            size: 2,
+            reserved: 0,
+            ..WindowConfig::default()
+        });
+        let (t, clock) = tracker();
+        let mut t = t.with_window(window.clone());
+        let dev = *b"DEV00001";
+        for counter in [1, 2] {
+            window.try_acquire(dev, Priority::Normal).unwrap();
+            t.register(&needs_ack(counter)).unwrap();
+        }
+        t.on_ack(&ack_for(&needs_ack(1)));
+        assert_eq!(window.in_flight(dev), 1);
+        for _ in 0..3 {
+            clock.advance(100 * MS);
+            t.poll(clock.now());
+        }
+        assert!(t.is_empty());
+        assert_eq!(window.in_flight(dev), 0);
+    }
+
+    #[test]
+    fn retransmit_then_ack() {
+        let (mut t, clock) = tracker();
+        let f = needs_ack(1);
+        t.register(&f).unwrap();
+        let first = clock.now();
+        assert_eq!(t.next_deadline(), Some(first + 100 * MS));
+
+        clock.advance(99 * MS);
+        assert!(t.poll(clock.now()).retransmit.is_empty());
+        clock.advance(MS);
+        assert_eq!(t.poll(clock.now()).retransmit, vec![f.clone()]);
+
+        let state = t.state(*b"DEV00001", 1).unwrap();
+        assert_eq!(state.attempts, 2);
+        assert_eq!(state.first_sent, first);
+        assert_eq!(state.last_sent, clock.now());
+
+        assert_eq!(t.on_ack(&ack_for(&f))[0].attempts, 2);
+        assert_eq!(t.next_deadline(), None);
+    }
+
+    #[test]
+    fn resend_all_counts_as_a_send_of_each() {
+        let (mut t, clock) = tracker();
+        t.register(&needs_ack(2)).unwrap();
+        t.register(&needs_ack(1)).unwrap();
+        clock.advance(100 * MS);
+        assert_eq!(t.poll(clock.now()).retransmit.len(), 2);
+
+        clock.advance(30 * MS);
+        let frames = t.resend_all(clock.now());
+        let counters: Vec<_> = frames.iter().map(|f| f.header.counter).collect();
+        assert_eq!(counters, [1, 2]);
+        assert_eq!(t.state(*b"DEV00001", 1).unwrap().attempts, 3);
+        assert_eq!(t.next_deadline(), Some(clock.now() + 100 * MS));
+
+        // the third send was the last; what remains is giving up
+        clock.advance(100 * MS);
+        assert_eq!(t.poll(clock.now()).expired.len(), 2);
+    }
+
+    #[test]
+    fn expires_after_max_attempts_and_bounds_memory() {
+        let (mut t, clock) = tracker();
+        t.register(&needs_ack(1)).unwrap();
+        assert_eq!(t.register(&needs_ack(1)), Err(AckError::Duplicate));
+        t.register(&needs_ack(2)).unwrap();
+        assert_eq!(t.register(&needs_ack(3)), Err(AckError::Full));
+
+        for _ in 0..2 {
+            clock.advance(100 * MS);
+            assert_eq!(t.poll(clock.now()).retransmit.len(), 2);
+        }
+        clock.advance(100 * MS);
+        let due = t.poll(clock.now());
+        assert!(due.retransmit.is_empty());
+        let counters: Vec<_> = due.expired.iter().map(|o| o.frame.header.counter).collect();
+        assert_eq!(counters, [1, 2]);
+        assert!(due.expired.iter().all(|o| o.attempts == 3));
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn policies_per_type_and_per_frame() {
+        use crate::retry::NoRetry;
+        let (t, clock) = tracker();
+        let mut t = t.with_policy_for(MsgType::Command, NoRetry);
+        let mut command = needs_ack(1);
+        command.header.msg_type = MsgType::Command;
+        t.register(&command).unwrap();
+        let quick = FixedInterval {
+            interval: 10 * MS,
+            max_attempts: 2,
+        };
+        t.register_with(&needs_ack(2), Arc::new(quick)).unwrap();
+        let state = t.state(*b"DEV00001", 2).unwrap();
+        assert_eq!(state.retry_at, Some(clock.now() + 10 * MS));
+        assert!(format!("{:?}", state.policy).contains("interval: 10ms"));
+        assert_eq!(t.state(*b"DEV00001", 1).unwrap().retry_at, None);
+
+        clock.advance(10 * MS);
+        assert_eq!(t.poll(clock.now()).retransmit, [needs_ack(2)]);
+        clock.advance(90 * MS);
+        let due = t.poll(clock.now());
+        assert_eq!(due.expired.len(), 1, "the command, never resent");
+        clock.advance(10 * MS);
+        assert_eq!(t.poll(clock.now()).expired[0].attempts, 2);
+    }
+
+    #[test]
+    fn ack_body_vectors() {
+        assert_eq!(AckBody::decode(&[]), Ok(AckBody::Single));
+        let body = AckBody::Cumulative { up_to: 0x0102 }.encode();
+        assert_eq!(body, [0x01, 0, 0, 0, 0, 0, 0, 0x01, 0x02]);
+        assert_eq!(
+            AckBody::decode(&body),
+            Ok(AckBody::Cumulative { up_to: 0x0102 })
+        );
+        assert_eq!(
+            AckBody::decode(&body[..5]),
+            Err(AckBodyError::BadLength { kind: 1, len: 5 })
+        );
+        assert_eq!(
+            AckBody::decode(&[0x7f]),
+            Err(AckBodyError::UnknownKind(0x7f))
+        );
+
+        let nack = AckBody::Nack {
+            reason: "busy".into(),
+        };
+        assert_eq!(nack.encode(), b"\x03busy");
+        assert_eq!(AckBody::decode(b"\x03busy"), Ok(nack));
+        assert_eq!(
+            AckBody::decode(&[0x04, 9, 9]),
+            Ok(AckBody::Reply(vec![9, 9]))
+        );
+    }
+
+    #[test]
+    fn cumulative_ack_resolves_everything_up_to_it() {
+        let (mut t, _clock) = tracker();
+        t.config.max_outstanding = 16;
+        for c in 1..=5 {
+            t.register(&needs_ack(c)).unwrap();
+        }
+        let mut other = needs_ack(2);
+        other.header.device_id = *b"DEV00002";
+        t.register(&other).unwrap();
+
+        let done = t.on_ack(&cumulative_ack(*b"DEV00001", 3));
+        let counters: Vec<_> = done.iter().map(|o| o.frame.header.counter).collect();
+        assert_eq!(counters, [1, 2, 3]);
+        assert_eq!(t.len(), 3);
+        assert_eq!(t.on_ack(&cumulative_ack(*b"DEV00001", 2)), []);
+        assert_eq!(t.len(), 3);
+    }
+
+    #[test]
+    fn acker_holds_the_point_at_a_hole() {
+        let clock = ManualClock::new();
+        let config = CumulativeAckConfig {
+            every_frames: 3,
+            max_delay: 50 * MS,
+            selective: false,
+        };
+        let mut r = CumulativeAcker::with_clock(config, Arc::new(clock.clone()));
+        assert_eq!(r.on_frame(&frame(1).header), None);
+        assert_eq!(r.on_frame(&frame(3).header), None);
+        assert_eq!(
+            r.on_frame(&frame(4).header),
+            Some(cumulative_ack(*b"DEV00001", 1))
+        );
+
+        assert_eq!(r.on_frame(&frame(2).header), None);
+        assert_eq!(r.point(*b"DEV00001"), Some(4));
+        assert_eq!(r.next_deadline(), Some(clock.now() + 50 * MS));
+        clock.advance(49 * MS);
+        assert_eq!(r.poll(clock.now()), []);
+        clock.advance(MS);
+        assert_eq!(r.poll(clock.now()), [cumulative_ack(*b"DEV00001", 4)]);
+        assert_eq!(r.next_deadline(), None);
+
+        // a retransmission from below the point is acked at the point
+        r.on_frame(&frame(2).header);
+        assert_eq!(r.point(*b"DEV00001"), Some(4));
+        clock.advance(50 * MS);
+        assert_eq!(r.poll(clock.now()), [cumulative_ack(*b"DEV00001", 4)]);
+    }
+
+    #[tokio::test]
+    async fn cumulative_acks_over_a_lossy_link() {
+        let clock = ManualClock::new();
+        let (a, mut b) = loopback_pair();
+        let mut lost = false;
+        let mut a = FaultyTransport::new(a, move |f| {
+            if f.header.counter == 3 && !lost {
+                lost = true;
+                Fault::Drop
+            } else {
+                Fault::Deliver
+            }
+        });
+        let config = AckConfig {
+            timeout: 100 * MS,
+            max_attempts: 3,
+            max_outstanding: 16,
+            ..AckConfig::default()
+        };
+        let mut sender = AckTracker::new(config, Arc::new(clock.clone()));
+        let acker_config = CumulativeAckConfig {
+            every_frames: 4,
+            max_delay: 50 * MS,
+            selective: false,
+        };
+        let mut receiver = CumulativeAcker::with_clock(acker_config, Arc::new(clock.clone()));
+
+        for c in 1..=8 {
+            let f = needs_ack(c);
+            a.send(&f).await.unwrap();
+            sender.register(&f).unwrap();
+        }
+        let mut acks = Vec::new();
+        for _ in 0..7 {
+            acks.extend(receiver.on_frame(&b.recv().await.unwrap().header));
+        }
+        assert_eq!(acks, [cumulative_ack(*b"DEV00001", 2)]);
+        clock.advance(50 * MS);
+        acks.extend(receiver.poll(clock.now()));
+        assert_eq!(acks.len(), 2);
+        assert_eq!(acks[1], cumulative_ack(*b"DEV00001", 2));
+        for ack in &acks {
+            b.send(ack).await.unwrap();
+            sender.on_ack(&a.recv().await.unwrap());
+        }
+        assert_eq!(sender.len(), 6);
+
+        // everything after the hole is resent; the old copies arriving
+        // after the point has moved past them never pull it back
+        clock.advance(50 * MS);
+        let due = sender.poll(clock.now());
+        let resent: Vec<_> = due.retransmit.iter().map(|f| f.header.counter).collect();
+        assert_eq!(resent, [3, 4, 5, 6, 7, 8]);
+        let mut acks = Vec::new();
+        for f in &due.retransmit {
+            a.send(f).await.unwrap();
+            acks.extend(receiver.on_frame(&b.recv().await.unwrap().header));
+            assert_eq!(receiver.point(*b"DEV00001"), Some(8));
+        }
+        assert_eq!(acks, [cumulative_ack(*b"DEV00001", 8)]);
+        b.send(&acks[0]).await.unwrap();
+        let done = sender.on_ack(&a.recv().await.unwrap());
+        assert_eq!(done.len(), 6);
+        assert!(sender.is_empty());
+    }
+
+    #[test]
+    fn selective_ack_vectors() {
+        let body = AckBody::Selective {
+            base: 2,
+            bitmap: vec![0b0000_0101, 0x80],
+        };
+        let bytes = body.encode();
+        assert_eq!(bytes, [0x02, 0, 0, 0, 0, 0, 0, 0, 2, 0x05, 0x80]);
+        assert_eq!(AckBody::decode(&bytes), Ok(body.clone()));
+        let covered: Vec<u64> = (0..20).filter(|&c| body.covers(2, c)).collect();
+        assert_eq!(covered, [0, 1, 2, 3, 5, 18]);
+
+        let mut too_long = vec![0x02; 9 + MAX_SACK_BYTES + 1];
+        assert_eq!(
+            AckBody::decode(&too_long),
+            Err(AckBodyError::BadLength { kind: 2, len: 42 })
+        );
+        too_long.truncate(9 + MAX_SACK_BYTES);
+        assert!(AckBody::decode(&too_long).is_ok());
+    }
+
+    #[test]
+    fn acker_reports_what_arrived_past_the_hole() {
+        let config = CumulativeAckConfig {
+            every_frames: 4,
+            max_delay: 50 * MS,
+            selective: true,
+        };
+        let mut r = CumulativeAcker::new(config);
+        for c in [1, 3, 4, 300] {
+            r.on_frame(&frame(c).header);
+        }
+        // 300 is beyond the bitmap; it will simply be resent
+        for c in [11, 12, 13] {
+            r.on_frame(&frame(c).header);
+        }
+        let ack = r.on_frame(&frame(2).header).unwrap();
+        assert_eq!(
+            ack,
+            selective_ack(*b"DEV00001", 4, vec![0b1100_0000, 0b0000_0001])
+        );
+    }
+
+    /// Sends 16 frames over a link that loses 3 and 9 once, and counts
+    /// retransmissions until everything is acked.
+    async fn retransmissions(selective: bool) -> usize {
+        let clock = ManualClock::new();
+        let (a, mut b) = loopback_pair();
+        let delivered = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut lost = vec![3, 9];
+        let count = delivered.clone();
+        let mut a = FaultyTransport::new(a, move |f| {
+            if let Some(i) = lost.iter().position(|&c| c == f.header.counter) {
+                lost.remove(i);
+                return Fault::Drop;
+            }
+            count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Fault::Deliver
+        });
+        let config = AckConfig {
+            timeout: 100 * MS,
+            max_attempts: 3,
+            max_outstanding: 64,
+            ..AckConfig::default()
+        };
+        let mut sender = AckTracker::new(config, Arc::new(clock.clone()));
+        let acker_config = CumulativeAckConfig {
+            every_frames: 4,
+            max_delay: 50 * MS,
+            selective,
+        };
+        let mut receiver = CumulativeAcker::with_clock(acker_config, Arc::new(clock.clone()));
+
+        for c in 1..=16 {
+            let f = needs_ack(c);
+            a.send(&f).await.unwrap();
+            sender.register(&f).unwrap();
+        }
+        let mut received = 0;
+        let mut resent = 0;
+        loop {
+            let mut acks = Vec::new();
+            while received < delivered.load(std::sync::atomic::Ordering::SeqCst) {
+                acks.extend(receiver.on_frame(&b.recv().await.unwrap().header));
+                received += 1;
+            }
+            clock.advance(50 * MS);
+            acks.extend(receiver.poll(clock.now()));
+            for ack in &acks {
+                b.send(ack).await.unwrap();
+                sender.on_ack(&a.recv().await.unwrap());
+            }
+            if sender.is_empty() {
+                return resent;
+            }
+            let due = sender.poll(clock.now());
+            assert_eq!(due.expired, []);
+            resent += due.retransmit.len();
+            for f in &due.retransmit {
+                a.send(f).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn selective_acks_resend_only_the_holes() {
+        assert_eq!(retransmissions(false).await, 14);
+        assert_eq!(retransmissions(true).await, 2);
+    }
+}
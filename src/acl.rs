@@ -0,0 +1,336 @@
+//! Who may send what to which device.
+//!
+//! An [`Authorizer`] decides on an [`Operation`]: a principal (a tenant's
+//! backend, say) sending a frame of some type to a device, with a body
+//! that may start with a command id. [`FrameSender::with_authorizer`]
+//! refuses denied sends with [`SendError::Unauthorized`], and
+//! [`Dispatcher::with_authorizer`] answers denied inbound COMMANDs with a
+//! NACK [`UNAUTHORIZED`] without running their handler.
+//!
+//! [`AclTable`] is an allow-list per principal; [`CachedAuthorizer`]
+//! remembers the decisions of a slower one. With the `metrics` feature,
+//! denials are counted per principal.
+//!
+//! [`FrameSender::with_authorizer`]: crate::sender::FrameSender::with_authorizer
+//! [`SendError::Unauthorized`]: crate::sender::SendError::Unauthorized
+//! [`Dispatcher::with_authorizer`]: crate::dispatch::Dispatcher::with_authorizer
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::dispatch::DeviceMatch;
+use crate::{FrameV1, MsgType};
+
+/// The NACK reason for a denied COMMAND.
+pub const UNAUTHORIZED: &str = "Unauthorized";
+
+/// An [`AclTable`] principal whose rules apply to every principal.
+pub const ANY_PRINCIPAL: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Operation<'a> {
+    pub principal: &'a str,
+    pub device_id: [u8; 8],
+    pub msg_type: MsgType,
+    pub body: &'a [u8],
+}
+
+impl<'a> Operation<'a> {
+    pub fn of(principal: &'a str, frame: &'a FrameV1) -> Self {
+        Operation {
+            principal,
+            device_id: frame.header.device_id,
+            msg_type: frame.header.msg_type,
+            body: &frame.body,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+pub trait Authorizer: Send + Sync {
+    fn authorize(&self, operation: &Operation<'_>) -> Decision;
+}
+
+/// A denied operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unauthorized {
+    pub principal: String,
+    pub device_id: [u8; 8],
+    pub msg_type: MsgType,
+}
+
+impl fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} may not send {} to {}",
+            self.principal,
+            self.msg_type.as_str(),
+            crate::hex::encode(&self.device_id)
+        )
+    }
+}
+
+impl std::error::Error for Unauthorized {}
+
+/// Ask `authorizer`, counting a denial.
+pub(crate) fn check(
+    authorizer: &dyn Authorizer,
+    operation: &Operation<'_>,
+) -> Result<(), Unauthorized> {
+    match authorizer.authorize(operation) {
+        Decision::Allow => Ok(()),
+        Decision::Deny => {
+            #[cfg(feature = "metrics")]
+            crate::metrics::global().authz_denied(operation.principal);
+            Err(Unauthorized {
+                principal: operation.principal.to_string(),
+                device_id: operation.device_id,
+                msg_type: operation.msg_type,
+            })
+        }
+    }
+}
+
+/// What an [`AclTable`] entry allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    msg_type: Option<MsgType>,
+    device: DeviceMatch,
+    body_prefix: Vec<u8>,
+}
+
+impl Rule {
+    /// Anything to any device.
+    pub fn any() -> Self {
+        Rule {
+            msg_type: None,
+            device: DeviceMatch::Any,
+            body_prefix: Vec::new(),
+        }
+    }
+
+    /// Frames of `msg_type` to any device.
+    pub fn new(msg_type: MsgType) -> Self {
+        Rule {
+            msg_type: Some(msg_type),
+            ..Rule::any()
+        }
+    }
+
+    pub fn with_device(mut self, device_id: [u8; 8]) -> Self {
+        self.device = DeviceMatch::Exact(device_id);
+        self
+    }
+
+    pub fn with_device_prefix(mut self, prefix: &[u8]) -> Self {
+        self.device = DeviceMatch::Prefix(prefix.to_vec());
+        self
+    }
+
+    pub fn with_body_prefix(mut self, prefix: &[u8]) -> Self {
+        self.body_prefix = prefix.to_vec();
+        self
+    }
+
+    fn matches(&self, operation: &Operation<'_>) -> bool {
+        self.msg_type.is_none_or(|t| t == operation.msg_type)
+            && self.device.matches(&operation.device_id)
+            && operation.body.starts_with(&self.body_prefix)
+    }
+}
+
+/// Allows an operation if a rule of its principal, or of
+/// [`ANY_PRINCIPAL`], matches it; denies everything else.
+#[derive(Debug, Clone, Default)]
+pub struct AclTable {
+    rules: HashMap<String, Vec<Rule>>,
+}
+
+impl AclTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(mut self, principal: impl Into<String>, rule: Rule) -> Self {
+        self.rules.entry(principal.into()).or_default().push(rule);
+        self
+    }
+}
+
+impl Authorizer for AclTable {
+    fn authorize(&self, operation: &Operation<'_>) -> Decision {
+        let allowed = [operation.principal, ANY_PRINCIPAL]
+            .iter()
+            .filter_map(|p| self.rules.get(*p))
+            .flatten()
+            .any(|r| r.matches(operation));
+        if allowed {
+            Decision::Allow
+        } else {
+            Decision::Deny
+        }
+    }
+}
+
+type CacheKey = (String, [u8; 8], MsgType, Vec<u8>);
+
+/// Remembers the decisions of `inner`, which must depend on no more than
+/// the first `key_len` bytes of the body. The cache is emptied when it
+/// reaches `capacity`, or by [`CachedAuthorizer::clear`] when the policy
+/// changes.
+pub struct CachedAuthorizer<A> {
+    inner: A,
+    key_len: usize,
+    capacity: usize,
+    cache: Mutex<HashMap<CacheKey, Decision>>,
+}
+
+impl<A: Authorizer> CachedAuthorizer<A> {
+    pub fn new(inner: A, key_len: usize, capacity: usize) -> Self {
+        CachedAuthorizer {
+            inner,
+            key_len,
+            capacity: capacity.max(1),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn clear(&self) {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<A: Authorizer> Authorizer for CachedAuthorizer<A> {
+    fn authorize(&self, operation: &Operation<'_>) -> Decision {
+        let body = &operation.body[..operation.body.len().min(self.key_len)];
+        let key = (
+            operation.principal.to_string(),
+            operation.device_id,
+            operation.msg_type,
+            body.to_vec(),
+        );
+        if let Some(&d) = self
+            .cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key)
+        {
+            return d;
+        }
+        let decision = self.inner.authorize(operation);
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if cache.len() >= self.capacity {
+            cache.clear();
+        }
+        cache.insert(key, decision);
+        decision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn op<'a>(principal: &'a str, device_id: &[u8; 8], body: &'a [u8]) -> Operation<'a> {
+        Operation {
+            principal,
+            device_id: *device_id,
+            msg_type: MsgType::Command,
+            body,
+        }
+    }
+
+    #[test]
+    fn table_allow_lists_with_wildcards() {
+        let acl = AclTable::new()
+            .allow(
+                "tenant-a",
+                Rule::new(MsgType::Command).with_device_prefix(b"A"),
+            )
+            .allow("tenant-b", Rule::any().with_device(*b"B0000001"))
+            .allow(
+                ANY_PRINCIPAL,
+                Rule::new(MsgType::Command).with_body_prefix(&[0x01]),
+            );
+
+        assert_eq!(
+            acl.authorize(&op("tenant-a", b"A0000042", &[0x09])),
+            Decision::Allow
+        );
+        assert_eq!(
+            acl.authorize(&op("tenant-a", b"B0000001", &[0x09])),
+            Decision::Deny
+        );
+        assert_eq!(
+            acl.authorize(&op("tenant-b", b"B0000001", &[0x09])),
+            Decision::Allow
+        );
+        assert_eq!(
+            acl.authorize(&op("tenant-b", b"B0000002", &[0x09])),
+            Decision::Deny
+        );
+        // command 0x01 is open to everyone
+        assert_eq!(
+            acl.authorize(&op("tenant-b", b"A0000042", &[0x01])),
+            Decision::Allow
+        );
+        assert_eq!(
+            acl.authorize(&op("nobody", b"A0000042", &[0x01, 7])),
+            Decision::Allow
+        );
+        assert_eq!(
+            acl.authorize(&op("nobody", b"A0000042", &[])),
+            Decision::Deny
+        );
+
+        let err = check(&acl, &op("tenant-a", b"B0000001", &[])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "tenant-a may not send command to 4230303030303031"
+        );
+    }
+
+    #[test]
+    fn cache_keys_on_the_command_id() {
+        struct Counting(Arc<AtomicUsize>);
+        impl Authorizer for Counting {
+            fn authorize(&self, operation: &Operation<'_>) -> Decision {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                if operation.body.first() == Some(&0x01) {
+                    Decision::Allow
+                } else {
+                    Decision::Deny
+                }
+            }
+        }
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cached = CachedAuthorizer::new(Counting(calls.clone()), 1, 2);
+        let dev = b"DEV00001";
+        assert_eq!(cached.authorize(&op("p", dev, &[0x01, 1])), Decision::Allow);
+        assert_eq!(cached.authorize(&op("p", dev, &[0x01, 2])), Decision::Allow);
+        assert_eq!(cached.authorize(&op("p", dev, &[0x02])), Decision::Deny);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        // full: starts over
+        assert_eq!(cached.authorize(&op("q", dev, &[0x01])), Decision::Allow);
+        assert_eq!(cached.len(), 1);
+        cached.clear();
+        assert!(cached.is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+//! Which bytes of a frame are which field.
+//!
+//! [`annotate`] splits raw bytes into the v1 header fields and the body,
+//! as [`FrameV1::decode`](crate::FrameV1::decode) reads them, but carries on
+//! past a field that is wrong: each span says whether its bytes would pass.
+//! Input that ends inside the header gets the field it ends in, cut short,
+//! or the first field missing entirely as an empty span, both marked bad.
+
+use std::ops::Range;
+
+use crate::{Flags, HEADER_LEN_V1, MAGIC, MsgType, VERSION_V1};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSpan {
+    /// Offsets in the input; empty for a field the input ends before.
+    pub range: Range<usize>,
+    pub name: &'static str,
+    /// What the bytes read as, or what is wrong with them.
+    pub value: String,
+    pub ok: bool,
+}
+
+/// Header fields, by offset.
+pub(crate) const FIELDS: [(&str, Range<usize>); 6] = [
+    ("magic", 0..2),
+    ("version", 2..3),
+    ("type", 3..4),
+    ("flags", 4..5),
+    ("device_id", 5..13),
+    ("counter", 13..21),
+];
+
+fn read(name: &str, bytes: &[u8]) -> (String, bool) {
+    match name {
+        "magic" if bytes == MAGIC => (String::from_utf8_lossy(bytes).into_owned(), true),
+        "magic" => (format!("not {:?}", String::from_utf8_lossy(&MAGIC)), false),
+        "version" => (bytes[0].to_string(), bytes[0] == VERSION_V1),
+        "type" => match MsgType::from_u8(bytes[0]) {
+            Some(t) => (t.as_str().to_string(), true),
+            None => (format!("unknown 0x{:02x}", bytes[0]), false),
+        },
+        "flags" => match Flags::new(bytes[0]) {
+            Ok(f) if f.ack_required() => ("ACK_REQUIRED".to_string(), true),
+            Ok(_) => ("none".to_string(), true),
+            Err(_) => (format!("reserved bits set: 0b{:08b}", bytes[0]), false),
+        },
+        "device_id" => (crate::hex::encode(bytes), true),
+        _ => (
+            u64::from_be_bytes(bytes.try_into().unwrap()).to_string(),
+            true,
+        ),
+    }
+}
+
+/// The fields `input` holds, in order, as far as it goes. A frame decodes
+/// exactly when every span is ok.
+pub fn annotate(input: &[u8]) -> Vec<FieldSpan> {
+    let mut spans = Vec::new();
+    for (name, range) in FIELDS {
+        if input.len() < range.end {
+            let range = range.start.min(input.len())..input.len();
+            let value = if range.is_empty() {
+                "missing"
+            } else {
+                "truncated"
+            };
+            spans.push(FieldSpan {
+                range,
+                name,
+                value: value.to_string(),
+                ok: false,
+            });
+            return spans;
+        }
+        let (value, ok) = read(name, &input[range.clone()]);
+        spans.push(FieldSpan {
+            range,
+            name,
+            value,
+            ok,
+        });
+    }
+    spans.push(FieldSpan {
+        range: HEADER_LEN_V1..input.len(),
+        name: "body",
+        value: format!("{} bytes", input.len() - HEADER_LEN_V1),
+        ok: true,
+    });
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FrameV1;
+    use crate::sim::{SPLITMIX_GAMMA, splitmix64};
+
+    fn frame() -> Vec<u8> {
+        let mut bytes = b"PP\x01\x01\x01DEV00001".to_vec();
+        bytes.extend(7u64.to_be_bytes());
+        bytes.extend(b"hi");
+        bytes
+    }
+
+    #[test]
+    fn names_every_field() {
+        let spans = annotate(&frame());
+        let summary: Vec<_> = spans
+            .iter()
+            .map(|s| (s.name, s.range.clone(), s.value.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("magic", 0..2, "PP"),
+                ("version", 2..3, "1"),
+                ("type", 3..4, "event"),
+                ("flags", 4..5, "ACK_REQUIRED"),
+                ("device_id", 5..13, "4445563030303031"),
+                ("counter", 13..21, "7"),
+                ("body", 21..23, "2 bytes"),
+            ]
+        );
+        assert!(spans.iter().all(|s| s.ok));
+    }
+
+    #[test]
+    fn carries_on_past_bad_fields() {
+        let mut bytes = frame();
+        bytes[3] = 0x09;
+        bytes[4] = 0x80;
+        let spans = annotate(&bytes[..17]);
+        let bad: Vec<_> = spans.iter().filter(|s| !s.ok).map(|s| s.name).collect();
+        assert_eq!(bad, ["type", "flags", "counter"]);
+        assert_eq!(spans[2].value, "unknown 0x09");
+        assert_eq!(spans.last().unwrap().range, 13..17);
+        assert_eq!(spans.last().unwrap().value, "truncated");
+
+        let spans = annotate(&bytes[..5]);
+        assert_eq!(spans.last().unwrap().range, 5..5);
+        assert_eq!(spans.last().unwrap().name, "device_id");
+        assert_eq!(annotate(&[])[0].value, "missing");
+    }
+
+    /// Spans tile the input, and are all ok exactly when it decodes.
+    #[test]
+    fn agrees_with_decode() {
+        let valid = frame();
+        let mut state = 0;
+        for _ in 0..2000 {
+            state = SPLITMIX_GAMMA.wrapping_add(state);
+            let r = splitmix64(state);
+            let mut bytes = valid[..(r % 30) as usize % (valid.len() + 1)].to_vec();
+            if !bytes.is_empty() {
+                let at = (r >> 8) as usize % bytes.len();
+                bytes[at] = [0x00, 0x01, 0x02, 0x05, 0x50, 0xff][(r >> 16) as usize % 6];
+            }
+            let spans = annotate(&bytes);
+            let mut end = 0;
+            for s in &spans {
+                assert_eq!(s.range.start, end);
+                end = s.range.end;
+            }
+            assert_eq!(end, bytes.len());
+            let ok = spans.iter().all(|s| s.ok);
+            assert_eq!(ok, FrameV1::decode(&bytes).is_ok(), "{bytes:02x?}");
+        }
+    }
+}
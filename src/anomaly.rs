@@ -0,0 +1,240 @@
+//! Noticing counters that move in ways they should not.
+//!
+//! [`CounterAnomalies`] is a [`FrameObserver`] that follows the highest
+//! counter each device has sent and reports an [`AnomalyEvent`] when one
+//! arrives too far behind it (a cloned device id, or a replay) or too far
+//! ahead (many frames lost). It only reports: whether the frame is let in
+//! is for a [`ReplayWindow`](crate::replay::ReplayWindow) to decide.
+//!
+//! Only EVENTs and COMMANDs are followed; an ACK or ERROR carries the
+//! counter of the frame it answers. v1 has no session id, so a device's
+//! HELLO stands for one: a rebooted device says HELLO and may start
+//! counting again anywhere without an alert.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::counter::CounterSpace;
+use crate::observer::FrameObserver;
+use crate::session::Control;
+use crate::{FrameV1, MsgType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnomalyKind {
+    /// Further behind the highest counter than `max_backward`.
+    Regression,
+    /// Further ahead of it than `max_jump`.
+    Jump,
+}
+
+impl AnomalyKind {
+    /// Metric label for the kind.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AnomalyKind::Regression => "regression",
+            AnomalyKind::Jump => "jump",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnomalyEvent {
+    pub device_id: [u8; 8],
+    /// The highest counter seen before.
+    pub previous: u64,
+    pub observed: u64,
+    pub kind: AnomalyKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnomalyConfig {
+    /// Steps back allowed without an alert; match the replay window.
+    pub max_backward: u64,
+    /// Steps forward allowed without an alert.
+    pub max_jump: u64,
+    /// Devices followed at once; the least recently heard is forgotten.
+    pub max_devices: usize,
+    pub space: CounterSpace,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            max_backward: 64,
+            max_jump: 1000,
+            max_devices: 10_000,
+            space: CounterSpace::Linear,
+        }
+    }
+}
+
+struct Followed {
+    highest: u64,
+    /// When it was last heard, in frames observed.
+    heard: u64,
+}
+
+#[derive(Default)]
+struct Devices {
+    followed: HashMap<[u8; 8], Followed>,
+    frames: u64,
+}
+
+type OnAnomaly = Box<dyn Fn(&AnomalyEvent) + Send + Sync>;
+
+pub struct CounterAnomalies {
+    config: AnomalyConfig,
+    devices: Mutex<Devices>,
+    on_anomaly: OnAnomaly,
+}
+
+impl CounterAnomalies {
+    pub fn new(
+        config: AnomalyConfig,
+        on_anomaly: impl Fn(&AnomalyEvent) + Send + Sync + 'static,
+    ) -> Self {
+        CounterAnomalies {
+            config: AnomalyConfig {
+                max_devices: config.max_devices.max(1),
+                ..config
+            },
+            devices: Mutex::default(),
+            on_anomaly: Box::new(on_anomaly),
+        }
+    }
+
+    /// Follow `frame`'s counter, returning the anomaly it shows, if any.
+    /// [`FrameObserver::on_rx`] calls this and reports what it returns.
+    pub fn check(&self, frame: &FrameV1) -> Option<AnomalyEvent> {
+        if !matches!(frame.header.msg_type, MsgType::Event | MsgType::Command) {
+            return None;
+        }
+        let device_id = frame.header.device_id;
+        let observed = frame.header.counter;
+        let hello = matches!(Control::parse(frame), Some(Control::Hello { .. }));
+        let mut devices = self.devices.lock().unwrap_or_else(|e| e.into_inner());
+        devices.frames += 1;
+        let heard = devices.frames;
+        if !devices.followed.contains_key(&device_id)
+            && devices.followed.len() >= self.config.max_devices
+        {
+            let quietest = devices
+                .followed
+                .iter()
+                .min_by_key(|(_, f)| f.heard)
+                .map(|(&id, _)| id);
+            if let Some(id) = quietest {
+                devices.followed.remove(&id);
+            }
+        }
+        let Some(f) = devices.followed.get_mut(&device_id) else {
+            let highest = observed;
+            devices
+                .followed
+                .insert(device_id, Followed { highest, heard });
+            return None;
+        };
+        f.heard = heard;
+        let previous = f.highest;
+        if hello {
+            f.highest = observed;
+            return None;
+        }
+        let space = self.config.space;
+        let kind = if let Some(steps) = space.ahead(previous, observed) {
+            f.highest = observed;
+            (steps > self.config.max_jump).then_some(AnomalyKind::Jump)
+        } else {
+            space
+                .behind(previous, observed)
+                .filter(|&steps| steps > self.config.max_backward)
+                .map(|_| AnomalyKind::Regression)
+        };
+        kind.map(|kind| AnomalyEvent {
+            device_id,
+            previous,
+            observed,
+            kind,
+        })
+    }
+}
+
+impl FrameObserver for CounterAnomalies {
+    fn on_rx(&self, frame: &FrameV1) {
+        let Some(event) = self.check(frame) else {
+            return;
+        };
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().counter_anomaly(event.kind.as_str());
+        (self.on_anomaly)(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::frame;
+    use std::sync::Arc;
+
+    const DEV: [u8; 8] = *b"DEV00001";
+
+    fn watcher() -> (CounterAnomalies, Arc<Mutex<Vec<AnomalyEvent>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let config = AnomalyConfig {
+            max_backward: 4,
+            max_jump: 100,
+            ..AnomalyConfig::default()
+        };
+        let anomalies = CounterAnomalies::new(config, {
+            let events = events.clone();
+            move |e| events.lock().unwrap().push(e.clone())
+        });
+        (anomalies, events)
+    }
+
+    fn event(counter: u64, previous: u64, kind: AnomalyKind) -> AnomalyEvent {
+        AnomalyEvent {
+            device_id: DEV,
+            previous,
+            observed: counter,
+            kind,
+        }
+    }
+
+    #[test]
+    fn reports_regressions_and_jumps() {
+        let (a, events) = watcher();
+        for counter in [10, 11, 7, 6, 12, 112, 213, 110, 214] {
+            a.on_rx(&frame(counter));
+        }
+        let mut ack = frame(1);
+        ack.header.msg_type = MsgType::Ack;
+        a.on_rx(&ack);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                event(6, 11, AnomalyKind::Regression),
+                event(213, 112, AnomalyKind::Jump),
+                event(110, 213, AnomalyKind::Regression),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_hello_starts_counting_again() {
+        let (a, events) = watcher();
+        a.on_rx(&frame(5000));
+        let mut hello = frame(1);
+        hello.header.msg_type = MsgType::Command;
+        hello.body = Control::Hello { version: 1 }.encode().into();
+        a.on_rx(&hello);
+        a.on_rx(&frame(2));
+        assert_eq!(*events.lock().unwrap(), []);
+        a.on_rx(&frame(5001));
+        assert_eq!(
+            *events.lock().unwrap(),
+            [event(5001, 2, AnomalyKind::Jump)],
+            "the old session's counters are a jump now"
+        );
+    }
+}
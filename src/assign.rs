@@ -0,0 +1,740 @@
+//! Giving factory-fresh devices a device id.
+//!
+//! A device without an id sends ID-REQUEST, `00 53 0e <hardware id>`, as a
+//! COMMAND from [`UNASSIGNED`]. The gateway takes an id from its
+//! [`IdPool`], records it in an [`IdStore`] before answering, and replies
+//! with ID-ASSIGN, `00 53 0f <hardware id> <device id>`, which the device
+//! acks with ID-ACK, `00 53 10 <hardware id> <device id>`. A gateway whose
+//! pool is used up answers ID-DENIED, `00 53 11 <hardware id>`.
+//!
+//! Every device being assigned sends from the same id, so the messages are
+//! told apart by the 16-byte hardware id they carry: devices asking at once
+//! get different ids, and each ignores the answers meant for others. An id
+//! once issued is never issued to other hardware, even if it is never
+//! acked; the same hardware asking again gets the same id back.
+//!
+//! For the same reason a [`Dispatcher`](crate::dispatch::Dispatcher) does
+//! not deduplicate frames from [`UNASSIGNED`]: two devices asking with the
+//! same counter are not one frame sent twice.
+//!
+//! Neither role does I/O. The application sends what they return; the
+//! device's request is retransmitted until answered, and the gateway's
+//! assignment, by [`GatewayAssignment::poll`], until acked.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::session::CONTROL_PREFIX;
+use crate::{FrameV1, MsgType};
+
+/// The device id a device sends from until it is assigned one.
+pub const UNASSIGNED: [u8; 8] = [0; 8];
+
+const ID_REQUEST: u8 = 0x0e;
+const ID_ASSIGN: u8 = 0x0f;
+const ID_ACK: u8 = 0x10;
+const ID_DENIED: u8 = 0x11;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssignMessage {
+    Request {
+        hardware_id: [u8; 16],
+    },
+    Assign {
+        hardware_id: [u8; 16],
+        device_id: [u8; 8],
+    },
+    Ack {
+        hardware_id: [u8; 16],
+        device_id: [u8; 8],
+    },
+    Denied {
+        hardware_id: [u8; 16],
+    },
+}
+
+impl AssignMessage {
+    /// The assignment message `frame` carries, if it is one.
+    pub fn parse(frame: &FrameV1) -> Option<AssignMessage> {
+        if frame.header.msg_type != MsgType::Command {
+            return None;
+        }
+        let (&kind, rest) = frame.body.strip_prefix(&CONTROL_PREFIX)?.split_first()?;
+        let (hardware_id, rest) = rest.split_first_chunk::<16>()?;
+        let hardware_id = *hardware_id;
+        match (kind, rest.len()) {
+            (ID_REQUEST, 0) => Some(AssignMessage::Request { hardware_id }),
+            (ID_ASSIGN, 8) => Some(AssignMessage::Assign {
+                hardware_id,
+                device_id: rest.try_into().ok()?,
+            }),
+            (ID_ACK, 8) => Some(AssignMessage::Ack {
+                hardware_id,
+                device_id: rest.try_into().ok()?,
+            }),
+            (ID_DENIED, 0) => Some(AssignMessage::Denied { hardware_id }),
+            _ => None,
+        }
+    }
+
+    /// The COMMAND body carrying this message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = CONTROL_PREFIX.to_vec();
+        match self {
+            AssignMessage::Request { hardware_id } => {
+                body.push(ID_REQUEST);
+                body.extend(hardware_id);
+            }
+            AssignMessage::Assign {
+                hardware_id,
+                device_id,
+            } => {
+                body.push(ID_ASSIGN);
+                body.extend(hardware_id);
+                body.extend(device_id);
+            }
+            AssignMessage::Ack {
+                hardware_id,
+                device_id,
+            } => {
+                body.push(ID_ACK);
+                body.extend(hardware_id);
+                body.extend(device_id);
+            }
+            AssignMessage::Denied { hardware_id } => {
+                body.push(ID_DENIED);
+                body.extend(hardware_id);
+            }
+        }
+        body
+    }
+
+    pub fn hardware_id(&self) -> [u8; 16] {
+        match self {
+            AssignMessage::Request { hardware_id }
+            | AssignMessage::Assign { hardware_id, .. }
+            | AssignMessage::Ack { hardware_id, .. }
+            | AssignMessage::Denied { hardware_id } => *hardware_id,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AllocError {
+    /// Every id in the pool has been issued.
+    Exhausted,
+    /// The store could not record the id.
+    Store(io::Error),
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::Exhausted => write!(f, "device id pool exhausted"),
+            AllocError::Store(e) => write!(f, "recording device id: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+/// Remembers every id issued, so that none is issued twice.
+pub trait IdStore: Send + Sync {
+    /// The ids issued so far, by the hardware they went to.
+    fn load_all(&self) -> io::Result<BTreeMap<[u8; 16], [u8; 8]>>;
+
+    /// Record that `device_id` went to `hardware_id`; durable on return.
+    fn persist(&self, hardware_id: [u8; 16], device_id: [u8; 8]) -> io::Result<()>;
+}
+
+/// Issued ids in a text file, one `hardware_id_hex device_id_hex` line
+/// each, replaced atomically (temp file, fsync, rename) on every persist.
+pub struct FileIdStore {
+    path: PathBuf,
+    issued: Mutex<BTreeMap<[u8; 16], [u8; 8]>>,
+}
+
+impl FileIdStore {
+    /// Open `path`, which need not exist yet.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let issued = match fs::read_to_string(&path) {
+            Ok(text) => parse(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(FileIdStore {
+            path,
+            issued: Mutex::new(issued),
+        })
+    }
+
+    fn write(&self, issued: &BTreeMap<[u8; 16], [u8; 8]>) -> io::Result<()> {
+        let mut text = String::new();
+        for (hardware, device) in issued {
+            text.push_str(&format!(
+                "{} {}\n",
+                crate::hex::encode(hardware),
+                crate::hex::encode(device)
+            ));
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(text.as_bytes())?;
+        tmp.sync_all()?;
+        drop(tmp);
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+fn parse(text: &str) -> io::Result<BTreeMap<[u8; 16], [u8; 8]>> {
+    let bad = |line: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad device id line {line:?}"),
+        )
+    };
+    text.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|line| {
+            let (hardware, device) = line.split_once(' ').ok_or_else(|| bad(line))?;
+            let hardware = crate::hex::decode(hardware)
+                .and_then(|h| <[u8; 16]>::try_from(h).ok())
+                .ok_or_else(|| bad(line))?;
+            let device = crate::hex::decode(device.trim())
+                .and_then(|d| <[u8; 8]>::try_from(d).ok())
+                .ok_or_else(|| bad(line))?;
+            Ok((hardware, device))
+        })
+        .collect()
+}
+
+impl IdStore for FileIdStore {
+    fn load_all(&self) -> io::Result<BTreeMap<[u8; 16], [u8; 8]>> {
+        Ok(self
+            .issued
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone())
+    }
+
+    fn persist(&self, hardware_id: [u8; 16], device_id: [u8; 8]) -> io::Result<()> {
+        let mut issued = self.issued.lock().unwrap_or_else(|e| e.into_inner());
+        let old = issued.insert(hardware_id, device_id);
+        let written = self.write(&issued);
+        if written.is_err() {
+            match old {
+                Some(old) => issued.insert(hardware_id, old),
+                None => issued.remove(&hardware_id),
+            };
+        }
+        written
+    }
+}
+
+/// The ids a gateway hands out: big-endian `u64`s in a range. Never
+/// includes [`UNASSIGNED`] or the discovery broadcast id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdPool {
+    first: u64,
+    last: u64,
+}
+
+impl IdPool {
+    pub fn new(range: RangeInclusive<u64>) -> Self {
+        IdPool {
+            first: (*range.start()).max(1),
+            last: (*range.end()).min(u64::MAX - 1),
+        }
+    }
+
+    pub fn contains(&self, device_id: [u8; 8]) -> bool {
+        (self.first..=self.last).contains(&u64::from_be_bytes(device_id))
+    }
+}
+
+impl Default for IdPool {
+    fn default() -> Self {
+        IdPool::new(1..=u64::MAX - 1)
+    }
+}
+
+struct Issued {
+    by_hardware: HashMap<[u8; 16], [u8; 8]>,
+    ids: BTreeSet<u64>,
+    /// Every id in the pool below it has been issued.
+    next: u64,
+}
+
+/// Hands out ids from a pool, lowest first, and remembers them in a store.
+pub struct IdAllocator {
+    pool: IdPool,
+    store: Arc<dyn IdStore>,
+    issued: Mutex<Issued>,
+}
+
+impl IdAllocator {
+    /// An allocator skipping every id `store` has recorded.
+    pub fn new(pool: IdPool, store: Arc<dyn IdStore>) -> io::Result<Self> {
+        let loaded = store.load_all()?;
+        let issued = Issued {
+            ids: loaded.values().map(|d| u64::from_be_bytes(*d)).collect(),
+            by_hardware: loaded.into_iter().collect(),
+            next: pool.first,
+        };
+        Ok(IdAllocator {
+            pool,
+            store,
+            issued: Mutex::new(issued),
+        })
+    }
+
+    /// The id for `hardware_id`: the one it was issued before, or else a
+    /// new one, recorded before it is returned.
+    pub fn allocate(&self, hardware_id: [u8; 16]) -> Result<[u8; 8], AllocError> {
+        let mut issued = self.lock();
+        if let Some(&id) = issued.by_hardware.get(&hardware_id) {
+            return Ok(id);
+        }
+        let mut next = issued.next;
+        while issued.ids.contains(&next) && next < self.pool.last {
+            next += 1;
+        }
+        issued.next = next;
+        if next > self.pool.last || issued.ids.contains(&next) {
+            return Err(AllocError::Exhausted);
+        }
+        let id = next.to_be_bytes();
+        self.store
+            .persist(hardware_id, id)
+            .map_err(AllocError::Store)?;
+        issued.ids.insert(next);
+        issued.by_hardware.insert(hardware_id, id);
+        Ok(id)
+    }
+
+    /// The id issued to `hardware_id`, if any.
+    pub fn issued(&self, hardware_id: [u8; 16]) -> Option<[u8; 8]> {
+        self.lock().by_hardware.get(&hardware_id).copied()
+    }
+
+    /// Ids in the pool not yet issued.
+    pub fn remaining(&self) -> u64 {
+        let issued = self.lock();
+        let used = issued.ids.range(self.pool.first..=self.pool.last).count() as u64;
+        (self.pool.last - self.pool.first + 1).saturating_sub(used)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Issued> {
+        self.issued.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssignError {
+    /// The gateway has no ids left.
+    Denied,
+    TimedOut,
+}
+
+impl fmt::Display for AssignError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssignError::Denied => write!(f, "device id assignment denied"),
+            AssignError::TimedOut => write!(f, "device id assignment timed out"),
+        }
+    }
+}
+
+impl std::error::Error for AssignError {}
+
+#[derive(Debug, Clone)]
+pub struct AssignConfig {
+    /// How long a device asks before giving up.
+    pub device_timeout: Duration,
+    /// How long the gateway waits for an ack before resending ID-ASSIGN.
+    pub resend_after: Duration,
+    /// ID-ASSIGNs the gateway sends per request before it stops.
+    pub attempts: u32,
+}
+
+impl Default for AssignConfig {
+    fn default() -> Self {
+        AssignConfig {
+            device_timeout: Duration::from_secs(60),
+            resend_after: Duration::from_secs(1),
+            attempts: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    Idle,
+    Requested,
+    Assigned([u8; 8]),
+    Failed(AssignError),
+}
+
+/// The device's side of the exchange.
+pub struct DeviceAssignment {
+    clock: Arc<dyn Clock>,
+    timeout: Duration,
+    hardware_id: [u8; 16],
+    started: Option<Instant>,
+    state: DeviceState,
+}
+
+impl DeviceAssignment {
+    pub fn new(hardware_id: [u8; 16], config: &AssignConfig) -> Self {
+        Self::with_clock(hardware_id, config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(hardware_id: [u8; 16], config: &AssignConfig, clock: Arc<dyn Clock>) -> Self {
+        DeviceAssignment {
+            clock,
+            timeout: config.device_timeout,
+            hardware_id,
+            started: None,
+            state: DeviceState::Idle,
+        }
+    }
+
+    /// Start asking for an id; also the request to retransmit while
+    /// [`DeviceState::Requested`].
+    pub fn request(&mut self) -> AssignMessage {
+        if self.state != DeviceState::Requested {
+            self.started = Some(self.clock.now());
+            self.state = DeviceState::Requested;
+        }
+        AssignMessage::Request {
+            hardware_id: self.hardware_id,
+        }
+    }
+
+    /// Feed a received assignment message; returns the answer to send.
+    /// Messages for other hardware are ignored. The assignment is acked
+    /// again each time it is repeated, in case the ack was lost.
+    pub fn on_message(&mut self, message: &AssignMessage) -> Option<AssignMessage> {
+        if message.hardware_id() != self.hardware_id {
+            return None;
+        }
+        match (self.state, message) {
+            (DeviceState::Requested, AssignMessage::Assign { device_id, .. }) => {
+                self.state = DeviceState::Assigned(*device_id);
+                Some(self.ack(*device_id))
+            }
+            (DeviceState::Assigned(ours), AssignMessage::Assign { device_id, .. })
+                if ours == *device_id =>
+            {
+                Some(self.ack(ours))
+            }
+            (DeviceState::Requested, AssignMessage::Denied { .. }) => {
+                self.state = DeviceState::Failed(AssignError::Denied);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn ack(&self, device_id: [u8; 8]) -> AssignMessage {
+        AssignMessage::Ack {
+            hardware_id: self.hardware_id,
+            device_id,
+        }
+    }
+
+    /// Give up if no id has come within `device_timeout`.
+    pub fn poll(&mut self, now: Instant) {
+        if self.state == DeviceState::Requested
+            && self.started.is_some_and(|t| now >= t + self.timeout)
+        {
+            self.state = DeviceState::Failed(AssignError::TimedOut);
+        }
+    }
+
+    pub fn state(&self) -> DeviceState {
+        self.state
+    }
+
+    pub fn device_id(&self) -> Option<[u8; 8]> {
+        match self.state {
+            DeviceState::Assigned(id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// What the gateway makes of an assignment message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GatewayStep {
+    Ignore,
+    Reply(AssignMessage),
+    /// The device acked its id.
+    Assigned {
+        hardware_id: [u8; 16],
+        device_id: [u8; 8],
+    },
+}
+
+enum Offer {
+    Unacked { sent: Instant, attempts: u32 },
+    Acked,
+}
+
+/// The gateway's side, for any number of devices at once.
+pub struct GatewayAssignment {
+    clock: Arc<dyn Clock>,
+    config: AssignConfig,
+    allocator: IdAllocator,
+    offers: Mutex<HashMap<[u8; 16], Offer>>,
+}
+
+impl GatewayAssignment {
+    pub fn new(allocator: IdAllocator, config: AssignConfig) -> Self {
+        Self::with_clock(allocator, config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(allocator: IdAllocator, config: AssignConfig, clock: Arc<dyn Clock>) -> Self {
+        GatewayAssignment {
+            clock,
+            config,
+            allocator,
+            offers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Feed a received assignment message. Fails only if the store could
+    /// not record a new id; the device's retransmission retries it.
+    pub fn on_message(&self, message: &AssignMessage) -> io::Result<GatewayStep> {
+        match *message {
+            AssignMessage::Request { hardware_id } => {
+                let device_id = match self.allocator.allocate(hardware_id) {
+                    Ok(id) => id,
+                    Err(AllocError::Exhausted) => {
+                        return Ok(GatewayStep::Reply(AssignMessage::Denied { hardware_id }));
+                    }
+                    Err(AllocError::Store(e)) => return Err(e),
+                };
+                let offer = Offer::Unacked {
+                    sent: self.clock.now(),
+                    attempts: 1,
+                };
+                self.lock().insert(hardware_id, offer);
+                Ok(GatewayStep::Reply(AssignMessage::Assign {
+                    hardware_id,
+                    device_id,
+                }))
+            }
+            AssignMessage::Ack {
+                hardware_id,
+                device_id,
+            } => {
+                if self.allocator.issued(hardware_id) != Some(device_id) {
+                    return Ok(GatewayStep::Ignore);
+                }
+                let previous = self.lock().insert(hardware_id, Offer::Acked);
+                if matches!(previous, Some(Offer::Acked)) {
+                    return Ok(GatewayStep::Ignore);
+                }
+                Ok(GatewayStep::Assigned {
+                    hardware_id,
+                    device_id,
+                })
+            }
+            _ => Ok(GatewayStep::Ignore),
+        }
+    }
+
+    /// The ID-ASSIGNs due to be resent. An assignment sent `attempts`
+    /// times without an ack is no longer resent, but its id stays issued.
+    pub fn poll(&self, now: Instant) -> Vec<AssignMessage> {
+        let mut resend = Vec::new();
+        self.lock().retain(|hardware_id, offer| {
+            let Offer::Unacked { sent, attempts } = offer else {
+                return true;
+            };
+            if now < *sent + self.config.resend_after {
+                return true;
+            }
+            if *attempts >= self.config.attempts {
+                return false;
+            }
+            *sent = now;
+            *attempts += 1;
+            if let Some(device_id) = self.allocator.issued(*hardware_id) {
+                resend.push(AssignMessage::Assign {
+                    hardware_id: *hardware_id,
+                    device_id,
+                });
+            }
+            true
+        });
+        resend.sort_by_key(|m| m.hardware_id());
+        resend
+    }
+
+    /// The id `hardware_id` acked, if it has.
+    pub fn assigned(&self, hardware_id: [u8; 16]) -> Option<[u8; 8]> {
+        match self.lock().get(&hardware_id) {
+            Some(Offer::Acked) => self.allocator.issued(hardware_id),
+            _ => None,
+        }
+    }
+
+    pub fn allocator(&self) -> &IdAllocator {
+        &self.allocator
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<[u8; 16], Offer>> {
+        self.offers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const HW_A: [u8; 16] = *b"hardware-unit-A!";
+    const HW_B: [u8; 16] = *b"hardware-unit-B!";
+    const HW_C: [u8; 16] = *b"hardware-unit-C!";
+
+    fn gateway(dir: &tempfile::TempDir, pool: RangeInclusive<u64>) -> GatewayAssignment {
+        let store = Arc::new(FileIdStore::open(dir.path().join("ids")).unwrap());
+        let allocator = IdAllocator::new(IdPool::new(pool), store).unwrap();
+        GatewayAssignment::new(allocator, AssignConfig::default())
+    }
+
+    /// Over frames, sent from `UNASSIGNED` and seen by every device.
+    fn over_the_air(m: AssignMessage) -> AssignMessage {
+        let mut f = crate::transport::tests::frame(1);
+        f.header.msg_type = MsgType::Command;
+        f.header.device_id = UNASSIGNED;
+        f.body = m.encode().into();
+        AssignMessage::parse(&f).unwrap()
+    }
+
+    fn reply(step: io::Result<GatewayStep>) -> AssignMessage {
+        match step.unwrap() {
+            GatewayStep::Reply(m) => over_the_air(m),
+            step => panic!("expected a reply, got {step:?}"),
+        }
+    }
+
+    #[test]
+    fn simultaneous_requests_get_their_own_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let gw = gateway(&dir, 100..=199);
+        let config = AssignConfig::default();
+        let mut a = DeviceAssignment::new(HW_A, &config);
+        let mut b = DeviceAssignment::new(HW_B, &config);
+
+        let to_a = reply(gw.on_message(&over_the_air(a.request())));
+        let to_b = reply(gw.on_message(&over_the_air(b.request())));
+        // both devices hear both answers
+        assert_eq!(b.on_message(&to_a), None);
+        let ack_a = a.on_message(&to_a).unwrap();
+        assert_eq!(a.on_message(&to_b), None);
+        let ack_b = b.on_message(&to_b).unwrap();
+        assert_eq!(a.device_id(), Some(100u64.to_be_bytes()));
+        assert_eq!(b.device_id(), Some(101u64.to_be_bytes()));
+
+        assert_eq!(
+            gw.on_message(&over_the_air(ack_b)).unwrap(),
+            GatewayStep::Assigned {
+                hardware_id: HW_B,
+                device_id: 101u64.to_be_bytes()
+            }
+        );
+        assert_eq!(gw.assigned(HW_A), None);
+        gw.on_message(&ack_a).unwrap();
+        assert_eq!(gw.assigned(HW_A), Some(100u64.to_be_bytes()));
+        assert_eq!(gw.on_message(&ack_a).unwrap(), GatewayStep::Ignore);
+    }
+
+    #[test]
+    fn resends_until_acked_and_repeats_the_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = ManualClock::new();
+        let store = Arc::new(FileIdStore::open(dir.path().join("ids")).unwrap());
+        let allocator = IdAllocator::new(IdPool::new(1..=10), store).unwrap();
+        let config = AssignConfig {
+            attempts: 2,
+            ..AssignConfig::default()
+        };
+        let gw = GatewayAssignment::with_clock(allocator, config.clone(), Arc::new(clock.clone()));
+        let mut dev = DeviceAssignment::new(HW_A, &config);
+
+        let assign = reply(gw.on_message(&dev.request()));
+        dev.on_message(&assign).unwrap(); // the ack is lost
+        assert_eq!(gw.poll(clock.now()), []);
+        clock.advance(config.resend_after);
+        let resent = gw.poll(clock.now());
+        assert_eq!(resent, std::slice::from_ref(&assign));
+        clock.advance(config.resend_after);
+        assert_eq!(gw.poll(clock.now()), [], "out of attempts");
+
+        // a device that lost its id asks again and gets the same one
+        let mut again = DeviceAssignment::new(HW_A, &config);
+        assert_eq!(reply(gw.on_message(&again.request())), assign);
+        let ack = again.on_message(&assign).unwrap();
+        assert!(matches!(
+            gw.on_message(&ack).unwrap(),
+            GatewayStep::Assigned { .. }
+        ));
+        let wrong = AssignMessage::Ack {
+            hardware_id: HW_B,
+            device_id: dev.device_id().unwrap(),
+        };
+        assert_eq!(gw.on_message(&wrong).unwrap(), GatewayStep::Ignore);
+    }
+
+    #[test]
+    fn pool_exhaustion_denies_and_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AssignConfig::default();
+        let gw = gateway(&dir, 7..=8);
+        for hw in [HW_A, HW_B] {
+            let mut dev = DeviceAssignment::new(hw, &config);
+            let assign = reply(gw.on_message(&dev.request()));
+            dev.on_message(&assign).unwrap();
+        }
+        assert_eq!(gw.allocator().remaining(), 0);
+        let mut late = DeviceAssignment::new(HW_C, &config);
+        let denied = reply(gw.on_message(&late.request()));
+        assert_eq!(denied, AssignMessage::Denied { hardware_id: HW_C });
+        assert_eq!(late.on_message(&denied), None);
+        assert_eq!(late.state(), DeviceState::Failed(AssignError::Denied));
+        drop(gw);
+
+        // ids issued before the restart are not issued again, even from a
+        // larger pool
+        let gw = gateway(&dir, 7..=9);
+        assert_eq!(gw.allocator().remaining(), 1);
+        assert_eq!(gw.allocator().issued(HW_B), Some(8u64.to_be_bytes()));
+        let mut retry = DeviceAssignment::new(HW_C, &config);
+        let assign = reply(gw.on_message(&retry.request()));
+        retry.on_message(&assign).unwrap();
+        assert_eq!(retry.device_id(), Some(9u64.to_be_bytes()));
+    }
+
+    #[test]
+    fn device_times_out() {
+        let clock = ManualClock::new();
+        let config = AssignConfig::default();
+        let mut dev = DeviceAssignment::with_clock(HW_A, &config, Arc::new(clock.clone()));
+        dev.request();
+        clock.advance(config.device_timeout);
+        dev.poll(clock.now());
+        assert_eq!(dev.state(), DeviceState::Failed(AssignError::TimedOut));
+        assert_eq!(IdPool::new(0..=u64::MAX), IdPool::default());
+    }
+}
@@ -0,0 +1,587 @@
+//! Checking that no counter was used for two frames.
+//!
+//! [`audit`] reads journals in order, as one stream, and reports for each
+//! device the frames it numbered, counters lower than the one before
+//! ([`Finding::Regression`]), the largest jump forward, and counters used
+//! by two different frames ([`Finding::Duplicate`]). A frame recorded
+//! again byte for byte is a retransmission, counted and not reported.
+//! Only EVENT and COMMAND frames are audited: an ACK or ERROR carries the
+//! counter of the frame it answers.
+//!
+//! Findings go to a callback as they are made and are not kept. Memory is
+//! bounded by the number of devices and the size of one spill bucket: each
+//! frame's device, counter, a hash of its bytes and where it was recorded
+//! go to one of `buckets` files, picked by device and counter, and the
+//! buckets are then sorted one at a time. Frames sharing a key and a hash
+//! are read back from their journals and compared before one is called a
+//! retransmission of the other.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::journal::{JournalError, JournalReader, ParallelReader};
+use crate::sink::{Direction, Payload};
+use crate::{FrameV1, MsgType};
+
+/// The bytes of a spilled frame: device, counter, hash, journal, offset.
+const ENTRY_LEN: usize = 8 + 8 + 8 + 4 + 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditConfig {
+    /// Records of the other direction are skipped; `None` audits both.
+    pub direction: Option<Direction>,
+    /// Spill files; each is read into memory whole, at 36 bytes a frame.
+    pub buckets: usize,
+    /// Where the spill files go, in a directory of their own that is
+    /// removed afterwards.
+    pub spill_dir: PathBuf,
+    /// Threads decoding each journal, with a [`ParallelReader`].
+    pub threads: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            direction: Some(Direction::Tx),
+            buckets: 128,
+            spill_dir: std::env::temp_dir(),
+            threads: 1,
+        }
+    }
+}
+
+/// Where a record is: which of the journals, and its offset in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RecordRef {
+    pub journal: usize,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// A second frame, not the same as the first, with its counter.
+    Duplicate {
+        device_id: [u8; 8],
+        counter: u64,
+        first: RecordRef,
+        second: RecordRef,
+    },
+    /// A counter below that of the device's frame before it.
+    Regression {
+        device_id: [u8; 8],
+        previous: u64,
+        counter: u64,
+        previous_at: RecordRef,
+        at: RecordRef,
+    },
+}
+
+/// Counters `after` and `next` with none between them, while at least
+/// one was expected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    pub after: u64,
+    pub next: u64,
+    /// The record with `next`.
+    pub at: RecordRef,
+}
+
+impl Gap {
+    pub fn missing(&self) -> u64 {
+        self.next - self.after - 1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceAudit {
+    pub device_id: [u8; 8],
+    pub frames: u64,
+    pub retransmissions: u64,
+    pub duplicates: u64,
+    pub regressions: u64,
+    /// The largest jump past the highest counter seen so far.
+    pub largest_gap: Option<Gap>,
+    pub first_counter: u64,
+    pub highest_counter: u64,
+}
+
+/// Damaged bytes in a journal, passed over to read the records after.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Damaged {
+    pub journal: usize,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// A journal that ended before its file did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stopped {
+    pub journal: usize,
+    pub offset: u64,
+    pub unread: u64,
+    /// The error, or that the last record was torn.
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    /// By device id.
+    pub devices: Vec<DeviceAudit>,
+    pub records: u64,
+    /// Records not audited: the other direction, an answer, or not a frame.
+    pub skipped: u64,
+    pub damaged: Vec<Damaged>,
+    pub stopped: Vec<Stopped>,
+}
+
+impl AuditReport {
+    /// Whether no counter was reused or went backwards.
+    pub fn clean(&self) -> bool {
+        self.devices
+            .iter()
+            .all(|d| d.duplicates == 0 && d.regressions == 0)
+    }
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    /// A journal could not be opened or read.
+    Journal {
+        journal: usize,
+        error: JournalError,
+    },
+    Spill(io::Error),
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditError::Journal { journal, error } => write!(f, "journal {journal}: {error}"),
+            AuditError::Spill(e) => write!(f, "spill file: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+impl From<io::Error> for AuditError {
+    fn from(e: io::Error) -> Self {
+        AuditError::Spill(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Entry {
+    device_id: [u8; 8],
+    counter: u64,
+    at: RecordRef,
+    hash: u64,
+}
+
+impl Entry {
+    fn encode(&self) -> [u8; ENTRY_LEN] {
+        let mut out = [0; ENTRY_LEN];
+        out[..8].copy_from_slice(&self.device_id);
+        out[8..16].copy_from_slice(&self.counter.to_be_bytes());
+        out[16..24].copy_from_slice(&self.hash.to_be_bytes());
+        out[24..28].copy_from_slice(&(self.at.journal as u32).to_be_bytes());
+        out[28..].copy_from_slice(&self.at.offset.to_be_bytes());
+        out
+    }
+
+    fn decode(b: &[u8]) -> Entry {
+        let u64_at = |at: usize| u64::from_be_bytes(b[at..at + 8].try_into().unwrap());
+        Entry {
+            device_id: b[..8].try_into().unwrap(),
+            counter: u64_at(8),
+            hash: u64_at(16),
+            at: RecordRef {
+                journal: u32::from_be_bytes(b[24..28].try_into().unwrap()) as usize,
+                offset: u64_at(28),
+            },
+        }
+    }
+}
+
+/// The spill files, removed when dropped.
+struct Spill {
+    dir: PathBuf,
+    files: Vec<BufWriter<File>>,
+}
+
+impl Spill {
+    fn create(parent: &Path, buckets: usize) -> io::Result<Spill> {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let dir = parent.join(format!("pipproto-audit-{}-{nanos}", std::process::id()));
+        fs::create_dir(&dir)?;
+        let mut spill = Spill {
+            dir,
+            files: Vec::new(),
+        };
+        for i in 0..buckets.max(1) {
+            spill
+                .files
+                .push(BufWriter::new(File::create(spill.bucket(i))?));
+        }
+        Ok(spill)
+    }
+
+    fn bucket(&self, i: usize) -> PathBuf {
+        self.dir.join(format!("{i:05}"))
+    }
+
+    fn push(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut h = DefaultHasher::new();
+        (entry.device_id, entry.counter).hash(&mut h);
+        let i = (h.finish() % self.files.len() as u64) as usize;
+        self.files[i].write_all(&entry.encode())
+    }
+
+    /// The entries of bucket `i`, sorted.
+    fn read(&mut self, i: usize) -> io::Result<Vec<Entry>> {
+        self.files[i].flush()?;
+        let mut bytes = Vec::new();
+        File::open(self.bucket(i))?.read_to_end(&mut bytes)?;
+        let mut entries: Vec<Entry> = bytes.chunks_exact(ENTRY_LEN).map(Entry::decode).collect();
+        entries.sort_unstable();
+        Ok(entries)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        self.files.clear();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct Tracker {
+    audit: DeviceAudit,
+    last: u64,
+    last_at: RecordRef,
+}
+
+fn content_hash(frame: &FrameV1) -> u64 {
+    let mut h = DefaultHasher::new();
+    frame.encode().hash(&mut h);
+    h.finish()
+}
+
+/// The frame recorded at `at`.
+fn read_frame<P: AsRef<Path>>(journals: &[P], at: RecordRef) -> Result<FrameV1, AuditError> {
+    let failed = |error| AuditError::Journal {
+        journal: at.journal,
+        error,
+    };
+    let mut reader = JournalReader::open_at(&journals[at.journal], at.offset).map_err(failed)?;
+    match reader.next() {
+        Some(Ok(record)) => match record.payload {
+            Payload::Frame(frame) => Ok(frame),
+            Payload::Undecodable { .. } => Err(failed(JournalError::Corrupt { offset: at.offset })),
+        },
+        Some(Err(e)) => Err(failed(e)),
+        None => Err(failed(JournalError::Corrupt { offset: at.offset })),
+    }
+}
+
+/// Audit the frames of `journals`, read in order, calling `finding` with
+/// regressions as they are read and then with duplicates, bucket by
+/// bucket.
+pub fn audit<P: AsRef<Path>>(
+    journals: &[P],
+    config: &AuditConfig,
+    mut finding: impl FnMut(&Finding),
+) -> Result<AuditReport, AuditError> {
+    let mut spill = Spill::create(&config.spill_dir, config.buckets)?;
+    let mut devices: HashMap<[u8; 8], Tracker> = HashMap::new();
+    let mut report = AuditReport::default();
+
+    for (journal, path) in journals.iter().enumerate() {
+        let failed = |error| AuditError::Journal { journal, error };
+        let len = fs::metadata(path).map_err(|e| failed(e.into()))?.len();
+        let mut reader = ParallelReader::open(path)
+            .map_err(failed)?
+            .with_threads(config.threads);
+        loop {
+            let at = RecordRef {
+                journal,
+                offset: reader.offset(),
+            };
+            let record = match reader.next() {
+                Some(Ok(record)) => record,
+                Some(Err(JournalError::Io(e))) => return Err(failed(JournalError::Io(e))),
+                Some(Err(JournalError::Skipped { offset, len })) => {
+                    report.damaged.push(Damaged {
+                        journal,
+                        offset,
+                        len,
+                    });
+                    continue;
+                }
+                Some(Err(e)) => {
+                    report.stopped.push(Stopped {
+                        journal,
+                        offset: at.offset,
+                        unread: len - at.offset,
+                        reason: e.to_string(),
+                    });
+                    break;
+                }
+                None => {
+                    if at.offset < len {
+                        report.stopped.push(Stopped {
+                            journal,
+                            offset: at.offset,
+                            unread: len - at.offset,
+                            reason: "torn record".into(),
+                        });
+                    }
+                    break;
+                }
+            };
+            report.records += 1;
+            let Payload::Frame(frame) = &record.payload else {
+                report.skipped += 1;
+                continue;
+            };
+            let h = &frame.header;
+            let numbered = matches!(h.msg_type, MsgType::Event | MsgType::Command);
+            if !numbered || config.direction.is_some_and(|d| d != record.direction) {
+                report.skipped += 1;
+                continue;
+            }
+            spill.push(&Entry {
+                device_id: h.device_id,
+                counter: h.counter,
+                at,
+                hash: content_hash(frame),
+            })?;
+
+            let Some(t) = devices.get_mut(&h.device_id) else {
+                let audit = DeviceAudit {
+                    device_id: h.device_id,
+                    frames: 1,
+                    retransmissions: 0,
+                    duplicates: 0,
+                    regressions: 0,
+                    largest_gap: None,
+                    first_counter: h.counter,
+                    highest_counter: h.counter,
+                };
+                devices.insert(
+                    h.device_id,
+                    Tracker {
+                        audit,
+                        last: h.counter,
+                        last_at: at,
+                    },
+                );
+                continue;
+            };
+            let a = &mut t.audit;
+            a.frames += 1;
+            if h.counter < t.last {
+                a.regressions += 1;
+                finding(&Finding::Regression {
+                    device_id: h.device_id,
+                    previous: t.last,
+                    counter: h.counter,
+                    previous_at: t.last_at,
+                    at,
+                });
+            }
+            if h.counter > a.highest_counter.saturating_add(1) {
+                let gap = Gap {
+                    after: a.highest_counter,
+                    next: h.counter,
+                    at,
+                };
+                if a.largest_gap.is_none_or(|g| gap.missing() > g.missing()) {
+                    a.largest_gap = Some(gap);
+                }
+            }
+            a.highest_counter = a.highest_counter.max(h.counter);
+            t.last = h.counter;
+            t.last_at = at;
+        }
+    }
+
+    for i in 0..spill.files.len() {
+        let entries = spill.read(i)?;
+        for group in entries.chunk_by(|a, b| (a.device_id, a.counter) == (b.device_id, b.counter)) {
+            if group.len() < 2 {
+                continue;
+            }
+            let audit = &mut devices.get_mut(&group[0].device_id).unwrap().audit;
+            // the first record of each different frame with this counter
+            let mut variants: Vec<(Entry, Option<FrameV1>)> = vec![(group[0], None)];
+            for entry in &group[1..] {
+                let mut same = false;
+                for (variant, frame) in variants.iter_mut() {
+                    if variant.hash != entry.hash {
+                        continue;
+                    }
+                    if frame.is_none() {
+                        *frame = Some(read_frame(journals, variant.at)?);
+                    }
+                    if frame.as_ref() == Some(&read_frame(journals, entry.at)?) {
+                        same = true;
+                        break;
+                    }
+                }
+                if same {
+                    audit.retransmissions += 1;
+                    continue;
+                }
+                audit.duplicates += 1;
+                finding(&Finding::Duplicate {
+                    device_id: entry.device_id,
+                    counter: entry.counter,
+                    first: group[0].at,
+                    second: entry.at,
+                });
+                variants.push((*entry, None));
+            }
+        }
+    }
+
+    report.devices = devices.into_values().map(|t| t.audit).collect();
+    report.devices.sort_by_key(|d| d.device_id);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::JournalWriter;
+    use crate::sink::{FrameRecord, FrameSink};
+    use crate::transport::tests::frame;
+
+    fn frame_from(device: u8, counter: u64, body: &[u8]) -> FrameV1 {
+        let mut f = frame(counter);
+        f.header.device_id[7] = device;
+        f.body = body.into();
+        f
+    }
+
+    fn write(path: &Path, frames: &[FrameV1]) -> Vec<u64> {
+        let mut journal = JournalWriter::open(path).unwrap();
+        for f in frames {
+            journal.record(&FrameRecord::tx(f.clone())).unwrap();
+        }
+        journal.flush().unwrap();
+        let mut offsets = Vec::new();
+        let mut reader = JournalReader::open(path).unwrap();
+        loop {
+            offsets.push(reader.offset());
+            if reader.next().is_none() {
+                break;
+            }
+        }
+        offsets.pop();
+        offsets
+    }
+
+    #[test]
+    fn finds_duplicates_and_regressions() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.log"), dir.path().join("b.log"));
+        let offsets_a = write(
+            &a,
+            &[
+                frame_from(1, 1, b"x"),
+                frame_from(1, 2, b"x"),
+                frame_from(1, 2, b"x"), // a retransmission
+                frame_from(2, 10, b"x"),
+                frame_from(1, 7, b"x"),
+            ],
+        );
+        let offsets_b = write(
+            &b,
+            &[
+                frame_from(1, 2, b"y"), // a duplicate, and a regression
+                frame_from(2, 11, b"x"),
+            ],
+        );
+        let config = AuditConfig {
+            buckets: 3,
+            spill_dir: dir.path().to_path_buf(),
+            ..AuditConfig::default()
+        };
+        let mut findings = Vec::new();
+        let report = audit(&[&a, &b], &config, |f| findings.push(f.clone())).unwrap();
+        assert!(!report.clean());
+        assert_eq!((report.records, report.skipped), (7, 0));
+        let ref_a = |i| RecordRef {
+            journal: 0,
+            offset: offsets_a[i],
+        };
+        let ref_b = RecordRef {
+            journal: 1,
+            offset: offsets_b[0],
+        };
+        let dev1 = frame_from(1, 0, b"").header.device_id;
+        assert_eq!(
+            findings,
+            [
+                Finding::Regression {
+                    device_id: dev1,
+                    previous: 7,
+                    counter: 2,
+                    previous_at: ref_a(4),
+                    at: ref_b,
+                },
+                Finding::Duplicate {
+                    device_id: dev1,
+                    counter: 2,
+                    first: ref_a(1),
+                    second: ref_b,
+                },
+            ]
+        );
+        let d = &report.devices[0];
+        assert_eq!(d.device_id, dev1);
+        assert_eq!(
+            (d.frames, d.retransmissions, d.duplicates, d.regressions),
+            (5, 1, 1, 1)
+        );
+        let gap = d.largest_gap.unwrap();
+        assert_eq!(
+            (gap.after, gap.next, gap.missing(), gap.at),
+            (2, 7, 4, ref_a(4))
+        );
+        let d = &report.devices[1];
+        assert_eq!((d.frames, d.duplicates, d.largest_gap), (2, 0, None));
+        // the spill directory is gone
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn skips_answers_and_other_direction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("j.log");
+        let mut journal = JournalWriter::open(&path).unwrap();
+        let mut ack = frame_from(1, 1, b"");
+        ack.header.msg_type = MsgType::Ack;
+        for record in [
+            FrameRecord::tx(frame_from(1, 1, b"")),
+            FrameRecord::tx(ack),
+            FrameRecord::rx(frame_from(1, 1, b"other")),
+        ] {
+            journal.record(&record).unwrap();
+        }
+        journal.flush().unwrap();
+        let report = audit(&[&path], &AuditConfig::default(), |_| {}).unwrap();
+        assert!(report.clean());
+        assert_eq!((report.records, report.skipped), (3, 2));
+        assert!(report.stopped.is_empty());
+    }
+}
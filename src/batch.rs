@@ -0,0 +1,303 @@
+//! Coalescing writer for stream transports.
+//!
+//! [`BatchingWriter`] buffers length-prefixed frames and writes them to the
+//! underlying writer in one go when the first of these is hit: a byte
+//! threshold, a frame-count threshold, or the oldest buffered frame having
+//! waited `max_delay`. The delay is checked on every write and by
+//! [`BatchingWriter::flush_if_due`], which callers with idle periods should
+//! call at [`BatchingWriter::deadline`].
+
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::FrameV1;
+use crate::clock::{Clock, SystemClock};
+use crate::codec::write_prefixed;
+
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    pub max_bytes: usize,
+    pub max_frames: usize,
+    pub max_delay: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_bytes: 16 * 1024,
+            max_frames: 64,
+            max_delay: Duration::from_millis(5),
+        }
+    }
+}
+
+pub struct BatchingWriter<W: Write> {
+    inner: Option<W>,
+    buf: Vec<u8>,
+    frames: usize,
+    oldest: Option<Instant>,
+    config: BatchConfig,
+    clock: Arc<dyn Clock>,
+}
+
+impl<W: Write> BatchingWriter<W> {
+    pub fn new(inner: W, config: BatchConfig) -> Self {
+        Self::with_clock(inner, config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(inner: W, config: BatchConfig, clock: Arc<dyn Clock>) -> Self {
+        BatchingWriter {
+            inner: Some(inner),
+            buf: Vec::with_capacity(config.max_bytes),
+            frames: 0,
+            oldest: None,
+            config,
+            clock,
+        }
+    }
+
+    pub fn write_frame(&mut self, frame: &FrameV1) -> io::Result<()> {
+        self.push(frame);
+        let full = self.buf.len() >= self.config.max_bytes || self.frames >= self.config.max_frames;
+        if full || self.is_due() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write now, together with anything already buffered (order is kept).
+    pub fn write_urgent(&mut self, frame: &FrameV1) -> io::Result<()> {
+        self.push(frame);
+        self.flush()
+    }
+
+    /// When the buffered frames must be flushed, if any are buffered.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.oldest.map(|t| t + self.config.max_delay)
+    }
+
+    /// Flush if the oldest buffered frame has waited `max_delay`. Returns
+    /// whether it flushed.
+    pub fn flush_if_due(&mut self) -> io::Result<bool> {
+        if !self.is_due() {
+            return Ok(false);
+        }
+        self.flush()?;
+        Ok(true)
+    }
+
+    pub fn buffered_frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn buffered_bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// Write out everything buffered and flush the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_buffer()?;
+        self.inner.as_mut().unwrap().flush()
+    }
+
+    /// Flush and hand back the underlying writer.
+    pub fn close(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.inner.take().unwrap())
+    }
+
+    fn push(&mut self, frame: &FrameV1) {
+        write_prefixed(frame, &mut self.buf);
+        self.frames += 1;
+        self.oldest.get_or_insert_with(|| self.clock.now());
+    }
+
+    fn is_due(&self) -> bool {
+        self.deadline().is_some_and(|d| self.clock.now() >= d)
+    }
+
+    /// On error only the bytes not yet written stay buffered, so a later
+    /// flush picks up where this one stopped.
+    fn write_buffer(&mut self) -> io::Result<()> {
+        let inner = self.inner.as_mut().unwrap();
+        let mut written = 0;
+        let result = loop {
+            if written == self.buf.len() {
+                break Ok(());
+            }
+            match inner.write(&self.buf[written..]) {
+                Ok(0) => break Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.buf.drain(..written);
+        if self.buf.is_empty() {
+            self.frames = 0;
+            self.oldest = None;
+        }
+        result
+    }
+}
+
+impl<W: Write> Drop for BatchingWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::transport::tests::frame;
+
+    /// Records each write call separately.
+    #[derive(Default)]
+    struct Writes(Vec<Vec<u8>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Takes `left` more bytes, then fails once.
+    struct FailsAfter {
+        out: Vec<u8>,
+        left: usize,
+    }
+
+    impl Write for FailsAfter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.left == 0 {
+                self.left = usize::MAX;
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            let n = buf.len().min(self.left);
+            self.out.extend_from_slice(&buf[..n]);
+            self.left -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn writer(config: BatchConfig) -> (BatchingWriter<Writes>, ManualClock) {
+        let clock = ManualClock::new();
+        let w = BatchingWriter::with_clock(Writes::default(), config, Arc::new(clock.clone()));
+        (w, clock)
+    }
+
+    const FRAME_BYTES: usize = 4 + 24;
+
+    #[test]
+    fn flush_on_frame_count() {
+        let (mut w, _) = writer(BatchConfig {
+            max_frames: 3,
+            ..BatchConfig::default()
+        });
+        w.write_frame(&frame(0)).unwrap();
+        w.write_frame(&frame(1)).unwrap();
+        assert!(w.get_ref().0.is_empty());
+        w.write_frame(&frame(2)).unwrap();
+        assert_eq!(w.get_ref().0.len(), 1);
+        assert_eq!(w.get_ref().0[0].len(), 3 * FRAME_BYTES);
+    }
+
+    #[test]
+    fn flush_on_bytes() {
+        let (mut w, _) = writer(BatchConfig {
+            max_bytes: 2 * FRAME_BYTES,
+            ..BatchConfig::default()
+        });
+        w.write_frame(&frame(0)).unwrap();
+        w.write_frame(&frame(1)).unwrap();
+        assert_eq!(w.buffered_frames(), 0);
+        assert_eq!(w.get_ref().0.len(), 1);
+    }
+
+    #[test]
+    fn time_based_flush_fires() {
+        let (mut w, clock) = writer(BatchConfig::default());
+        w.write_frame(&frame(0)).unwrap();
+        assert_eq!(w.deadline(), Some(clock.now() + Duration::from_millis(5)));
+
+        clock.advance(Duration::from_millis(4));
+        assert!(!w.flush_if_due().unwrap());
+        clock.advance(Duration::from_millis(1));
+        assert!(w.flush_if_due().unwrap());
+        assert_eq!(w.get_ref().0.len(), 1);
+        assert_eq!(w.deadline(), None);
+
+        // an overdue batch also goes out on the next write
+        w.write_frame(&frame(1)).unwrap();
+        clock.advance(Duration::from_millis(10));
+        w.write_frame(&frame(2)).unwrap();
+        assert_eq!(w.get_ref().0.len(), 2);
+        assert_eq!(w.get_ref().0[1].len(), 2 * FRAME_BYTES);
+    }
+
+    #[test]
+    fn urgent_bypasses_delay_in_order() {
+        let (mut w, _) = writer(BatchConfig::default());
+        w.write_frame(&frame(0)).unwrap();
+        w.write_urgent(&frame(1)).unwrap();
+
+        let mut expected = Vec::new();
+        write_prefixed(&frame(0), &mut expected);
+        write_prefixed(&frame(1), &mut expected);
+        assert_eq!(w.get_ref().0, [expected]);
+    }
+
+    #[test]
+    fn close_and_drop_flush() {
+        let (mut w, _) = writer(BatchConfig::default());
+        w.write_frame(&frame(0)).unwrap();
+        let inner = w.close().unwrap();
+        assert_eq!(inner.0.len(), 1);
+
+        let mut out = Vec::new();
+        {
+            let mut w = BatchingWriter::new(&mut out, BatchConfig::default());
+            w.write_frame(&frame(0)).unwrap();
+        }
+        assert_eq!(out.len(), FRAME_BYTES);
+    }
+
+    #[test]
+    fn a_failed_flush_keeps_only_what_was_not_written() {
+        let mut sink = FailsAfter {
+            out: Vec::new(),
+            left: 10,
+        };
+        let mut w = BatchingWriter::new(&mut sink, BatchConfig::default());
+        w.write_frame(&frame(0)).unwrap();
+        w.write_frame(&frame(1)).unwrap();
+        assert!(w.flush().is_err());
+        assert_eq!(w.buffered_bytes(), 2 * FRAME_BYTES - 10);
+        w.flush().unwrap();
+        assert_eq!(w.buffered_frames(), 0);
+        drop(w);
+
+        let mut expected = Vec::new();
+        write_prefixed(&frame(0), &mut expected);
+        write_prefixed(&frame(1), &mut expected);
+        assert_eq!(sink.out, expected);
+    }
+}
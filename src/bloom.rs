@@ -0,0 +1,423 @@
+//! A duplicate filter in fixed memory, for receivers that can't afford a
+//! [`DedupCache`](crate::dedup::DedupCache).
+//!
+//! [`RotatingBloom`] is two Bloom filters of `WORDS` 64-bit words each,
+//! sized at compile time: `16 * WORDS` bytes in all and nothing on the
+//! heap. [`RotatingBloom::new`] is `const`, so one can live in a `static`.
+//! This crate needs `std`, so it does not run on a `no_std` target itself;
+//! a receiver built without it can take this module's hashing, sizing and
+//! rotation as the reference for its own filter.
+//! [`RotatingBloom::check_and_insert`] says whether an id is
+//! [`Membership::ProbablyNew`] or [`Membership::SeenBefore`]. New ids go
+//! into the newer filter until it rotates: the older one is cleared and
+//! takes the new ids from then on. It rotates after
+//! [`RotatingBloom::with_rotate_after`] new ids and, given
+//! [`RotatingBloom::with_rotate_every`], on the first
+//! [`RotatingBloom::tick`] that long after the last rotation. An id seen
+//! again while only the older filter has it is copied into the newer.
+//!
+//! Nothing seen since the last rotation is missed. The price is false
+//! positives: a new id reads as seen with probability about
+//! `(1 - e^(-k·n/m))^k` per filter, for `m = 64 · WORDS` bits,
+//! `k = HASHES` and `n` ids in the filter. By default a filter rotates
+//! after `m · ln 2 / k` ids, where that comes to `2^-k`, so with both
+//! full it stays under `2^(1-k)`. [`RotatingBloom::false_positive_rate`]
+//! estimates it from the bits set. With the default rotation:
+//!
+//! | `WORDS` | `HASHES` | memory | ids per filter | false positives, both full |
+//! |---------|----------|--------|----------------|----------------------------|
+//! | 64      | 4        | 1 KiB  | 709            | 12%                        |
+//! | 128     | 5        | 2 KiB  | 1135           | 6.1%                       |
+//! | 256     | 7        | 4 KiB  | 1622           | 1.6%                       |
+//! | 512     | 8        | 8 KiB  | 2838           | 0.8%                       |
+//!
+//! A false positive drops a new frame as a duplicate, so this suits
+//! frames whose sender resends until acked (the ack goes out all the
+//! same), and a filter should rotate slower than the sender's whole
+//! retransmission period. Hashes are keyed by
+//! [`RotatingBloom::with_seed`].
+//!
+//! It is a [`DuplicateFilter`], so a
+//! [`SharedDedupCache`](crate::dedup::SharedDedupCache) can hold one for
+//! the dispatcher in place of a `DedupCache`; [`ClockedBloom`] ticks it
+//! from a [`Clock`] there.
+
+use core::time::Duration;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::FrameId;
+use crate::clock::{Clock, SystemClock};
+use crate::dedup::{Delivery, DuplicateFilter};
+use crate::sim::{SPLITMIX_GAMMA, splitmix64};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Membership {
+    ProbablyNew,
+    SeenBefore,
+}
+
+/// Two Bloom filters of `WORDS` words taking turns, `HASHES` bits per id;
+/// see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct RotatingBloom<const WORDS: usize, const HASHES: usize> {
+    filters: [[u64; WORDS]; 2],
+    /// Ids put into each filter.
+    counts: [u32; 2],
+    /// Which filter takes new ids.
+    newer: usize,
+    seed: u64,
+    rotate_after: u32,
+    rotate_every: Option<Duration>,
+    rotated_at: Option<Duration>,
+}
+
+impl<const WORDS: usize, const HASHES: usize> RotatingBloom<WORDS, HASHES> {
+    const BITS: u64 = WORDS as u64 * 64;
+
+    /// Rotates after `m · ln 2 / k` ids.
+    pub const fn new() -> Self {
+        assert!(WORDS > 0 && HASHES > 0);
+        let rotate_after = Self::BITS * 693 / (1000 * HASHES as u64);
+        RotatingBloom {
+            filters: [[0; WORDS]; 2],
+            counts: [0; 2],
+            newer: 0,
+            seed: 0,
+            rotate_after: if rotate_after > u32::MAX as u64 {
+                u32::MAX
+            } else if rotate_after == 0 {
+                1
+            } else {
+                rotate_after as u32
+            },
+            rotate_every: None,
+            rotated_at: None,
+        }
+    }
+
+    /// Rotate once the newer filter has `ids` ids.
+    pub const fn with_rotate_after(mut self, ids: u32) -> Self {
+        self.rotate_after = if ids == 0 { 1 } else { ids };
+        self
+    }
+
+    /// Rotate, too, on a [`RotatingBloom::tick`] `every` after the last.
+    pub const fn with_rotate_every(mut self, every: Duration) -> Self {
+        self.rotate_every = Some(every);
+        self
+    }
+
+    pub const fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Whether `id` was seen before, probably, remembering it either way.
+    pub fn check_and_insert(&mut self, id: FrameId) -> Membership {
+        let hashes = self.hashes(id);
+        if self.has(self.newer, hashes) {
+            return Membership::SeenBefore;
+        }
+        let seen = self.has(1 - self.newer, hashes);
+        self.set(hashes);
+        if self.counts[self.newer] >= self.rotate_after {
+            self.rotate();
+        }
+        if seen {
+            Membership::SeenBefore
+        } else {
+            Membership::ProbablyNew
+        }
+    }
+
+    /// Whether `id` was seen before, probably, without remembering it.
+    pub fn contains(&self, id: FrameId) -> bool {
+        let hashes = self.hashes(id);
+        self.has(0, hashes) || self.has(1, hashes)
+    }
+
+    /// The time is `now`, on any clock that only goes forward (an uptime,
+    /// say): rotate if [`RotatingBloom::with_rotate_every`] has passed.
+    /// The first tick only starts the count.
+    pub fn tick(&mut self, now: Duration) {
+        let Some(every) = self.rotate_every else {
+            return;
+        };
+        match self.rotated_at {
+            Some(at) if now.saturating_sub(at) < every => {}
+            Some(_) => {
+                self.rotate();
+                self.rotated_at = Some(now);
+            }
+            None => self.rotated_at = Some(now),
+        }
+    }
+
+    /// Clear the older filter and put new ids into it.
+    pub fn rotate(&mut self) {
+        self.newer = 1 - self.newer;
+        self.filters[self.newer] = [0; WORDS];
+        self.counts[self.newer] = 0;
+    }
+
+    /// Ids put into either filter.
+    pub fn len(&self) -> usize {
+        self.counts[0] as usize + self.counts[1] as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The chance, from the bits set, that a new id reads as seen.
+    pub fn false_positive_rate(&self) -> f64 {
+        let miss = |filter: &[u64; WORDS]| {
+            let set: u32 = filter.iter().map(|w| w.count_ones()).sum();
+            let fill = set as f64 / Self::BITS as f64;
+            let mut p = 1.0;
+            for _ in 0..HASHES {
+                p *= fill;
+            }
+            1.0 - p
+        };
+        1.0 - miss(&self.filters[0]) * miss(&self.filters[1])
+    }
+
+    /// Two hashes of `id`, the bits being `h1 + i·h2` (Kirsch and
+    /// Mitzenmacher).
+    fn hashes(&self, id: FrameId) -> (u64, u64) {
+        let device = u64::from_le_bytes(id.device_id);
+        let h = splitmix64(self.seed.wrapping_add(SPLITMIX_GAMMA) ^ device);
+        let h1 = splitmix64(h ^ id.counter.wrapping_mul(SPLITMIX_GAMMA));
+        let h2 = splitmix64(h1.wrapping_add(SPLITMIX_GAMMA)) | 1;
+        (h1, h2)
+    }
+
+    fn bits((h1, h2): (u64, u64)) -> impl Iterator<Item = (usize, u64)> {
+        (0..HASHES as u64).map(move |i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % Self::BITS;
+            ((bit / 64) as usize, 1 << (bit % 64))
+        })
+    }
+
+    fn has(&self, filter: usize, hashes: (u64, u64)) -> bool {
+        Self::bits(hashes).all(|(word, mask)| self.filters[filter][word] & mask != 0)
+    }
+
+    fn set(&mut self, hashes: (u64, u64)) {
+        let filter = &mut self.filters[self.newer];
+        for (word, mask) in Self::bits(hashes) {
+            filter[word] |= mask;
+        }
+        self.counts[self.newer] += 1;
+    }
+}
+
+impl<const WORDS: usize, const HASHES: usize> Default for RotatingBloom<WORDS, HASHES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rotates on frame count alone; see [`ClockedBloom`] for time.
+impl<const WORDS: usize, const HASHES: usize> DuplicateFilter for RotatingBloom<WORDS, HASHES> {
+    fn check_and_record(&mut self, id: FrameId) -> Delivery {
+        match self.check_and_insert(id) {
+            Membership::ProbablyNew => Delivery::FirstDelivery,
+            Membership::SeenBefore => Delivery::Duplicate,
+        }
+    }
+
+    fn contains(&self, id: FrameId) -> bool {
+        RotatingBloom::contains(self, id)
+    }
+
+    fn len(&self) -> usize {
+        RotatingBloom::len(self)
+    }
+}
+
+/// A [`RotatingBloom`] ticked from a [`Clock`] before every check.
+pub struct ClockedBloom<const WORDS: usize, const HASHES: usize> {
+    filter: RotatingBloom<WORDS, HASHES>,
+    clock: Arc<dyn Clock>,
+    start: Instant,
+}
+
+impl<const WORDS: usize, const HASHES: usize> ClockedBloom<WORDS, HASHES> {
+    pub fn new(filter: RotatingBloom<WORDS, HASHES>) -> Self {
+        Self::with_clock(filter, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(mut filter: RotatingBloom<WORDS, HASHES>, clock: Arc<dyn Clock>) -> Self {
+        let start = clock.now();
+        filter.tick(Duration::ZERO);
+        ClockedBloom {
+            filter,
+            clock,
+            start,
+        }
+    }
+
+    pub fn get_ref(&self) -> &RotatingBloom<WORDS, HASHES> {
+        &self.filter
+    }
+
+    pub fn check_and_insert(&mut self, id: FrameId) -> Membership {
+        self.filter
+            .tick(self.clock.now().saturating_duration_since(self.start));
+        self.filter.check_and_insert(id)
+    }
+}
+
+impl<const WORDS: usize, const HASHES: usize> DuplicateFilter for ClockedBloom<WORDS, HASHES> {
+    fn check_and_record(&mut self, id: FrameId) -> Delivery {
+        match self.check_and_insert(id) {
+            Membership::ProbablyNew => Delivery::FirstDelivery,
+            Membership::SeenBefore => Delivery::Duplicate,
+        }
+    }
+
+    fn contains(&self, id: FrameId) -> bool {
+        self.filter.contains(id)
+    }
+
+    fn len(&self) -> usize {
+        self.filter.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::sim::Rng;
+
+    fn id(counter: u64) -> FrameId {
+        FrameId {
+            device_id: *b"DEV00001",
+            counter,
+        }
+    }
+
+    /// Distinct ids from a seeded sequence, over a few devices.
+    fn ids(seed: u64) -> impl Iterator<Item = FrameId> {
+        let mut rng = Rng(seed);
+        (0..).map(move |n: u64| FrameId {
+            device_id: (n % 5).to_le_bytes(),
+            counter: rng.next_u64(),
+        })
+    }
+
+    #[test]
+    fn false_positive_rate_is_as_documented() {
+        type Filter = RotatingBloom<64, 4>;
+        let mut filter = Filter::new().with_seed(7);
+        assert_eq!(filter.rotate_after, 709);
+        let mut known = ids(1);
+        let mut fill = |filter: &mut Filter| {
+            for id in known.by_ref().take(708) {
+                filter.check_and_insert(id);
+            }
+        };
+        let rate = |filter: &Filter| {
+            let trials = 20_000;
+            let seen = ids(2).take(trials).filter(|&i| filter.contains(i)).count();
+            seen as f64 / trials as f64
+        };
+
+        // one filter nearly full: 2^-4
+        fill(&mut filter);
+        let (measured, estimated) = (rate(&filter), filter.false_positive_rate());
+        assert!((measured - 0.0623).abs() < 0.01, "{measured}");
+        assert!(
+            (measured - estimated).abs() < 0.01,
+            "{measured} vs {estimated}"
+        );
+
+        // both: under 2^-3
+        filter.rotate();
+        fill(&mut filter);
+        let (measured, estimated) = (rate(&filter), filter.false_positive_rate());
+        assert!((measured - 0.1207).abs() < 0.015, "{measured}");
+        assert!(
+            (measured - estimated).abs() < 0.015,
+            "{measured} vs {estimated}"
+        );
+        assert!(estimated < 0.125);
+    }
+
+    #[test]
+    fn remembers_one_generation_at_least_and_two_at_most() {
+        let mut filter = RotatingBloom::<64, 4>::new().with_rotate_after(100);
+        for n in 0..250 {
+            assert_eq!(filter.check_and_insert(id(n)), Membership::ProbablyNew);
+        }
+        assert_eq!(filter.len(), 150);
+        // 200..250 in the newer filter, 100..200 in the older
+        assert!((100..250).all(|n| filter.contains(id(n))));
+
+        filter.rotate();
+        filter.rotate();
+        assert!(filter.is_empty());
+        assert!((0..250).all(|n| !filter.contains(id(n))));
+        assert_eq!(filter.false_positive_rate(), 0.0);
+    }
+
+    #[test]
+    fn a_repeat_is_carried_into_the_newer_filter() {
+        let mut filter = RotatingBloom::<16, 3>::new().with_rotate_after(u32::MAX);
+        filter.check_and_insert(id(1));
+        filter.rotate();
+        assert_eq!(filter.check_and_insert(id(1)), Membership::SeenBefore);
+        filter.rotate();
+        assert!(filter.contains(id(1)));
+        filter.rotate();
+        assert!(!filter.contains(id(1)));
+    }
+
+    #[test]
+    fn seeds_change_the_bits() {
+        let a = RotatingBloom::<16, 3>::new();
+        let b = RotatingBloom::<16, 3>::new().with_seed(1);
+        assert_ne!(a.hashes(id(1)), b.hashes(id(1)));
+    }
+
+    #[test]
+    fn ticks_rotate_on_time() {
+        let mut filter = RotatingBloom::<16, 3>::new()
+            .with_rotate_after(u32::MAX)
+            .with_rotate_every(Duration::from_secs(10));
+        filter.tick(Duration::from_secs(100));
+        filter.check_and_insert(id(1));
+        filter.tick(Duration::from_secs(109));
+        filter.check_and_insert(id(2));
+        assert_eq!(filter.len(), 2);
+        filter.tick(Duration::from_secs(110));
+        filter.tick(Duration::from_secs(119));
+        assert!(filter.contains(id(1)));
+        filter.tick(Duration::from_secs(120));
+        assert!(filter.is_empty());
+    }
+
+    #[test]
+    fn clocked_filters_rotate_under_a_dedup_cache() {
+        let clock = ManualClock::new();
+        let filter = RotatingBloom::<16, 3>::new()
+            .with_rotate_after(u32::MAX)
+            .with_rotate_every(Duration::from_secs(1));
+        let mut filter = ClockedBloom::with_clock(filter, Arc::new(clock.clone()));
+        assert_eq!(filter.check_and_record(id(1)), Delivery::FirstDelivery);
+        assert_eq!(filter.check_and_record(id(1)), Delivery::Duplicate);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(filter.check_and_record(id(1)), Delivery::Duplicate);
+        // the repeat was carried into the newer filter
+        clock.advance(Duration::from_secs(1));
+        filter.check_and_record(id(2));
+        assert!(filter.get_ref().contains(id(1)));
+        clock.advance(Duration::from_secs(1));
+        filter.check_and_record(id(3));
+        assert!(!filter.get_ref().contains(id(1)));
+    }
+}
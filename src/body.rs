@@ -0,0 +1,386 @@
+//! Frame bodies, kept inline in the frame when they are short.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, DerefMut};
+
+use bytes::Bytes;
+
+/// Bodies up to this many bytes are kept inline, in the frame itself,
+/// rather than on the heap.
+///
+/// Most bodies are under 48 bytes: control messages, acks and the usual
+/// sensor EVENT. A `Body` needs 56 bytes anyway, 24 for a `Vec` on the heap
+/// side, and 54 fills them once the variant tag and the length are in.
+pub const INLINE_BODY: usize = 54;
+
+/// A frame body: a byte string that derefs to `[u8]` and compares, hashes,
+/// prints and serializes as one, whether it is kept inline or on the heap.
+#[derive(Clone)]
+pub struct Body(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Inline { len: u8, bytes: [u8; INLINE_BODY] },
+    Heap(Vec<u8>),
+}
+
+impl Body {
+    pub const fn new() -> Self {
+        Body(Repr::Inline {
+            len: 0,
+            bytes: [0; INLINE_BODY],
+        })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Inline { len, bytes } => &bytes[..*len as usize],
+            Repr::Heap(v) => v,
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        match &mut self.0 {
+            Repr::Inline { len, bytes } => &mut bytes[..*len as usize],
+            Repr::Heap(v) => v,
+        }
+    }
+
+    /// Whether the bytes are kept in the body itself.
+    pub fn is_inline(&self) -> bool {
+        matches!(self.0, Repr::Inline { .. })
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.0 {
+            Repr::Inline { .. } => self.as_slice().to_vec(),
+            Repr::Heap(v) => v,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        self.extend_from_slice(&[byte]);
+    }
+
+    /// Moves the bytes to the heap once they no longer fit inline.
+    pub fn extend_from_slice(&mut self, more: &[u8]) {
+        match &mut self.0 {
+            Repr::Inline { len, bytes } if *len as usize + more.len() <= INLINE_BODY => {
+                let at = *len as usize;
+                bytes[at..at + more.len()].copy_from_slice(more);
+                *len += more.len() as u8;
+            }
+            Repr::Inline { .. } => {
+                let mut v = Vec::with_capacity(self.len() + more.len());
+                v.extend_from_slice(self.as_slice());
+                v.extend_from_slice(more);
+                self.0 = Repr::Heap(v);
+            }
+            Repr::Heap(v) => v.extend_from_slice(more),
+        }
+    }
+
+    pub fn truncate(&mut self, new_len: usize) {
+        match &mut self.0 {
+            Repr::Inline { len, .. } => *len = (*len).min(new_len.min(INLINE_BODY) as u8),
+            Repr::Heap(v) => v.truncate(new_len),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// The bytes from `at` on, leaving those before it.
+    ///
+    /// # Panics
+    ///
+    /// If `at` is past the end.
+    pub fn split_off(&mut self, at: usize) -> Body {
+        let tail = Body::from(&self[at..]);
+        self.truncate(at);
+        tail
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::new()
+    }
+}
+
+impl Deref for Body {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl DerefMut for Body {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
+}
+
+impl AsRef<[u8]> for Body {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for Body {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl Borrow<[u8]> for Body {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Self {
+        if bytes.len() > INLINE_BODY {
+            return Body(Repr::Heap(bytes.to_vec()));
+        }
+        let mut inline = [0; INLINE_BODY];
+        inline[..bytes.len()].copy_from_slice(bytes);
+        Body(Repr::Inline {
+            len: bytes.len() as u8,
+            bytes: inline,
+        })
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for Body {
+    fn from(bytes: [u8; N]) -> Self {
+        Body::from(&bytes[..])
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for Body {
+    fn from(bytes: &[u8; N]) -> Self {
+        Body::from(&bytes[..])
+    }
+}
+
+/// Takes over the `Vec`, without copying it inline.
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body(Repr::Heap(bytes))
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(bytes: Bytes) -> Self {
+        if bytes.len() <= INLINE_BODY {
+            Body::from(&bytes[..])
+        } else {
+            Body::from(Vec::from(bytes))
+        }
+    }
+}
+
+impl From<Body> for Vec<u8> {
+    fn from(body: Body) -> Self {
+        body.into_vec()
+    }
+}
+
+impl From<Body> for Bytes {
+    fn from(body: Body) -> Self {
+        Bytes::from(body.into_vec())
+    }
+}
+
+impl FromIterator<u8> for Body {
+    fn from_iter<I: IntoIterator<Item = u8>>(iter: I) -> Self {
+        let mut body = Body::new();
+        body.extend(iter);
+        body
+    }
+}
+
+impl Extend<u8> for Body {
+    fn extend<I: IntoIterator<Item = u8>>(&mut self, iter: I) {
+        for byte in iter {
+            self.push(byte);
+        }
+    }
+}
+
+impl<'a> Extend<&'a u8> for Body {
+    fn extend<I: IntoIterator<Item = &'a u8>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().copied());
+    }
+}
+
+impl<'a> IntoIterator for &'a Body {
+    type Item = &'a u8;
+    type IntoIter = std::slice::Iter<'a, u8>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl PartialEq for Body {
+    fn eq(&self, other: &Body) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Body {}
+
+impl PartialOrd for Body {
+    fn partial_cmp(&self, other: &Body) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Body {
+    fn cmp(&self, other: &Body) -> std::cmp::Ordering {
+        self.as_slice().cmp(other.as_slice())
+    }
+}
+
+impl Hash for Body {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_slice().hash(state);
+    }
+}
+
+macro_rules! eq_bytes {
+    ($($t:ty),*) => {$(
+        impl PartialEq<$t> for Body {
+            fn eq(&self, other: &$t) -> bool {
+                self.as_slice() == &other[..]
+            }
+        }
+
+        impl PartialEq<Body> for $t {
+            fn eq(&self, other: &Body) -> bool {
+                &self[..] == other.as_slice()
+            }
+        }
+    )*};
+}
+
+eq_bytes!([u8], &[u8], Vec<u8>);
+
+impl<const N: usize> PartialEq<[u8; N]> for Body {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        self.as_slice() == other
+    }
+}
+
+impl<const N: usize> PartialEq<&[u8; N]> for Body {
+    fn eq(&self, other: &&[u8; N]) -> bool {
+        self.as_slice() == *other
+    }
+}
+
+/// Why a body couldn't be encoded from, or decoded into, a structured
+/// value: a CBOR one (feature `cbor`) or a protobuf message (feature
+/// `protobuf`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodyError {
+    /// The body, or the value encoded, is over the `max` allowed.
+    TooLarge { len: usize, max: usize },
+    /// The value couldn't be encoded: the encoder's message.
+    Encode(String),
+    /// The body isn't the type encoded: the decoder's message, such as
+    /// serde's for a missing field.
+    Decode(String),
+    /// The body isn't the protobuf message asked for.
+    #[cfg(feature = "protobuf")]
+    Protobuf(prost::DecodeError),
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::TooLarge { len, max } => {
+                write!(f, "body too large: {len} bytes, at most {max}")
+            }
+            BodyError::Encode(e) => write!(f, "cannot encode body: {e}"),
+            BodyError::Decode(e) => write!(f, "cannot decode body: {e}"),
+            #[cfg(feature = "protobuf")]
+            BodyError::Protobuf(e) => write!(f, "cannot decode body: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "protobuf")]
+            BodyError::Protobuf(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_in_56_bytes() {
+        assert_eq!(std::mem::size_of::<Body>(), 56);
+    }
+
+    #[test]
+    fn short_bodies_stay_inline() {
+        for len in [0, 1, INLINE_BODY - 1, INLINE_BODY] {
+            let bytes: Vec<u8> = (0..len as u8).collect();
+            let body = Body::from(&bytes[..]);
+            assert!(body.is_inline(), "{len}");
+            assert_eq!(body, bytes);
+            assert_eq!(body.into_vec(), bytes);
+        }
+        assert!(!Body::from(&[0; INLINE_BODY + 1]).is_inline());
+    }
+
+    #[test]
+    fn behaves_as_a_vec() {
+        let mut body = Body::from(b"ab");
+        let mut vec = b"ab".to_vec();
+        for byte in 0..100 {
+            body.push(byte);
+            vec.push(byte);
+            assert_eq!(body, vec);
+        }
+        assert!(!body.is_inline());
+        assert_eq!(body.split_off(10), vec.split_off(10));
+        assert_eq!(body, vec);
+        body.truncate(3);
+        vec.truncate(3);
+        body.extend([7, 8]);
+        vec.extend([7, 8]);
+        assert_eq!(body, vec);
+        assert_eq!(format!("{body:?}"), format!("{vec:?}"));
+
+        let mut short = Body::from(b"hello");
+        short.truncate(10);
+        assert_eq!(short, b"hello");
+        short.truncate(2);
+        assert_eq!(short, *b"he");
+        short.clear();
+        assert!(short.is_empty());
+        assert_eq!(Body::from(vec.clone()), Body::from(&vec[..]));
+        let (a, b) = (Body::from(b"a"), Body::from(vec![b'b']));
+        assert!(a < b);
+    }
+}
@@ -0,0 +1,333 @@
+//! Holding off sends to a device that keeps failing.
+//!
+//! A [`CircuitBreaker`] counts each device's consecutive delivery
+//! failures. At `threshold` it opens the device's circuit: senders given
+//! the breaker fail at once with [`CircuitOpen`] instead of sending. After
+//! `cooldown` the circuit is half-open and lets one probe frame through; a
+//! success closes it, a failure opens it again. A probe whose outcome is
+//! never reported is followed by another after a further `cooldown`.
+//!
+//! A [`Requester`](crate::request::Requester) over a sender with a
+//! breaker reports its commands' outcomes itself. Otherwise report them
+//! with [`CircuitBreaker::success`] and [`CircuitBreaker::failure`], e.g.
+//! for frames an [`AckTracker`](crate::ack::AckTracker) gave up on.
+//! Changes of state go to a [`DeviceRegistry`], a callback and, with the
+//! `metrics` feature, `pipproto_circuit_transitions_total`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::registry::DeviceRegistry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CircuitState {
+    #[default]
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// A way a delivery can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// No ack or answer in time.
+    Timeout,
+    Nack,
+    /// The device answered with an ERROR frame.
+    ErrorFrame,
+    /// The transport failed to send.
+    Send,
+}
+
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// Consecutive failures that open a circuit.
+    pub threshold: u32,
+    /// Time a circuit stays open before a probe.
+    pub cooldown: Duration,
+    /// Failures that count; others neither count nor reset the count.
+    pub counts: Vec<Failure>,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            threshold: 5,
+            cooldown: Duration::from_secs(30),
+            counts: vec![Failure::Timeout, Failure::Nack],
+        }
+    }
+}
+
+/// A send refused because the device's circuit is open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitOpen {
+    pub device_id: [u8; 8],
+    /// Time until a probe may be sent.
+    pub retry_in: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "circuit open for {}, retry in {:?}",
+            String::from_utf8_lossy(&self.device_id),
+            self.retry_in
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    pub device_id: [u8; 8],
+    pub from: CircuitState,
+    pub to: CircuitState,
+}
+
+struct Circuit {
+    state: CircuitState,
+    failures: u32,
+    /// When it opened or, half-open, when the probe went out.
+    since: Instant,
+}
+
+type OnTransition = Arc<dyn Fn(&Transition) + Send + Sync>;
+
+struct Inner {
+    /// Devices with failures counted or a circuit not closed.
+    circuits: HashMap<[u8; 8], Circuit>,
+}
+
+/// Circuits per device. Clones share them.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    clock: Arc<dyn Clock>,
+    config: Arc<BreakerConfig>,
+    inner: Arc<Mutex<Inner>>,
+    registry: Option<DeviceRegistry>,
+    on_transition: Option<OnTransition>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: BreakerConfig, clock: Arc<dyn Clock>) -> Self {
+        CircuitBreaker {
+            clock,
+            config: Arc::new(BreakerConfig {
+                threshold: config.threshold.max(1),
+                ..config
+            }),
+            inner: Arc::new(Mutex::new(Inner {
+                circuits: HashMap::new(),
+            })),
+            registry: None,
+            on_transition: None,
+        }
+    }
+
+    /// Keep each known device's [`DeviceInfo::circuit`](crate::registry::DeviceInfo::circuit)
+    /// in `registry` up to date.
+    pub fn with_registry(mut self, registry: DeviceRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Call `f` on every change of state.
+    pub fn with_on_transition(mut self, f: impl Fn(&Transition) + Send + Sync + 'static) -> Self {
+        self.on_transition = Some(Arc::new(f));
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn state(&self, device_id: [u8; 8]) -> CircuitState {
+        self.lock()
+            .circuits
+            .get(&device_id)
+            .map_or(CircuitState::Closed, |c| c.state)
+    }
+
+    /// Whether a frame may be sent to `device_id` now. Past an open
+    /// circuit's cooldown this lets the probe through.
+    pub fn admit(&self, device_id: [u8; 8]) -> Result<(), CircuitOpen> {
+        let now = self.clock.now();
+        let transition = {
+            let mut inner = self.lock();
+            let Some(c) = inner.circuits.get_mut(&device_id) else {
+                return Ok(());
+            };
+            if c.state == CircuitState::Closed {
+                return Ok(());
+            }
+            let ready = c.since + self.config.cooldown;
+            if now < ready {
+                return Err(CircuitOpen {
+                    device_id,
+                    retry_in: ready - now,
+                });
+            }
+            c.since = now;
+            let from = std::mem::replace(&mut c.state, CircuitState::HalfOpen);
+            (from != CircuitState::HalfOpen).then_some(from)
+        };
+        if let Some(from) = transition {
+            self.notify(device_id, from, CircuitState::HalfOpen);
+        }
+        Ok(())
+    }
+
+    /// A frame to `device_id` got through.
+    pub fn success(&self, device_id: [u8; 8]) {
+        let removed = self.lock().circuits.remove(&device_id);
+        if let Some(c) = removed
+            && c.state != CircuitState::Closed
+        {
+            self.notify(device_id, c.state, CircuitState::Closed);
+        }
+    }
+
+    /// A frame to `device_id` did not get through.
+    pub fn failure(&self, device_id: [u8; 8], failure: Failure) {
+        if !self.config.counts.contains(&failure) {
+            return;
+        }
+        let now = self.clock.now();
+        let transition = {
+            let mut inner = self.lock();
+            let c = inner.circuits.entry(device_id).or_insert(Circuit {
+                state: CircuitState::Closed,
+                failures: 0,
+                since: now,
+            });
+            c.failures = c.failures.saturating_add(1);
+            let opens = match c.state {
+                CircuitState::Closed => c.failures >= self.config.threshold,
+                CircuitState::HalfOpen => true,
+                // an outcome of a frame sent before it opened
+                CircuitState::Open => false,
+            };
+            opens.then(|| {
+                c.since = now;
+                std::mem::replace(&mut c.state, CircuitState::Open)
+            })
+        };
+        if let Some(from) = transition {
+            self.notify(device_id, from, CircuitState::Open);
+        }
+    }
+
+    fn notify(&self, device_id: [u8; 8], from: CircuitState, to: CircuitState) {
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().circuit_transition(to.as_str());
+        if let Some(registry) = &self.registry {
+            registry.set_circuit(device_id, to);
+        }
+        if let Some(f) = &self.on_transition {
+            f(&Transition {
+                device_id,
+                from,
+                to,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const DEV: [u8; 8] = *b"DEV00001";
+    const SEC: Duration = Duration::from_secs(1);
+
+    fn breaker() -> (CircuitBreaker, ManualClock, Arc<Mutex<Vec<CircuitState>>>) {
+        let clock = ManualClock::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let config = BreakerConfig {
+            threshold: 3,
+            cooldown: 10 * SEC,
+            ..BreakerConfig::default()
+        };
+        let b = CircuitBreaker::with_clock(config, Arc::new(clock.clone())).with_on_transition({
+            let seen = seen.clone();
+            move |t| seen.lock().unwrap().push(t.to)
+        });
+        (b, clock, seen)
+    }
+
+    #[test]
+    fn opens_probes_and_closes() {
+        let (b, clock, seen) = breaker();
+        b.failure(DEV, Failure::Timeout);
+        b.failure(DEV, Failure::Nack);
+        b.success(DEV);
+        b.failure(DEV, Failure::Timeout);
+        b.failure(DEV, Failure::ErrorFrame);
+        b.failure(DEV, Failure::Timeout);
+        assert_eq!(b.state(DEV), CircuitState::Closed, "reset, then 2 counted");
+        b.failure(DEV, Failure::Timeout);
+        assert_eq!(b.state(DEV), CircuitState::Open);
+        assert_eq!(
+            b.admit(DEV),
+            Err(CircuitOpen {
+                device_id: DEV,
+                retry_in: 10 * SEC
+            })
+        );
+        assert!(b.admit(*b"DEV00002").is_ok());
+
+        clock.advance(10 * SEC);
+        assert!(b.admit(DEV).is_ok(), "the probe");
+        assert_eq!(b.state(DEV), CircuitState::HalfOpen);
+        assert!(b.admit(DEV).is_err(), "one probe at a time");
+        b.failure(DEV, Failure::Timeout);
+        assert_eq!(b.state(DEV), CircuitState::Open);
+
+        clock.advance(10 * SEC);
+        b.admit(DEV).unwrap();
+        b.success(DEV);
+        assert_eq!(b.state(DEV), CircuitState::Closed);
+        assert!(b.admit(DEV).is_ok());
+        use CircuitState::*;
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [Open, HalfOpen, Open, HalfOpen, Closed]
+        );
+    }
+
+    #[test]
+    fn an_unanswered_probe_is_followed_by_another() {
+        let (b, clock, _) = breaker();
+        for _ in 0..3 {
+            b.failure(DEV, Failure::Nack);
+        }
+        clock.advance(10 * SEC);
+        b.admit(DEV).unwrap();
+        clock.advance(9 * SEC);
+        assert_eq!(b.admit(DEV).unwrap_err().retry_in, SEC);
+        clock.advance(SEC);
+        assert!(b.admit(DEV).is_ok());
+        assert_eq!(b.state(DEV), CircuitState::HalfOpen);
+    }
+}
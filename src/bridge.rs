@@ -0,0 +1,354 @@
+//! Relay between two transports, e.g. a serial radio modem and TCP.
+//!
+//! Frames received on one side are sent out the other, never back out the
+//! side they arrived on. v1 frames carry no hop count, so bridges must not
+//! be wired into loops.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::transport::{Transport, TransportError};
+use crate::{FrameHeaderV1, FrameV1};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    AToB,
+    BToA,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    A,
+    B,
+}
+
+#[derive(Debug)]
+pub enum BridgeError {
+    /// A side disconnected and had no reconnect hook, or the hook failed.
+    Disconnected(Side, TransportError),
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BridgeError::Disconnected(side, e) => write!(f, "side {side:?} lost: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for BridgeError {}
+
+#[derive(Debug, Default)]
+pub struct DirectionStats {
+    forwarded: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl DirectionStats {
+    pub fn forwarded(&self) -> u64 {
+        self.forwarded.load(Ordering::Relaxed)
+    }
+
+    /// Frames filtered out, undecodable, or that could not be sent.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BridgeStats {
+    pub a_to_b: DirectionStats,
+    pub b_to_a: DirectionStats,
+}
+
+impl BridgeStats {
+    pub fn direction(&self, dir: Direction) -> &DirectionStats {
+        match dir {
+            Direction::AToB => &self.a_to_b,
+            Direction::BToA => &self.b_to_a,
+        }
+    }
+}
+
+type Filter = Box<dyn Fn(&FrameHeaderV1) -> bool + Send>;
+type ReconnectFuture<T> = Pin<Box<dyn Future<Output = Result<T, TransportError>> + Send>>;
+type Reconnect<T> = Box<dyn FnMut() -> ReconnectFuture<T> + Send>;
+
+struct Endpoint<T> {
+    transport: T,
+    reconnect: Option<Reconnect<T>>,
+}
+
+impl<T: Transport> Endpoint<T> {
+    /// Replace the transport via the reconnect hook, once.
+    async fn reconnect(&mut self, side: Side, cause: TransportError) -> Result<(), BridgeError> {
+        let Some(hook) = self.reconnect.as_mut() else {
+            return Err(BridgeError::Disconnected(side, cause));
+        };
+        self.transport = hook()
+            .await
+            .map_err(|e| BridgeError::Disconnected(side, e))?;
+        Ok(())
+    }
+}
+
+pub struct Bridge<A, B> {
+    a: Endpoint<A>,
+    b: Endpoint<B>,
+    filter_a_to_b: Option<Filter>,
+    filter_b_to_a: Option<Filter>,
+    stats: Arc<BridgeStats>,
+}
+
+fn is_link_loss(e: &TransportError) -> bool {
+    matches!(e, TransportError::Disconnected | TransportError::Io(_))
+}
+
+impl<A: Transport, B: Transport> Bridge<A, B> {
+    pub fn new(a: A, b: B) -> Self {
+        Bridge {
+            a: Endpoint {
+                transport: a,
+                reconnect: None,
+            },
+            b: Endpoint {
+                transport: b,
+                reconnect: None,
+            },
+            filter_a_to_b: None,
+            filter_b_to_a: None,
+            stats: Arc::default(),
+        }
+    }
+
+    /// Only forward frames in `dir` whose header satisfies `pred`.
+    pub fn with_filter<F>(mut self, dir: Direction, pred: F) -> Self
+    where
+        F: Fn(&FrameHeaderV1) -> bool + Send + 'static,
+    {
+        match dir {
+            Direction::AToB => self.filter_a_to_b = Some(Box::new(pred)),
+            Direction::BToA => self.filter_b_to_a = Some(Box::new(pred)),
+        }
+        self
+    }
+
+    /// Called to replace side A after it disconnects.
+    pub fn with_reconnect_a<F, Fut>(mut self, mut f: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<A, TransportError>> + Send + 'static,
+    {
+        self.a.reconnect = Some(Box::new(move || Box::pin(f())));
+        self
+    }
+
+    /// Called to replace side B after it disconnects.
+    pub fn with_reconnect_b<F, Fut>(mut self, mut f: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<B, TransportError>> + Send + 'static,
+    {
+        self.b.reconnect = Some(Box::new(move || Box::pin(f())));
+        self
+    }
+
+    /// Shared counters, readable while [`Bridge::run`] is going.
+    pub fn stats(&self) -> Arc<BridgeStats> {
+        self.stats.clone()
+    }
+
+    /// Forward in both directions until a side is lost for good.
+    pub async fn run(&mut self) -> Result<(), BridgeError> {
+        loop {
+            tokio::select! {
+                r = self.a.transport.recv() => match r {
+                    Ok(frame) => self.forward_a_to_b(frame).await?,
+                    Err(e) if is_link_loss(&e) => self.a.reconnect(Side::A, e).await?,
+                    Err(TransportError::Protocol(_)) => self.count_drop(Direction::AToB),
+                    Err(_) => {}
+                },
+                r = self.b.transport.recv() => match r {
+                    Ok(frame) => self.forward_b_to_a(frame).await?,
+                    Err(e) if is_link_loss(&e) => self.b.reconnect(Side::B, e).await?,
+                    Err(TransportError::Protocol(_)) => self.count_drop(Direction::BToA),
+                    Err(_) => {}
+                },
+            }
+        }
+    }
+
+    fn count_drop(&self, dir: Direction) {
+        self.stats
+            .direction(dir)
+            .dropped
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    async fn forward_a_to_b(&mut self, frame: FrameV1) -> Result<(), BridgeError> {
+        let keep = self.filter_a_to_b.as_ref().is_none_or(|f| f(&frame.header));
+        let stats = &self.stats.a_to_b;
+        let fut = forward(keep, &frame, &mut self.b, Side::B, stats);
+        #[cfg(feature = "tracing")]
+        let fut = tracing::Instrument::instrument(
+            fut,
+            crate::trace::frame_span!("forward frame", &frame.header),
+        );
+        fut.await
+    }
+
+    async fn forward_b_to_a(&mut self, frame: FrameV1) -> Result<(), BridgeError> {
+        let keep = self.filter_b_to_a.as_ref().is_none_or(|f| f(&frame.header));
+        let stats = &self.stats.b_to_a;
+        let fut = forward(keep, &frame, &mut self.a, Side::A, stats);
+        #[cfg(feature = "tracing")]
+        let fut = tracing::Instrument::instrument(
+            fut,
+            crate::trace::frame_span!("forward frame", &frame.header),
+        );
+        fut.await
+    }
+}
+
+/// Send `frame` out `to`, reconnecting and retrying once if the link is down.
+async fn forward<T: Transport>(
+    keep: bool,
+    frame: &FrameV1,
+    to: &mut Endpoint<T>,
+    side: Side,
+    stats: &DirectionStats,
+) -> Result<(), BridgeError> {
+    if !keep {
+        stats.dropped.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    let mut result = to.transport.send(frame).await;
+    if let Err(e) = result {
+        if !is_link_loss(&e) {
+            stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        to.reconnect(side, e).await?;
+        result = to.transport.send(frame).await;
+    }
+
+    let counter = if result.is_ok() {
+        &stats.forwarded
+    } else {
+        &stats.dropped
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MsgType;
+    use crate::transport::tests::frame;
+    use crate::transport::{LoopbackTransport, loopback_pair};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    async fn recv(t: &mut LoopbackTransport) -> FrameV1 {
+        tokio::time::timeout(Duration::from_secs(1), t.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn forwards_both_ways() {
+        let (a, mut radio) = loopback_pair();
+        let (b, mut cloud) = loopback_pair();
+        let mut bridge = Bridge::new(a, b);
+        let stats = bridge.stats();
+        let task = tokio::spawn(async move { bridge.run().await });
+
+        radio.send(&frame(1)).await.unwrap();
+        assert_eq!(recv(&mut cloud).await, frame(1));
+        cloud.send(&frame(2)).await.unwrap();
+        assert_eq!(recv(&mut radio).await, frame(2));
+
+        assert_eq!(stats.a_to_b.forwarded(), 1);
+        assert_eq!(stats.b_to_a.forwarded(), 1);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn filter_drops_per_direction() {
+        let (a, mut radio) = loopback_pair();
+        let (b, mut cloud) = loopback_pair();
+        let mut bridge =
+            Bridge::new(a, b).with_filter(Direction::AToB, |h| h.msg_type != MsgType::Event);
+        let stats = bridge.stats();
+        let task = tokio::spawn(async move { bridge.run().await });
+
+        let mut command = frame(2);
+        command.header.msg_type = MsgType::Command;
+        radio.send(&frame(1)).await.unwrap();
+        radio.send(&command).await.unwrap();
+
+        assert_eq!(recv(&mut cloud).await, command);
+        assert_eq!(stats.a_to_b.dropped(), 1);
+        assert_eq!(stats.a_to_b.forwarded(), 1);
+
+        // events still flow the other way
+        cloud.send(&frame(3)).await.unwrap();
+        assert_eq!(recv(&mut radio).await, frame(3));
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn never_echoes_back() {
+        let (a, mut radio) = loopback_pair();
+        let (b, mut cloud) = loopback_pair();
+        let mut bridge = Bridge::new(a, b);
+        let task = tokio::spawn(async move { bridge.run().await });
+
+        radio.send(&frame(1)).await.unwrap();
+        assert_eq!(recv(&mut cloud).await, frame(1));
+        let echoed = tokio::time::timeout(Duration::from_millis(20), radio.recv()).await;
+        assert!(echoed.is_err());
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn reconnects_lost_side() {
+        let (a, mut radio) = loopback_pair();
+        let (b, cloud) = loopback_pair();
+        let (b2, mut cloud2) = loopback_pair();
+        let spare = Arc::new(Mutex::new(Some(b2)));
+
+        let mut bridge = Bridge::new(a, b).with_reconnect_b(move || {
+            let next = spare.lock().unwrap().take();
+            async move { next.ok_or(TransportError::Disconnected) }
+        });
+        let task = tokio::spawn(async move { bridge.run().await });
+
+        drop(cloud);
+        radio.send(&frame(1)).await.unwrap();
+        assert_eq!(recv(&mut cloud2).await, frame(1));
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn lost_side_without_hook_ends_run() {
+        let (a, radio) = loopback_pair();
+        let (b, _cloud) = loopback_pair();
+        let mut bridge = Bridge::new(a, b);
+
+        drop(radio);
+        assert!(matches!(
+            bridge.run().await,
+            Err(BridgeError::Disconnected(
+                Side::A,
+                TransportError::Disconnected
+            ))
+        ));
+    }
+}
@@ -0,0 +1,392 @@
+//! Fanning received frames out to the consumers that asked for them.
+//!
+//! An [`EventBus`] holds subscriptions, each a [`Filter`] on message type,
+//! device (exact, prefix or a group of ids), body prefix (an event id,
+//! say) and optionally a [`FrameFilter`] expression, and hands every
+//! published frame to each subscription it matches. Subscriptions come and
+//! go at runtime: [`EventBus::subscribe`] adds one, dropping the
+//! [`Subscription`] removes it. A published frame is shared: every
+//! subscription it matches gets the same [`Arc<RxFrame>`], so the frame is
+//! copied once however many consumers there are.
+//!
+//! Each subscription buffers at most `capacity` frames. A subscriber that
+//! falls further behind loses the oldest and is told how many with a
+//! [`Lagged`] before the frames that follow, so a slow consumer costs
+//! bounded memory and never holds up the others. Once every handle on the
+//! bus is dropped, subscriptions end after the frames they still buffer.
+//!
+//! [`Dispatcher::with_bus`](crate::dispatch::Dispatcher::with_bus)
+//! publishes the EVENTs it admits.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use futures::Stream;
+
+use crate::MsgType;
+use crate::dispatch::DeviceMatch;
+use crate::filter::FrameFilter;
+use crate::transport::RxFrame;
+
+/// Which frames a subscription receives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    msg_type: Option<MsgType>,
+    device: DeviceMatch,
+    group: Option<BTreeSet<[u8; 8]>>,
+    body_prefix: Vec<u8>,
+    expr: Option<FrameFilter>,
+}
+
+impl Filter {
+    /// Every frame.
+    pub fn any() -> Self {
+        Filter {
+            msg_type: None,
+            device: DeviceMatch::Any,
+            group: None,
+            body_prefix: Vec::new(),
+            expr: None,
+        }
+    }
+
+    /// Frames of `msg_type`.
+    pub fn new(msg_type: MsgType) -> Self {
+        Filter {
+            msg_type: Some(msg_type),
+            ..Filter::any()
+        }
+    }
+
+    pub fn with_device(mut self, device_id: [u8; 8]) -> Self {
+        self.device = DeviceMatch::Exact(device_id);
+        self
+    }
+
+    pub fn with_device_prefix(mut self, prefix: &[u8]) -> Self {
+        self.device = DeviceMatch::Prefix(prefix.to_vec());
+        self
+    }
+
+    /// Only frames from these devices.
+    pub fn with_group(mut self, devices: impl IntoIterator<Item = [u8; 8]>) -> Self {
+        self.group = Some(devices.into_iter().collect());
+        self
+    }
+
+    pub fn with_body_prefix(mut self, prefix: &[u8]) -> Self {
+        self.body_prefix = prefix.to_vec();
+        self
+    }
+
+    /// Only frames `expr` matches too.
+    pub fn with_expr(mut self, expr: FrameFilter) -> Self {
+        self.expr = Some(expr);
+        self
+    }
+
+    pub fn matches(&self, frame: &RxFrame) -> bool {
+        let device_id = &frame.header.device_id;
+        self.msg_type.is_none_or(|t| t == frame.header.msg_type)
+            && self.device.matches(device_id)
+            && self.group.as_ref().is_none_or(|g| g.contains(device_id))
+            && frame.body.starts_with(&self.body_prefix)
+            && self.expr.as_ref().is_none_or(|e| e.matches(frame))
+    }
+}
+
+/// Frames a subscriber missed by falling behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
+impl fmt::Display for Lagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "subscriber lagged, {} frames dropped", self.0)
+    }
+}
+
+impl std::error::Error for Lagged {}
+
+struct Buffer {
+    frames: VecDeque<Arc<RxFrame>>,
+    lagged: u64,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+struct Subscriber {
+    filter: Filter,
+    buffer: Arc<Mutex<Buffer>>,
+}
+
+struct Inner {
+    capacity: usize,
+    next_id: AtomicU64,
+    /// Live [`EventBus`] handles; the last one dropped closes the bus.
+    handles: AtomicUsize,
+    subscribers: Mutex<HashMap<u64, Subscriber>>,
+}
+
+/// Cloning gives another handle on the same subscriptions.
+pub struct EventBus {
+    inner: Arc<Inner>,
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl EventBus {
+    /// Subscriptions buffer up to `capacity` frames each.
+    pub fn new(capacity: usize) -> Self {
+        EventBus {
+            inner: Arc::new(Inner {
+                capacity: capacity.max(1),
+                next_id: AtomicU64::new(0),
+                handles: AtomicUsize::new(1),
+                subscribers: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn subscribe(&self, filter: Filter) -> Subscription {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let buffer = Arc::new(Mutex::new(Buffer {
+            frames: VecDeque::new(),
+            lagged: 0,
+            closed: false,
+            waker: None,
+        }));
+        lock(&self.inner.subscribers).insert(
+            id,
+            Subscriber {
+                filter,
+                buffer: buffer.clone(),
+            },
+        );
+        Subscription {
+            id,
+            bus: self.inner.clone(),
+            buffer,
+        }
+    }
+
+    /// Hand `frame` to every matching subscription. Returns how many.
+    pub fn publish(&self, frame: &RxFrame) -> usize {
+        let subscribers = lock(&self.inner.subscribers);
+        let mut shared = None;
+        let mut delivered = 0;
+        for s in subscribers.values().filter(|s| s.filter.matches(frame)) {
+            let shared = shared.get_or_insert_with(|| Arc::new(frame.clone()));
+            let mut buffer = lock(&s.buffer);
+            if buffer.frames.len() >= self.inner.capacity {
+                buffer.frames.pop_front();
+                buffer.lagged += 1;
+            }
+            buffer.frames.push_back(shared.clone());
+            if let Some(w) = buffer.waker.take() {
+                w.wake();
+            }
+            delivered += 1;
+        }
+        delivered
+    }
+
+    pub fn subscribers(&self) -> usize {
+        lock(&self.inner.subscribers).len()
+    }
+}
+
+impl Clone for EventBus {
+    fn clone(&self) -> Self {
+        self.inner.handles.fetch_add(1, Ordering::Relaxed);
+        EventBus {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Drop for EventBus {
+    fn drop(&mut self) {
+        if self.inner.handles.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return;
+        }
+        for s in lock(&self.inner.subscribers).values() {
+            let mut buffer = lock(&s.buffer);
+            buffer.closed = true;
+            if let Some(w) = buffer.waker.take() {
+                w.wake();
+            }
+        }
+    }
+}
+
+/// A stream of the frames matching one filter, preceded by a [`Lagged`]
+/// wherever some were dropped. Dropping it unsubscribes; it ends once the
+/// bus is dropped and its buffered frames are taken.
+pub struct Subscription {
+    id: u64,
+    bus: Arc<Inner>,
+    buffer: Arc<Mutex<Buffer>>,
+}
+
+impl Subscription {
+    /// The next frame or gap, if one is buffered.
+    pub fn try_next(&mut self) -> Option<Result<Arc<RxFrame>, Lagged>> {
+        lock(&self.buffer).take()
+    }
+
+    /// Every handle on the bus is gone, so no more frames will come.
+    pub fn is_closed(&self) -> bool {
+        lock(&self.buffer).closed
+    }
+}
+
+impl Buffer {
+    fn take(&mut self) -> Option<Result<Arc<RxFrame>, Lagged>> {
+        if self.lagged > 0 {
+            return Some(Err(Lagged(std::mem::take(&mut self.lagged))));
+        }
+        self.frames.pop_front().map(Ok)
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Result<Arc<RxFrame>, Lagged>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut buffer = lock(&self.buffer);
+        match buffer.take() {
+            Some(item) => Poll::Ready(Some(item)),
+            None if buffer.closed => Poll::Ready(None),
+            None => {
+                buffer.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        lock(&self.bus.subscribers).remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{PeerAddr, TransportId};
+    use futures::StreamExt;
+
+    fn rx(msg_type: MsgType, device_id: &[u8; 8], body: &[u8]) -> RxFrame {
+        let mut f = crate::transport::tests::frame(1);
+        f.header.msg_type = msg_type;
+        f.header.device_id = *device_id;
+        f.body = body.into();
+        RxFrame::new(f, PeerAddr::Opaque("test".into()), TransportId::UNASSIGNED)
+    }
+
+    fn drain(s: &mut Subscription) -> Vec<Result<Vec<u8>, Lagged>> {
+        std::iter::from_fn(|| s.try_next())
+            .map(|r| r.map(|f| f.body.to_vec()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn overlapping_subscriptions_each_get_the_frame() {
+        let bus = EventBus::new(8);
+        let mut all = bus.subscribe(Filter::new(MsgType::Event));
+        let mut kitchen = bus.subscribe(Filter::new(MsgType::Event).with_device_prefix(b"KIT"));
+        let mut group = bus.subscribe(Filter::any().with_group([*b"KIT00001", *b"HALL0001"]));
+        let mut alarms = bus.subscribe(Filter::new(MsgType::Event).with_body_prefix(&[0x07]));
+
+        assert_eq!(bus.publish(&rx(MsgType::Event, b"KIT00001", &[0x07])), 4);
+        assert_eq!(bus.publish(&rx(MsgType::Event, b"KIT00002", &[0x01])), 2);
+        assert_eq!(bus.publish(&rx(MsgType::Command, b"HALL0001", &[0x01])), 1);
+        assert_eq!(bus.publish(&rx(MsgType::Event, b"GARAGE01", &[0x02])), 1);
+
+        let first = all.next().await.unwrap().unwrap();
+        assert_eq!(first.header.device_id, *b"KIT00001");
+        assert_eq!(drain(&mut all), [Ok(vec![0x01]), Ok(vec![0x02])]);
+        assert_eq!(drain(&mut kitchen), [Ok(vec![0x07]), Ok(vec![0x01])]);
+        assert_eq!(drain(&mut group), [Ok(vec![0x07]), Ok(vec![0x01])]);
+        assert_eq!(drain(&mut alarms), [Ok(vec![0x07])]);
+
+        drop(kitchen);
+        assert_eq!(bus.subscribers(), 3);
+        assert_eq!(bus.publish(&rx(MsgType::Event, b"KIT00003", &[0x01])), 1);
+    }
+
+    #[test]
+    fn expressions_narrow_a_subscription() {
+        let bus = EventBus::new(8);
+        let expr = "type == command || device == 4b49543030303031"
+            .parse()
+            .unwrap();
+        let mut sub = bus.subscribe(Filter::any().with_device_prefix(b"KIT").with_expr(expr));
+        bus.publish(&rx(MsgType::Event, b"KIT00001", &[1]));
+        bus.publish(&rx(MsgType::Event, b"KIT00002", &[2]));
+        bus.publish(&rx(MsgType::Command, b"KIT00002", &[3]));
+        bus.publish(&rx(MsgType::Command, b"HALL0001", &[4]));
+        assert_eq!(drain(&mut sub), [Ok(vec![1]), Ok(vec![3])]);
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_lags_without_holding_others_up() {
+        let bus = EventBus::new(2);
+        let mut slow = bus.subscribe(Filter::any());
+        let mut fast = bus.subscribe(Filter::any());
+        let reader = tokio::spawn(async move {
+            let mut got = Vec::new();
+            while let Some(item) = fast.next().await {
+                got.push(item.unwrap().body[0]);
+            }
+            got
+        });
+        for i in 0..5 {
+            bus.publish(&rx(MsgType::Event, b"DEV00001", &[i]));
+            tokio::task::yield_now().await;
+        }
+        drop(bus);
+        assert_eq!(reader.await.unwrap(), [0, 1, 2, 3, 4]);
+        assert!(slow.is_closed());
+        assert_eq!(drain(&mut slow), [Err(Lagged(3)), Ok(vec![3]), Ok(vec![4])]);
+        assert!(slow.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn subscriptions_share_one_copy_and_end_with_the_last_handle() {
+        let bus = EventBus::new(4);
+        let publisher = bus.clone();
+        let mut a = bus.subscribe(Filter::any());
+        let mut b = bus.subscribe(Filter::new(MsgType::Event));
+        publisher.publish(&rx(MsgType::Event, b"DEV00001", &[1]));
+        let (x, y) = (
+            a.try_next().unwrap().unwrap(),
+            b.try_next().unwrap().unwrap(),
+        );
+        assert!(Arc::ptr_eq(&x, &y));
+
+        let waiting = tokio::spawn(async move {
+            let mut got = Vec::new();
+            while let Some(item) = a.next().await {
+                got.push(item.unwrap().body[0]);
+            }
+            got
+        });
+        tokio::task::yield_now().await;
+        drop(bus);
+        assert!(!b.is_closed());
+        publisher.publish(&rx(MsgType::Event, b"DEV00001", &[2]));
+        drop(publisher);
+        assert!(b.is_closed());
+        assert_eq!(b.next().await.unwrap().unwrap().body, [2]);
+        assert!(b.next().await.is_none());
+        assert_eq!(waiting.await.unwrap(), [2]);
+    }
+}
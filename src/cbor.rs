@@ -0,0 +1,233 @@
+//! CBOR bodies (feature `cbor`), for structured payloads.
+//!
+//! [`FrameV1::from_cbor_body`] encodes a serde value as a frame's body,
+//! and [`FrameV1::body_as_cbor`] decodes it again, both within
+//! [`DEFAULT_MAX_CBOR_BODY`]; a [`CborConfig`] sets another limit. A body
+//! over the limit is refused before it is decoded, and a body must hold
+//! exactly one CBOR value. v1 frames have no content-type byte, so which
+//! bodies are CBOR is up to the application.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{BodyError, Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+/// The largest body [`CborConfig::new`] encodes or decodes.
+pub const DEFAULT_MAX_CBOR_BODY: usize = 16 * 1024;
+
+/// How CBOR bodies are encoded and decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CborConfig {
+    max_body: usize,
+}
+
+impl CborConfig {
+    pub fn new() -> Self {
+        CborConfig {
+            max_body: DEFAULT_MAX_CBOR_BODY,
+        }
+    }
+
+    /// Refuse bodies over `max` bytes, either way.
+    pub fn with_max_body(mut self, max: usize) -> Self {
+        self.max_body = max;
+        self
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+
+    /// `value` as CBOR.
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, BodyError> {
+        let mut body = Vec::new();
+        ciborium::into_writer(value, &mut body).map_err(|e| match e {
+            ciborium::ser::Error::Io(e) => BodyError::Encode(e.to_string()),
+            ciborium::ser::Error::Value(e) => BodyError::Encode(e),
+        })?;
+        self.check(body.len())?;
+        Ok(body)
+    }
+
+    /// The value `body` holds.
+    pub fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, BodyError> {
+        self.check(body.len())?;
+        let mut rest = body;
+        let value = ciborium::from_reader(&mut rest).map_err(|e| {
+            BodyError::Decode(match e {
+                ciborium::de::Error::Io(_) => "truncated CBOR".to_string(),
+                ciborium::de::Error::Syntax(at) => format!("bad CBOR at byte {at}"),
+                ciborium::de::Error::Semantic(Some(at), e) => format!("{e} (at byte {at})"),
+                ciborium::de::Error::Semantic(None, e) => e,
+                ciborium::de::Error::RecursionLimitExceeded => "CBOR nested too deeply".into(),
+            })
+        })?;
+        if !rest.is_empty() {
+            let at = body.len() - rest.len();
+            return Err(BodyError::Decode(format!(
+                "trailing bytes after the CBOR value at byte {at}"
+            )));
+        }
+        Ok(value)
+    }
+
+    fn check(&self, len: usize) -> Result<(), BodyError> {
+        if len > self.max_body {
+            return Err(BodyError::TooLarge {
+                len,
+                max: self.max_body,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for CborConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameV1 {
+    /// A frame, without flags, whose body is `value` as CBOR.
+    pub fn from_cbor_body<T: Serialize + ?Sized>(
+        device_id: [u8; 8],
+        counter: u64,
+        msg_type: MsgType,
+        value: &T,
+    ) -> Result<FrameV1, BodyError> {
+        Ok(FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type,
+                flags: Flags::default(),
+                device_id,
+                counter,
+            },
+            body: CborConfig::new().encode(value)?.into(),
+        })
+    }
+
+    /// The value the body holds as CBOR.
+    pub fn body_as_cbor<T: DeserializeOwned>(&self) -> Result<T, BodyError> {
+        CborConfig::new().decode(&self.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        value: f64,
+        position: Option<Position>,
+        tags: Vec<String>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Position {
+        lat: f32,
+        lon: f32,
+        calibration: BTreeMap<u16, i64>,
+    }
+
+    fn reading() -> Reading {
+        Reading {
+            sensor: "t1".to_string(),
+            value: 21.5,
+            position: Some(Position {
+                lat: 45.5,
+                lon: 9.25,
+                calibration: BTreeMap::from([(1, -3), (300, 70_000)]),
+            }),
+            tags: vec!["roof".to_string(), "north".to_string()],
+        }
+    }
+
+    #[test]
+    fn nested_structs_round_trip() {
+        let frame = FrameV1::from_cbor_body(*b"DEV00001", 7, MsgType::Event, &reading()).unwrap();
+        assert_eq!(frame.header.msg_type, MsgType::Event);
+        assert_eq!(frame.header.counter, 7);
+        assert!(!frame.header.flags.ack_required());
+        let back = FrameV1::decode(&frame.encode()).unwrap();
+        assert_eq!(back.body_as_cbor::<Reading>().unwrap(), reading());
+    }
+
+    #[test]
+    fn maps_keep_non_string_keys() {
+        let map = HashMap::from([((1u8, 2u8), vec![true]), ((3, 4), vec![false, true])]);
+        let frame = FrameV1::from_cbor_body(*b"DEV00001", 1, MsgType::Command, &map).unwrap();
+        assert_eq!(
+            frame.body_as_cbor::<HashMap<(u8, u8), Vec<bool>>>(),
+            Ok(map)
+        );
+
+        let map = BTreeMap::from([(-1i32, "minus"), (i32::MAX, "max")]);
+        let frame = FrameV1::from_cbor_body(*b"DEV00001", 2, MsgType::Command, &map).unwrap();
+        // a CBOR map with integer keys, not strings
+        assert_eq!(frame.body[0], 0xa2);
+        assert_eq!(frame.body[1], 0x20);
+        assert_eq!(
+            frame.body_as_cbor::<BTreeMap<i32, String>>().unwrap()[&-1],
+            "minus"
+        );
+    }
+
+    #[test]
+    fn truncated_and_wrong_bodies_are_decode_errors() {
+        let mut frame =
+            FrameV1::from_cbor_body(*b"DEV00001", 7, MsgType::Event, &reading()).unwrap();
+        let body = frame.body.to_vec();
+        frame.body = body[..body.len() - 3].into();
+        assert_eq!(
+            frame.body_as_cbor::<Reading>(),
+            Err(BodyError::Decode("truncated CBOR".to_string()))
+        );
+
+        let Err(BodyError::Decode(message)) = CborConfig::new().decode::<Position>(&body) else {
+            panic!("a Reading decoded as a Position");
+        };
+        assert!(message.contains("lat"), "{message}");
+
+        let mut long = body.clone();
+        long.push(0);
+        let e = CborConfig::new().decode::<Reading>(&long).unwrap_err();
+        assert_eq!(
+            e,
+            BodyError::Decode(format!(
+                "trailing bytes after the CBOR value at byte {}",
+                body.len()
+            ))
+        );
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_refused() {
+        let body = CborConfig::new().encode(&reading()).unwrap();
+        let config = CborConfig::new().with_max_body(8);
+        let too_large = BodyError::TooLarge {
+            len: body.len(),
+            max: 8,
+        };
+        assert_eq!(config.encode(&reading()), Err(too_large.clone()));
+        assert_eq!(config.decode::<Reading>(&body), Err(too_large));
+        assert_eq!(config.decode::<u8>(&config.encode(&7u8).unwrap()), Ok(7));
+
+        let big = vec![0u8; DEFAULT_MAX_CBOR_BODY];
+        let e = FrameV1::from_cbor_body(*b"DEV00001", 1, MsgType::Event, &big).unwrap_err();
+        assert!(matches!(
+            e,
+            BodyError::TooLarge {
+                max: DEFAULT_MAX_CBOR_BODY,
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,195 @@
+//! A C header of protocol constants, generated from this crate's
+//! definitions, for firmware that implements the protocol natively.
+//!
+//! [`header`] writes `pipproto_defs.h`: the magic bytes, version, header
+//! length, each header field's offset and size (as
+//! [`annotate`](crate::annotate) lays them out), the message types, the
+//! flag bits, the [`DecodeError`] codes and the length limits. It has no
+//! includes and only `#define`s, so it builds in any C dialect. The one
+//! committed at `include/pipproto_defs.h` is [`header`]'s output.
+
+use std::fmt::Write as _;
+
+use crate::annotate::FIELDS;
+use crate::codec::{DEFAULT_MAX_FRAME_LEN, PREFIX_LEN};
+use crate::datagram::{DEFAULT_MTU, ENTRY_PREFIX, MAX_DATAGRAM, PACK_MAGIC};
+use crate::{DecodeError, Flags, HEADER_LEN_V1, MAGIC, MsgType, VERSION_V1};
+
+/// Every [`DecodeError`], in [`DecodeError::code`] order.
+pub(crate) const DECODE_ERRORS: [DecodeError; 7] = [
+    DecodeError::TooShort,
+    DecodeError::BadMagic,
+    DecodeError::BadVersion(0),
+    DecodeError::UnknownMsgType(0),
+    DecodeError::ReservedFlags(0),
+    DecodeError::BadDeviceIdBytes,
+    DecodeError::BadCounterBytes,
+];
+
+/// The C name of a header field in [`FIELDS`].
+fn field_name(name: &str) -> String {
+    match name {
+        "type" => "MSG_TYPE".to_string(),
+        name => name.to_uppercase(),
+    }
+}
+
+fn define(out: &mut String, name: &str, value: impl std::fmt::Display, comment: &str) {
+    let line = format!("#define {name} {value}");
+    if comment.is_empty() {
+        let _ = writeln!(out, "{line}");
+    } else {
+        let _ = writeln!(out, "{line:<44} /* {comment} */");
+    }
+}
+
+fn hex(out: &mut String, name: &str, value: u8, comment: &str) {
+    define(out, name, format_args!("0x{value:02x}u"), comment);
+}
+
+fn uint(out: &mut String, name: &str, value: usize, comment: &str) {
+    define(out, name, format_args!("{value}u"), comment);
+}
+
+/// `pipproto_defs.h`.
+pub fn header() -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "/* PiProto v1 constants, generated by pipproto {} (`pipproto c-defs`).",
+        env!("CARGO_PKG_VERSION")
+    );
+    out.push_str(" * Do not edit: regenerate it from the crate instead. */\n\n");
+    out.push_str("#ifndef PIPPROTO_DEFS_H\n#define PIPPROTO_DEFS_H\n\n");
+
+    out.push_str("/* What frames, and packed datagrams (PP_PACK_MAGIC), start with. */\n");
+    for (name, magic) in [("PP_MAGIC", MAGIC), ("PP_PACK_MAGIC", PACK_MAGIC)] {
+        for (i, b) in magic.into_iter().enumerate() {
+            hex(
+                &mut out,
+                &format!("{name}_{i}"),
+                b,
+                &format!("'{}'", b as char),
+            );
+        }
+    }
+    uint(&mut out, "PP_MAGIC_LEN", MAGIC.len(), "");
+    out.push('\n');
+
+    hex(&mut out, "PP_VERSION_V1", VERSION_V1, "");
+    uint(
+        &mut out,
+        "PP_HEADER_LEN_V1",
+        HEADER_LEN_V1,
+        "a frame is this plus its body",
+    );
+    out.push('\n');
+
+    out.push_str("/* Header fields: byte offset and size. Integers are big-endian. */\n");
+    for (name, range) in FIELDS {
+        let name = field_name(name);
+        uint(&mut out, &format!("PP_{name}_OFFSET"), range.start, "");
+        uint(&mut out, &format!("PP_{name}_SIZE"), range.len(), "");
+    }
+    uint(&mut out, "PP_BODY_OFFSET", HEADER_LEN_V1, "");
+    out.push('\n');
+
+    out.push_str("/* msg_type values; any other is an error. */\n");
+    for t in (0..=u8::MAX).filter_map(MsgType::from_u8) {
+        let name = format!("PP_MSG_TYPE_{}", t.as_str().to_uppercase());
+        hex(&mut out, &name, t as u8, "");
+    }
+    out.push('\n');
+
+    out.push_str("/* Flag bits; reserved bits must be zero. */\n");
+    hex(&mut out, "PP_FLAG_ACK_REQUIRED", Flags::ACK_REQUIRED, "");
+    hex(&mut out, "PP_FLAGS_RESERVED", !Flags::ACK_REQUIRED, "");
+    out.push('\n');
+
+    out.push_str("/* Why a frame doesn't decode, and the offset of the field at fault. */\n");
+    define(&mut out, "PP_ERR_OK", 0, "");
+    for e in DECODE_ERRORS {
+        // the message without the sample value
+        let message = e.to_string();
+        let message = message.split(':').next().unwrap_or_default();
+        let comment = match e.offset() {
+            Some(offset) => format!("{message}, at offset {offset}"),
+            None => message.to_string(),
+        };
+        let name = format!("PP_ERR_{}", e.kind().to_uppercase());
+        define(&mut out, &name, e.code(), &comment);
+    }
+    out.push('\n');
+
+    out.push_str("/* Length limits, in bytes. */\n");
+    let limits = [
+        ("PP_MIN_FRAME_LEN", HEADER_LEN_V1, "an empty body"),
+        (
+            "PP_STREAM_PREFIX_LEN",
+            PREFIX_LEN,
+            "big-endian, before each frame",
+        ),
+        (
+            "PP_STREAM_MAX_FRAME_LEN",
+            DEFAULT_MAX_FRAME_LEN,
+            "a stream decoder's default",
+        ),
+        (
+            "PP_MAX_DATAGRAM",
+            MAX_DATAGRAM,
+            "largest UDP payload over IPv4",
+        ),
+        (
+            "PP_MAX_DATAGRAM_BODY",
+            MAX_DATAGRAM - HEADER_LEN_V1,
+            "in one datagram",
+        ),
+        (
+            "PP_DEFAULT_MTU",
+            DEFAULT_MTU,
+            "payload budget on unknown paths",
+        ),
+        (
+            "PP_PACK_ENTRY_PREFIX_LEN",
+            ENTRY_PREFIX,
+            "big-endian, before each packed frame",
+        ),
+    ];
+    for (name, value, comment) in limits {
+        uint(&mut out, name, value, comment);
+    }
+
+    out.push_str("\n#endif /* PIPPROTO_DEFS_H */\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_errors_are_numbered_in_order() {
+        for (i, e) in DECODE_ERRORS.iter().enumerate() {
+            assert_eq!(usize::from(e.code()), i + 1, "{e:?}");
+        }
+    }
+
+    #[test]
+    fn defines_every_field_type_and_error() {
+        let h = header();
+        for (name, range) in FIELDS {
+            let name = field_name(name);
+            assert!(h.contains(&format!("#define PP_{name}_OFFSET {}u\n", range.start)));
+            assert!(h.contains(&format!("#define PP_{name}_SIZE {}u\n", range.len())));
+        }
+        assert!(h.contains("#define PP_MSG_TYPE_ERROR 0x04u\n"));
+        assert!(h.contains("#define PP_FLAGS_RESERVED 0xfeu\n"));
+        assert!(h.contains("#define PP_ERR_RESERVED_FLAGS 5 "));
+        assert!(h.contains("/* reserved flag bits set, at offset 4 */"));
+        assert!(h.starts_with(&format!(
+            "/* PiProto v1 constants, generated by pipproto {} ",
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert_eq!(h, header());
+    }
+}
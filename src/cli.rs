@@ -0,0 +1,3911 @@
+//! The command-line tool behind `src/main.rs`, whose docs describe it.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::hint::black_box;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use pipproto::annotate::{FieldSpan, annotate};
+use pipproto::audit::{AuditConfig, AuditError, AuditReport, Finding, RecordRef};
+use pipproto::cdefs;
+use pipproto::codec::DEFAULT_MAX_FRAME_LEN;
+use pipproto::corpus;
+use pipproto::datagram::{UnpackError, unpack};
+use pipproto::diff::{DiffConfig, Difference, Summary as DiffSummary};
+use pipproto::dissector;
+use pipproto::filter::FrameFilter;
+use pipproto::gaps::{GapConfig, GapDetector, GapEvent};
+use pipproto::journal::{
+    DEFAULT_BUCKET, JournalError, JournalIndex, JournalReader, ParallelReader,
+};
+use pipproto::observer::FrameObserver;
+use pipproto::playback::{self, PlaybackConfig, PlaybackSummary};
+use pipproto::repl::{self, ReplCommand, Workbench};
+use pipproto::request::{Reply, RequestConfig, RequestError, Requester};
+use pipproto::sender::{CounterError, CounterSource, FrameSender, TimestampCounter};
+use pipproto::sink::{Direction, FrameRecord, Payload};
+use pipproto::stats::{StatsConfig, TrafficStats};
+use pipproto::transport::{TcpTransport, Transport, TransportError, UdpTransport};
+use pipproto::vectors;
+use pipproto::{DecodeError, Flags, FrameHeaderV1, FrameV1, MsgType};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+const USAGE: &str = "usage: pipproto decode [--binary] [--json] [--file PATH | HEX...]
+       pipproto dump [--binary] [--file PATH | HEX...]
+       pipproto encode [--file PATH] [--out PATH]
+       pipproto vectors [--out PATH]
+       pipproto verify-vectors [--file PATH]
+       pipproto dissector [--port PORT] [--out PATH]
+       pipproto c-defs [--out PATH]
+       pipproto schema [--out PATH]
+       pipproto gen-corpus [--seed N] [--count N] --out DIR
+       pipproto bench [--duration DURATION] [--warmup DURATION] [--threads N]
+                      [--body-size SIZES] [--json]
+       pipproto listen [--udp ADDR] [--tcp ADDR] [--json] [--device HEX] [--type TYPE]
+                       [--filter EXPR]
+       pipproto send HOST:PORT --device HEX [--type TYPE] [--body BODY] [--flags N]
+                     [--counter N] [--timeout DURATION] [--json]
+       pipproto repl [--timeout DURATION]
+       pipproto analyze [--json] [--top N] [--filter EXPR] [--threads N] JOURNAL
+       pipproto diff [--window N] [--timing DURATION|off] [--direction rx|tx] [--json]
+                     LEFT RIGHT
+       pipproto audit [--direction rx|tx|both] [--buckets N] [--spill DIR] [--threads N]
+                      [--json] JOURNAL...
+       pipproto replay [--tcp] HOST:PORT [--speed X | --no-timing] [--filter EXPR]
+                       [--direction rx|tx|both] [--map-device FROM=TO]...
+                       [--xor-device MASK] [--reset-counters] JOURNAL
+       pipproto tail [-f] [-n N | --from-start] [--since TIME] [--until TIME]
+                     [--device HEX]... [--type TYPE]... [--counter A..B]
+                     [--direction rx|tx] [--filter EXPR] [--json] JOURNAL
+       pipproto reindex [--bucket DURATION] JOURNAL...";
+
+/// Body bytes shown in the text output.
+const PREVIEW: usize = 16;
+/// Bytes per line of a dump.
+const DUMP_WIDTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Input {
+    Args(String),
+    File(PathBuf),
+    Stdin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Decode {
+    input: Input,
+    binary: bool,
+    json: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Encode {
+    input: Input,
+    out: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Dissector {
+    port: u16,
+    out: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GenCorpus {
+    seed: u64,
+    count: usize,
+    out: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Bench {
+    duration: Duration,
+    warmup: Duration,
+    threads: usize,
+    body_sizes: Vec<RangeInclusive<usize>>,
+    json: bool,
+}
+
+/// The frames `listen` prints.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Filter {
+    device: Option<[u8; 8]>,
+    msg_type: Option<MsgType>,
+    expr: Option<FrameFilter>,
+}
+
+impl Filter {
+    fn matches(&self, frame: &FrameV1) -> bool {
+        self.device.is_none_or(|d| d == frame.header.device_id)
+            && self.msg_type.is_none_or(|t| t == frame.header.msg_type)
+            && self.expr.as_ref().is_none_or(|e| e.matches(frame))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Listen {
+    udp: Option<SocketAddr>,
+    tcp: Option<SocketAddr>,
+    json: bool,
+    filter: Filter,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Decode(Decode),
+    Dump(Decode),
+    Encode(Encode),
+    Vectors(Option<PathBuf>),
+    VerifyVectors(Input),
+    Dissector(Dissector),
+    CDefs(Option<PathBuf>),
+    Schema(Option<PathBuf>),
+    GenCorpus(GenCorpus),
+    Bench(Bench),
+    Listen(Listen),
+    Send(SendFrame),
+    Repl(Duration),
+    Analyze(Analyze),
+    Diff(Diff),
+    Audit(Audit),
+    Replay(Replay),
+    Tail(Tail),
+    Reindex(Reindex),
+}
+
+/// The journal records `tail` prints.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct RecordFilter {
+    /// Any of these; empty for all.
+    devices: Vec<[u8; 8]>,
+    types: Vec<MsgType>,
+    counters: Option<RangeInclusive<u64>>,
+    direction: Option<Direction>,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+    expr: Option<FrameFilter>,
+}
+
+impl RecordFilter {
+    fn matches(&self, record: &FrameRecord) -> bool {
+        let when = self.since.is_none_or(|t| record.timestamp >= t)
+            && self.until.is_none_or(|t| record.timestamp <= t);
+        let what = match record.frame() {
+            Some(frame) => {
+                let h = &frame.header;
+                (self.devices.is_empty() || self.devices.contains(&h.device_id))
+                    && (self.types.is_empty() || self.types.contains(&h.msg_type))
+                    && self
+                        .counters
+                        .as_ref()
+                        .is_none_or(|r| r.contains(&h.counter))
+                    && self.expr.as_ref().is_none_or(|e| e.matches(frame))
+            }
+            // no header to pick it by
+            None => {
+                self.devices.is_empty()
+                    && self.types.is_empty()
+                    && self.counters.is_none()
+                    && self.expr.is_none()
+            }
+        };
+        when && what && self.direction.is_none_or(|d| d == record.direction)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Tail {
+    path: PathBuf,
+    filter: RecordFilter,
+    /// Records printed from what is already there; `None` for all.
+    lines: Option<usize>,
+    follow: bool,
+    json: bool,
+    /// How often `-f` looks for more.
+    poll: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Analyze {
+    path: PathBuf,
+    json: bool,
+    /// Devices listed, by frames and by counters missing.
+    top: usize,
+    filter: Option<FrameFilter>,
+    /// Threads decoding the journal.
+    threads: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Diff {
+    left: PathBuf,
+    right: PathBuf,
+    direction: Option<Direction>,
+    config: DiffConfig,
+    json: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Reindex {
+    paths: Vec<PathBuf>,
+    bucket: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Audit {
+    paths: Vec<PathBuf>,
+    config: AuditConfig,
+    json: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Replay {
+    path: PathBuf,
+    target: String,
+    tcp: bool,
+    config: PlaybackConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Body {
+    Bytes(Vec<u8>),
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SendFrame {
+    target: String,
+    device: [u8; 8],
+    msg_type: MsgType,
+    body: Body,
+    flags: Flags,
+    counter: Option<u64>,
+    timeout: Duration,
+    json: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (command, rest) = args.split_first().ok_or(USAGE)?;
+    match command.as_str() {
+        "decode" => parse_decode(rest).map(Command::Decode),
+        "dump" => match parse_decode(rest)? {
+            Decode { json: true, .. } => Err(format!("dump has no --json\n{USAGE}")),
+            dump => Ok(Command::Dump(dump)),
+        },
+        "encode" => parse_encode(rest).map(Command::Encode),
+        "vectors" => match parse_encode(rest)? {
+            Encode {
+                input: Input::Stdin,
+                out,
+            } => Ok(Command::Vectors(out)),
+            _ => Err(format!("vectors takes no --file\n{USAGE}")),
+        },
+        "verify-vectors" => match parse_encode(rest)? {
+            Encode { input, out: None } => Ok(Command::VerifyVectors(input)),
+            _ => Err(format!("verify-vectors takes no --out\n{USAGE}")),
+        },
+        "dissector" => parse_dissector(rest).map(Command::Dissector),
+        "c-defs" => match parse_encode(rest)? {
+            Encode {
+                input: Input::Stdin,
+                out,
+            } => Ok(Command::CDefs(out)),
+            _ => Err(format!("c-defs takes no --file\n{USAGE}")),
+        },
+        "schema" => match parse_encode(rest)? {
+            Encode {
+                input: Input::Stdin,
+                out,
+            } => Ok(Command::Schema(out)),
+            _ => Err(format!("schema takes no --file\n{USAGE}")),
+        },
+        "gen-corpus" => parse_gen_corpus(rest).map(Command::GenCorpus),
+        "bench" => parse_bench(rest).map(Command::Bench),
+        "listen" => parse_listen(rest).map(Command::Listen),
+        "send" => parse_send(rest).map(Command::Send),
+        "repl" => parse_repl(rest).map(Command::Repl),
+        "analyze" => parse_analyze(rest).map(Command::Analyze),
+        "diff" => parse_diff(rest).map(Command::Diff),
+        "audit" => parse_audit(rest).map(Command::Audit),
+        "replay" => parse_replay(rest).map(Command::Replay),
+        "tail" => parse_tail(rest).map(Command::Tail),
+        "reindex" => parse_reindex(rest).map(Command::Reindex),
+        "-h" | "--help" => Err(USAGE.to_string()),
+        _ => Err(format!("unknown command {command:?}\n{USAGE}")),
+    }
+}
+
+fn parse_decode(args: &[String]) -> Result<Decode, String> {
+    let mut binary = false;
+    let mut json = false;
+    let mut file = None;
+    let mut hex = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--binary" => binary = true,
+            "--json" => json = true,
+            "--file" => {
+                let path = rest.next().ok_or("--file needs a path")?;
+                file = Some(PathBuf::from(path));
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with("--") => {
+                return Err(format!("unknown option {flag:?}\n{USAGE}"));
+            }
+            _ => hex.push(arg.as_str()),
+        }
+    }
+    let input = match (file, hex.is_empty()) {
+        (Some(_), false) => return Err("give --file or hex arguments, not both".into()),
+        (Some(path), true) => Input::File(path),
+        (None, false) if binary => return Err("--binary reads a file or stdin".into()),
+        (None, false) => Input::Args(hex.join(" ")),
+        (None, true) => Input::Stdin,
+    };
+    Ok(Decode {
+        input,
+        binary,
+        json,
+    })
+}
+
+fn parse_encode(args: &[String]) -> Result<Encode, String> {
+    let mut input = Input::Stdin;
+    let mut out = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut path = || {
+            rest.next()
+                .map(PathBuf::from)
+                .ok_or(format!("{arg} needs a path"))
+        };
+        match arg.as_str() {
+            "--file" => input = Input::File(path()?),
+            "--out" => out = Some(path()?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => return Err(format!("unexpected argument {arg:?}\n{USAGE}")),
+        }
+    }
+    Ok(Encode { input, out })
+}
+
+fn parse_dissector(args: &[String]) -> Result<Dissector, String> {
+    let mut port = dissector::DEFAULT_PORT;
+    let mut out = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--port" => {
+                let v = rest.next().ok_or("--port needs a value")?;
+                port = v.parse().map_err(|_| format!("bad --port {v:?}"))?;
+            }
+            "--out" => out = Some(PathBuf::from(rest.next().ok_or("--out needs a path")?)),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => return Err(format!("unexpected argument {arg:?}\n{USAGE}")),
+        }
+    }
+    Ok(Dissector { port, out })
+}
+
+fn parse_gen_corpus(args: &[String]) -> Result<GenCorpus, String> {
+    let mut seed = 0;
+    let mut count = 2000;
+    let mut out = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--seed" => {
+                let v = rest.next().ok_or("--seed needs a value")?;
+                seed = v.parse().map_err(|_| format!("bad --seed {v:?}"))?;
+            }
+            "--count" => {
+                let v = rest.next().ok_or("--count needs a value")?;
+                count = v.parse().map_err(|_| format!("bad --count {v:?}"))?;
+            }
+            "--out" => out = Some(PathBuf::from(rest.next().ok_or("--out needs a path")?)),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => return Err(format!("unexpected argument {arg:?}\n{USAGE}")),
+        }
+    }
+    let out = out.ok_or(format!("gen-corpus needs --out\n{USAGE}"))?;
+    Ok(GenCorpus { seed, count, out })
+}
+
+/// An address, or a port on every interface.
+fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+    match s.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::from(([0, 0, 0, 0], port))),
+        Err(_) => s.parse().map_err(|_| format!("bad address {s:?}")),
+    }
+}
+
+fn parse_device(hex: &str) -> Result<[u8; 8], String> {
+    parse_hex(hex)
+        .ok()
+        .and_then(|d| <[u8; 8]>::try_from(d).ok())
+        .ok_or(format!("--device takes 16 hex digits, not {hex:?}"))
+}
+
+fn parse_type(name: &str) -> Result<MsgType, String> {
+    [
+        MsgType::Event,
+        MsgType::Command,
+        MsgType::Ack,
+        MsgType::Error,
+    ]
+    .into_iter()
+    .find(|t| t.as_str() == name.to_ascii_lowercase())
+    .ok_or(format!("unknown type {name:?}"))
+}
+
+/// `500ms`, `2s`, `1.5s`, or plain seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let bad = || format!("bad duration {s:?}");
+    if let Some(ms) = s.strip_suffix("ms") {
+        return ms.parse().map(Duration::from_millis).map_err(|_| bad());
+    }
+    let secs: f64 = s
+        .strip_suffix('s')
+        .unwrap_or(s)
+        .parse()
+        .map_err(|_| bad())?;
+    Duration::try_from_secs_f64(secs).map_err(|_| bad())
+}
+
+fn parse_base64(text: &str) -> Result<Vec<u8>, String> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let v = ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or(format!("not base64: {:?}", c as char))?;
+        acc = acc << 6 | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+fn parse_body(s: &str) -> Result<Body, String> {
+    if let Some(path) = s.strip_prefix('@') {
+        Ok(Body::File(PathBuf::from(path)))
+    } else if let Some(b64) = s.strip_prefix("base64:") {
+        parse_base64(b64).map(Body::Bytes)
+    } else {
+        parse_hex(s).map(Body::Bytes)
+    }
+}
+
+fn parse_send(args: &[String]) -> Result<SendFrame, String> {
+    let mut target = None;
+    let mut device = None;
+    let mut command = SendFrame {
+        target: String::new(),
+        device: [0; 8],
+        msg_type: MsgType::Command,
+        body: Body::Bytes(Vec::new()),
+        flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+        counter: None,
+        timeout: Duration::from_secs(5),
+        json: false,
+    };
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--device" => device = Some(parse_device(value()?)?),
+            "--type" => command.msg_type = parse_type(value()?)?,
+            "--body" => command.body = parse_body(value()?)?,
+            "--flags" => {
+                let v = value()?;
+                let bits = match v.strip_prefix("0x") {
+                    Some(hex) => u8::from_str_radix(hex, 16),
+                    None => v.parse(),
+                }
+                .map_err(|_| format!("bad flags {v:?}"))?;
+                command.flags = Flags::new(bits).map_err(|e| e.to_string())?;
+            }
+            "--counter" => {
+                let v = value()?;
+                command.counter = Some(v.parse().map_err(|_| format!("bad counter {v:?}"))?);
+            }
+            "--timeout" => command.timeout = parse_duration(value()?)?,
+            "--json" => command.json = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with("--") => {
+                return Err(format!("unknown option {flag:?}\n{USAGE}"));
+            }
+            _ if target.is_none() => target = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {arg:?}\n{USAGE}")),
+        }
+    }
+    command.target = target.ok_or(format!("send needs a target\n{USAGE}"))?;
+    command.device = device.ok_or(format!("send needs --device\n{USAGE}"))?;
+    Ok(command)
+}
+
+/// How long `repl` waits for replies.
+fn parse_repl(args: &[String]) -> Result<Duration, String> {
+    match args {
+        [] => Ok(Duration::from_secs(2)),
+        [flag, value] if flag == "--timeout" => parse_duration(value),
+        [flag] if flag == "--timeout" => Err(format!("{flag} needs a value")),
+        [flag, ..] if flag == "-h" || flag == "--help" => Err(USAGE.to_string()),
+        [arg, ..] => Err(format!("unexpected argument {arg:?}\n{USAGE}")),
+    }
+}
+
+/// Days since 1970-01-01 of a civil date, after Howard Hinnant's
+/// `days_from_civil`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// `YYYY-MM-DDTHH:MM:SS[.fff]Z` as [`utc`] writes it, or Unix seconds.
+fn parse_time(s: &str) -> Result<SystemTime, String> {
+    let bad = || format!("bad time {s:?}");
+    let secs = if let Ok(secs) = s.parse::<f64>() {
+        secs
+    } else {
+        let (date, time) = s
+            .strip_suffix('Z')
+            .and_then(|t| t.split_once('T'))
+            .ok_or_else(bad)?;
+        let date: Vec<i64> = date
+            .split('-')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map_err(|_| bad())?;
+        let [year, month, day] = date[..] else {
+            return Err(bad());
+        };
+        let mut time = time.splitn(3, ':');
+        let mut field = || time.next().ok_or_else(bad);
+        let (hour, minute): (u8, u8) = (
+            field()?.parse().map_err(|_| bad())?,
+            field()?.parse().map_err(|_| bad())?,
+        );
+        let second: f64 = field()?.parse().map_err(|_| bad())?;
+        if !(1..=12).contains(&month)
+            || !(1..=31).contains(&day)
+            || hour > 23
+            || minute > 59
+            || !(0.0..61.0).contains(&second)
+        {
+            return Err(bad());
+        }
+        let days = days_from_civil(year, month, day);
+        (days * 86_400 + i64::from(hour) * 3600 + i64::from(minute) * 60) as f64 + second
+    };
+    let since = Duration::try_from_secs_f64(secs).map_err(|_| bad())?;
+    Ok(UNIX_EPOCH + since)
+}
+
+/// `A..B`, `A..`, `..B` or `N`, inclusive.
+fn parse_counters(s: &str) -> Result<RangeInclusive<u64>, String> {
+    let bad = || format!("bad counter range {s:?}");
+    let n = |t: &str, or: u64| {
+        if t.is_empty() {
+            Ok(or)
+        } else {
+            t.parse().map_err(|_| bad())
+        }
+    };
+    match s.split_once("..") {
+        Some((from, to)) => Ok(n(from, 0)?..=n(to.trim_start_matches('='), u64::MAX)?),
+        None => {
+            let c = s.parse().map_err(|_| bad())?;
+            Ok(c..=c)
+        }
+    }
+}
+
+/// `SIZE` or `A..B`, comma-separated.
+fn parse_sizes(s: &str) -> Result<Vec<RangeInclusive<usize>>, String> {
+    let bad = || format!("bad body sizes {s:?}");
+    s.split(',')
+        .map(|part| {
+            let (from, to) = part.split_once("..").unwrap_or((part, part));
+            let from: usize = from.parse().map_err(|_| bad())?;
+            let to: usize = to.trim_start_matches('=').parse().map_err(|_| bad())?;
+            if from > to {
+                return Err(bad());
+            }
+            Ok(from..=to)
+        })
+        .collect()
+}
+
+fn parse_bench(args: &[String]) -> Result<Bench, String> {
+    let mut bench = Bench {
+        duration: Duration::from_secs(2),
+        warmup: Duration::from_millis(500),
+        threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        body_sizes: vec![0..=256],
+        json: false,
+    };
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--duration" => bench.duration = parse_duration(value()?)?,
+            "--warmup" => bench.warmup = parse_duration(value()?)?,
+            "--threads" => {
+                let v = value()?;
+                bench.threads = match v.parse() {
+                    Ok(0) | Err(_) => return Err(format!("bad --threads {v:?}")),
+                    Ok(n) => n,
+                };
+            }
+            "--body-size" => bench.body_sizes = parse_sizes(value()?)?,
+            "--json" => bench.json = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => return Err(format!("unexpected argument {arg:?}\n{USAGE}")),
+        }
+    }
+    Ok(bench)
+}
+
+fn parse_tail(args: &[String]) -> Result<Tail, String> {
+    let mut path = None;
+    let mut tail = Tail {
+        path: PathBuf::new(),
+        filter: RecordFilter::default(),
+        lines: Some(10),
+        follow: false,
+        json: false,
+        poll: Duration::from_millis(250),
+    };
+    let (mut lines, mut from_start) = (None, false);
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "-f" | "--follow" => tail.follow = true,
+            "-n" | "--lines" => {
+                let v = value()?;
+                lines = Some(v.parse().map_err(|_| format!("bad line count {v:?}"))?);
+            }
+            "--from-start" => from_start = true,
+            "--since" => tail.filter.since = Some(parse_time(value()?)?),
+            "--until" => tail.filter.until = Some(parse_time(value()?)?),
+            "--device" => tail.filter.devices.push(parse_device(value()?)?),
+            "--type" => tail.filter.types.push(parse_type(value()?)?),
+            "--counter" => tail.filter.counters = Some(parse_counters(value()?)?),
+            "--filter" => tail.filter.expr = Some(parse_filter(value()?)?),
+            "--direction" => {
+                tail.filter.direction = Some(match value()?.as_str() {
+                    "rx" => Direction::Rx,
+                    "tx" => Direction::Tx,
+                    d => return Err(format!("--direction is rx or tx, not {d:?}")),
+                });
+            }
+            "--json" => tail.json = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with('-') => {
+                return Err(format!("unknown option {flag:?}\n{USAGE}"));
+            }
+            _ if path.is_none() => path = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {arg:?}\n{USAGE}")),
+        }
+    }
+    if from_start && lines.is_some() {
+        return Err(format!("-n and --from-start conflict\n{USAGE}"));
+    }
+    tail.lines = match lines {
+        Some(n) => Some(n),
+        None if from_start || tail.filter.since.is_some() => None,
+        None => tail.lines,
+    };
+    tail.path = path.ok_or(format!("tail needs a journal\n{USAGE}"))?;
+    Ok(tail)
+}
+
+/// A filter expression, or where and why it does not parse.
+fn parse_filter(expr: &str) -> Result<FrameFilter, String> {
+    FrameFilter::parse(expr).map_err(|e| {
+        let caret = " ".repeat(expr[..e.offset].chars().count());
+        format!("--filter: {e}\n  {expr}\n  {caret}^")
+    })
+}
+
+fn parse_analyze(args: &[String]) -> Result<Analyze, String> {
+    let mut path = None;
+    let (mut json, mut top, mut filter, mut threads) = (false, 10, None, 1);
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--threads" => {
+                let v = rest.next().ok_or("--threads needs a value")?;
+                threads = match v.parse() {
+                    Ok(0) | Err(_) => return Err(format!("bad --threads {v:?}")),
+                    Ok(n) => n,
+                };
+            }
+            "--top" => {
+                let v = rest.next().ok_or("--top needs a value")?;
+                top = v.parse().map_err(|_| format!("bad --top {v:?}"))?;
+            }
+            "--filter" => {
+                let v = rest.next().ok_or("--filter needs a value")?;
+                filter = Some(parse_filter(v)?);
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with("--") => {
+                return Err(format!("unknown option {flag:?}\n{USAGE}"));
+            }
+            _ if path.is_none() => path = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {arg:?}\n{USAGE}")),
+        }
+    }
+    let path = path.ok_or(format!("analyze needs a journal\n{USAGE}"))?;
+    Ok(Analyze {
+        path,
+        json,
+        top,
+        filter,
+        threads,
+    })
+}
+
+fn parse_diff(args: &[String]) -> Result<Diff, String> {
+    let mut paths = Vec::new();
+    let (mut direction, mut config, mut json) = (None, DiffConfig::default(), false);
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--window" => {
+                let v = value()?;
+                config.window = v.parse().map_err(|_| format!("bad --window {v:?}"))?;
+            }
+            "--timing" => {
+                config.timing = match value()?.as_str() {
+                    "off" => None,
+                    v => Some(parse_duration(v)?),
+                }
+            }
+            "--direction" => {
+                direction = Some(match value()?.as_str() {
+                    "rx" => Direction::Rx,
+                    "tx" => Direction::Tx,
+                    d => return Err(format!("--direction is rx or tx, not {d:?}")),
+                });
+            }
+            "--json" => json = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with("--") => {
+                return Err(format!("unknown option {flag:?}\n{USAGE}"));
+            }
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let [left, right]: [PathBuf; 2] = paths
+        .try_into()
+        .map_err(|_| format!("diff needs two journals\n{USAGE}"))?;
+    Ok(Diff {
+        left,
+        right,
+        direction,
+        config,
+        json,
+    })
+}
+
+fn parse_audit(args: &[String]) -> Result<Audit, String> {
+    let mut audit = Audit {
+        paths: Vec::new(),
+        config: AuditConfig::default(),
+        json: false,
+    };
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--direction" => {
+                audit.config.direction = match value()?.as_str() {
+                    "rx" => Some(Direction::Rx),
+                    "tx" => Some(Direction::Tx),
+                    "both" => None,
+                    d => return Err(format!("--direction is rx, tx or both, not {d:?}")),
+                };
+            }
+            "--buckets" => {
+                let v = value()?;
+                audit.config.buckets = match v.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("bad --buckets {v:?}")),
+                };
+            }
+            "--spill" => audit.config.spill_dir = PathBuf::from(value()?),
+            "--threads" => {
+                let v = value()?;
+                audit.config.threads = match v.parse() {
+                    Ok(0) | Err(_) => return Err(format!("bad --threads {v:?}")),
+                    Ok(n) => n,
+                };
+            }
+            "--json" => audit.json = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with("--") => {
+                return Err(format!("unknown option {flag:?}\n{USAGE}"));
+            }
+            _ => audit.paths.push(PathBuf::from(arg)),
+        }
+    }
+    if audit.paths.is_empty() {
+        return Err(format!("audit needs a journal\n{USAGE}"));
+    }
+    Ok(audit)
+}
+
+fn parse_reindex(args: &[String]) -> Result<Reindex, String> {
+    let mut reindex = Reindex {
+        paths: Vec::new(),
+        bucket: DEFAULT_BUCKET,
+    };
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--bucket" => {
+                reindex.bucket = match parse_duration(value()?)? {
+                    d if d.as_millis() > 0 => d,
+                    _ => return Err("--bucket must be at least 1ms".into()),
+                };
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with("--") => {
+                return Err(format!("unknown option {flag:?}\n{USAGE}"));
+            }
+            _ => reindex.paths.push(PathBuf::from(arg)),
+        }
+    }
+    if reindex.paths.is_empty() {
+        return Err(format!("reindex needs a journal\n{USAGE}"));
+    }
+    Ok(reindex)
+}
+
+fn parse_replay(args: &[String]) -> Result<Replay, String> {
+    let mut positional = Vec::new();
+    let mut tcp = false;
+    let mut config = PlaybackConfig::default();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--tcp" => tcp = true,
+            "--speed" => {
+                let v = value()?;
+                config.speed = match v.parse::<f64>() {
+                    Ok(x) if x > 0.0 && x.is_finite() => Some(x),
+                    _ => return Err(format!("bad --speed {v:?}")),
+                };
+            }
+            "--no-timing" => config.speed = None,
+            "--filter" => config.filter = Some(parse_filter(value()?)?),
+            "--direction" => {
+                config.direction = match value()?.as_str() {
+                    "rx" => Some(Direction::Rx),
+                    "tx" => Some(Direction::Tx),
+                    "both" => None,
+                    d => return Err(format!("--direction is rx, tx or both, not {d:?}")),
+                };
+            }
+            "--map-device" => {
+                let v = value()?;
+                let (from, to) = v
+                    .split_once('=')
+                    .ok_or(format!("--map-device wants FROM=TO, not {v:?}"))?;
+                config
+                    .rewrite
+                    .devices
+                    .insert(parse_device(from)?, parse_device(to)?);
+            }
+            "--xor-device" => config.rewrite.device_xor = parse_device(value()?)?,
+            "--reset-counters" => config.rewrite.reset_counters = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with("--") => {
+                return Err(format!("unknown option {flag:?}\n{USAGE}"));
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+    let [target, path]: [String; 2] = positional
+        .try_into()
+        .map_err(|_| format!("replay needs a target and a journal\n{USAGE}"))?;
+    Ok(Replay {
+        path: PathBuf::from(path),
+        target,
+        tcp,
+        config,
+    })
+}
+
+fn parse_listen(args: &[String]) -> Result<Listen, String> {
+    let mut listen = Listen {
+        udp: None,
+        tcp: None,
+        json: false,
+        filter: Filter::default(),
+    };
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--udp" => listen.udp = Some(parse_addr(value()?)?),
+            "--tcp" => listen.tcp = Some(parse_addr(value()?)?),
+            "--json" => listen.json = true,
+            "--device" => listen.filter.device = Some(parse_device(value()?)?),
+            "--type" => listen.filter.msg_type = Some(parse_type(value()?)?),
+            "--filter" => listen.filter.expr = Some(parse_filter(value()?)?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => return Err(format!("unexpected argument {arg:?}\n{USAGE}")),
+        }
+    }
+    if listen.udp.is_none() && listen.tcp.is_none() {
+        return Err(format!("listen needs --udp or --tcp\n{USAGE}"));
+    }
+    Ok(listen)
+}
+
+/// Hex digits with any whitespace between them.
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if let Some(pos) = digits.iter().position(|b| !b.is_ascii_hexdigit()) {
+        return Err(format!("not a hex digit: {:?}", digits[pos] as char));
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".into());
+    }
+    Ok(digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+fn flag_names(frame: &FrameV1) -> Vec<&'static str> {
+    let mut names = Vec::new();
+    if frame.header.flags.ack_required() {
+        names.push("ACK_REQUIRED");
+    }
+    names
+}
+
+fn format_text(frame: &FrameV1) -> String {
+    let h = &frame.header;
+    let flags = flag_names(frame);
+    let flags = if flags.is_empty() {
+        "-".to_string()
+    } else {
+        flags.join(" ")
+    };
+    let mut preview = hex(&frame.body[..frame.body.len().min(PREVIEW)]);
+    if frame.body.len() > PREVIEW {
+        preview.push_str("...");
+    }
+    format!(
+        "version:   {}\ntype:      {}\nflags:     0x{:02x} {flags}\ndevice_id: {}\ncounter:   {}\nbody:      {} bytes {preview}\n",
+        h.version,
+        h.msg_type.as_str(),
+        h.flags.bits(),
+        h.device_id_hex(),
+        h.counter,
+        frame.body.len(),
+    )
+}
+
+fn format_json(frame: &FrameV1) -> String {
+    format!("{{{}}}\n", json_fields(frame))
+}
+
+/// The members of [`format_json`]'s object.
+fn json_fields(frame: &FrameV1) -> String {
+    let h = &frame.header;
+    let flags: Vec<String> = flag_names(frame)
+        .iter()
+        .map(|f| format!("\"{f}\""))
+        .collect();
+    format!(
+        "\"version\":{},\"msg_type\":\"{}\",\"flags\":[{}],\"device_id\":\"{}\",\"counter\":{},\"body_len\":{},\"body\":\"{}\"",
+        h.version,
+        h.msg_type.as_str(),
+        flags.join(","),
+        h.device_id_hex(),
+        h.counter,
+        frame.body.len(),
+        hex(&frame.body),
+    )
+}
+
+fn format_error(error: &DecodeError, len: usize, json: bool) -> String {
+    // a short frame is at fault where it ends
+    let offset = error.offset().unwrap_or(len);
+    if json {
+        format!(
+            "{{\"error\":\"{}\",\"message\":\"{error}\",\"offset\":{offset}}}\n",
+            error.kind()
+        )
+    } else {
+        format!("error: {error} at offset {offset}\n")
+    }
+}
+
+fn read_input(input: &Input) -> io::Result<Vec<u8>> {
+    match input {
+        Input::Args(text) => Ok(text.clone().into_bytes()),
+        Input::File(path) => std::fs::read(path),
+        Input::Stdin => {
+            let mut bytes = Vec::new();
+            io::stdin().read_to_end(&mut bytes)?;
+            Ok(bytes)
+        }
+    }
+}
+
+/// `at` as UTC, to the millisecond: `2026-01-31T12:00:00.000Z`.
+fn utc(at: SystemTime) -> String {
+    let since = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // days to a civil date, after Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since.subsec_millis()
+    )
+}
+
+/// JSON string contents, escaped.
+fn json_str(s: &str) -> String {
+    s.chars().fold(String::new(), |mut out, c| {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+        out
+    })
+}
+
+/// What arrived on a socket.
+enum Seen<'a> {
+    Frame(FrameV1),
+    /// Bytes that did not decode, and why.
+    Malformed {
+        error: String,
+        bytes: &'a [u8],
+    },
+}
+
+#[derive(Default)]
+struct Counts {
+    frames: AtomicU64,
+    printed: AtomicU64,
+    malformed: AtomicU64,
+}
+
+/// Turns what `listen` receives into lines, counting as it goes.
+struct Printer {
+    json: bool,
+    filter: Filter,
+    counts: Counts,
+    out: Box<dyn Fn(&str) + Send + Sync>,
+}
+
+impl Printer {
+    fn line(&self, at: SystemTime, from: &str, seen: Seen<'_>) -> Option<String> {
+        let at = utc(at);
+        match seen {
+            Seen::Frame(frame) => {
+                self.counts.frames.fetch_add(1, Ordering::Relaxed);
+                if !self.filter.matches(&frame) {
+                    return None;
+                }
+                self.counts.printed.fetch_add(1, Ordering::Relaxed);
+                Some(if self.json {
+                    format!(
+                        "{{\"at\":\"{at}\",\"from\":\"{}\",{}}}",
+                        json_str(from),
+                        json_fields(&frame)
+                    )
+                } else {
+                    format!("{at} {from} {frame}")
+                })
+            }
+            Seen::Malformed { error, bytes } => {
+                self.counts.malformed.fetch_add(1, Ordering::Relaxed);
+                let mut preview = hex(&bytes[..bytes.len().min(PREVIEW)]);
+                if bytes.len() > PREVIEW {
+                    preview.push_str("...");
+                }
+                Some(if self.json {
+                    format!(
+                        "{{\"at\":\"{at}\",\"from\":\"{}\",\"error\":\"{}\",\"len\":{},\"bytes\":\"{preview}\"}}",
+                        json_str(from),
+                        json_str(&error),
+                        bytes.len()
+                    )
+                } else {
+                    format!(
+                        "{at} {from} warning: {error} ({} bytes) {preview}",
+                        bytes.len()
+                    )
+                })
+            }
+        }
+    }
+
+    fn print(&self, from: &str, seen: Seen<'_>) {
+        if let Some(line) = self.line(SystemTime::now(), from, seen) {
+            (self.out)(&line);
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} frames received, {} printed, {} malformed",
+            self.counts.frames.load(Ordering::Relaxed),
+            self.counts.printed.load(Ordering::Relaxed),
+            self.counts.malformed.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Where in the datagram `e` is, down to the field where there is one.
+fn unpack_error(e: &UnpackError, len: usize) -> String {
+    match e {
+        UnpackError::Frame { offset: 0, error } if error.offset().is_none() => {
+            format!("{error} at offset {len}")
+        }
+        UnpackError::Frame { offset, error } => match error.offset() {
+            Some(at) => format!("{error} at offset {}", offset + at),
+            None => format!("{error} in the frame at offset {offset}"),
+        },
+        UnpackError::Truncated { .. } => e.to_string(),
+    }
+}
+
+async fn serve_udp(socket: UdpSocket, printer: &Printer) -> io::Result<()> {
+    let mut buf = vec![0; 65_536];
+    loop {
+        let (n, from) = socket.recv_from(&mut buf).await?;
+        let from = from.to_string();
+        let datagram = &buf[..n];
+        if datagram.is_empty() {
+            printer.print(
+                &from,
+                Seen::Malformed {
+                    error: "empty datagram".into(),
+                    bytes: datagram,
+                },
+            );
+        }
+        for item in unpack(datagram) {
+            let seen = match item {
+                Ok(frame) => Seen::Frame(frame),
+                Err(e) => Seen::Malformed {
+                    error: unpack_error(&e, datagram.len()),
+                    bytes: datagram,
+                },
+            };
+            printer.print(&from, seen);
+        }
+    }
+}
+
+/// Length-prefixed frames from one connection, until it closes.
+async fn serve_stream(mut stream: TcpStream, from: String, printer: &Printer) {
+    let mut body = Vec::new();
+    loop {
+        let len = match stream.read_u32().await {
+            Ok(len) => len as usize,
+            Err(_) => return,
+        };
+        if len > DEFAULT_MAX_FRAME_LEN {
+            let error = format!("frame of {len} bytes is too large; closing");
+            let prefix = (len as u32).to_be_bytes();
+            printer.print(
+                &from,
+                Seen::Malformed {
+                    error,
+                    bytes: &prefix,
+                },
+            );
+            return;
+        }
+        body.resize(len, 0);
+        if stream.read_exact(&mut body).await.is_err() {
+            let error = "connection closed inside a frame".to_string();
+            printer.print(&from, Seen::Malformed { error, bytes: &[] });
+            return;
+        }
+        let seen = match FrameV1::decode(&body) {
+            Ok(frame) => Seen::Frame(frame),
+            Err(e) => Seen::Malformed {
+                error: format!("{e} at offset {}", e.offset().unwrap_or(body.len())),
+                bytes: &body,
+            },
+        };
+        printer.print(&from, seen);
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, printer: Arc<Printer>) -> io::Result<()> {
+    loop {
+        let (stream, from) = listener.accept().await?;
+        let printer = printer.clone();
+        tokio::spawn(async move { serve_stream(stream, from.to_string(), &printer).await });
+    }
+}
+
+/// Print what arrives until Ctrl-C or a socket fails.
+async fn listen(listen: &Listen, printer: Arc<Printer>) -> io::Result<()> {
+    let udp = match listen.udp {
+        Some(addr) => Some(UdpSocket::bind(addr).await?),
+        None => None,
+    };
+    let tcp = match listen.tcp {
+        Some(addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+    if let Some(udp) = &udp {
+        eprintln!("listening on udp {}", udp.local_addr()?);
+    }
+    if let Some(tcp) = &tcp {
+        eprintln!("listening on tcp {}", tcp.local_addr()?);
+    }
+    let udp = async {
+        match udp {
+            Some(socket) => serve_udp(socket, &printer).await,
+            None => std::future::pending().await,
+        }
+    };
+    let tcp = async {
+        match tcp {
+            Some(listener) => serve_tcp(listener, printer.clone()).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        r = udp => r,
+        r = tcp => r,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+fn run_listen(command: &Listen) -> ExitCode {
+    let printer = Arc::new(Printer {
+        json: command.json,
+        filter: command.filter.clone(),
+        counts: Counts::default(),
+        out: Box::new(|line| println!("{line}")),
+    });
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("tokio runtime");
+    let result = runtime.block_on(listen(command, printer.clone()));
+    eprintln!("{}", printer.summary());
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The given counter, or else one from the clock; remembers the last.
+struct SendCounter {
+    fixed: Option<u64>,
+    clock: TimestampCounter,
+    last: AtomicU64,
+}
+
+impl CounterSource for SendCounter {
+    fn next(&self, device_id: [u8; 8]) -> Result<u64, CounterError> {
+        let counter = match self.fixed {
+            Some(c) => c,
+            None => self.clock.next(device_id)?,
+        };
+        self.last.store(counter, Ordering::Relaxed);
+        Ok(counter)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Acked(Reply),
+    /// Sent without ACK_REQUIRED, so nothing to wait for.
+    Sent,
+    Nacked(String),
+    ErrorFrame(u16),
+    Timeout,
+    Failed(String),
+}
+
+impl Outcome {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Outcome::Acked(_) | Outcome::Sent => 0,
+            Outcome::Failed(_) => 1,
+            Outcome::Nacked(_) => 3,
+            Outcome::ErrorFrame(_) => 4,
+            Outcome::Timeout => 5,
+        }
+    }
+}
+
+/// One line describing how the frame numbered `counter` fared, `rtt`
+/// after it was sent.
+fn format_outcome(outcome: &Outcome, counter: u64, rtt: Duration, json: bool) -> String {
+    let ms = rtt.as_secs_f64() * 1000.0;
+    if json {
+        let detail = match outcome {
+            Outcome::Acked(Reply::Ack) => "\"outcome\":\"ack\"".to_string(),
+            Outcome::Acked(Reply::Data(data)) => {
+                format!("\"outcome\":\"ack\",\"reply\":\"{}\"", hex(data))
+            }
+            Outcome::Sent => "\"outcome\":\"sent\"".to_string(),
+            Outcome::Nacked(reason) => {
+                format!("\"outcome\":\"nack\",\"reason\":\"{}\"", json_str(reason))
+            }
+            Outcome::ErrorFrame(code) => format!("\"outcome\":\"error\",\"code\":{code}"),
+            Outcome::Timeout => "\"outcome\":\"timeout\"".to_string(),
+            Outcome::Failed(e) => format!("\"outcome\":\"failed\",\"error\":\"{}\"", json_str(e)),
+        };
+        return format!("{{{detail},\"counter\":{counter},\"rtt_ms\":{ms:.3}}}");
+    }
+    match outcome {
+        Outcome::Acked(Reply::Ack) => format!("ack for ctr={counter} in {ms:.1}ms"),
+        Outcome::Acked(Reply::Data(data)) => format!(
+            "ack for ctr={counter} in {ms:.1}ms, reply {} bytes {}",
+            data.len(),
+            hex(data)
+        ),
+        Outcome::Sent => format!("sent ctr={counter}"),
+        Outcome::Nacked(reason) => format!("nack for ctr={counter} in {ms:.1}ms: {reason}"),
+        Outcome::ErrorFrame(code) => {
+            format!("error {code:#06x} for ctr={counter} in {ms:.1}ms")
+        }
+        Outcome::Timeout => format!("no answer for ctr={counter} in {ms:.0}ms"),
+        Outcome::Failed(e) => format!("failed: {e}"),
+    }
+}
+
+/// Feed the replies arriving on `rx` to `requester`, until the socket fails.
+async fn replies<C: CounterSource>(
+    rx: &mut UdpTransport,
+    requester: &Requester<UdpTransport, C>,
+) -> TransportError {
+    loop {
+        match rx.recv().await {
+            Ok(frame) => {
+                requester.on_frame(&frame);
+            }
+            Err(TransportError::Protocol(_)) => {}
+            Err(e) => return e,
+        }
+    }
+}
+
+/// Send the frame and wait for its answer: the outcome, the counter used
+/// and how long it took.
+async fn send_frame(command: &SendFrame, body: Vec<u8>) -> (Outcome, u64, Duration) {
+    let counters = Arc::new(SendCounter {
+        fixed: command.counter,
+        clock: TimestampCounter::new(),
+        last: AtomicU64::new(0),
+    });
+    let start = Instant::now();
+    let outcome = match exchange(command, body, counters.clone()).await {
+        Ok(outcome) => outcome,
+        Err(e) => Outcome::Failed(e.to_string()),
+    };
+    (
+        outcome,
+        counters.last.load(Ordering::Relaxed),
+        start.elapsed(),
+    )
+}
+
+async fn exchange(
+    command: &SendFrame,
+    body: Vec<u8>,
+    counters: Arc<SendCounter>,
+) -> io::Result<Outcome> {
+    let peer = tokio::net::lookup_host(&command.target)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("{} did not resolve", command.target)))?;
+    let local: SocketAddr = if peer.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    // one socket, sending through the sender and received from here
+    let socket = std::net::UdpSocket::bind(local)?;
+    socket.set_nonblocking(true)?;
+    let mut rx = UdpTransport::new(UdpSocket::from_std(socket.try_clone()?)?, peer);
+    let sender = FrameSender::new(
+        UdpTransport::new(UdpSocket::from_std(socket)?, peer),
+        counters,
+    );
+    let (msg_type, device) = (command.msg_type, command.device);
+    if !command.flags.ack_required() {
+        return Ok(
+            match sender.send_raw(msg_type, command.flags, device, body).await {
+                Ok(_) => Outcome::Sent,
+                Err(e) => Outcome::Failed(e.to_string()),
+            },
+        );
+    }
+    let config = RequestConfig {
+        timeout: command.timeout,
+        ..RequestConfig::default()
+    };
+    let requester = Requester::new(sender, config);
+    let answer = tokio::select! {
+        answer = requester.send_request(msg_type, device, body) => answer,
+        e = replies(&mut rx, &requester) => return Err(io::Error::other(e.to_string())),
+    };
+    Ok(match answer {
+        Ok(reply) => Outcome::Acked(reply),
+        Err(RequestError::Nacked(reason)) => Outcome::Nacked(reason),
+        Err(RequestError::ErrorFrame(code)) => Outcome::ErrorFrame(code),
+        Err(RequestError::Timeout) => Outcome::Timeout,
+        Err(e) => Outcome::Failed(e.to_string()),
+    })
+}
+
+fn run_send(command: &SendFrame) -> ExitCode {
+    let body = match &command.body {
+        Body::Bytes(bytes) => bytes.clone(),
+        Body::File(path) => match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("error: {}: {e}", path.display());
+                return ExitCode::from(2);
+            }
+        },
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("tokio runtime");
+    let (outcome, counter, rtt) = runtime.block_on(send_frame(command, body));
+    println!("{}", format_outcome(&outcome, counter, rtt, command.json));
+    ExitCode::from(outcome.exit_code())
+}
+
+/// Send `bytes` to `target` as they are, and describe what comes back
+/// within `timeout`.
+async fn send_bytes(bytes: &[u8], target: &str, timeout: Duration) -> io::Result<String> {
+    let peer = tokio::net::lookup_host(target)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("{target} did not resolve")))?;
+    let local: SocketAddr = if peer.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.send_to(bytes, peer).await?;
+    let mut out = format!("sent {} bytes", bytes.len());
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buf = vec![0; 65_536];
+    let mut replies = 0;
+    while let Ok(got) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (n, from) = got?;
+        replies += 1;
+        let _ = match FrameV1::decode(&buf[..n]) {
+            Ok(frame) => write!(out, "\nreply from {from}: {frame}"),
+            Err(e) => write!(
+                out,
+                "\nreply from {from}: {}",
+                format_error(&e, n, false).trim_end()
+            ),
+        };
+    }
+    if replies == 0 {
+        let _ = write!(out, ", no reply in {}ms", timeout.as_millis());
+    }
+    Ok(out)
+}
+
+/// Send the repl's frame: through the request path if it decodes and
+/// wants an ack, as raw bytes otherwise.
+async fn repl_send(bytes: &[u8], target: &str, timeout: Duration) -> String {
+    match FrameV1::decode(bytes) {
+        Ok(frame) if frame.header.flags.ack_required() => {
+            let h = &frame.header;
+            let command = SendFrame {
+                target: target.to_string(),
+                device: h.device_id,
+                msg_type: h.msg_type,
+                body: Body::Bytes(Vec::new()),
+                flags: h.flags,
+                counter: Some(h.counter),
+                timeout,
+                json: false,
+            };
+            let (outcome, counter, rtt) = send_frame(&command, frame.body.into_vec()).await;
+            format_outcome(&outcome, counter, rtt, false)
+        }
+        _ => send_bytes(bytes, target, timeout)
+            .await
+            .unwrap_or_else(|e| format!("failed: {e}")),
+    }
+}
+
+/// What a repl command prints, or why it failed.
+fn repl_step(
+    command: ReplCommand,
+    bench: &mut Workbench,
+    history: &[String],
+    send: impl FnOnce(&[u8], &str) -> String,
+) -> Result<String, String> {
+    Ok(match command {
+        ReplCommand::Edit(edit) => {
+            bench.apply(&edit);
+            String::new()
+        }
+        ReplCommand::Show => format_dump(bench.bytes(), &annotate(bench.bytes())),
+        ReplCommand::Send(target) => send(bench.bytes(), &target) + "\n",
+        ReplCommand::Save(path) => {
+            std::fs::write(&path, bench.template())
+                .map_err(|e| format!("{}: {e}", path.display()))?;
+            format!(
+                "saved {} bytes to {}\n",
+                bench.bytes().len(),
+                path.display()
+            )
+        }
+        ReplCommand::Load(path) => {
+            let text =
+                std::fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+            *bench =
+                Workbench::from_template(&text).map_err(|e| format!("{}: {e}", path.display()))?;
+            format!(
+                "loaded {} bytes from {}\n",
+                bench.bytes().len(),
+                path.display()
+            )
+        }
+        ReplCommand::History => history
+            .iter()
+            .enumerate()
+            .map(|(i, line)| format!("{:>4}  {line}\n", i + 1))
+            .collect(),
+        ReplCommand::Help => format!("{}\n", repl::HELP),
+        ReplCommand::Quit => unreachable!("quit ends the loop"),
+    })
+}
+
+fn run_repl(timeout: Duration) -> ExitCode {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("tokio runtime");
+    let stdin = io::stdin();
+    let prompt = stdin.is_terminal();
+    let mut bench = Workbench::default();
+    let mut history = Vec::new();
+    let mut line = String::new();
+    loop {
+        if prompt {
+            print!("pipproto> ");
+            let _ = io::stdout().flush();
+        }
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("error: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        let command = match repl::parse_line(&line) {
+            Ok(Some(ReplCommand::Quit)) => break,
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("error: {e}");
+                continue;
+            }
+        };
+        history.push(line.trim().to_string());
+        let send = |bytes: &[u8], target: &str| runtime.block_on(repl_send(bytes, target, timeout));
+        match repl_step(command, &mut bench, &history, send) {
+            Ok(out) => {
+                let _ = io::stdout().write_all(out.as_bytes());
+            }
+            Err(e) => eprintln!("error: {e}"),
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Devices `analyze` keeps apart; any more only count in the totals.
+const ANALYZE_DEVICES: usize = 100_000;
+
+/// What `analyze` has read of a journal so far.
+struct Analysis {
+    filter: Option<FrameFilter>,
+    stats: TrafficStats,
+    gaps: GapDetector,
+    records: u64,
+    decode_errors: BTreeMap<&'static str, u64>,
+    /// Frames by body length.
+    body_lens: BTreeMap<usize, u64>,
+    first: Option<SystemTime>,
+    last: Option<SystemTime>,
+}
+
+impl Analysis {
+    fn new(filter: Option<FrameFilter>) -> Self {
+        Analysis {
+            filter,
+            stats: TrafficStats::new(StatsConfig {
+                max_devices: ANALYZE_DEVICES,
+            }),
+            // everything still open at the end is a gap
+            gaps: GapDetector::new(GapConfig {
+                horizon: Duration::ZERO,
+                max_gaps: 4096,
+                max_devices: ANALYZE_DEVICES,
+                ..GapConfig::default()
+            }),
+            records: 0,
+            decode_errors: BTreeMap::new(),
+            body_lens: BTreeMap::new(),
+            first: None,
+            last: None,
+        }
+    }
+
+    fn add(&mut self, record: &FrameRecord) {
+        if let (Some(filter), Some(frame)) = (&self.filter, record.frame())
+            && !filter.matches(frame)
+        {
+            return;
+        }
+        self.records += 1;
+        let at = record.timestamp;
+        self.first = Some(self.first.map_or(at, |t| t.min(at)));
+        self.last = Some(self.last.map_or(at, |t| t.max(at)));
+        match &record.payload {
+            Payload::Frame(frame) => {
+                match record.direction {
+                    Direction::Rx => self.stats.on_rx(frame),
+                    Direction::Tx => self.stats.on_tx(frame),
+                }
+                *self.body_lens.entry(frame.body.len()).or_default() += 1;
+                // acks and errors repeat the counter of what they answer
+                let numbered = matches!(frame.header.msg_type, MsgType::Event | MsgType::Command);
+                if record.direction == Direction::Rx && numbered {
+                    self.gaps.on_frame(&frame.header);
+                }
+            }
+            Payload::Undecodable { bytes, error } => {
+                self.stats.on_decode_error(bytes, error);
+                *self.decode_errors.entry(error.kind()).or_default() += 1;
+            }
+        }
+    }
+
+    /// The smallest body length at least `p` of the frames have.
+    fn percentile(&self, p: f64) -> Option<usize> {
+        let total: u64 = self.body_lens.values().sum();
+        let rank = ((p * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.body_lens.iter().find_map(|(&len, &n)| {
+            seen += n;
+            (seen >= rank).then_some(len)
+        })
+    }
+}
+
+/// Counter gaps left in one device's received frames.
+struct DeviceGaps {
+    device_id: [u8; 8],
+    ranges: usize,
+    missing: u64,
+}
+
+struct Report {
+    analysis: Analysis,
+    top: usize,
+    /// Damaged bytes passed over, by offset and length.
+    damaged: Vec<(u64, u64)>,
+    /// Why reading ended before the end of the file, and the bytes left.
+    stopped: Option<(String, u64)>,
+}
+
+impl Report {
+    fn gaps(&mut self) -> Vec<DeviceGaps> {
+        let mut by_device: BTreeMap<[u8; 8], DeviceGaps> = BTreeMap::new();
+        for event in self.analysis.gaps.poll(Instant::now()) {
+            if let GapEvent::Missing(range) = event {
+                let d = by_device.entry(range.device_id).or_insert(DeviceGaps {
+                    device_id: range.device_id,
+                    ranges: 0,
+                    missing: 0,
+                });
+                d.ranges += 1;
+                d.missing += range.to.wrapping_sub(range.from).wrapping_add(1);
+            }
+        }
+        let mut gaps: Vec<_> = by_device.into_values().collect();
+        gaps.sort_by_key(|d| std::cmp::Reverse(d.missing));
+        gaps
+    }
+
+    fn format(mut self, json: bool) -> String {
+        let gaps = self.gaps();
+        let (gap_ranges, missing) = gaps
+            .iter()
+            .fold((0, 0), |(r, m), d| (r + d.ranges, m + d.missing));
+        let a = &self.analysis;
+        let snapshot = a.stats.snapshot();
+        let (rx, tx) = (&snapshot.rx, &snapshot.tx);
+        let types = [
+            ("event", rx.by_type.event + tx.by_type.event),
+            ("command", rx.by_type.command + tx.by_type.command),
+            ("ack", rx.by_type.ack + tx.by_type.ack),
+            ("error", rx.by_type.error + tx.by_type.error),
+        ];
+        let sizes = [
+            ("p50", a.percentile(0.5)),
+            ("p90", a.percentile(0.9)),
+            ("p99", a.percentile(0.99)),
+            ("max", a.body_lens.keys().next_back().copied()),
+        ];
+        let mut devices = snapshot.devices.clone();
+        devices.sort_by_key(|d| (std::cmp::Reverse(d.frames), d.device_id));
+        devices.truncate(self.top);
+        let span = match (a.first, a.last) {
+            (Some(first), Some(last)) => last.duration_since(first).unwrap_or_default(),
+            _ => Duration::ZERO,
+        };
+        let errors: u64 = a.decode_errors.values().sum();
+
+        let mut out = String::new();
+        if json {
+            let time =
+                |t: Option<SystemTime>| t.map_or("null".into(), |t| format!("\"{}\"", utc(t)));
+            let _ = write!(
+                out,
+                "{{\"records\":{},\"frames\":{},\"rx\":{},\"tx\":{},\"devices\":{},",
+                a.records,
+                rx.frames + tx.frames,
+                rx.frames,
+                tx.frames,
+                snapshot.devices.len()
+            );
+            let _ = write!(
+                out,
+                "\"first\":{},\"last\":{},\"span_secs\":{:.3},",
+                time(a.first),
+                time(a.last),
+                span.as_secs_f64()
+            );
+            let _ = write!(out, "\"decode_errors\":{{\"total\":{errors}");
+            for (kind, n) in &a.decode_errors {
+                let _ = write!(out, ",\"{kind}\":{n}");
+            }
+            let by_type: Vec<_> = types.iter().map(|(t, n)| format!("\"{t}\":{n}")).collect();
+            let _ = write!(out, "}},\"by_type\":{{{}}},", by_type.join(","));
+            let sizes: Vec<_> = sizes
+                .iter()
+                .map(|(p, n)| format!("\"{p}\":{}", n.map_or("null".into(), |n| n.to_string())))
+                .collect();
+            let _ = write!(out, "\"body_len\":{{{}}},", sizes.join(","));
+            let top: Vec<_> = devices
+                .iter()
+                .map(|d| {
+                    format!(
+                        "{{\"device_id\":\"{}\",\"frames\":{},\"bytes\":{}}}",
+                        hex(&d.device_id),
+                        d.frames,
+                        d.bytes
+                    )
+                })
+                .collect();
+            let _ = write!(out, "\"top_devices\":[{}],", top.join(","));
+            let gap_devices: Vec<_> = gaps
+                .iter()
+                .take(self.top)
+                .map(|d| {
+                    format!(
+                        "{{\"device_id\":\"{}\",\"ranges\":{},\"missing\":{}}}",
+                        hex(&d.device_id),
+                        d.ranges,
+                        d.missing
+                    )
+                })
+                .collect();
+            let _ = write!(
+                out,
+                "\"gaps\":{{\"devices\":{},\"ranges\":{gap_ranges},\"missing\":{missing},\"top\":[{}]}},",
+                gaps.len(),
+                gap_devices.join(",")
+            );
+            let damaged: Vec<_> = self
+                .damaged
+                .iter()
+                .map(|(offset, len)| format!("{{\"offset\":{offset},\"len\":{len}}}"))
+                .collect();
+            let _ = write!(out, "\"damaged\":[{}],", damaged.join(","));
+            let _ = match &self.stopped {
+                Some((why, left)) => write!(
+                    out,
+                    "\"stopped\":\"{}\",\"unread_bytes\":{left}}}",
+                    json_str(why)
+                ),
+                None => write!(out, "\"stopped\":null,\"unread_bytes\":0}}"),
+            };
+            out.push('\n');
+            return out;
+        }
+
+        let _ = write!(out, "records  {}", a.records);
+        if let (Some(first), Some(last)) = (a.first, a.last) {
+            let _ = write!(
+                out,
+                ", {} to {} ({:.3}s)",
+                utc(first),
+                utc(last),
+                span.as_secs_f64()
+            );
+        }
+        let _ = writeln!(
+            out,
+            "\nframes   {} (rx {}, tx {}) from {} devices",
+            rx.frames + tx.frames,
+            rx.frames,
+            tx.frames,
+            snapshot.devices.len()
+        );
+        let kinds: Vec<_> = a
+            .decode_errors
+            .iter()
+            .map(|(k, n)| format!("{k} {n}"))
+            .collect();
+        let _ = write!(out, "errors   {errors} frames did not decode");
+        if !kinds.is_empty() {
+            let _ = write!(out, ": {}", kinds.join(", "));
+        }
+        let types: Vec<_> = types.iter().map(|(t, n)| format!("{t} {n}")).collect();
+        let _ = writeln!(out, "\ntypes    {}", types.join(", "));
+        if sizes[0].1.is_some() {
+            let sizes: Vec<_> = sizes
+                .iter()
+                .map(|(p, n)| format!("{p} {}B", n.unwrap_or(0)))
+                .collect();
+            let _ = writeln!(out, "bodies   {}", sizes.join(", "));
+        }
+        if !devices.is_empty() {
+            let _ = writeln!(out, "busiest devices");
+            for d in &devices {
+                let _ = writeln!(
+                    out,
+                    "  {}  {} frames, {} bytes",
+                    hex(&d.device_id),
+                    d.frames,
+                    d.bytes
+                );
+            }
+        }
+        let _ = writeln!(
+            out,
+            "gaps     {missing} counters missing in {gap_ranges} ranges from {} devices",
+            gaps.len()
+        );
+        for d in gaps.iter().take(self.top) {
+            let _ = writeln!(
+                out,
+                "  {}  {} missing in {} ranges",
+                hex(&d.device_id),
+                d.missing,
+                d.ranges
+            );
+        }
+        for (offset, len) in &self.damaged {
+            let _ = writeln!(
+                out,
+                "warning: skipped {len} damaged bytes at offset {offset}"
+            );
+        }
+        if let Some((why, left)) = &self.stopped {
+            let _ = writeln!(out, "warning: stopped at {why}, {left} bytes not read");
+        }
+        out
+    }
+}
+
+/// Read the journal at `path` through, or up to where it is damaged.
+fn analyze(command: &Analyze) -> Result<Report, JournalError> {
+    let len = std::fs::metadata(&command.path)?.len();
+    let mut reader = ParallelReader::open(&command.path)?.with_threads(command.threads);
+    let mut analysis = Analysis::new(command.filter.clone());
+    let (mut damaged, mut failed) = (Vec::new(), None);
+    for record in reader.by_ref() {
+        match record {
+            Ok(record) => analysis.add(&record),
+            Err(JournalError::Skipped { offset, len }) => damaged.push((offset, len)),
+            Err(e) => failed = Some(e),
+        }
+    }
+    let left = len.saturating_sub(reader.offset());
+    let stopped = match failed {
+        Some(JournalError::Corrupt { offset }) => {
+            Some((format!("corrupt record at offset {offset}"), left))
+        }
+        Some(e) => Some((e.to_string(), left)),
+        None if left > 0 => Some((format!("torn record at offset {}", reader.offset()), left)),
+        None => None,
+    };
+    Ok(Report {
+        analysis,
+        top: command.top,
+        damaged,
+        stopped,
+    })
+}
+
+fn run_analyze(command: &Analyze) -> ExitCode {
+    match analyze(command) {
+        Ok(report) => {
+            let _ = io::stdout().write_all(report.format(command.json).as_bytes());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("error: {}: {e}", command.path.display());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The records of a journal, up to a corrupt or torn tail. It and any
+/// damaged records skipped are warned of on stderr.
+fn journal_records(path: &PathBuf) -> Result<Vec<FrameRecord>, JournalError> {
+    let len = std::fs::metadata(path)?.len();
+    let mut reader = JournalReader::open(path)?;
+    let mut records = Vec::new();
+    let mut failed = None;
+    for record in reader.by_ref() {
+        match record {
+            Ok(record) => records.push(record),
+            Err(e @ JournalError::Skipped { .. }) => {
+                eprintln!("warning: {}: {e}", path.display());
+            }
+            Err(e) => failed = Some(e),
+        }
+    }
+    let left = len.saturating_sub(reader.offset());
+    match failed {
+        Some(e) => eprintln!("warning: {}: {e}; {left} bytes not read", path.display()),
+        None if left > 0 => eprintln!(
+            "warning: {}: torn record at offset {}; {left} bytes not read",
+            path.display(),
+            reader.offset()
+        ),
+        None => {}
+    }
+    Ok(records)
+}
+
+/// The frames of a journal's records in `direction`, or in both.
+fn journal_frames(
+    path: &PathBuf,
+    direction: Option<Direction>,
+) -> Result<Vec<(SystemTime, FrameV1)>, JournalError> {
+    let frames = journal_records(path)?
+        .into_iter()
+        .filter(|r| direction.is_none_or(|d| d == r.direction))
+        .filter_map(|r| match r.payload {
+            Payload::Frame(frame) => Some((r.timestamp, frame)),
+            Payload::Undecodable { .. } => None,
+        })
+        .collect();
+    Ok(frames)
+}
+
+async fn replay(command: &Replay, records: Vec<FrameRecord>) -> io::Result<PlaybackSummary> {
+    let mut last_report = Instant::now();
+    let progress = |s: &PlaybackSummary| {
+        if last_report.elapsed() >= Duration::from_secs(1) {
+            last_report = Instant::now();
+            eprintln!(
+                "replay: {} sent, {} skipped, {} failed",
+                s.sent, s.skipped, s.failed
+            );
+        }
+    };
+    if command.tcp {
+        let mut transport = TcpTransport::connect(&command.target).await?;
+        let summary = playback::play(&mut transport, records, &command.config, progress).await;
+        let _ = transport.close().await;
+        return Ok(summary);
+    }
+    let peer = tokio::net::lookup_host(&command.target)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("{} did not resolve", command.target)))?;
+    let local: SocketAddr = if peer.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let mut transport = UdpTransport::new(UdpSocket::bind(local).await?, peer);
+    Ok(playback::play(&mut transport, records, &command.config, progress).await)
+}
+
+fn run_replay(command: &Replay) -> ExitCode {
+    let records = match journal_records(&command.path) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("error: {}: {e}", command.path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("tokio runtime");
+    let summary = match runtime.block_on(replay(command, records)) {
+        Ok(summary) => summary,
+        Err(e) => {
+            eprintln!("error: {}: {e}", command.target);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!(
+        "{} sent in {:.3}s, {} skipped, {} failed",
+        summary.sent,
+        summary.elapsed.as_secs_f64(),
+        summary.skipped,
+        summary.failed
+    );
+    if let Some(e) = &summary.last_error {
+        let end = if summary.disconnected {
+            "; stopped"
+        } else {
+            ""
+        };
+        eprintln!("error: {}: {e}{end}", command.target);
+    }
+    if summary.failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn difference_line(d: &Difference, json: bool) -> String {
+    let body_runs = |runs: &[pipproto::diff::ByteRun]| {
+        let runs: Vec<_> = runs
+            .iter()
+            .map(|r| {
+                format!(
+                    "{{\"offset\":{},\"left\":\"{}\",\"right\":\"{}\"}}",
+                    r.offset,
+                    hex(&r.left),
+                    hex(&r.right)
+                )
+            })
+            .collect();
+        runs.join(",")
+    };
+    match d {
+        Difference::Only { side, at, frame } if json => format!(
+            "{{\"kind\":\"only\",\"side\":\"{}\",\"at\":\"{}\",\"frame\":{{{}}}}}",
+            side.as_str(),
+            utc(*at),
+            json_fields(frame)
+        ),
+        Difference::Only { at, .. } => format!("{} {d}", utc(*at)),
+        Difference::Changed { left, right, body } if json => format!(
+            "{{\"kind\":\"changed\",\"device_id\":\"{}\",\"counter\":{},\"left\":{{{}}},\
+             \"right\":{{{}}},\"body\":[{}]}}",
+            left.header.device_id_hex(),
+            left.header.counter,
+            json_fields(left),
+            json_fields(right),
+            body_runs(body)
+        ),
+        Difference::Timing { id, left, right } if json => format!(
+            "{{\"kind\":\"timing\",\"device_id\":\"{}\",\"counter\":{},\
+             \"left_secs\":{:.3},\"right_secs\":{:.3}}}",
+            hex(&id.device_id),
+            id.counter,
+            left.as_secs_f64(),
+            right.as_secs_f64()
+        ),
+        _ => d.to_string(),
+    }
+}
+
+fn summary_line(s: &DiffSummary, json: bool) -> String {
+    let side = |s: &pipproto::diff::SideSummary| {
+        format!(
+            "{{\"frames\":{},\"duplicates\":{},\"only\":{}}}",
+            s.frames, s.duplicates, s.only
+        )
+    };
+    if json {
+        return format!(
+            "{{\"kind\":\"summary\",\"left\":{},\"right\":{},\"matched\":{},\
+             \"identical\":{},\"changed\":{},\"timing\":{}}}",
+            side(&s.left),
+            side(&s.right),
+            s.matched,
+            s.identical,
+            s.changed,
+            s.timing
+        );
+    }
+    format!(
+        "left {} frames ({} retransmitted, {} only there), right {} frames \
+         ({} retransmitted, {} only there); {} paired, {} identical, {} changed, \
+         {} apart in time",
+        s.left.frames,
+        s.left.duplicates,
+        s.left.only,
+        s.right.frames,
+        s.right.duplicates,
+        s.right.only,
+        s.matched,
+        s.identical,
+        s.changed,
+        s.timing
+    )
+}
+
+/// `JOURNAL@OFFSET`, or the two members of a JSON object.
+fn record_ref(paths: &[PathBuf], at: RecordRef, json: bool) -> String {
+    let path = paths[at.journal].display().to_string();
+    if json {
+        format!(
+            "{{\"journal\":\"{}\",\"offset\":{}}}",
+            json_str(&path),
+            at.offset
+        )
+    } else {
+        format!("{path}@{}", at.offset)
+    }
+}
+
+fn finding_line(paths: &[PathBuf], finding: &Finding, json: bool) -> String {
+    let at = |r| record_ref(paths, r, json);
+    match finding {
+        Finding::Duplicate {
+            device_id,
+            counter,
+            first,
+            second,
+        } if json => format!(
+            "{{\"kind\":\"duplicate\",\"device_id\":\"{}\",\"counter\":{counter},\
+             \"first\":{},\"second\":{}}}",
+            hex(device_id),
+            at(*first),
+            at(*second)
+        ),
+        Finding::Duplicate {
+            device_id,
+            counter,
+            first,
+            second,
+        } => format!(
+            "duplicate device={} counter={counter} at {}, first at {}",
+            hex(device_id),
+            at(*second),
+            at(*first)
+        ),
+        Finding::Regression {
+            device_id,
+            previous,
+            counter,
+            previous_at,
+            at: here,
+        } if json => format!(
+            "{{\"kind\":\"regression\",\"device_id\":\"{}\",\"previous\":{previous},\
+             \"counter\":{counter},\"previous_at\":{},\"at\":{}}}",
+            hex(device_id),
+            at(*previous_at),
+            at(*here)
+        ),
+        Finding::Regression {
+            device_id,
+            previous,
+            counter,
+            previous_at,
+            at: here,
+        } => format!(
+            "regression device={} counter={counter} at {}, after {previous} at {}",
+            hex(device_id),
+            at(*here),
+            at(*previous_at)
+        ),
+    }
+}
+
+fn format_audit(paths: &[PathBuf], report: &AuditReport, json: bool) -> String {
+    let mut out = String::new();
+    if json {
+        let devices: Vec<_> = report
+            .devices
+            .iter()
+            .map(|d| {
+                let gap = match d.largest_gap {
+                    Some(g) => format!(
+                        "{{\"after\":{},\"next\":{},\"missing\":{},\"at\":{}}}",
+                        g.after,
+                        g.next,
+                        g.missing(),
+                        record_ref(paths, g.at, true)
+                    ),
+                    None => "null".into(),
+                };
+                format!(
+                    "{{\"device_id\":\"{}\",\"frames\":{},\"retransmissions\":{},\
+                     \"duplicates\":{},\"regressions\":{},\"first_counter\":{},\
+                     \"highest_counter\":{},\"largest_gap\":{gap}}}",
+                    hex(&d.device_id),
+                    d.frames,
+                    d.retransmissions,
+                    d.duplicates,
+                    d.regressions,
+                    d.first_counter,
+                    d.highest_counter
+                )
+            })
+            .collect();
+        let stopped: Vec<_> = report
+            .stopped
+            .iter()
+            .map(|s| {
+                format!(
+                    "{{\"journal\":\"{}\",\"offset\":{},\"unread_bytes\":{},\"reason\":\"{}\"}}",
+                    json_str(&paths[s.journal].display().to_string()),
+                    s.offset,
+                    s.unread,
+                    json_str(&s.reason)
+                )
+            })
+            .collect();
+        let damaged: Vec<_> = report
+            .damaged
+            .iter()
+            .map(|d| {
+                format!(
+                    "{{\"journal\":\"{}\",\"offset\":{},\"len\":{}}}",
+                    json_str(&paths[d.journal].display().to_string()),
+                    d.offset,
+                    d.len
+                )
+            })
+            .collect();
+        let _ = writeln!(
+            out,
+            "{{\"kind\":\"report\",\"clean\":{},\"records\":{},\"skipped\":{},\
+             \"devices\":[{}],\"damaged\":[{}],\"stopped\":[{}]}}",
+            report.clean(),
+            report.records,
+            report.skipped,
+            devices.join(","),
+            damaged.join(","),
+            stopped.join(",")
+        );
+        return out;
+    }
+    let _ = writeln!(
+        out,
+        "{} records, {} skipped, {} devices: {}",
+        report.records,
+        report.skipped,
+        report.devices.len(),
+        if report.clean() {
+            "clean"
+        } else {
+            "counters reused or going backwards"
+        }
+    );
+    for d in &report.damaged {
+        let _ = writeln!(
+            out,
+            "{}: skipped {} damaged bytes at offset {}",
+            paths[d.journal].display(),
+            d.len,
+            d.offset
+        );
+    }
+    for s in &report.stopped {
+        let _ = writeln!(
+            out,
+            "{} ends early: {} at offset {}, {} bytes not read",
+            paths[s.journal].display(),
+            s.reason,
+            s.offset,
+            s.unread
+        );
+    }
+    if report.devices.is_empty() {
+        return out;
+    }
+    let _ = writeln!(
+        out,
+        "\n{:<16}  {:>10}  {:>10}  {:>10}  {:>10}  largest gap",
+        "device", "frames", "retrans", "duplicates", "regressed"
+    );
+    for d in &report.devices {
+        let gap = match d.largest_gap {
+            Some(g) => format!(
+                "{} after {} at {}",
+                g.missing(),
+                g.after,
+                record_ref(paths, g.at, false)
+            ),
+            None => "-".into(),
+        };
+        let _ = writeln!(
+            out,
+            "{}  {:>10}  {:>10}  {:>10}  {:>10}  {gap}",
+            hex(&d.device_id),
+            d.frames,
+            d.retransmissions,
+            d.duplicates,
+            d.regressions
+        );
+    }
+    out
+}
+
+fn run_audit(command: &Audit) -> ExitCode {
+    let mut stdout = io::stdout().lock();
+    let result = pipproto::audit::audit(&command.paths, &command.config, |f| {
+        let _ = writeln!(stdout, "{}", finding_line(&command.paths, f, command.json));
+    });
+    match result {
+        Ok(report) => {
+            let _ =
+                stdout.write_all(format_audit(&command.paths, &report, command.json).as_bytes());
+            if report.clean() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(AuditError::Journal { journal, error }) => {
+            eprintln!("error: {}: {error}", command.paths[journal].display());
+            ExitCode::from(2)
+        }
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run_diff(command: &Diff) -> ExitCode {
+    let read = |path: &PathBuf| {
+        journal_frames(path, command.direction)
+            .map_err(|e| eprintln!("error: {}: {e}", path.display()))
+    };
+    let (Ok(left), Ok(right)) = (read(&command.left), read(&command.right)) else {
+        return ExitCode::from(2);
+    };
+    let report = pipproto::diff::diff(left, right, &command.config);
+    let mut out = String::new();
+    for d in &report.differences {
+        let _ = writeln!(out, "{}", difference_line(d, command.json));
+    }
+    let _ = writeln!(out, "{}", summary_line(&report.summary, command.json));
+    let _ = io::stdout().write_all(out.as_bytes());
+    if report.summary.same() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn record_line(record: &FrameRecord, json: bool) -> String {
+    let at = utc(record.timestamp);
+    let direction = record.direction.as_str();
+    let from = record.source.as_ref().map(|s| s.to_string());
+    match &record.payload {
+        Payload::Frame(frame) if json => format!(
+            "{{\"at\":\"{at}\",\"direction\":\"{direction}\",\"from\":{},{}}}",
+            from.map_or("null".into(), |f| format!("\"{}\"", json_str(&f))),
+            json_fields(frame)
+        ),
+        Payload::Frame(frame) => {
+            format!(
+                "{at} {direction} {} {frame}",
+                from.as_deref().unwrap_or("-")
+            )
+        }
+        Payload::Undecodable { bytes, error } => {
+            let mut preview = hex(&bytes[..bytes.len().min(PREVIEW)]);
+            if bytes.len() > PREVIEW {
+                preview.push_str("...");
+            }
+            if json {
+                format!(
+                    "{{\"at\":\"{at}\",\"direction\":\"{direction}\",\"from\":{},\"error\":\"{error}\",\"len\":{},\"bytes\":\"{preview}\"}}",
+                    from.map_or("null".into(), |f| format!("\"{}\"", json_str(&f))),
+                    bytes.len()
+                )
+            } else {
+                format!(
+                    "{at} {direction} {} warning: {error} ({} bytes) {preview}",
+                    from.as_deref().unwrap_or("-"),
+                    bytes.len()
+                )
+            }
+        }
+    }
+}
+
+/// Identifies the file at a path, to notice it being replaced.
+#[cfg(unix)]
+fn file_id(meta: &std::fs::Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(meta)
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &std::fs::Metadata) -> u64 {
+    0
+}
+
+/// Where `tail` has read a journal up to.
+struct Follower<'a> {
+    tail: &'a Tail,
+    file: u64,
+    offset: u64,
+    /// The offset of a corrupt record already warned about.
+    warned: Option<u64>,
+}
+
+impl Follower<'_> {
+    /// Print what the journal already holds, as `-n` asks, and get ready
+    /// to follow it.
+    fn start<'a>(tail: &'a Tail, out: &mut dyn FnMut(&str)) -> Result<Follower<'a>, JournalError> {
+        let file = file_id(&std::fs::metadata(&tail.path)?);
+        let mut follower = Follower {
+            tail,
+            file,
+            offset: 0,
+            warned: None,
+        };
+        if let (Some(since), false, None) = (
+            tail.filter.since,
+            tail.filter.devices.is_empty(),
+            tail.lines,
+        ) && let Ok(index) = JournalIndex::for_journal(&tail.path)
+        {
+            follower.offset = tail
+                .filter
+                .devices
+                .iter()
+                .map(|&d| index.start(d, since))
+                .min()
+                .unwrap();
+        }
+        let mut last = VecDeque::new();
+        follower.read(&mut |line| match tail.lines {
+            None => out(line),
+            Some(n) => {
+                last.push_back(line.to_string());
+                if last.len() > n {
+                    last.pop_front();
+                }
+            }
+        })?;
+        for line in last {
+            out(&line);
+        }
+        Ok(follower)
+    }
+
+    /// Print the records after `offset` that pass the filter, as far as
+    /// they are complete.
+    fn read(&mut self, out: &mut dyn FnMut(&str)) -> Result<(), JournalError> {
+        let mut reader = JournalReader::open_at(&self.tail.path, self.offset)?;
+        let mut failed = None;
+        for record in reader.by_ref() {
+            match record {
+                Ok(record) if self.tail.filter.matches(&record) => {
+                    out(&record_line(&record, self.tail.json));
+                }
+                Ok(_) => {}
+                Err(e @ JournalError::Skipped { .. }) => {
+                    eprintln!("warning: {}: {e}", self.tail.path.display());
+                }
+                Err(e) => failed = Some(e),
+            }
+        }
+        self.offset = reader.offset();
+        if let Some(e) = failed
+            && self.warned != Some(self.offset)
+        {
+            eprintln!("warning: {}: {e}", self.tail.path.display());
+            self.warned = Some(self.offset);
+        }
+        Ok(())
+    }
+
+    /// Print what was appended since the last look, or all of the file if
+    /// it was replaced in the meantime, by a new file or a shorter one.
+    fn poll(&mut self, out: &mut dyn FnMut(&str)) {
+        // between the steps of a rotation there may be no file at all
+        let Ok(meta) = std::fs::metadata(&self.tail.path) else {
+            return;
+        };
+        if file_id(&meta) != self.file || meta.len() < self.offset {
+            self.file = file_id(&meta);
+            self.offset = 0;
+            self.warned = None;
+        } else if meta.len() == self.offset {
+            return;
+        }
+        // a new file may not have its magic yet; try again next time
+        let _ = self.read(out);
+    }
+}
+
+fn run_tail(tail: &Tail) -> ExitCode {
+    let mut out = |line: &str| {
+        let mut stdout = io::stdout().lock();
+        let _ = writeln!(stdout, "{line}").and_then(|()| stdout.flush());
+    };
+    let mut follower = match Follower::start(tail, &mut out) {
+        Ok(follower) => follower,
+        Err(e) => {
+            eprintln!("error: {}: {e}", tail.path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    if !tail.follow {
+        return ExitCode::SUCCESS;
+    }
+    loop {
+        std::thread::sleep(tail.poll);
+        follower.poll(&mut out);
+    }
+}
+
+/// The journals `reindex` was given, a directory standing for its segments.
+fn reindex_targets(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut targets = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            targets.push(path.clone());
+            continue;
+        }
+        let mut segments = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            let segment = entry?.path();
+            if segment.extension().is_some_and(|e| e == "log") {
+                segments.push(segment);
+            }
+        }
+        segments.sort();
+        targets.extend(segments);
+    }
+    Ok(targets)
+}
+
+fn run_reindex(command: &Reindex) -> Result<String, String> {
+    let mut out = String::new();
+    let targets = reindex_targets(&command.paths).map_err(|e| format!("error: {e}\n"))?;
+    for path in targets {
+        let index = JournalIndex::build(&path, command.bucket)
+            .map_err(|e| format!("{out}error: {}: {e}\n", path.display()))?;
+        let idx = JournalIndex::path_for(&path);
+        index
+            .save(&idx)
+            .map_err(|e| format!("{out}error: {}: {e}\n", idx.display()))?;
+        let _ = writeln!(
+            out,
+            "{}: {} entries over {} bytes",
+            idx.display(),
+            index.len(),
+            index.covered()
+        );
+    }
+    Ok(out)
+}
+
+/// The frame a JSON document describes, encoded.
+#[cfg(feature = "serde")]
+fn encode(input: &[u8]) -> Result<Vec<u8>, String> {
+    let frame: FrameV1 =
+        serde_json::from_slice(input).map_err(|e| format!("error: invalid frame: {e}\n"))?;
+    Ok(frame.encode())
+}
+
+#[cfg(not(feature = "serde"))]
+fn encode(_: &[u8]) -> Result<Vec<u8>, String> {
+    Err("error: encode needs the `serde` feature\n".into())
+}
+
+fn run_vectors(out: Option<&PathBuf>) -> Result<String, String> {
+    let json = vectors::to_json(&vectors::generate());
+    match out {
+        Some(path) => std::fs::write(path, json)
+            .map(|()| String::new())
+            .map_err(|e| format!("error: {}: {e}\n", path.display())),
+        None => Ok(json),
+    }
+}
+
+fn run_dissector(command: &Dissector) -> Result<String, String> {
+    let lua = dissector::lua(command.port);
+    match &command.out {
+        Some(path) => std::fs::write(path, lua)
+            .map(|()| String::new())
+            .map_err(|e| format!("error: {}: {e}\n", path.display())),
+        None => Ok(lua),
+    }
+}
+
+fn run_c_defs(out: Option<&PathBuf>) -> Result<String, String> {
+    let header = cdefs::header();
+    match out {
+        Some(path) => std::fs::write(path, header)
+            .map(|()| String::new())
+            .map_err(|e| format!("error: {}: {e}\n", path.display())),
+        None => Ok(header),
+    }
+}
+
+#[cfg(feature = "schema")]
+fn run_schema(out: Option<&PathBuf>) -> Result<String, String> {
+    let json = pipproto::schema::to_json(&pipproto::schema::frame_schema());
+    match out {
+        Some(path) => std::fs::write(path, json)
+            .map(|()| String::new())
+            .map_err(|e| format!("error: {}: {e}\n", path.display())),
+        None => Ok(json),
+    }
+}
+
+#[cfg(not(feature = "schema"))]
+fn run_schema(_: Option<&PathBuf>) -> Result<String, String> {
+    Err("error: schema needs the `schema` feature\n".into())
+}
+
+fn run_gen_corpus(command: &GenCorpus) -> Result<String, String> {
+    let cases = corpus::generate(command.seed, command.count);
+    corpus::write(&command.out, command.seed, &cases)
+        .map_err(|e| format!("error: {}: {e}\n", command.out.display()))?;
+    let valid = cases.iter().filter(|c| c.decodes()).count();
+    Ok(format!(
+        "wrote {} cases to {} ({valid} decode, {} do not)\n",
+        cases.len(),
+        command.out.display(),
+        cases.len() - valid
+    ))
+}
+
+#[cfg(feature = "serde")]
+fn run_verify_vectors(input: &[u8]) -> Result<String, String> {
+    let text = std::str::from_utf8(input).map_err(|_| "error: vectors file is not UTF-8\n")?;
+    let cases = vectors::parse(text).map_err(|e| format!("error: {e}\n"))?;
+    let mut out = String::new();
+    let mut failed = 0;
+    for case in &cases {
+        if let Err(why) = vectors::check(case) {
+            failed += 1;
+            let _ = writeln!(out, "FAIL {}: {why}", case.name);
+        }
+    }
+    let _ = writeln!(out, "{} vectors checked, {failed} failed", cases.len());
+    if failed == 0 { Ok(out) } else { Err(out) }
+}
+
+#[cfg(not(feature = "serde"))]
+fn run_verify_vectors(_: &[u8]) -> Result<String, String> {
+    Err("error: verify-vectors needs the `serde` feature\n".into())
+}
+
+fn run_encode(command: &Encode, input: &[u8]) -> Result<String, String> {
+    let bytes = encode(input)?;
+    match &command.out {
+        Some(path) => std::fs::write(path, bytes)
+            .map(|()| String::new())
+            .map_err(|e| format!("error: {}: {e}\n", path.display())),
+        None => Ok(hex(&bytes) + "\n"),
+    }
+}
+
+/// Rows of hex with, under each byte, its field's key, or `^^` if the
+/// field is wrong; then a legend of the fields.
+fn format_dump(bytes: &[u8], spans: &[FieldSpan]) -> String {
+    let key = |at: usize| {
+        let span = spans.iter().find(|s| s.range.contains(&at)).unwrap();
+        let k = span.name.chars().next().unwrap();
+        if span.ok {
+            format!("{k}{k}")
+        } else {
+            "^^".into()
+        }
+    };
+    let mut out = String::new();
+    for (row, chunk) in bytes.chunks(DUMP_WIDTH).enumerate() {
+        let start = row * DUMP_WIDTH;
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+        let keys: Vec<String> = (start..start + chunk.len()).map(key).collect();
+        let _ = writeln!(out, "{start:04x}  {}", hex.join(" "));
+        let _ = writeln!(out, "      {}", keys.join(" "));
+    }
+    if !bytes.is_empty() {
+        out.push('\n');
+    }
+    for span in spans {
+        let at = match (span.range.start, span.range.end) {
+            (s, e) if s == e => format!("{s}"),
+            (s, e) if e == s + 1 => format!("{s}"),
+            (s, e) => format!("{s}-{}", e - 1),
+        };
+        let _ = writeln!(
+            out,
+            "{}  {:<10} {at:<6} {}{}",
+            span.name.chars().next().unwrap(),
+            span.name,
+            span.value,
+            if span.ok { "" } else { "  <-- bad" }
+        );
+    }
+    out
+}
+
+/// The bytes given, read as hex unless `--binary`.
+fn input_bytes(decode: &Decode, input: &[u8]) -> Result<Vec<u8>, String> {
+    if decode.binary {
+        Ok(input.to_vec())
+    } else {
+        parse_hex(&String::from_utf8_lossy(input))
+    }
+}
+
+/// The dump, and whether the frame decodes.
+fn run_dump(dump: &Decode, input: &[u8]) -> Result<(String, bool), String> {
+    let bytes = input_bytes(dump, input)?;
+    let spans = annotate(&bytes);
+    Ok((format_dump(&bytes, &spans), spans.iter().all(|s| s.ok)))
+}
+
+/// What to print, and whether the frame decoded.
+fn run(decode: &Decode, input: &[u8]) -> Result<String, String> {
+    let bytes = input_bytes(decode, input)?;
+    match FrameV1::decode(&bytes) {
+        Ok(frame) if decode.json => Ok(format_json(&frame)),
+        Ok(frame) => Ok(format_text(&frame)),
+        Err(e) => Err(format_error(&e, bytes.len(), decode.json)),
+    }
+}
+
+/// Frames generated for `bench`, cycled through.
+const BENCH_POOL: usize = 1024;
+/// Frames between clock reads in `bench`, one of them timed on its own.
+const BENCH_CHUNK: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BenchOp {
+    Encode,
+    Decode,
+    /// Just the header, leaving the body where it is.
+    DecodeHeader,
+}
+
+impl BenchOp {
+    const ALL: [BenchOp; 3] = [BenchOp::Encode, BenchOp::Decode, BenchOp::DecodeHeader];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            BenchOp::Encode => "encode",
+            BenchOp::Decode => "decode",
+            BenchOp::DecodeHeader => "decode_header",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct BenchResult {
+    op: BenchOp,
+    threads: usize,
+    frames: u64,
+    bytes: u64,
+    elapsed: Duration,
+    /// Sampled per-frame times, sorted.
+    latencies: Vec<Duration>,
+}
+
+impl BenchResult {
+    fn frames_per_sec(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn mb_per_sec(&self) -> f64 {
+        self.bytes as f64 / 1e6 / self.elapsed.as_secs_f64()
+    }
+
+    fn latency(&self, q: f64) -> Duration {
+        let last = self.latencies.len().saturating_sub(1);
+        self.latencies
+            .get((last as f64 * q).round() as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Frames with bodies drawn from the sizes, the same ones every run.
+fn bench_frames(sizes: &[RangeInclusive<usize>]) -> Vec<FrameV1> {
+    pipproto::workload::mixed(BENCH_POOL, sizes)
+}
+
+/// One thread's share of a run: warm up, then go until `duration` is up.
+fn bench_thread(
+    op: BenchOp,
+    frames: &[FrameV1],
+    encoded: &[Vec<u8>],
+    bench: &Bench,
+) -> (u64, u64, Duration, Vec<Duration>) {
+    let once = |i: usize| match op {
+        BenchOp::Encode => black_box(black_box(&frames[i]).encode()).len(),
+        BenchOp::Decode => {
+            black_box(FrameV1::decode(black_box(&encoded[i]))).map_or(0, |f| f.encoded_len())
+        }
+        BenchOp::DecodeHeader => {
+            let _ = black_box(FrameHeaderV1::decode(black_box(&encoded[i])));
+            encoded[i].len()
+        }
+    };
+    let mut i = 0;
+    let start = Instant::now();
+    while start.elapsed() < bench.warmup {
+        for _ in 0..BENCH_CHUNK {
+            once(i % frames.len());
+            i += 1;
+        }
+    }
+    let (mut count, mut bytes, mut latencies) = (0, 0, Vec::new());
+    let start = Instant::now();
+    while start.elapsed() < bench.duration {
+        let timed = Instant::now();
+        bytes += once(i % frames.len()) as u64;
+        latencies.push(timed.elapsed());
+        for _ in 1..BENCH_CHUNK {
+            i += 1;
+            bytes += once(i % frames.len()) as u64;
+        }
+        i += 1;
+        count += BENCH_CHUNK as u64;
+    }
+    (count, bytes, start.elapsed(), latencies)
+}
+
+fn bench_run(op: BenchOp, threads: usize, frames: &[FrameV1], bench: &Bench) -> BenchResult {
+    let encoded: Vec<_> = frames.iter().map(FrameV1::encode).collect();
+    let shares: Vec<_> = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| s.spawn(|| bench_thread(op, frames, &encoded, bench)))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    let mut result = BenchResult {
+        op,
+        threads,
+        frames: 0,
+        bytes: 0,
+        elapsed: Duration::ZERO,
+        latencies: Vec::new(),
+    };
+    for (frames, bytes, elapsed, latencies) in shares {
+        result.frames += frames;
+        result.bytes += bytes;
+        result.elapsed = result.elapsed.max(elapsed);
+        result.latencies.extend(latencies);
+    }
+    result.latencies.sort();
+    result
+}
+
+/// Every operation, on one thread and then on `bench.threads`.
+fn bench(bench: &Bench) -> Vec<BenchResult> {
+    let frames = bench_frames(&bench.body_sizes);
+    let mut threads = vec![1];
+    if bench.threads > 1 {
+        threads.push(bench.threads);
+    }
+    let mut out = Vec::new();
+    for op in BenchOp::ALL {
+        for &n in &threads {
+            out.push(bench_run(op, n, &frames, bench));
+        }
+    }
+    out
+}
+
+fn format_bench(bench: &Bench, results: &[BenchResult]) -> String {
+    let quantiles = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)];
+    let mut out = String::new();
+    if bench.json {
+        let sizes: Vec<_> = bench
+            .body_sizes
+            .iter()
+            .map(|r| format!("[{},{}]", r.start(), r.end()))
+            .collect();
+        let _ = write!(
+            out,
+            "{{\"duration_ms\":{},\"warmup_ms\":{},\"body_sizes\":[{}],\"results\":[",
+            bench.duration.as_millis(),
+            bench.warmup.as_millis(),
+            sizes.join(",")
+        );
+        let results: Vec<_> = results
+            .iter()
+            .map(|r| {
+                let latency: Vec<_> = quantiles
+                    .iter()
+                    .map(|(name, q)| format!("\"{name}\":{}", r.latency(*q).as_nanos()))
+                    .collect();
+                format!(
+                    "{{\"op\":\"{}\",\"threads\":{},\"frames\":{},\"bytes\":{},\"secs\":{:.3},\
+                     \"frames_per_sec\":{:.0},\"mb_per_sec\":{:.1},\"latency_ns\":{{{}}}}}",
+                    r.op.as_str(),
+                    r.threads,
+                    r.frames,
+                    r.bytes,
+                    r.elapsed.as_secs_f64(),
+                    r.frames_per_sec(),
+                    r.mb_per_sec(),
+                    latency.join(",")
+                )
+            })
+            .collect();
+        let _ = writeln!(out, "{}]}}", results.join(","));
+        return out;
+    }
+    for r in results {
+        let latency: Vec<_> = quantiles
+            .iter()
+            .map(|(name, q)| format!("{name} {:?}", r.latency(*q)))
+            .collect();
+        let _ = writeln!(
+            out,
+            "{:<13} {:>3} thread{}  {:>12.0} frames/s  {:>9.1} MB/s  {}",
+            r.op.as_str(),
+            r.threads,
+            if r.threads == 1 { " " } else { "s" },
+            r.frames_per_sec(),
+            r.mb_per_sec(),
+            latency.join("  ")
+        );
+    }
+    out
+}
+
+pub(crate) fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Ok(command) => command,
+        Err(usage) => {
+            eprintln!("{usage}");
+            return ExitCode::from(2);
+        }
+    };
+    let input = match &command {
+        Command::Decode(c) | Command::Dump(c) => &c.input,
+        Command::Encode(c) => &c.input,
+        Command::VerifyVectors(input) => input,
+        Command::Vectors(out) => return finish(run_vectors(out.as_ref())),
+        Command::Dissector(c) => return finish(run_dissector(c)),
+        Command::CDefs(out) => return finish(run_c_defs(out.as_ref())),
+        Command::Schema(out) => return finish(run_schema(out.as_ref())),
+        Command::GenCorpus(c) => return finish(run_gen_corpus(c)),
+        Command::Bench(c) => return finish(Ok(format_bench(c, &bench(c)))),
+        Command::Listen(c) => return run_listen(c),
+        Command::Send(c) => return run_send(c),
+        Command::Repl(timeout) => return run_repl(*timeout),
+        Command::Analyze(c) => return run_analyze(c),
+        Command::Diff(c) => return run_diff(c),
+        Command::Audit(c) => return run_audit(c),
+        Command::Replay(c) => return run_replay(c),
+        Command::Tail(c) => return run_tail(c),
+        Command::Reindex(c) => return finish(run_reindex(c)),
+    };
+    let input = match read_input(input) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(2);
+        }
+    };
+    let result = match &command {
+        Command::Decode(c) => run(c, &input),
+        Command::Dump(c) => match run_dump(c, &input) {
+            Ok((dump, ok)) => {
+                let _ = io::stdout().write_all(dump.as_bytes());
+                return if ok {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::FAILURE
+                };
+            }
+            Err(e) => Err(e),
+        },
+        Command::Encode(c) => run_encode(c, &input),
+        Command::VerifyVectors(_) => run_verify_vectors(&input),
+        Command::Listen(_)
+        | Command::Send(_)
+        | Command::Repl(_)
+        | Command::Analyze(_)
+        | Command::Diff(_)
+        | Command::Audit(_)
+        | Command::Replay(_)
+        | Command::Tail(_)
+        | Command::Reindex(_)
+        | Command::Vectors(_)
+        | Command::Dissector(_)
+        | Command::CDefs(_)
+        | Command::Schema(_)
+        | Command::GenCorpus(_)
+        | Command::Bench(_) => {
+            unreachable!()
+        }
+    };
+    finish(result)
+}
+
+/// Output to stdout on success, to stderr with status 1 otherwise.
+fn finish(result: Result<String, String>) -> ExitCode {
+    match result {
+        Ok(out) => {
+            let _ = io::stdout().write_all(out.as_bytes());
+            ExitCode::SUCCESS
+        }
+        Err(out) => {
+            let _ = io::stderr().write_all(out.as_bytes());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pipproto::{Flags, FrameHeaderV1, VERSION_V1};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    fn parse_decode(s: &str) -> Result<Decode, String> {
+        match parse_args(&args(s))? {
+            Command::Decode(d) => Ok(d),
+            c => panic!("not a decode: {c:?}"),
+        }
+    }
+
+    fn frame(body: &[u8]) -> FrameV1 {
+        FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Event,
+                flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+                device_id: *b"DEV00001",
+                counter: 7,
+            },
+            body: body.into(),
+        }
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let decode = parse_decode("decode --json 5050 0101").unwrap();
+        assert_eq!(decode.input, Input::Args("5050 0101".into()));
+        assert!(decode.json && !decode.binary);
+        let decode = parse_decode("decode --binary --file f.bin").unwrap();
+        assert_eq!(decode.input, Input::File("f.bin".into()));
+        assert_eq!(parse_decode("decode").unwrap().input, Input::Stdin);
+
+        assert!(parse_decode("decode --binary 5050").is_err());
+        assert!(parse_decode("decode --file").is_err());
+        assert!(parse_decode("decode --file f 5050").is_err());
+        assert!(parse_decode("decode --frobnicate").is_err());
+        assert!(parse_args(&args("frobnicate")).is_err());
+        assert!(parse_args(&[]).is_err());
+
+        assert_eq!(
+            parse_args(&args("encode --file f.json --out f.bin")).unwrap(),
+            Command::Encode(Encode {
+                input: Input::File("f.json".into()),
+                out: Some("f.bin".into()),
+            })
+        );
+        assert!(parse_args(&args("encode --out")).is_err());
+        assert!(parse_args(&args("encode 5050")).is_err());
+        assert!(matches!(
+            parse_args(&args("dump 5050")),
+            Ok(Command::Dump(_))
+        ));
+        assert!(parse_args(&args("dump --json 5050")).is_err());
+
+        assert_eq!(
+            parse_args(&args("dissector --port 5683 --out p.lua")).unwrap(),
+            Command::Dissector(Dissector {
+                port: 5683,
+                out: Some("p.lua".into()),
+            })
+        );
+        assert!(matches!(
+            parse_args(&args("dissector")),
+            Ok(Command::Dissector(Dissector {
+                port: 4000,
+                out: None
+            }))
+        ));
+        assert!(parse_args(&args("dissector --port 70000")).is_err());
+        assert_eq!(
+            parse_args(&args("c-defs --out d.h")).unwrap(),
+            Command::CDefs(Some("d.h".into()))
+        );
+        assert!(parse_args(&args("c-defs --file x")).is_err());
+        assert_eq!(parse_args(&args("schema")).unwrap(), Command::Schema(None));
+
+        assert_eq!(
+            parse_args(&args("gen-corpus --seed 7 --out c")).unwrap(),
+            Command::GenCorpus(GenCorpus {
+                seed: 7,
+                count: 2000,
+                out: "c".into(),
+            })
+        );
+        assert!(parse_args(&args("gen-corpus --count 5")).is_err());
+    }
+
+    #[test]
+    fn dumps_with_the_bad_bytes_marked() {
+        let mut bytes = frame(b"hi").encode();
+        bytes[3] = 0x09;
+        let dump = Decode {
+            input: Input::Stdin,
+            binary: true,
+            json: false,
+        };
+        let (out, ok) = run_dump(&dump, &bytes).unwrap();
+        assert!(!ok);
+        assert_eq!(
+            out,
+            "0000  50 50 01 09 01 44 45 56 30 30 30 30 31 00 00 00\n\
+             \x20     mm mm vv ^^ ff dd dd dd dd dd dd dd dd cc cc cc\n\
+             0010  00 00 00 00 07 68 69\n\
+             \x20     cc cc cc cc cc bb bb\n\
+             \n\
+             m  magic      0-1    PP\n\
+             v  version    2      1\n\
+             t  type       3      unknown 0x09  <-- bad\n\
+             f  flags      4      ACK_REQUIRED\n\
+             d  device_id  5-12   4445563030303031\n\
+             c  counter    13-20  7\n\
+             b  body       21-22  2 bytes\n"
+        );
+        let (out, ok) = run_dump(&dump, &bytes[..4]).unwrap();
+        assert!(!ok);
+        assert!(
+            out.ends_with("f  flags      4      missing  <-- bad\n"),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn parses_listen_arguments() {
+        let Command::Listen(listen) = parse_args(&args(
+            "listen --udp 4000 --tcp 127.0.0.1:4001 --json --device 4445563030303031 --type Event",
+        ))
+        .unwrap() else {
+            panic!("not a listen");
+        };
+        assert_eq!(listen.udp, Some("0.0.0.0:4000".parse().unwrap()));
+        assert_eq!(listen.tcp, Some("127.0.0.1:4001".parse().unwrap()));
+        assert!(listen.json);
+        assert_eq!(
+            listen.filter,
+            Filter {
+                device: Some(*b"DEV00001"),
+                msg_type: Some(MsgType::Event),
+                expr: None,
+            }
+        );
+        let with_expr = [
+            "listen",
+            "--udp",
+            "1",
+            "--filter",
+            "counter > 5 && !ack_required",
+        ];
+        let Command::Listen(listen) = parse_args(&with_expr.map(String::from)).unwrap() else {
+            panic!("not a listen");
+        };
+        let mut f = frame(b"");
+        assert!(!listen.filter.matches(&f));
+        f.header.flags = Flags::new(0).unwrap();
+        assert!(listen.filter.matches(&f));
+        let bad = ["listen", "--udp", "1", "--filter", "counter >> 5"];
+        assert_eq!(
+            parse_args(&bad.map(String::from)).unwrap_err(),
+            "--filter: expected a value, found `>` at offset 9\n  counter >> 5\n           ^"
+        );
+        assert!(parse_args(&args("listen --json")).is_err(), "no socket");
+        assert!(parse_args(&args("listen --udp nowhere")).is_err());
+        assert!(parse_args(&args("listen --udp 1 --device 4445")).is_err());
+        assert!(parse_args(&args("listen --udp 1 --type beacon")).is_err());
+    }
+
+    fn collecting(json: bool, filter: Filter) -> (Arc<Printer>, Arc<Mutex<Vec<String>>>) {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        let printer = Printer {
+            json,
+            filter,
+            counts: Counts::default(),
+            out: Box::new(move |line| sink.lock().unwrap().push(line.to_string())),
+        };
+        (Arc::new(printer), lines)
+    }
+
+    #[test]
+    fn formats_listen_lines() {
+        let at = UNIX_EPOCH + Duration::from_millis(1_790_000_000_123);
+        assert_eq!(utc(at), "2026-09-21T14:13:20.123Z");
+        assert_eq!(utc(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let filter = Filter {
+            device: None,
+            msg_type: Some(MsgType::Command),
+            expr: None,
+        };
+        let (text, _) = collecting(false, Filter::default());
+        let (json, _) = collecting(true, filter);
+        let from = "10.0.0.7:4000";
+        assert_eq!(
+            text.line(at, from, Seen::Frame(frame(b"hi"))).unwrap(),
+            "2026-09-21T14:13:20.123Z 10.0.0.7:4000 event dev=4445563030303031 ctr=7 ack body=2B"
+        );
+        assert_eq!(json.line(at, from, Seen::Frame(frame(b"hi"))), None);
+        let bad = Seen::Malformed {
+            error: "bad \"magic\"".into(),
+            bytes: &[0xab; 20],
+        };
+        assert_eq!(
+            json.line(at, from, bad).unwrap(),
+            "{\"at\":\"2026-09-21T14:13:20.123Z\",\"from\":\"10.0.0.7:4000\",\
+             \"error\":\"bad \\\"magic\\\"\",\"len\":20,\"bytes\":\"abababababababababababababababab...\"}"
+        );
+        assert_eq!(json.summary(), "1 frames received, 0 printed, 1 malformed");
+    }
+
+    #[tokio::test]
+    async fn prints_what_arrives() {
+        let (printer, lines) = collecting(false, Filter::default());
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (udp_addr, tcp_addr) = (udp.local_addr().unwrap(), tcp.local_addr().unwrap());
+        let send = async {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client
+                .send_to(&frame(b"hi").encode(), udp_addr)
+                .await
+                .unwrap();
+            let mut bad = frame(b"").encode();
+            bad[4] = 0x80;
+            client.send_to(&bad, udp_addr).await.unwrap();
+            while lines.lock().unwrap().len() < 2 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            let mut stream = TcpStream::connect(tcp_addr).await.unwrap();
+            let mut prefixed = Vec::new();
+            pipproto::codec::write_prefixed(&frame(b"tcp"), &mut prefixed);
+            prefixed.extend(7u32.to_be_bytes());
+            prefixed.extend(b"PP\x01\x01\x00ab");
+            tokio::io::AsyncWriteExt::write_all(&mut stream, &prefixed)
+                .await
+                .unwrap();
+            while lines.lock().unwrap().len() < 4 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::select! {
+            _ = serve_udp(udp, &printer) => unreachable!(),
+            _ = serve_tcp(tcp, printer.clone()) => unreachable!(),
+            _ = send => {}
+        }
+        let lines = lines.lock().unwrap();
+        assert!(lines[0].ends_with("ctr=7 ack body=2B"), "{}", lines[0]);
+        assert!(
+            lines[1].ends_with("warning: reserved flag bits set: 0b10000000 at offset 4 (21 bytes) 50500101804445563030303031000000..."),
+            "{}",
+            lines[1]
+        );
+        assert!(lines[2].ends_with("ack body=3B"), "{}", lines[2]);
+        assert!(
+            lines[3].ends_with("warning: input too short at offset 7 (7 bytes) 50500101006162"),
+            "{}",
+            lines[3]
+        );
+        assert_eq!(
+            printer.summary(),
+            "2 frames received, 2 printed, 2 malformed"
+        );
+    }
+
+    fn parse_send(s: &str) -> Result<SendFrame, String> {
+        match parse_args(&args(s))? {
+            Command::Send(c) => Ok(c),
+            c => panic!("not a send: {c:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_send_arguments() {
+        let send = parse_send("send 10.0.0.5:9000 --device 4445563030303031").unwrap();
+        assert_eq!(send.target, "10.0.0.5:9000");
+        assert_eq!(send.device, *b"DEV00001");
+        assert_eq!(send.msg_type, MsgType::Command);
+        assert!(send.flags.ack_required());
+        assert_eq!(send.timeout, Duration::from_secs(5));
+
+        let send = parse_send(
+            "send h:1 --device 4445563030303031 --type event --body base64:aGk= \
+             --flags 0 --counter 42 --timeout 250ms --json",
+        )
+        .unwrap();
+        assert_eq!(send.msg_type, MsgType::Event);
+        assert_eq!(send.body, Body::Bytes(b"hi".to_vec()));
+        assert!(!send.flags.ack_required());
+        assert_eq!(send.counter, Some(42));
+        assert_eq!(send.timeout, Duration::from_millis(250));
+        assert!(send.json);
+
+        assert_eq!(parse_body("@x.bin").unwrap(), Body::File("x.bin".into()));
+        assert_eq!(parse_body("6869").unwrap(), Body::Bytes(b"hi".to_vec()));
+        assert_eq!(parse_base64("aGVsbG8").unwrap(), b"hello");
+        assert!(parse_body("base64:a*").is_err());
+        assert_eq!(parse_duration("1.5").unwrap(), Duration::from_millis(1500));
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_send("send h:1").unwrap_err().contains("--device"));
+        assert!(parse_send("send --device 4445563030303031").is_err());
+        assert!(parse_send("send h:1 --device 4445563030303031 --flags 0x80").is_err());
+    }
+
+    #[test]
+    fn parses_bench_arguments() {
+        let Ok(Command::Bench(b)) = parse_args(&args(
+            "bench --duration 1s --warmup 100ms --threads 3 --body-size 0..64,1024 --json",
+        )) else {
+            panic!()
+        };
+        assert_eq!(
+            b,
+            Bench {
+                duration: Duration::from_secs(1),
+                warmup: Duration::from_millis(100),
+                threads: 3,
+                body_sizes: vec![0..=64, 1024..=1024],
+                json: true,
+            }
+        );
+        assert!(parse_args(&args("bench --threads 0")).is_err());
+        assert!(parse_args(&args("bench --body-size 9..3")).is_err());
+        assert!(parse_args(&args("bench --body-size 1,")).is_err());
+    }
+
+    #[test]
+    fn benches_every_operation() {
+        let b = Bench {
+            duration: Duration::from_millis(20),
+            warmup: Duration::from_millis(5),
+            threads: 2,
+            body_sizes: vec![8..=8],
+            json: true,
+        };
+        let frames = bench_frames(&b.body_sizes);
+        assert_eq!(frames.len(), BENCH_POOL);
+        assert!(frames.iter().all(|f| f.body.len() == 8));
+        let results = bench(&b);
+        let runs: Vec<_> = results.iter().map(|r| (r.op, r.threads)).collect();
+        assert_eq!(
+            runs,
+            [
+                (BenchOp::Encode, 1),
+                (BenchOp::Encode, 2),
+                (BenchOp::Decode, 1),
+                (BenchOp::Decode, 2),
+                (BenchOp::DecodeHeader, 1),
+                (BenchOp::DecodeHeader, 2),
+            ]
+        );
+        for r in &results {
+            assert!(r.frames > 0 && r.frames % BENCH_CHUNK as u64 == 0);
+            assert_eq!(r.bytes, r.frames * 29);
+            assert_eq!(r.latencies.len() as u64, r.frames / BENCH_CHUNK as u64);
+            assert!(r.elapsed >= b.duration);
+        }
+        let json: serde_json::Value = serde_json::from_str(&format_bench(&b, &results)).unwrap();
+        assert_eq!(json["results"][3]["op"], "decode");
+        assert_eq!(json["results"][3]["threads"], 2);
+        assert!(json["results"][3]["latency_ns"]["p99"].is_u64());
+    }
+
+    #[test]
+    fn parses_analyze_arguments() {
+        assert_eq!(
+            parse_args(&args("analyze --top 3 --json j.log")).unwrap(),
+            Command::Analyze(Analyze {
+                path: "j.log".into(),
+                json: true,
+                top: 3,
+                filter: None,
+                threads: 1,
+            })
+        );
+        match parse_args(&args("analyze --threads 8 j.log")).unwrap() {
+            Command::Analyze(a) => assert_eq!(a.threads, 8),
+            c => panic!("not an analyze: {c:?}"),
+        }
+        assert!(parse_args(&args("analyze --threads 0 j.log")).is_err());
+        assert!(parse_args(&args("analyze")).is_err());
+        assert!(parse_args(&args("analyze j.log --top x")).is_err());
+    }
+
+    fn parse_tail(s: &str) -> Result<Tail, String> {
+        match parse_args(&args(s))? {
+            Command::Tail(t) => Ok(t),
+            c => panic!("not a tail: {c:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_tail_arguments() {
+        let tail = parse_tail("tail j.log").unwrap();
+        assert_eq!((tail.lines, tail.follow), (Some(10), false));
+        let tail = parse_tail(
+            "tail -f --device 4445563030303031 --device 4445563030303032 --type ack \
+             --counter 5.. --direction tx --since 2023-11-14T22:13:20.5Z --json j.log",
+        )
+        .unwrap();
+        assert!(tail.follow && tail.json);
+        assert_eq!(tail.lines, None, "--since reads from the start");
+        assert_eq!(tail.filter.devices, [*b"DEV00001", *b"DEV00002"]);
+        assert_eq!(tail.filter.types, [MsgType::Ack]);
+        assert_eq!(tail.filter.counters, Some(5..=u64::MAX));
+        assert_eq!(tail.filter.direction, Some(Direction::Tx));
+        let since = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        assert_eq!(tail.filter.since, Some(since));
+        assert_eq!(
+            parse_tail("tail -n 3 --from-start j.log")
+                .unwrap_err()
+                .lines()
+                .next(),
+            Some("-n and --from-start conflict")
+        );
+        assert_eq!(parse_tail("tail --from-start j.log").unwrap().lines, None);
+
+        assert_eq!(parse_time(&utc(since)).unwrap(), since);
+        assert_eq!(parse_time("1700000000.5").unwrap(), since);
+        assert_eq!(
+            parse_time("2000-02-29T00:00:00Z").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(951_782_400)
+        );
+        assert!(parse_time("2000-13-01T00:00:00Z").is_err());
+        assert!(parse_time("yesterday").is_err());
+        assert_eq!(parse_counters("3..=7").unwrap(), 3..=7);
+        assert_eq!(parse_counters("..7").unwrap(), 0..=7);
+        assert_eq!(parse_counters("9").unwrap(), 9..=9);
+        assert!(parse_counters("a..b").is_err());
+    }
+
+    #[test]
+    fn tail_filters_and_follows_a_journal() {
+        use pipproto::journal::JournalWriter;
+        use pipproto::sink::FrameSink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        let write = |journal: &mut JournalWriter, counters: std::ops::Range<u64>| {
+            for counter in counters {
+                let mut f = frame(b"");
+                f.header.counter = counter;
+                let mut record = FrameRecord::rx(f);
+                record.timestamp = UNIX_EPOCH + Duration::from_secs(counter);
+                journal.record(&record).unwrap();
+            }
+            let bad =
+                FrameRecord::undecodable(Direction::Rx, b"PP".to_vec(), DecodeError::TooShort);
+            journal
+                .record(&FrameRecord {
+                    timestamp: UNIX_EPOCH,
+                    ..bad
+                })
+                .unwrap();
+            journal.flush().unwrap();
+        };
+        let mut journal = JournalWriter::open(&path).unwrap();
+        write(&mut journal, 1..6);
+
+        let mut tail = parse_tail("tail -n 2 --counter 2..4 x").unwrap();
+        tail.path = path.clone();
+        let mut lines = Vec::new();
+        let mut follower = Follower::start(&tail, &mut |l| lines.push(l.to_string())).unwrap();
+        assert_eq!(
+            lines,
+            [
+                "1970-01-01T00:00:03.000Z rx - event dev=4445563030303031 ctr=3 ack body=0B",
+                "1970-01-01T00:00:04.000Z rx - event dev=4445563030303031 ctr=4 ack body=0B",
+            ]
+        );
+
+        lines.clear();
+        follower.poll(&mut |l| lines.push(l.to_string()));
+        assert!(lines.is_empty());
+        write(&mut journal, 2..3);
+        follower.poll(&mut |l| lines.push(l.to_string()));
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("ctr=2"));
+
+        // rotated: the old file moved aside, a new one in its place
+        drop(journal);
+        std::fs::rename(&path, dir.path().join("journal.log.1")).unwrap();
+        lines.clear();
+        follower.poll(&mut |l| lines.push(l.to_string()));
+        assert!(lines.is_empty());
+        let mut journal = JournalWriter::open(&path).unwrap();
+        write(&mut journal, 4..5);
+        follower.poll(&mut |l| lines.push(l.to_string()));
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("ctr=4"));
+
+        tail.filter = RecordFilter::default();
+        tail.lines = None;
+        tail.json = true;
+        lines.clear();
+        Follower::start(&tail, &mut |l| lines.push(l.to_string())).unwrap();
+        assert_eq!(
+            lines[1],
+            r#"{"at":"1970-01-01T00:00:00.000Z","direction":"rx","from":null,"error":"input too short","len":2,"bytes":"5050"}"#
+        );
+    }
+
+    #[test]
+    fn tail_starts_where_the_index_points() {
+        use pipproto::journal::JournalWriter;
+        use pipproto::sink::FrameSink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        let mut journal = JournalWriter::open(&path).unwrap();
+        for counter in 0..600 {
+            let mut f = frame(b"");
+            f.header.counter = counter;
+            f.header.device_id[7] = b'0' + (counter % 3) as u8;
+            let mut record = FrameRecord::rx(f);
+            record.timestamp = UNIX_EPOCH + Duration::from_secs(counter);
+            journal.record(&record).unwrap();
+        }
+        journal.flush().unwrap();
+
+        let mut tail = parse_tail("tail --since 540 --device 4445563030303031 x").unwrap();
+        tail.path = path.clone();
+        let mut lines = Vec::new();
+        let scanned = Follower::start(&tail, &mut |l| lines.push(l.to_string())).unwrap();
+        assert_eq!(lines.len(), 20);
+
+        let reindex = Reindex {
+            paths: vec![dir.path().to_path_buf()],
+            bucket: Duration::from_secs(60),
+        };
+        assert_eq!(
+            run_reindex(&reindex).unwrap(),
+            format!(
+                "{}: 30 entries over {} bytes\n",
+                dir.path().join("journal.idx").display(),
+                scanned.offset
+            )
+        );
+        let mut indexed = Vec::new();
+        Follower::start(&tail, &mut |l| indexed.push(l.to_string())).unwrap();
+        assert_eq!(indexed, lines);
+        // the minute from 540s on starts with ctr=541 for this device
+        let mut first = JournalReader::open(&path).unwrap();
+        first.next();
+        let record_len = first.offset() - 4;
+        let since = UNIX_EPOCH + Duration::from_secs(540);
+        let index = JournalIndex::for_journal(&path).unwrap();
+        assert_eq!(index.start(*b"DEV00001", since), 4 + 541 * record_len);
+    }
+
+    #[test]
+    fn formats_outcomes() {
+        let ms = Duration::from_micros(1500);
+        let data = Outcome::Acked(Reply::Data(b"ok".to_vec()));
+        assert_eq!(
+            format_outcome(&data, 9, ms, false),
+            "ack for ctr=9 in 1.5ms, reply 2 bytes 6f6b"
+        );
+        assert_eq!(
+            format_outcome(&data, 9, ms, true),
+            r#"{"outcome":"ack","reply":"6f6b","counter":9,"rtt_ms":1.500}"#
+        );
+        let nack = Outcome::Nacked("busy".into());
+        assert_eq!(
+            format_outcome(&nack, 9, ms, false),
+            "nack for ctr=9 in 1.5ms: busy"
+        );
+        assert_eq!(
+            format_outcome(&Outcome::ErrorFrame(0x0102), 9, ms, false),
+            "error 0x0102 for ctr=9 in 1.5ms"
+        );
+        let codes: Vec<_> = [
+            Outcome::Acked(Reply::Ack),
+            Outcome::Sent,
+            Outcome::Failed(String::new()),
+            nack,
+            Outcome::ErrorFrame(1),
+            Outcome::Timeout,
+        ]
+        .iter()
+        .map(Outcome::exit_code)
+        .collect();
+        assert_eq!(codes, [0, 0, 1, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn sends_and_waits_for_the_answer() {
+        let device = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut send = parse_send("send x --device 4445563030303031 --timeout 2s").unwrap();
+        send.target = device.local_addr().unwrap().to_string();
+        send.counter = Some(77);
+        let answer = async {
+            let mut buf = [0; 128];
+            // a nack, then an ERROR frame
+            for reply in [
+                |h: &FrameHeaderV1| {
+                    pipproto::request::ack_reply(
+                        h,
+                        pipproto::ack::AckBody::Nack {
+                            reason: "busy".into(),
+                        },
+                    )
+                },
+                |h: &FrameHeaderV1| pipproto::request::error_reply(h, 7, b""),
+            ] {
+                let (n, from) = device.recv_from(&mut buf).await.unwrap();
+                let command = FrameV1::decode(&buf[..n]).unwrap();
+                assert_eq!(command.header.counter, 77);
+                assert_eq!(command.body, b"hi");
+                device
+                    .send_to(&reply(&command.header).encode(), from)
+                    .await
+                    .unwrap();
+            }
+            std::future::pending::<()>().await;
+        };
+        let run = async {
+            let (first, counter, _) = send_frame(&send, b"hi".to_vec()).await;
+            let (second, _, _) = send_frame(&send, b"hi".to_vec()).await;
+            send.timeout = Duration::from_millis(50);
+            let (third, _, rtt) = send_frame(&send, b"hi".to_vec()).await;
+            (first, counter, second, third, rtt)
+        };
+        let (first, counter, second, third, rtt) = tokio::select! {
+            _ = answer => unreachable!(),
+            r = run => r,
+        };
+        assert_eq!(first, Outcome::Nacked("busy".into()));
+        assert_eq!(counter, 77);
+        assert_eq!(second, Outcome::ErrorFrame(7));
+        assert_eq!(third, Outcome::Timeout);
+        assert!(rtt >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn tolerates_whitespace_in_hex() {
+        assert_eq!(
+            parse_hex("de ad\n be\tef\n").unwrap(),
+            [0xde, 0xad, 0xbe, 0xef]
+        );
+        assert!(parse_hex("abc").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+
+    #[test]
+    fn prints_each_field() {
+        let body: Vec<u8> = (0..20).collect();
+        let text = format_text(&frame(&body));
+        assert_eq!(
+            text,
+            "version:   1\n\
+             type:      event\n\
+             flags:     0x01 ACK_REQUIRED\n\
+             device_id: 4445563030303031\n\
+             counter:   7\n\
+             body:      20 bytes 000102030405060708090a0b0c0d0e0f...\n"
+        );
+        assert_eq!(
+            format_json(&frame(b"hi")),
+            "{\"version\":1,\"msg_type\":\"event\",\"flags\":[\"ACK_REQUIRED\"],\
+             \"device_id\":\"4445563030303031\",\"counter\":7,\"body_len\":2,\"body\":\"6869\"}\n"
+        );
+    }
+
+    #[test]
+    fn decodes_hex_and_binary_input() {
+        let bytes = frame(b"hi").encode();
+        let hex_input = Decode {
+            input: Input::Stdin,
+            binary: false,
+            json: false,
+        };
+        let spaced: Vec<String> = bytes.iter().map(|b| format!("{b:02X}")).collect();
+        let out = run(&hex_input, spaced.join(" ").as_bytes()).unwrap();
+        assert!(out.contains("counter:   7\n"));
+        let binary = Decode {
+            binary: true,
+            ..hex_input
+        };
+        assert_eq!(run(&binary, &bytes).unwrap(), out);
+    }
+
+    #[test]
+    fn reports_decode_errors_with_the_offset() {
+        let mut bytes = frame(b"").encode();
+        bytes[3] = 0x09;
+        let decode = Decode {
+            input: Input::Stdin,
+            binary: true,
+            json: false,
+        };
+        assert_eq!(
+            run(&decode, &bytes).unwrap_err(),
+            "error: unknown msg_type: 0x09 at offset 3\n"
+        );
+        let json = Decode {
+            json: true,
+            ..decode
+        };
+        assert_eq!(
+            run(&json, &bytes[..5]).unwrap_err(),
+            "{\"error\":\"too_short\",\"message\":\"input too short\",\"offset\":5}\n"
+        );
+    }
+
+    #[test]
+    fn repl_steps() {
+        assert_eq!(
+            parse_args(&args("repl --timeout 500ms")),
+            Ok(Command::Repl(Duration::from_millis(500)))
+        );
+        assert!(parse_args(&args("repl 127.0.0.1:4000")).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("frame.hex");
+        let mut bench = Workbench::default();
+        let mut history = Vec::new();
+        let mut step = |line: &str, bench: &mut Workbench| {
+            history.push(line.to_string());
+            let command = repl::parse_line(line).unwrap().unwrap();
+            repl_step(command, bench, &history, |bytes, target| {
+                format!("{} bytes to {target}", bytes.len())
+            })
+        };
+        step("set body 0102", &mut bench).unwrap();
+        let shown = step("show", &mut bench).unwrap();
+        assert!(shown.contains("0010  00 00 00 00 01 01 02"), "{shown}");
+        assert!(shown.contains("b  body       21-22"), "{shown}");
+        assert_eq!(
+            step("send 127.0.0.1:4000", &mut bench).unwrap(),
+            "23 bytes to 127.0.0.1:4000\n"
+        );
+        let save = format!("save {}", path.display());
+        assert!(
+            step(&save, &mut bench)
+                .unwrap()
+                .starts_with("saved 23 bytes")
+        );
+        step("reset", &mut bench).unwrap();
+        let load = format!("load {}", path.display());
+        step(&load, &mut bench).unwrap();
+        assert_eq!(bench.frame().unwrap().body, [1, 2]);
+        let listed = step("history", &mut bench).unwrap();
+        assert!(listed.starts_with("   1  set body 0102\n   2  show\n"));
+        assert!(listed.ends_with("   7  history\n"));
+        let missing = format!("load {}", dir.path().join("nope").display());
+        assert!(step(&missing, &mut bench).is_err());
+    }
+
+    #[test]
+    fn parses_audit() {
+        let Command::Audit(audit) = parse_args(&args(
+            "audit --direction both --buckets 8 --threads 4 --json a.log b.log",
+        ))
+        .unwrap() else {
+            panic!("not an audit");
+        };
+        assert_eq!(
+            audit.paths,
+            [PathBuf::from("a.log"), PathBuf::from("b.log")]
+        );
+        assert_eq!(audit.config.direction, None);
+        assert_eq!(audit.config.buckets, 8);
+        assert_eq!(audit.config.threads, 4);
+        assert!(audit.json);
+        assert!(parse_args(&args("audit")).is_err());
+        assert!(parse_args(&args("audit --buckets 0 a.log")).is_err());
+    }
+
+    #[test]
+    fn parses_reindex() {
+        let Command::Reindex(reindex) =
+            parse_args(&args("reindex --bucket 5s a.log journal")).unwrap()
+        else {
+            panic!("not a reindex");
+        };
+        assert_eq!(
+            reindex.paths,
+            [PathBuf::from("a.log"), PathBuf::from("journal")]
+        );
+        assert_eq!(reindex.bucket, Duration::from_secs(5));
+        assert!(parse_args(&args("reindex")).is_err());
+        assert!(parse_args(&args("reindex --bucket 0s a.log")).is_err());
+    }
+}
@@ -0,0 +1,829 @@
+//! tokio-util codec for the length-prefixed stream framing (RFC section 11).
+//!
+//! Items are `Result<FrameV1, DecodeError>`: a frame that fails to decode
+//! is reported without ending the stream, because its length prefix kept
+//! the stream in sync. Framing errors ([`ProtocolError::TooLarge`], a
+//! truncated tail) do end it.
+//!
+//! [`SharedFrameCodec`] decodes [`FrameV1B`]s instead, their bodies
+//! slices of the read buffer rather than copies, and
+//! [`write_prefixed_vectored`] and [`write_prefixed_vectored_async`] write
+//! a frame without copying its body next to its header first.
+//!
+//! [`decode_all`], [`decode_all_shared`] and [`decode_each`] decode a
+//! whole buffer of length-prefixed frames, such as a burst read in one go.
+//!
+//! [`decode_header_then_stream`] reads one length-prefixed frame from a
+//! blocking reader without buffering its body, for bodies too large to
+//! hold in memory.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::AsyncWrite;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::transport::{ProtocolError, TransportError};
+use crate::vectored;
+use crate::{DecodeError, FrameHeaderV1, FrameV1, FrameV1B, HEADER_LEN_V1, MAGIC};
+
+pub const DEFAULT_MAX_FRAME_LEN: usize = 64 * 1024;
+
+pub(crate) const PREFIX_LEN: usize = 4;
+
+/// Append `frame` to `out` with its length prefix.
+pub fn write_prefixed(frame: &FrameV1, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(frame.encoded_len() as u32).to_be_bytes());
+    frame.encode_into(out);
+}
+
+/// The length prefix and header of `frame`.
+fn prefixed_head<B: AsRef<[u8]>>(frame: &FrameV1<B>) -> [u8; PREFIX_LEN + HEADER_LEN_V1] {
+    let mut head = [0u8; PREFIX_LEN + HEADER_LEN_V1];
+    head[..PREFIX_LEN].copy_from_slice(&(frame.encoded_len() as u32).to_be_bytes());
+    head[PREFIX_LEN..].copy_from_slice(&frame.header.to_bytes());
+    head
+}
+
+/// Write `frame` with its length prefix to `out`, the prefix and header
+/// and the body as separate buffers of one vectored write where `out`
+/// supports them.
+pub fn write_prefixed_vectored<B: AsRef<[u8]>>(
+    frame: &FrameV1<B>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    vectored::write_all(out, [&prefixed_head(frame), frame.body.as_ref()])
+}
+
+/// As [`write_prefixed_vectored`], for an async writer.
+pub async fn write_prefixed_vectored_async<B: AsRef<[u8]>>(
+    frame: &FrameV1<B>,
+    out: &mut (impl AsyncWrite + Unpin),
+) -> io::Result<()> {
+    vectored::write_all_async(out, [&prefixed_head(frame), frame.body.as_ref()]).await
+}
+
+/// `frame` with its length prefix, as a buffer whose body part is the
+/// frame's own, for `AsyncWriteExt::write_all_buf`.
+pub fn prefixed_buf(frame: &FrameV1B) -> impl Buf + use<> {
+    Bytes::copy_from_slice(&prefixed_head(frame)).chain(frame.body.clone())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    max_frame_len: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_len: usize) -> Self {
+        FrameCodec { max_frame_len }
+    }
+
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    /// Split the next frame, length prefix and all, off `src`.
+    fn split_frame(&self, src: &mut BytesMut) -> Result<Option<BytesMut>, TransportError> {
+        let Some(prefix) = src.first_chunk::<PREFIX_LEN>() else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(*prefix) as usize;
+        if len > self.max_frame_len {
+            return Err(ProtocolError::TooLarge(len).into());
+        }
+        if src.len() < PREFIX_LEN + len {
+            src.reserve(PREFIX_LEN + len - src.len());
+            return Ok(None);
+        }
+        Ok(Some(src.split_to(PREFIX_LEN + len)))
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec::new(DEFAULT_MAX_FRAME_LEN)
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Result<FrameV1, DecodeError>;
+    type Error = TransportError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, TransportError> {
+        let Some(bytes) = self.split_frame(src)? else {
+            return Ok(None);
+        };
+        let item = FrameV1::decode(&bytes[PREFIX_LEN..]);
+        #[cfg(feature = "metrics")]
+        {
+            let m = crate::metrics::global();
+            m.bytes_in(bytes.len());
+            m.decoded(&item);
+        }
+        #[cfg(feature = "tracing")]
+        crate::trace::decoded(&item, bytes.len() - PREFIX_LEN);
+        Ok(Some(item))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, TransportError> {
+        match self.decode(src)? {
+            Some(item) => Ok(Some(item)),
+            None if src.is_empty() => Ok(None),
+            None => Err(ProtocolError::Truncated.into()),
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> Encoder<&FrameV1<B>> for FrameCodec {
+    type Error = TransportError;
+
+    fn encode(&mut self, frame: &FrameV1<B>, dst: &mut BytesMut) -> Result<(), TransportError> {
+        let len = frame.encoded_len();
+        if len > self.max_frame_len {
+            return Err(ProtocolError::TooLarge(len).into());
+        }
+        dst.reserve(PREFIX_LEN + len);
+        dst.put_u32(len as u32);
+        dst.extend_from_slice(&frame.header.to_bytes());
+        dst.extend_from_slice(frame.body.as_ref());
+        #[cfg(feature = "metrics")]
+        {
+            let m = crate::metrics::global();
+            m.bytes_out(PREFIX_LEN + len);
+            m.encoded(frame);
+        }
+        #[cfg(feature = "tracing")]
+        crate::trace::encoded(frame);
+        Ok(())
+    }
+}
+
+impl<B: AsRef<[u8]>> Encoder<FrameV1<B>> for FrameCodec {
+    type Error = TransportError;
+
+    fn encode(&mut self, frame: FrameV1<B>, dst: &mut BytesMut) -> Result<(), TransportError> {
+        self.encode(&frame, dst)
+    }
+}
+
+/// As [`FrameCodec`], but decoding [`FrameV1B`]s whose bodies are slices
+/// of the read buffer: a frame holds on to the part of the buffer it was
+/// read into until it is dropped, and the codec reads on into new space.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SharedFrameCodec(pub FrameCodec);
+
+impl Decoder for SharedFrameCodec {
+    type Item = Result<FrameV1B, DecodeError>;
+    type Error = TransportError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, TransportError> {
+        let Some(bytes) = self.0.split_frame(src)? else {
+            return Ok(None);
+        };
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().bytes_in(bytes.len());
+        let mut bytes = bytes.freeze();
+        bytes.advance(PREFIX_LEN);
+        #[cfg(feature = "tracing")]
+        let len = bytes.len();
+        let item = FrameV1B::decode_bytes(bytes);
+        #[cfg(feature = "metrics")]
+        match &item {
+            Ok(f) => crate::metrics::global().received(f),
+            Err(e) => crate::metrics::global().decode_error(e),
+        }
+        #[cfg(feature = "tracing")]
+        crate::trace::decoded(&item, len);
+        Ok(Some(item))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, TransportError> {
+        match self.decode(src)? {
+            Some(item) => Ok(Some(item)),
+            None if src.is_empty() => Ok(None),
+            None => Err(ProtocolError::Truncated.into()),
+        }
+    }
+}
+
+impl<T> Encoder<T> for SharedFrameCodec
+where
+    FrameCodec: Encoder<T, Error = TransportError>,
+{
+    type Error = TransportError;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), TransportError> {
+        self.0.encode(item, dst)
+    }
+}
+
+/// What [`decode_each`] does after a frame that does not decode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Stop there.
+    #[default]
+    Stop,
+    /// Carry on: after the frame, when its length prefix was fine, or
+    /// else from the next place a frame looks to start.
+    Resync,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Frames longer than this are [`DecodeIssue::BadLength`].
+    pub max_frame_len: usize,
+    pub on_error: OnError,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            on_error: OnError::Stop,
+        }
+    }
+}
+
+/// Something wrong in a buffer of frames, at the offset of a length
+/// prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeIssue {
+    /// The frame failed to decode.
+    Frame { offset: usize, error: DecodeError },
+    /// The length is over the maximum.
+    BadLength { offset: usize, len: usize },
+    /// The frame runs past the end of the buffer.
+    Truncated { offset: usize },
+    /// `len` bytes were passed over to get back in step.
+    Skipped { offset: usize, len: usize },
+}
+
+impl DecodeIssue {
+    pub fn offset(&self) -> usize {
+        match self {
+            DecodeIssue::Frame { offset, .. }
+            | DecodeIssue::BadLength { offset, .. }
+            | DecodeIssue::Truncated { offset }
+            | DecodeIssue::Skipped { offset, .. } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for DecodeIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeIssue::Frame { offset, error } => write!(f, "frame at offset {offset}: {error}"),
+            DecodeIssue::BadLength { offset, len } => {
+                write!(f, "bad frame length {len} at offset {offset}")
+            }
+            DecodeIssue::Truncated { offset } => write!(f, "truncated frame at offset {offset}"),
+            DecodeIssue::Skipped { offset, len } => {
+                write!(f, "skipped {len} bytes at offset {offset}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeIssue {}
+
+/// The length of the frame prefixed at `at`, if it is within the maximum
+/// and fits in `buf`.
+fn frame_len(buf: &[u8], at: usize, max: usize) -> Result<usize, DecodeIssue> {
+    let prefix = buf[at..].first_chunk::<PREFIX_LEN>();
+    let Some(prefix) = prefix else {
+        return Err(DecodeIssue::Truncated { offset: at });
+    };
+    let len = u32::from_be_bytes(*prefix) as usize;
+    if len > max {
+        return Err(DecodeIssue::BadLength { offset: at, len });
+    }
+    if buf.len() - at - PREFIX_LEN < len {
+        return Err(DecodeIssue::Truncated { offset: at });
+    }
+    Ok(len)
+}
+
+/// The next offset after `from` where a whole frame with a valid header
+/// starts.
+fn resync(buf: &[u8], from: usize, max: usize) -> Option<usize> {
+    (from..buf.len().saturating_sub(PREFIX_LEN + HEADER_LEN_V1 - 1)).find(|&at| {
+        let head = &buf[at + PREFIX_LEN..];
+        head.starts_with(&MAGIC)
+            && frame_len(buf, at, max).is_ok()
+            && FrameHeaderV1::decode(head).is_ok()
+    })
+}
+
+/// Walk a buffer of length-prefixed frames, as [`write_prefixed`] writes
+/// them, passing each that decodes to `frame` with its offset and its
+/// body still in `buf`. Returns what went wrong along the way: with
+/// [`OnError::Stop`], at most one issue, where decoding stopped.
+pub fn decode_each<'a>(
+    buf: &'a [u8],
+    options: &DecodeOptions,
+    mut frame: impl FnMut(usize, FrameHeaderV1, &'a [u8]),
+) -> Vec<DecodeIssue> {
+    let mut issues = Vec::new();
+    let mut at = 0;
+    while at < buf.len() {
+        let issue = match frame_len(buf, at, options.max_frame_len) {
+            Ok(len) => {
+                let bytes = &buf[at + PREFIX_LEN..at + PREFIX_LEN + len];
+                let offset = at;
+                at += PREFIX_LEN + len;
+                match FrameHeaderV1::decode(bytes) {
+                    Ok(header) => {
+                        frame(offset, header, &bytes[HEADER_LEN_V1..]);
+                        continue;
+                    }
+                    Err(error) => DecodeIssue::Frame { offset, error },
+                }
+            }
+            Err(issue) => {
+                let Some(next) = resync(buf, at + 1, options.max_frame_len)
+                    .filter(|_| options.on_error == OnError::Resync)
+                else {
+                    issues.push(issue);
+                    break;
+                };
+                // a frame follows, so the length was wrong, not cut short
+                issues.push(match issue {
+                    DecodeIssue::Truncated { offset } => DecodeIssue::BadLength {
+                        offset,
+                        len: u32::from_be_bytes(*buf[offset..].first_chunk().unwrap()) as usize,
+                    },
+                    issue => issue,
+                });
+                let skipped = DecodeIssue::Skipped {
+                    offset: at,
+                    len: next - at,
+                };
+                at = next;
+                skipped
+            }
+        };
+        issues.push(issue);
+        if options.on_error == OnError::Stop {
+            break;
+        }
+    }
+    issues
+}
+
+/// As [`decode_each`], collecting the frames.
+pub fn decode_all(buf: &[u8], options: &DecodeOptions) -> (Vec<FrameV1>, Vec<DecodeIssue>) {
+    let mut frames = Vec::new();
+    let issues = decode_each(buf, options, |_, header, body| {
+        frames.push(FrameV1 {
+            header,
+            body: body.into(),
+        })
+    });
+    (frames, issues)
+}
+
+/// As [`decode_all`], the bodies left in `buf` rather than copied.
+pub fn decode_all_shared(
+    buf: &Bytes,
+    options: &DecodeOptions,
+) -> (Vec<FrameV1B>, Vec<DecodeIssue>) {
+    let mut frames = Vec::new();
+    let issues = decode_each(buf, options, |_, header, body| {
+        frames.push(FrameV1 {
+            header,
+            body: buf.slice_ref(body),
+        })
+    });
+    (frames, issues)
+}
+
+const STREAM_CHUNK: usize = 8 * 1024;
+
+/// [`decode_header_then_stream`] failed. Every variant says how many body
+/// bytes already reached the sink, so partial output can be cleaned up.
+#[derive(Debug)]
+pub enum StreamBodyError {
+    /// The header was invalid; nothing was written.
+    Decode(DecodeError),
+    /// The reader ended before the frame did.
+    Truncated { written: u64 },
+    /// Reading or writing failed.
+    Io { error: io::Error, written: u64 },
+}
+
+impl StreamBodyError {
+    pub fn written(&self) -> u64 {
+        match self {
+            StreamBodyError::Decode(_) => 0,
+            StreamBodyError::Truncated { written } | StreamBodyError::Io { written, .. } => {
+                *written
+            }
+        }
+    }
+}
+
+impl fmt::Display for StreamBodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamBodyError::Decode(e) => write!(f, "{e}"),
+            StreamBodyError::Truncated { written } => {
+                write!(f, "stream ended after {written} body bytes")
+            }
+            StreamBodyError::Io { error, written } => {
+                write!(f, "io error after {written} body bytes: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StreamBodyError {}
+
+/// Read one length-prefixed frame from `reader`, validate its header, then
+/// copy exactly its body into `sink` in chunks, returning the header.
+///
+/// No maximum length applies; the body is never held in memory. v1 frames
+/// carry no checksum, so the body is passed through as read.
+pub fn decode_header_then_stream(
+    reader: &mut impl Read,
+    sink: &mut impl Write,
+) -> Result<FrameHeaderV1, StreamBodyError> {
+    let io_err = |error: io::Error, written| match error.kind() {
+        io::ErrorKind::UnexpectedEof => StreamBodyError::Truncated { written },
+        _ => StreamBodyError::Io { error, written },
+    };
+
+    let mut head = [0u8; PREFIX_LEN + HEADER_LEN_V1];
+    reader
+        .read_exact(&mut head[..PREFIX_LEN])
+        .map_err(|e| io_err(e, 0))?;
+    let len = u32::from_be_bytes(*head.first_chunk::<PREFIX_LEN>().unwrap()) as usize;
+    if len < HEADER_LEN_V1 {
+        return Err(StreamBodyError::Decode(DecodeError::TooShort));
+    }
+    reader
+        .read_exact(&mut head[PREFIX_LEN..])
+        .map_err(|e| io_err(e, 0))?;
+    let header = FrameHeaderV1::decode(&head[PREFIX_LEN..]).map_err(StreamBodyError::Decode)?;
+
+    let mut remaining = (len - HEADER_LEN_V1) as u64;
+    let mut written = 0;
+    let mut buf = [0u8; STREAM_CHUNK];
+    while remaining > 0 {
+        let want = remaining.min(STREAM_CHUNK as u64) as usize;
+        let n = match reader.read(&mut buf[..want]) {
+            Ok(0) => return Err(StreamBodyError::Truncated { written }),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(io_err(e, written)),
+        };
+        remaining -= n as u64;
+        let mut chunk = &buf[..n];
+        while !chunk.is_empty() {
+            match sink.write(chunk) {
+                Ok(0) => {
+                    let error = io::Error::from(io::ErrorKind::WriteZero);
+                    return Err(StreamBodyError::Io { error, written });
+                }
+                Ok(accepted) => {
+                    written += accepted as u64;
+                    chunk = &chunk[accepted..];
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(StreamBodyError::Io { error, written }),
+            }
+        }
+    }
+    #[cfg(feature = "metrics")]
+    crate::metrics::global().bytes_in(PREFIX_LEN + len);
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::frame;
+
+    #[test]
+    fn encode_then_decode() {
+        let mut codec = FrameCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(&frame(1), &mut buf).unwrap();
+        codec.encode(frame(2), &mut buf).unwrap();
+        assert_eq!(&buf[..4], &24u32.to_be_bytes());
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Ok(frame(1))));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Ok(frame(2))));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn partial_input_waits() {
+        let mut codec = FrameCodec::default();
+        let mut full = BytesMut::new();
+        codec.encode(&frame(1), &mut full).unwrap();
+
+        let mut buf = BytesMut::from(&full[..10]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&full[10..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Ok(frame(1))));
+    }
+
+    #[test]
+    fn shared_bodies_are_slices_of_the_read_buffer() {
+        let mut codec = SharedFrameCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(&frame(1), &mut buf).unwrap();
+        codec.encode(FrameV1B::from(frame(2)), &mut buf).unwrap();
+        buf.extend_from_slice(&[0, 0, 0, 3, b'P', b'P', 1]);
+        let start = buf.as_ptr() as usize;
+
+        let first = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(FrameV1::from(first.clone()), frame(1));
+        assert_eq!(
+            first.body.as_ptr() as usize,
+            start + PREFIX_LEN + HEADER_LEN_V1
+        );
+        let second = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(FrameV1::from(second), frame(2));
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(DecodeError::TooShort))
+        );
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn vectored_writes_match_flat_ones() {
+        /// Takes at most 5 bytes per write, so writes are resumed mid-buffer.
+        struct Trickle(Vec<u8>);
+
+        impl Write for Trickle {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let n = buf.len().min(5);
+                self.0.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut flat = Vec::new();
+        write_prefixed(&frame(7), &mut flat);
+        let mut out = Trickle(Vec::new());
+        write_prefixed_vectored(&frame(7), &mut out).unwrap();
+        assert_eq!(out.0, flat);
+        let mut out = Vec::new();
+        write_prefixed_vectored(&FrameV1B::from(frame(7)), &mut out).unwrap();
+        assert_eq!(out, flat);
+        let mut buf = prefixed_buf(&FrameV1B::from(frame(7)));
+        assert_eq!(buf.copy_to_bytes(buf.remaining()), flat);
+    }
+
+    #[tokio::test]
+    async fn async_vectored_writes_match_flat_ones() {
+        use tokio::io::AsyncReadExt;
+
+        let mut flat = Vec::new();
+        write_prefixed(&frame(7), &mut flat);
+        let (mut a, mut b) = tokio::io::duplex(8);
+        let (written, read) = tokio::join!(
+            async {
+                write_prefixed_vectored_async(&frame(7), &mut a).await?;
+                drop(a);
+                io::Result::Ok(())
+            },
+            async {
+                let mut read = Vec::new();
+                b.read_to_end(&mut read).await.map(|_| read)
+            },
+        );
+        written.unwrap();
+        assert_eq!(read.unwrap(), flat);
+    }
+
+    /// Frames 1 to 10, and where each starts.
+    fn ten() -> (Vec<u8>, Vec<usize>) {
+        let (mut buf, mut starts) = (Vec::new(), Vec::new());
+        for c in 1..=10 {
+            starts.push(buf.len());
+            write_prefixed(&frame(c), &mut buf);
+        }
+        (buf, starts)
+    }
+
+    fn decode_counters(buf: &[u8], on_error: OnError) -> (Vec<u64>, Vec<DecodeIssue>) {
+        let options = DecodeOptions {
+            on_error,
+            ..DecodeOptions::default()
+        };
+        let (frames, issues) = decode_all(buf, &options);
+        (frames.iter().map(|f| f.header.counter).collect(), issues)
+    }
+
+    #[test]
+    fn decodes_a_buffer_of_frames() {
+        let (buf, starts) = ten();
+        let (frames, issues) = decode_all(&buf, &DecodeOptions::default());
+        assert_eq!(frames, (1..=10).map(frame).collect::<Vec<_>>());
+        assert!(issues.is_empty());
+        let shared = Bytes::from(buf.clone());
+        let (same, _) = decode_all_shared(&shared, &DecodeOptions::default());
+        assert_eq!(
+            same.into_iter().map(FrameV1::from).collect::<Vec<_>>(),
+            frames
+        );
+
+        let mut seen = Vec::new();
+        decode_each(&buf, &DecodeOptions::default(), |offset, header, body| {
+            assert_eq!(body, frame(header.counter).body);
+            seen.push(offset);
+        });
+        assert_eq!(seen, starts);
+
+        // as the stream codec sees it
+        let mut stream = BytesMut::from(&buf[..]);
+        let mut codec = FrameCodec::default();
+        for f in &frames {
+            assert_eq!(codec.decode(&mut stream).unwrap(), Some(Ok(f.clone())));
+        }
+        assert_eq!(decode_all(&[], &DecodeOptions::default()), (vec![], vec![]));
+    }
+
+    #[test]
+    fn bad_frames_stop_or_are_passed_over() {
+        let (clean, starts) = ten();
+
+        // a bad header, the length still right
+        let mut buf = clean.clone();
+        buf[starts[3] + PREFIX_LEN + 2] = 9;
+        let bad = DecodeIssue::Frame {
+            offset: starts[3],
+            error: DecodeError::BadVersion(9),
+        };
+        assert_eq!(
+            decode_counters(&buf, OnError::Stop),
+            (vec![1, 2, 3], vec![bad.clone()])
+        );
+        assert_eq!(
+            decode_counters(&buf, OnError::Resync),
+            (vec![1, 2, 3, 5, 6, 7, 8, 9, 10], vec![bad])
+        );
+
+        // a length gone wrong, over the maximum or past the end
+        for max_frame_len in [100, DEFAULT_MAX_FRAME_LEN] {
+            let len = 0x7f00;
+            let mut buf = clean.clone();
+            buf[starts[5]..starts[5] + PREFIX_LEN].copy_from_slice(&(len as u32).to_be_bytes());
+            let options = DecodeOptions {
+                max_frame_len,
+                on_error: OnError::Resync,
+            };
+            let (frames, issues) = decode_all(&buf, &options);
+            let counters: Vec<_> = frames.iter().map(|f| f.header.counter).collect();
+            assert_eq!(counters, [1, 2, 3, 4, 5, 7, 8, 9, 10]);
+            assert_eq!(
+                issues,
+                [
+                    DecodeIssue::BadLength {
+                        offset: starts[5],
+                        len
+                    },
+                    DecodeIssue::Skipped {
+                        offset: starts[5],
+                        len: starts[6] - starts[5]
+                    }
+                ],
+                "max {max_frame_len}"
+            );
+        }
+
+        // garbage between frames, and a truncated tail
+        let mut buf = clean[..starts[2]].to_vec();
+        buf.extend_from_slice(&[0xff; 7]);
+        buf.extend_from_slice(&clean[starts[2]..clean.len() - 1]);
+        let (counters, issues) = decode_counters(&buf, OnError::Resync);
+        assert_eq!(counters, (1..=9).collect::<Vec<_>>());
+        assert_eq!(
+            issues.iter().map(|i| i.to_string()).collect::<Vec<_>>(),
+            [
+                format!("bad frame length 4294967295 at offset {}", starts[2]),
+                format!("skipped 7 bytes at offset {}", starts[2]),
+                format!("truncated frame at offset {}", starts[9] + 7),
+            ]
+        );
+        let (counters, issues) = decode_counters(&buf, OnError::Stop);
+        assert_eq!((counters.len(), issues.len()), (2, 1));
+    }
+
+    #[test]
+    fn truncated_tail_at_eof() {
+        let mut codec = FrameCodec::default();
+        let mut buf = BytesMut::from(&[0u8, 0, 0, 24, b'P'][..]);
+        assert!(matches!(
+            codec.decode_eof(&mut buf),
+            Err(TransportError::Protocol(ProtocolError::Truncated))
+        ));
+    }
+
+    /// Yields a length-prefixed frame whose body is `body_len` bytes of a
+    /// counting pattern, at most 4 KB per read, without materialising it.
+    struct PatternReader {
+        head: Vec<u8>,
+        pos: u64,
+        total: u64,
+    }
+
+    impl PatternReader {
+        fn new(body_len: u64) -> Self {
+            let head_frame = FrameV1 {
+                body: crate::Body::new(),
+                ..frame(1)
+            };
+            let mut head = ((HEADER_LEN_V1 as u64 + body_len) as u32)
+                .to_be_bytes()
+                .to_vec();
+            head.extend_from_slice(&head_frame.encode());
+            let total = head.len() as u64 + body_len;
+            PatternReader {
+                head,
+                pos: 0,
+                total,
+            }
+        }
+    }
+
+    impl Read for PatternReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(4096).min((self.total - self.pos) as usize);
+            for b in &mut buf[..n] {
+                let head_len = self.head.len() as u64;
+                *b = match self.pos {
+                    p if p < head_len => self.head[p as usize],
+                    p => (p - head_len) as u8,
+                };
+                self.pos += 1;
+            }
+            Ok(n)
+        }
+    }
+
+    /// Checks the pattern and the largest single write.
+    #[derive(Default)]
+    struct PatternSink {
+        written: u64,
+        largest_write: usize,
+    }
+
+    impl Write for PatternSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &b in buf {
+                assert_eq!(b, self.written as u8);
+                self.written += 1;
+            }
+            self.largest_write = self.largest_write.max(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streams_multi_megabyte_body_in_chunks() {
+        let body_len = 5 * 1024 * 1024 + 7;
+        let mut reader = PatternReader::new(body_len);
+        let mut sink = PatternSink::default();
+        let header = decode_header_then_stream(&mut reader, &mut sink).unwrap();
+        assert_eq!(header, frame(1).header);
+        assert_eq!(sink.written, body_len);
+        assert!(sink.largest_write <= 4096);
+    }
+
+    #[test]
+    fn stream_errors_report_bytes_written() {
+        let mut bytes = Vec::new();
+        write_prefixed(&frame(1), &mut bytes);
+        let mut sink = Vec::new();
+        let err = decode_header_then_stream(&mut &bytes[..bytes.len() - 1], &mut sink).unwrap_err();
+        assert!(matches!(err, StreamBodyError::Truncated { written: 2 }));
+        assert_eq!(sink, [1, 2]);
+
+        let mut full = [0u8; 1];
+        let err = decode_header_then_stream(&mut &bytes[..], &mut &mut full[..]).unwrap_err();
+        assert!(matches!(err, StreamBodyError::Io { written: 1, .. }));
+        assert_eq!(full, [1]);
+
+        bytes[4] = b'X';
+        let err = decode_header_then_stream(&mut &bytes[..], &mut sink).unwrap_err();
+        assert!(matches!(
+            err,
+            StreamBodyError::Decode(DecodeError::BadMagic)
+        ));
+        assert_eq!(err.written(), 0);
+    }
+}
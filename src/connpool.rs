@@ -0,0 +1,876 @@
+//! Outbound connections to many peers, kept in one pool.
+//!
+//! A [`ConnectionPool`] keeps connections keyed by peer, by whatever
+//! identifies one to the application (a [`PeerAddr`], a gateway's name),
+//! and opens them through a [`Connect`]. [`ConnectionPool::get_or_connect`]
+//! returns a [`Connection`] to a peer, opening one first if there is none;
+//! callers racing for the same peer share the one connection. A peer only
+//! gets another connection while all of its connections have sends
+//! waiting, up to [`PoolConfig::max_per_peer`].
+//!
+//! Each connection is driven by a task of its own on the current tokio
+//! runtime, which owns the transport. Sends wait in a queue of
+//! [`PoolConfig::queue_len`] frames, including those made while the
+//! connection is still being opened; a full queue refuses a frame at once
+//! with [`PoolError::QueueFull`]. A connection is closed once nothing has
+//! been sent or received on it for [`PoolConfig::idle_timeout`], when its
+//! transport fails and, with a [`HealthCheck`], when a PING sent after a
+//! quiet spell goes unanswered. The [`Connection`] outlives it: its next
+//! send opens another, and frames still queued move there. Frames received
+//! on pooled connections are published to the pool's [`EventBus`], if it
+//! has one.
+//!
+//! [`ConnectionPool::stats`] counts the connections being opened, open and
+//! idle, and those that failed. With the `metrics` feature the global
+//! registry counts them too, across pools.
+//!
+//! [`PeerAddr`]: crate::transport::PeerAddr
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
+
+use crate::bus::EventBus;
+use crate::sender::{CounterMap, CounterSource};
+use crate::session::Control;
+use crate::transport::{ProtocolError, RxFrame, Transport, TransportError};
+use crate::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+/// Opens connections to peers. Closures from the peer to a future of the
+/// transport are one.
+pub trait Connect<K>: Send + Sync + 'static {
+    type Transport: Transport + 'static;
+
+    fn connect(
+        &self,
+        peer: K,
+    ) -> impl Future<Output = Result<Self::Transport, TransportError>> + Send;
+}
+
+impl<K, F, Fut, T> Connect<K> for F
+where
+    F: Fn(K) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T, TransportError>> + Send,
+    T: Transport + 'static,
+{
+    type Transport = T;
+
+    fn connect(&self, peer: K) -> impl Future<Output = Result<T, TransportError>> + Send {
+        self(peer)
+    }
+}
+
+/// Quiet connections are PINGed, and closed if nothing answers.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    /// Who the PINGs are addressed to.
+    pub device_id: [u8; 8],
+    /// Nothing received for this long sends a PING.
+    pub interval: Duration,
+    /// Nothing received for this long after the PING closes the connection.
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Connections open to one peer at most.
+    pub max_per_peer: usize,
+    /// Sends waiting per connection, including while it is being opened.
+    pub queue_len: usize,
+    pub connect_timeout: Duration,
+    /// A connection nothing is sent or received on for this long is closed.
+    /// Health-check PINGs and their answers don't count.
+    pub idle_timeout: Duration,
+    pub health_check: Option<HealthCheck>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_per_peer: 1,
+            queue_len: 64,
+            connect_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(300),
+            health_check: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PoolError {
+    /// The connection has `queue_len` sends waiting already.
+    QueueFull,
+    /// The peer couldn't be connected to; the next send tries again.
+    Connect(Arc<TransportError>),
+    /// The transport failed sending the frame, and the connection is closed.
+    Send(TransportError),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::QueueFull => write!(f, "send queue full"),
+            PoolError::Connect(e) => write!(f, "cannot connect: {e}"),
+            PoolError::Send(e) => write!(f, "send failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+/// Connections by state, and what became of those closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ConnectionStats {
+    pub connecting: u64,
+    /// Idle or busy.
+    pub open: u64,
+    /// Open, with nothing to send.
+    pub idle: u64,
+    /// Connects that failed and connections closed by a failure, ever.
+    pub failed: u64,
+    /// Connections closed for being idle, ever.
+    pub evicted: u64,
+}
+
+// Connection states; the first three index `Shared::counts` and the
+// metrics' gauges.
+const CONNECTING: u8 = 0;
+const IDLE: u8 = 1;
+const BUSY: u8 = 2;
+const CLOSED: u8 = 3;
+
+/// What became of a queued frame.
+enum Outcome {
+    Sent,
+    Failed(TransportError),
+    ConnectFailed(Arc<TransportError>),
+    /// The connection closed before the frame's turn came.
+    NotSent,
+}
+
+type Queued = (FrameV1, oneshot::Sender<Outcome>);
+
+/// Unset while connecting, then whether the connect worked.
+type Ready = Option<Result<(), Arc<TransportError>>>;
+
+struct Status {
+    state: AtomicU8,
+    ready: watch::Sender<Ready>,
+}
+
+struct Conn {
+    queue: mpsc::Sender<Queued>,
+    status: Arc<Status>,
+}
+
+impl Conn {
+    fn state(&self) -> u8 {
+        self.status.state.load(Ordering::SeqCst)
+    }
+
+    fn queued(&self) -> usize {
+        self.queue.max_capacity() - self.queue.capacity()
+    }
+
+    fn has_work(&self) -> bool {
+        self.state() == BUSY || self.queued() > 0
+    }
+}
+
+struct Shared<K> {
+    peers: Mutex<HashMap<K, Vec<Arc<Conn>>>>,
+    counts: [AtomicU64; 3],
+    failed: AtomicU64,
+    evicted: AtomicU64,
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn moved(counts: &[AtomicU64; 3], from: u8, to: u8) {
+    if let Some(c) = counts.get(usize::from(from)) {
+        c.fetch_sub(1, Ordering::Relaxed);
+    }
+    if let Some(c) = counts.get(usize::from(to)) {
+        c.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Connections to any number of peers. Cloning gives another handle on
+/// the same connections; they close once every handle, and every
+/// [`Connection`], is dropped.
+pub struct ConnectionPool<K, C> {
+    connector: Arc<C>,
+    config: Arc<PoolConfig>,
+    bus: Option<EventBus>,
+    counters: Arc<dyn CounterSource>,
+    shared: Arc<Shared<K>>,
+}
+
+impl<K, C> Clone for ConnectionPool<K, C> {
+    fn clone(&self) -> Self {
+        ConnectionPool {
+            connector: self.connector.clone(),
+            config: self.config.clone(),
+            bus: self.bus.clone(),
+            counters: self.counters.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<K, C> fmt::Debug for ConnectionPool<K, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("config", &self.config)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl<K, C> ConnectionPool<K, C> {
+    pub fn stats(&self) -> ConnectionStats {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let [connecting, idle, busy] = &self.shared.counts;
+        ConnectionStats {
+            connecting: get(connecting),
+            open: get(idle) + get(busy),
+            idle: get(idle),
+            failed: get(&self.shared.failed),
+            evicted: get(&self.shared.evicted),
+        }
+    }
+
+    /// Peers with a connection open or being opened.
+    pub fn peers(&self) -> usize {
+        let peers = lock(&self.shared.peers);
+        peers
+            .values()
+            .filter(|conns| conns.iter().any(|c| c.state() != CLOSED))
+            .count()
+    }
+}
+
+impl<K, C> ConnectionPool<K, C>
+where
+    K: Clone + Eq + Hash + Send + Sync + 'static,
+    C: Connect<K>,
+{
+    pub fn new(connector: C, config: PoolConfig) -> Self {
+        ConnectionPool {
+            connector: Arc::new(connector),
+            config: Arc::new(config),
+            bus: None,
+            counters: Arc::new(CounterMap::default()),
+            shared: Arc::new(Shared {
+                peers: Mutex::new(HashMap::new()),
+                counts: [const { AtomicU64::new(0) }; 3],
+                failed: AtomicU64::new(0),
+                evicted: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Publish the frames pooled connections receive to `bus`.
+    pub fn with_bus(mut self, bus: EventBus) -> Self {
+        self.bus = Some(bus);
+        self
+    }
+
+    /// Number health-check PINGs from `counters`, which should be what
+    /// numbers the other frames to that device. A fresh [`CounterMap`] if
+    /// not given.
+    pub fn with_counters(mut self, counters: Arc<dyn CounterSource>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// A connection to `peer`, open or being opened.
+    pub fn connection(&self, peer: K) -> Connection<K, C> {
+        self.pick(&peer);
+        Connection {
+            pool: self.clone(),
+            peer,
+        }
+    }
+
+    /// A connection to `peer`, once it is open.
+    pub async fn get_or_connect(&self, peer: K) -> Result<Connection<K, C>, PoolError> {
+        let connection = self.connection(peer);
+        connection.ready().await?;
+        Ok(connection)
+    }
+
+    /// A connection without sends waiting, else a new one if the peer
+    /// has room for it, else the one with the fewest sends waiting.
+    fn pick(&self, peer: &K) -> Arc<Conn> {
+        let mut peers = lock(&self.shared.peers);
+        let conns = peers.entry(peer.clone()).or_default();
+        conns.retain(|c| c.state() != CLOSED);
+        if let Some(c) = conns.iter().find(|c| !c.has_work()) {
+            return c.clone();
+        }
+        if conns.len() < self.config.max_per_peer.max(1) {
+            let c = self.open(peer.clone());
+            conns.push(c.clone());
+            return c;
+        }
+        conns.iter().min_by_key(|c| c.queued()).unwrap().clone()
+    }
+
+    fn open(&self, peer: K) -> Arc<Conn> {
+        let (queue, queued) = mpsc::channel(self.config.queue_len.max(1));
+        let status = Arc::new(Status {
+            state: AtomicU8::new(CONNECTING),
+            ready: watch::Sender::new(None),
+        });
+        moved(&self.shared.counts, CLOSED, CONNECTING);
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().connection_moved(CLOSED, CONNECTING);
+        let driver = Driver {
+            peer,
+            connector: self.connector.clone(),
+            config: self.config.clone(),
+            bus: self.bus.clone(),
+            counters: self.counters.clone(),
+            shared: Arc::downgrade(&self.shared),
+            status: status.clone(),
+        };
+        tokio::spawn(driver.run(queued));
+        Arc::new(Conn { queue, status })
+    }
+}
+
+/// A handle on the pool's connections to one peer. Each send goes to
+/// whichever connection the pool picks for it, so with more than one per
+/// peer, frames sent one after another may arrive out of order.
+pub struct Connection<K, C> {
+    pool: ConnectionPool<K, C>,
+    peer: K,
+}
+
+impl<K: fmt::Debug, C> fmt::Debug for Connection<K, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("peer", &self.peer)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K, C> Connection<K, C>
+where
+    K: Clone + Eq + Hash + Send + Sync + 'static,
+    C: Connect<K>,
+{
+    pub fn peer(&self) -> &K {
+        &self.peer
+    }
+
+    /// Wait for a connection to open, opening one first if the peer has
+    /// none.
+    pub async fn ready(&self) -> Result<(), PoolError> {
+        let conn = self.pool.pick(&self.peer);
+        let mut ready = conn.status.ready.subscribe();
+        let ready = match ready.wait_for(Option::is_some).await {
+            Ok(ready) => ready.clone(),
+            Err(_) => Some(Err(Arc::new(TransportError::Disconnected))),
+        };
+        match ready {
+            Some(Err(e)) => Err(PoolError::Connect(e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Queue `frame` and wait until the transport has taken it.
+    pub async fn send(&self, frame: &FrameV1) -> Result<(), PoolError> {
+        // once more if the connection closes before the frame's turn
+        for _ in 0..2 {
+            let conn = self.pool.pick(&self.peer);
+            let (reply, outcome) = oneshot::channel();
+            match conn.queue.try_send((frame.clone(), reply)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => return Err(PoolError::QueueFull),
+                Err(TrySendError::Closed(_)) => {
+                    if let Some(Err(e)) = &*conn.status.ready.borrow() {
+                        return Err(PoolError::Connect(e.clone()));
+                    }
+                    continue;
+                }
+            }
+            match outcome.await {
+                Ok(Outcome::Sent) => return Ok(()),
+                Ok(Outcome::Failed(e)) => return Err(PoolError::Send(e)),
+                Ok(Outcome::ConnectFailed(e)) => return Err(PoolError::Connect(e)),
+                Ok(Outcome::NotSent) | Err(_) => {}
+            }
+        }
+        Err(PoolError::Send(TransportError::Disconnected))
+    }
+}
+
+/// Why a connection closed.
+enum End {
+    Idle,
+    Failed,
+    /// Every handle on the pool is gone.
+    Dropped,
+}
+
+/// The task owning one connection's transport.
+struct Driver<K, C> {
+    peer: K,
+    connector: Arc<C>,
+    config: Arc<PoolConfig>,
+    bus: Option<EventBus>,
+    counters: Arc<dyn CounterSource>,
+    shared: Weak<Shared<K>>,
+    status: Arc<Status>,
+}
+
+impl<K, C> Driver<K, C>
+where
+    K: Clone + Eq + Hash + Send + Sync + 'static,
+    C: Connect<K>,
+{
+    async fn run(self, mut queue: mpsc::Receiver<Queued>) {
+        let connect = self.connector.connect(self.peer.clone());
+        let connected = tokio::time::timeout(self.config.connect_timeout, connect).await;
+        let mut transport = match connected {
+            Ok(Ok(transport)) => transport,
+            Ok(Err(e)) => return self.connect_failed(e, queue),
+            Err(_) => return self.connect_failed(TransportError::Timeout, queue),
+        };
+        self.set(IDLE);
+        self.status.ready.send_replace(Some(Ok(())));
+
+        let end = self.serve(&mut transport, &mut queue).await;
+        self.close(&mut queue, |_| Outcome::NotSent);
+        let _ = transport.close().await;
+        match end {
+            End::Idle => self.count(|s| &s.evicted),
+            End::Failed => self.failed(),
+            End::Dropped => {}
+        }
+    }
+
+    async fn serve(&self, transport: &mut C::Transport, queue: &mut mpsc::Receiver<Queued>) -> End {
+        let health = self.config.health_check.as_ref();
+        let mut active = Instant::now();
+        let mut heard = active;
+        // when the unanswered PING went out, and its counter
+        let mut ping: Option<(Instant, u64)> = None;
+        loop {
+            let idle_at = active + self.config.idle_timeout;
+            let check_at = health.map(|h| match ping {
+                Some((sent, _)) => sent + h.timeout,
+                None => heard + h.interval,
+            });
+            let wake = check_at.map_or(idle_at, |at| at.min(idle_at));
+            tokio::select! {
+                queued = queue.recv() => {
+                    let Some((frame, reply)) = queued else {
+                        return End::Dropped;
+                    };
+                    self.set(BUSY);
+                    let result = transport.send(&frame).await;
+                    active = Instant::now();
+                    match result {
+                        Ok(()) => {
+                            let _ = reply.send(Outcome::Sent);
+                        }
+                        Err(e) => {
+                            let _ = reply.send(Outcome::Failed(e));
+                            return End::Failed;
+                        }
+                    }
+                    if queue.is_empty() {
+                        self.set(IDLE);
+                    }
+                }
+                received = transport.recv_rx() => match received {
+                    Ok(rx) => {
+                        heard = Instant::now();
+                        let pong = ping.is_some_and(|(_, counter)| is_pong(&rx, health, counter));
+                        ping = None;
+                        if !pong {
+                            active = heard;
+                            if let Some(bus) = &self.bus {
+                                bus.publish(&rx);
+                            }
+                        }
+                    }
+                    Err(TransportError::Protocol(ProtocolError::Decode(_))) => {}
+                    Err(_) => return End::Failed,
+                },
+                _ = tokio::time::sleep_until(wake) => {
+                    let now = Instant::now();
+                    if now >= idle_at {
+                        return End::Idle;
+                    }
+                    let Some(h) = health.filter(|_| check_at.is_some_and(|at| now >= at)) else {
+                        continue;
+                    };
+                    if ping.is_some() {
+                        return End::Failed;
+                    }
+                    // a PING that can't be numbered goes unanswered
+                    let counter = self.counters.next(h.device_id).unwrap_or(0);
+                    if transport.send(&ping_frame(h.device_id, counter)).await.is_err() {
+                        return End::Failed;
+                    }
+                    ping = Some((now, counter));
+                }
+            }
+        }
+    }
+
+    fn connect_failed(&self, e: TransportError, mut queue: mpsc::Receiver<Queued>) {
+        let e = Arc::new(e);
+        self.status.ready.send_replace(Some(Err(e.clone())));
+        self.close(&mut queue, |_| Outcome::ConnectFailed(e.clone()));
+        self.failed();
+    }
+
+    /// Take the connection out of the pool, then answer what is still
+    /// queued.
+    fn close(&self, queue: &mut mpsc::Receiver<Queued>, outcome: impl Fn(&FrameV1) -> Outcome) {
+        self.set(CLOSED);
+        if let Some(shared) = self.shared.upgrade() {
+            let mut peers = lock(&shared.peers);
+            if let Some(conns) = peers.get_mut(&self.peer) {
+                conns.retain(|c| !Arc::ptr_eq(&c.status, &self.status));
+                if conns.is_empty() {
+                    peers.remove(&self.peer);
+                }
+            }
+        }
+        queue.close();
+        while let Ok((frame, reply)) = queue.try_recv() {
+            let _ = reply.send(outcome(&frame));
+        }
+    }
+
+    fn set(&self, to: u8) {
+        let from = self.status.state.swap(to, Ordering::SeqCst);
+        if from == to {
+            return;
+        }
+        if let Some(shared) = self.shared.upgrade() {
+            moved(&shared.counts, from, to);
+        }
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().connection_moved(from, to);
+    }
+
+    fn failed(&self) {
+        self.count(|s| &s.failed);
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().connection_failed();
+    }
+
+    fn count(&self, counter: impl Fn(&Shared<K>) -> &AtomicU64) {
+        if let Some(shared) = self.shared.upgrade() {
+            counter(&shared).fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn ping_frame(device_id: [u8; 8], counter: u64) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Command,
+            flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+            device_id,
+            counter,
+        },
+        body: Control::Ping.encode().into(),
+    }
+}
+
+/// The ACK answering the health check's PING numbered `counter`.
+fn is_pong(rx: &RxFrame, health: Option<&HealthCheck>, counter: u64) -> bool {
+    health.is_some_and(|h| {
+        rx.header.msg_type == MsgType::Ack
+            && rx.header.device_id == h.device_id
+            && rx.header.counter == counter
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use futures::StreamExt;
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::bus::Filter;
+    use crate::transport::tests::frame;
+    use crate::transport::{LoopbackTransport, loopback_pair_with_capacity};
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Connects to `peer` by handing the far end of a loopback pair to the
+    /// test, after `delay`.
+    #[derive(Clone)]
+    struct Peers {
+        connects: Arc<AtomicUsize>,
+        ends: mpsc::UnboundedSender<(&'static str, LoopbackTransport)>,
+        delay: Duration,
+        capacity: usize,
+        gate: Option<Arc<Notify>>,
+    }
+
+    fn peers() -> (
+        Peers,
+        mpsc::UnboundedReceiver<(&'static str, LoopbackTransport)>,
+    ) {
+        let (ends, far) = mpsc::unbounded_channel();
+        let peers = Peers {
+            connects: Arc::new(AtomicUsize::new(0)),
+            ends,
+            delay: Duration::ZERO,
+            capacity: 64,
+            gate: None,
+        };
+        (peers, far)
+    }
+
+    impl Peers {
+        fn connector(
+            &self,
+        ) -> impl Fn(
+            &'static str,
+        ) -> std::pin::Pin<
+            Box<dyn Future<Output = Result<LoopbackTransport, TransportError>> + Send>,
+        > + Send
+        + Sync
+        + 'static {
+            let peers = self.clone();
+            move |peer| {
+                let peers = peers.clone();
+                Box::pin(async move {
+                    peers.connects.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(peers.delay).await;
+                    if let Some(gate) = &peers.gate {
+                        gate.notified().await;
+                    }
+                    if peer == "down" {
+                        return Err(TransportError::Disconnected);
+                    }
+                    let (near, far) = loopback_pair_with_capacity(peers.capacity);
+                    let _ = peers.ends.send((peer, far));
+                    Ok(near)
+                })
+            }
+        }
+
+        fn connects(&self) -> usize {
+            self.connects.load(Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn racing_callers_share_one_connection() {
+        let (mut peers, mut far) = peers();
+        peers.delay = 10 * MS;
+        let pool = ConnectionPool::new(peers.connector(), PoolConfig::default());
+        let tasks: Vec<_> = (0..16)
+            .map(|i| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    let c = pool.get_or_connect("gw1").await.unwrap();
+                    c.send(&frame(i)).await.unwrap();
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap();
+        }
+        assert_eq!(peers.connects(), 1);
+        assert_eq!(pool.peers(), 1);
+        let (peer, mut end) = far.recv().await.unwrap();
+        assert_eq!(peer, "gw1");
+        let mut counters: Vec<_> = Vec::new();
+        for _ in 0..16 {
+            counters.push(end.recv().await.unwrap().header.counter);
+        }
+        counters.sort_unstable();
+        assert_eq!(counters, (0..16).collect::<Vec<_>>());
+        assert!(far.try_recv().is_err());
+
+        let stats = pool.stats();
+        assert_eq!((stats.connecting, stats.open, stats.idle), (0, 1, 1));
+        pool.get_or_connect("gw2").await.unwrap();
+        assert_eq!((peers.connects(), pool.stats().open), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn sends_queue_while_connecting_up_to_the_bound() {
+        let (mut peers, mut far) = peers();
+        let gate = Arc::new(Notify::new());
+        peers.gate = Some(gate.clone());
+        let config = PoolConfig {
+            queue_len: 2,
+            ..PoolConfig::default()
+        };
+        let pool = ConnectionPool::new(peers.connector(), config);
+        let c = Arc::new(pool.connection("gw1"));
+        let sends: Vec<_> = (1..=2)
+            .map(|i| {
+                let c = c.clone();
+                tokio::spawn(async move { c.send(&frame(i)).await })
+            })
+            .collect();
+        tokio::time::sleep(5 * MS).await;
+        assert_eq!(pool.stats().connecting, 1);
+        assert!(matches!(c.send(&frame(3)).await, Err(PoolError::QueueFull)));
+
+        gate.notify_one();
+        for s in sends {
+            s.await.unwrap().unwrap();
+        }
+        let (_, mut end) = far.recv().await.unwrap();
+        assert_eq!(end.recv().await.unwrap().header.counter, 1);
+        assert_eq!(end.recv().await.unwrap().header.counter, 2);
+        assert_eq!(peers.connects(), 1);
+    }
+
+    #[tokio::test]
+    async fn busy_connections_make_room_for_more_up_to_the_limit() {
+        let (mut peers, mut far) = peers();
+        // nobody reads the far ends, so the second send on each waits
+        peers.capacity = 1;
+        let config = PoolConfig {
+            max_per_peer: 2,
+            ..PoolConfig::default()
+        };
+        let pool = ConnectionPool::new(peers.connector(), config);
+        let c = Arc::new(pool.get_or_connect("gw1").await.unwrap());
+        for i in 0..6 {
+            let c = c.clone();
+            tokio::spawn(async move { c.send(&frame(i)).await });
+            tokio::time::sleep(5 * MS).await;
+        }
+        assert_eq!(peers.connects(), 2);
+        let stats = pool.stats();
+        assert_eq!((stats.open, stats.idle), (2, 0));
+
+        let (_, mut a) = far.recv().await.unwrap();
+        let (_, mut b) = far.recv().await.unwrap();
+        let mut got = 0;
+        while got < 6 {
+            tokio::select! {
+                Ok(_) = a.recv() => got += 1,
+                Ok(_) = b.recv() => got += 1,
+            }
+        }
+        tokio::time::sleep(5 * MS).await;
+        assert_eq!(pool.stats().idle, 2);
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed_and_reopened() {
+        let (peers, mut far) = peers();
+        let bus = EventBus::new(8);
+        let mut received = bus.subscribe(Filter::any());
+        let config = PoolConfig {
+            idle_timeout: 30 * MS,
+            ..PoolConfig::default()
+        };
+        let pool = ConnectionPool::new(peers.connector(), config).with_bus(bus);
+        let c = pool.get_or_connect("gw1").await.unwrap();
+        c.send(&frame(1)).await.unwrap();
+        let (_, mut end) = far.recv().await.unwrap();
+        end.send(&frame(2)).await.unwrap();
+        let rx = received.next().await.unwrap().unwrap();
+        assert_eq!(rx.header.counter, 2);
+
+        tokio::time::sleep(60 * MS).await;
+        let stats = pool.stats();
+        assert_eq!((stats.open, stats.evicted), (0, 1));
+        assert_eq!(pool.peers(), 0);
+        // frame 1, then the close
+        assert!(end.recv().await.is_ok());
+        assert!(matches!(
+            end.recv().await,
+            Err(TransportError::Disconnected)
+        ));
+
+        c.send(&frame(3)).await.unwrap();
+        assert_eq!(peers.connects(), 2);
+        let (_, mut end) = far.recv().await.unwrap();
+        assert_eq!(end.recv().await.unwrap().header.counter, 3);
+    }
+
+    #[tokio::test]
+    async fn unanswered_health_checks_close_the_connection() {
+        let (peers, mut far) = peers();
+        let config = PoolConfig {
+            health_check: Some(HealthCheck {
+                device_id: *b"GW000001",
+                interval: 10 * MS,
+                timeout: 20 * MS,
+            }),
+            ..PoolConfig::default()
+        };
+        let pool = ConnectionPool::new(peers.connector(), config);
+        let _answered = pool.get_or_connect("answers").await.unwrap();
+        let _silent = pool.get_or_connect("silent").await.unwrap();
+        for _ in 0..2 {
+            let (peer, mut end) = far.recv().await.unwrap();
+            tokio::spawn(async move {
+                while let Ok(ping) = end.recv().await {
+                    assert_eq!(Control::parse(&ping), Some(Control::Ping));
+                    if peer == "answers" {
+                        end.send(&crate::ping::pong(&ping).unwrap()).await.unwrap();
+                    }
+                }
+            });
+        }
+        tokio::time::sleep(80 * MS).await;
+        let stats = pool.stats();
+        assert_eq!((stats.open, stats.failed), (1, 1));
+        assert_eq!(peers.connects(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_connects_fail_what_was_queued() {
+        let (peers, _far) = peers();
+        let pool = ConnectionPool::new(peers.connector(), PoolConfig::default());
+        let e = pool.get_or_connect("down").await.unwrap_err();
+        assert!(matches!(e, PoolError::Connect(_)), "{e:?}");
+        assert_eq!(e.to_string(), "cannot connect: disconnected");
+        let c = pool.connection("down");
+        assert!(matches!(
+            c.send(&frame(1)).await,
+            Err(PoolError::Connect(_))
+        ));
+        let stats = pool.stats();
+        assert_eq!((stats.connecting, stats.open, stats.failed), (0, 0, 2));
+        assert_eq!(pool.peers(), 0);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_pool_closes_its_connections() {
+        let (peers, mut far) = peers();
+        let pool = ConnectionPool::new(peers.connector(), PoolConfig::default());
+        pool.get_or_connect("gw1").await.unwrap();
+        let (_, mut end) = far.recv().await.unwrap();
+        drop(pool);
+        assert!(matches!(
+            end.recv().await,
+            Err(TransportError::Disconnected)
+        ));
+    }
+}
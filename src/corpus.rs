@@ -0,0 +1,387 @@
+//! A corpus of edge-case frames, for fuzzing other parsers.
+//!
+//! Where [`vectors`](crate::vectors) is a short fixed list, [`generate`]
+//! makes as many cases as asked for from a seed, the same ones for the same
+//! seed and count on every machine. The first cases walk the boundaries:
+//! every message type with every flags byte, empty and largest bodies,
+//! extreme counters and device ids, the frame cut short at every offset,
+//! and each bit of the magic, version and type flipped. The rest are random
+//! frames, most of them with one field changed. Each case says whether it
+//! decodes, and if not with which error, worked out from what was changed
+//! rather than by decoding it.
+//!
+//! [`write`] puts each case in a directory as `NNNNN-name.bin`, the raw
+//! frame, next to `NNNNN-name.json`:
+//!
+//! ```text
+//! {"corpus_version":1,"seed":7,"index":0,"name":"type_event_flags_00",
+//!  "what":"..","len":23,"decodes":true,"error":null,"offset":null,
+//!  "frame":{"msg_type":"event","flags":0,"device_id":HEX,"counter":N,"body_len":2}}
+//! ```
+//!
+//! where `frame` is null for a case that does not decode.
+
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+
+use crate::codec::DEFAULT_MAX_FRAME_LEN;
+use crate::datagram::MAX_DATAGRAM;
+use crate::sim::Rng;
+use crate::vectors::{Expect, frame};
+use crate::{DecodeError, Flags, FrameV1, HEADER_LEN_V1, MAGIC, MsgType, VERSION_V1};
+
+/// The `corpus_version` of the manifests [`write`] writes.
+pub const CORPUS_VERSION: u32 = 1;
+
+/// Longest body of a frame sent as one UDP datagram.
+pub const MAX_BODY: usize = MAX_DATAGRAM - HEADER_LEN_V1;
+
+const TYPES: [MsgType; 4] = [
+    MsgType::Event,
+    MsgType::Command,
+    MsgType::Ack,
+    MsgType::Error,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Case {
+    pub name: String,
+    /// What the case exercises, in a sentence.
+    pub what: String,
+    pub bytes: Vec<u8>,
+    pub expect: Expect,
+}
+
+impl Case {
+    pub fn decodes(&self) -> bool {
+        matches!(self.expect, Expect::Frame(_))
+    }
+}
+
+fn valid(name: String, what: String, frame: FrameV1) -> Case {
+    Case {
+        name,
+        what,
+        bytes: frame.encode(),
+        expect: Expect::Frame(frame),
+    }
+}
+
+fn invalid(name: String, what: String, bytes: Vec<u8>, error: DecodeError) -> Case {
+    Case {
+        name,
+        what,
+        bytes,
+        expect: Expect::Error {
+            kind: error.kind().to_string(),
+            offset: error.offset(),
+        },
+    }
+}
+
+fn bytes(rng: &mut Rng, len: usize) -> Vec<u8> {
+    (0..len).map(|_| rng.next_u64() as u8).collect()
+}
+
+fn device(rng: &mut Rng) -> [u8; 8] {
+    match rng.next_u64() % 8 {
+        0 => [0; 8],
+        1 => [0xff; 8],
+        _ => rng.next_u64().to_be_bytes(),
+    }
+}
+
+fn counter(rng: &mut Rng) -> u64 {
+    match rng.next_u64() % 8 {
+        0 => 0,
+        1 => u64::MAX,
+        2 => u32::MAX as u64 + (rng.next_u64() % 3) - 1,
+        3 => i64::MAX as u64 + (rng.next_u64() % 3) - 1,
+        _ => rng.next_u64(),
+    }
+}
+
+fn body_len(rng: &mut Rng) -> usize {
+    match rng.next_u64() % 16 {
+        0 => 0,
+        1 => MAX_BODY,
+        2..=4 => (rng.next_u64() % 4096) as usize,
+        _ => (rng.next_u64() % 64) as usize,
+    }
+}
+
+fn random_frame(rng: &mut Rng) -> FrameV1 {
+    let msg_type = TYPES[(rng.next_u64() % 4) as usize];
+    let flags = (rng.next_u64() % 2) as u8;
+    let device_id = device(rng);
+    let counter = counter(rng);
+    let len = body_len(rng);
+    frame(msg_type, flags, device_id, counter, &bytes(rng, len))
+}
+
+/// What `frame` decodes as once header byte `at` is `byte`, every other
+/// byte being as it was.
+fn with_byte(frame: &FrameV1, at: usize, byte: u8) -> Result<FrameV1, DecodeError> {
+    let mut out = frame.clone();
+    let h = &mut out.header;
+    match at {
+        0 | 1 if byte == MAGIC[at] => {}
+        0 | 1 => return Err(DecodeError::BadMagic),
+        2 if byte == VERSION_V1 => {}
+        2 => return Err(DecodeError::BadVersion(byte)),
+        3 => h.msg_type = MsgType::from_u8(byte).ok_or(DecodeError::UnknownMsgType(byte))?,
+        4 => h.flags = Flags::new(byte)?,
+        5..13 => h.device_id[at - 5] = byte,
+        13..HEADER_LEN_V1 => {
+            let mut counter = h.counter.to_be_bytes();
+            counter[at - 13] = byte;
+            h.counter = u64::from_be_bytes(counter);
+        }
+        _ => unreachable!("offset {at} is past the header"),
+    }
+    Ok(out)
+}
+
+fn changed(name: String, what: String, frame: &FrameV1, at: usize, byte: u8) -> Case {
+    let mut bytes = frame.encode();
+    bytes[at] = byte;
+    match with_byte(frame, at, byte) {
+        Ok(frame) => valid(name, what, frame),
+        Err(e) => invalid(name, what, bytes, e),
+    }
+}
+
+fn truncated(name: String, frame: &FrameV1, len: usize) -> Case {
+    let what = format!("the frame cut to its first {len} bytes");
+    if len < HEADER_LEN_V1 {
+        let bytes = frame.encode()[..len].to_vec();
+        return invalid(name, what, bytes, DecodeError::TooShort);
+    }
+    let mut frame = frame.clone();
+    frame.body.truncate(len - HEADER_LEN_V1);
+    valid(name, what, frame)
+}
+
+/// The boundary cases, in order.
+fn boundaries(rng: &mut Rng) -> Vec<Case> {
+    let mut out = Vec::new();
+    let device_id = rng.next_u64().to_be_bytes();
+    let body = bytes(rng, 16);
+    for t in TYPES {
+        for flags in 0..=u8::MAX {
+            let name = format!("type_{}_flags_{flags:02x}", t.as_str());
+            let what = format!("{}, flags 0b{flags:08b}", t.as_str());
+            let base = frame(t, 0, device_id, 1, &body);
+            out.push(changed(name, what, &base, 4, flags));
+        }
+    }
+
+    let base = frame(MsgType::Event, 0, device_id, 1, &body);
+    for (name, len) in [
+        ("body_empty", 0),
+        ("body_one_byte", 1),
+        ("body_max_datagram", MAX_BODY),
+        ("body_max_stream", DEFAULT_MAX_FRAME_LEN - HEADER_LEN_V1),
+    ] {
+        let what = format!("a body of {len} bytes");
+        let f = frame(MsgType::Event, 0, device_id, 1, &bytes(rng, len));
+        out.push(valid(name.to_string(), what, f));
+    }
+    for (name, counter) in [
+        ("counter_zero", 0),
+        ("counter_u64_max_minus_one", u64::MAX - 1),
+        ("counter_u64_max", u64::MAX),
+    ] {
+        let what = format!("counter {counter}");
+        let f = frame(MsgType::Event, 0, device_id, counter, &body);
+        out.push(valid(name.to_string(), what, f));
+    }
+    for (name, id) in [("device_id_zero", [0; 8]), ("device_id_ones", [0xff; 8])] {
+        let what = format!("device id {}", crate::hex::encode(&id));
+        out.push(valid(
+            name.to_string(),
+            what,
+            frame(MsgType::Event, 0, id, 1, &body),
+        ));
+    }
+    for len in 0..base.encoded_len() {
+        out.push(truncated(format!("truncated_at_{len}"), &base, len));
+    }
+    let encoded = base.encode();
+    for (field, at) in [("magic", 0), ("magic", 1), ("version", 2), ("type", 3)] {
+        for bit in 0..8 {
+            let i = if field == "magic" { at * 8 + bit } else { bit };
+            let name = format!("{field}_bit_{i}_flipped");
+            let what = format!("bit {bit} of byte {at} ({field}) flipped");
+            out.push(changed(name, what, &base, at, encoded[at] ^ (1 << bit)));
+        }
+    }
+    out
+}
+
+/// Random case number `index`.
+fn random(rng: &mut Rng, index: usize) -> Case {
+    let f = random_frame(rng);
+    match rng.next_u64() % 4 {
+        0 => {
+            let what = format!("a random frame with a {} byte body", f.body.len());
+            valid(format!("random_{index}"), what, f)
+        }
+        1 => {
+            let len = (rng.next_u64() % f.encoded_len() as u64) as usize;
+            truncated(format!("random_{index}_truncated_at_{len}"), &f, len)
+        }
+        2 => {
+            let at = (rng.next_u64() % HEADER_LEN_V1 as u64) as usize;
+            let bit = rng.next_u64() % 8;
+            let name = format!("random_{index}_byte_{at}_bit_{bit}_flipped");
+            let what = format!("a random frame with bit {bit} of byte {at} flipped");
+            let byte = f.encode()[at] ^ (1 << bit);
+            changed(name, what, &f, at, byte)
+        }
+        _ => {
+            let at = (rng.next_u64() % HEADER_LEN_V1 as u64) as usize;
+            let byte = rng.next_u64() as u8;
+            let name = format!("random_{index}_byte_{at}_set_{byte:02x}");
+            let what = format!("a random frame with byte {at} set to 0x{byte:02x}");
+            changed(name, what, &f, at, byte)
+        }
+    }
+}
+
+/// `count` cases from `seed`: the boundary cases first, as many as fit,
+/// then random ones.
+pub fn generate(seed: u64, count: usize) -> Vec<Case> {
+    let mut rng = Rng(seed);
+    let mut out = boundaries(&mut rng);
+    out.truncate(count);
+    while out.len() < count {
+        let case = random(&mut rng, out.len());
+        out.push(case);
+    }
+    out
+}
+
+/// File name of case `index`, without its extension.
+pub fn stem(index: usize, case: &Case) -> String {
+    format!("{index:05}-{}", case.name)
+}
+
+/// The sidecar manifest of case `index`.
+pub fn manifest(seed: u64, index: usize, case: &Case) -> String {
+    let mut out = format!(
+        "{{\"corpus_version\":{CORPUS_VERSION},\"seed\":{seed},\"index\":{index},\
+         \"name\":\"{}\",\"what\":\"{}\",\"len\":{},\"decodes\":{},",
+        case.name,
+        case.what,
+        case.bytes.len(),
+        case.decodes()
+    );
+    match &case.expect {
+        Expect::Frame(f) => {
+            let h = &f.header;
+            let _ = write!(
+                out,
+                "\"error\":null,\"offset\":null,\"frame\":{{\"msg_type\":\"{}\",\"flags\":{},\
+                 \"device_id\":\"{}\",\"counter\":{},\"body_len\":{}}}}}",
+                h.msg_type.as_str(),
+                h.flags.bits(),
+                h.device_id_hex(),
+                h.counter,
+                f.body.len()
+            );
+        }
+        Expect::Error { kind, offset } => {
+            let offset = offset.map_or("null".to_string(), |o| o.to_string());
+            let _ = write!(
+                out,
+                "\"error\":\"{kind}\",\"offset\":{offset},\"frame\":null}}"
+            );
+        }
+    }
+    out.push('\n');
+    out
+}
+
+/// Write `cases` into `dir`, which is created if need be.
+pub fn write(dir: &Path, seed: u64, cases: &[Case]) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for (index, case) in cases.iter().enumerate() {
+        let stem = stem(index, case);
+        std::fs::write(dir.join(format!("{stem}.bin")), &case.bytes)?;
+        std::fs::write(
+            dir.join(format!("{stem}.json")),
+            manifest(seed, index, case),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vectors::{Vector, check};
+
+    fn agrees(case: &Case) -> Result<(), String> {
+        check(&Vector {
+            name: case.name.clone(),
+            bytes: case.bytes.clone(),
+            expect: case.expect.clone(),
+        })
+    }
+
+    #[test]
+    fn expectations_agree_with_the_decoder() {
+        for seed in [0, 1, 0xdead_beef] {
+            for case in generate(seed, 3000) {
+                if let Err(e) = agrees(&case) {
+                    panic!("seed {seed}, {}: {e}", case.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn same_seed_same_corpus() {
+        assert_eq!(generate(7, 1500), generate(7, 1500));
+        assert_ne!(generate(7, 1500), generate(8, 1500));
+        // a shorter corpus is a prefix of a longer one
+        assert_eq!(generate(7, 1500)[..1100], generate(7, 1100)[..]);
+        assert_eq!(generate(7, 10).len(), 10);
+    }
+
+    #[test]
+    fn covers_the_boundaries() {
+        let cases = generate(3, 0x500);
+        let find = |name: &str| cases.iter().find(|c| c.name == name).unwrap();
+        assert!(find("type_ack_flags_01").decodes());
+        assert!(!find("type_ack_flags_02").decodes());
+        assert_eq!(find("body_max_datagram").bytes.len(), MAX_DATAGRAM);
+        assert!(!find("truncated_at_20").decodes());
+        assert!(find("truncated_at_21").decodes());
+        // event 0x01 with bit 1 flipped is ack 0x03, bit 2 is unknown 0x05
+        assert!(find("type_bit_1_flipped").decodes());
+        assert!(!find("type_bit_2_flipped").decodes());
+        assert!(!find("magic_bit_15_flipped").decodes());
+        let valid = cases.iter().filter(|c| c.decodes()).count();
+        assert!(valid > 100 && valid < cases.len() - 100, "{valid}");
+    }
+
+    #[test]
+    fn writes_a_manifest_beside_each_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let cases = generate(5, 4);
+        write(dir.path(), 5, &cases).unwrap();
+        let manifest = std::fs::read_to_string(dir.path().join("00002-type_event_flags_02.json"));
+        assert_eq!(
+            manifest.unwrap(),
+            "{\"corpus_version\":1,\"seed\":5,\"index\":2,\"name\":\"type_event_flags_02\",\
+             \"what\":\"event, flags 0b00000010\",\"len\":37,\"decodes\":false,\
+             \"error\":\"reserved_flags\",\"offset\":4,\"frame\":null}\n"
+        );
+        let bin = std::fs::read(dir.path().join("00001-type_event_flags_01.bin")).unwrap();
+        assert_eq!(bin, cases[1].bytes);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 8);
+    }
+}
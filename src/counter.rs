@@ -0,0 +1,135 @@
+//! Counter ordering and what happens at `u64::MAX`.
+//!
+//! [`CounterSpace::Linear`] orders counters as plain integers, so a
+//! counter that wraps to 0 looks ancient. [`CounterSpace::Serial`] uses
+//! RFC 1982 serial number arithmetic: `b` is after `a` if it is less than
+//! 2^63 steps ahead modulo 2^64, so comparisons keep working across the
+//! wrap. Two counters exactly 2^63 apart are unordered.
+//!
+//! Senders pick an [`OverflowPolicy`] for the step past `u64::MAX`.
+//! Matching acks to frames compares ids for equality only, so it is the
+//! same in either space.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+const HALF: u64 = 1 << 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CounterSpace {
+    #[default]
+    Linear,
+    Serial,
+}
+
+impl CounterSpace {
+    /// Like `a.cmp(&b)`; `None` if the space can't order them.
+    pub fn compare(self, a: u64, b: u64) -> Option<Ordering> {
+        match self {
+            CounterSpace::Linear => Some(a.cmp(&b)),
+            CounterSpace::Serial => match b.wrapping_sub(a) {
+                0 => Some(Ordering::Equal),
+                HALF => None,
+                d if d < HALF => Some(Ordering::Less),
+                _ => Some(Ordering::Greater),
+            },
+        }
+    }
+
+    /// Steps from `from` forward to `to`, if `to` is strictly after it.
+    pub fn ahead(self, from: u64, to: u64) -> Option<u64> {
+        match self {
+            CounterSpace::Linear => to.checked_sub(from),
+            CounterSpace::Serial => Some(to.wrapping_sub(from)).filter(|&d| d < HALF),
+        }
+        .filter(|&d| d > 0)
+    }
+
+    /// Steps from `to` forward to `from`, if `to` is at or before it.
+    pub fn behind(self, from: u64, to: u64) -> Option<u64> {
+        match self {
+            CounterSpace::Linear => from.checked_sub(to),
+            CounterSpace::Serial => Some(from.wrapping_sub(to)).filter(|&d| d < HALF),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// `u64::MAX` is followed by 0; receivers should use
+    /// [`CounterSpace::Serial`].
+    Wrapping,
+    /// Stay at `u64::MAX`. Every later frame repeats it and is rejected as
+    /// a replay, so this only suits senders that will be re-keyed first.
+    Saturating,
+    /// Refuse to go past `u64::MAX`.
+    #[default]
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CounterOverflow;
+
+impl fmt::Display for CounterOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "counter exhausted")
+    }
+}
+
+impl std::error::Error for CounterOverflow {}
+
+/// The counter after `current` under `policy`.
+pub fn next_counter(current: u64, policy: OverflowPolicy) -> Result<u64, CounterOverflow> {
+    match (current.checked_add(1), policy) {
+        (Some(n), _) => Ok(n),
+        (None, OverflowPolicy::Wrapping) => Ok(0),
+        (None, OverflowPolicy::Saturating) => Ok(u64::MAX),
+        (None, OverflowPolicy::Error) => Err(CounterOverflow),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_comparison_straddles_the_wrap() {
+        let s = CounterSpace::Serial;
+        assert_eq!(s.compare(u64::MAX, 0), Some(Ordering::Less));
+        assert_eq!(s.compare(0, u64::MAX), Some(Ordering::Greater));
+        assert_eq!(s.compare(u64::MAX - 5, 10), Some(Ordering::Less));
+        assert_eq!(s.compare(7, 7), Some(Ordering::Equal));
+        assert_eq!(s.compare(0, HALF), None);
+        assert_eq!(s.compare(0, HALF - 1), Some(Ordering::Less));
+        assert_eq!(s.compare(0, HALF + 1), Some(Ordering::Greater));
+
+        assert_eq!(s.ahead(u64::MAX - 1, 2), Some(4));
+        assert_eq!(s.ahead(2, u64::MAX - 1), None);
+        assert_eq!(s.behind(2, u64::MAX - 1), Some(4));
+        assert_eq!(s.behind(2, 2), Some(0));
+        assert_eq!(s.ahead(2, 2), None);
+    }
+
+    #[test]
+    fn linear_comparison_does_not_wrap() {
+        let l = CounterSpace::Linear;
+        assert_eq!(l.compare(u64::MAX, 0), Some(Ordering::Greater));
+        assert_eq!(l.ahead(u64::MAX, 0), None);
+        assert_eq!(l.behind(u64::MAX, 0), Some(u64::MAX));
+        assert_eq!(l.ahead(1, 3), Some(2));
+    }
+
+    #[test]
+    fn overflow_policies() {
+        assert_eq!(next_counter(4, OverflowPolicy::Error), Ok(5));
+        assert_eq!(next_counter(u64::MAX, OverflowPolicy::Wrapping), Ok(0));
+        assert_eq!(
+            next_counter(u64::MAX, OverflowPolicy::Saturating),
+            Ok(u64::MAX)
+        );
+        assert_eq!(
+            next_counter(u64::MAX, OverflowPolicy::Error),
+            Err(CounterOverflow)
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! Keeping per-device counters across restarts.
+//!
+//! A [`CounterStore`] remembers the last counter used per device. Stores
+//! may persist lazily, so after a crash the loaded values can lag the
+//! counters actually sent; [`CounterMap::with_store`] adds a safety margin
+//! on load to stay clear of them.
+//!
+//! [`CounterMap::with_store`]: crate::sender::CounterMap::with_store
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+pub trait CounterStore: Send + Sync {
+    fn load_all(&self) -> io::Result<BTreeMap<[u8; 8], u64>>;
+
+    /// Record that `device_id` has used `counter`. May be buffered.
+    fn persist(&self, device_id: [u8; 8], counter: u64) -> io::Result<()>;
+
+    /// Make everything persisted so far durable.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct FileState {
+    counters: BTreeMap<[u8; 8], u64>,
+    unwritten: u32,
+}
+
+/// Counters in a text file, one `device_id_hex counter` line per device.
+/// The file is replaced atomically (temp file, fsync, rename) once every
+/// `persist_every` increments, and on flush and drop.
+pub struct FileCounterStore {
+    path: PathBuf,
+    persist_every: u32,
+    state: Mutex<FileState>,
+}
+
+impl FileCounterStore {
+    /// Open `path`, which need not exist yet.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let counters = match fs::read_to_string(&path) {
+            Ok(text) => parse(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(FileCounterStore {
+            path,
+            persist_every: 1,
+            state: Mutex::new(FileState {
+                counters,
+                unwritten: 0,
+            }),
+        })
+    }
+
+    /// Write the file only every `n` increments. Up to `n - 1` increments
+    /// can be lost in a crash, so load with a safety margin of at least `n`.
+    pub fn with_persist_every(mut self, n: u32) -> Self {
+        self.persist_every = n.max(1);
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FileState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self, state: &mut FileState) -> io::Result<()> {
+        let mut text = String::new();
+        for (device, counter) in &state.counters {
+            text.push_str(&format!("{} {counter}\n", crate::hex::encode(device)));
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(text.as_bytes())?;
+        tmp.sync_all()?;
+        drop(tmp);
+        fs::rename(&tmp_path, &self.path)?;
+        state.unwritten = 0;
+        Ok(())
+    }
+}
+
+fn parse(text: &str) -> io::Result<BTreeMap<[u8; 8], u64>> {
+    let bad = |line: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad counter line {line:?}"),
+        )
+    };
+    text.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|line| {
+            let (device, counter) = line.split_once(' ').ok_or_else(|| bad(line))?;
+            let device = crate::hex::decode(device)
+                .and_then(|d| <[u8; 8]>::try_from(d).ok())
+                .ok_or_else(|| bad(line))?;
+            let counter = counter.trim().parse().map_err(|_| bad(line))?;
+            Ok((device, counter))
+        })
+        .collect()
+}
+
+impl CounterStore for FileCounterStore {
+    fn load_all(&self) -> io::Result<BTreeMap<[u8; 8], u64>> {
+        Ok(self.lock().counters.clone())
+    }
+
+    fn persist(&self, device_id: [u8; 8], counter: u64) -> io::Result<()> {
+        let mut state = self.lock();
+        state.counters.insert(device_id, counter);
+        state.unwritten += 1;
+        if state.unwritten >= self.persist_every {
+            self.write(&mut state)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.unwritten > 0 {
+            self.write(&mut state)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FileCounterStore {
+    fn drop(&mut self) {
+        let _ = CounterStore::flush(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_writes_and_reloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counters");
+        let store = FileCounterStore::open(&path).unwrap().with_persist_every(3);
+        store.persist(*b"DEV00001", 1).unwrap();
+        store.persist(*b"DEV00002", 7).unwrap();
+        assert!(!path.exists());
+        store.persist(*b"DEV00001", 2).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "4445563030303031 2\n4445563030303032 7\n"
+        );
+
+        store.persist(*b"DEV00001", 3).unwrap();
+        drop(store);
+        let reopened = FileCounterStore::open(&path).unwrap();
+        assert_eq!(reopened.load_all().unwrap()[b"DEV00001"], 3);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counters");
+        fs::write(&path, "nothex 1\n").unwrap();
+        let err = FileCounterStore::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,295 @@
+//! CRC-32 (IEEE 802.3, reflected, as used by zlib and Ethernet).
+//!
+//! On x86_64 with PCLMULQDQ, long inputs are folded 64 bytes at a time with
+//! carry-less multiplies; elsewhere, and for short inputs and tails, a
+//! slicing-by-8 table takes 8 bytes per step.
+
+/// `TABLES[0]` is the classic bytewise table; `TABLES[k]` advances a byte
+/// followed by `k` zero bytes.
+const TABLES: [[u32; 256]; 8] = {
+    let mut tables = [[0u32; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        tables[0][i] = c;
+        i += 1;
+    }
+    let mut k = 1;
+    while k < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev >> 8) ^ tables[0][(prev & 0xff) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    tables
+};
+
+/// Incremental CRC-32.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(0xffff_ffff)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        #[cfg(target_arch = "x86_64")]
+        if pclmul::available() {
+            self.0 = pclmul::update(self.0, data);
+            return;
+        }
+        self.0 = update_portable(self.0, data);
+    }
+
+    pub fn finish(self) -> u32 {
+        self.0 ^ 0xffff_ffff
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut c = Crc32::new();
+    c.update(data);
+    c.finish()
+}
+
+/// As [`crc32`], with the table alone, whatever the CPU offers.
+pub fn crc32_portable(data: &[u8]) -> u32 {
+    update_portable(0xffff_ffff, data) ^ 0xffff_ffff
+}
+
+/// Indexes of the `(data, crc)` pairs whose CRC doesn't match, checking
+/// for hardware support once for the lot.
+pub fn verify_many<'a>(items: impl IntoIterator<Item = (&'a [u8], u32)>) -> Vec<usize> {
+    #[cfg(target_arch = "x86_64")]
+    let update = if pclmul::available() {
+        pclmul::update
+    } else {
+        update_portable
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let update = update_portable;
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (data, crc))| update(0xffff_ffff, data) ^ 0xffff_ffff != *crc)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+fn update_portable(mut crc: u32, data: &[u8]) -> u32 {
+    let t = &TABLES;
+    let mut chunks = data.chunks_exact(8);
+    for c in &mut chunks {
+        let a = u32::from_le_bytes([c[0], c[1], c[2], c[3]]) ^ crc;
+        let b = u32::from_le_bytes([c[4], c[5], c[6], c[7]]);
+        crc = t[7][(a & 0xff) as usize]
+            ^ t[6][((a >> 8) & 0xff) as usize]
+            ^ t[5][((a >> 16) & 0xff) as usize]
+            ^ t[4][(a >> 24) as usize]
+            ^ t[3][(b & 0xff) as usize]
+            ^ t[2][((b >> 8) & 0xff) as usize]
+            ^ t[1][((b >> 16) & 0xff) as usize]
+            ^ t[0][(b >> 24) as usize];
+    }
+    for &b in chunks.remainder() {
+        crc = t[0][((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Folding with carry-less multiplies, after Intel's "Fast CRC Computation
+/// for Generic Polynomials Using PCLMULQDQ Instruction" (2009), in its bit
+/// reflected form. The constants are powers of x modulo the polynomial.
+#[cfg(target_arch = "x86_64")]
+mod pclmul {
+    use std::arch::x86_64::*;
+
+    /// x^(4*128+32) and x^(4*128-32), for folding 4 lanes by 64 bytes.
+    const K1K2: (i64, i64) = (0x1_5444_2bd4, 0x1_c6e4_1596);
+    /// x^(128+32) and x^(128-32), for folding by 16 bytes.
+    const K3K4: (i64, i64) = (0x1_7519_97d0, 0x0_ccaa_009e);
+    /// x^64, for the step from 96 bits down to 64.
+    const K5: i64 = 0x1_63cd_6124;
+    /// The polynomial, and floor(x^64 / P(x)), for the Barrett reduction.
+    const P: i64 = 0x1_db71_0641;
+    const MU: i64 = 0x1_f701_1641;
+
+    /// Below this, setting up the folding costs more than it saves.
+    const MIN_LEN: usize = 128;
+
+    pub(super) fn available() -> bool {
+        is_x86_feature_detected!("pclmulqdq") && is_x86_feature_detected!("sse4.1")
+    }
+
+    /// As [`super::update_portable`]; only to be called once
+    /// [`available`] says so.
+    pub(super) fn update(crc: u32, data: &[u8]) -> u32 {
+        if data.len() < MIN_LEN {
+            return super::update_portable(crc, data);
+        }
+        // SAFETY: the CPU has the features, as checked by the caller.
+        unsafe { fold(crc, data) }
+    }
+
+    #[target_feature(enable = "pclmulqdq", enable = "sse4.1")]
+    fn fold(crc: u32, data: &[u8]) -> u32 {
+        let mut blocks = data.chunks_exact(16).map(load);
+        let mut next = || blocks.next().unwrap();
+
+        let mut x3 = _mm_xor_si128(next(), _mm_cvtsi32_si128(crc as i32));
+        let mut x2 = next();
+        let mut x1 = next();
+        let mut x0 = next();
+        let mut left = data.len() / 16 - 4;
+
+        let k1k2 = _mm_set_epi64x(K1K2.1, K1K2.0);
+        while left >= 4 {
+            x3 = reduce128(x3, next(), k1k2);
+            x2 = reduce128(x2, next(), k1k2);
+            x1 = reduce128(x1, next(), k1k2);
+            x0 = reduce128(x0, next(), k1k2);
+            left -= 4;
+        }
+
+        let k3k4 = _mm_set_epi64x(K3K4.1, K3K4.0);
+        let mut x = reduce128(x3, x2, k3k4);
+        x = reduce128(x, x1, k3k4);
+        x = reduce128(x, x0, k3k4);
+        for _ in 0..left {
+            x = reduce128(x, next(), k3k4);
+        }
+
+        // 128 bits down to 64.
+        let low32 = _mm_set_epi32(0, 0, 0, !0);
+        let x = _mm_xor_si128(
+            _mm_clmulepi64_si128::<0x10>(x, k3k4),
+            _mm_srli_si128::<8>(x),
+        );
+        let x = _mm_xor_si128(
+            _mm_clmulepi64_si128::<0x00>(_mm_and_si128(x, low32), _mm_set_epi64x(0, K5)),
+            _mm_srli_si128::<4>(x),
+        );
+
+        // Barrett reduction to 32 bits, taking the upper half of the
+        // 64-bit remainder as the input is bit reflected.
+        let pu = _mm_set_epi64x(MU, P);
+        let t1 = _mm_clmulepi64_si128::<0x10>(_mm_and_si128(x, low32), pu);
+        let t2 = _mm_clmulepi64_si128::<0x00>(_mm_and_si128(t1, low32), pu);
+        let crc = _mm_extract_epi32::<1>(_mm_xor_si128(x, t2)) as u32;
+
+        super::update_portable(crc, data.chunks_exact(16).remainder())
+    }
+
+    #[target_feature(enable = "pclmulqdq", enable = "sse4.1")]
+    fn reduce128(a: __m128i, b: __m128i, keys: __m128i) -> __m128i {
+        let t1 = _mm_clmulepi64_si128::<0x00>(a, keys);
+        let t2 = _mm_clmulepi64_si128::<0x11>(a, keys);
+        _mm_xor_si128(_mm_xor_si128(b, t1), t2)
+    }
+
+    fn load(block: &[u8]) -> __m128i {
+        let block: &[u8; 16] = block.try_into().unwrap();
+        // SAFETY: 16 readable bytes; the load needs no alignment.
+        unsafe { _mm_loadu_si128(block.as_ptr().cast()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Rng;
+
+    /// The bytewise loop the tables stand in for.
+    fn reference(data: &[u8]) -> u32 {
+        let mut crc = 0xffff_ffff;
+        for &b in data {
+            crc = TABLES[0][((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+        crc ^ 0xffff_ffff
+    }
+
+    fn random(rng: &mut Rng, len: usize) -> Vec<u8> {
+        (0..len).map(|_| rng.next_u64() as u8).collect()
+    }
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32_portable(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn incremental_matches_one_shot() {
+        let mut c = Crc32::new();
+        c.update(b"1234");
+        c.update(b"56789");
+        assert_eq!(c.finish(), crc32(b"123456789"));
+    }
+
+    #[test]
+    fn fast_paths_match_the_bytewise_loop() {
+        let mut rng = Rng(7);
+        let data = random(&mut rng, 70_000);
+        let lens = (0..300).chain([1023, 1024, 1025, 4096, 65_536, 65_537]);
+        for len in lens {
+            // Odd offsets too, so the loads aren't all aligned.
+            for at in [0, 1, 7] {
+                let input = &data[at..at + len];
+                let want = reference(input);
+                assert_eq!(crc32_portable(input), want, "len {len} at {at}");
+                assert_eq!(crc32(input), want, "len {len} at {at}");
+            }
+        }
+    }
+
+    #[test]
+    fn random_splits_match_one_shot() {
+        let mut rng = Rng(11);
+        for _ in 0..200 {
+            let len = rng.next_u64() as usize % 2000;
+            let data = random(&mut rng, len);
+            let mut c = Crc32::new();
+            let mut rest = &data[..];
+            while !rest.is_empty() {
+                let n = 1 + rng.next_u64() as usize % rest.len().min(300);
+                c.update(&rest[..n]);
+                rest = &rest[n..];
+            }
+            assert_eq!(c.finish(), reference(&data));
+        }
+    }
+
+    #[test]
+    fn verify_many_reports_the_mismatches() {
+        let blocks = [&b"123456789"[..], &[0u8; 200][..], b""];
+        let crcs: Vec<u32> = blocks.iter().map(|b| reference(b)).collect();
+        let good = blocks.iter().copied().zip(crcs.iter().copied());
+        assert!(verify_many(good).is_empty());
+        let bad = [
+            (blocks[0], crcs[0]),
+            (blocks[1], crcs[1] ^ 1),
+            (blocks[2], 1),
+        ];
+        assert_eq!(verify_many(bad), [1, 2]);
+    }
+}
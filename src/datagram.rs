@@ -0,0 +1,241 @@
+//! Packing several frames into one datagram.
+//!
+//! A packed datagram is `PK` followed by `len(2) || frame` entries. A
+//! datagram holding a single frame is sent as the plain frame, so receivers
+//! that never pack still understand it.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::{DecodeError, FrameV1};
+
+pub const PACK_MAGIC: [u8; 2] = *b"PK";
+
+/// Largest UDP payload over IPv4.
+pub const MAX_DATAGRAM: usize = 65_507;
+
+/// Safe payload budget on paths of unknown MTU.
+pub const DEFAULT_MTU: usize = 1200;
+
+pub(crate) const ENTRY_PREFIX: usize = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Packer {
+    mtu: usize,
+}
+
+impl Packer {
+    /// `mtu` is the datagram payload budget, clamped to [`MAX_DATAGRAM`].
+    pub fn new(mtu: usize) -> Self {
+        Packer {
+            mtu: mtu.min(MAX_DATAGRAM),
+        }
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Greedily take frames from the front of `queue` into one datagram,
+    /// never splitting a frame. A frame too large to share a datagram is
+    /// returned alone (plain). Returns `None` when the queue is empty.
+    pub fn pack(&self, queue: &mut VecDeque<FrameV1>) -> Option<Vec<u8>> {
+        let first = queue.pop_front()?.encode();
+        let fits = |used: usize, f: &FrameV1| used + ENTRY_PREFIX + f.encoded_len() <= self.mtu;
+
+        let used = PACK_MAGIC.len() + ENTRY_PREFIX + first.len();
+        match queue.front() {
+            Some(next) if used <= self.mtu && fits(used, next) => {}
+            _ => return Some(first),
+        }
+
+        let mut out = Vec::with_capacity(self.mtu);
+        out.extend_from_slice(&PACK_MAGIC);
+        push_entry(&mut out, &first);
+        while let Some(next) = queue.front() {
+            if !fits(out.len(), next) {
+                break;
+            }
+            push_entry(&mut out, &next.encode());
+            queue.pop_front();
+        }
+        Some(out)
+    }
+}
+
+impl Default for Packer {
+    fn default() -> Self {
+        Packer::new(DEFAULT_MTU)
+    }
+}
+
+fn push_entry(out: &mut Vec<u8>, frame: &[u8]) {
+    out.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+    out.extend_from_slice(frame);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnpackError {
+    /// The entry starting at `offset` runs past the end of the datagram.
+    Truncated { offset: usize },
+    /// The frame starting at `offset` failed to decode.
+    Frame { offset: usize, error: DecodeError },
+}
+
+impl fmt::Display for UnpackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnpackError::Truncated { offset } => write!(f, "truncated entry at offset {offset}"),
+            UnpackError::Frame { offset, error } => write!(f, "frame at offset {offset}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for UnpackError {}
+
+/// Iterate the frames in a received datagram, packed or plain. Each frame
+/// is reported individually; a bad entry does not hide the ones after it,
+/// except for truncation, which ends the iteration.
+pub fn unpack(datagram: &[u8]) -> Unpack<'_> {
+    if datagram.starts_with(&PACK_MAGIC) {
+        Unpack {
+            buf: datagram,
+            pos: PACK_MAGIC.len(),
+            packed: true,
+        }
+    } else {
+        Unpack {
+            buf: datagram,
+            pos: 0,
+            packed: false,
+        }
+    }
+}
+
+pub struct Unpack<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    packed: bool,
+}
+
+impl Iterator for Unpack<'_> {
+    type Item = Result<FrameV1, UnpackError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.buf.len() {
+            return None;
+        }
+
+        let offset = self.pos;
+        if !self.packed {
+            self.pos = self.buf.len();
+            return Some(
+                FrameV1::decode(self.buf).map_err(|error| UnpackError::Frame { offset, error }),
+            );
+        }
+
+        let rest = &self.buf[offset..];
+        let len = match rest {
+            [hi, lo, ..] => u16::from_be_bytes([*hi, *lo]) as usize,
+            _ => {
+                self.pos = self.buf.len();
+                return Some(Err(UnpackError::Truncated { offset }));
+            }
+        };
+        let Some(frame) = rest.get(ENTRY_PREFIX..ENTRY_PREFIX + len) else {
+            self.pos = self.buf.len();
+            return Some(Err(UnpackError::Truncated { offset }));
+        };
+        self.pos += ENTRY_PREFIX + len;
+
+        let offset = offset + ENTRY_PREFIX;
+        Some(FrameV1::decode(frame).map_err(|error| UnpackError::Frame { offset, error }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Flags, FrameHeaderV1, MsgType, VERSION_V1};
+
+    fn frame(counter: u64, body_len: usize) -> FrameV1 {
+        FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Event,
+                flags: Flags::new(0).unwrap(),
+                device_id: *b"DEV00001",
+                counter,
+            },
+            body: vec![0xab; body_len].into(),
+        }
+    }
+
+    #[test]
+    fn packs_greedily_without_splitting() {
+        // each entry is 2 + 21 + 9 = 32 bytes; 2 + 3 * 32 = 98
+        let packer = Packer::new(100);
+        let mut queue: VecDeque<_> = (0..5).map(|c| frame(c, 9)).collect();
+
+        let dgram = packer.pack(&mut queue).unwrap();
+        assert_eq!(dgram.len(), 98);
+        assert_eq!(queue.len(), 2);
+
+        let counters: Vec<u64> = unpack(&dgram).map(|f| f.unwrap().header.counter).collect();
+        assert_eq!(counters, [0, 1, 2]);
+    }
+
+    #[test]
+    fn single_frame_is_sent_plain() {
+        let packer = Packer::new(100);
+        let mut queue = VecDeque::from([frame(1, 4)]);
+        assert_eq!(packer.pack(&mut queue).unwrap(), frame(1, 4).encode());
+    }
+
+    #[test]
+    fn oversized_frame_is_sent_alone() {
+        let packer = Packer::new(64);
+        let mut queue = VecDeque::from([frame(1, 200), frame(2, 1)]);
+
+        assert_eq!(packer.pack(&mut queue).unwrap(), frame(1, 200).encode());
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn empty_queue_packs_nothing() {
+        assert_eq!(Packer::default().pack(&mut VecDeque::new()), None);
+    }
+
+    #[test]
+    fn unpack_reports_each_frame() {
+        let packer = Packer::new(1000);
+        let mut queue: VecDeque<_> = (0..3).map(|c| frame(c, 2)).collect();
+        let mut dgram = packer.pack(&mut queue).unwrap();
+
+        // corrupt magic of the middle frame: 2 (PK) + 25 (first entry) + 2 (len)
+        dgram[29] = b'X';
+
+        let items: Vec<_> = unpack(&dgram).collect();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0], Ok(frame(0, 2)));
+        assert_eq!(
+            items[1],
+            Err(UnpackError::Frame {
+                offset: 29,
+                error: DecodeError::BadMagic
+            })
+        );
+        assert_eq!(items[2], Ok(frame(2, 2)));
+    }
+
+    #[test]
+    fn unpack_truncated_entry() {
+        let mut dgram = PACK_MAGIC.to_vec();
+        push_entry(&mut dgram, &frame(0, 0).encode());
+        dgram.extend_from_slice(&[0x00, 0x30, b'P']);
+
+        let items: Vec<_> = unpack(&dgram).collect();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1], Err(UnpackError::Truncated { offset: 25 }));
+    }
+}
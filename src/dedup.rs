@@ -0,0 +1,280 @@
+//! Suppressing duplicate deliveries of retransmitted frames.
+//!
+//! [`DedupCache`] remembers the most recently seen [`FrameId`]s, up to a
+//! fixed capacity and optionally for a limited time. Memory is bounded at
+//! the cost of exactness: a duplicate that arrives after its id was
+//! evicted (by capacity or TTL) is reported as a first delivery. Size the
+//! cache to cover the sender's whole retransmission period.
+//!
+//! Both it and the fixed-memory
+//! [`RotatingBloom`](crate::bloom::RotatingBloom) are a
+//! [`DuplicateFilter`], which is what a [`SharedDedupCache`] holds.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::FrameId;
+use crate::clock::{Clock, SystemClock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    FirstDelivery,
+    Duplicate,
+}
+
+/// Decides which frames were delivered before.
+pub trait DuplicateFilter: Send {
+    /// Whether `id` is new, remembering it either way.
+    fn check_and_record(&mut self, id: FrameId) -> Delivery;
+
+    /// Whether `id` would be a duplicate, without remembering it.
+    fn contains(&self, id: FrameId) -> bool;
+
+    /// Ids remembered.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DedupConfig {
+    pub capacity: usize,
+    /// Forget ids this long after they were first seen.
+    pub ttl: Option<Duration>,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            capacity: 4096,
+            ttl: None,
+        }
+    }
+}
+
+struct Entry {
+    /// Recency rank, the key in `order`.
+    used: u64,
+    first_seen: Instant,
+}
+
+pub struct DedupCache {
+    clock: Arc<dyn Clock>,
+    config: DedupConfig,
+    entries: HashMap<FrameId, Entry>,
+    order: BTreeMap<u64, FrameId>,
+    next_use: u64,
+}
+
+impl DedupCache {
+    pub fn new(config: DedupConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: DedupConfig, clock: Arc<dyn Clock>) -> Self {
+        DedupCache {
+            clock,
+            config: DedupConfig {
+                capacity: config.capacity.max(1),
+                ..config
+            },
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_use: 0,
+        }
+    }
+
+    /// Whether `id` is new, remembering it either way. A duplicate counts
+    /// as a use for LRU purposes but does not extend its TTL.
+    pub fn check_and_record(&mut self, id: FrameId) -> Delivery {
+        let now = self.clock.now();
+        let used = self.next_use;
+        self.next_use += 1;
+
+        if let Some(e) = self.entries.get_mut(&id) {
+            let expired = self
+                .config
+                .ttl
+                .is_some_and(|ttl| now.duration_since(e.first_seen) >= ttl);
+            self.order.remove(&e.used);
+            self.order.insert(used, id);
+            e.used = used;
+            if !expired {
+                return Delivery::Duplicate;
+            }
+            e.first_seen = now;
+            return Delivery::FirstDelivery;
+        }
+
+        if self.entries.len() >= self.config.capacity
+            && let Some((_, oldest)) = self.order.pop_first()
+        {
+            self.entries.remove(&oldest);
+        }
+        self.entries.insert(
+            id,
+            Entry {
+                used,
+                first_seen: now,
+            },
+        );
+        self.order.insert(used, id);
+        Delivery::FirstDelivery
+    }
+
+    /// Whether `id` was seen within the TTL, without counting as a use.
+    pub fn contains(&self, id: FrameId) -> bool {
+        let now = self.clock.now();
+        self.entries.get(&id).is_some_and(|e| {
+            self.config
+                .ttl
+                .is_none_or(|ttl| now.duration_since(e.first_seen) < ttl)
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl DuplicateFilter for DedupCache {
+    fn check_and_record(&mut self, id: FrameId) -> Delivery {
+        DedupCache::check_and_record(self, id)
+    }
+
+    fn contains(&self, id: FrameId) -> bool {
+        DedupCache::contains(self, id)
+    }
+
+    fn len(&self) -> usize {
+        DedupCache::len(self)
+    }
+}
+
+/// A [`DedupCache`], or another [`DuplicateFilter`], shared between
+/// tasks. Clones share one cache.
+#[derive(Clone)]
+pub struct SharedDedupCache {
+    inner: Arc<Mutex<dyn DuplicateFilter>>,
+}
+
+impl SharedDedupCache {
+    pub fn new(cache: impl DuplicateFilter + 'static) -> Self {
+        SharedDedupCache {
+            inner: Arc::new(Mutex::new(cache)),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, dyn DuplicateFilter + 'static> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn check_and_record(&self, id: FrameId) -> Delivery {
+        self.lock().check_and_record(id)
+    }
+
+    pub fn contains(&self, id: FrameId) -> bool {
+        self.lock().contains(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn id(counter: u64) -> FrameId {
+        FrameId {
+            device_id: *b"DEV00001",
+            counter,
+        }
+    }
+
+    #[test]
+    fn duplicates_within_capacity() {
+        let mut c = DedupCache::new(DedupConfig::default());
+        assert_eq!(c.check_and_record(id(1)), Delivery::FirstDelivery);
+        assert_eq!(c.check_and_record(id(2)), Delivery::FirstDelivery);
+        assert_eq!(c.check_and_record(id(1)), Delivery::Duplicate);
+        let other = FrameId {
+            device_id: *b"DEV00002",
+            counter: 1,
+        };
+        assert_eq!(c.check_and_record(other), Delivery::FirstDelivery);
+    }
+
+    #[test]
+    fn evicted_duplicate_counts_as_first_delivery() {
+        let mut c = DedupCache::new(DedupConfig {
+            capacity: 2,
+            ttl: None,
+        });
+        c.check_and_record(id(1));
+        c.check_and_record(id(2));
+        // touching 1 makes 2 the least recently used
+        assert_eq!(c.check_and_record(id(1)), Delivery::Duplicate);
+        c.check_and_record(id(3));
+        assert_eq!(c.len(), 2);
+        assert_eq!(c.check_and_record(id(1)), Delivery::Duplicate);
+        assert_eq!(c.check_and_record(id(2)), Delivery::FirstDelivery);
+    }
+
+    #[test]
+    fn ttl_expires_ids() {
+        let clock = ManualClock::new();
+        let config = DedupConfig {
+            capacity: 16,
+            ttl: Some(Duration::from_secs(10)),
+        };
+        let mut c = DedupCache::with_clock(config, Arc::new(clock.clone()));
+        c.check_and_record(id(1));
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(c.check_and_record(id(1)), Delivery::Duplicate);
+        clock.advance(Duration::from_secs(1));
+        assert!(!c.contains(id(1)));
+        assert_eq!(c.check_and_record(id(1)), Delivery::FirstDelivery);
+        assert!(c.contains(id(1)));
+        assert_eq!(c.check_and_record(id(1)), Delivery::Duplicate);
+    }
+
+    #[test]
+    fn shared_cache_delivers_each_id_once() {
+        let cache = SharedDedupCache::new(DedupCache::new(DedupConfig::default()));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    (0..500)
+                        .filter(|&n| cache.check_and_record(id(n)) == Delivery::FirstDelivery)
+                        .count()
+                })
+            })
+            .collect();
+        let firsts: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(firsts, 500);
+    }
+
+    #[test]
+    fn shared_cache_takes_any_filter() {
+        let filter = crate::bloom::RotatingBloom::<64, 4>::new().with_rotate_after(2);
+        let cache = SharedDedupCache::new(filter);
+        assert_eq!(cache.check_and_record(id(1)), Delivery::FirstDelivery);
+        assert_eq!(cache.check_and_record(id(1)), Delivery::Duplicate);
+        assert_eq!(cache.len(), 1);
+    }
+}
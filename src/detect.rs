@@ -0,0 +1,152 @@
+//! Finding frames in raw captures without decoding them.
+//!
+//! [`scan`] reports every offset where a fully valid v1 header starts. A
+//! header alone is weak evidence, since "PP" and a few plausible bytes can
+//! occur anywhere, so candidates are graded: a stream length prefix
+//! (RFC section 11) in front of the header that fits the buffer is better,
+//! and a prefix that lands exactly on the next candidate or the end of the
+//! buffer is best. Headers inside the body of a prefixed candidate are
+//! dropped, which keeps "PP" in bodies from showing up as frames.
+
+use std::collections::HashSet;
+
+use crate::{FrameHeaderV1, HEADER_LEN_V1, MAGIC};
+
+const PREFIX_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    /// A valid header and nothing more.
+    Header,
+    /// Preceded by a length prefix that fits the buffer.
+    Prefixed,
+    /// The length prefix chains to another frame or the end of the buffer.
+    Chained,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CandidateFrame {
+    /// Where the header starts.
+    pub offset: usize,
+    pub header: FrameHeaderV1,
+    /// Frame length from the prefix, when there is one.
+    pub len: Option<usize>,
+    pub confidence: Confidence,
+}
+
+impl CandidateFrame {
+    fn end(&self) -> Option<usize> {
+        self.len.map(|l| self.offset + l)
+    }
+}
+
+/// Every valid header in `buf`, in offset order.
+pub fn scan(buf: &[u8]) -> Vec<CandidateFrame> {
+    let mut found: Vec<CandidateFrame> = (0..buf.len().saturating_sub(HEADER_LEN_V1 - 1))
+        .filter(|&i| buf[i..].starts_with(&MAGIC))
+        .filter_map(|offset| {
+            let header = FrameHeaderV1::decode(&buf[offset..]).ok()?;
+            let len = offset
+                .checked_sub(PREFIX_LEN)
+                .map(|p| u32::from_be_bytes(buf[p..offset].try_into().unwrap()) as usize)
+                .filter(|&l| l >= HEADER_LEN_V1 && l <= buf.len() - offset);
+            Some(CandidateFrame {
+                offset,
+                header,
+                len,
+                confidence: match len {
+                    Some(_) => Confidence::Prefixed,
+                    None => Confidence::Header,
+                },
+            })
+        })
+        .collect();
+
+    let starts: HashSet<usize> = found
+        .iter()
+        .filter(|c| c.len.is_some())
+        .map(|c| c.offset)
+        .collect();
+    let ends: HashSet<usize> = found.iter().filter_map(CandidateFrame::end).collect();
+    for c in &mut found {
+        if let Some(end) = c.end()
+            && (end == buf.len()
+                || starts.contains(&(end + PREFIX_LEN))
+                || ends.contains(&(c.offset - PREFIX_LEN)))
+        {
+            c.confidence = Confidence::Chained;
+        }
+    }
+
+    // a header inside a prefixed frame's body is part of that body
+    let bodies: Vec<(usize, usize)> = found
+        .iter()
+        .filter_map(|c| Some((c.offset, c.end()?)))
+        .collect();
+    found.retain(|c| {
+        !bodies
+            .iter()
+            .any(|&(start, end)| c.offset > start && c.offset < end)
+    });
+    found
+}
+
+/// A quick guess: `buf` is a bare frame, or holds a length-prefixed one.
+pub fn is_probably_pipproto(buf: &[u8]) -> bool {
+    FrameHeaderV1::decode(buf).is_ok()
+        || scan(buf)
+            .iter()
+            .any(|c| c.confidence >= Confidence::Prefixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::write_prefixed;
+    use crate::transport::tests::frame;
+
+    fn noise(n: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed;
+        (0..n)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_frames_salted_into_noise() {
+        let mut buf = noise(4001, 1);
+        let a = frame(1).encode();
+        let b = frame(2).encode();
+        buf[101..101 + a.len()].copy_from_slice(&a);
+        buf[3333..3333 + b.len()].copy_from_slice(&b);
+
+        let found = scan(&buf);
+        let offsets: Vec<_> = found.iter().map(|c| c.offset).collect();
+        assert_eq!(offsets, [101, 3333]);
+        assert_eq!(found[1].header, frame(2).header);
+        assert!(!is_probably_pipproto(&noise(4001, 1)));
+        assert!(is_probably_pipproto(&a));
+    }
+
+    #[test]
+    fn chains_stream_frames_and_ignores_headers_in_bodies() {
+        let mut inner = frame(9);
+        inner.body = frame(5).encode().into();
+        let mut buf = noise(7, 2);
+        write_prefixed(&frame(1), &mut buf);
+        write_prefixed(&inner, &mut buf);
+
+        let found = scan(&buf);
+        assert_eq!(found.len(), 2, "{found:?}");
+        assert_eq!(found[0].offset, 11);
+        assert_eq!(found[0].len, Some(24));
+        assert!(found.iter().all(|c| c.confidence == Confidence::Chained));
+        assert_eq!(found[1].header.counter, 9);
+        assert!(is_probably_pipproto(&buf));
+    }
+}
@@ -0,0 +1,503 @@
+//! What differs between two recordings of the same traffic.
+//!
+//! [`diff`] pairs up the frames of two streams, say a journal from each of
+//! two gateway versions, by [`FrameId`]: sender and counter. A frame seen
+//! again on the same side within `window` frames is a retransmission and
+//! is counted, not compared; a frame whose pair has not turned up once the
+//! other side is `window` frames past it is reported as on one side only.
+//! So the two streams may be reordered against each other by up to
+//! `window` frames. Paired frames are compared by type, flags and body,
+//! and by when they arrived counted from each stream's first frame, since
+//! the recordings need not have started together.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use crate::{FrameId, FrameV1};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffConfig {
+    /// How far apart, in frames, a pair may be, and how far back a
+    /// retransmission is recognised.
+    pub window: usize,
+    /// Arrival times further apart than this are reported; `None` never.
+    pub timing: Option<Duration>,
+}
+
+impl Default for DiffConfig {
+    fn default() -> Self {
+        DiffConfig {
+            window: 1024,
+            timing: Some(Duration::from_secs(1)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Side::Left => "left",
+            Side::Right => "right",
+        }
+    }
+}
+
+/// Bytes from `offset` on that differ between the two bodies. A body
+/// longer than the other ends in a run the shorter side has nothing of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteRun {
+    pub offset: usize,
+    pub left: Vec<u8>,
+    pub right: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// A frame the other side did not have.
+    Only {
+        side: Side,
+        at: SystemTime,
+        frame: FrameV1,
+    },
+    /// A pair whose type, flags or body differ.
+    Changed {
+        left: FrameV1,
+        right: FrameV1,
+        body: Vec<ByteRun>,
+    },
+    /// A pair that arrived further apart than allowed, each time counted
+    /// from its stream's first frame.
+    Timing {
+        id: FrameId,
+        left: Duration,
+        right: Duration,
+    },
+}
+
+/// The differences between two bodies, byte by byte.
+pub fn byte_runs(left: &[u8], right: &[u8]) -> Vec<ByteRun> {
+    let mut runs = Vec::new();
+    let len = left.len().max(right.len());
+    let mut i = 0;
+    while i < len {
+        if left.get(i) == right.get(i) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < len && left.get(i) != right.get(i) {
+            i += 1;
+        }
+        let part = |b: &[u8]| b[start.min(b.len())..i.min(b.len())].to_vec();
+        runs.push(ByteRun {
+            offset: start,
+            left: part(left),
+            right: part(right),
+        });
+    }
+    runs
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SideSummary {
+    pub frames: u64,
+    pub duplicates: u64,
+    /// Frames the other side did not have.
+    pub only: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub left: SideSummary,
+    pub right: SideSummary,
+    pub matched: u64,
+    /// Pairs that were the same, timing aside.
+    pub identical: u64,
+    pub changed: u64,
+    pub timing: u64,
+}
+
+impl Summary {
+    /// Whether the streams carried the same frames.
+    pub fn same(&self) -> bool {
+        self.left.only == 0 && self.right.only == 0 && self.changed == 0 && self.timing == 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// In the order they were found.
+    pub differences: Vec<Difference>,
+    pub summary: Summary,
+}
+
+#[derive(Debug, Default)]
+struct Stream {
+    start: Option<SystemTime>,
+    read: usize,
+    /// Where each frame was last first seen, to spot retransmissions.
+    seen: HashMap<FrameId, usize>,
+    seen_order: VecDeque<(usize, FrameId)>,
+    /// Frames waiting for their pair.
+    pending: HashMap<FrameId, (usize, SystemTime, FrameV1)>,
+    pending_order: VecDeque<(usize, FrameId)>,
+    summary: SideSummary,
+}
+
+impl Stream {
+    fn since_start(&self, at: SystemTime) -> Duration {
+        self.start
+            .and_then(|s| at.duration_since(s).ok())
+            .unwrap_or_default()
+    }
+
+    /// The pending frame at `pos`, if it is still waiting.
+    fn take(&mut self, pos: usize, id: &FrameId) -> Option<(SystemTime, FrameV1)> {
+        match self.pending.get(id) {
+            Some((p, ..)) if *p == pos => self.pending.remove(id).map(|(_, at, f)| (at, f)),
+            _ => None,
+        }
+    }
+}
+
+struct Aligner {
+    config: DiffConfig,
+    streams: [Stream; 2],
+    report: Report,
+}
+
+impl Aligner {
+    fn push(&mut self, side: Side, at: SystemTime, frame: FrameV1) {
+        let window = self.config.window;
+        let [left, right] = &mut self.streams;
+        let (this, other) = match side {
+            Side::Left => (left, right),
+            Side::Right => (right, left),
+        };
+        let id = frame.header.id();
+        let pos = this.read;
+        this.read += 1;
+        this.summary.frames += 1;
+        this.start.get_or_insert(at);
+
+        while let Some(&(p, old)) = this.seen_order.front() {
+            if p + window >= pos {
+                break;
+            }
+            this.seen_order.pop_front();
+            if this.seen.get(&old) == Some(&p) {
+                this.seen.remove(&old);
+            }
+        }
+        if this.seen.contains_key(&id) {
+            this.summary.duplicates += 1;
+            return;
+        }
+        this.seen.insert(id, pos);
+        this.seen_order.push_back((pos, id));
+
+        let mut found = Vec::new();
+        match other.pending.remove(&id) {
+            Some((_, other_at, other_frame)) => {
+                let (l, r) = match side {
+                    Side::Left => ((this, at, frame), (other, other_at, other_frame)),
+                    Side::Right => ((other, other_at, other_frame), (this, at, frame)),
+                };
+                let (left_at, right_at) = (l.0.since_start(l.1), r.0.since_start(r.1));
+                found.extend(compare(&self.config, l.2, r.2, left_at, right_at));
+                self.report.summary.matched += 1;
+            }
+            None => {
+                this.pending.insert(id, (pos, at, frame));
+                this.pending_order.push_back((pos, id));
+            }
+        }
+
+        let [left, right] = &mut self.streams;
+        let other = match side {
+            Side::Left => right,
+            Side::Right => left,
+        };
+        while let Some(&(p, old)) = other.pending_order.front() {
+            if p + window >= pos {
+                break;
+            }
+            other.pending_order.pop_front();
+            if let Some((at, frame)) = other.take(p, &old) {
+                other.summary.only += 1;
+                let side = match side {
+                    Side::Left => Side::Right,
+                    Side::Right => Side::Left,
+                };
+                found.push(Difference::Only { side, at, frame });
+            }
+        }
+        self.record(found);
+    }
+
+    fn record(&mut self, found: Vec<Difference>) {
+        let summary = &mut self.report.summary;
+        for d in &found {
+            match d {
+                Difference::Changed { .. } => summary.changed += 1,
+                Difference::Timing { .. } => summary.timing += 1,
+                Difference::Only { .. } => {}
+            }
+        }
+        self.report.differences.extend(found);
+    }
+
+    fn finish(mut self) -> Report {
+        for (side, stream) in [Side::Left, Side::Right].into_iter().zip(&mut self.streams) {
+            while let Some((p, id)) = stream.pending_order.pop_front() {
+                if let Some((at, frame)) = stream.take(p, &id) {
+                    stream.summary.only += 1;
+                    self.report
+                        .differences
+                        .push(Difference::Only { side, at, frame });
+                }
+            }
+        }
+        let [left, right] = &self.streams;
+        let summary = &mut self.report.summary;
+        summary.left = left.summary;
+        summary.right = right.summary;
+        summary.identical = summary.matched - summary.changed;
+        self.report
+    }
+}
+
+fn compare(
+    config: &DiffConfig,
+    left: FrameV1,
+    right: FrameV1,
+    left_at: Duration,
+    right_at: Duration,
+) -> Vec<Difference> {
+    let mut out = Vec::new();
+    let id = left.header.id();
+    if let Some(limit) = config.timing
+        && left_at.abs_diff(right_at) > limit
+    {
+        out.push(Difference::Timing {
+            id,
+            left: left_at,
+            right: right_at,
+        });
+    }
+    let (l, r) = (&left.header, &right.header);
+    if l.msg_type != r.msg_type || l.flags != r.flags || left.body != right.body {
+        let body = byte_runs(&left.body, &right.body);
+        out.insert(0, Difference::Changed { left, right, body });
+    }
+    out
+}
+
+/// The differences between the frames of `left` and `right`, each given
+/// with when it was recorded.
+pub fn diff<L, R>(left: L, right: R, config: &DiffConfig) -> Report
+where
+    L: IntoIterator<Item = (SystemTime, FrameV1)>,
+    R: IntoIterator<Item = (SystemTime, FrameV1)>,
+{
+    let mut aligner = Aligner {
+        config: config.clone(),
+        streams: Default::default(),
+        report: Report {
+            differences: Vec::new(),
+            summary: Summary::default(),
+        },
+    };
+    let (mut left, mut right) = (left.into_iter().fuse(), right.into_iter().fuse());
+    loop {
+        let (l, r) = (left.next(), right.next());
+        if l.is_none() && r.is_none() {
+            break;
+        }
+        if let Some((at, frame)) = l {
+            aligner.push(Side::Left, at, frame);
+        }
+        if let Some((at, frame)) = r {
+            aligner.push(Side::Right, at, frame);
+        }
+    }
+    aligner.finish()
+}
+
+/// One line, without the time of a frame on one side only.
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = crate::hex::encode;
+        match self {
+            Difference::Only { side, frame, .. } => write!(f, "only {}: {frame}", side.as_str()),
+            Difference::Changed { left, right, body } => {
+                let (l, r) = (&left.header, &right.header);
+                let mut parts = Vec::new();
+                if l.msg_type != r.msg_type {
+                    parts.push(format!(
+                        "type {} -> {}",
+                        l.msg_type.as_str(),
+                        r.msg_type.as_str()
+                    ));
+                }
+                if l.flags != r.flags {
+                    parts.push(format!(
+                        "flags 0b{:08b} -> 0b{:08b}",
+                        l.flags.bits(),
+                        r.flags.bits()
+                    ));
+                }
+                if !body.is_empty() {
+                    let runs: Vec<_> = body
+                        .iter()
+                        .map(|run| {
+                            format!(
+                                "@{}: {} -> {}",
+                                run.offset,
+                                or_none(&hex(&run.left)),
+                                or_none(&hex(&run.right))
+                            )
+                        })
+                        .collect();
+                    parts.push(format!("body {}", runs.join(" ")));
+                }
+                write!(
+                    f,
+                    "changed dev={} ctr={}: {}",
+                    l.device_id_hex(),
+                    l.counter,
+                    parts.join(", ")
+                )
+            }
+            Difference::Timing { id, left, right } => write!(
+                f,
+                "timing dev={} ctr={}: left at {:.3}s, right at {:.3}s",
+                hex(&id.device_id),
+                id.counter,
+                left.as_secs_f64(),
+                right.as_secs_f64()
+            ),
+        }
+    }
+}
+
+fn or_none(hex: &str) -> &str {
+    if hex.is_empty() { "(none)" } else { hex }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Flags, FrameHeaderV1, MsgType, VERSION_V1};
+
+    fn frame(device: u8, counter: u64, body: &[u8]) -> FrameV1 {
+        FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Event,
+                flags: Flags::new(0).unwrap(),
+                device_id: [device; 8],
+                counter,
+            },
+            body: body.into(),
+        }
+    }
+
+    fn at(ms: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    fn stream(frames: &[(u64, FrameV1)]) -> Vec<(SystemTime, FrameV1)> {
+        frames.iter().map(|(ms, f)| (at(*ms), f.clone())).collect()
+    }
+
+    #[test]
+    fn same_streams_are_the_same() {
+        let frames: Vec<_> = (0..100).map(|c| (c * 10, frame(1, c, b"x"))).collect();
+        let report = diff(stream(&frames), stream(&frames), &DiffConfig::default());
+        assert!(report.differences.is_empty());
+        assert!(report.summary.same());
+        assert_eq!(report.summary.identical, 100);
+    }
+
+    #[test]
+    fn tolerates_reordering_and_retransmissions() {
+        let left: Vec<_> = (0..50).map(|c| (c, frame(1, c, b""))).collect();
+        let mut right = left.clone();
+        right.swap(3, 10);
+        right.reverse();
+        right.insert(20, right[5].clone());
+        right.push(right[0].clone());
+        let config = DiffConfig {
+            window: 64,
+            timing: None,
+        };
+        let report = diff(stream(&left), stream(&right), &config);
+        assert_eq!(report.differences, []);
+        assert_eq!(report.summary.right.duplicates, 2);
+        assert_eq!(report.summary.matched, 50);
+
+        // reordered further than the window, pairs are missed
+        let config = DiffConfig {
+            window: 4,
+            ..config
+        };
+        let report = diff(stream(&left), stream(&right), &config);
+        assert!(report.summary.left.only > 0 && report.summary.right.only > 0);
+        assert_eq!(report.summary.left.only + report.summary.matched, 50);
+    }
+
+    #[test]
+    fn reports_every_kind_of_difference() {
+        let mut flagged = frame(1, 3, b"aXc");
+        flagged.header.flags = Flags::new(Flags::ACK_REQUIRED).unwrap();
+        let left = stream(&[
+            (0, frame(1, 1, b"")),
+            (10, frame(1, 2, b"")),
+            (20, frame(1, 3, b"abcd")),
+            (30, frame(2, 1, b"")),
+        ]);
+        let right = stream(&[
+            (500, frame(1, 1, b"")),
+            (510, flagged),
+            (1600, frame(2, 1, b"")),
+            (2600, frame(2, 2, b"")),
+        ]);
+        let report = diff(left, right, &DiffConfig::default());
+        let lines: Vec<_> = report.differences.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "changed dev=0101010101010101 ctr=3: flags 0b00000000 -> 0b00000001, \
+                 body @1: 62 -> 58 @3: 64 -> (none)",
+                "timing dev=0202020202020202 ctr=1: left at 0.030s, right at 1.100s",
+                "only left: event dev=0101010101010101 ctr=2 body=0B",
+                "only right: event dev=0202020202020202 ctr=2 body=0B",
+            ]
+        );
+        let s = report.summary;
+        assert_eq!((s.matched, s.identical, s.changed, s.timing), (3, 2, 1, 1));
+        assert_eq!((s.left.only, s.right.only, s.left.frames), (1, 1, 4));
+        assert!(!s.same());
+    }
+
+    #[test]
+    fn byte_runs_cover_length_changes() {
+        assert_eq!(byte_runs(b"abc", b"abc"), []);
+        assert_eq!(
+            byte_runs(b"ab", b"aXYZ"),
+            [ByteRun {
+                offset: 1,
+                left: b"b".to_vec(),
+                right: b"XYZ".to_vec(),
+            }]
+        );
+    }
+}
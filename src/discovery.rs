@@ -0,0 +1,346 @@
+//! Finding out which devices are there.
+//!
+//! A [`Discovery`] sends a DISCOVER, `00 53 0c <round> <max delay>`, as a
+//! COMMAND to [`BROADCAST`], and records the ANNOUNCE each device answers
+//! with, `00 53 0d <round> <version> <capabilities>`, in a
+//! [`DeviceRegistry`]. Devices wait a delay spread over `max_delay` before
+//! answering, so replies do not all arrive at once; [`announce`] works it
+//! out from the device id and round.
+//!
+//! The application feeds every received frame to [`Discovery::on_frame`],
+//! which takes the announcements. A device announcing twice in a round is
+//! recorded once; one answering after [`Discovery::discover`] has given up
+//! on its round, or to an older round, is still recorded in the registry.
+//! [`Discovery::run`] solicits announcements periodically.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use crate::registry::{DeviceInfo, DeviceRegistry};
+use crate::sender::{CounterSource, FrameSender, SendError};
+use crate::session::CONTROL_PREFIX;
+use crate::transport::{RxFrame, Transport, TransportError};
+use crate::{FrameV1, MsgType};
+
+/// The device id a DISCOVER is addressed to.
+pub const BROADCAST: [u8; 8] = [0xff; 8];
+
+const DISCOVER: u8 = 0x0c;
+const ANNOUNCE: u8 = 0x0d;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoveryMessage {
+    Discover {
+        round: u32,
+        /// Answer within this many milliseconds.
+        max_delay_ms: u16,
+    },
+    Announce {
+        round: u32,
+        version: u8,
+        capabilities: Vec<u8>,
+    },
+}
+
+impl DiscoveryMessage {
+    pub fn parse(frame: &FrameV1) -> Option<DiscoveryMessage> {
+        if frame.header.msg_type != MsgType::Command {
+            return None;
+        }
+        let (&kind, rest) = frame.body.strip_prefix(&CONTROL_PREFIX)?.split_first()?;
+        let (round, rest) = rest.split_first_chunk::<4>()?;
+        let round = u32::from_be_bytes(*round);
+        match (kind, rest) {
+            (DISCOVER, [a, b]) => Some(DiscoveryMessage::Discover {
+                round,
+                max_delay_ms: u16::from_be_bytes([*a, *b]),
+            }),
+            (ANNOUNCE, [version, capabilities @ ..]) => Some(DiscoveryMessage::Announce {
+                round,
+                version: *version,
+                capabilities: capabilities.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The COMMAND body carrying this message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = CONTROL_PREFIX.to_vec();
+        match self {
+            DiscoveryMessage::Discover {
+                round,
+                max_delay_ms,
+            } => {
+                body.push(DISCOVER);
+                body.extend(round.to_be_bytes());
+                body.extend(max_delay_ms.to_be_bytes());
+            }
+            DiscoveryMessage::Announce {
+                round,
+                version,
+                capabilities,
+            } => {
+                body.push(ANNOUNCE);
+                body.extend(round.to_be_bytes());
+                body.push(*version);
+                body.extend(capabilities);
+            }
+        }
+        body
+    }
+}
+
+/// A device's answer to `request`, if it is a DISCOVER: how long to wait
+/// before sending it, and the ANNOUNCE body. The wait is the same for a
+/// device and round each time, and spread evenly over devices.
+pub fn announce(
+    request: &FrameV1,
+    device_id: [u8; 8],
+    version: u8,
+    capabilities: &[u8],
+) -> Option<(Duration, Vec<u8>)> {
+    let Some(DiscoveryMessage::Discover {
+        round,
+        max_delay_ms,
+    }) = DiscoveryMessage::parse(request)
+    else {
+        return None;
+    };
+    // FNV-1a
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in device_id.iter().chain(&round.to_be_bytes()) {
+        h = (h ^ *b as u64).wrapping_mul(0x100000001b3);
+    }
+    let delay = Duration::from_millis(h % (max_delay_ms as u64 + 1));
+    let body = DiscoveryMessage::Announce {
+        round,
+        version,
+        capabilities: capabilities.to_vec(),
+    }
+    .encode();
+    Some((delay, body))
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// Spread of the devices' answers; at most 65.535 s.
+    pub max_delay: Duration,
+    /// Rounds whose announcements are remembered for deduplication.
+    pub rounds_kept: usize,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            max_delay: Duration::from_secs(2),
+            rounds_kept: 8,
+        }
+    }
+}
+
+pub struct Discovery<T, C> {
+    sender: FrameSender<T, C>,
+    registry: DeviceRegistry,
+    config: DiscoveryConfig,
+    round: AtomicU32,
+    /// Who announced in each recent round.
+    heard: Mutex<BTreeMap<u32, BTreeSet<[u8; 8]>>>,
+}
+
+impl<T: Transport, C: CounterSource> Discovery<T, C> {
+    pub fn new(
+        sender: FrameSender<T, C>,
+        registry: DeviceRegistry,
+        config: DiscoveryConfig,
+    ) -> Self {
+        Discovery {
+            sender,
+            registry,
+            config,
+            round: AtomicU32::new(0),
+            heard: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Take the frame if it is an ANNOUNCE, recording its sender. Returns
+    /// whether it was one.
+    pub fn on_frame(&self, frame: &RxFrame) -> bool {
+        let Some(DiscoveryMessage::Announce {
+            round,
+            version,
+            capabilities,
+        }) = DiscoveryMessage::parse(frame)
+        else {
+            return false;
+        };
+        let device_id = frame.header.device_id;
+        let fresh = {
+            let mut heard = self.heard.lock().unwrap_or_else(|e| e.into_inner());
+            // rounds too old to be kept can no longer be deduplicated
+            let oldest = heard.keys().next().copied();
+            match heard.get_mut(&round) {
+                Some(devices) => devices.insert(device_id),
+                None => oldest.is_none_or(|o| round < o),
+            }
+        };
+        if fresh {
+            self.registry.observe(frame);
+            self.registry.set_version(device_id, version);
+            self.registry.set_capabilities(device_id, capabilities);
+        }
+        true
+    }
+
+    /// Send a DISCOVER and return its round.
+    pub async fn solicit(&self) -> Result<u32, SendError<TransportError>> {
+        let round = self.round.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        {
+            let mut heard = self.heard.lock().unwrap_or_else(|e| e.into_inner());
+            heard.insert(round, BTreeSet::new());
+            while heard.len() > self.config.rounds_kept.max(1) {
+                heard.pop_first();
+            }
+        }
+        let max_delay_ms = self.config.max_delay.as_millis().min(u16::MAX as u128) as u16;
+        let body = DiscoveryMessage::Discover {
+            round,
+            max_delay_ms,
+        }
+        .encode();
+        self.sender.send_command(BROADCAST, body).await?;
+        Ok(round)
+    }
+
+    /// Solicit announcements and wait `timeout` for them. Returns the
+    /// devices that answered, by id.
+    pub async fn discover(
+        &self,
+        timeout: Duration,
+    ) -> Result<Vec<DeviceInfo>, SendError<TransportError>> {
+        let round = self.solicit().await?;
+        tokio::time::sleep(timeout).await;
+        let devices = self
+            .heard
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&round)
+            .cloned()
+            .unwrap_or_default();
+        Ok(devices
+            .into_iter()
+            .filter_map(|d| self.registry.get(d))
+            .collect())
+    }
+
+    /// Solicit announcements every `interval` until a send fails.
+    pub async fn run(&self, interval: Duration) -> SendError<TransportError> {
+        loop {
+            if let Err(e) = self.solicit().await {
+                return e;
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    pub fn registry(&self) -> &DeviceRegistry {
+        &self.registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::RegistryConfig;
+    use crate::sender::CounterMap;
+    use crate::transport::loopback_pair;
+    use std::sync::Arc;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn announce_delays_are_stable_and_bounded() {
+        let mut request = crate::transport::tests::frame(1);
+        request.header.msg_type = MsgType::Command;
+        request.body = DiscoveryMessage::Discover {
+            round: 7,
+            max_delay_ms: 100,
+        }
+        .encode()
+        .into();
+        let delays: BTreeSet<_> = (0..50u8)
+            .map(|i| announce(&request, [i; 8], 1, &[]).unwrap().0)
+            .collect();
+        assert!(delays.iter().all(|d| *d <= 100 * MS));
+        assert!(delays.len() > 20, "spread out: {delays:?}");
+        let (delay, body) = announce(&request, [3; 8], 1, &[9]).unwrap();
+        assert_eq!(
+            announce(&request, [3; 8], 1, &[9]),
+            Some((delay, body.clone()))
+        );
+        request.body = body.into();
+        assert_eq!(
+            DiscoveryMessage::parse(&request),
+            Some(DiscoveryMessage::Announce {
+                round: 7,
+                version: 1,
+                capabilities: vec![9]
+            })
+        );
+        assert_eq!(announce(&request, [3; 8], 1, &[]), None, "not a DISCOVER");
+    }
+
+    #[tokio::test]
+    async fn discovers_simulated_devices() {
+        let (gateway_out, mut devices_in) = loopback_pair();
+        let (devices_out, mut gateway_in) = loopback_pair();
+        let registry = DeviceRegistry::new(RegistryConfig::default());
+        let discovery = Arc::new(Discovery::new(
+            FrameSender::new(gateway_out, Arc::new(CounterMap::default())),
+            registry.clone(),
+            DiscoveryConfig {
+                max_delay: 20 * MS,
+                ..DiscoveryConfig::default()
+            },
+        ));
+
+        // three devices; B announces twice, C only after discover is done
+        let to_gateway = FrameSender::new(devices_out, Arc::new(CounterMap::default()));
+        tokio::spawn(async move {
+            let request = devices_in.recv().await.unwrap();
+            for (id, extra, copies) in [
+                (*b"DEVICE-A", 0, 1),
+                (*b"DEVICE-B", 0, 2),
+                (*b"DEVICE-C", 100, 1),
+            ] {
+                let (delay, body) = announce(&request, id, 1, &[id[7]]).unwrap();
+                let to_gateway = to_gateway.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay + extra * MS).await;
+                    for _ in 0..copies {
+                        to_gateway.send_command(id, body.clone()).await.unwrap();
+                    }
+                });
+            }
+        });
+        let receiver = discovery.clone();
+        tokio::spawn(async move {
+            while let Ok(frame) = gateway_in.recv_rx().await {
+                assert!(receiver.on_frame(&frame));
+            }
+        });
+
+        let found = discovery.discover(60 * MS).await.unwrap();
+        let ids: Vec<_> = found.iter().map(|d| d.device_id).collect();
+        assert_eq!(ids, [*b"DEVICE-A", *b"DEVICE-B"]);
+        assert_eq!(found[1].frames, 1, "the duplicate is not recorded");
+        assert_eq!(found[1].capabilities, b"B");
+        assert_eq!(found[1].version, Some(1));
+
+        tokio::time::sleep(100 * MS).await;
+        assert_eq!(registry.len(), 3, "the late device is recorded anyway");
+        assert_eq!(registry.get(*b"DEVICE-C").unwrap().capabilities, b"C");
+    }
+}
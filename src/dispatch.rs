@@ -0,0 +1,1189 @@
+//! Routing received frames to handlers.
+//!
+//! A [`Dispatcher`] holds handlers registered per [`Route`]: a message
+//! type, optionally narrowed to device ids and to bodies starting with
+//! given bytes (a command id, say). The most specific matching route wins:
+//! an exact device over a device prefix (longer first) over any device,
+//! then the longer body prefix; among equals, the first registered. Frames
+//! no route matches go to the fallback handler, if there is one.
+//!
+//! Handlers are sync ([`Handler`]) or async ([`AsyncHandler`]); closures
+//! of the right shape are both. The dispatcher answers for them:
+//!
+//! - success: an ACK if the frame has ACK_REQUIRED, carrying the returned
+//!   bytes as [`AckBody::Reply`] if there are any;
+//! - [`HandlerError::Refused`]: a NACK if the frame has ACK_REQUIRED;
+//! - [`HandlerError::Failed`]: an ERROR frame for an ack-required frame or
+//!   a COMMAND;
+//! - a panic: counted, then as `Failed` with [`HANDLER_PANICKED`];
+//! - no handler: a NACK "Unhandled" if the frame has ACK_REQUIRED.
+//!
+//! v1 has no flag asking for silence, so a frame without ACK_REQUIRED is
+//! only ever answered when it is a COMMAND that failed. ACK and ERROR
+//! frames are never answered.
+//!
+//! Optional stages run first: a [`RateLimiter`], whose deferred frames
+//! come back through [`Dispatcher::dispatch_deferred`], then an
+//! `Authenticator` (feature `auth`), then a [`SharedDedupCache`], then an
+//! [`Authorizer`]. A frame from a device not yet authenticated from its
+//! source is answered with a CHALLENGE, numbered like any other frame the
+//! gateway sends the device, or refused with the reason, and goes no
+//! further. A COMMAND the authorizer denies is refused with
+//! [`UNAUTHORIZED`]. A frame counts as delivered
+//! once its handler succeeded; a duplicate of it is answered again, with
+//! the reply data its first delivery got if that is among the last
+//! [`REPLY_CACHE`], without reaching its handler. A duplicate arriving
+//! while the first copy is still being handled gets a plain ACK and does
+//! not reach the handler either. A repeat of a frame that was denied or
+//! failed goes through the stages and its handler again.
+//!
+//! Frames from [`UNASSIGNED`] skip the dedup stage: every device being
+//! assigned an id, or paired, sends from that id and starts its counter
+//! alike, so only the hardware id in the body tells them apart. Those
+//! exchanges are safe to repeat and resend on their own.
+//!
+//! A [`ReorderBuffer`] between the rate limiter and the rest hands each
+//! device's EVENTs and COMMANDs on in counter order, as long as frames are
+//! dispatched one at a time. A frame that waited is answered from
+//! [`Dispatcher::dispatch_deferred`], which also gives up on frames that
+//! never came; call it periodically when reordering too.
+//!
+//! A [`ReplyLimiter`] caps the ERROR and NACK replies per source; the
+//! summaries it sends once a source may be answered again come back from
+//! [`Dispatcher::dispatch_deferred`].
+//!
+//! EVENTs that get through are also published to an [`EventBus`], if
+//! there is one. An EVENT no route matches counts as handled when a
+//! subscription took it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+#[cfg(feature = "auth")]
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+
+use crate::ack::AckBody;
+use crate::acl::{self, Authorizer, Operation, UNAUTHORIZED};
+use crate::assign::UNASSIGNED;
+#[cfg(feature = "auth")]
+use crate::auth::{Authenticator, Check};
+use crate::bus::EventBus;
+use crate::dedup::{Delivery, SharedDedupCache};
+use crate::gaps::MissingRange;
+use crate::qos::{Qos, QosError};
+use crate::ratelimit::{Admission, RateLimiter, ReplyLimiter};
+use crate::reorder::{Release, ReorderBuffer};
+use crate::request::{ack_reply, error_reply};
+#[cfg(feature = "auth")]
+use crate::sender::{CounterSource, stamp};
+use crate::transport::{PeerAddr, RxFrame, TransportId};
+use crate::{FrameHeaderV1, FrameId, FrameV1, MsgType};
+
+/// Replies with data kept for answering duplicates again.
+pub const REPLY_CACHE: usize = 1024;
+
+/// Error code of the reply to a frame whose handler panicked.
+pub const HANDLER_PANICKED: u16 = 0xffff;
+
+/// What a handler returns: bytes to reply with, if any, or why it failed.
+pub type HandlerResult = Result<Option<Vec<u8>>, HandlerError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandlerError {
+    /// Answered with an ERROR frame.
+    Failed { code: u16, detail: Vec<u8> },
+    /// Answered with a NACK.
+    Refused(String),
+}
+
+impl HandlerError {
+    pub fn code(code: u16) -> Self {
+        HandlerError::Failed {
+            code,
+            detail: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::Failed { code, .. } => write!(f, "handler failed with {code:#06x}"),
+            HandlerError::Refused(reason) => write!(f, "handler refused: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+pub trait Handler: Send + Sync {
+    fn handle(&self, frame: &RxFrame) -> HandlerResult;
+}
+
+impl<F: Fn(&RxFrame) -> HandlerResult + Send + Sync> Handler for F {
+    fn handle(&self, frame: &RxFrame) -> HandlerResult {
+        self(frame)
+    }
+}
+
+pub trait AsyncHandler: Send + Sync {
+    fn handle(&self, frame: RxFrame) -> BoxFuture<'static, HandlerResult>;
+}
+
+impl<F, Fut> AsyncHandler for F
+where
+    F: Fn(RxFrame) -> Fut + Send + Sync,
+    Fut: Future<Output = HandlerResult> + Send + 'static,
+{
+    fn handle(&self, frame: RxFrame) -> BoxFuture<'static, HandlerResult> {
+        Box::pin(self(frame))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceMatch {
+    Any,
+    Exact([u8; 8]),
+    Prefix(Vec<u8>),
+}
+
+impl DeviceMatch {
+    pub(crate) fn matches(&self, device_id: &[u8; 8]) -> bool {
+        match self {
+            DeviceMatch::Any => true,
+            DeviceMatch::Exact(id) => id == device_id,
+            DeviceMatch::Prefix(p) => device_id.starts_with(p),
+        }
+    }
+}
+
+/// Which frames a handler is for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    msg_type: MsgType,
+    device: DeviceMatch,
+    body_prefix: Vec<u8>,
+}
+
+impl Route {
+    /// Every frame of `msg_type`.
+    pub fn new(msg_type: MsgType) -> Self {
+        Route {
+            msg_type,
+            device: DeviceMatch::Any,
+            body_prefix: Vec::new(),
+        }
+    }
+
+    pub fn with_device(mut self, device_id: [u8; 8]) -> Self {
+        self.device = DeviceMatch::Exact(device_id);
+        self
+    }
+
+    pub fn with_device_prefix(mut self, prefix: &[u8]) -> Self {
+        self.device = DeviceMatch::Prefix(prefix.to_vec());
+        self
+    }
+
+    pub fn with_body_prefix(mut self, prefix: &[u8]) -> Self {
+        self.body_prefix = prefix.to_vec();
+        self
+    }
+
+    fn matches(&self, frame: &FrameV1) -> bool {
+        self.msg_type == frame.header.msg_type
+            && self.device.matches(&frame.header.device_id)
+            && frame.body.starts_with(&self.body_prefix)
+    }
+
+    /// Higher is more specific.
+    fn specificity(&self) -> (u8, usize, usize) {
+        let device = match &self.device {
+            DeviceMatch::Any => (0, 0),
+            DeviceMatch::Prefix(p) => (1, p.len()),
+            DeviceMatch::Exact(_) => (2, 0),
+        };
+        (device.0, device.1, self.body_prefix.len())
+    }
+}
+
+enum Entry {
+    Sync(Box<dyn Handler>),
+    Async(Box<dyn AsyncHandler>),
+}
+
+/// Where and when a deferred frame arrived, for when it is released.
+struct Arrival {
+    received_at: SystemTime,
+    source: PeerAddr,
+    transport_id: TransportId,
+}
+
+/// The reply data of the latest delivered frames, by id, and the frames
+/// whose handlers are running.
+#[derive(Default)]
+struct Replies {
+    data: HashMap<FrameId, Vec<u8>>,
+    order: VecDeque<FrameId>,
+    in_flight: HashSet<FrameId>,
+}
+
+impl Replies {
+    fn insert(&mut self, id: FrameId, data: Vec<u8>) {
+        if self.order.len() >= REPLY_CACHE
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.data.remove(&oldest);
+        }
+        self.order.push_back(id);
+        self.data.insert(id, data);
+    }
+}
+
+/// A frame id held in [`Replies::in_flight`] until dropped, whether its
+/// handler returned, failed or panicked.
+struct Reservation<'a> {
+    replies: &'a Mutex<Replies>,
+    id: FrameId,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        lock(self.replies).in_flight.remove(&self.id);
+    }
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[derive(Default)]
+pub struct Dispatcher {
+    /// Most specific first.
+    routes: Vec<(Route, Entry)>,
+    fallback: Option<Entry>,
+    rate_limit: Option<Mutex<RateLimiter>>,
+    deferred: Mutex<HashMap<FrameId, Vec<Arrival>>>,
+    reorder: Option<(Mutex<ReorderBuffer>, OnGap)>,
+    reply_limit: Option<Mutex<ReplyLimiter>>,
+    /// Replies to frames the reorder buffer released later.
+    released: Mutex<Vec<FrameV1>>,
+    #[cfg(feature = "auth")]
+    authenticator: Option<(Mutex<Authenticator>, Arc<dyn CounterSource>)>,
+    dedup: Option<SharedDedupCache>,
+    replies: Mutex<Replies>,
+    authorizer: Option<(Box<dyn Authorizer>, PrincipalOf)>,
+    bus: Option<EventBus>,
+    panics: AtomicU64,
+}
+
+/// Names the principal an inbound frame comes from.
+type PrincipalOf = Box<dyn Fn(&RxFrame) -> String + Send + Sync>;
+
+/// Told the counters a reorder buffer gave up on.
+type OnGap = Box<dyn Fn(&MissingRange) + Send + Sync>;
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(mut self, route: Route, entry: Entry) -> Self {
+        let s = route.specificity();
+        let at = self
+            .routes
+            .iter()
+            .position(|(r, _)| r.specificity() < s)
+            .unwrap_or(self.routes.len());
+        self.routes.insert(at, (route, entry));
+        self
+    }
+
+    pub fn route(self, route: Route, handler: impl Handler + 'static) -> Self {
+        self.add(route, Entry::Sync(Box::new(handler)))
+    }
+
+    pub fn route_async(self, route: Route, handler: impl AsyncHandler + 'static) -> Self {
+        self.add(route, Entry::Async(Box::new(handler)))
+    }
+
+    /// Handles frames no route matches.
+    pub fn fallback(mut self, handler: impl Handler + 'static) -> Self {
+        self.fallback = Some(Entry::Sync(Box::new(handler)));
+        self
+    }
+
+    pub fn fallback_async(mut self, handler: impl AsyncHandler + 'static) -> Self {
+        self.fallback = Some(Entry::Async(Box::new(handler)));
+        self
+    }
+
+    pub fn with_rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limit = Some(Mutex::new(limiter));
+        self
+    }
+
+    /// Hand frames on in counter order through `buffer`, telling `on_gap`
+    /// the counters it stops waiting for.
+    pub fn with_reorder(
+        mut self,
+        buffer: ReorderBuffer,
+        on_gap: impl Fn(&MissingRange) + Send + Sync + 'static,
+    ) -> Self {
+        self.reorder = Some((Mutex::new(buffer), Box::new(on_gap)));
+        self
+    }
+
+    /// Hold back ERROR and NACK replies beyond what `limiter` allows.
+    pub fn with_reply_limit(mut self, limiter: ReplyLimiter) -> Self {
+        self.reply_limit = Some(Mutex::new(limiter));
+        self
+    }
+
+    /// Let frames through only from devices `authenticator` has
+    /// authenticated from the frame's source. Challenges are numbered
+    /// from `counters`, which should be what numbers the other frames to
+    /// those devices.
+    #[cfg(feature = "auth")]
+    pub fn with_authenticator(
+        mut self,
+        authenticator: Authenticator,
+        counters: Arc<dyn CounterSource>,
+    ) -> Self {
+        self.authenticator = Some((Mutex::new(authenticator), counters));
+        self
+    }
+
+    pub fn with_dedup(mut self, cache: SharedDedupCache) -> Self {
+        self.dedup = Some(cache);
+        self
+    }
+
+    /// Hand COMMANDs to their handlers only if `authorizer` allows the
+    /// principal `principal_of` names, e.g. from the frame's source.
+    pub fn with_authorizer(
+        mut self,
+        authorizer: impl Authorizer + 'static,
+        principal_of: impl Fn(&RxFrame) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.authorizer = Some((Box::new(authorizer), Box::new(principal_of)));
+        self
+    }
+
+    /// Publish admitted EVENTs to `bus`.
+    pub fn with_bus(mut self, bus: EventBus) -> Self {
+        self.bus = Some(bus);
+        self
+    }
+
+    /// Run `frame` through the stages and its handler. Returns the reply
+    /// to send back, if any.
+    pub async fn dispatch(&self, frame: RxFrame) -> Option<FrameV1> {
+        #[cfg(feature = "tracing")]
+        let span = crate::trace::frame_span!("dispatch", &frame.header);
+        let fut = self.admit(frame);
+        #[cfg(feature = "tracing")]
+        let fut = tracing::Instrument::instrument(fut, span);
+        fut.await
+    }
+
+    async fn admit(&self, frame: RxFrame) -> Option<FrameV1> {
+        let Some(limiter) = &self.rate_limit else {
+            return self.order(frame).await;
+        };
+        let RxFrame {
+            frame,
+            received_at,
+            source,
+            transport_id,
+        } = frame;
+        let id = frame.header.id();
+        let admission = lock(limiter).check(frame);
+        match admission {
+            Admission::Allow(frame) => {
+                let frame = RxFrame {
+                    frame,
+                    received_at,
+                    source,
+                    transport_id,
+                };
+                self.order(frame).await
+            }
+            Admission::Dropped => None,
+            Admission::Deferred => {
+                lock(&self.deferred).entry(id).or_default().push(Arrival {
+                    received_at,
+                    source,
+                    transport_id,
+                });
+                None
+            }
+            Admission::Nack(nack) => self.limit(&source, Some(nack)),
+        }
+    }
+
+    /// Dispatch the deferred frames the rate limiter lets through now and
+    /// the frames the reorder buffer stops holding back. Returns their
+    /// replies; call it periodically when deferring or reordering.
+    pub async fn dispatch_deferred(&self) -> Vec<FrameV1> {
+        let mut replies = self.limited().await;
+        if let Some((buffer, _)) = &self.reorder {
+            let release = lock(buffer).poll();
+            replies.extend(self.release(release, None).await);
+        }
+        replies.append(&mut lock(&self.released));
+        if let Some(limiter) = &self.reply_limit {
+            replies.extend(lock(limiter).summaries());
+        }
+        replies
+    }
+
+    fn limit(&self, source: &PeerAddr, reply: Option<FrameV1>) -> Option<FrameV1> {
+        match &self.reply_limit {
+            Some(limiter) => lock(limiter).check(source, reply?),
+            None => reply,
+        }
+    }
+
+    async fn limited(&self) -> Vec<FrameV1> {
+        let Some(limiter) = &self.rate_limit else {
+            return Vec::new();
+        };
+        let ready = lock(limiter).poll_deferred();
+        let mut replies = Vec::new();
+        for frame in ready {
+            let arrival = {
+                let mut deferred = lock(&self.deferred);
+                let id = frame.header.id();
+                let arrivals = deferred.entry(id).or_default();
+                let arrival = (!arrivals.is_empty()).then(|| arrivals.remove(0));
+                if arrivals.is_empty() {
+                    deferred.remove(&id);
+                }
+                arrival
+            };
+            let Some(a) = arrival else { continue };
+            let frame = RxFrame {
+                frame,
+                received_at: a.received_at,
+                source: a.source,
+                transport_id: a.transport_id,
+            };
+            replies.extend(self.order(frame).await);
+        }
+        replies
+    }
+
+    async fn order(&self, frame: RxFrame) -> Option<FrameV1> {
+        let Some((buffer, _)) = &self.reorder else {
+            return self.deliver(frame).await;
+        };
+        let id = frame.header.id();
+        let release = lock(buffer).push(frame);
+        let mut replies = self.release(release, Some(id)).await;
+        replies.pop()
+    }
+
+    /// Deliver `release` in order. The reply to the frame `trigger` names
+    /// comes back alone, if it was released; any others come back unless
+    /// there is a trigger, when they wait for `dispatch_deferred`.
+    async fn release(&self, release: Release, trigger: Option<FrameId>) -> Vec<FrameV1> {
+        let Some((_, on_gap)) = &self.reorder else {
+            return Vec::new();
+        };
+        release.gaps.iter().for_each(on_gap);
+        let mut own = Vec::new();
+        let mut others = Vec::new();
+        for frame in release.frames {
+            let triggered = Some(frame.header.id()) == trigger;
+            let reply = self.deliver(frame).await;
+            if triggered {
+                own.extend(reply);
+            } else {
+                others.extend(reply);
+            }
+        }
+        if trigger.is_none() {
+            return others;
+        }
+        lock(&self.released).append(&mut others);
+        own
+    }
+
+    async fn deliver(&self, frame: RxFrame) -> Option<FrameV1> {
+        let source = frame.source.clone();
+        let reply = self.answer(frame).await;
+        self.limit(&source, reply)
+    }
+
+    async fn answer(&self, frame: RxFrame) -> Option<FrameV1> {
+        let header = frame.header.clone();
+        let id = header.id();
+        #[cfg(feature = "auth")]
+        if let Some((authenticator, counters)) = &self.authenticator {
+            let check = lock(authenticator).check(&frame);
+            match check {
+                Check::Pass => {}
+                Check::Challenge(c) => {
+                    // with no counter to send it under, the challenge is
+                    // left unsent, as is any frame whose counter fails
+                    let counter = counters.next(c.device_id).ok()?;
+                    return Some(stamp(c.msg_type, c.flags, c.device_id, counter, c.body));
+                }
+                Check::Authenticated => return reply(&header, Ok(None)),
+                Check::Rejected(e) => {
+                    return reply(&header, Err(HandlerError::Refused(e.to_string())));
+                }
+            }
+        }
+        let dedup = self
+            .dedup
+            .as_ref()
+            .filter(|_| header.device_id != UNASSIGNED);
+        let _reservation = match dedup {
+            Some(dedup) => {
+                let mut replies = lock(&self.replies);
+                if dedup.contains(id) {
+                    let data = replies.data.get(&id).cloned();
+                    return reply(&header, Ok(data));
+                }
+                if !replies.in_flight.insert(id) {
+                    return reply(&header, Ok(None));
+                }
+                Some(Reservation {
+                    replies: &self.replies,
+                    id,
+                })
+            }
+            None => None,
+        };
+        if let Some((authorizer, principal_of)) = &self.authorizer
+            && header.msg_type == MsgType::Command
+        {
+            let principal = principal_of(&frame);
+            if acl::check(authorizer.as_ref(), &Operation::of(&principal, &frame)).is_err() {
+                return reply(&header, Err(HandlerError::Refused(UNAUTHORIZED.into())));
+            }
+        }
+        let published = match &self.bus {
+            Some(bus) if header.msg_type == MsgType::Event => bus.publish(&frame),
+            _ => 0,
+        };
+        let entry = self
+            .routes
+            .iter()
+            .find(|(r, _)| r.matches(&frame))
+            .map(|(_, e)| e)
+            .or(self.fallback.as_ref());
+        let outcome = match entry {
+            None if published > 0 => Ok(Ok(None)),
+            None => Ok(Err(HandlerError::Refused("Unhandled".into()))),
+            Some(Entry::Sync(h)) => catch_unwind(AssertUnwindSafe(|| h.handle(&frame))),
+            Some(Entry::Async(h)) => {
+                AssertUnwindSafe(async move { h.handle(frame).await })
+                    .catch_unwind()
+                    .await
+            }
+        };
+        let result = outcome.unwrap_or_else(|_| {
+            self.panics.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            crate::metrics::global().handler_panicked();
+            Err(HandlerError::Failed {
+                code: HANDLER_PANICKED,
+                detail: b"handler panicked".to_vec(),
+            })
+        });
+        if let (Some(dedup), Ok(data)) = (dedup, &result) {
+            let mut replies = lock(&self.replies);
+            if dedup.check_and_record(id) == Delivery::FirstDelivery
+                && let Some(data) = data
+            {
+                replies.insert(id, data.clone());
+            }
+        }
+        reply(&header, result)
+    }
+
+    /// Whether frames sent with `qos` get its guarantee here.
+    pub fn check_qos(&self, qos: Qos) -> Result<(), QosError> {
+        if qos.needs_dedup() && self.dedup.is_none() {
+            return Err(QosError::NoDedup);
+        }
+        Ok(())
+    }
+
+    /// Handlers that have panicked so far.
+    pub fn panics(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+}
+
+/// The automatic answer to a frame with `header`, as the module docs set out.
+fn reply(header: &FrameHeaderV1, result: HandlerResult) -> Option<FrameV1> {
+    if matches!(header.msg_type, MsgType::Ack | MsgType::Error) {
+        return None;
+    }
+    let ack_required = header.flags.ack_required();
+    match result {
+        Ok(None) if ack_required => Some(ack_reply(header, AckBody::Single)),
+        Ok(Some(data)) if ack_required => Some(ack_reply(header, AckBody::Reply(data))),
+        Err(HandlerError::Refused(reason)) if ack_required => {
+            Some(ack_reply(header, AckBody::Nack { reason }))
+        }
+        Err(HandlerError::Failed { code, detail })
+            if ack_required || header.msg_type == MsgType::Command =>
+        {
+            Some(error_reply(header, code, &detail))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Flags;
+    use crate::clock::ManualClock;
+    use crate::dedup::{DedupCache, DedupConfig};
+    use crate::ratelimit::{OverLimit, RateLimitConfig};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const DEV: [u8; 8] = *b"DEV00001";
+
+    fn rx(msg_type: MsgType, device_id: [u8; 8], body: &[u8], ack: bool) -> RxFrame {
+        let mut f = crate::transport::tests::frame(7);
+        f.header.msg_type = msg_type;
+        f.header.device_id = device_id;
+        f.header.flags = Flags::new(if ack { Flags::ACK_REQUIRED } else { 0 }).unwrap();
+        f.body = body.into();
+        RxFrame::new(f, PeerAddr::Opaque("test".into()), TransportId::UNASSIGNED)
+    }
+
+    /// A handler answering with `tag`.
+    fn tagged(tag: u8) -> impl Handler {
+        move |_: &RxFrame| Ok(Some(vec![tag]))
+    }
+
+    fn answered_by(reply: Option<FrameV1>) -> Option<u8> {
+        match AckBody::decode(&reply?.body) {
+            Ok(AckBody::Reply(data)) => data.first().copied(),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn most_specific_route_wins() {
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Command), tagged(1))
+            .route(
+                Route::new(MsgType::Command).with_device_prefix(b"DEV"),
+                tagged(2),
+            )
+            .route(Route::new(MsgType::Command).with_device(DEV), tagged(3))
+            .route(
+                Route::new(MsgType::Command)
+                    .with_device(DEV)
+                    .with_body_prefix(&[0x10]),
+                tagged(4),
+            )
+            .route(Route::new(MsgType::Command).with_device(DEV), tagged(5))
+            .fallback(tagged(9));
+
+        let cases: [(&[u8; 8], &[u8], MsgType, u8); 5] = [
+            (&DEV, &[0x10, 0], MsgType::Command, 4),
+            (&DEV, &[0x11], MsgType::Command, 3),
+            (b"DEV00002", &[0x10], MsgType::Command, 2),
+            (b"XYZ00001", &[], MsgType::Command, 1),
+            (&DEV, &[0x10], MsgType::Event, 9),
+        ];
+        for (device, body, msg_type, tag) in cases {
+            let reply = d.dispatch(rx(msg_type, *device, body, true)).await;
+            assert_eq!(answered_by(reply), Some(tag), "{device:?} {body:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn automatic_replies() {
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Event), |_: &RxFrame| Ok(None))
+            .route_async(Route::new(MsgType::Command), |f: RxFrame| async move {
+                match f.body.first() {
+                    Some(1) => Err(HandlerError::Refused("door open".into())),
+                    Some(2) => Err(HandlerError::code(0x0102)),
+                    _ => Ok(None),
+                }
+            });
+
+        let ack = d
+            .dispatch(rx(MsgType::Event, DEV, &[], true))
+            .await
+            .unwrap();
+        assert_eq!(ack.header.msg_type, MsgType::Ack);
+        assert_eq!(ack.header.counter, 7);
+        assert_eq!(AckBody::decode(&ack.body), Ok(AckBody::Single));
+        assert_eq!(d.dispatch(rx(MsgType::Event, DEV, &[], false)).await, None);
+
+        let nack = d.dispatch(rx(MsgType::Command, DEV, &[1], true)).await;
+        assert_eq!(
+            AckBody::decode(&nack.unwrap().body),
+            Ok(AckBody::Nack {
+                reason: "door open".into()
+            })
+        );
+        assert_eq!(
+            d.dispatch(rx(MsgType::Command, DEV, &[1], false)).await,
+            None
+        );
+
+        // a failed command is answered even without ACK_REQUIRED
+        let error = d.dispatch(rx(MsgType::Command, DEV, &[2], false)).await;
+        let error = error.unwrap();
+        assert_eq!(error.header.msg_type, MsgType::Error);
+        assert_eq!(error.body[..2], [0x01, 0x02]);
+
+        // nobody handles ACKs, and nobody answers them either
+        assert_eq!(d.dispatch(rx(MsgType::Ack, DEV, &[], true)).await, None);
+        let unhandled = d.dispatch(rx(MsgType::Error, DEV, &[], true)).await;
+        assert_eq!(unhandled, None);
+    }
+
+    #[tokio::test]
+    async fn unmatched_frames_are_refused() {
+        let d = Dispatcher::new();
+        let nack = d.dispatch(rx(MsgType::Event, DEV, &[], true)).await;
+        assert_eq!(
+            AckBody::decode(&nack.unwrap().body),
+            Ok(AckBody::Nack {
+                reason: "Unhandled".into()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn panics_are_isolated_and_counted() {
+        let d = Dispatcher::new()
+            .route(
+                Route::new(MsgType::Command),
+                |_: &RxFrame| -> HandlerResult { panic!("sync handler") },
+            )
+            .route_async(Route::new(MsgType::Event), |_: RxFrame| async {
+                panic!("async handler")
+            });
+        for msg_type in [MsgType::Command, MsgType::Event] {
+            let reply = d.dispatch(rx(msg_type, DEV, &[], true)).await.unwrap();
+            assert_eq!(reply.header.msg_type, MsgType::Error);
+            assert_eq!(reply.body[..2], HANDLER_PANICKED.to_be_bytes());
+        }
+        assert_eq!(d.panics(), 2);
+    }
+
+    #[tokio::test]
+    async fn duplicates_are_acked_but_not_handled() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Event), {
+                let calls = calls.clone();
+                move |_: &RxFrame| {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    Ok(None)
+                }
+            })
+            .with_dedup(SharedDedupCache::new(DedupCache::new(
+                DedupConfig::default(),
+            )));
+        for _ in 0..3 {
+            let ack = d.dispatch(rx(MsgType::Event, DEV, &[], true)).await;
+            assert_eq!(ack.unwrap().header.msg_type, MsgType::Ack);
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    fn dedup() -> SharedDedupCache {
+        SharedDedupCache::new(DedupCache::new(DedupConfig::default()))
+    }
+
+    #[tokio::test]
+    async fn repeats_of_denied_commands_are_denied_again() {
+        use crate::acl::AclTable;
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Command), tagged(1))
+            .with_dedup(dedup())
+            .with_authorizer(AclTable::new(), |_: &RxFrame| "gw-1".to_string());
+        for _ in 0..2 {
+            let nack = d.dispatch(rx(MsgType::Command, DEV, &[1], true)).await;
+            assert_eq!(
+                AckBody::decode(&nack.unwrap().body),
+                Ok(AckBody::Nack {
+                    reason: UNAUTHORIZED.into()
+                })
+            );
+        }
+    }
+
+    #[cfg(feature = "auth")]
+    #[tokio::test]
+    async fn frames_wait_for_their_source_to_authenticate() {
+        use crate::auth::{AuthConfig, Authenticator, response};
+        use crate::sender::CounterMap;
+        let counters = Arc::new(CounterMap::default());
+        let keys: HashMap<[u8; 8], Vec<u8>> = [(DEV, b"key".to_vec())].into();
+        let calls = Arc::new(AtomicU64::new(0));
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Command), {
+                let calls = calls.clone();
+                move |_: &RxFrame| -> HandlerResult {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    Ok(None)
+                }
+            })
+            .with_authenticator(
+                Authenticator::new(Arc::new(keys), AuthConfig::default()),
+                counters.clone(),
+            )
+            .with_dedup(dedup());
+        let command = || rx(MsgType::Command, DEV, &[1], true);
+
+        // the gateway has already sent the device frames 1 to 3
+        for _ in 0..3 {
+            counters.next(DEV).unwrap();
+        }
+        let challenge = d.dispatch(command()).await.unwrap();
+        assert_eq!(challenge.header.msg_type, MsgType::Command);
+        assert_eq!(challenge.header.counter, 4);
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+        let answer = response(b"key", &challenge).unwrap();
+        let ack = d.dispatch(rx(MsgType::Command, DEV, &answer, true)).await;
+        assert_eq!(AckBody::decode(&ack.unwrap().body), Ok(AckBody::Single));
+
+        let ack = d.dispatch(command()).await.unwrap();
+        assert_eq!(AckBody::decode(&ack.body), Ok(AckBody::Single));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // the same device id from elsewhere is challenged, not answered
+        let mut spoofed = command();
+        spoofed.source = PeerAddr::Opaque("spoofer".into());
+        let challenge = d.dispatch(spoofed).await.unwrap();
+        assert_eq!(challenge.header.msg_type, MsgType::Command);
+        assert_eq!(challenge.header.counter, 5);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn repeats_of_failed_frames_are_handled_again() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Command), {
+                let calls = calls.clone();
+                move |_: &RxFrame| -> HandlerResult {
+                    match calls.fetch_add(1, Ordering::Relaxed) {
+                        0 => Err(HandlerError::code(0x0102)),
+                        1 => panic!("flaky handler"),
+                        _ => Ok(None),
+                    }
+                }
+            })
+            .with_dedup(dedup());
+        let send = || d.dispatch(rx(MsgType::Command, DEV, &[], true));
+        assert_eq!(send().await.unwrap().header.msg_type, MsgType::Error);
+        let panicked = send().await.unwrap();
+        assert_eq!(panicked.body[..2], HANDLER_PANICKED.to_be_bytes());
+        let ack = send().await.unwrap();
+        assert_eq!(AckBody::decode(&ack.body), Ok(AckBody::Single));
+        // delivered now: acked without the handler
+        let ack = send().await.unwrap();
+        assert_eq!(AckBody::decode(&ack.body), Ok(AckBody::Single));
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn duplicates_of_frames_being_handled_are_not_handled_again() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let d = Dispatcher::new()
+            .route_async(Route::new(MsgType::Command), {
+                let calls = calls.clone();
+                move |_: RxFrame| {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    async {
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        Ok(Some(vec![0x40]))
+                    }
+                }
+            })
+            .with_dedup(dedup());
+        let send = || d.dispatch(rx(MsgType::Command, DEV, &[], true));
+        let (first, second) = tokio::join!(send(), send());
+        assert_eq!(answered_by(first), Some(0x40));
+        let second = second.unwrap();
+        assert_eq!(AckBody::decode(&second.body), Ok(AckBody::Single));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // once handled, repeats get the reply it got
+        assert_eq!(answered_by(send().await), Some(0x40));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn unassigned_devices_are_not_taken_for_duplicates() {
+        use crate::assign::{
+            AssignConfig, AssignMessage, FileIdStore, GatewayAssignment, GatewayStep, IdAllocator,
+            IdPool,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(FileIdStore::open(dir.path().join("ids")).unwrap());
+        let allocator = IdAllocator::new(IdPool::new(100..=199), store).unwrap();
+        let gw = GatewayAssignment::new(allocator, AssignConfig::default());
+        let d = Dispatcher::new()
+            .route(
+                Route::new(MsgType::Command).with_device(UNASSIGNED),
+                move |f: &RxFrame| {
+                    let step = AssignMessage::parse(f).map(|m| gw.on_message(&m).unwrap());
+                    match step {
+                        Some(GatewayStep::Reply(m)) => Ok(Some(m.encode())),
+                        _ => Err(HandlerError::Refused("not an id request".into())),
+                    }
+                },
+            )
+            .with_dedup(dedup());
+        let d = &d;
+        let assigned = |hardware_id| {
+            let body = AssignMessage::Request { hardware_id }.encode();
+            async move {
+                let ack = d
+                    .dispatch(rx(MsgType::Command, UNASSIGNED, &body, true))
+                    .await;
+                match AckBody::decode(&ack.unwrap().body) {
+                    Ok(AckBody::Reply(data)) => data,
+                    other => panic!("expected an assignment, got {other:?}"),
+                }
+            }
+        };
+        let to_a = assigned(*b"hardware-unit-A!").await;
+        let to_b = assigned(*b"hardware-unit-B!").await;
+        assert_eq!(to_a[3..19], *b"hardware-unit-A!");
+        assert_eq!(to_b[3..19], *b"hardware-unit-B!");
+        assert_eq!(to_a[19..], 100u64.to_be_bytes());
+        assert_eq!(to_b[19..], 101u64.to_be_bytes());
+        // a resent request is answered again, with the same id
+        assert_eq!(assigned(*b"hardware-unit-A!").await, to_a);
+    }
+
+    #[tokio::test]
+    async fn repeats_of_requests_get_the_same_reply() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Command), {
+                let calls = calls.clone();
+                move |_: &RxFrame| {
+                    let n = calls.fetch_add(1, Ordering::Relaxed);
+                    Ok(Some(vec![0x40 + n as u8]))
+                }
+            })
+            .with_dedup(dedup());
+        for _ in 0..3 {
+            let reply = d.dispatch(rx(MsgType::Command, DEV, &[], true)).await;
+            assert_eq!(answered_by(reply), Some(0x40));
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn unauthorized_commands_are_nacked() {
+        use crate::acl::{AclTable, Rule};
+        let acl = AclTable::new().allow("gw-1", Rule::new(MsgType::Command).with_body_prefix(&[1]));
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Command), tagged(1))
+            .route(Route::new(MsgType::Event), tagged(2))
+            .with_authorizer(acl, |f: &RxFrame| match &f.source {
+                PeerAddr::Opaque(name) if name == "test" => "gw-1".to_string(),
+                _ => "unknown".to_string(),
+            });
+        assert_eq!(
+            answered_by(d.dispatch(rx(MsgType::Command, DEV, &[1], true)).await),
+            Some(1)
+        );
+        let nack = d
+            .dispatch(rx(MsgType::Command, DEV, &[2], true))
+            .await
+            .unwrap();
+        assert_eq!(
+            AckBody::decode(&nack.body),
+            Ok(AckBody::Nack {
+                reason: UNAUTHORIZED.into()
+            })
+        );
+        assert_eq!(
+            d.dispatch(rx(MsgType::Command, DEV, &[2], false)).await,
+            None
+        );
+        // events are not commands
+        assert_eq!(
+            answered_by(d.dispatch(rx(MsgType::Event, DEV, &[2], true)).await),
+            Some(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn events_reach_subscribers_and_handlers() {
+        use crate::bus::Filter;
+        let bus = EventBus::new(4);
+        let mut sub = bus.subscribe(Filter::new(MsgType::Event).with_body_prefix(&[1]));
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Event).with_body_prefix(&[1]), tagged(1))
+            .with_bus(bus.clone());
+        assert_eq!(
+            answered_by(d.dispatch(rx(MsgType::Event, DEV, &[1], true)).await),
+            Some(1)
+        );
+        assert_eq!(sub.try_next().unwrap().unwrap().body, [1]);
+
+        // a subscription alone is enough to handle an event
+        let _sub2 = bus.subscribe(Filter::new(MsgType::Event).with_body_prefix(&[2]));
+        let ack = d
+            .dispatch(rx(MsgType::Event, DEV, &[2], true))
+            .await
+            .unwrap();
+        assert_eq!(AckBody::decode(&ack.body), Ok(AckBody::Single));
+        let nack = d
+            .dispatch(rx(MsgType::Event, DEV, &[3], true))
+            .await
+            .unwrap();
+        assert!(matches!(
+            AckBody::decode(&nack.body),
+            Ok(AckBody::Nack { .. })
+        ));
+        assert!(sub.try_next().is_none());
+    }
+
+    #[tokio::test]
+    async fn deferred_frames_keep_their_arrival() {
+        let clock = ManualClock::new();
+        let limiter = RateLimiter::with_clock(
+            RateLimitConfig {
+                rate: 10,
+                burst: 1,
+                over_limit: OverLimit::Defer,
+                ..RateLimitConfig::default()
+            },
+            Arc::new(clock.clone()),
+        );
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Event), |f: &RxFrame| {
+                Ok(Some(f.source.to_string().into_bytes()))
+            })
+            .with_rate_limit(limiter);
+        assert!(
+            d.dispatch(rx(MsgType::Event, DEV, &[], true))
+                .await
+                .is_some()
+        );
+        let mut late = rx(MsgType::Event, DEV, &[], true);
+        late.source = PeerAddr::Opaque("late".into());
+        assert_eq!(d.dispatch(late).await, None);
+        assert_eq!(d.dispatch_deferred().await, []);
+
+        clock.advance(Duration::from_millis(100));
+        let replies = d.dispatch_deferred().await;
+        assert_eq!(replies.len(), 1);
+        assert_eq!(
+            AckBody::decode(&replies[0].body),
+            Ok(AckBody::Reply(b"late".to_vec()))
+        );
+    }
+
+    #[tokio::test]
+    async fn reordered_frames_reach_handlers_in_order() {
+        use crate::reorder::ReorderConfig;
+        let clock = ManualClock::new();
+        let buffer = ReorderBuffer::with_clock(
+            ReorderConfig {
+                timeout: Duration::from_millis(100),
+                ..ReorderConfig::default()
+            },
+            Arc::new(clock.clone()),
+        );
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let gaps = Arc::new(Mutex::new(Vec::new()));
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Event), {
+                let seen = seen.clone();
+                move |f: &RxFrame| {
+                    lock(&seen).push(f.header.counter);
+                    Ok(None)
+                }
+            })
+            .with_dedup(SharedDedupCache::new(DedupCache::new(
+                DedupConfig::default(),
+            )))
+            .with_reorder(buffer, {
+                let gaps = gaps.clone();
+                move |g: &MissingRange| lock(&gaps).push((g.from, g.to))
+            });
+        let event = |counter| {
+            let mut f = rx(MsgType::Event, DEV, &[], true);
+            f.frame.header.counter = counter;
+            f
+        };
+        assert!(d.dispatch(event(1)).await.is_some());
+        assert_eq!(d.dispatch(event(3)).await, None);
+        assert_eq!(d.dispatch(event(3)).await, None, "waiting already");
+        let ack = d.dispatch(event(2)).await.unwrap();
+        assert_eq!(ack.header.counter, 2);
+        let acks = d.dispatch_deferred().await;
+        assert_eq!(
+            acks.iter().map(|a| a.header.counter).collect::<Vec<_>>(),
+            [3]
+        );
+        // delivered already: acked again, not handled again
+        assert!(d.dispatch(event(2)).await.is_some());
+
+        assert_eq!(d.dispatch(event(5)).await, None);
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(d.dispatch_deferred().await.len(), 1);
+        assert_eq!(*lock(&seen), [1, 2, 3, 5]);
+        assert_eq!(*lock(&gaps), [(4, 4)]);
+    }
+
+    #[tokio::test]
+    async fn error_replies_are_capped_but_acks_are_not() {
+        use crate::ratelimit::{LimitBy, ReplyLimitConfig};
+        let clock = ManualClock::new();
+        let limiter = ReplyLimiter::with_clock(
+            ReplyLimitConfig {
+                rate: 1,
+                burst: 1,
+                by: LimitBy::Peer,
+                ..ReplyLimitConfig::default()
+            },
+            Arc::new(clock.clone()),
+        );
+        let d = Dispatcher::new()
+            .route(Route::new(MsgType::Event), |_: &RxFrame| Ok(None))
+            .with_reply_limit(limiter);
+        let bad = || rx(MsgType::Command, DEV, &[], true);
+        assert!(d.dispatch(bad()).await.is_some());
+        for _ in 0..3 {
+            assert_eq!(d.dispatch(bad()).await, None);
+            assert!(
+                d.dispatch(rx(MsgType::Event, DEV, &[], true))
+                    .await
+                    .is_some()
+            );
+        }
+        // the same device over another link is another source
+        let mut elsewhere = bad();
+        elsewhere.source = PeerAddr::Opaque("other".into());
+        assert!(d.dispatch(elsewhere).await.is_some());
+        clock.advance(Duration::from_secs(1));
+        assert!(d.dispatch(bad()).await.is_some());
+        assert_eq!(d.dispatch_deferred().await, [], "no summaries asked for");
+    }
+}
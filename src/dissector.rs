@@ -0,0 +1,268 @@
+//! A Wireshark dissector, generated from this crate's definitions.
+//!
+//! [`lua`] writes a Lua plugin that decodes frames on a UDP port: the
+//! header fields as [`annotate`](crate::annotate) lays them out, message
+//! type names from [`MsgType`], the flag bits, and packed datagrams
+//! ([`PACK_MAGIC`]). Frames are handed to a decoder by version byte, so an
+//! unknown version is flagged rather than misread. The port is also a
+//! preference in Wireshark. The plugin committed at
+//! `docs/pipproto-dissector.lua` is [`lua`] with [`DEFAULT_PORT`].
+
+use std::fmt::Write as _;
+
+use crate::annotate::FIELDS;
+use crate::datagram::PACK_MAGIC;
+use crate::{Flags, HEADER_LEN_V1, MAGIC, MsgType, VERSION_V1};
+
+/// The port the committed plugin registers on.
+pub const DEFAULT_PORT: u16 = 4000;
+
+/// The `ProtoField` constructor for a header field.
+fn proto_field(name: &str) -> &'static str {
+    match name {
+        "magic" => "ProtoField.string(\"pipproto.magic\", \"Magic\")",
+        "version" => "ProtoField.uint8(\"pipproto.version\", \"Version\", base.DEC)",
+        "type" => "ProtoField.uint8(\"pipproto.type\", \"Type\", base.HEX, msg_types)",
+        "flags" => "ProtoField.uint8(\"pipproto.flags\", \"Flags\", base.HEX)",
+        "device_id" => "ProtoField.bytes(\"pipproto.device_id\", \"Device ID\")",
+        "counter" => "ProtoField.uint64(\"pipproto.counter\", \"Counter\", base.DEC)",
+        _ => unreachable!("no Wireshark field for header field {name:?}"),
+    }
+}
+
+fn lua_string(bytes: &[u8]) -> String {
+    format!("\"{}\"", String::from_utf8_lossy(bytes))
+}
+
+/// The plugin, registering on UDP `port` by default.
+pub fn lua(port: u16) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "-- PiProto dissector for Wireshark, generated by pipproto {} \
+         (`pipproto dissector`).",
+        env!("CARGO_PKG_VERSION")
+    );
+    out.push_str("-- Do not edit: regenerate it from the crate instead.\n\n");
+    out.push_str("local pipproto = Proto(\"pipproto\", \"PiProto\")\n\n");
+
+    let _ = writeln!(out, "local MAGIC = {}", lua_string(&MAGIC));
+    let _ = writeln!(out, "local PACK_MAGIC = {}", lua_string(&PACK_MAGIC));
+    let _ = writeln!(out, "local VERSION_V1 = {VERSION_V1}");
+    let _ = writeln!(out, "local HEADER_LEN_V1 = {HEADER_LEN_V1}");
+    let _ = writeln!(out, "local ACK_REQUIRED = 0x{:02x}", Flags::ACK_REQUIRED);
+    let _ = writeln!(
+        out,
+        "local RESERVED_FLAGS = 0x{:02x}\n",
+        !Flags::ACK_REQUIRED
+    );
+
+    out.push_str("local msg_types = {\n");
+    for t in (0..=u8::MAX).filter_map(MsgType::from_u8) {
+        let _ = writeln!(out, "    [0x{:02x}] = \"{}\",", t as u8, t.as_str());
+    }
+    out.push_str("}\n\n");
+
+    out.push_str("local f = pipproto.fields\n");
+    for (name, _) in FIELDS {
+        let _ = writeln!(out, "f.{name} = {}", proto_field(name));
+    }
+    let _ = writeln!(
+        out,
+        "f.ack_required = ProtoField.bool(\"pipproto.flags.ack_required\", \
+         \"ACK_REQUIRED\", 8, nil, ACK_REQUIRED)"
+    );
+    out.push_str(
+        "f.reserved = ProtoField.uint8(\"pipproto.flags.reserved\", \"Reserved\", \
+         base.HEX, nil, RESERVED_FLAGS)\n",
+    );
+    out.push_str("f.body = ProtoField.bytes(\"pipproto.body\", \"Body\")\n");
+    out.push_str(
+        "f.entry_len = ProtoField.uint16(\"pipproto.entry_len\", \"Entry length\", \
+         base.DEC)\n\n",
+    );
+
+    out.push_str("local e = pipproto.experts\n");
+    for (name, text, group) in [
+        ("truncated", "Frame ends inside the header", "MALFORMED"),
+        ("bad_magic", "Not a PiProto frame", "MALFORMED"),
+        ("unknown_version", "Unknown protocol version", "PROTOCOL"),
+        ("unknown_type", "Unknown message type", "PROTOCOL"),
+        ("reserved_flags", "Reserved flag bits set", "PROTOCOL"),
+        (
+            "bad_entry",
+            "Packed entry runs past the datagram",
+            "MALFORMED",
+        ),
+    ] {
+        let _ = writeln!(
+            out,
+            "e.{name} = ProtoExpert.new(\"pipproto.{name}\", \"{text}\", \
+             expert.group.{group}, expert.severity.ERROR)"
+        );
+    }
+    out.push('\n');
+
+    let _ = writeln!(
+        out,
+        "pipproto.prefs.port = Pref.uint(\"UDP port\", {port}, \
+         \"UDP port to decode as PiProto\")\n"
+    );
+
+    out.push_str("-- Header fields: field, offset, length.\n");
+    out.push_str("local header_v1 = {\n");
+    for (name, range) in FIELDS {
+        let _ = writeln!(out, "    {{ f.{name}, {}, {} }},", range.start, range.len());
+    }
+    out.push_str("}\n");
+    out.push_str(DISSECT);
+
+    out
+}
+
+/// Everything after the tables, which refers to them by name.
+const DISSECT: &str = r#"
+local function dissect_v1(buf, pinfo, item)
+    for _, field in ipairs(header_v1) do
+        local at, len = field[2], field[3]
+        if buf:len() < at + len then
+            item:add_proto_expert_info(e.truncated)
+            return
+        end
+        local added = item:add(field[1], buf(at, len))
+        if field[1] == f.flags then
+            added:add(f.ack_required, buf(at, len))
+            added:add(f.reserved, buf(at, len))
+        end
+    end
+    if buf:len() > HEADER_LEN_V1 then
+        item:add(f.body, buf(HEADER_LEN_V1))
+    end
+
+    local msg_type = buf(3, 1):uint()
+    local name = msg_types[msg_type]
+    if name == nil then
+        item:add_proto_expert_info(e.unknown_type)
+        name = string.format("0x%02x", msg_type)
+    end
+    if bit.band(buf(4, 1):uint(), RESERVED_FLAGS) ~= 0 then
+        item:add_proto_expert_info(e.reserved_flags)
+    end
+    pinfo.cols.info:append(string.format(
+        " %s device=%s counter=%s len=%d",
+        name,
+        buf(5, 8):bytes():tohex(),
+        tostring(buf(13, 8):uint64()),
+        buf:len() - HEADER_LEN_V1
+    ))
+end
+
+local versions = {
+    [VERSION_V1] = dissect_v1,
+}
+
+local function dissect_frame(buf, pinfo, tree)
+    local item = tree:add(pipproto, buf())
+    if buf:len() < 3 then
+        item:add_proto_expert_info(e.truncated)
+        return
+    end
+    if buf(0, 2):string() ~= MAGIC then
+        item:add_proto_expert_info(e.bad_magic)
+        return
+    end
+    local dissect = versions[buf(2, 1):uint()]
+    if dissect == nil then
+        item:add(f.magic, buf(0, 2))
+        item:add(f.version, buf(2, 1))
+        item:add_proto_expert_info(e.unknown_version)
+        return
+    end
+    dissect(buf, pinfo, item)
+end
+
+function pipproto.dissector(buf, pinfo, tree)
+    if buf:len() < 2 then
+        return 0
+    end
+    local magic = buf(0, 2):string()
+    if magic ~= MAGIC and magic ~= PACK_MAGIC then
+        return 0
+    end
+    pinfo.cols.protocol = "PIPPROTO"
+    pinfo.cols.info = ""
+    if magic == MAGIC then
+        dissect_frame(buf, pinfo, tree)
+        return buf:len()
+    end
+
+    local packed = tree:add(pipproto, buf(), "PiProto packed datagram")
+    local at = 2
+    while at < buf:len() do
+        if buf:len() < at + 2 then
+            packed:add_proto_expert_info(e.bad_entry)
+            break
+        end
+        local len = buf(at, 2):uint()
+        if buf:len() < at + 2 + len then
+            packed:add(f.entry_len, buf(at, 2))
+            packed:add_proto_expert_info(e.bad_entry)
+            break
+        end
+        packed:add(f.entry_len, buf(at, 2))
+        if len > 0 then
+            dissect_frame(buf(at + 2, len):tvb(), pinfo, packed)
+        end
+        at = at + 2 + len
+    end
+    return buf:len()
+end
+
+local registered = nil
+
+local function register()
+    local udp = DissectorTable.get("udp.port")
+    if registered ~= nil then
+        udp:remove(registered, pipproto)
+    end
+    registered = pipproto.prefs.port
+    udp:add(registered, pipproto)
+end
+
+function pipproto.prefs_changed()
+    register()
+end
+
+register()
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_every_type_and_field() {
+        let lua = lua(DEFAULT_PORT);
+        for t in [
+            MsgType::Event,
+            MsgType::Command,
+            MsgType::Ack,
+            MsgType::Error,
+        ] {
+            assert!(lua.contains(&format!("[0x{:02x}] = \"{}\"", t as u8, t.as_str())));
+        }
+        for (name, range) in FIELDS {
+            assert!(lua.contains(&format!("{{ f.{name}, {}, {} }}", range.start, range.len())));
+        }
+        assert!(lua.contains("local RESERVED_FLAGS = 0xfe"));
+        assert!(lua.starts_with(&format!(
+            "-- PiProto dissector for Wireshark, generated by pipproto {}",
+            env!("CARGO_PKG_VERSION")
+        )));
+    }
+
+    #[test]
+    fn port_is_the_preference_default() {
+        assert!(lua(5683).contains("Pref.uint(\"UDP port\", 5683,"));
+    }
+}
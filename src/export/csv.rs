@@ -0,0 +1,119 @@
+use std::io::{self, Write};
+
+use super::{ExportOptions, Row};
+use crate::sink::{FrameRecord, FrameSink};
+
+/// Writes the header on creation, then one line per record.
+pub struct CsvExporter<W: Write> {
+    out: W,
+    options: ExportOptions,
+    rows: u64,
+}
+
+impl<W: Write> CsvExporter<W> {
+    pub fn new(mut out: W, options: ExportOptions) -> io::Result<Self> {
+        writeln!(out, "{}", options.columns().join(","))?;
+        Ok(CsvExporter {
+            out,
+            options,
+            rows: 0,
+        })
+    }
+
+    pub fn write(&mut self, record: &FrameRecord) -> io::Result<()> {
+        let row = Row::new(record);
+        let opt = |v: Option<String>| v.unwrap_or_default();
+        write!(
+            self.out,
+            "{},{},{},{},{},{},{}",
+            row.timestamp_ms,
+            opt(row.device_id),
+            row.msg_type.unwrap_or_default(),
+            opt(row.counter.map(|c| c.to_string())),
+            opt(row.flags.map(|f| f.to_string())),
+            row.body.len(),
+            quote(&opt(row.error)),
+        )?;
+        if self.options.include_body {
+            write!(self.out, ",{}", crate::hex::encode(row.body))?;
+        }
+        writeln!(self.out)?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> FrameSink for CsvExporter<W> {
+    type Error = io::Error;
+
+    fn record(&mut self, record: &FrameRecord) -> io::Result<()> {
+        self.write(record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// RFC 4180 quoting, only when needed.
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Export every record and return how many were written.
+pub fn write_csv<W, I>(out: W, records: I, options: ExportOptions) -> io::Result<u64>
+where
+    W: Write,
+    I: IntoIterator<Item = FrameRecord>,
+{
+    let mut exporter = CsvExporter::new(out, options)?;
+    for record in records {
+        exporter.write(&record)?;
+    }
+    let rows = exporter.rows();
+    exporter.finish()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::tests::records;
+
+    #[test]
+    fn writes_header_and_rows() {
+        let mut out = Vec::new();
+        let options = ExportOptions { include_body: true };
+        assert_eq!(write_csv(&mut out, records(), options).unwrap(), 2);
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "timestamp_ms,device_id,msg_type,counter,flags,body_len,error,body_hex",
+                "1700000000123,4445563030303031,event,7,0,3,,010203",
+                "1700000000456,,,,,1,input too short,50",
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes_when_needed() {
+        assert_eq!(quote("plain"), "plain");
+        assert_eq!(quote("a, \"b\""), "\"a, \"\"b\"\"\"");
+    }
+}
@@ -0,0 +1,378 @@
+//! C bindings for frame encode and decode, for components that can't
+//! link Rust directly. `include/pipproto.h` is generated from this file
+//! by cbindgen (see `cbindgen.toml`).
+//!
+//! Ownership: a body [`pp_frame_decode`] fills in belongs to the library
+//! and is released with [`pp_frame_free`]; a body the caller points a
+//! `PpFrame` at for [`pp_frame_encode`] stays the caller's, and is only
+//! read. No function unwinds into C: a panic comes back as
+//! [`PpError::Panic`].
+
+use std::ffi::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::{DecodeError, Flags, FrameHeaderV1, HEADER_LEN_V1, MsgType, VERSION_V1};
+
+/// Length of an encoded header; an encoded frame is this plus its body.
+pub const PP_HEADER_LEN: usize = 21;
+
+const _: () = assert!(PP_HEADER_LEN == HEADER_LEN_V1);
+
+/// What a call returns: `Ok`, a [`DecodeError`] kind, or a misuse.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PpError {
+    Ok = 0,
+    TooShort = 1,
+    BadMagic = 2,
+    BadVersion = 3,
+    UnknownMsgType = 4,
+    ReservedFlags = 5,
+    BadDeviceIdBytes = 6,
+    BadCounterBytes = 7,
+    /// A pointer that must not be NULL was.
+    NullPointer = 8,
+    /// The output buffer is smaller than the encoded frame.
+    BufferTooSmall = 9,
+    /// The library panicked; the call had no effect.
+    Panic = 10,
+}
+
+impl From<DecodeError> for PpError {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::TooShort => PpError::TooShort,
+            DecodeError::BadMagic => PpError::BadMagic,
+            DecodeError::BadVersion(_) => PpError::BadVersion,
+            DecodeError::UnknownMsgType(_) => PpError::UnknownMsgType,
+            DecodeError::ReservedFlags(_) => PpError::ReservedFlags,
+            DecodeError::BadDeviceIdBytes => PpError::BadDeviceIdBytes,
+            DecodeError::BadCounterBytes => PpError::BadCounterBytes,
+        }
+    }
+}
+
+/// A frame's header fields and body.
+///
+/// `body` is NULL when `body_len` is 0.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PpFrame {
+    pub version: u8,
+    pub msg_type: u8,
+    pub flags: u8,
+    pub device_id: [u8; 8],
+    pub counter: u64,
+    pub body: *mut u8,
+    pub body_len: usize,
+}
+
+impl PpFrame {
+    const EMPTY: PpFrame = PpFrame {
+        version: 0,
+        msg_type: 0,
+        flags: 0,
+        device_id: [0; 8],
+        counter: 0,
+        body: ptr::null_mut(),
+        body_len: 0,
+    };
+}
+
+fn guard(call: impl FnOnce() -> Result<(), PpError>) -> PpError {
+    match panic::catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(())) => PpError::Ok,
+        Ok(Err(e)) => e,
+        Err(_) => PpError::Panic,
+    }
+}
+
+/// Decode the `len` bytes at `input` into `*out`, its body a copy the
+/// library allocates.
+///
+/// `*out` is overwritten, not freed; on error it holds no body. Release
+/// a decoded frame with [`pp_frame_free`].
+///
+/// # Safety
+///
+/// `input` must point to `len` readable bytes (or may be NULL if `len`
+/// is 0), and `out` to a writable `PpFrame`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pp_frame_decode(
+    input: *const u8,
+    len: usize,
+    out: *mut PpFrame,
+) -> PpError {
+    guard(|| {
+        if out.is_null() {
+            return Err(PpError::NullPointer);
+        }
+        unsafe { out.write(PpFrame::EMPTY) };
+        let input = match (input.is_null(), len) {
+            (true, 0) => &[][..],
+            (true, _) => return Err(PpError::NullPointer),
+            (false, _) => unsafe { std::slice::from_raw_parts(input, len) },
+        };
+        let h = FrameHeaderV1::decode(input)?;
+        let (body, body_len) = match &input[HEADER_LEN_V1..] {
+            [] => (ptr::null_mut(), 0),
+            body => (
+                Box::into_raw(Box::<[u8]>::from(body)).cast::<u8>(),
+                body.len(),
+            ),
+        };
+        unsafe {
+            out.write(PpFrame {
+                version: h.version,
+                msg_type: h.msg_type as u8,
+                flags: h.flags.bits(),
+                device_id: h.device_id,
+                counter: h.counter,
+                body,
+                body_len,
+            })
+        };
+        Ok(())
+    })
+}
+
+/// Encode `*frame` into the `cap` bytes at `out`, setting `*written` to
+/// the encoded length.
+///
+/// If `cap` is too small nothing is written to `out` and the call returns
+/// `BufferTooSmall`, `*written` still set to the length needed, so
+/// passing a NULL `out` and a `cap` of 0 asks for that length.
+///
+/// # Safety
+///
+/// `frame` must point to a valid `PpFrame` whose `body` points to
+/// `body_len` readable bytes; `out` to `cap` writable bytes, not
+/// overlapping the body; and `written` to a writable `size_t`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pp_frame_encode(
+    frame: *const PpFrame,
+    out: *mut u8,
+    cap: usize,
+    written: *mut usize,
+) -> PpError {
+    guard(|| {
+        if frame.is_null() || written.is_null() {
+            return Err(PpError::NullPointer);
+        }
+        let frame = unsafe { &*frame };
+        if frame.version != VERSION_V1 {
+            return Err(PpError::BadVersion);
+        }
+        let header = FrameHeaderV1 {
+            version: frame.version,
+            msg_type: MsgType::from_u8(frame.msg_type).ok_or(PpError::UnknownMsgType)?,
+            flags: Flags::new(frame.flags)?,
+            device_id: frame.device_id,
+            counter: frame.counter,
+        };
+        let body = match (frame.body.is_null(), frame.body_len) {
+            (true, 0) => &[][..],
+            (true, _) => return Err(PpError::NullPointer),
+            (false, n) => unsafe { std::slice::from_raw_parts(frame.body, n) },
+        };
+        let len = PP_HEADER_LEN + body.len();
+        unsafe { written.write(len) };
+        if cap < len {
+            return Err(PpError::BufferTooSmall);
+        }
+        if out.is_null() {
+            return Err(PpError::NullPointer);
+        }
+        let out = unsafe { std::slice::from_raw_parts_mut(out, len) };
+        out[..PP_HEADER_LEN].copy_from_slice(&header.to_bytes());
+        out[PP_HEADER_LEN..].copy_from_slice(body);
+        Ok(())
+    })
+}
+
+/// Release the body of a frame [`pp_frame_decode`] filled in, leaving
+/// `*frame` with none. NULL, or a frame with no body, is a no-op, so
+/// freeing twice is harmless.
+///
+/// # Safety
+///
+/// `frame` must be NULL or point to a `PpFrame` whose body, if any, came
+/// from [`pp_frame_decode`], never one the caller set.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pp_frame_free(frame: *mut PpFrame) {
+    let _ = guard(|| {
+        let Some(frame) = (unsafe { frame.as_mut() }) else {
+            return Ok(());
+        };
+        if !frame.body.is_null() {
+            let body = ptr::slice_from_raw_parts_mut(frame.body, frame.body_len);
+            drop(unsafe { Box::from_raw(body) });
+        }
+        frame.body = ptr::null_mut();
+        frame.body_len = 0;
+        Ok(())
+    });
+}
+
+/// A static, NUL-terminated description of `code`, for logs.
+#[unsafe(no_mangle)]
+pub extern "C" fn pp_error_message(code: i32) -> *const c_char {
+    let message = match code {
+        0 => c"ok",
+        1 => c"input too short",
+        2 => c"bad magic",
+        3 => c"unsupported version",
+        4 => c"unknown msg_type",
+        5 => c"reserved flag bits set",
+        6 => c"bad device_id bytes",
+        7 => c"bad counter bytes",
+        8 => c"null pointer",
+        9 => c"output buffer too small",
+        10 => c"panic in pipproto",
+        _ => c"unknown error code",
+    };
+    message.as_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+    use crate::FrameV1;
+
+    fn sample(body: &[u8]) -> FrameV1 {
+        FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Command,
+                flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+                device_id: *b"DEV00042",
+                counter: 0x0102_0304_0506_0708,
+            },
+            body: body.into(),
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> (PpError, PpFrame) {
+        let mut out = PpFrame::EMPTY;
+        let code = unsafe { pp_frame_decode(bytes.as_ptr(), bytes.len(), &mut out) };
+        (code, out)
+    }
+
+    fn encode(frame: &PpFrame, cap: usize) -> (PpError, Vec<u8>, usize) {
+        let mut out = vec![0; cap];
+        let mut written = 0;
+        let code = unsafe { pp_frame_encode(frame, out.as_mut_ptr(), cap, &mut written) };
+        (code, out, written)
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        for body in [&b""[..], b"hello", &[7; 300]] {
+            let bytes = sample(body).encode();
+            let (code, mut frame) = decode(&bytes);
+            assert_eq!(code, PpError::Ok);
+            assert_eq!(frame.msg_type, MsgType::Command as u8);
+            assert_eq!(frame.flags, 1);
+            assert_eq!(&frame.device_id, b"DEV00042");
+            assert_eq!(frame.counter, 0x0102_0304_0506_0708);
+            assert_eq!(frame.body_len, body.len());
+            assert_eq!(frame.body.is_null(), body.is_empty());
+
+            let (code, out, written) = encode(&frame, bytes.len() + 8);
+            assert_eq!(code, PpError::Ok);
+            assert_eq!(&out[..written], bytes);
+
+            unsafe { pp_frame_free(&mut frame) };
+            assert!(frame.body.is_null());
+            assert_eq!(frame.body_len, 0);
+            unsafe { pp_frame_free(&mut frame) };
+        }
+        unsafe { pp_frame_free(ptr::null_mut()) };
+    }
+
+    #[test]
+    fn decode_errors_map_to_codes() {
+        let good = sample(b"x").encode();
+        let with = |at: usize, byte: u8| {
+            let mut bytes = good.clone();
+            bytes[at] = byte;
+            bytes
+        };
+        for (bytes, want) in [
+            (good[..20].to_vec(), PpError::TooShort),
+            (with(0, b'X'), PpError::BadMagic),
+            (with(2, 9), PpError::BadVersion),
+            (with(3, 0x7f), PpError::UnknownMsgType),
+            (with(4, 0b10), PpError::ReservedFlags),
+        ] {
+            let (code, frame) = decode(&bytes);
+            assert_eq!(code, want);
+            assert!(frame.body.is_null());
+        }
+        let mut out = PpFrame::EMPTY;
+        let code = unsafe { pp_frame_decode(ptr::null(), 0, &mut out) };
+        assert_eq!(code, PpError::TooShort);
+        let code = unsafe { pp_frame_decode(ptr::null(), 4, &mut out) };
+        assert_eq!(code, PpError::NullPointer);
+        let code = unsafe { pp_frame_decode(good.as_ptr(), good.len(), ptr::null_mut()) };
+        assert_eq!(code, PpError::NullPointer);
+    }
+
+    #[test]
+    fn encode_checks_fields_and_room() {
+        let mut body = *b"abc";
+        let frame = PpFrame {
+            version: VERSION_V1,
+            msg_type: MsgType::Event as u8,
+            flags: 0,
+            device_id: *b"DEV00001",
+            counter: 9,
+            body: body.as_mut_ptr(),
+            body_len: body.len(),
+        };
+        let needed = PP_HEADER_LEN + 3;
+        let (code, out, written) = encode(&frame, needed - 1);
+        assert_eq!((code, written), (PpError::BufferTooSmall, needed));
+        assert!(out.iter().all(|&b| b == 0));
+        let mut written = 0;
+        let code = unsafe { pp_frame_encode(&frame, ptr::null_mut(), 0, &mut written) };
+        assert_eq!((code, written), (PpError::BufferTooSmall, needed));
+
+        let check = |change: fn(&mut PpFrame), want| {
+            let mut bad = PpFrame { ..frame };
+            change(&mut bad);
+            assert_eq!(encode(&bad, 64).0, want);
+        };
+        check(|f| f.version = 2, PpError::BadVersion);
+        check(|f| f.msg_type = 0, PpError::UnknownMsgType);
+        check(|f| f.flags = 0x80, PpError::ReservedFlags);
+        check(|f| f.body = ptr::null_mut(), PpError::NullPointer);
+        assert_eq!(body, *b"abc");
+    }
+
+    #[test]
+    fn every_code_has_a_message() {
+        for code in 0..=10 {
+            let message = unsafe { CStr::from_ptr(pp_error_message(code)) };
+            assert_ne!(message, c"unknown error code", "{code}");
+        }
+        assert_eq!(
+            unsafe { CStr::from_ptr(pp_error_message(-1)) },
+            c"unknown error code"
+        );
+        assert_eq!(
+            unsafe { CStr::from_ptr(pp_error_message(PpError::Panic as i32)) },
+            c"panic in pipproto"
+        );
+    }
+
+    #[test]
+    fn error_codes_are_decode_error_codes() {
+        for e in crate::cdefs::DECODE_ERRORS {
+            assert_eq!(PpError::from(e.clone()) as i32, i32::from(e.code()));
+        }
+    }
+}
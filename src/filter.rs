@@ -0,0 +1,555 @@
+//! A small expression language for picking frames.
+//!
+//! [`FrameFilter::parse`] compiles an expression once, and
+//! [`FrameFilter::matches`] tests frames against it, e.g.
+//!
+//! ```text
+//! device == DEADBEEF00000001 && (type == event || type == error) && counter > 1000 && body_len < 64
+//! ```
+//!
+//! The grammar, loosest binding first:
+//!
+//! ```text
+//! expr       = and { "||" and }
+//! and        = unary { "&&" unary }
+//! unary      = "!" unary | "(" expr ")" | "ack_required" | comparison
+//! comparison = "device" ("==" | "!=") HEX16
+//!            | "type" ("==" | "!=") ("event" | "command" | "ack" | "error")
+//!            | ("counter" | "body_len") OP NUMBER
+//! OP         = "==" | "!=" | "<" | "<=" | ">" | ">="
+//! ```
+//!
+//! `HEX16` is a device id as 16 hex digits and `NUMBER` a u64, decimal or
+//! `0x` hex. Names and hex digits are case-insensitive, and whitespace
+//! between tokens is free. `!` and parentheses nest at most [`MAX_DEPTH`]
+//! deep. A [`FilterError`] gives the byte offset of the token at fault.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{FrameV1, MsgType};
+
+/// How deep `!` and parentheses may nest.
+pub const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn as_str(self) -> &'static str {
+        match self {
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        }
+    }
+
+    fn test<T: Ord>(self, a: T, b: T) -> bool {
+        match self {
+            Op::Eq => a == b,
+            Op::Ne => a != b,
+            Op::Lt => a < b,
+            Op::Le => a <= b,
+            Op::Gt => a > b,
+            Op::Ge => a >= b,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    All(Vec<Node>),
+    Any(Vec<Node>),
+    Not(Box<Node>),
+    AckRequired,
+    /// `==` or `!=` only, as for the type.
+    Device(Op, [u8; 8]),
+    Type(Op, MsgType),
+    Counter(Op, u64),
+    BodyLen(Op, u64),
+}
+
+impl Node {
+    fn matches(&self, frame: &FrameV1) -> bool {
+        let h = &frame.header;
+        match self {
+            Node::All(nodes) => nodes.iter().all(|n| n.matches(frame)),
+            Node::Any(nodes) => nodes.iter().any(|n| n.matches(frame)),
+            Node::Not(node) => !node.matches(frame),
+            Node::AckRequired => h.flags.ack_required(),
+            Node::Device(op, id) => op.test(&h.device_id, id),
+            Node::Type(op, t) => (h.msg_type == *t) == (*op == Op::Eq),
+            Node::Counter(op, n) => op.test(h.counter, *n),
+            Node::BodyLen(op, n) => op.test(frame.body.len() as u64, *n),
+        }
+    }
+}
+
+/// A compiled filter expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameFilter(Node);
+
+impl FrameFilter {
+    pub fn parse(expr: &str) -> Result<Self, FilterError> {
+        let mut parser = Parser {
+            tokens: lex(expr)?,
+            pos: 0,
+            end: expr.len(),
+            depth: 0,
+        };
+        let node = parser.expr()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(token.expected("`&&`, `||` or the end"));
+        }
+        Ok(FrameFilter(node))
+    }
+
+    pub fn matches(&self, frame: &FrameV1) -> bool {
+        self.0.matches(frame)
+    }
+}
+
+impl FromStr for FrameFilter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, FilterError> {
+        FrameFilter::parse(s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterErrorKind {
+    /// A character no token starts with.
+    UnexpectedChar(char),
+    /// `found` is `None` at the end of the input.
+    Expected {
+        expected: &'static str,
+        found: Option<String>,
+    },
+    UnknownField(String),
+    /// An operator the field has no use for, like `<` on a device id.
+    BadOperator {
+        field: &'static str,
+        op: &'static str,
+    },
+    BadValue {
+        field: &'static str,
+        value: String,
+    },
+    /// `!` and parentheses nested deeper than [`MAX_DEPTH`].
+    TooDeep,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterError {
+    /// Byte offset in the expression.
+    pub offset: usize,
+    pub kind: FilterErrorKind,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            FilterErrorKind::UnexpectedChar(c) => write!(f, "unexpected {c:?}")?,
+            FilterErrorKind::Expected {
+                expected,
+                found: Some(found),
+            } => write!(f, "expected {expected}, found `{found}`")?,
+            FilterErrorKind::Expected {
+                expected,
+                found: None,
+            } => write!(f, "expected {expected}, found the end")?,
+            FilterErrorKind::UnknownField(name) => write!(f, "unknown field `{name}`")?,
+            FilterErrorKind::BadOperator { field, op } => {
+                write!(f, "`{op}` does not apply to {field}")?
+            }
+            FilterErrorKind::BadValue { field, value } => {
+                write!(f, "`{value}` is not a valid {field}")?
+            }
+            FilterErrorKind::TooDeep => write!(f, "nested more than {MAX_DEPTH} deep")?,
+        }
+        write!(f, " at offset {}", self.offset)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Word,
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    offset: usize,
+    text: &'a str,
+    kind: Kind,
+}
+
+impl Token<'_> {
+    fn expected(&self, expected: &'static str) -> FilterError {
+        FilterError {
+            offset: self.offset,
+            kind: FilterErrorKind::Expected {
+                expected,
+                found: Some(self.text.to_string()),
+            },
+        }
+    }
+}
+
+fn lex(input: &str) -> Result<Vec<Token<'_>>, FilterError> {
+    let mut tokens = Vec::new();
+    let bytes = input.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &input[i..];
+        let c = rest.chars().next().unwrap();
+        if c.is_whitespace() {
+            i += c.len_utf8();
+            continue;
+        }
+        let (len, kind) = if c.is_ascii_alphanumeric() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            (len, Kind::Word)
+        } else {
+            const SYMBOLS: [(&str, Kind); 11] = [
+                ("&&", Kind::And),
+                ("||", Kind::Or),
+                ("==", Kind::Op(Op::Eq)),
+                ("!=", Kind::Op(Op::Ne)),
+                ("<=", Kind::Op(Op::Le)),
+                (">=", Kind::Op(Op::Ge)),
+                ("<", Kind::Op(Op::Lt)),
+                (">", Kind::Op(Op::Gt)),
+                ("!", Kind::Not),
+                ("(", Kind::Open),
+                (")", Kind::Close),
+            ];
+            let Some(&(symbol, kind)) = SYMBOLS.iter().find(|(s, _)| rest.starts_with(s)) else {
+                return Err(FilterError {
+                    offset: i,
+                    kind: FilterErrorKind::UnexpectedChar(c),
+                });
+            };
+            (symbol.len(), kind)
+        };
+        tokens.push(Token {
+            offset: i,
+            text: &rest[..len],
+            kind,
+        });
+        i += len;
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    end: usize,
+    /// `!`s and parentheses open around the current token.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self, expected: &'static str) -> Result<Token<'a>, FilterError> {
+        let token = self.tokens.get(self.pos).copied().ok_or(FilterError {
+            offset: self.end,
+            kind: FilterErrorKind::Expected {
+                expected,
+                found: None,
+            },
+        })?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, kind: Kind) -> bool {
+        let found = self.tokens.get(self.pos).is_some_and(|t| t.kind == kind);
+        self.pos += usize::from(found);
+        found
+    }
+
+    fn expr(&mut self) -> Result<Node, FilterError> {
+        let mut any = vec![self.and()?];
+        while self.eat(Kind::Or) {
+            any.push(self.and()?);
+        }
+        Ok(if any.len() == 1 {
+            any.pop().unwrap()
+        } else {
+            Node::Any(any)
+        })
+    }
+
+    fn and(&mut self) -> Result<Node, FilterError> {
+        let mut all = vec![self.unary()?];
+        while self.eat(Kind::And) {
+            all.push(self.unary()?);
+        }
+        Ok(if all.len() == 1 {
+            all.pop().unwrap()
+        } else {
+            Node::All(all)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Node, FilterError> {
+        let token = self.next("a condition")?;
+        if matches!(token.kind, Kind::Not | Kind::Open) {
+            if self.depth == MAX_DEPTH {
+                return Err(FilterError {
+                    offset: token.offset,
+                    kind: FilterErrorKind::TooDeep,
+                });
+            }
+            self.depth += 1;
+        }
+        let node = match token.kind {
+            Kind::Not => Node::Not(Box::new(self.unary()?)),
+            Kind::Open => {
+                let node = self.expr()?;
+                let close = self.next("`)`")?;
+                if close.kind != Kind::Close {
+                    return Err(close.expected("`)`"));
+                }
+                node
+            }
+            Kind::Word if token.text.eq_ignore_ascii_case("ack_required") => {
+                return Ok(Node::AckRequired);
+            }
+            Kind::Word => return self.comparison(token),
+            _ => return Err(token.expected("a condition")),
+        };
+        self.depth -= 1;
+        Ok(node)
+    }
+
+    fn comparison(&mut self, name: Token<'a>) -> Result<Node, FilterError> {
+        let field = match name.text.to_ascii_lowercase().as_str() {
+            "device" => "device",
+            "type" => "type",
+            "counter" => "counter",
+            "body_len" => "body_len",
+            _ => {
+                return Err(FilterError {
+                    offset: name.offset,
+                    kind: FilterErrorKind::UnknownField(name.text.to_string()),
+                });
+            }
+        };
+        let op_token = self.next("an operator")?;
+        let Kind::Op(op) = op_token.kind else {
+            return Err(op_token.expected("an operator"));
+        };
+        if matches!(field, "device" | "type") && !matches!(op, Op::Eq | Op::Ne) {
+            return Err(FilterError {
+                offset: op_token.offset,
+                kind: FilterErrorKind::BadOperator {
+                    field,
+                    op: op.as_str(),
+                },
+            });
+        }
+        let value = self.next("a value")?;
+        if value.kind != Kind::Word {
+            return Err(value.expected("a value"));
+        }
+        let bad = || FilterError {
+            offset: value.offset,
+            kind: FilterErrorKind::BadValue {
+                field,
+                value: value.text.to_string(),
+            },
+        };
+        let text = value.text;
+        let number = || match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => text.parse().ok(),
+        };
+        Ok(match field {
+            "device" => {
+                let id = crate::hex::decode(text).and_then(|d| <[u8; 8]>::try_from(d).ok());
+                Node::Device(op, id.ok_or_else(bad)?)
+            }
+            "type" => {
+                let types = [
+                    MsgType::Event,
+                    MsgType::Command,
+                    MsgType::Ack,
+                    MsgType::Error,
+                ];
+                let t = types
+                    .into_iter()
+                    .find(|t| t.as_str().eq_ignore_ascii_case(text));
+                Node::Type(op, t.ok_or_else(bad)?)
+            }
+            "counter" => Node::Counter(op, number().ok_or_else(bad)?),
+            _ => Node::BodyLen(op, number().ok_or_else(bad)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Flags;
+    use crate::transport::tests::frame;
+
+    /// An event, a command without ACK_REQUIRED, and an error.
+    fn samples() -> [FrameV1; 3] {
+        let ack = Flags::new(Flags::ACK_REQUIRED).unwrap();
+        let mut event = frame(1500);
+        event.header.flags = ack;
+        event.header.device_id = [0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 1];
+        event.body = vec![0; 10].into();
+        let mut command = frame(7);
+        command.header.msg_type = MsgType::Command;
+        command.body = vec![0; 100].into();
+        let mut error = frame(2000);
+        error.header.msg_type = MsgType::Error;
+        error.header.flags = ack;
+        error.body.clear();
+        [event, command, error]
+    }
+
+    #[test]
+    fn matches_the_sample_frames() {
+        let cases: &[(&str, [bool; 3])] = &[
+            ("type == event", [true, false, false]),
+            ("TYPE != Event", [false, true, true]),
+            ("device == DEADBEEF00000001", [true, false, false]),
+            ("device != deadbeef00000001", [false, true, true]),
+            ("counter > 1000", [true, false, true]),
+            ("counter <= 0x5dc", [true, true, false]),
+            ("counter == 7 || counter >= 2000", [false, true, true]),
+            ("body_len < 64", [true, false, true]),
+            ("body_len == 0", [false, false, true]),
+            ("ack_required", [true, false, true]),
+            ("!ack_required", [false, true, false]),
+            ("!!ack_required", [true, false, true]),
+            (
+                "device == DEADBEEF00000001 && (type == event || type == error) \
+                 && counter > 1000 && body_len < 64",
+                [true, false, false],
+            ),
+            (
+                "type == event || type == error && counter < 100",
+                [true, false, false],
+            ),
+            (
+                "(type == event || type == error) && counter < 100",
+                [false, false, false],
+            ),
+            ("!(type == command) && body_len>5", [true, false, false]),
+            (
+                "counter > 1 && counter < 1000 || body_len == 0",
+                [false, true, true],
+            ),
+        ];
+        let frames = samples();
+        for (expr, expected) in cases {
+            let filter = FrameFilter::parse(expr).unwrap_or_else(|e| panic!("{expr}: {e}"));
+            let got = frames.each_ref().map(|f| filter.matches(f));
+            assert_eq!(&got, expected, "{expr}");
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_token() {
+        let cases: &[(&str, usize, &str)] = &[
+            ("", 0, "expected a condition, found the end at offset 0"),
+            ("colour == red", 0, "unknown field `colour` at offset 0"),
+            ("type < event", 5, "`<` does not apply to type at offset 5"),
+            ("type == frob", 8, "`frob` is not a valid type at offset 8"),
+            (
+                "device == DEAD",
+                10,
+                "`DEAD` is not a valid device at offset 10",
+            ),
+            ("counter > -1", 10, "unexpected '-' at offset 10"),
+            (
+                "counter >",
+                9,
+                "expected a value, found the end at offset 9",
+            ),
+            (
+                "counter 5",
+                8,
+                "expected an operator, found `5` at offset 8",
+            ),
+            (
+                "(ack_required",
+                13,
+                "expected `)`, found the end at offset 13",
+            ),
+            (
+                "ack_required ack_required",
+                13,
+                "expected `&&`, `||` or the end, found `ack_required` at offset 13",
+            ),
+            (
+                "body_len == 99999999999999999999",
+                12,
+                "`99999999999999999999` is not a valid body_len at offset 12",
+            ),
+            (
+                "type == event &&",
+                16,
+                "expected a condition, found the end at offset 16",
+            ),
+            (
+                "type == event & ack_required",
+                14,
+                "unexpected '&' at offset 14",
+            ),
+            (
+                "counter == (1)",
+                11,
+                "expected a value, found `(` at offset 11",
+            ),
+        ];
+        for &(expr, offset, message) in cases {
+            let e = expr.parse::<FrameFilter>().unwrap_err();
+            assert_eq!(
+                (e.offset, e.to_string().as_str()),
+                (offset, message),
+                "{expr}"
+            );
+        }
+    }
+
+    #[test]
+    fn nesting_is_bounded() {
+        let deepest = format!("{}ack_required{}", "(!".repeat(32), ")".repeat(32));
+        assert!(FrameFilter::parse(&deepest).is_ok());
+        let e = FrameFilter::parse(&format!("!{deepest}")).unwrap_err();
+        assert_eq!((e.offset, e.kind.clone()), (64, FilterErrorKind::TooDeep));
+        assert_eq!(e.to_string(), "nested more than 64 deep at offset 64");
+
+        // far past what the stack would take unchecked
+        let hostile = "!(".repeat(100_000);
+        let e = FrameFilter::parse(&hostile).unwrap_err();
+        assert_eq!(e.kind, FilterErrorKind::TooDeep);
+        assert!(FrameFilter::parse(&"!".repeat(100_000)).is_err());
+    }
+}
@@ -0,0 +1,547 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use bytes::Bytes;
+use tokio::io::AsyncWrite;
+
+use crate::Body;
+use crate::pool::{BufferPool, Pooled};
+use crate::vectored;
+
+pub const MAGIC: [u8; 2] = *b"PP";
+pub const VERSION_V1: u8 = 0x01;
+
+// Header v1: magic(2) + version(1) + type(1) + flags(1) + device_id(8) + counter(8) = 21
+pub const HEADER_LEN_V1: usize = 21;
+
+/// An encoded header, as [`FrameHeaderV1::to_bytes`] returns it.
+pub type HeaderBytes = [u8; HEADER_LEN_V1];
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum MsgType {
+    Event = 0x01,
+    Command = 0x02,
+    Ack = 0x03,
+    Error = 0x04,
+}
+
+impl MsgType {
+    pub fn from_u8(v: u8) -> Option<MsgType> {
+        match v {
+            0x01 => Some(MsgType::Event),
+            0x02 => Some(MsgType::Command),
+            0x03 => Some(MsgType::Ack),
+            0x04 => Some(MsgType::Error),
+            _ => None,
+        }
+    }
+
+    /// Lowercase name, as used in topic names and log lines.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MsgType::Event => "event",
+            MsgType::Command => "command",
+            MsgType::Ack => "ack",
+            MsgType::Error => "error",
+        }
+    }
+}
+
+/// Flags byte (v1)
+/// bit0 = ACK_REQUIRED
+/// bits1..7 reserved MUST be zero
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const ACK_REQUIRED: u8 = 0b0000_0001;
+
+    pub fn new(bits: u8) -> Result<Self, DecodeError> {
+        // reserved bits 1..7 must be zero
+        if (bits & 0b1111_1110) != 0 {
+            return Err(DecodeError::ReservedFlags(bits));
+        }
+        Ok(Flags(bits))
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn ack_required(self) -> bool {
+        (self.0 & Self::ACK_REQUIRED) != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameHeaderV1 {
+    pub version: u8,
+    pub msg_type: MsgType,
+    pub flags: Flags,
+    pub device_id: [u8; 8],
+    pub counter: u64,
+}
+
+/// What names a frame across retransmissions: its sender and counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FrameId {
+    pub device_id: [u8; 8],
+    pub counter: u64,
+}
+
+/// A frame, its body owned as a [`Body`], inline when short, or, as
+/// [`FrameV1B`], shared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameV1<B = Body> {
+    pub header: FrameHeaderV1,
+    pub body: B,
+}
+
+/// A frame whose body is a slice of a shared buffer, such as the one it
+/// was received into: cloning it, or taking its body, copies nothing.
+pub type FrameV1B = FrameV1<Bytes>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    TooShort,
+    BadMagic,
+    BadVersion(u8),
+    UnknownMsgType(u8),
+    ReservedFlags(u8),
+    BadDeviceIdBytes,
+    BadCounterBytes,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort => write!(f, "input too short"),
+            DecodeError::BadMagic => write!(f, "bad magic"),
+            DecodeError::BadVersion(v) => write!(f, "unsupported version: 0x{v:02x}"),
+            DecodeError::UnknownMsgType(v) => write!(f, "unknown msg_type: 0x{v:02x}"),
+            DecodeError::ReservedFlags(b) => write!(f, "reserved flag bits set: 0b{b:08b}"),
+            DecodeError::BadDeviceIdBytes => write!(f, "bad device_id bytes"),
+            DecodeError::BadCounterBytes => write!(f, "bad counter bytes"),
+        }
+    }
+}
+
+impl DecodeError {
+    /// Stable snake_case identifier, for structured error reports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DecodeError::TooShort => "too_short",
+            DecodeError::BadMagic => "bad_magic",
+            DecodeError::BadVersion(_) => "bad_version",
+            DecodeError::UnknownMsgType(_) => "unknown_msg_type",
+            DecodeError::ReservedFlags(_) => "reserved_flags",
+            DecodeError::BadDeviceIdBytes => "bad_device_id_bytes",
+            DecodeError::BadCounterBytes => "bad_counter_bytes",
+        }
+    }
+
+    /// Stable numeric code, from 1: the C bindings' `PpError` value and
+    /// `PP_ERR_*` in `pipproto_defs.h`.
+    pub fn code(&self) -> u8 {
+        match self {
+            DecodeError::TooShort => 1,
+            DecodeError::BadMagic => 2,
+            DecodeError::BadVersion(_) => 3,
+            DecodeError::UnknownMsgType(_) => 4,
+            DecodeError::ReservedFlags(_) => 5,
+            DecodeError::BadDeviceIdBytes => 6,
+            DecodeError::BadCounterBytes => 7,
+        }
+    }
+
+    /// Byte offset of the offending field, where there is one.
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            DecodeError::TooShort => None,
+            DecodeError::BadMagic => Some(0),
+            DecodeError::BadVersion(_) => Some(2),
+            DecodeError::UnknownMsgType(_) => Some(3),
+            DecodeError::ReservedFlags(_) => Some(4),
+            DecodeError::BadDeviceIdBytes => Some(5),
+            DecodeError::BadCounterBytes => Some(13),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl FrameHeaderV1 {
+    pub fn id(&self) -> FrameId {
+        FrameId {
+            device_id: self.device_id,
+            counter: self.counter,
+        }
+    }
+
+    /// device_id as 16 lowercase hex digits.
+    pub fn device_id_hex(&self) -> String {
+        crate::hex::encode(&self.device_id)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.to_bytes().to_vec()
+    }
+
+    /// As [`encode`](Self::encode), appending to `out`.
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_bytes());
+    }
+
+    /// As [`encode`](Self::encode), without allocating.
+    pub fn to_bytes(&self) -> HeaderBytes {
+        let mut out = [0u8; HEADER_LEN_V1];
+        out[0..2].copy_from_slice(&MAGIC);
+        out[2] = self.version;
+        out[3] = self.msg_type as u8;
+        out[4] = self.flags.bits();
+        out[5..13].copy_from_slice(&self.device_id);
+        out[13..21].copy_from_slice(&self.counter.to_be_bytes());
+        out
+    }
+
+    pub fn decode(input: &[u8]) -> Result<Self, DecodeError> {
+        if input.len() < HEADER_LEN_V1 {
+            return Err(DecodeError::TooShort);
+        }
+
+        if input[0..2] != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+
+        let version = input[2];
+        if version != VERSION_V1 {
+            return Err(DecodeError::BadVersion(version));
+        }
+
+        let msg_raw = input[3];
+        let msg_type = MsgType::from_u8(msg_raw).ok_or(DecodeError::UnknownMsgType(msg_raw))?;
+
+        let flags_raw = input[4];
+        let flags = Flags::new(flags_raw)?;
+
+        let device_id: [u8; 8] = input[5..13]
+            .try_into()
+            .map_err(|_| DecodeError::BadDeviceIdBytes)?;
+
+        let counter_bytes: [u8; 8] = input[13..21]
+            .try_into()
+            .map_err(|_| DecodeError::BadCounterBytes)?;
+        let counter = u64::from_be_bytes(counter_bytes);
+
+        Ok(FrameHeaderV1::toggle(
+            version, msg_type, flags, device_id, counter,
+        ))
+    }
+
+    fn toggle(
+        version: u8,
+        msg_type: MsgType,
+        flags: Flags,
+        device_id: [u8; 8],
+        counter: u64,
+    ) -> Self {
+        Self {
+            version,
+            msg_type,
+            flags,
+            device_id,
+            counter,
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> FrameV1<B> {
+    /// Length of [`FrameV1::encode`]'s output.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN_V1 + self.body.as_ref().len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.encoded_len());
+        self.encode_into(&mut out);
+        out
+    }
+
+    /// As [`encode`](Self::encode), appending to `out`.
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        self.header.encode_into(out);
+        out.extend_from_slice(self.body.as_ref());
+    }
+
+    /// As [`encode`](Self::encode), into a buffer from `pool`.
+    pub fn encode_pooled(&self, pool: &BufferPool) -> Pooled {
+        let mut out = pool.get(self.encoded_len());
+        self.encode_into(&mut out);
+        out
+    }
+
+    /// The header and the body that [`encode`](Self::encode) puts one
+    /// after the other, for writing without copying them together.
+    pub fn encoded_parts(&self) -> (HeaderBytes, &[u8]) {
+        (self.header.to_bytes(), self.body.as_ref())
+    }
+
+    /// Write the encoded frame to `w`, header and body as one vectored
+    /// write where `w` supports them and one after the other where not.
+    pub fn write_vectored_to(&self, w: &mut impl Write) -> io::Result<()> {
+        let (header, body) = self.encoded_parts();
+        vectored::write_all(w, [&header, body])
+    }
+
+    /// As [`write_vectored_to`](Self::write_vectored_to), for an async
+    /// writer.
+    pub async fn write_vectored_to_async(
+        &self,
+        w: &mut (impl AsyncWrite + Unpin),
+    ) -> io::Result<()> {
+        let (header, body) = self.encoded_parts();
+        vectored::write_all_async(w, [&header, body]).await
+    }
+}
+
+impl FrameV1 {
+    pub fn decode(input: &[u8]) -> Result<Self, DecodeError> {
+        let header = FrameHeaderV1::decode(input)?;
+        let body = Body::from(&input[HEADER_LEN_V1..]);
+        Ok(FrameV1 { header, body })
+    }
+}
+
+impl FrameV1B {
+    /// As [`FrameV1::decode`], the body left in `input` rather than copied.
+    pub fn decode_bytes(input: Bytes) -> Result<Self, DecodeError> {
+        let header = FrameHeaderV1::decode(&input)?;
+        let body = input.slice(HEADER_LEN_V1..);
+        Ok(FrameV1 { header, body })
+    }
+}
+
+/// Copies a body kept inline; takes over one on the heap.
+impl From<FrameV1> for FrameV1B {
+    fn from(frame: FrameV1) -> Self {
+        FrameV1 {
+            header: frame.header,
+            body: frame.body.into(),
+        }
+    }
+}
+
+/// Copies the body, unless it is too long to keep inline and nothing else
+/// shares its buffer.
+impl From<FrameV1B> for FrameV1 {
+    fn from(frame: FrameV1B) -> Self {
+        FrameV1 {
+            header: frame.header,
+            body: frame.body.into(),
+        }
+    }
+}
+
+/// A one-line summary: `event dev=4445563030303031 ctr=7 ack body=2B`.
+/// The body itself is left out.
+impl<B: AsRef<[u8]>> fmt::Display for FrameV1<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let h = &self.header;
+        write!(
+            f,
+            "{} dev={} ctr={}",
+            h.msg_type.as_str(),
+            h.device_id_hex(),
+            h.counter
+        )?;
+        if h.flags.ack_required() {
+            write!(f, " ack")?;
+        }
+        write!(f, " body={}B", self.body.as_ref().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_summary() {
+        let frame = FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Event,
+                flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+                device_id: *b"DEV00001",
+                counter: 7,
+            },
+            body: b"hi".to_vec(),
+        };
+        assert_eq!(
+            frame.to_string(),
+            "event dev=4445563030303031 ctr=7 ack body=2B"
+        );
+    }
+
+    #[test]
+    fn roundtrip_header_v1() {
+        let h = FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Command,
+            flags: Flags::new(0).unwrap(),
+            device_id: *b"ABCDEFGH",
+            counter: 123456,
+        };
+
+        let bytes = h.encode();
+        let parsed = FrameHeaderV1::decode(&bytes).unwrap();
+        assert_eq!(parsed, h);
+    }
+
+    #[test]
+    fn reject_too_short() {
+        let bytes = vec![0u8; HEADER_LEN_V1 - 1];
+        let err = FrameHeaderV1::decode(&bytes).unwrap_err();
+        assert_eq!(err, DecodeError::TooShort);
+    }
+
+    #[test]
+    fn reject_bad_magic() {
+        let mut bytes = vec![0u8; HEADER_LEN_V1];
+        bytes[0] = b'X';
+        bytes[1] = b'Y';
+        bytes[2] = VERSION_V1;
+        bytes[3] = MsgType::Event as u8;
+
+        let err = FrameHeaderV1::decode(&bytes).unwrap_err();
+        assert_eq!(err, DecodeError::BadMagic);
+    }
+
+    #[test]
+    fn reject_bad_version() {
+        let mut bytes = vec![0u8; HEADER_LEN_V1];
+        bytes[0] = b'P';
+        bytes[1] = b'P';
+        bytes[2] = 0x02;
+        bytes[3] = MsgType::Event as u8;
+
+        let err = FrameHeaderV1::decode(&bytes).unwrap_err();
+        assert_eq!(err, DecodeError::BadVersion(0x02));
+    }
+
+    #[test]
+    fn reject_unknown_msg_type() {
+        let mut bytes = vec![0u8; HEADER_LEN_V1];
+        bytes[0] = b'P';
+        bytes[1] = b'P';
+        bytes[2] = VERSION_V1;
+        bytes[3] = 0x99;
+
+        let err = FrameHeaderV1::decode(&bytes).unwrap_err();
+        assert_eq!(err, DecodeError::UnknownMsgType(0x99));
+    }
+
+    #[test]
+    fn reject_reserved_flags() {
+        let mut bytes = vec![0u8; HEADER_LEN_V1];
+        bytes[0] = b'P';
+        bytes[1] = b'P';
+        bytes[2] = VERSION_V1;
+        bytes[3] = MsgType::Event as u8;
+        bytes[4] = 0b0000_0010; // reserved bit1 set
+
+        let err = FrameHeaderV1::decode(&bytes).unwrap_err();
+        assert_eq!(err, DecodeError::ReservedFlags(0b0000_0010));
+    }
+
+    #[test]
+    fn flags_ack_required() {
+        let f = Flags::new(Flags::ACK_REQUIRED).unwrap();
+        assert!(f.ack_required());
+
+        let f2 = Flags::new(0).unwrap();
+        assert!(!f2.ack_required());
+    }
+
+    #[test]
+    fn roundtrip_frame_with_body() {
+        let header = FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Event,
+            flags: Flags::new(0).unwrap(),
+            device_id: *b"ABCDEFGH",
+            counter: 999,
+        };
+
+        let f = FrameV1 {
+            header,
+            body: vec![1, 2, 3, 4, 5].into(),
+        };
+
+        let bytes = f.encode();
+        let parsed = FrameV1::decode(&bytes).unwrap();
+        assert_eq!(parsed, f);
+    }
+
+    #[tokio::test]
+    async fn parts_and_vectored_writes_match_encode() {
+        let f = FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Event,
+                flags: Flags::new(0).unwrap(),
+                device_id: *b"ABCDEFGH",
+                counter: 999,
+            },
+            body: Body::from(vec![9; 300]),
+        };
+        let bytes = f.encode();
+        let (header, body) = f.encoded_parts();
+        assert_eq!(header, bytes[..HEADER_LEN_V1]);
+        assert_eq!(header.to_vec(), f.header.encode());
+        assert_eq!(body.as_ptr(), f.body.as_ptr());
+
+        let mut out = Vec::new();
+        f.write_vectored_to(&mut out).unwrap();
+        assert_eq!(out, bytes);
+        let mut out = Vec::new();
+        f.write_vectored_to_async(&mut out).await.unwrap();
+        assert_eq!(out, bytes);
+    }
+
+    #[test]
+    fn shared_bodies_decode_and_compare_as_owned_ones() {
+        let f = FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Command,
+                flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+                device_id: *b"ABCDEFGH",
+                counter: 5,
+            },
+            body: Body::from(b"payload"),
+        };
+        let input = Bytes::from(f.encode());
+        let shared = FrameV1B::decode_bytes(input.clone()).unwrap();
+        assert_eq!(shared, FrameV1B::from(f.clone()));
+        assert_eq!(shared.body.as_ptr(), input[HEADER_LEN_V1..].as_ptr());
+        assert_eq!(shared.encode(), f.encode());
+        assert_eq!(shared.to_string(), f.to_string());
+        assert_eq!(FrameV1::<Body>::from(shared.clone()), f);
+        let mut other = shared.clone();
+        other.body = Bytes::from_static(b"payloae");
+        assert_ne!(other, shared);
+        for len in 0..HEADER_LEN_V1 + 1 {
+            assert_eq!(
+                FrameV1B::decode_bytes(input.slice(..len)).map(FrameV1::<Body>::from),
+                FrameV1::decode(&input[..len])
+            );
+        }
+    }
+}
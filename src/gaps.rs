@@ -0,0 +1,438 @@
+//! Noticing frames that never arrived.
+//!
+//! Devices number their frames, so a jump in a device's counters means
+//! frames were lost, or are still on their way. A [`GapDetector`] is fed
+//! the header of every received frame and the passage of time, and keeps
+//! per device the ranges of counters not yet seen below the highest one
+//! that was. A range still open `horizon` after it appeared is reported
+//! [`GapEvent::Missing`]; a counter in a reported range that turns up
+//! after all is reported [`GapEvent::Recovered`]. It does no I/O.
+//!
+//! Tracking starts at the first counter heard from a device; anything
+//! before it is ignored. Counters are ordered in the configured
+//! [`CounterSpace`]; use [`CounterSpace::Serial`] for senders that wrap.
+//! Each device holds at most `max_gaps` ranges: past that the oldest is
+//! reported straight away, if it has not been, and forgotten, so a
+//! counter filling it later is ignored.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::FrameHeaderV1;
+use crate::clock::{Clock, SystemClock};
+use crate::counter::CounterSpace;
+
+#[derive(Debug, Clone)]
+pub struct GapConfig {
+    /// How long a gap may stay open, e.g. for reordering, before it is
+    /// reported.
+    pub horizon: Duration,
+    /// Open and reported gaps remembered per device.
+    pub max_gaps: usize,
+    /// Devices tracked at once; the least recently heard is evicted.
+    pub max_devices: usize,
+    pub space: CounterSpace,
+}
+
+impl Default for GapConfig {
+    fn default() -> Self {
+        GapConfig {
+            horizon: Duration::from_secs(5),
+            max_gaps: 64,
+            max_devices: 10_000,
+            space: CounterSpace::Linear,
+        }
+    }
+}
+
+/// Counters `from` to `to`, inclusive, that did not arrive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingRange {
+    pub device_id: [u8; 8],
+    pub from: u64,
+    pub to: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GapEvent {
+    Missing(MissingRange),
+    /// A counter reported missing arrived late.
+    Recovered {
+        device_id: [u8; 8],
+        counter: u64,
+    },
+}
+
+#[derive(Debug)]
+struct Gap {
+    /// Inclusive, as an offset like the key.
+    end: u64,
+    opened: Instant,
+    reported: bool,
+}
+
+#[derive(Debug)]
+struct Device {
+    /// The first counter heard; gaps are kept as offsets from it.
+    origin: u64,
+    top: u64,
+    gaps: BTreeMap<u64, Gap>,
+    heard: Instant,
+}
+
+impl Device {
+    fn range(&self, device_id: [u8; 8], start: u64, end: u64) -> MissingRange {
+        MissingRange {
+            device_id,
+            from: self.origin.wrapping_add(start),
+            to: self.origin.wrapping_add(end),
+        }
+    }
+}
+
+pub struct GapDetector {
+    clock: Arc<dyn Clock>,
+    config: GapConfig,
+    devices: HashMap<[u8; 8], Device>,
+    /// Reported by eviction, for the next poll.
+    evicted: Vec<GapEvent>,
+}
+
+impl GapDetector {
+    pub fn new(config: GapConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: GapConfig, clock: Arc<dyn Clock>) -> Self {
+        GapDetector {
+            clock,
+            config: GapConfig {
+                max_gaps: config.max_gaps.max(1),
+                max_devices: config.max_devices.max(1),
+                ..config
+            },
+            devices: HashMap::new(),
+            evicted: Vec::new(),
+        }
+    }
+
+    /// Note a frame's counter. Returns `Recovered` if it fills a gap
+    /// already reported.
+    pub fn on_frame(&mut self, header: &FrameHeaderV1) -> Option<GapEvent> {
+        let now = self.clock.now();
+        let device_id = header.device_id;
+        let Some(d) = self.devices.get_mut(&device_id) else {
+            if self.devices.len() >= self.config.max_devices {
+                self.evict_quietest();
+            }
+            let device = Device {
+                origin: header.counter,
+                top: 0,
+                gaps: BTreeMap::new(),
+                heard: now,
+            };
+            self.devices.insert(device_id, device);
+            return None;
+        };
+        d.heard = now;
+        let offset = self.config.space.behind(header.counter, d.origin)?;
+        if offset > d.top {
+            if offset > d.top + 1 {
+                let gap = Gap {
+                    end: offset - 1,
+                    opened: now,
+                    reported: false,
+                };
+                d.gaps.insert(d.top + 1, gap);
+                if d.gaps.len() > self.config.max_gaps
+                    && let Some((start, oldest)) = d.gaps.pop_first()
+                    && !oldest.reported
+                {
+                    let range = d.range(device_id, start, oldest.end);
+                    self.evicted.push(GapEvent::Missing(range));
+                }
+            }
+            d.top = offset;
+            return None;
+        }
+
+        let (&start, gap) = d.gaps.range_mut(..=offset).next_back()?;
+        if gap.end < offset {
+            return None;
+        }
+        let (end, opened, reported) = (gap.end, gap.opened, gap.reported);
+        if offset == start {
+            d.gaps.remove(&start);
+        } else {
+            gap.end = offset - 1;
+        }
+        if offset < end {
+            let rest = Gap {
+                end,
+                opened,
+                reported,
+            };
+            d.gaps.insert(offset + 1, rest);
+        }
+        reported.then_some(GapEvent::Recovered {
+            device_id,
+            counter: header.counter,
+        })
+    }
+
+    fn evict_quietest(&mut self) {
+        if let Some(id) = self
+            .devices
+            .iter()
+            .min_by_key(|(_, d)| d.heard)
+            .map(|(id, _)| *id)
+        {
+            self.devices.remove(&id);
+        }
+    }
+
+    /// Gaps that have stayed open for the horizon by `now`, by device id
+    /// and counter.
+    pub fn poll(&mut self, now: Instant) -> Vec<GapEvent> {
+        let mut events = std::mem::take(&mut self.evicted);
+        let mut ids: Vec<_> = self.devices.keys().copied().collect();
+        ids.sort();
+        for id in ids {
+            let d = self.devices.get_mut(&id).unwrap();
+            let mut due = Vec::new();
+            for (&start, gap) in &mut d.gaps {
+                if !gap.reported && now >= gap.opened + self.config.horizon {
+                    gap.reported = true;
+                    due.push((start, gap.end));
+                }
+            }
+            for (start, end) in due {
+                events.push(GapEvent::Missing(d.range(id, start, end)));
+            }
+        }
+        events
+    }
+
+    /// When [`GapDetector::poll`] next has something to report.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if !self.evicted.is_empty() {
+            return Some(self.clock.now());
+        }
+        self.devices
+            .values()
+            .flat_map(|d| d.gaps.values())
+            .filter(|g| !g.reported)
+            .map(|g| g.opened + self.config.horizon)
+            .min()
+    }
+
+    /// The ranges not yet seen from `device_id`, reported or not.
+    pub fn gaps(&self, device_id: [u8; 8]) -> Vec<MissingRange> {
+        let Some(d) = self.devices.get(&device_id) else {
+            return Vec::new();
+        };
+        d.gaps
+            .iter()
+            .map(|(&start, g)| d.range(device_id, start, g.end))
+            .collect()
+    }
+
+    pub fn forget(&mut self, device_id: [u8; 8]) {
+        self.devices.remove(&device_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::transport::tests::frame;
+    use std::collections::BTreeSet;
+
+    const DEV: [u8; 8] = *b"DEV00001";
+    const MS: Duration = Duration::from_millis(1);
+
+    fn header(counter: u64) -> FrameHeaderV1 {
+        frame(counter).header
+    }
+
+    fn missing(from: u64, to: u64) -> GapEvent {
+        GapEvent::Missing(MissingRange {
+            device_id: DEV,
+            from,
+            to,
+        })
+    }
+
+    #[test]
+    fn reports_after_the_horizon_and_corrects_late_arrivals() {
+        let clock = ManualClock::new();
+        let config = GapConfig {
+            horizon: 100 * MS,
+            ..GapConfig::default()
+        };
+        let mut gaps = GapDetector::with_clock(config, Arc::new(clock.clone()));
+        for c in [10, 11, 15, 13, 20] {
+            assert_eq!(gaps.on_frame(&header(c)), None);
+        }
+        assert_eq!(gaps.next_deadline(), Some(clock.now() + 100 * MS));
+        clock.advance(99 * MS);
+        assert_eq!(gaps.poll(clock.now()), []);
+        clock.advance(MS);
+        assert_eq!(
+            gaps.poll(clock.now()),
+            [missing(12, 12), missing(14, 14), missing(16, 19)]
+        );
+        assert_eq!(gaps.poll(clock.now()), [], "reported once");
+
+        assert_eq!(
+            gaps.on_frame(&header(17)),
+            Some(GapEvent::Recovered {
+                device_id: DEV,
+                counter: 17
+            })
+        );
+        assert_eq!(gaps.on_frame(&header(17)), None, "a duplicate");
+        assert_eq!(gaps.on_frame(&header(9)), None, "before tracking began");
+        assert_eq!(
+            gaps.gaps(DEV),
+            [
+                MissingRange {
+                    device_id: DEV,
+                    from: 12,
+                    to: 12
+                },
+                MissingRange {
+                    device_id: DEV,
+                    from: 14,
+                    to: 14
+                },
+                MissingRange {
+                    device_id: DEV,
+                    from: 16,
+                    to: 16
+                },
+                MissingRange {
+                    device_id: DEV,
+                    from: 18,
+                    to: 19
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn state_is_bounded() {
+        let clock = ManualClock::new();
+        let config = GapConfig {
+            max_gaps: 2,
+            ..GapConfig::default()
+        };
+        let mut gaps = GapDetector::with_clock(config, Arc::new(clock.clone()));
+        for c in [0, 2, 4, 6] {
+            gaps.on_frame(&header(c));
+        }
+        assert_eq!(gaps.gaps(DEV).len(), 2);
+        assert_eq!(gaps.poll(clock.now()), [missing(1, 1)], "evicted early");
+        assert_eq!(gaps.on_frame(&header(1)), None, "forgotten");
+    }
+
+    /// xorshift64
+    fn rng(seed: u64) -> impl FnMut() -> u64 {
+        let mut x = seed | 1;
+        move || {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        }
+    }
+
+    /// Shuffled, lossy sequences, some across the wrap: what is reported
+    /// missing is what was lost, less what came late.
+    #[test]
+    fn bookkeeping_over_shuffled_lossy_sequences() {
+        for seed in 1..=40u64 {
+            let mut next = rng(seed);
+            let (space, origin) = if seed % 2 == 0 {
+                (CounterSpace::Serial, u64::MAX - 100)
+            } else {
+                (CounterSpace::Linear, 1000)
+            };
+            let n = 300;
+            // the first and last are always delivered
+            let mut sent: Vec<u64> = (0..n).map(|i| origin.wrapping_add(i)).collect();
+            let mut lost = BTreeSet::new();
+            let mut late = Vec::new();
+            sent.retain(|&c| {
+                let keep =
+                    c == origin || c == origin.wrapping_add(n - 1) || !next().is_multiple_of(10);
+                if !keep {
+                    lost.insert(c);
+                    if next().is_multiple_of(3) {
+                        late.push(c);
+                    }
+                }
+                keep
+            });
+            // shuffle within runs of 8, leaving the first in place
+            for run in sent[1..].chunks_mut(8) {
+                for i in (1..run.len()).rev() {
+                    run.swap(i, (next() % (i as u64 + 1)) as usize);
+                }
+            }
+
+            let clock = ManualClock::new();
+            let config = GapConfig {
+                horizon: 50 * MS,
+                max_gaps: 1000,
+                space,
+                ..GapConfig::default()
+            };
+            let mut gaps = GapDetector::with_clock(config, Arc::new(clock.clone()));
+            let mut events = Vec::new();
+            for c in &sent {
+                events.extend(gaps.on_frame(&header(*c)));
+                clock.advance(MS);
+                events.extend(gaps.poll(clock.now()));
+            }
+            clock.advance(50 * MS);
+            events.extend(gaps.poll(clock.now()));
+            for c in &late {
+                events.extend(gaps.on_frame(&header(*c)));
+            }
+
+            let mut reported = BTreeSet::new();
+            let mut recovered = BTreeSet::new();
+            for e in events {
+                match e {
+                    GapEvent::Missing(r) => {
+                        let mut c = r.from;
+                        loop {
+                            assert!(reported.insert(c), "seed {seed}: {c} reported twice");
+                            if c == r.to {
+                                break;
+                            }
+                            c = c.wrapping_add(1);
+                        }
+                    }
+                    GapEvent::Recovered { counter, .. } => {
+                        assert!(recovered.insert(counter));
+                    }
+                }
+            }
+            assert_eq!(reported, lost, "seed {seed}");
+            assert_eq!(recovered, late.iter().copied().collect(), "seed {seed}");
+            let still: BTreeSet<_> = gaps
+                .gaps(DEV)
+                .iter()
+                .flat_map(|r| {
+                    let len = r.to.wrapping_sub(r.from);
+                    (0..=len).map(move |i| r.from.wrapping_add(i))
+                })
+                .collect();
+            assert_eq!(&still | &recovered, lost, "seed {seed}");
+        }
+    }
+}
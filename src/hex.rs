@@ -0,0 +1,34 @@
+//! Lowercase hex helpers shared by the text representations.
+
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Accepts upper- or lowercase digits; `None` on odd length or bad digits.
+pub(crate) fn decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        assert_eq!(encode(&[0xde, 0xad, 0x00, 0x01]), "dead0001");
+        assert_eq!(decode("DEAD0001"), Some(vec![0xde, 0xad, 0x00, 0x01]));
+    }
+
+    #[test]
+    fn reject_bad_input() {
+        assert_eq!(decode("abc"), None);
+        assert_eq!(decode("zz"), None);
+        assert_eq!(decode("+1"), None);
+    }
+}
@@ -0,0 +1,254 @@
+//! HTTP gateway (feature `http`).
+//!
+//! - `POST /frames` takes a JSON frame (see the `serde` representation) and
+//!   returns `{"frame": "<base64>", "len": n}`, or hands the frame to the
+//!   configured forwarder and returns 202.
+//! - `POST /decode` takes raw bytes (`application/octet-stream`) or base64
+//!   text and returns the decoded JSON frame. Decode errors map to 422 with
+//!   `{"error": {"kind", "message", "offset"}}`.
+
+use axum::Json;
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::FrameV1;
+
+pub const DEFAULT_BODY_LIMIT: usize = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct GatewayConfig {
+    /// Request bodies above this size are rejected with 413.
+    pub max_body_bytes: usize,
+    /// When set, `POST /frames` forwards instead of returning the bytes.
+    pub forward: Option<mpsc::Sender<FrameV1>>,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        GatewayConfig {
+            max_body_bytes: DEFAULT_BODY_LIMIT,
+            forward: None,
+        }
+    }
+}
+
+pub fn router(config: GatewayConfig) -> Router {
+    let limit = config.max_body_bytes;
+    Router::new()
+        .route("/frames", post(post_frames))
+        .route("/decode", post(post_decode))
+        .layer(DefaultBodyLimit::max(limit))
+        .with_state(config)
+}
+
+fn error(status: StatusCode, kind: &str, message: String, offset: Option<usize>) -> Response {
+    let body = json!({ "error": { "kind": kind, "message": message, "offset": offset } });
+    (status, Json(body)).into_response()
+}
+
+async fn post_frames(
+    State(config): State<GatewayConfig>,
+    payload: Result<Json<FrameV1>, JsonRejection>,
+) -> Response {
+    let frame = match payload {
+        Ok(Json(frame)) => frame,
+        Err(rej) => return error(rej.status(), "invalid_frame", rej.body_text(), None),
+    };
+
+    match config.forward {
+        Some(tx) => match tx.send(frame).await {
+            Ok(()) => (StatusCode::ACCEPTED, Json(json!({ "forwarded": true }))).into_response(),
+            Err(_) => error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "forwarder_closed",
+                "transport is not accepting frames".to_string(),
+                None,
+            ),
+        },
+        None => {
+            let bytes = frame.encode();
+            Json(json!({ "frame": BASE64.encode(&bytes), "len": bytes.len() })).into_response()
+        }
+    }
+}
+
+async fn post_decode(headers: HeaderMap, body: Bytes) -> Response {
+    let raw = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/octet-stream"));
+
+    let bytes = if raw {
+        body.to_vec()
+    } else {
+        let text = String::from_utf8_lossy(&body);
+        match BASE64.decode(text.trim()) {
+            Ok(b) => b,
+            Err(e) => {
+                return error(StatusCode::BAD_REQUEST, "bad_base64", e.to_string(), None);
+            }
+        }
+    };
+
+    match FrameV1::decode(&bytes) {
+        Ok(frame) => Json(frame).into_response(),
+        Err(e) => error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            e.kind(),
+            e.to_string(),
+            e.offset(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Flags, FrameHeaderV1, MsgType, VERSION_V1};
+    use axum::body::Body;
+    use axum::http::Request;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    fn sample() -> FrameV1 {
+        FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Command,
+                flags: Flags::new(0).unwrap(),
+                device_id: [0, 0, 0, 0, 0, 0, 0, 9],
+                counter: 3,
+            },
+            body: vec![1, 2, 3].into(),
+        }
+    }
+
+    async fn call(
+        app: Router,
+        path: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> (StatusCode, Value) {
+        let req = Request::post(path)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap();
+        let resp = app.oneshot(req).await.unwrap();
+        let status = resp.status();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn encode_frame_from_json() {
+        let doc = serde_json::to_vec(&sample()).unwrap();
+        let (status, body) = call(
+            router(GatewayConfig::default()),
+            "/frames",
+            "application/json",
+            doc,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["frame"], BASE64.encode(sample().encode()));
+        assert_eq!(body["len"], 24);
+    }
+
+    #[tokio::test]
+    async fn forward_frame() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let config = GatewayConfig {
+            forward: Some(tx),
+            ..GatewayConfig::default()
+        };
+        let doc = serde_json::to_vec(&sample()).unwrap();
+        let (status, _) = call(router(config), "/frames", "application/json", doc).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(rx.recv().await, Some(sample()));
+    }
+
+    #[tokio::test]
+    async fn decode_raw_and_base64() {
+        let bytes = sample().encode();
+        let (status, body) = call(
+            router(GatewayConfig::default()),
+            "/decode",
+            "application/octet-stream",
+            bytes.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::to_value(sample()).unwrap());
+
+        let text = BASE64.encode(&bytes).into_bytes();
+        let (status, body) = call(
+            router(GatewayConfig::default()),
+            "/decode",
+            "text/plain",
+            text,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["counter"], 3);
+    }
+
+    #[tokio::test]
+    async fn decode_error_maps_to_422() {
+        let mut bytes = sample().encode();
+        bytes[2] = 0x07;
+        let (status, body) = call(
+            router(GatewayConfig::default()),
+            "/decode",
+            "application/octet-stream",
+            bytes,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"]["kind"], "bad_version");
+        assert_eq!(body["error"]["offset"], 2);
+    }
+
+    #[tokio::test]
+    async fn invalid_json_frame_is_rejected() {
+        let doc = br#"{"msg_type":"event","device_id":"01","counter":1}"#.to_vec();
+        let (status, body) = call(
+            router(GatewayConfig::default()),
+            "/frames",
+            "application/json",
+            doc,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"]["kind"], "invalid_frame");
+    }
+
+    #[tokio::test]
+    async fn body_limit_enforced() {
+        let config = GatewayConfig {
+            max_body_bytes: 16,
+            ..GatewayConfig::default()
+        };
+        let (status, _) = call(
+            router(config),
+            "/decode",
+            "application/octet-stream",
+            vec![0; 64],
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
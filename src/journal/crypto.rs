@@ -0,0 +1,468 @@
+//! Journals encrypted at rest.
+//!
+//! An encrypted journal starts with [`ENCRYPTED_MAGIC`](super::ENCRYPTED_MAGIC) and a header
+//! naming the [`KeyStore`] entry and key id its key is under, and a random
+//! salt. Its records are framed as in a `PPJ2` journal, each entry sealed
+//! on its own as `nonce(12) ciphertext tag(16)` with ChaCha20-Poly1305,
+//! under a key derived with HKDF-SHA256 from the stored key and the salt,
+//! and a random nonce. The record's offset in the file is sealed with it as
+//! associated data, so a record moved, copied or shifted by a deletion
+//! fails to open. Otherwise records stand alone: an encrypted journal is
+//! read, resumed from an offset and recovered after damage as a plain one
+//! is, and a record that fails to open is skipped as one failing its CRC
+//! is. Version 1 journals, whose records are not bound to their offsets,
+//! are still read and appended to.
+//!
+//! The key id is the store's [`KeyStore::key_id`] when the file is
+//! created; a file keeps its key when the store's current one changes, so
+//! the store must keep older keys for as long as their files are read.
+
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+
+use super::segments::JournalDirReader;
+use super::{
+    ENCRYPTED_VERSION, EncryptedHeader, JournalError, JournalReader, JournalWriter, Keys,
+    RotationConfig,
+};
+use crate::auth::KeyStore;
+
+const INFO: &[u8] = b"pipproto journal v1";
+const NONCE: usize = 12;
+
+/// Where encrypted journals get their keys: the keys a [`KeyStore`] keeps
+/// for `name`, as it would for a device id.
+#[derive(Clone)]
+pub struct JournalKeys {
+    store: Arc<dyn KeyStore>,
+    name: [u8; 8],
+}
+
+impl JournalKeys {
+    pub fn new(store: Arc<dyn KeyStore>, name: [u8; 8]) -> Self {
+        JournalKeys { store, name }
+    }
+
+    fn locked(&self, key_id: u32) -> JournalError {
+        JournalError::Locked {
+            key: self.name,
+            key_id,
+        }
+    }
+
+    /// The header of a new journal, under the current key.
+    pub(super) fn fresh(&self) -> Result<(EncryptedHeader, Cipher), JournalError> {
+        let key_id = self.store.key_id(&self.name).ok_or(self.locked(0))?;
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let header = EncryptedHeader {
+            version: ENCRYPTED_VERSION,
+            key: self.name,
+            key_id,
+            salt,
+        };
+        Ok((header, self.unlock(&header)?))
+    }
+
+    pub(super) fn unlock(&self, header: &EncryptedHeader) -> Result<Cipher, JournalError> {
+        if header.key != self.name {
+            return Err(JournalError::Locked {
+                key: header.key,
+                key_id: header.key_id,
+            });
+        }
+        let key = self
+            .store
+            .key_by_id(&self.name, header.key_id)
+            .ok_or(self.locked(header.key_id))?;
+        let mut derived = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&header.salt), &key)
+            .expand(INFO, &mut derived)
+            .unwrap();
+        Ok(Cipher {
+            aead: ChaCha20Poly1305::new(&derived.into()),
+            bound: header.version >= 2,
+        })
+    }
+}
+
+/// Seals and opens the entries of one journal file.
+#[derive(Clone)]
+pub(super) struct Cipher {
+    aead: ChaCha20Poly1305,
+    /// Whether entries are bound to their offsets, as from version 2 on.
+    bound: bool,
+}
+
+impl Cipher {
+    /// Seal the entry of the record starting at `offset`.
+    pub(super) fn seal(&self, entry: &[u8], offset: u64) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE];
+        OsRng.fill_bytes(&mut nonce);
+        let aad = offset.to_be_bytes();
+        let payload = Payload {
+            msg: entry,
+            aad: if self.bound { &aad } else { &[] },
+        };
+        let sealed = self.aead.encrypt(Nonce::from_slice(&nonce), payload);
+        [&nonce[..], &sealed.unwrap()].concat()
+    }
+
+    /// `None` if the entry was tampered with, damaged, or sealed for
+    /// another offset.
+    pub(super) fn open(&self, sealed: &[u8], offset: u64) -> Option<Vec<u8>> {
+        let (nonce, sealed) = sealed.split_at_checked(NONCE)?;
+        let aad = offset.to_be_bytes();
+        let payload = Payload {
+            msg: sealed,
+            aad: if self.bound { &aad } else { &[] },
+        };
+        self.aead.decrypt(Nonce::from_slice(nonce), payload).ok()
+    }
+}
+
+impl JournalReader<BufReader<std::fs::File>> {
+    /// Open a journal encrypted under `keys`, or a plain one.
+    pub fn open_encrypted(
+        path: impl AsRef<Path>,
+        keys: &JournalKeys,
+    ) -> Result<Self, JournalError> {
+        JournalReader::open_with(path.as_ref(), &Keys::Store(keys.clone()))
+    }
+
+    /// As [`JournalReader::open_at`], for [`JournalReader::open_encrypted`].
+    pub fn open_encrypted_at(
+        path: impl AsRef<Path>,
+        keys: &JournalKeys,
+        offset: u64,
+    ) -> Result<Self, JournalError> {
+        JournalReader::open_encrypted(path, keys)?.resume_at(offset)
+    }
+}
+
+impl<R: Read> JournalReader<R> {
+    /// Read a journal encrypted under `keys`, or a plain one.
+    pub fn with_keys(reader: R, keys: &JournalKeys) -> Result<Self, JournalError> {
+        JournalReader::start(reader, &Keys::Store(keys.clone()))
+    }
+}
+
+impl JournalWriter {
+    /// As [`JournalWriter::open`], encrypting under `keys`: a new journal
+    /// under their current key, an encrypted one under the key it has.
+    /// Appending to a plain journal fails.
+    pub fn open_encrypted(path: impl AsRef<Path>, keys: JournalKeys) -> Result<Self, JournalError> {
+        JournalWriter::open_with(path.as_ref(), Keys::Store(keys))
+    }
+
+    /// As [`JournalWriter::open_dir`], encrypting new segments under the
+    /// current key of `keys`. A plain newest segment is left as it is and
+    /// a new one started.
+    pub fn open_dir_encrypted(
+        dir: impl AsRef<Path>,
+        config: RotationConfig,
+        keys: JournalKeys,
+    ) -> Result<Self, JournalError> {
+        JournalWriter::open_dir_with(dir.as_ref(), config, Keys::Store(keys))
+    }
+}
+
+impl JournalDirReader {
+    /// Read segments encrypted under `keys` as well as plain ones.
+    pub fn with_keys(mut self, keys: JournalKeys) -> Self {
+        self.keys = Keys::Store(keys);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::{ENCRYPTED_MAGIC, RECORD_MARK};
+    use crate::sink::{FrameRecord, FrameSink};
+    use crate::transport::tests::frame;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const NAME: [u8; 8] = *b"JOURNAL1";
+
+    /// Keys by id, the current one set by the test.
+    struct Ring(Mutex<(u32, HashMap<u32, Vec<u8>>)>);
+
+    impl Ring {
+        fn new() -> Arc<Self> {
+            let keys = HashMap::from([(1, vec![1; 32]), (2, vec![2; 32])]);
+            Arc::new(Ring(Mutex::new((1, keys))))
+        }
+
+        fn use_key(&self, key_id: u32) {
+            self.0.lock().unwrap().0 = key_id;
+        }
+
+        fn forget(&self, key_id: u32) {
+            self.0.lock().unwrap().1.remove(&key_id);
+        }
+    }
+
+    impl KeyStore for Ring {
+        fn key(&self, device_id: &[u8; 8]) -> Option<Vec<u8>> {
+            self.key_by_id(device_id, self.key_id(device_id)?)
+        }
+
+        fn key_id(&self, device_id: &[u8; 8]) -> Option<u32> {
+            (*device_id == NAME).then(|| self.0.lock().unwrap().0)
+        }
+
+        fn key_by_id(&self, device_id: &[u8; 8], key_id: u32) -> Option<Vec<u8>> {
+            let keys = self.0.lock().unwrap();
+            (*device_id == NAME).then(|| keys.1.get(&key_id).cloned())?
+        }
+    }
+
+    fn record(counter: u64) -> FrameRecord {
+        let mut r = FrameRecord::rx(frame(counter));
+        r.timestamp = UNIX_EPOCH + Duration::from_secs(1_700_000_000 + counter);
+        r
+    }
+
+    fn read_all<R: Read>(reader: JournalReader<R>) -> (Vec<u64>, Vec<String>) {
+        let (mut counters, mut errors) = (Vec::new(), Vec::new());
+        for record in reader {
+            match record {
+                Ok(r) => counters.push(r.frame().unwrap().header.counter),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        (counters, errors)
+    }
+
+    #[test]
+    fn roundtrips_and_reopens() {
+        let ring = Ring::new();
+        let keys = JournalKeys::new(ring.clone(), NAME);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        let mut journal = JournalWriter::open_encrypted(&path, keys.clone()).unwrap();
+        let written: Vec<_> = (1..=10).map(record).collect();
+        for r in &written {
+            journal.record(r).unwrap();
+        }
+        journal.flush().unwrap();
+        drop(journal);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes[..4], ENCRYPTED_MAGIC);
+        // no frame in the clear
+        let encoded = written[3].frame().unwrap().encode();
+        assert!(!bytes.windows(encoded.len()).any(|w| w == encoded));
+
+        let read: Vec<_> = JournalReader::open_encrypted(&path, &keys)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, written);
+        assert!(matches!(
+            JournalReader::open(&path),
+            Err(JournalError::Locked {
+                key: NAME,
+                key_id: 1
+            })
+        ));
+
+        // appended to under its own key, after the store moved on
+        ring.use_key(2);
+        let mut journal = JournalWriter::open_encrypted(&path, keys.clone()).unwrap();
+        journal.record(&record(11)).unwrap();
+        journal.flush().unwrap();
+        let mut reader = JournalReader::open_encrypted(&path, &keys).unwrap();
+        assert_eq!(reader.by_ref().take(10).count(), 10);
+        let (counters, _) =
+            read_all(JournalReader::open_encrypted_at(&path, &keys, reader.offset()).unwrap());
+        assert_eq!(counters, [11]);
+
+        ring.forget(1);
+        assert!(matches!(
+            JournalReader::open_encrypted(&path, &keys),
+            Err(JournalError::Locked { key_id: 1, .. })
+        ));
+        assert!(JournalWriter::open_encrypted(dir.path().join("other.log"), keys).is_ok());
+    }
+
+    #[test]
+    fn tampering_is_skipped_and_reported() {
+        let keys = JournalKeys::new(Ring::new(), NAME);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        let mut journal = JournalWriter::open_encrypted(&path, keys.clone()).unwrap();
+        for c in 1..=10 {
+            journal.record(&record(c)).unwrap();
+        }
+        journal.flush().unwrap();
+        let clean = std::fs::read(&path).unwrap();
+        let starts: Vec<_> = clean
+            .windows(RECORD_MARK.len())
+            .enumerate()
+            .filter(|(_, w)| *w == RECORD_MARK)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(starts.len(), 10);
+        let record_len = starts[1] - starts[0];
+
+        // a flipped ciphertext byte, with the CRC made to match
+        let mut bytes = clean.clone();
+        let at = starts[4] + RECORD_MARK.len();
+        bytes[at + 4 + NONCE + 5] ^= 1;
+        let crc_at = at + record_len - RECORD_MARK.len() - 4;
+        let crc = crate::crc::crc32(&bytes[at..crc_at]);
+        bytes[crc_at..crc_at + 4].copy_from_slice(&crc.to_be_bytes());
+        let (counters, errors) = read_all(JournalReader::with_keys(&bytes[..], &keys).unwrap());
+        assert_eq!(counters, [1, 2, 3, 4, 6, 7, 8, 9, 10]);
+        assert_eq!(
+            errors,
+            [format!(
+                "skipped {record_len} damaged bytes at offset {}",
+                starts[4]
+            )]
+        );
+
+        // a flipped nonce byte, and a CRC left to fail
+        let mut bytes = clean;
+        bytes[starts[7] + 9] ^= 0x80;
+        let (counters, errors) = read_all(JournalReader::with_keys(&bytes[..], &keys).unwrap());
+        assert_eq!(counters, [1, 2, 3, 4, 5, 6, 7, 9, 10]);
+        assert_eq!(errors.len(), 1);
+
+        // another store's key under the same name opens nothing
+        let other: HashMap<[u8; 8], Vec<u8>> = HashMap::from([(NAME, vec![9; 32])]);
+        let other = JournalKeys::new(Arc::new(other), NAME);
+        assert!(matches!(
+            JournalReader::open_encrypted(&path, &other),
+            Err(JournalError::Locked { key_id: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn moved_records_do_not_open() {
+        let keys = JournalKeys::new(Ring::new(), NAME);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        let mut journal = JournalWriter::open_encrypted(&path, keys.clone()).unwrap();
+        for c in 1..=6 {
+            journal.record(&record(c)).unwrap();
+        }
+        journal.flush().unwrap();
+        let clean = std::fs::read(&path).unwrap();
+        let starts: Vec<_> = clean
+            .windows(RECORD_MARK.len())
+            .enumerate()
+            .filter(|(_, w)| *w == RECORD_MARK)
+            .map(|(i, _)| i)
+            .collect();
+        let record_len = starts[1] - starts[0];
+        let (third, fourth) = (starts[2], starts[3]);
+
+        // the third and fourth records swapped, each intact on its own
+        let mut bytes = clean.clone();
+        bytes[third..fourth + record_len].rotate_left(record_len);
+        let (counters, errors) = read_all(JournalReader::with_keys(&bytes[..], &keys).unwrap());
+        assert_eq!(counters, [1, 2, 5, 6]);
+        assert_eq!(
+            errors,
+            [format!(
+                "skipped {} damaged bytes at offset {third}",
+                2 * record_len
+            )]
+        );
+
+        // the second record copied over the fifth
+        let mut bytes = clean;
+        bytes.copy_within(starts[1]..starts[1] + record_len, starts[4]);
+        let (counters, errors) = read_all(JournalReader::with_keys(&bytes[..], &keys).unwrap());
+        assert_eq!(counters, [1, 2, 3, 4, 6]);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn version_1_journals_are_still_read() {
+        let keys = JournalKeys::new(Ring::new(), NAME);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        drop(JournalWriter::open_encrypted(&path, keys.clone()).unwrap());
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[ENCRYPTED_MAGIC.len()] = 1;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut journal = JournalWriter::open_encrypted(&path, keys.clone()).unwrap();
+        for c in 1..=3 {
+            journal.record(&record(c)).unwrap();
+        }
+        journal.flush().unwrap();
+        let (counters, errors) = read_all(JournalReader::open_encrypted(&path, &keys).unwrap());
+        assert_eq!((counters, errors.len()), (vec![1, 2, 3], 0));
+
+        // its records were sealed without their offsets
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[ENCRYPTED_MAGIC.len()] = 2;
+        let (counters, _) = read_all(JournalReader::with_keys(&bytes[..], &keys).unwrap());
+        assert!(counters.is_empty());
+    }
+
+    #[test]
+    fn directories_mix_plain_and_encrypted_segments() {
+        let keys = JournalKeys::new(Ring::new(), NAME);
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = JournalWriter::open_dir(dir.path(), RotationConfig::default()).unwrap();
+        for c in 1..=3 {
+            journal.record(&record(c)).unwrap();
+        }
+        journal.flush().unwrap();
+        drop(journal);
+        let config = RotationConfig {
+            max_bytes: Some(300),
+            ..RotationConfig::default()
+        };
+        let mut journal =
+            JournalWriter::open_dir_encrypted(dir.path(), config, keys.clone()).unwrap();
+        for c in 4..=9 {
+            journal.record(&record(c)).unwrap();
+        }
+        journal.flush().unwrap();
+        drop(journal);
+        let mut journal = JournalWriter::open_dir(dir.path(), RotationConfig::default()).unwrap();
+        journal.record(&record(10)).unwrap();
+        journal.flush().unwrap();
+
+        let mut encrypted = 0;
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let bytes = std::fs::read(entry.unwrap().path()).unwrap();
+            encrypted += usize::from(bytes[..4] == ENCRYPTED_MAGIC);
+        }
+        assert!(encrypted >= 2, "{encrypted} encrypted segments");
+
+        let read: Vec<_> = JournalDirReader::open(dir.path())
+            .unwrap()
+            .with_keys(keys.clone())
+            .map(|r| r.unwrap().frame().unwrap().header.counter)
+            .collect();
+        assert_eq!(read, (1..=10).collect::<Vec<_>>());
+        // without the keys: the plain segments, and an error for each other
+        let reader = JournalDirReader::open(dir.path()).unwrap();
+        let (plain, locked): (Vec<_>, Vec<_>) = reader.partition(|r| r.is_ok());
+        assert_eq!(plain.len(), 4);
+        assert_eq!(locked.len(), encrypted);
+
+        let mut reader = JournalDirReader::open(dir.path()).unwrap().with_keys(keys);
+        let from = record(6).timestamp;
+        assert!(!reader.seek_device(frame(0).header.device_id, from).unwrap());
+        let first = reader.next().unwrap().unwrap();
+        assert!(first.timestamp <= from);
+    }
+}
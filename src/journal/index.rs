@@ -0,0 +1,462 @@
+//! Sidecar indexes for seeking in a journal by device and time.
+//!
+//! `journal.idx`, next to `journal.log`, maps each device and time bucket
+//! to the offset of the device's first record in that bucket, so a reader
+//! can start close to a device's records from some time on and scan
+//! forward from there. It is laid out as
+//!
+//! ```text
+//! magic "PPX" version(1) bucket_ms(8) covered(8) count(4)
+//! count × (device_id(8) bucket(8) offset(8)) crc32(4)
+//! ```
+//!
+//! where `covered` is how much of the journal was indexed; records past it
+//! are scanned instead. An index that is missing, corrupt, of another
+//! version or covering more than the journal holds is not used, and readers
+//! fall back to scanning from the start. [`JournalIndex::load_or_build`]
+//! brings such an index back up to date.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{JOURNAL_MAGIC, JournalError, JournalReader};
+use crate::crc::crc32;
+use crate::sink::FrameRecord;
+
+pub const INDEX_MAGIC: [u8; 3] = *b"PPX";
+pub const INDEX_VERSION: u8 = 1;
+pub const DEFAULT_BUCKET: Duration = Duration::from_secs(60);
+
+const INDEX_HEAD: usize = 3 + 1 + 8 + 8 + 4;
+const INDEX_ENTRY: usize = 8 + 8 + 8;
+
+#[derive(Debug)]
+pub enum IndexError {
+    Io(io::Error),
+    /// The file is not an index.
+    BadMagic,
+    Version(u8),
+    /// The index failed its CRC or does not parse.
+    Corrupt,
+    /// The index covers more than the journal holds, so it is of another,
+    /// or a truncated, journal.
+    Stale {
+        covered: u64,
+        len: u64,
+    },
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexError::Io(e) => write!(f, "io error: {e}"),
+            IndexError::BadMagic => write!(f, "not a journal index"),
+            IndexError::Version(v) => write!(f, "unsupported index version {v}"),
+            IndexError::Corrupt => write!(f, "corrupt index"),
+            IndexError::Stale { covered, len } => {
+                write!(f, "index covers {covered} bytes of a {len} byte journal")
+            }
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+impl From<io::Error> for IndexError {
+    fn from(e: io::Error) -> Self {
+        IndexError::Io(e)
+    }
+}
+
+/// Where each device's records start, bucket by bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalIndex {
+    bucket_ms: u64,
+    covered: u64,
+    /// `(device_id, bucket)` to the offset of its first record.
+    entries: BTreeMap<([u8; 8], u64), u64>,
+}
+
+impl JournalIndex {
+    /// An index of a journal with no records yet.
+    pub fn new(bucket: Duration) -> Self {
+        JournalIndex {
+            bucket_ms: (bucket.as_millis() as u64).max(1),
+            covered: JOURNAL_MAGIC.len() as u64,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// The sidecar of `journal`.
+    pub fn path_for(journal: &Path) -> PathBuf {
+        journal.with_extension("idx")
+    }
+
+    pub fn bucket(&self) -> Duration {
+        Duration::from_millis(self.bucket_ms)
+    }
+
+    /// Bytes of the journal indexed.
+    pub fn covered(&self) -> u64 {
+        self.covered
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn bucket_of(&self, at: SystemTime) -> u64 {
+        at.duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+            / self.bucket_ms
+    }
+
+    /// Note `record`, read from or written to `offset` and ending at `end`.
+    pub fn add(&mut self, record: &FrameRecord, offset: u64, end: u64) {
+        if let Some(frame) = record.frame() {
+            let key = (frame.header.device_id, self.bucket_of(record.timestamp));
+            self.entries.entry(key).or_insert(offset);
+        }
+        self.covered = self.covered.max(end);
+    }
+
+    /// The offset to scan from for `device_id`'s records from `from` on:
+    /// its first indexed record since then, or the end of what is indexed.
+    pub fn start(&self, device_id: [u8; 8], from: SystemTime) -> u64 {
+        let first = (device_id, self.bucket_of(from));
+        self.entries
+            .range(first..=(device_id, u64::MAX))
+            .map(|(_, &offset)| offset)
+            .min()
+            .unwrap_or(self.covered)
+    }
+
+    /// Index the records of `journal` from `self.covered()` on.
+    fn extend(&mut self, journal: &Path) -> Result<(), JournalError> {
+        let mut reader = JournalReader::open_at(journal, self.covered)?;
+        loop {
+            let offset = reader.offset();
+            match reader.next() {
+                Some(Ok(record)) => self.add(&record, offset, reader.offset()),
+                Some(Err(JournalError::Skipped { .. })) => {}
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Index `journal` by reading all of it.
+    pub fn build(journal: &Path, bucket: Duration) -> Result<Self, JournalError> {
+        let mut index = JournalIndex::new(bucket);
+        index.extend(journal)?;
+        Ok(index)
+    }
+
+    /// The sidecar of `journal`, if it can be used for it.
+    pub fn for_journal(journal: &Path) -> Result<Self, IndexError> {
+        let index = JournalIndex::load(&JournalIndex::path_for(journal))?;
+        let len = fs::metadata(journal)?.len();
+        if index.covered > len {
+            return Err(IndexError::Stale {
+                covered: index.covered,
+                len,
+            });
+        }
+        Ok(index)
+    }
+
+    /// The sidecar of `journal`, extended over records appended since it
+    /// was saved, or rebuilt if it cannot be used or has another bucket
+    /// width. Saved again if it changed.
+    pub fn load_or_build(journal: &Path, bucket: Duration) -> Result<Self, JournalError> {
+        let (mut index, saved) = match JournalIndex::for_journal(journal) {
+            Ok(index) if index.bucket() == bucket => {
+                let covered = index.covered;
+                (index, Some(covered))
+            }
+            _ => (JournalIndex::new(bucket), None),
+        };
+        index.extend(journal)?;
+        if saved != Some(index.covered) {
+            index.save(&JournalIndex::path_for(journal))?;
+        }
+        Ok(index)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, IndexError> {
+        JournalIndex::decode(&fs::read(path)?)
+    }
+
+    /// Write the index to `path` through a temporary file. It is not
+    /// synced: an index lost in a crash is rebuilt.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, self.encode())?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(INDEX_HEAD + self.entries.len() * INDEX_ENTRY + 4);
+        out.extend_from_slice(&INDEX_MAGIC);
+        out.push(INDEX_VERSION);
+        out.extend_from_slice(&self.bucket_ms.to_be_bytes());
+        out.extend_from_slice(&self.covered.to_be_bytes());
+        out.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for ((device_id, bucket), offset) in &self.entries {
+            out.extend_from_slice(device_id);
+            out.extend_from_slice(&bucket.to_be_bytes());
+            out.extend_from_slice(&offset.to_be_bytes());
+        }
+        out.extend_from_slice(&crc32(&out).to_be_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, IndexError> {
+        if bytes.get(..3) != Some(&INDEX_MAGIC[..]) {
+            return Err(IndexError::BadMagic);
+        }
+        match bytes.get(3) {
+            Some(&INDEX_VERSION) => {}
+            Some(&v) => return Err(IndexError::Version(v)),
+            None => return Err(IndexError::Corrupt),
+        }
+        let (body, crc) = bytes
+            .split_last_chunk::<4>()
+            .filter(|(body, _)| body.len() >= INDEX_HEAD)
+            .ok_or(IndexError::Corrupt)?;
+        if crc32(body) != u32::from_be_bytes(*crc) {
+            return Err(IndexError::Corrupt);
+        }
+        let u64_at = |at: usize| u64::from_be_bytes(body[at..at + 8].try_into().unwrap());
+        let count = u32::from_be_bytes(body[20..24].try_into().unwrap()) as usize;
+        if body.len() != INDEX_HEAD + count * INDEX_ENTRY {
+            return Err(IndexError::Corrupt);
+        }
+        let entries = body[INDEX_HEAD..]
+            .chunks_exact(INDEX_ENTRY)
+            .map(|e| {
+                let device_id = e[..8].try_into().unwrap();
+                let bucket = u64::from_be_bytes(e[8..16].try_into().unwrap());
+                let offset = u64::from_be_bytes(e[16..].try_into().unwrap());
+                ((device_id, bucket), offset)
+            })
+            .collect();
+        Ok(JournalIndex {
+            bucket_ms: u64_at(4).max(1),
+            covered: u64_at(12),
+            entries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::{JournalDirReader, JournalWriter, RotationConfig};
+    use crate::sink::FrameSink;
+    use crate::transport::tests::frame;
+    use std::cell::Cell;
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::rc::Rc;
+
+    /// On a minute.
+    const T0: u64 = 1_700_000_040;
+
+    fn record(device: u8, secs: u64) -> FrameRecord {
+        let mut f = frame(secs);
+        f.header.device_id[7] = device;
+        let mut r = FrameRecord::rx(f);
+        r.timestamp = UNIX_EPOCH + Duration::from_secs(T0 + secs);
+        r
+    }
+
+    fn device(n: u8) -> [u8; 8] {
+        let mut d = *b"DEV00001";
+        d[7] = n;
+        d
+    }
+
+    /// 20 devices each sending every 5s for an hour.
+    fn generate(journal: &mut JournalWriter) {
+        for secs in (0..3600).step_by(5) {
+            for d in 0..20 {
+                journal.record(&record(d, secs)).unwrap();
+            }
+        }
+        journal.flush().unwrap();
+    }
+
+    /// Counts the bytes read through it.
+    struct Counting<R> {
+        inner: R,
+        read: Rc<Cell<u64>>,
+    }
+
+    impl<R: Read> Read for Counting<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.read.set(self.read.get() + n as u64);
+            Ok(n)
+        }
+    }
+
+    impl<R: Seek> Seek for Counting<R> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    /// `device`'s records in the minute from `from`.
+    fn minute<I>(records: I, device_id: [u8; 8], from: SystemTime) -> Vec<u64>
+    where
+        I: Iterator<Item = Result<FrameRecord, JournalError>>,
+    {
+        let until = from + Duration::from_secs(60);
+        records
+            .map(Result::unwrap)
+            .skip_while(|r| r.timestamp < from)
+            .take_while(|r| r.timestamp < until)
+            .filter(|r| r.frame().unwrap().header.device_id == device_id)
+            .map(|r| r.frame().unwrap().header.counter)
+            .collect()
+    }
+
+    #[test]
+    fn seeking_reads_a_small_part_of_the_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        let mut journal = JournalWriter::open(&path)
+            .unwrap()
+            .with_index(DEFAULT_BUCKET)
+            .unwrap();
+        generate(&mut journal);
+        let len = fs::metadata(&path).unwrap().len();
+        let index = JournalIndex::for_journal(&path).unwrap();
+        assert_eq!(index.covered(), len);
+        assert_eq!(index, JournalIndex::build(&path, DEFAULT_BUCKET).unwrap());
+        assert_eq!(index.len(), 20 * 60);
+
+        let from = UNIX_EPOCH + Duration::from_secs(T0 + 1800);
+        let read = Rc::new(Cell::new(0));
+        let file = Counting {
+            inner: File::open(&path).unwrap(),
+            read: read.clone(),
+        };
+        let mut reader = JournalReader::new(io::BufReader::new(file)).unwrap();
+        assert!(reader.seek_device(Some(&index), device(7), from).unwrap());
+        let found = minute(&mut reader, device(7), from);
+        assert_eq!(found, (1800..1860).step_by(5).collect::<Vec<_>>());
+        // a minute of an hour, and what the buffer reads ahead
+        assert!(read.get() * 20 < len, "read {} of {len}", read.get());
+
+        let all = minute(JournalReader::open(&path).unwrap(), device(7), from);
+        assert_eq!(found, all);
+    }
+
+    #[test]
+    fn unusable_indexes_fall_back_to_a_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        let mut journal = JournalWriter::open(&path)
+            .unwrap()
+            .with_index(DEFAULT_BUCKET)
+            .unwrap();
+        generate(&mut journal);
+        drop(journal);
+        let idx = JournalIndex::path_for(&path);
+        let index = JournalIndex::load(&idx).unwrap();
+
+        let mut bytes = index.encode();
+        bytes[3] = 2;
+        assert!(matches!(
+            JournalIndex::decode(&bytes),
+            Err(IndexError::Version(2))
+        ));
+        let mut bytes = index.encode();
+        bytes[30] ^= 1;
+        assert!(matches!(
+            JournalIndex::decode(&bytes),
+            Err(IndexError::Corrupt)
+        ));
+        assert!(matches!(
+            JournalIndex::decode(b"PPJ1"),
+            Err(IndexError::BadMagic)
+        ));
+
+        // cut short, as a crash might
+        let len = fs::metadata(&path).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len / 2)
+            .unwrap();
+        assert!(matches!(
+            JournalIndex::for_journal(&path),
+            Err(IndexError::Stale { .. })
+        ));
+        let from = UNIX_EPOCH + Duration::from_secs(T0 + 60);
+        let mut reader = JournalReader::open(&path).unwrap();
+        reader.next();
+        assert!(!reader.seek_device(Some(&index), device(3), from).unwrap());
+        assert_eq!(reader.offset(), JOURNAL_MAGIC.len() as u64);
+        assert_eq!(minute(reader, device(3), from).len(), 12);
+
+        // rebuilt, then carried on when the journal grows
+        let mut journal = JournalWriter::open(&path).unwrap();
+        let rebuilt = JournalIndex::load_or_build(&path, DEFAULT_BUCKET).unwrap();
+        assert_eq!(rebuilt.covered(), fs::metadata(&path).unwrap().len());
+        journal.record(&record(99, 7200)).unwrap();
+        journal.flush().unwrap();
+        let grown = JournalIndex::load_or_build(&path, DEFAULT_BUCKET).unwrap();
+        assert_eq!(grown.len(), rebuilt.len() + 1);
+        assert_eq!(JournalIndex::load(&idx).unwrap(), grown);
+    }
+
+    #[test]
+    fn segments_are_indexed_one_by_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RotationConfig {
+            max_bytes: Some(64 << 10),
+            ..RotationConfig::default()
+        };
+        let mut journal = JournalWriter::open_dir(dir.path(), config)
+            .unwrap()
+            .with_index(DEFAULT_BUCKET)
+            .unwrap();
+        generate(&mut journal);
+        drop(journal);
+        let segments: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().unwrap() == "log")
+            .collect();
+        assert!(segments.len() > 5);
+        for segment in &segments {
+            let index = JournalIndex::for_journal(segment).unwrap();
+            assert_eq!(index.covered(), fs::metadata(segment).unwrap().len());
+        }
+
+        let from = UNIX_EPOCH + Duration::from_secs(T0 + 3000);
+        let mut reader = JournalDirReader::open(dir.path()).unwrap();
+        assert!(reader.seek_device(device(12), from).unwrap());
+        let found = minute(&mut reader, device(12), from);
+        assert_eq!(found, (3000..3060).step_by(5).collect::<Vec<_>>());
+        let all = minute(
+            JournalDirReader::open(dir.path()).unwrap(),
+            device(12),
+            from,
+        );
+        assert_eq!(found, all);
+    }
+}
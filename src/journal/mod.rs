@@ -0,0 +1,1063 @@
+//! Append-only journal of [`FrameRecord`]s.
+//!
+//! `journal.log` starts with the magic `PPJ2`, followed by records laid out
+//! as `mark(4) len(4) entry(len) crc32(4)`, where `mark` is
+//! [`RECORD_MARK`], for finding records again after damage, and the CRC
+//! covers `len` and the entry. Journals from before the mark start with
+//! `PPJ1` and have none; they are read, and appended to, as they are. An
+//! entry is
+//!
+//! ```text
+//! ts_ms(8) direction(1) transport_id(8) source_kind(1) source_len(2)
+//! source(source_len) payload_kind(1) bytes(rest)
+//! ```
+//!
+//! - `direction` is 0 for rx, 1 for tx; `transport_id` 0 means none.
+//! - `source_kind` is 0 none, 1 socket, 2 unix, 3 serial, 4 opaque, and
+//!   `source` is its UTF-8 text (unix paths are converted lossily).
+//! - `payload_kind` is 0 for an encoded frame, 1 for undecodable bytes as
+//!   received; their error is recovered by decoding them again.
+//!
+//! Reading skips damaged records, reporting the bytes it passed over, and
+//! stops at a record torn by a crash; [`JournalWriter::open`] truncates it
+//! before appending.
+//!
+//! A journal may instead be a directory of segments, each a file as above,
+//! rolled over by size or age and pruned by a retention policy: see
+//! [`JournalWriter::open_dir`] and [`JournalDirReader`].
+//!
+//! A sidecar [`JournalIndex`] lets readers seek to a device's records from
+//! a given time without reading everything before them.
+//!
+//! [`ParallelReader`] reads a large journal on several threads, yielding
+//! just what a [`JournalReader`] would.
+//!
+//! A journal starting with [`ENCRYPTED_MAGIC`] has its entries sealed under
+//! a key from a `KeyStore`; reading it takes `JournalKeys` (feature
+//! `journal-encryption`), and without them it fails with
+//! [`JournalError::Locked`].
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::FrameV1;
+use crate::crc::crc32;
+use crate::pool::{BufferPool, Pooled};
+use crate::sink::{Direction, FrameRecord, FrameSink, Payload};
+use crate::transport::{PeerAddr, TransportId};
+
+#[cfg(feature = "journal-encryption")]
+mod crypto;
+mod index;
+mod parallel;
+mod segments;
+
+#[cfg(feature = "journal-encryption")]
+use crypto::Cipher;
+#[cfg(feature = "journal-encryption")]
+pub use crypto::JournalKeys;
+pub use index::{DEFAULT_BUCKET, INDEX_MAGIC, INDEX_VERSION, IndexError, JournalIndex};
+pub use parallel::{DEFAULT_CHUNK_LEN, ParallelReader};
+pub use segments::{JournalDirReader, Retention, RotationConfig, SegmentCodec};
+
+pub const JOURNAL_MAGIC: [u8; 4] = *b"PPJ2";
+/// The magic of journals without [`RECORD_MARK`]s.
+pub const JOURNAL_MAGIC_V1: [u8; 4] = *b"PPJ1";
+/// Starts every record of a `PPJ2` journal.
+pub const RECORD_MARK: [u8; 4] = [0xa5, 0x50, 0x52, 0x5a];
+/// The magic of encrypted journals, framed as `PPJ2` ones after a header.
+pub const ENCRYPTED_MAGIC: [u8; 4] = *b"PPJE";
+
+/// Fixed part of an entry, before the source text.
+const ENTRY_HEAD: usize = 8 + 1 + 8 + 1 + 2;
+const MAX_ENTRY: usize = 16 << 20;
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    /// The file is not a journal.
+    BadMagic,
+    /// A record failed its CRC or does not parse, and no readable record
+    /// follows it.
+    Corrupt {
+        offset: u64,
+    },
+    /// `len` bytes from `offset` held no readable record and were passed
+    /// over; reading goes on after them.
+    Skipped {
+        offset: u64,
+        len: u64,
+    },
+    /// The journal is encrypted under `key_id` of the keys kept for `key`,
+    /// and that key is not at hand.
+    Locked {
+        key: [u8; 8],
+        key_id: u32,
+    },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Io(e) => write!(f, "io error: {e}"),
+            JournalError::BadMagic => write!(f, "not a journal file"),
+            JournalError::Corrupt { offset } => write!(f, "corrupt record at offset {offset}"),
+            JournalError::Skipped { offset, len } => {
+                write!(f, "skipped {len} damaged bytes at offset {offset}")
+            }
+            JournalError::Locked { key, key_id } => write!(
+                f,
+                "journal encrypted under key {}#{key_id}, which is not available",
+                crate::hex::encode(key)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self {
+        JournalError::Io(e)
+    }
+}
+
+#[cfg(test)]
+fn encode_entry(record: &FrameRecord) -> Vec<u8> {
+    let mut out = Vec::with_capacity(entry_len_hint(record));
+    encode_entry_into(record, &mut out);
+    out
+}
+
+/// Room enough for most entries of `record`: the source address may need
+/// more.
+fn entry_len_hint(record: &FrameRecord) -> usize {
+    let payload = match &record.payload {
+        Payload::Frame(f) => f.encoded_len(),
+        Payload::Undecodable { bytes, .. } => bytes.len(),
+    };
+    ENTRY_HEAD + 64 + payload
+}
+
+fn encode_entry_into(record: &FrameRecord, out: &mut Vec<u8>) {
+    let ts_ms = record
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    let (kind, source) = match &record.source {
+        None => (0, String::new()),
+        Some(PeerAddr::Socket(a)) => (1, a.to_string()),
+        Some(PeerAddr::Unix(p)) => (2, p.to_string_lossy().into_owned()),
+        Some(PeerAddr::Serial(s)) => (3, s.clone()),
+        Some(PeerAddr::Opaque(s)) => (4, s.clone()),
+    };
+    let source = &source.as_bytes()[..source.len().min(u16::MAX as usize)];
+
+    out.extend_from_slice(&ts_ms.to_be_bytes());
+    out.push(match record.direction {
+        Direction::Rx => 0,
+        Direction::Tx => 1,
+    });
+    out.extend_from_slice(&record.transport_id.map_or(0, |id| id.0).to_be_bytes());
+    out.push(kind);
+    out.extend_from_slice(&(source.len() as u16).to_be_bytes());
+    out.extend_from_slice(source);
+    match &record.payload {
+        Payload::Frame(f) => {
+            out.push(0);
+            f.encode_into(out);
+        }
+        Payload::Undecodable { bytes, .. } => {
+            out.push(1);
+            out.extend_from_slice(bytes);
+        }
+    }
+}
+
+fn decode_entry(entry: &[u8]) -> Option<FrameRecord> {
+    let head = entry.get(..ENTRY_HEAD)?;
+    let ts_ms = u64::from_be_bytes(head[..8].try_into().unwrap());
+    let direction = match head[8] {
+        0 => Direction::Rx,
+        1 => Direction::Tx,
+        _ => return None,
+    };
+    let id = u64::from_be_bytes(head[9..17].try_into().unwrap());
+    let source_len = u16::from_be_bytes(head[18..20].try_into().unwrap()) as usize;
+    let rest = &entry[ENTRY_HEAD..];
+    let text = std::str::from_utf8(rest.get(..source_len)?).ok()?;
+    let source = match head[17] {
+        0 => None,
+        1 => Some(PeerAddr::Socket(text.parse::<SocketAddr>().ok()?)),
+        2 => Some(PeerAddr::Unix(PathBuf::from(text))),
+        3 => Some(PeerAddr::Serial(text.into())),
+        4 => Some(PeerAddr::Opaque(text.into())),
+        _ => return None,
+    };
+    let (&payload_kind, bytes) = rest[source_len..].split_first()?;
+    let payload = match (payload_kind, FrameV1::decode(bytes)) {
+        (0, Ok(frame)) => Payload::Frame(frame),
+        (1, Err(error)) => Payload::Undecodable {
+            bytes: bytes.to_vec(),
+            error,
+        },
+        _ => return None,
+    };
+    Some(FrameRecord {
+        timestamp: UNIX_EPOCH + Duration::from_millis(ts_ms),
+        direction,
+        payload,
+        source,
+        transport_id: (id != 0).then_some(TransportId(id)),
+    })
+}
+
+/// What follows [`ENCRYPTED_MAGIC`]: `version(1) key(8) key_id(4)
+/// salt(16)`, the entry of a key store and which of its keys the journal
+/// is encrypted under, and the salt its own key is derived with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EncryptedHeader {
+    /// 2 binds each record to its offset; 1 did not.
+    version: u8,
+    key: [u8; 8],
+    key_id: u32,
+    salt: [u8; 16],
+}
+
+const ENCRYPTED_HEAD: usize = 1 + 8 + 4 + 16;
+/// What sealing adds to an entry: a nonce and a tag.
+const SEAL_OVERHEAD: usize = 12 + 16;
+const ENCRYPTED_VERSION: u8 = 2;
+
+impl EncryptedHeader {
+    /// The start of the journal, magic included.
+    #[cfg_attr(not(feature = "journal-encryption"), allow(dead_code))]
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::from(ENCRYPTED_MAGIC);
+        out.push(self.version);
+        out.extend_from_slice(&self.key);
+        out.extend_from_slice(&self.key_id.to_be_bytes());
+        out.extend_from_slice(&self.salt);
+        out
+    }
+
+    fn decode(b: &[u8; ENCRYPTED_HEAD]) -> Option<Self> {
+        (1..=ENCRYPTED_VERSION)
+            .contains(&b[0])
+            .then(|| EncryptedHeader {
+                version: b[0],
+                key: b[1..9].try_into().unwrap(),
+                key_id: u32::from_be_bytes(b[9..13].try_into().unwrap()),
+                salt: b[13..].try_into().unwrap(),
+            })
+    }
+}
+
+/// Without the `journal-encryption` feature there is no cipher, and
+/// encrypted journals are [`JournalError::Locked`].
+#[cfg(not(feature = "journal-encryption"))]
+#[derive(Clone)]
+enum Cipher {}
+
+#[cfg(not(feature = "journal-encryption"))]
+impl Cipher {
+    fn seal(&self, _entry: &[u8], _offset: u64) -> Vec<u8> {
+        match *self {}
+    }
+
+    fn open(&self, _sealed: &[u8], _offset: u64) -> Option<Vec<u8>> {
+        match *self {}
+    }
+}
+
+/// The keys a reader or writer has for encrypted journals.
+#[derive(Clone, Default)]
+enum Keys {
+    #[default]
+    None,
+    #[cfg(feature = "journal-encryption")]
+    Store(JournalKeys),
+}
+
+impl Keys {
+    /// The start of a new journal, and the cipher for its records.
+    fn header(&self) -> Result<(Vec<u8>, Option<Cipher>), JournalError> {
+        match self {
+            Keys::None => Ok((JOURNAL_MAGIC.to_vec(), None)),
+            #[cfg(feature = "journal-encryption")]
+            Keys::Store(keys) => {
+                let (header, cipher) = keys.fresh()?;
+                Ok((header.encode(), Some(cipher)))
+            }
+        }
+    }
+
+    fn unlock(&self, header: &EncryptedHeader) -> Result<Cipher, JournalError> {
+        match self {
+            Keys::None => Err(JournalError::Locked {
+                key: header.key,
+                key_id: header.key_id,
+            }),
+            #[cfg(feature = "journal-encryption")]
+            Keys::Store(keys) => keys.unlock(header),
+        }
+    }
+
+    fn encrypting(&self) -> bool {
+        !matches!(self, Keys::None)
+    }
+}
+
+/// How much one read from the underlying reader asks for.
+const READ_CHUNK: usize = 8 << 10;
+
+/// What is at the front of a reader's lookahead.
+enum Parsed {
+    /// A record, and the bytes it takes.
+    Record(FrameRecord, usize),
+    /// Not a record.
+    Bad,
+    /// Maybe the start of one, cut short by the end of the input.
+    Short,
+}
+
+/// Reads records in order. Ends at EOF or a torn final record.
+///
+/// A damaged record is skipped: the reader looks for the next record that
+/// checks out, yields [`JournalError::Skipped`] for the bytes in between
+/// and carries on. If none follows, it yields [`JournalError::Corrupt`]
+/// and ends the iteration, or, if the damage could be a record cut short
+/// by a crash, just ends it.
+pub struct JournalReader<R> {
+    reader: R,
+    /// Bytes read from `reader` and not yet used, from `ahead_at` on.
+    ahead: Vec<u8>,
+    ahead_at: usize,
+    eof: bool,
+    offset: u64,
+    /// The magic's: 1 for `PPJ1`, 2 for `PPJ2` and encrypted journals.
+    version: u8,
+    /// Where the first record starts, after the magic and any header.
+    first: u64,
+    /// Opens the entries of an encrypted journal.
+    cipher: Option<Cipher>,
+    done: bool,
+}
+
+impl JournalReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, JournalError> {
+        JournalReader::open_with(path.as_ref(), &Keys::None)
+    }
+
+    /// Open the journal and carry on from `offset`, as given by
+    /// [`JournalReader::offset`] for an earlier reader of the same file.
+    pub fn open_at(path: impl AsRef<Path>, offset: u64) -> Result<Self, JournalError> {
+        JournalReader::open_with(path.as_ref(), &Keys::None)?.resume_at(offset)
+    }
+
+    fn open_with(path: &Path, keys: &Keys) -> Result<Self, JournalError> {
+        JournalReader::start(BufReader::new(File::open(path)?), keys)
+    }
+
+    fn resume_at(mut self, offset: u64) -> Result<Self, JournalError> {
+        if offset > self.offset {
+            self.seek_to(offset)?;
+        }
+        Ok(self)
+    }
+}
+
+/// `read_exact`, a short input being no journal.
+fn read_head(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), JournalError> {
+    match reader.read_exact(buf) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(JournalError::BadMagic),
+        result => Ok(result?),
+    }
+}
+
+impl<R: Read> JournalReader<R> {
+    pub fn new(reader: R) -> Result<Self, JournalError> {
+        JournalReader::start(reader, &Keys::None)
+    }
+
+    fn start(mut reader: R, keys: &Keys) -> Result<Self, JournalError> {
+        let mut magic = [0u8; 4];
+        read_head(&mut reader, &mut magic)?;
+        let (version, cipher) = match magic {
+            JOURNAL_MAGIC => (2, None),
+            JOURNAL_MAGIC_V1 => (1, None),
+            ENCRYPTED_MAGIC => {
+                let mut head = [0u8; ENCRYPTED_HEAD];
+                read_head(&mut reader, &mut head)?;
+                let header = EncryptedHeader::decode(&head).ok_or(JournalError::BadMagic)?;
+                (2, Some(keys.unlock(&header)?))
+            }
+            _ => return Err(JournalError::BadMagic),
+        };
+        let first = (magic.len() + cipher.as_ref().map_or(0, |_| ENCRYPTED_HEAD)) as u64;
+        Ok(JournalReader {
+            reader,
+            ahead: Vec::new(),
+            ahead_at: 0,
+            eof: false,
+            offset: first,
+            version,
+            first,
+            cipher,
+            done: false,
+        })
+    }
+
+    /// File offset just past the last record returned, or the bytes
+    /// skipped after it.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The format version, from the journal's magic.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    fn available(&self) -> &[u8] {
+        &self.ahead[self.ahead_at..]
+    }
+
+    /// Read until `n` bytes are available, or the input ends.
+    fn fill(&mut self, n: usize) -> io::Result<()> {
+        while self.available().len() < n && !self.eof {
+            if self.ahead_at > READ_CHUNK {
+                self.ahead.drain(..self.ahead_at);
+                self.ahead_at = 0;
+            }
+            let len = self.ahead.len();
+            self.ahead.resize(len + READ_CHUNK.max(n), 0);
+            let read = loop {
+                match self.reader.read(&mut self.ahead[len..]) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result,
+                }
+            };
+            self.ahead.truncate(len + *read.as_ref().unwrap_or(&0));
+            self.eof = read? == 0;
+        }
+        Ok(())
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.ahead_at += n;
+        self.offset += n as u64;
+    }
+
+    /// Whether a record starts at the front of the lookahead.
+    fn parse(&mut self) -> io::Result<Parsed> {
+        let mark = if self.version >= 2 {
+            RECORD_MARK.len()
+        } else {
+            0
+        };
+        self.fill(mark + 4 + ENTRY_HEAD)?;
+        let b = self.available();
+        let seen = b.len().min(mark);
+        if b[..seen] != RECORD_MARK[..seen] {
+            return Ok(Parsed::Bad);
+        }
+        if b.len() < mark + 4 {
+            return Ok(Parsed::Short);
+        }
+        let n = u32::from_be_bytes(b[mark..mark + 4].try_into().unwrap()) as usize;
+        if !(ENTRY_HEAD < n && n <= MAX_ENTRY) {
+            return Ok(Parsed::Bad);
+        }
+        // the fixed fields, checked first so that the bytes after damage
+        // can be tried one by one without a CRC over each
+        if let Some(head) = b
+            .get(mark + 4..mark + 4 + ENTRY_HEAD)
+            .filter(|_| self.cipher.is_none())
+        {
+            let source_len = u16::from_be_bytes([head[18], head[19]]) as usize;
+            if head[8] > 1 || head[17] > 4 || ENTRY_HEAD + source_len >= n {
+                return Ok(Parsed::Bad);
+            }
+        }
+        self.fill(mark + 8 + n)?;
+        let b = self.available();
+        if b.len() < mark + 8 + n {
+            return Ok(Parsed::Short);
+        }
+        let crc = u32::from_be_bytes(b[mark + 4 + n..mark + 8 + n].try_into().unwrap());
+        if crc32(&b[mark..mark + 4 + n]) != crc {
+            return Ok(Parsed::Bad);
+        }
+        let entry = &b[mark + 4..mark + 4 + n];
+        let opened;
+        let entry = match &self.cipher {
+            // a record that fails to open is damaged like one failing its CRC
+            Some(cipher) => match cipher.open(entry, self.offset) {
+                Some(entry) => {
+                    opened = entry;
+                    &opened
+                }
+                None => return Ok(Parsed::Bad),
+            },
+            None => entry,
+        };
+        Ok(match decode_entry(entry) {
+            Some(record) => Parsed::Record(record, mark + 8 + n),
+            None => Parsed::Bad,
+        })
+    }
+
+    /// Drop bytes up to where a record may start, `false` if there are
+    /// none left.
+    fn resync(&mut self) -> io::Result<bool> {
+        self.consume(1);
+        if self.version < 2 {
+            self.fill(1)?;
+            return Ok(!self.available().is_empty());
+        }
+        loop {
+            self.fill(READ_CHUNK)?;
+            let b = self.available();
+            match b.windows(RECORD_MARK.len()).position(|w| w == RECORD_MARK) {
+                Some(at) => {
+                    self.consume(at);
+                    return Ok(true);
+                }
+                None if self.eof => return Ok(false),
+                // keep what may be the start of a mark
+                None => self.consume(b.len().saturating_sub(RECORD_MARK.len() - 1)),
+            }
+        }
+    }
+
+    /// `None` at EOF or a torn record.
+    fn read_record(&mut self) -> Result<Option<FrameRecord>, JournalError> {
+        let start = self.offset;
+        let first = match self.parse()? {
+            Parsed::Record(record, len) => {
+                self.consume(len);
+                return Ok(Some(record));
+            }
+            Parsed::Short if self.available().is_empty() => return Ok(None),
+            first => first,
+        };
+        while self.resync()? {
+            if let Parsed::Record(..) = self.parse()? {
+                return Err(JournalError::Skipped {
+                    offset: start,
+                    len: self.offset - start,
+                });
+            }
+        }
+        // nothing readable after it: a record cut short, or a damaged one
+        self.offset = start;
+        match first {
+            Parsed::Short => Ok(None),
+            _ => Err(JournalError::Corrupt { offset: start }),
+        }
+    }
+
+    pub(super) fn boxed(self) -> JournalReader<Box<dyn Read + Send>>
+    where
+        R: Send + 'static,
+    {
+        JournalReader {
+            reader: Box::new(self.reader),
+            ahead: self.ahead,
+            ahead_at: self.ahead_at,
+            eof: self.eof,
+            offset: self.offset,
+            version: self.version,
+            first: self.first,
+            cipher: self.cipher,
+            done: self.done,
+        }
+    }
+
+    /// Forget what was read ahead, the reader having moved to `offset`.
+    fn moved_to(&mut self, offset: u64) {
+        self.ahead.clear();
+        self.ahead_at = 0;
+        self.eof = false;
+        self.offset = offset;
+        self.done = false;
+    }
+}
+
+impl<R: Read + Seek> JournalReader<R> {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.moved_to(offset);
+        Ok(())
+    }
+
+    /// Move to where `device_id`'s records from `from` on start, as far as
+    /// `index` tells, and return `true`. Without an index fit for this
+    /// journal, rewind to the first record and return `false`. Either way,
+    /// records of other devices and times still follow, for the caller to
+    /// skip.
+    pub fn seek_device(
+        &mut self,
+        index: Option<&JournalIndex>,
+        device_id: [u8; 8],
+        from: SystemTime,
+    ) -> Result<bool, JournalError> {
+        let len = self.reader.seek(SeekFrom::End(0))?;
+        let (offset, indexed) = match index {
+            Some(index) if index.covered() <= len => (index.start(device_id, from), true),
+            _ => (self.first, false),
+        };
+        self.seek_to(offset)?;
+        Ok(indexed)
+    }
+}
+
+impl<R: Read> Iterator for JournalReader<R> {
+    type Item = Result<FrameRecord, JournalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_record().transpose();
+        if !matches!(result, Some(Ok(_) | Err(JournalError::Skipped { .. }))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// Appends records to a journal file. Records are buffered until
+/// [`FrameSink::flush`], which also syncs the file.
+pub struct JournalWriter {
+    /// The file being written, the current segment for a directory.
+    path: PathBuf,
+    /// As [`JournalReader::version`].
+    version: u8,
+    file: BufWriter<File>,
+    /// Bytes in the file, written or buffered.
+    len: u64,
+    /// Where the file's first record starts.
+    first: u64,
+    /// Where new segments get their keys.
+    keys: Keys,
+    /// Seals entries for an encrypted journal.
+    cipher: Option<Cipher>,
+    /// Kept up to date as records are written, and saved on flush.
+    index: Option<Box<JournalIndex>>,
+    rotation: Option<Box<segments::Rotation>>,
+    /// Where records are put together, if not in buffers of their own.
+    pool: Option<BufferPool>,
+}
+
+impl JournalWriter {
+    /// Create the journal, or reopen it for appending after checking its
+    /// magic and cutting off any torn or corrupt tail. Damage before the
+    /// last readable record is left as it is.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, JournalError> {
+        JournalWriter::open_with(path.as_ref(), Keys::None)
+    }
+
+    fn open_with(path: &Path, keys: Keys) -> Result<Self, JournalError> {
+        let path = path.to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut len = file.metadata()?.len();
+        let (version, first, cipher);
+        if len == 0 {
+            let (header, c) = keys.header()?;
+            file.write_all(&header)?;
+            (version, first, cipher) = (2, header.len() as u64, c);
+            len = first;
+        } else {
+            let mut reader = JournalReader::start(BufReader::new(&file), &keys)?;
+            if keys.encrypting() && !reader.is_encrypted() {
+                return Err(JournalError::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{}: not an encrypted journal", path.display()),
+                )));
+            }
+            (version, first) = (reader.version(), reader.first);
+            for _ in reader.by_ref() {}
+            let good_len = reader.offset();
+            cipher = reader.cipher;
+            if len != good_len {
+                file.set_len(good_len)?;
+                file.sync_all()?;
+                len = good_len;
+            }
+        }
+        Ok(JournalWriter {
+            path,
+            version,
+            file: BufWriter::new(file),
+            len,
+            first,
+            keys,
+            cipher,
+            index: None,
+            rotation: None,
+            pool: None,
+        })
+    }
+
+    /// Keep a [`JournalIndex`] with buckets `bucket` wide next to the
+    /// journal, or each segment, catching up on records written without
+    /// it first. Encrypted journals are not indexed: the index would give
+    /// away which devices were heard from when.
+    pub fn with_index(mut self, bucket: Duration) -> Result<Self, JournalError> {
+        if self.keys.encrypting() {
+            return Ok(self);
+        }
+        self.file.flush()?;
+        self.index = Some(Box::new(JournalIndex::load_or_build(&self.path, bucket)?));
+        Ok(self)
+    }
+
+    /// Put records together in buffers from `pool` rather than fresh ones.
+    pub fn with_pool(mut self, pool: BufferPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+}
+
+impl FrameSink for JournalWriter {
+    type Error = JournalError;
+
+    fn record(&mut self, record: &FrameRecord) -> Result<(), JournalError> {
+        let mut entry = Pooled::new_in(self.pool.as_ref(), entry_len_hint(record));
+        encode_entry_into(record, &mut entry);
+        let mark = if self.version >= 2 {
+            RECORD_MARK.len()
+        } else {
+            0
+        };
+        if self.rotation.is_some() {
+            // sealed under the key of the segment it goes to
+            let sealing = if self.cipher.is_some() {
+                SEAL_OVERHEAD
+            } else {
+                0
+            };
+            let len = mark + 8 + entry.len() + sealing;
+            self.roll_if_due(len as u64, record.timestamp)?;
+        }
+        let sealed;
+        let entry = match &self.cipher {
+            Some(cipher) => {
+                sealed = cipher.seal(&entry, self.len);
+                &sealed[..]
+            }
+            None => &entry[..],
+        };
+        let mut out = Pooled::new_in(self.pool.as_ref(), entry.len() + 12);
+        if mark > 0 {
+            out.extend_from_slice(&RECORD_MARK);
+        }
+        out.extend_from_slice(&(entry.len() as u32).to_be_bytes());
+        out.extend_from_slice(entry);
+        let crc = crc32(&out[mark..]);
+        out.extend_from_slice(&crc.to_be_bytes());
+        if let Some(index) = &mut self.index {
+            index.add(record, self.len, self.len + out.len() as u64);
+        }
+        self.file.write_all(&out)?;
+        self.len += out.len() as u64;
+        Ok(())
+    }
+
+    /// Also saves the index, and compresses and prunes segments completed
+    /// since the last flush.
+    fn flush(&mut self) -> Result<(), JournalError> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        if let Some(index) = &self.index {
+            index.save(&JournalIndex::path_for(&self.path))?;
+        }
+        if let Some(rotation) = &mut self.rotation {
+            rotation.maintain()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DecodeError;
+    use crate::transport::tests::frame;
+    use crate::transport::{Transport, loopback_pair};
+    use std::time::SystemTime;
+
+    /// The journal keeps millisecond precision.
+    fn at_ms(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    #[tokio::test]
+    async fn rx_metadata_roundtrips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        let (mut a, mut b) = loopback_pair();
+        a.send(&frame(1)).await.unwrap();
+        let mut rx = b.recv_rx().await.unwrap();
+        rx.received_at = at_ms(1_700_000_000_123);
+
+        let mut tx = FrameRecord::tx(frame(2));
+        tx.timestamp = at_ms(1_700_000_000_456);
+        let mut bad =
+            FrameRecord::undecodable(Direction::Rx, b"XX".to_vec(), DecodeError::TooShort);
+        bad.timestamp = at_ms(1);
+        bad.source = Some(PeerAddr::Socket("10.0.0.7:4000".parse().unwrap()));
+        let written = [FrameRecord::from(rx.clone()), tx, bad];
+
+        let mut journal = JournalWriter::open(&path).unwrap();
+        for r in &written {
+            journal.record(r).unwrap();
+        }
+        journal.flush().unwrap();
+
+        let read: Vec<_> = JournalReader::open(&path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, written);
+        assert_eq!(read[0].source, Some(PeerAddr::Opaque("loopback:a".into())));
+        assert_eq!(read[0].transport_id, Some(b.id()));
+        assert_eq!(read[0].frame(), Some(&*rx));
+    }
+
+    #[test]
+    fn reopen_truncates_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        let mut journal = JournalWriter::open(&path).unwrap();
+        journal.record(&FrameRecord::rx(frame(1))).unwrap();
+        journal.record(&FrameRecord::rx(frame(2))).unwrap();
+        journal.flush().unwrap();
+        drop(journal);
+
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let mut journal = JournalWriter::open(&path).unwrap();
+        journal.record(&FrameRecord::rx(frame(3))).unwrap();
+        journal.flush().unwrap();
+        let counters: Vec<_> = JournalReader::open(&path)
+            .unwrap()
+            .map(|r| r.unwrap().frame().unwrap().header.counter)
+            .collect();
+        assert_eq!(counters, [1, 3]);
+    }
+
+    #[test]
+    fn pooled_writer_writes_the_same_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = BufferPool::new();
+        let mut plain = JournalWriter::open(dir.path().join("plain.log")).unwrap();
+        let mut pooled = JournalWriter::open(dir.path().join("pooled.log"))
+            .unwrap()
+            .with_pool(pool.clone());
+        for c in 0..20 {
+            let record = FrameRecord::rx(frame(c));
+            plain.record(&record).unwrap();
+            pooled.record(&record).unwrap();
+        }
+        plain.flush().unwrap();
+        pooled.flush().unwrap();
+        let plain = std::fs::read(dir.path().join("plain.log")).unwrap();
+        let pooled = std::fs::read(dir.path().join("pooled.log")).unwrap();
+        assert_eq!(plain, pooled);
+        let stats = pool.stats();
+        assert_eq!(stats.outstanding, 0);
+        assert!(stats.hits >= 38, "{stats:?}");
+    }
+
+    #[test]
+    fn reading_resumes_where_it_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        let mut journal = JournalWriter::open(&path).unwrap();
+        journal.record(&FrameRecord::rx(frame(1))).unwrap();
+        journal.flush().unwrap();
+        let mut reader = JournalReader::open(&path).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().is_none());
+
+        journal.record(&FrameRecord::rx(frame(2))).unwrap();
+        journal.flush().unwrap();
+        let counters: Vec<_> = JournalReader::open_at(&path, reader.offset())
+            .unwrap()
+            .map(|r| r.unwrap().frame().unwrap().header.counter)
+            .collect();
+        assert_eq!(counters, [2]);
+    }
+
+    #[test]
+    fn corrupt_record_is_reported() {
+        let mut journal = Vec::from(JOURNAL_MAGIC);
+        journal.extend_from_slice(&[0, 0, 0, 1, 0xff, 0, 0, 0, 0]);
+        let mut reader = JournalReader::new(&journal[..]).unwrap();
+        assert!(matches!(
+            reader.next(),
+            Some(Err(JournalError::Corrupt { offset: 4 }))
+        ));
+        assert!(reader.next().is_none());
+        assert!(matches!(
+            JournalReader::new(&b"PPQ1"[..]),
+            Err(JournalError::BadMagic)
+        ));
+    }
+
+    /// A journal of records with counters 1 to 20, and where each starts.
+    fn twenty(version: u8) -> (Vec<u8>, Vec<usize>) {
+        let mut bytes = Vec::from(if version == 1 {
+            JOURNAL_MAGIC_V1
+        } else {
+            JOURNAL_MAGIC
+        });
+        let mut starts = Vec::new();
+        for counter in 1..=20 {
+            starts.push(bytes.len());
+            if version >= 2 {
+                bytes.extend_from_slice(&RECORD_MARK);
+            }
+            let entry = encode_entry(&FrameRecord::rx(frame(counter)));
+            let len = (entry.len() as u32).to_be_bytes();
+            bytes.extend_from_slice(&len);
+            bytes.extend_from_slice(&entry);
+            bytes.extend_from_slice(&crc32(&[&len[..], &entry].concat()).to_be_bytes());
+        }
+        (bytes, starts)
+    }
+
+    /// The counters read, and the errors along the way.
+    fn read_all(bytes: &[u8]) -> (Vec<u64>, Vec<String>) {
+        let (mut counters, mut errors) = (Vec::new(), Vec::new());
+        for record in JournalReader::new(bytes).unwrap() {
+            match record {
+                Ok(r) => counters.push(r.frame().unwrap().header.counter),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        (counters, errors)
+    }
+
+    #[test]
+    fn damaged_records_are_skipped() {
+        for version in [1, 2] {
+            let (clean, starts) = twenty(version);
+            let record_len = starts[1] - starts[0];
+            let mark = if version == 1 { 0 } else { 4 };
+            // in the mark, the length, the entry's head, the frame, the CRC
+            for at in [
+                0,
+                mark,
+                mark + 3,
+                mark + 12,
+                record_len - 20,
+                record_len - 1,
+            ] {
+                let mut bytes = clean.clone();
+                bytes[starts[6] + at] ^= 0x40;
+                let (counters, errors) = read_all(&bytes);
+                let expected: Vec<u64> = (1..=20).filter(|&c| c != 7).collect();
+                assert_eq!(counters, expected, "v{version}, byte {at}");
+                assert_eq!(
+                    errors,
+                    [format!(
+                        "skipped {record_len} damaged bytes at offset {}",
+                        starts[6]
+                    )],
+                    "v{version}, byte {at}"
+                );
+            }
+
+            // a length running past the end of the file
+            let mut bytes = clean.clone();
+            bytes[starts[3] + mark] = 0x7f;
+            let (counters, errors) = read_all(&bytes);
+            assert_eq!(counters.len(), 19, "v{version}");
+            assert_eq!(errors.len(), 1);
+
+            // several in a row are skipped as one
+            let mut bytes = clean.clone();
+            for r in 9..12 {
+                bytes[starts[r] + mark + 15] ^= 1;
+            }
+            let (counters, errors) = read_all(&bytes);
+            assert_eq!(
+                counters,
+                [1, 2, 3, 4, 5, 6, 7, 8, 9, 13, 14, 15, 16, 17, 18, 19, 20]
+            );
+            assert_eq!(
+                errors,
+                [format!(
+                    "skipped {} damaged bytes at offset {}",
+                    3 * record_len,
+                    starts[9]
+                )]
+            );
+
+            // the last record: damaged, or its length so that it looks torn
+            let mut bytes = clean.clone();
+            bytes[starts[19] + mark + 30] ^= 1;
+            let (counters, errors) = read_all(&bytes);
+            assert_eq!(counters, (1..20).collect::<Vec<_>>());
+            assert_eq!(errors, [format!("corrupt record at offset {}", starts[19])]);
+            let mut bytes = clean.clone();
+            bytes[starts[19] + mark + 2] = 0x7f;
+            let (counters, errors) = read_all(&bytes);
+            assert_eq!(counters, (1..20).collect::<Vec<_>>());
+            assert!(errors.is_empty());
+
+            // garbage between records
+            let mut bytes = clean[..starts[5]].to_vec();
+            bytes.extend_from_slice(&[0xa5; 100]);
+            bytes.extend_from_slice(&RECORD_MARK);
+            bytes.extend_from_slice(&clean[starts[5]..]);
+            let (counters, errors) = read_all(&bytes);
+            assert_eq!(counters, (1..=20).collect::<Vec<_>>());
+            assert_eq!(
+                errors,
+                [format!("skipped 104 damaged bytes at offset {}", starts[5])]
+            );
+        }
+    }
+
+    #[test]
+    fn reopening_keeps_records_after_damage() {
+        for version in [1, 2] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("journal.log");
+            let (mut bytes, starts) = twenty(version);
+            bytes[starts[4] + 10] ^= 1;
+            // and a torn tail
+            bytes.truncate(bytes.len() - 3);
+            std::fs::write(&path, &bytes).unwrap();
+
+            let mut journal = JournalWriter::open(&path).unwrap();
+            journal.record(&FrameRecord::rx(frame(21))).unwrap();
+            journal.flush().unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            assert_eq!(&bytes[..4], if version == 1 { b"PPJ1" } else { b"PPJ2" });
+            let (counters, errors) = read_all(&bytes);
+            let expected: Vec<u64> = (1..=21).filter(|&c| c != 5 && c != 20).collect();
+            assert_eq!(counters, expected, "v{version}");
+            assert_eq!(errors.len(), 1);
+        }
+    }
+}
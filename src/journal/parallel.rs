@@ -0,0 +1,458 @@
+//! Reading one large journal on several threads.
+//!
+//! [`ParallelReader`] cuts the file into chunks, has worker threads read
+//! them, and joins their results up into what a [`JournalReader`] reading
+//! the whole file gives: the same records and errors, at the same
+//! offsets, in the same order.
+//!
+//! A chunk starts at the first record at or after its cut that checks
+//! out, found the way a reader finds the record after damage, and is
+//! read up to the first record starting at or past where the next chunk
+//! starts. A "record" that checks out but is really inside a longer one (a
+//! body holding a journal record, say) starts a chunk in the wrong place:
+//! the chunk before reads on past it, and the chunk is cut down to where
+//! that one stopped, or read again from there.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use super::{JournalError, JournalReader, Parsed};
+use crate::sink::FrameRecord;
+
+/// Bytes per chunk, unless [`ParallelReader::with_chunk_len`] says
+/// otherwise.
+pub const DEFAULT_CHUNK_LEN: u64 = 16 << 20;
+
+type Item = Result<FrameRecord, JournalError>;
+
+/// What a worker made of a chunk.
+struct Chunk<T> {
+    /// Where reading started and stopped, and where it was to stop.
+    start: u64,
+    end: u64,
+    stop: u64,
+    /// Whether reading ended for good, as a reader's iteration does at an
+    /// error other than [`JournalError::Skipped`] or a torn record.
+    ended: bool,
+    out: T,
+}
+
+/// Where the first record at or after `cut` starts, or the end of the
+/// file if none does.
+fn sync(path: &Path, cut: u64, len: u64) -> Result<u64, JournalError> {
+    if cut >= len {
+        return Ok(len);
+    }
+    let mut reader = JournalReader::open(path)?.resume_at(cut)?;
+    loop {
+        if let Parsed::Record(..) = reader.parse()? {
+            return Ok(reader.offset);
+        }
+        if !reader.resync()? {
+            return Ok(len);
+        }
+    }
+}
+
+/// Read from `start` up to the first record starting at or past `stop`,
+/// handing `each` every item with the offsets it starts and ends at.
+fn read_range<T>(
+    path: &Path,
+    start: u64,
+    stop: u64,
+    mut out: T,
+    each: &impl Fn(&mut T, u64, Item, u64),
+) -> Result<Chunk<T>, JournalError> {
+    let mut reader = JournalReader::open(path)?.resume_at(start)?;
+    let ended = loop {
+        let at = reader.offset();
+        if at >= stop {
+            break false;
+        }
+        let Some(item) = reader.next() else {
+            break true;
+        };
+        let ended = !matches!(item, Ok(_) | Err(JournalError::Skipped { .. }));
+        each(&mut out, at, item, reader.offset());
+        if ended {
+            break true;
+        }
+    };
+    Ok(Chunk {
+        start,
+        end: reader.offset(),
+        stop,
+        ended,
+        out,
+    })
+}
+
+/// Drop the items of `chunk` before `at`, if one of them starts there.
+fn trim(chunk: &mut Chunk<Vec<(Item, u64)>>, at: u64) {
+    let starts = std::iter::once(chunk.start).chain(chunk.out.iter().map(|(_, end)| *end));
+    if let Some(skip) = starts.take_while(|&s| s <= at).position(|s| s == at) {
+        chunk.out.drain(..skip);
+        chunk.start = at;
+    }
+}
+
+/// Reads a journal file as a [`JournalReader`] does, decoding chunks of
+/// it on several threads.
+///
+/// As an iterator it yields the reader's items in order, a few chunks
+/// being decoded ahead at a time, and [`offset`](Self::offset) follows
+/// along. [`fold_unordered`](Self::fold_unordered) is for aggregates that
+/// don't need the records in order. Encrypted journals are not read.
+#[derive(Debug)]
+pub struct ParallelReader {
+    path: PathBuf,
+    len: u64,
+    threads: usize,
+    chunk_len: u64,
+    /// Where the records not yet decoded start, and the cut the next
+    /// chunks go from.
+    at: u64,
+    cut: u64,
+    /// Decoded items, and the offsets they end at.
+    ready: VecDeque<(Item, u64)>,
+    offset: u64,
+    ended: bool,
+}
+
+impl ParallelReader {
+    /// Open the journal at `path`, to be read with as many threads as
+    /// there are CPUs.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, JournalError> {
+        let path = path.as_ref().to_path_buf();
+        let first = JournalReader::open(&path)?.offset();
+        let len = std::fs::metadata(&path)?.len();
+        Ok(ParallelReader {
+            path,
+            len,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            chunk_len: DEFAULT_CHUNK_LEN,
+            at: first,
+            cut: first,
+            ready: VecDeque::new(),
+            offset: first,
+            ended: false,
+        })
+    }
+
+    /// Use `threads` threads, one meaning the caller's only.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn with_chunk_len(mut self, bytes: u64) -> Self {
+        self.chunk_len = bytes.max(1);
+        self
+    }
+
+    /// As [`JournalReader::offset`].
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Chunk from `cut` to `next_cut`, reading from `start` if it is
+    /// known.
+    fn chunk<T>(
+        &self,
+        start: Option<u64>,
+        cut: u64,
+        next_cut: u64,
+        out: T,
+        each: &impl Fn(&mut T, u64, Item, u64),
+    ) -> Result<Chunk<T>, JournalError> {
+        let start = match start {
+            Some(start) => start,
+            None => sync(&self.path, cut, self.len)?,
+        };
+        let stop = sync(&self.path, next_cut, self.len)?;
+        read_range(&self.path, start, stop, out, each)
+    }
+
+    /// Make `chunk` start at `at`, where the one before it ended, and
+    /// return it.
+    fn join_up<T>(
+        &self,
+        chunk: Result<Chunk<T>, JournalError>,
+        at: u64,
+        out: impl FnOnce() -> T,
+        each: &impl Fn(&mut T, u64, Item, u64),
+    ) -> Result<Chunk<T>, JournalError> {
+        match chunk {
+            Ok(chunk) if chunk.start == at => Ok(chunk),
+            Ok(chunk) => read_range(&self.path, at, chunk.stop, out(), each),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Decode the next chunks, a thread each.
+    fn read_ahead(&mut self) {
+        let left = (self.len - self.cut).div_ceil(self.chunk_len);
+        let n = left.min(self.threads as u64);
+        let cut = |k: u64| (self.cut + k * self.chunk_len).min(self.len);
+        let push =
+            |items: &mut Vec<(Item, u64)>, _: u64, item: Item, end: u64| items.push((item, end));
+        let chunks: Vec<_> = if n == 1 {
+            vec![self.chunk(Some(self.at), cut(0), cut(1), Vec::new(), &push)]
+        } else {
+            let this = &*self;
+            thread::scope(|s| {
+                let workers: Vec<_> = (0..n)
+                    .map(|k| {
+                        let start = (k == 0).then_some(this.at);
+                        s.spawn(move || this.chunk(start, cut(k), cut(k + 1), Vec::new(), &push))
+                    })
+                    .collect();
+                workers.into_iter().map(|w| w.join().unwrap()).collect()
+            })
+        };
+        self.cut = cut(n);
+
+        for chunk in chunks {
+            if self.ended {
+                break;
+            }
+            let chunk = chunk.map(|mut chunk| {
+                trim(&mut chunk, self.at);
+                chunk
+            });
+            let chunk = self.join_up(chunk, self.at, Vec::new, &push);
+            match chunk {
+                Ok(chunk) => {
+                    self.ready.extend(chunk.out);
+                    self.at = chunk.end;
+                    self.ended = chunk.ended;
+                }
+                Err(e) => {
+                    self.ready.push_back((Err(e), self.at));
+                    self.ended = true;
+                }
+            }
+        }
+    }
+
+    /// Run `each` over every item, with the offset it starts at, on the
+    /// worker threads, a chunk at a time into a value from `init`; then
+    /// put the chunks' values together with `merge`, in file order.
+    ///
+    /// The items are those the iterator yields. Within a chunk they come
+    /// in order, but the chunks are read at the same time.
+    pub fn fold_unordered<T: Send>(
+        self,
+        init: impl Fn() -> T + Sync,
+        each: impl Fn(&mut T, u64, Item) + Sync,
+        mut merge: impl FnMut(T, T) -> T,
+    ) -> T {
+        let chunks = (self.len - self.cut).div_ceil(self.chunk_len);
+        let cut = |k: u64| (self.cut + k * self.chunk_len).min(self.len);
+        let each = |out: &mut T, at: u64, item: Item, _: u64| each(out, at, item);
+        let next = AtomicUsize::new(0);
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|s| {
+            for _ in 0..(self.threads as u64).min(chunks) {
+                let tx = tx.clone();
+                let (this, next, init, each) = (&self, &next, &init, &each);
+                s.spawn(move || {
+                    loop {
+                        let k = next.fetch_add(1, Ordering::Relaxed) as u64;
+                        if k >= chunks {
+                            break;
+                        }
+                        let start = (k == 0).then_some(this.at);
+                        let chunk = this.chunk(start, cut(k), cut(k + 1), init(), each);
+                        if tx.send((k, chunk)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+
+            let mut acc = init();
+            let (mut at, mut want, mut pending) = (self.at, 0, BTreeMap::new());
+            let mut ended = false;
+            for (k, chunk) in rx {
+                if ended {
+                    continue;
+                }
+                pending.insert(k, chunk);
+                while let Some(chunk) = pending.remove(&want) {
+                    want += 1;
+                    match self.join_up(chunk, at, &init, &each) {
+                        Ok(chunk) => {
+                            acc = merge(acc, chunk.out);
+                            at = chunk.end;
+                            ended = chunk.ended;
+                        }
+                        Err(e) => {
+                            let mut out = init();
+                            each(&mut out, at, Err(e), at);
+                            acc = merge(acc, out);
+                            ended = true;
+                        }
+                    }
+                    if ended {
+                        // the rest is past the end of the iteration
+                        next.store(chunks as usize, Ordering::Relaxed);
+                        pending.clear();
+                        break;
+                    }
+                }
+            }
+            acc
+        })
+    }
+}
+
+impl Iterator for ParallelReader {
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Item> {
+        loop {
+            if let Some((item, end)) = self.ready.pop_front() {
+                self.offset = end;
+                return Some(item);
+            }
+            if self.ended || self.cut >= self.len {
+                return None;
+            }
+            self.read_ahead();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::JournalWriter;
+    use crate::sink::FrameSink;
+    use crate::transport::tests::frame;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    /// What `JournalReader` reads: each item, and the offset after it.
+    fn sequential(path: &Path) -> Vec<(String, u64)> {
+        let mut reader = JournalReader::open(path).unwrap();
+        let mut items = Vec::new();
+        while let Some(item) = reader.next() {
+            items.push((format!("{item:?}"), reader.offset()));
+        }
+        items
+    }
+
+    fn parallel(path: &Path, threads: usize, chunk_len: u64) -> Vec<(String, u64)> {
+        let mut reader = ParallelReader::open(path)
+            .unwrap()
+            .with_threads(threads)
+            .with_chunk_len(chunk_len);
+        let mut items = Vec::new();
+        while let Some(item) = reader.next() {
+            items.push((format!("{item:?}"), reader.offset()));
+        }
+        items
+    }
+
+    /// A journal of records every third of which has a whole journal
+    /// record for a body, so that cuts often find a record inside one.
+    fn nested(path: &Path, records: u64) {
+        let inner = path.with_extension("inner");
+        let mut journal = JournalWriter::open(&inner).unwrap();
+        journal.record(&FrameRecord::rx(frame(0))).unwrap();
+        journal.flush().unwrap();
+        let inner = std::fs::read(&inner).unwrap()[4..].to_vec();
+
+        let mut journal = JournalWriter::open(path).unwrap();
+        for c in 0..records {
+            let mut f = frame(c);
+            if c % 3 == 0 {
+                f.body = inner.clone().into();
+            }
+            journal.record(&FrameRecord::rx(f)).unwrap();
+        }
+        journal.flush().unwrap();
+    }
+
+    #[test]
+    fn reads_what_a_reader_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        nested(&path, 300);
+        let want = sequential(&path);
+        assert_eq!(want.len(), 300);
+        for (threads, chunk_len) in [(1, 13), (4, 13), (4, 57), (3, 100), (8, 1000), (2, 1 << 20)] {
+            assert_eq!(
+                parallel(&path, threads, chunk_len),
+                want,
+                "{threads} x {chunk_len}"
+            );
+        }
+    }
+
+    #[test]
+    fn damage_is_reported_where_it_is() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        nested(&path, 200);
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        for at in [100, len / 3, len / 2, len / 2 + 40] {
+            file.seek(SeekFrom::Start(at)).unwrap();
+            file.write_all(&[0xff; 9]).unwrap();
+        }
+        // and a torn tail
+        file.set_len(len - 5).unwrap();
+
+        let want = sequential(&path);
+        assert!(want.iter().any(|(item, _)| item.contains("Skipped")));
+        for (threads, chunk_len) in [(4, 11), (4, 50), (3, 999)] {
+            assert_eq!(
+                parallel(&path, threads, chunk_len),
+                want,
+                "{threads} x {chunk_len}"
+            );
+        }
+
+        // a corrupt record with nothing readable after it ends the reading
+        file.set_len(len / 2 + 30).unwrap();
+        let want = sequential(&path);
+        assert!(want.last().unwrap().0.contains("Corrupt"));
+        assert_eq!(parallel(&path, 4, 64), want);
+    }
+
+    #[test]
+    fn folds_every_chunk_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.log");
+        nested(&path, 500);
+        let counter = |item: Item| match item {
+            Ok(record) => Ok(record.frame().unwrap().header.counter),
+            Err(e) => Err(e.to_string()),
+        };
+        let want: Vec<_> = JournalReader::open(&path).unwrap().map(counter).collect();
+        for chunk_len in [11, 33, 4096] {
+            let mut counters = ParallelReader::open(&path)
+                .unwrap()
+                .with_threads(4)
+                .with_chunk_len(chunk_len)
+                .fold_unordered(
+                    Vec::new,
+                    // a chunk started inside a record may well read
+                    // garbage, but is thrown away
+                    |out, _, item| out.push(counter(item)),
+                    |mut a, b| {
+                        a.extend(b);
+                        a
+                    },
+                );
+            counters.sort();
+            assert_eq!(counters, want, "{chunk_len}");
+        }
+    }
+}
@@ -0,0 +1,748 @@
+//! Journals kept as a directory of segments.
+//!
+//! [`JournalWriter::open_dir`] appends to the newest segment in a
+//! directory and starts another when a record would take the current one
+//! past `max_bytes`, or comes `max_age` after its first record. Ages are
+//! measured in record timestamps. Segments are named `SEQ-MS.log`, a
+//! sequence number and the Unix time in milliseconds of the record that
+//! opened them (of the writer opening, for the first), such as
+//! `00000003-1760443200123.log`. The new segment is in place and synced
+//! before the old one is flushed and let go, and the record that was due
+//! goes to it.
+//!
+//! After a rollover the next flush compresses completed segments, if a
+//! [`SegmentCodec`] is configured, to `SEQ-MS.log.EXT`, then deletes the
+//! oldest, or moves them to an archive directory, while the [`Retention`]
+//! limits are exceeded. Every step leaves complete files behind: a file is
+//! written under a `.tmp` name, synced and renamed, and a segment is
+//! removed only once its compressed copy is in place. Opening the
+//! directory again clears away what a crash interrupted.
+//!
+//! [`JournalDirReader`] reads the segments in order, as one journal.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{ENCRYPTED_MAGIC, JournalError, JournalIndex, JournalReader, JournalWriter, Keys};
+use crate::sink::FrameRecord;
+
+/// Compresses completed segments and reads them back.
+pub trait SegmentCodec: Send + Sync {
+    /// Added to a compressed segment's file name, after a dot.
+    fn extension(&self) -> &str;
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()>;
+    fn decompress(&self, input: File) -> io::Result<Box<dyn Read + Send>>;
+}
+
+/// Which completed segments are kept. The segment being written always is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Segments kept, counting the one being written.
+    pub max_segments: Option<usize>,
+    /// A segment goes once the segment after it opened this long before
+    /// the newest record.
+    pub max_age: Option<Duration>,
+    /// Bytes kept on disk, counting the segment being written.
+    pub max_total_bytes: Option<u64>,
+    /// Segments past the limits are moved here rather than deleted.
+    pub archive: Option<PathBuf>,
+}
+
+#[derive(Clone, Default)]
+pub struct RotationConfig {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub retention: Retention,
+    pub codec: Option<Arc<dyn SegmentCodec>>,
+}
+
+impl fmt::Debug for RotationConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RotationConfig")
+            .field("max_bytes", &self.max_bytes)
+            .field("max_age", &self.max_age)
+            .field("retention", &self.retention)
+            .field("codec", &self.codec.as_ref().map(|c| c.extension()))
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    seq: u64,
+    start_ms: u64,
+    /// The codec's extension, for a compressed segment.
+    ext: Option<String>,
+    path: PathBuf,
+    len: u64,
+}
+
+/// `SEQ-MS.log`, or `SEQ-MS.log.EXT`.
+fn parse_name(name: &str) -> Option<(u64, u64, Option<String>)> {
+    let (stem, ext) = match name.split_once(".log") {
+        Some((stem, "")) => (stem, None),
+        Some((stem, ext)) => (stem, Some(ext.strip_prefix('.')?.to_string())),
+        None => return None,
+    };
+    if ext
+        .as_deref()
+        .is_some_and(|e| e.is_empty() || e.ends_with("tmp"))
+    {
+        return None;
+    }
+    let (seq, ms) = stem.split_once('-')?;
+    Some((seq.parse().ok()?, ms.parse().ok()?, ext))
+}
+
+/// The segments in `dir` by sequence number, a plain one ahead of a
+/// compressed copy of it.
+fn list(dir: &Path) -> io::Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some((seq, start_ms, ext)) = entry.file_name().to_str().and_then(parse_name) else {
+            continue;
+        };
+        segments.push(Segment {
+            seq,
+            start_ms,
+            ext,
+            path: entry.path(),
+            len: entry.metadata()?.len(),
+        });
+    }
+    segments.sort_by_key(|s| (s.seq, s.ext.is_some()));
+    Ok(segments)
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// A new segment holding just `header`, open for appending.
+fn create_segment(
+    dir: &Path,
+    seq: u64,
+    at: SystemTime,
+    header: &[u8],
+) -> io::Result<(PathBuf, File)> {
+    let ms = at
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    let path = dir.join(format!("{seq:08}-{ms}.log"));
+    let tmp = tmp_path(&path);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&tmp)?;
+    file.write_all(header)?;
+    file.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    sync_dir(dir)?;
+    let file = OpenOptions::new().append(true).open(&path)?;
+    Ok((path, file))
+}
+
+/// Remove what an interrupted step left: `.tmp` files, and segments whose
+/// compressed copy was finished.
+fn recover(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "tmp") {
+            fs::remove_file(path)?;
+        }
+    }
+    let segments = list(dir)?;
+    for pair in segments.windows(2) {
+        if pair[0].seq == pair[1].seq && pair[0].ext.is_none() {
+            fs::remove_file(&pair[0].path)?;
+        }
+    }
+    sync_dir(dir)
+}
+
+/// The [`JournalIndex`] of a segment, compressed or not.
+fn sidecar(segment: &Segment) -> PathBuf {
+    let name = segment.path.file_name().unwrap().to_string_lossy();
+    let stem = name.split_once(".log").map_or(&*name, |(stem, _)| stem);
+    segment.path.with_file_name(format!("{stem}.idx"))
+}
+
+/// Whether the plain segment at `path` is an encrypted journal.
+fn is_encrypted(path: &Path) -> io::Result<bool> {
+    let mut magic = [0u8; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        result => result.map(|()| magic == ENCRYPTED_MAGIC),
+    }
+}
+
+/// Remove a file that may not be there.
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Compress `segment`, dropping its index: offsets into a compressed
+/// segment are of no use.
+fn compress(dir: &Path, segment: &Segment, codec: &dyn SegmentCodec) -> io::Result<Segment> {
+    let mut name = segment.path.as_os_str().to_owned();
+    name.push(format!(".{}", codec.extension()));
+    let path = PathBuf::from(name);
+    let tmp = tmp_path(&path);
+    let mut output = BufWriter::new(File::create(&tmp)?);
+    codec.compress(&mut BufReader::new(File::open(&segment.path)?), &mut output)?;
+    let file = output.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    sync_dir(dir)?;
+    fs::remove_file(&segment.path)?;
+    remove(&sidecar(segment))?;
+    sync_dir(dir)?;
+    Ok(Segment {
+        ext: Some(codec.extension().to_string()),
+        len: fs::metadata(&path)?.len(),
+        path,
+        ..segment.clone()
+    })
+}
+
+/// What a [`JournalWriter`] writing to a directory keeps.
+pub(super) struct Rotation {
+    dir: PathBuf,
+    config: RotationConfig,
+    /// The segment being written.
+    seq: u64,
+    /// Its first record's timestamp.
+    started: Option<SystemTime>,
+    /// The latest record timestamp, retention ages are counted back from.
+    newest: Option<SystemTime>,
+    /// Whether segments were completed since the last [`Rotation::maintain`].
+    pending: bool,
+}
+
+impl Rotation {
+    /// Compress completed segments, then prune them, if any were completed.
+    pub(super) fn maintain(&mut self) -> Result<(), JournalError> {
+        if !self.pending {
+            return Ok(());
+        }
+        let mut segments = list(&self.dir)?;
+        if let Some(codec) = &self.config.codec {
+            for s in segments.iter_mut() {
+                if s.seq < self.seq && s.ext.is_none() {
+                    *s = compress(&self.dir, s, codec.as_ref())?;
+                }
+            }
+        }
+        self.prune(&segments)?;
+        self.pending = false;
+        Ok(())
+    }
+
+    fn prune(&self, segments: &[Segment]) -> io::Result<()> {
+        let r = &self.config.retention;
+        let mut count = segments.len();
+        let mut total: u64 = segments.iter().map(|s| s.len).sum();
+        let newest_ms = self
+            .newest
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64);
+        for (i, s) in segments.iter().enumerate() {
+            if s.seq >= self.seq {
+                break;
+            }
+            // the next segment opened after this one's last record
+            let expired = match (r.max_age, newest_ms, segments.get(i + 1)) {
+                (Some(age), Some(newest), Some(next)) => {
+                    next.start_ms.saturating_add(age.as_millis() as u64) < newest
+                }
+                _ => false,
+            };
+            let over = r.max_segments.is_some_and(|max| count > max)
+                || r.max_total_bytes.is_some_and(|max| total > max);
+            if !(expired || over) {
+                break;
+            }
+            let index = sidecar(s);
+            match &r.archive {
+                Some(archive) => {
+                    fs::create_dir_all(archive)?;
+                    fs::rename(&s.path, archive.join(s.path.file_name().unwrap()))?;
+                    if index.exists() {
+                        fs::rename(&index, archive.join(index.file_name().unwrap()))?;
+                    }
+                }
+                None => {
+                    fs::remove_file(&s.path)?;
+                    remove(&index)?;
+                }
+            }
+            count -= 1;
+            total -= s.len;
+        }
+        sync_dir(&self.dir)
+    }
+}
+
+impl JournalWriter {
+    /// Write to a directory of segments, creating it if need be, and
+    /// carry on in its newest segment.
+    pub fn open_dir(dir: impl AsRef<Path>, config: RotationConfig) -> Result<Self, JournalError> {
+        JournalWriter::open_dir_with(dir.as_ref(), config, Keys::None)
+    }
+
+    /// As [`JournalWriter::open_dir`], the newest segment only carried on
+    /// in if it is encrypted, or not, as the segments to be written are.
+    pub(super) fn open_dir_with(
+        dir: &Path,
+        config: RotationConfig,
+        keys: Keys,
+    ) -> Result<Self, JournalError> {
+        let dir = dir.to_path_buf();
+        fs::create_dir_all(&dir)?;
+        recover(&dir)?;
+        let path = match list(&dir)?.pop() {
+            Some(s) if s.ext.is_none() && is_encrypted(&s.path)? == keys.encrypting() => s.path,
+            last => {
+                let seq = last.map_or(1, |s| s.seq + 1);
+                create_segment(&dir, seq, SystemTime::now(), &keys.header()?.0)?.0
+            }
+        };
+        let seq = parse_name(path.file_name().unwrap().to_str().unwrap())
+            .unwrap()
+            .0;
+        let mut writer = JournalWriter::open_with(&path, keys.clone())?;
+        let (mut started, mut newest) = (None, None);
+        for record in JournalReader::open_with(&path, &keys)?.flatten() {
+            started.get_or_insert(record.timestamp);
+            newest = Some(record.timestamp);
+        }
+        writer.rotation = Some(Box::new(Rotation {
+            dir,
+            config,
+            seq,
+            started,
+            newest,
+            pending: true,
+        }));
+        writer.rotation.as_mut().unwrap().maintain()?;
+        Ok(writer)
+    }
+
+    /// Start a new segment if a record `len` bytes long, from `at`, is not
+    /// to go in the current one.
+    pub(super) fn roll_if_due(&mut self, len: u64, at: SystemTime) -> Result<(), JournalError> {
+        let r = self.rotation.as_mut().unwrap();
+        r.newest = Some(r.newest.map_or(at, |t| t.max(at)));
+        let started = *r.started.get_or_insert(at);
+        let empty = self.len <= self.first;
+        let too_big = r.config.max_bytes.is_some_and(|max| self.len + len > max);
+        let too_old = r
+            .config
+            .max_age
+            .is_some_and(|age| at.duration_since(started).is_ok_and(|d| d >= age));
+        if empty || !(too_big || too_old) {
+            return Ok(());
+        }
+        let (header, cipher) = self.keys.header()?;
+        let (path, file) = create_segment(&r.dir, r.seq + 1, at, &header)?;
+        let old = std::mem::replace(&mut self.file, BufWriter::new(file));
+        let old_path = std::mem::replace(&mut self.path, path);
+        r.seq += 1;
+        r.started = Some(at);
+        r.pending = true;
+        self.len = header.len() as u64;
+        self.first = self.len;
+        self.cipher = cipher;
+        let old = old.into_inner().map_err(|e| e.into_error())?;
+        old.sync_data()?;
+        if let Some(index) = &mut self.index {
+            let bucket = index.bucket();
+            std::mem::replace(&mut **index, JournalIndex::new(bucket))
+                .save(&JournalIndex::path_for(&old_path))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the segments of a directory in order, as one journal. A segment
+/// ending in a torn or corrupt record (yielded as an error) is followed by
+/// the next one. Segments created while reading are read in turn, but not
+/// records appended to a segment already read to its end.
+pub struct JournalDirReader {
+    dir: PathBuf,
+    codec: Option<Arc<dyn SegmentCodec>>,
+    pub(super) keys: Keys,
+    reading: Option<(PathBuf, JournalReader<Box<dyn Read + Send>>)>,
+    last_seq: Option<u64>,
+    done: bool,
+}
+
+impl JournalDirReader {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, JournalError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::read_dir(&dir)?;
+        Ok(JournalDirReader {
+            dir,
+            codec: None,
+            keys: Keys::None,
+            reading: None,
+            last_seq: None,
+            done: false,
+        })
+    }
+
+    /// Read segments compressed by `codec` as well.
+    pub fn with_codec(mut self, codec: Arc<dyn SegmentCodec>) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// The segment being read.
+    pub fn segment(&self) -> Option<&Path> {
+        self.reading.as_ref().map(|(path, _)| path.as_path())
+    }
+
+    /// Skip to `device_id`'s records from `from` on: to the last segment
+    /// opened by then, and in it to where its index points, if it has one.
+    /// Segments are picked by the times in their names, which assumes
+    /// records were written in time order. Returns whether an index was
+    /// used; records of other devices and times still follow, for the
+    /// caller to skip.
+    pub fn seek_device(
+        &mut self,
+        device_id: [u8; 8],
+        from: SystemTime,
+    ) -> Result<bool, JournalError> {
+        let mut segments = list(&self.dir)?;
+        segments.dedup_by_key(|s| s.seq);
+        let from_ms = from
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let at = segments
+            .iter()
+            .rposition(|s| s.start_ms <= from_ms)
+            .unwrap_or(0);
+        self.reading = None;
+        self.done = false;
+        let Some(s) = segments.get(at) else {
+            self.last_seq = None;
+            return Ok(false);
+        };
+        self.last_seq = Some(s.seq);
+        if s.ext.is_some() {
+            self.reading = Some((s.path.clone(), self.open_segment(s)?));
+            return Ok(false);
+        }
+        let mut reader = JournalReader::open_with(&s.path, &self.keys)?;
+        let index = JournalIndex::for_journal(&s.path).ok();
+        let indexed = reader.seek_device(index.as_ref(), device_id, from)?;
+        self.reading = Some((s.path.clone(), reader.boxed()));
+        Ok(indexed)
+    }
+
+    fn open_segment(
+        &self,
+        s: &Segment,
+    ) -> Result<JournalReader<Box<dyn Read + Send>>, JournalError> {
+        let file = File::open(&s.path)?;
+        let input: Box<dyn Read + Send> = match (&s.ext, &self.codec) {
+            (None, _) => Box::new(file),
+            (Some(ext), Some(codec)) if ext == codec.extension() => codec.decompress(file)?,
+            (Some(ext), _) => {
+                return Err(JournalError::Io(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{}: no codec for .{ext}", s.path.display()),
+                )));
+            }
+        };
+        JournalReader::start(
+            Box::new(BufReader::new(input)) as Box<dyn Read + Send>,
+            &self.keys,
+        )
+    }
+}
+
+impl Iterator for JournalDirReader {
+    type Item = Result<FrameRecord, JournalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.done {
+                return None;
+            }
+            if let Some((_, reader)) = &mut self.reading {
+                match reader.next() {
+                    Some(record) => return Some(record),
+                    None => self.reading = None,
+                }
+            }
+            let segments = match list(&self.dir) {
+                Ok(segments) => segments,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            };
+            let last = self.last_seq;
+            let next = segments
+                .into_iter()
+                .find(|s| last.is_none_or(|l| s.seq > l))?;
+            self.last_seq = Some(next.seq);
+            match self.open_segment(&next) {
+                Ok(reader) => self.reading = Some((next.path, reader)),
+                // pruned since it was listed
+                Err(JournalError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::RECORD_MARK;
+    use crate::sink::FrameSink;
+    use crate::transport::tests::frame;
+
+    /// Flips every bit: not smaller, but not the same bytes either.
+    #[derive(Debug)]
+    struct Invert;
+
+    struct Inverted<R>(R);
+
+    impl<R: Read> Read for Inverted<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.read(buf)?;
+            buf[..n].iter_mut().for_each(|b| *b = !*b);
+            Ok(n)
+        }
+    }
+
+    impl SegmentCodec for Invert {
+        fn extension(&self) -> &str {
+            "inv"
+        }
+
+        fn compress(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+            io::copy(&mut Inverted(input), output).map(|_| ())
+        }
+
+        fn decompress(&self, input: File) -> io::Result<Box<dyn Read + Send>> {
+            Ok(Box::new(Inverted(input)))
+        }
+    }
+
+    fn record(counter: u64) -> FrameRecord {
+        let mut r = FrameRecord::rx(frame(counter));
+        r.timestamp = UNIX_EPOCH + Duration::from_secs(1_700_000_000 + counter);
+        r
+    }
+
+    fn write(journal: &mut JournalWriter, counters: std::ops::RangeInclusive<u64>) {
+        for c in counters {
+            journal.record(&record(c)).unwrap();
+            journal.flush().unwrap();
+        }
+    }
+
+    fn counters(reader: JournalDirReader) -> Vec<u64> {
+        reader
+            .map(|r| r.unwrap().frame().unwrap().header.counter)
+            .collect()
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Bytes a record of [`record`] takes.
+    fn record_len() -> u64 {
+        (super::super::encode_entry(&record(1)).len() + RECORD_MARK.len() + 8) as u64
+    }
+
+    #[test]
+    fn rolls_by_size_and_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RotationConfig {
+            max_bytes: Some(4 + 3 * record_len()),
+            ..RotationConfig::default()
+        };
+        let mut journal = JournalWriter::open_dir(dir.path(), config).unwrap();
+        write(&mut journal, 1..=7);
+        let segments = names(dir.path());
+        assert_eq!(segments.len(), 3, "{segments:?}");
+        assert!(segments[0].starts_with("00000001-"));
+        // named after the record that opened it
+        assert_eq!(segments[1], "00000002-1700000004000.log");
+        assert_eq!(segments[2], "00000003-1700000007000.log");
+        assert_eq!(
+            counters(JournalDirReader::open(dir.path()).unwrap()),
+            (1..=7).collect::<Vec<_>>()
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = RotationConfig {
+            max_age: Some(Duration::from_secs(2)),
+            ..RotationConfig::default()
+        };
+        let mut journal = JournalWriter::open_dir(dir.path(), config).unwrap();
+        write(&mut journal, 1..=5);
+        // 1 2 | 3 4 | 5
+        assert_eq!(names(dir.path()).len(), 3);
+    }
+
+    #[test]
+    fn reopening_carries_on_in_the_newest_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RotationConfig {
+            max_bytes: Some(4 + 2 * record_len()),
+            ..RotationConfig::default()
+        };
+        let mut journal = JournalWriter::open_dir(dir.path(), config.clone()).unwrap();
+        write(&mut journal, 1..=3);
+        drop(journal);
+        let mut journal = JournalWriter::open_dir(dir.path(), config).unwrap();
+        write(&mut journal, 4..=4);
+        assert_eq!(names(dir.path()).len(), 2);
+        write(&mut journal, 5..=5);
+        assert_eq!(names(dir.path()).len(), 3);
+        assert_eq!(
+            counters(JournalDirReader::open(dir.path()).unwrap()),
+            [1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn compresses_and_prunes_completed_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("archive");
+        let segments = dir.path().join("journal");
+        let config = RotationConfig {
+            max_bytes: Some(4 + record_len()),
+            retention: Retention {
+                max_segments: Some(3),
+                archive: Some(archive.clone()),
+                ..Retention::default()
+            },
+            codec: Some(Arc::new(Invert)),
+            ..RotationConfig::default()
+        };
+        let mut journal = JournalWriter::open_dir(&segments, config).unwrap();
+        write(&mut journal, 1..=6);
+        let kept = names(&segments);
+        assert_eq!(kept.len(), 3);
+        assert!(kept[0].starts_with("00000004-") && kept[0].ends_with(".log.inv"));
+        assert!(kept[2].ends_with(".log"));
+        assert_eq!(names(&archive).len(), 3);
+
+        let reader = JournalDirReader::open(&segments)
+            .unwrap()
+            .with_codec(Arc::new(Invert));
+        assert_eq!(counters(reader), [4, 5, 6]);
+        let mut plain = JournalDirReader::open(&segments).unwrap();
+        assert!(matches!(
+            plain.next(),
+            Some(Err(JournalError::Io(e))) if e.kind() == io::ErrorKind::Unsupported
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = RotationConfig {
+            max_bytes: Some(4 + record_len()),
+            retention: Retention {
+                max_age: Some(Duration::from_secs(2)),
+                ..Retention::default()
+            },
+            ..RotationConfig::default()
+        };
+        let mut journal = JournalWriter::open_dir(dir.path(), config).unwrap();
+        write(&mut journal, 1..=6);
+        // 4 opened 2s before 6, so 3 and older have gone
+        assert_eq!(
+            counters(JournalDirReader::open(dir.path()).unwrap()),
+            [3, 4, 5, 6]
+        );
+    }
+
+    #[test]
+    fn recovers_from_an_interrupted_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RotationConfig {
+            max_bytes: Some(4 + 2 * record_len()),
+            ..RotationConfig::default()
+        };
+        let mut journal = JournalWriter::open_dir(dir.path(), config.clone()).unwrap();
+        write(&mut journal, 1..=4);
+        drop(journal);
+        let first = dir.path().join(&names(dir.path())[0]);
+
+        // a compressed copy half written, another finished, and a segment
+        // created but not yet written to
+        fs::write(tmp_path(&first.with_extension("log.inv")), b"half").unwrap();
+        let mut inverted = Vec::new();
+        Invert
+            .compress(&mut File::open(&first).unwrap(), &mut inverted)
+            .unwrap();
+        let mut done = first.as_os_str().to_owned();
+        done.push(".inv");
+        fs::write(&done, inverted).unwrap();
+        create_segment(
+            dir.path(),
+            3,
+            UNIX_EPOCH + Duration::from_secs(1_700_000_005),
+            &crate::journal::JOURNAL_MAGIC,
+        )
+        .unwrap();
+        fs::write(dir.path().join("00000004-1.log.tmp"), b"").unwrap();
+        let reader = JournalDirReader::open(dir.path())
+            .unwrap()
+            .with_codec(Arc::new(Invert));
+        assert_eq!(counters(reader), [1, 2, 3, 4]);
+
+        let mut journal = JournalWriter::open_dir(dir.path(), config).unwrap();
+        write(&mut journal, 5..=5);
+        let segments = names(dir.path());
+        assert_eq!(segments.len(), 3, "{segments:?}");
+        assert!(segments[0].ends_with(".log.inv"));
+        let reader = JournalDirReader::open(dir.path())
+            .unwrap()
+            .with_codec(Arc::new(Invert));
+        assert_eq!(counters(reader), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn segment_names() {
+        assert_eq!(parse_name("00000002-17.log"), Some((2, 17, None)));
+        assert_eq!(
+            parse_name("00000002-17.log.gz"),
+            Some((2, 17, Some("gz".into())))
+        );
+        assert_eq!(parse_name("00000002-17.log.tmp"), None);
+        assert_eq!(parse_name("00000002-17.log.gz.tmp"), None);
+        assert_eq!(parse_name("notes.txt"), None);
+    }
+}
@@ -0,0 +1,178 @@
+//! Per-device keys derived from a fleet master secret (feature `auth`).
+//!
+//! [`derive_device_key`] is HKDF-SHA256 (RFC 5869) with
+//!
+//! - salt: the ASCII bytes `pipproto-device-key-v1`,
+//! - input key material: the master secret,
+//! - info: the purpose label (`auth` or `encrypt`, ASCII), a zero byte,
+//!   then the 8 DeviceID bytes,
+//! - output length: 32 bytes,
+//!
+//! so firmware can derive its own key from the master secret it was
+//! flashed with, or be given just its key. RFC §9 lists test vectors.
+//! [`DerivedKeys`] is a [`KeyStore`] that derives the auth key of any
+//! device asked about.
+
+use std::fmt;
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::auth::KeyStore;
+
+const SALT: &[u8] = b"pipproto-device-key-v1";
+
+/// Shortest master secret accepted.
+pub const MIN_MASTER_LEN: usize = 16;
+
+pub type DeviceKey = [u8; 32];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPurpose {
+    /// HMAC keys: challenge responses and frame tags.
+    Auth,
+    Encrypt,
+}
+
+impl KeyPurpose {
+    pub fn label(self) -> &'static str {
+        match self {
+            KeyPurpose::Auth => "auth",
+            KeyPurpose::Encrypt => "encrypt",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShortSecret {
+    pub len: usize,
+}
+
+impl fmt::Display for ShortSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "master secret of {} bytes, need at least {MIN_MASTER_LEN}",
+            self.len
+        )
+    }
+}
+
+impl std::error::Error for ShortSecret {}
+
+/// A master secret. Its `Debug` output leaves the bytes out.
+#[derive(Clone)]
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Result<Self, ShortSecret> {
+        let bytes = bytes.into();
+        if bytes.len() < MIN_MASTER_LEN {
+            return Err(ShortSecret { len: bytes.len() });
+        }
+        Ok(Secret(bytes))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({} bytes)", self.0.len())
+    }
+}
+
+pub fn derive_device_key(master: &Secret, device_id: &[u8; 8], purpose: KeyPurpose) -> DeviceKey {
+    let hk = Hkdf::<Sha256>::new(Some(SALT), &master.0);
+    let info = [purpose.label().as_bytes(), &[0], device_id].concat();
+    let mut key = [0u8; 32];
+    hk.expand(&info, &mut key).unwrap();
+    key
+}
+
+/// Every device's [`KeyPurpose::Auth`] key, derived on demand.
+#[derive(Debug, Clone)]
+pub struct DerivedKeys {
+    master: Secret,
+}
+
+impl DerivedKeys {
+    pub fn new(master: Secret) -> Self {
+        DerivedKeys { master }
+    }
+}
+
+impl KeyStore for DerivedKeys {
+    fn key(&self, device_id: &[u8; 8]) -> Option<Vec<u8>> {
+        Some(derive_device_key(&self.master, device_id, KeyPurpose::Auth).to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(key: DeviceKey) -> String {
+        crate::hex::encode(&key)
+    }
+
+    /// The vectors in RFC §9.
+    #[test]
+    fn published_vectors() {
+        let master = Secret::new((0..32).collect::<Vec<u8>>()).unwrap();
+        let vectors: [([u8; 8], KeyPurpose, &str); 6] = [
+            (
+                *b"DEV00001",
+                KeyPurpose::Auth,
+                "6b7110b5413e53b77b43f1f89afa3222754f5fafe5ccf4618e528be68a5d2359",
+            ),
+            (
+                *b"DEV00001",
+                KeyPurpose::Encrypt,
+                "5ad5ef79cf2b35e4554262b0f7c9c6ffbc2111567babad2b352cd0c5080c1d6d",
+            ),
+            (
+                [0; 8],
+                KeyPurpose::Auth,
+                "5424c2a07f120ff2c18f644bfd1c818ff563c3a3bb146dd17bfbaeba447ea472",
+            ),
+            (
+                [0; 8],
+                KeyPurpose::Encrypt,
+                "9d4db3c5ead7f989d819e8cfb6dd3b2577d5d0cb8e60b589e97a0224b46e2f31",
+            ),
+            (
+                [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+                KeyPurpose::Auth,
+                "1b65d6e77655dca5337a4e24d9bebc7b0176e96b6fb22dd8ddf46b5fa2fb098b",
+            ),
+            (
+                [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+                KeyPurpose::Encrypt,
+                "d31e76c90a56cc6ab0be4afba04586798a73a7a13969fc2c67c16d22c6eb9dee",
+            ),
+        ];
+        for (device_id, purpose, expected) in vectors {
+            assert_eq!(
+                hex(derive_device_key(&master, &device_id, purpose)),
+                expected,
+                "{device_id:?} {purpose:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn store_hands_out_auth_keys() {
+        assert_eq!(
+            Secret::new(vec![7; 15]).unwrap_err(),
+            ShortSecret { len: 15 }
+        );
+        let master = Secret::new(vec![7; 16]).unwrap();
+        assert_eq!(format!("{master:?}"), "Secret(16 bytes)");
+        let store = DerivedKeys::new(master.clone());
+        let dev = *b"DEV00001";
+        assert_eq!(
+            store.key(&dev).unwrap(),
+            derive_device_key(&master, &dev, KeyPurpose::Auth)
+        );
+        assert_ne!(store.key(&dev), store.key(b"DEV00002"));
+    }
+}
@@ -0,0 +1,106 @@
+//! PiProto v1 reference implementation.
+//!
+//! See `docs/rfc-0001-pipproto-v1.md` for the wire format.
+
+pub mod ack;
+pub mod acl;
+pub mod annotate;
+pub mod anomaly;
+pub mod assign;
+pub mod audit;
+#[cfg(feature = "auth")]
+pub mod auth;
+pub mod batch;
+pub mod bloom;
+mod body;
+pub mod breaker;
+pub mod bridge;
+pub mod bus;
+#[cfg(feature = "cbor")]
+pub mod cbor;
+pub mod cdefs;
+pub mod clock;
+pub mod codec;
+pub mod connpool;
+pub mod corpus;
+pub mod counter;
+pub mod counter_store;
+pub mod crc;
+pub mod datagram;
+pub mod dedup;
+pub mod detect;
+pub mod diff;
+pub mod discovery;
+pub mod disk_queue;
+pub mod dispatch;
+pub mod dissector;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+mod frame;
+pub mod gaps;
+mod hex;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod journal;
+#[cfg(feature = "auth")]
+pub mod kdf;
+pub mod liveness;
+#[cfg(feature = "log")]
+pub mod logging;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod netsim;
+pub mod observer;
+#[cfg(feature = "pairing")]
+pub mod pairing;
+pub mod ping;
+pub mod playback;
+pub mod pool;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+#[cfg(feature = "python")]
+mod python;
+pub mod qos;
+pub mod queue;
+pub mod ratelimit;
+pub mod registry;
+pub mod reorder;
+pub mod repl;
+pub mod replay;
+#[cfg(feature = "serde")]
+mod repr;
+pub mod request;
+pub mod retry;
+#[cfg(feature = "schema")]
+pub mod schema;
+pub mod sender;
+pub mod session;
+pub mod shaper;
+pub mod sim;
+pub mod sink;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+pub mod timesync;
+#[cfg(feature = "tracing")]
+mod trace;
+pub mod transport;
+pub mod tunnel;
+mod vectored;
+pub mod vectors;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod wheel;
+pub mod window;
+pub mod workload;
+
+pub use body::{Body, BodyError, INLINE_BODY};
+pub use frame::{
+    DecodeError, Flags, FrameHeaderV1, FrameId, FrameV1, FrameV1B, HEADER_LEN_V1, HeaderBytes,
+    MAGIC, MsgType, VERSION_V1,
+};
@@ -0,0 +1,233 @@
+//! Noticing devices that have gone quiet.
+//!
+//! A [`LivenessMonitor`] is fed the header of every received frame, the
+//! same frames a [`DeviceRegistry`](crate::registry::DeviceRegistry)
+//! sees, and the passage of time, and reports devices going
+//! [`LivenessEvent::Offline`] and coming back [`LivenessEvent::Online`].
+//! It does no I/O.
+//!
+//! A device is offline after `missed_intervals` whole `timeout`s without
+//! a frame, so one lost frame on a lossy link does not flap it. With
+//! `probe_grace` set, it is first sent a probe, [`Control::Ping`] as an
+//! ack-required COMMAND, and has that long to answer before it is
+//! declared offline; any frame, the ack included, counts as an answer.
+//!
+//! [`Control::Ping`]: crate::session::Control::Ping
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::FrameHeaderV1;
+use crate::clock::{Clock, SystemClock};
+
+#[derive(Debug, Clone)]
+pub struct LivenessConfig {
+    pub timeout: Duration,
+    /// Consecutive silent timeouts before a device is offline.
+    pub missed_intervals: u32,
+    /// Probe a silent device and give it this long before it is offline.
+    pub probe_grace: Option<Duration>,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        LivenessConfig {
+            timeout: Duration::from_secs(30),
+            missed_intervals: 3,
+            probe_grace: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LivenessEvent {
+    Online([u8; 8]),
+    Offline([u8; 8]),
+    /// Send the device a PING; see the module docs.
+    Probe([u8; 8]),
+}
+
+struct Device {
+    heard: Instant,
+    online: bool,
+    probed: bool,
+}
+
+pub struct LivenessMonitor {
+    clock: Arc<dyn Clock>,
+    config: LivenessConfig,
+    devices: BTreeMap<[u8; 8], Device>,
+}
+
+impl LivenessMonitor {
+    pub fn new(config: LivenessConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: LivenessConfig, clock: Arc<dyn Clock>) -> Self {
+        LivenessMonitor {
+            clock,
+            config: LivenessConfig {
+                missed_intervals: config.missed_intervals.max(1),
+                ..config
+            },
+            devices: BTreeMap::new(),
+        }
+    }
+
+    /// Expect frames from `device_id` from now on, taking it to be online,
+    /// e.g. for devices known from before a restart.
+    pub fn watch(&mut self, device_id: [u8; 8]) {
+        let now = self.clock.now();
+        self.devices.entry(device_id).or_insert(Device {
+            heard: now,
+            online: true,
+            probed: false,
+        });
+    }
+
+    pub fn forget(&mut self, device_id: [u8; 8]) {
+        self.devices.remove(&device_id);
+    }
+
+    /// Note a frame from its sender. Returns `Online` if the sender is new
+    /// or was offline.
+    pub fn on_frame(&mut self, header: &FrameHeaderV1) -> Option<LivenessEvent> {
+        let now = self.clock.now();
+        let device = self.devices.entry(header.device_id).or_insert(Device {
+            heard: now,
+            online: false,
+            probed: false,
+        });
+        device.heard = now;
+        device.probed = false;
+        let was_online = std::mem::replace(&mut device.online, true);
+        (!was_online).then_some(LivenessEvent::Online(header.device_id))
+    }
+
+    /// Probes to send and devices gone offline by `now`, by device id.
+    pub fn poll(&mut self, now: Instant) -> Vec<LivenessEvent> {
+        let limit = self.config.timeout * self.config.missed_intervals;
+        let grace = self.config.probe_grace;
+        let mut events = Vec::new();
+        for (&id, device) in &mut self.devices {
+            if !device.online || now < device.heard + limit {
+                continue;
+            }
+            let Some(grace) = grace else {
+                device.online = false;
+                events.push(LivenessEvent::Offline(id));
+                continue;
+            };
+            if !device.probed {
+                device.probed = true;
+                events.push(LivenessEvent::Probe(id));
+            }
+            if now >= device.heard + limit + grace {
+                device.online = false;
+                events.push(LivenessEvent::Offline(id));
+            }
+        }
+        events
+    }
+
+    /// When [`LivenessMonitor::poll`] next has something to report.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let limit = self.config.timeout * self.config.missed_intervals;
+        let grace = self.config.probe_grace.unwrap_or_default();
+        self.devices
+            .values()
+            .filter(|d| d.online)
+            .map(|d| d.heard + limit + if d.probed { grace } else { Duration::ZERO })
+            .min()
+    }
+
+    /// `None` for a device never heard from or watched.
+    pub fn is_online(&self, device_id: [u8; 8]) -> Option<bool> {
+        self.devices.get(&device_id).map(|d| d.online)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::transport::tests::frame;
+
+    const DEV: [u8; 8] = *b"DEV00001";
+    const SEC: Duration = Duration::from_secs(1);
+
+    fn monitor(
+        missed_intervals: u32,
+        probe_grace: Option<Duration>,
+    ) -> (LivenessMonitor, ManualClock) {
+        let clock = ManualClock::new();
+        let config = LivenessConfig {
+            timeout: 10 * SEC,
+            missed_intervals,
+            probe_grace,
+        };
+        (
+            LivenessMonitor::with_clock(config, Arc::new(clock.clone())),
+            clock,
+        )
+    }
+
+    #[test]
+    fn offline_after_silence_and_online_again() {
+        let (mut m, clock) = monitor(1, None);
+        let header = frame(1).header;
+        assert_eq!(m.on_frame(&header), Some(LivenessEvent::Online(DEV)));
+        assert_eq!(m.on_frame(&header), None);
+        assert_eq!(m.next_deadline(), Some(clock.now() + 10 * SEC));
+
+        clock.advance(9 * SEC);
+        assert_eq!(m.poll(clock.now()), []);
+        clock.advance(SEC);
+        assert_eq!(m.poll(clock.now()), [LivenessEvent::Offline(DEV)]);
+        assert_eq!(m.poll(clock.now()), [], "reported once");
+        assert_eq!(m.next_deadline(), None);
+        assert_eq!(m.is_online(DEV), Some(false));
+
+        clock.advance(60 * SEC);
+        assert_eq!(m.on_frame(&header), Some(LivenessEvent::Online(DEV)));
+    }
+
+    #[test]
+    fn consecutive_misses_suppress_flapping() {
+        let (mut m, clock) = monitor(3, None);
+        m.on_frame(&frame(1).header);
+        // a frame every 25 s misses intervals, but never three in a row
+        for _ in 0..10 {
+            clock.advance(25 * SEC);
+            assert_eq!(m.poll(clock.now()), []);
+            assert_eq!(m.on_frame(&frame(1).header), None);
+        }
+        clock.advance(30 * SEC);
+        assert_eq!(m.poll(clock.now()), [LivenessEvent::Offline(DEV)]);
+    }
+
+    #[test]
+    fn probe_gets_a_grace_period() {
+        let (mut m, clock) = monitor(1, Some(2 * SEC));
+        m.watch(DEV);
+        m.watch(*b"DEV00002");
+        clock.advance(10 * SEC);
+        assert_eq!(
+            m.poll(clock.now()),
+            [
+                LivenessEvent::Probe(DEV),
+                LivenessEvent::Probe(*b"DEV00002")
+            ]
+        );
+        assert_eq!(m.next_deadline(), Some(clock.now() + 2 * SEC));
+
+        // DEV00001 answers the probe in time
+        clock.advance(SEC);
+        assert_eq!(m.on_frame(&frame(5).header), None);
+        clock.advance(SEC);
+        assert_eq!(m.poll(clock.now()), [LivenessEvent::Offline(*b"DEV00002")]);
+        assert_eq!(m.is_online(DEV), Some(true));
+    }
+}
@@ -0,0 +1,209 @@
+//! `log` crate output for frame traffic (feature `log`).
+//!
+//! [`LogObserver`] writes one line per frame, by default at debug:
+//!
+//! ```text
+//! tx Event dev=4445563030303031 ctr=7 len=24
+//! rx decode error kind=bad_magic offset=0 bytes=5858010100
+//! ```
+//!
+//! Decode errors are logged at warn with a hex preview of the bytes.
+
+use std::fmt::Write;
+
+use log::Level;
+
+use crate::observer::FrameObserver;
+use crate::{DecodeError, FrameV1, MsgType};
+
+const TARGET: &str = "pipproto";
+
+#[derive(Debug, Clone)]
+pub struct LogObserver {
+    level: Level,
+    body_preview: usize,
+    error_preview: usize,
+    msg_types: Option<Vec<MsgType>>,
+}
+
+impl Default for LogObserver {
+    fn default() -> Self {
+        LogObserver {
+            level: Level::Debug,
+            body_preview: 0,
+            error_preview: 16,
+            msg_types: None,
+        }
+    }
+}
+
+impl LogObserver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Level of the per-frame lines. Decode errors are always warn.
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Append up to `n` body bytes as hex to each frame line.
+    pub fn with_body_preview(mut self, n: usize) -> Self {
+        self.body_preview = n;
+        self
+    }
+
+    /// Hex bytes shown for frames that fail to decode (default 16).
+    pub fn with_error_preview(mut self, n: usize) -> Self {
+        self.error_preview = n;
+        self
+    }
+
+    /// Only log frames of these types.
+    pub fn with_msg_types(mut self, types: impl IntoIterator<Item = MsgType>) -> Self {
+        self.msg_types = Some(types.into_iter().collect());
+        self
+    }
+
+    fn log_frame(&self, dir: &str, frame: &FrameV1) {
+        let h = &frame.header;
+        if !log::log_enabled!(target: TARGET, self.level)
+            || self
+                .msg_types
+                .as_ref()
+                .is_some_and(|t| !t.contains(&h.msg_type))
+        {
+            return;
+        }
+        let mut line = format!(
+            "{dir} {:?} dev={} ctr={} len={}",
+            h.msg_type,
+            h.device_id_hex(),
+            h.counter,
+            frame.encoded_len()
+        );
+        if self.body_preview > 0 {
+            line.push_str(" body=");
+            push_preview(&mut line, &frame.body, self.body_preview);
+        }
+        log::log!(target: TARGET, self.level, "{line}");
+    }
+}
+
+fn push_preview(out: &mut String, bytes: &[u8], max: usize) {
+    out.push_str(&crate::hex::encode(&bytes[..bytes.len().min(max)]));
+    if bytes.len() > max {
+        out.push_str("..");
+    }
+}
+
+impl FrameObserver for LogObserver {
+    fn on_rx(&self, frame: &FrameV1) {
+        self.log_frame("rx", frame);
+    }
+
+    fn on_tx(&self, frame: &FrameV1) {
+        self.log_frame("tx", frame);
+    }
+
+    fn on_decode_error(&self, bytes: &[u8], error: &DecodeError) {
+        if !log::log_enabled!(target: TARGET, Level::Warn) {
+            return;
+        }
+        let mut line = format!("rx decode error kind={}", error.kind());
+        if let Some(offset) = error.offset() {
+            let _ = write!(line, " offset={offset}");
+        }
+        if !bytes.is_empty() && self.error_preview > 0 {
+            line.push_str(" bytes=");
+            push_preview(&mut line, bytes, self.error_preview);
+        }
+        log::warn!(target: TARGET, "{line}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::frame;
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    thread_local! {
+        static LINES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Collects records per thread, so parallel tests don't see each other.
+    struct TestLogger;
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let line = format!("{} {}", record.level(), record.args());
+            LINES.with(|l| l.borrow_mut().push(line));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn captured(f: impl FnOnce()) -> Vec<String> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&TestLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        LINES.with(|l| l.borrow_mut().clear());
+        f();
+        LINES.with(|l| l.take())
+    }
+
+    #[test]
+    fn frame_line_format() {
+        let obs = LogObserver::new().with_body_preview(2);
+        let lines = captured(|| {
+            obs.on_tx(&frame(7));
+            obs.on_rx(&frame(8));
+        });
+        assert_eq!(
+            lines,
+            [
+                "DEBUG tx Event dev=4445563030303031 ctr=7 len=24 body=0102..",
+                "DEBUG rx Event dev=4445563030303031 ctr=8 len=24 body=0102..",
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_error_is_warn_with_preview() {
+        let obs = LogObserver::new().with_error_preview(3);
+        let lines = captured(|| {
+            obs.on_decode_error(b"XX\x01\x01\x00", &DecodeError::BadMagic);
+            obs.on_decode_error(&[], &DecodeError::TooShort);
+        });
+        assert_eq!(
+            lines,
+            [
+                "WARN rx decode error kind=bad_magic offset=0 bytes=585801..",
+                "WARN rx decode error kind=too_short",
+            ]
+        );
+    }
+
+    #[test]
+    fn level_and_type_filter() {
+        let obs = LogObserver::new()
+            .with_level(Level::Info)
+            .with_msg_types([MsgType::Command]);
+        let mut command = frame(2);
+        command.header.msg_type = MsgType::Command;
+        let lines = captured(|| {
+            obs.on_rx(&frame(1));
+            obs.on_rx(&command);
+        });
+        assert_eq!(lines, ["INFO rx Command dev=4445563030303031 ctr=2 len=24"]);
+    }
+}
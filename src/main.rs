@@ -1,298 +1,167 @@
-use std::fmt;
-
-const MAGIC: [u8; 2] = *b"PP";
-const VERSION_V1: u8 = 0x01;
-
-// Header v1: magic(2) + version(1) + type(1) + flags(1) + device_id(8) + counter(8) = 21
-const HEADER_LEN_V1: usize = 21;
-
-#[repr(u8)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum MsgType {
-    Event = 0x01,
-    Command = 0x02,
-    Ack = 0x03,
-    Error = 0x04,
-}
-
-impl MsgType {
-    fn from_u8(v: u8) -> Option<MsgType> {
-        match v {
-            0x01 => Some(MsgType::Event),
-            0x02 => Some(MsgType::Command),
-            0x03 => Some(MsgType::Ack),
-            0x04 => Some(MsgType::Error),
-            _ => None,
-        }
-    }
-}
-
-/// Flags byte (v1)
-/// bit0 = ACK_REQUIRED
-/// bits1..7 reserved MUST be zero
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-struct Flags(u8);
-
-impl Flags {
-    const ACK_REQUIRED: u8 = 0b0000_0001;
-
-    fn new(bits: u8) -> Result<Self, DecodeError> {
-        // reserved bits 1..7 must be zero
-        if (bits & 0b1111_1110) != 0 {
-            return Err(DecodeError::ReservedFlags(bits));
-        }
-        Ok(Flags(bits))
-    }
-
-    fn bits(self) -> u8 {
-        self.0
-    }
-
-    fn ack_required(self) -> bool {
-        (self.0 & Self::ACK_REQUIRED) != 0
-    }
-}
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct FrameHeaderV1 {
-    version: u8,
-    msg_type: MsgType,
-    flags: Flags,
-    device_id: [u8; 8],
-    counter: u64,
-}
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct FrameV1 {
-    header: FrameHeaderV1,
-    body: Vec<u8>,
+//! `pipproto decode` prints the fields of a frame given as hex or raw
+//! bytes, `pipproto dump` shows which bytes are which field, and
+//! `pipproto encode` builds a frame from JSON. The rest talk to the
+//! network, or read a journal of what was sent and received.
+//!
+//! ```text
+//! pipproto decode [--binary] [--json] [--file PATH | HEX...]
+//! pipproto dump [--binary] [--file PATH | HEX...]
+//! pipproto encode [--file PATH] [--out PATH]
+//! pipproto vectors [--out PATH]
+//! pipproto verify-vectors [--file PATH]
+//! pipproto dissector [--port PORT] [--out PATH]
+//! pipproto c-defs [--out PATH]
+//! pipproto schema [--out PATH]
+//! pipproto gen-corpus [--seed N] [--count N] --out DIR
+//! pipproto bench [--duration DURATION] [--warmup DURATION] [--threads N]
+//!                [--body-size SIZES] [--json]
+//! pipproto listen [--udp ADDR] [--tcp ADDR] [--json] [--device HEX] [--type TYPE]
+//!                 [--filter EXPR]
+//! pipproto send HOST:PORT --device HEX [--type TYPE] [--body BODY] [--flags N]
+//!               [--counter N] [--timeout DURATION] [--json]
+//! pipproto repl [--timeout DURATION]
+//! pipproto analyze [--json] [--top N] [--filter EXPR] JOURNAL
+//! pipproto diff [--window N] [--timing DURATION|off] [--direction rx|tx] [--json]
+//!               LEFT RIGHT
+//! pipproto audit [--direction rx|tx|both] [--buckets N] [--spill DIR] [--json]
+//!                JOURNAL...
+//! pipproto replay [--tcp] HOST:PORT [--speed X | --no-timing] [--filter EXPR]
+//!                 [--direction rx|tx|both] [--map-device FROM=TO]...
+//!                 [--xor-device MASK] [--reset-counters] JOURNAL
+//! pipproto tail [-f] [-n N | --from-start] [--since TIME] [--until TIME]
+//!               [--device HEX]... [--type TYPE]... [--counter A..B]
+//!               [--direction rx|tx] [--filter EXPR] [--json] JOURNAL
+//! pipproto reindex [--bucket DURATION] JOURNAL...
+//! ```
+//!
+//! Hex comes from the arguments, a file, or stdin when neither is given;
+//! whitespace in it is ignored. `--binary` reads raw bytes from the file or
+//! stdin instead. A frame that does not decode is reported with the byte
+//! offset at fault, and the exit status is 1; a usage error exits with 2.
+//! `dump` works on any input, marking the bytes of fields that are wrong,
+//! and exits with 1 if there are any.
+//!
+//! `encode` (feature `serde`) reads the frame's serde representation, with
+//! the body in `body` as base64 or in `body_hex`, from the file or stdin,
+//! and writes it as hex to stdout, or as raw bytes to `--out`. A document
+//! describing an impossible frame is rejected, with exit status 1.
+//!
+//! `vectors` writes the test vectors file (see `pipproto::vectors`), the
+//! one committed as `docs/test-vectors-v1.json`. `verify-vectors` (feature
+//! `serde`) checks this crate against such a file, listing the cases that
+//! fail, and exits with 1 if any do.
+//!
+//! `dissector` writes a Wireshark Lua plugin for frames on UDP `--port`
+//! (default 4000), generated from this crate's field layout (see
+//! `pipproto::dissector`). Copy it into Wireshark's plugin directory.
+//!
+//! `c-defs` writes `pipproto_defs.h`, the protocol's constants, field
+//! offsets and error codes as C `#define`s (see `pipproto::cdefs`), the
+//! one committed in `include/`.
+//!
+//! `schema` (feature `schema`) writes the JSON Schema of the documents
+//! `encode` reads and the HTTP gateway exchanges (see `pipproto::schema`),
+//! the one committed as `docs/frame-v1.schema.json`.
+//!
+//! `gen-corpus` writes `--count` (default 2000) edge-case frames into a
+//! directory, each as a `.bin` file beside a `.json` manifest saying what
+//! it is and whether it should decode (see `pipproto::corpus`). The same
+//! `--seed` (default 0) gives the same files.
+//!
+//! `bench` measures encode, decode and header-only decode on frames with
+//! bodies drawn from `--body-size`, a comma-separated list of sizes and
+//! `A..B` ranges (default `0..256`), first on one thread and then on
+//! `--threads` (default: one per CPU). Each run warms up for `--warmup`
+//! (default 500ms) and then goes for `--duration` (default 2s), reporting
+//! frames and megabytes per second and percentiles of the time one frame
+//! takes, sampled one frame in 256 and including the cost of reading the
+//! clock.
+//!
+//! `listen` binds a UDP socket, a TCP listener taking length-prefixed
+//! frames, or both (an address may be just a port), and prints a line per
+//! frame received, or an ndjson object with `--json`. `--device` and
+//! `--type` pick which frames are printed, as does a `--filter`
+//! expression (see `pipproto::filter`); input that does not decode is
+//! always reported, with a hex preview. Ctrl-C stops it with a count of
+//! what was received.
+//!
+//! `send` sends one frame over UDP, a COMMAND with ACK_REQUIRED unless told
+//! otherwise, and waits up to `--timeout` (default 5s) for the answer.
+//! The body is hex, `base64:` then base64, or `@` then a file to read.
+//! The counter is taken from the clock unless given. The exit status tells
+//! the answer apart: 0 for an ack (or, without ACK_REQUIRED, once sent), 3
+//! for a nack, 4 for an ERROR frame, 5 for no answer in time, and 1 if the
+//! frame could not be sent.
+//!
+//! `repl` reads commands from stdin that build a frame field by field or
+//! byte by byte, show it as `dump` would, send it, and save it to or load
+//! it from a file of hex (see `pipproto::repl`; `help` lists them). A
+//! frame that decodes and asks for an ack is sent as `send` sends it,
+//! waiting up to `--timeout` (default 2s) for the answer; anything else is
+//! sent as it is, and whatever comes back in that time is shown.
+//!
+//! `analyze` reads a journal record by record and reports its frames by
+//! type, decode errors by kind, the busiest devices, body sizes, counter
+//! gaps and the time covered, as text or one JSON object. Damaged records
+//! are skipped, and a corrupt or torn tail ends the reading, with a
+//! warning, not an error; the report covers what could be read. With `--filter` only the frames it matches are
+//! counted, though records that did not decode still are.
+//!
+//! `diff` pairs the frames of two journals by device and counter (see
+//! `pipproto::diff`) and lists frames only one has, pairs whose type,
+//! flags or body differ, with the bytes that do, and pairs recorded more
+//! than `--timing` (default 1s) apart, each counted from its journal's
+//! first frame. Retransmissions within `--window` frames (default 1024)
+//! are counted, not compared, and pairs may be that far out of order.
+//! With `--json` each difference is an ndjson object, then a summary. It
+//! exits with 0 when there are no differences, 1 when there are, and 2
+//! when a journal cannot be read.
+//!
+//! `audit` reads journals in order, as one stream, for EVENT and COMMAND
+//! frames sent (or `--direction rx` or `both`) with a counter another,
+//! different frame already had, or lower than the device's frame before
+//! (see `pipproto::audit`). Each finding is a line naming the records
+//! involved as `JOURNAL@OFFSET`, or an ndjson object with `--json`, and a
+//! report of every device's frames, retransmissions, duplicates,
+//! regressions and largest gap follows. Frames are spilled to `--buckets`
+//! (default 128) files under `--spill` (default the system's temporary
+//! directory), so memory does not grow with the journals. It exits with
+//! 0 when the counters are clean, 1 when they are not, and 2 when a
+//! journal cannot be read.
+//!
+//! `replay` sends the frames of a journal's received records (or
+//! `--direction tx` or `both`) to a host over UDP, or TCP with `--tcp`,
+//! spaced as they were recorded, `--speed` times faster, or back to back
+//! with `--no-timing` (see `pipproto::playback`). `--filter` picks frames
+//! by their recorded fields. `--map-device` replaces a device id,
+//! `--xor-device` flips bits in every other one, and `--reset-counters`
+//! numbers each device's frames from 1, so the replay can run beside the
+//! live devices. Progress goes to stderr once a second, and a summary to
+//! stdout; it exits with 1 if any frame could not be sent.
+//!
+//! `tail` prints the last `-n` (default 10) records of a journal that pass
+//! its filters, or all of them with `--from-start` or `--since`, and with
+//! `-f` keeps printing records as they are appended, polling the file. A
+//! journal replaced by rotation or compaction is read again from its
+//! start. `--device` and `--type` may be repeated; records that did not
+//! decode are only shown when no device, type, counter or `--filter`
+//! filter is given. With `--since` and `--device`, reading starts where
+//! the journal's index (see `pipproto::journal::JournalIndex`) says those
+//! devices' records from then on do.
+//!
+//! `reindex` rebuilds the index of each journal, or of each segment of a
+//! journal directory, with buckets `--bucket` (default 60s) wide.
+//!
+//! Times are `YYYY-MM-DDTHH:MM:SS[.fff]Z` or Unix seconds.
+
+#[cfg(not(target_family = "wasm"))]
+mod cli;
+
+#[cfg(not(target_family = "wasm"))]
+fn main() -> std::process::ExitCode {
+    cli::main()
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum DecodeError {
-    TooShort,
-    BadMagic,
-    BadVersion(u8),
-    UnknownMsgType(u8),
-    ReservedFlags(u8),
-    BadDeviceIdBytes,
-    BadCounterBytes,
-}
-
-impl fmt::Display for DecodeError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            DecodeError::TooShort => write!(f, "input too short"),
-            DecodeError::BadMagic => write!(f, "bad magic"),
-            DecodeError::BadVersion(v) => write!(f, "unsupported version: 0x{v:02x}"),
-            DecodeError::UnknownMsgType(v) => write!(f, "unknown msg_type: 0x{v:02x}"),
-            DecodeError::ReservedFlags(b) => write!(f, "reserved flag bits set: 0b{b:08b}"),
-            DecodeError::BadDeviceIdBytes => write!(f, "bad device_id bytes"),
-            DecodeError::BadCounterBytes => write!(f, "bad counter bytes"),
-        }
-    }
-}
-
-impl FrameHeaderV1 {
-    fn encode(&self) -> Vec<u8> {
-        let mut out = Vec::with_capacity(HEADER_LEN_V1);
-
-        out.extend_from_slice(&MAGIC);
-        out.push(self.version);
-        out.push(self.msg_type as u8);
-        out.push(self.flags.bits());
-        out.extend_from_slice(&self.device_id);
-        out.extend_from_slice(&self.counter.to_be_bytes());
-
-        out
-    }
-
-    fn decode(input: &[u8]) -> Result<Self, DecodeError> {
-        if input.len() < HEADER_LEN_V1 {
-            return Err(DecodeError::TooShort);
-        }
-
-        if input[0..2] != MAGIC {
-            return Err(DecodeError::BadMagic);
-        }
-
-        let version = input[2];
-        if version != VERSION_V1 {
-            return Err(DecodeError::BadVersion(version));
-        }
-
-        let msg_raw = input[3];
-        let msg_type = MsgType::from_u8(msg_raw).ok_or(DecodeError::UnknownMsgType(msg_raw))?;
-
-        let flags_raw = input[4];
-        let flags = Flags::new(flags_raw)?;
-
-        let device_id: [u8; 8] = input[5..13]
-            .try_into()
-            .map_err(|_| DecodeError::BadDeviceIdBytes)?;
-
-        let counter_bytes: [u8; 8] = input[13..21]
-            .try_into()
-            .map_err(|_| DecodeError::BadCounterBytes)?;
-        let counter = u64::from_be_bytes(counter_bytes);
-
-        Ok(FrameHeaderV1::toggle(
-            version, msg_type, flags, device_id, counter,
-        ))
-    }
-
-    fn toggle(
-        version: u8,
-        msg_type: MsgType,
-        flags: Flags,
-        device_id: [u8; 8],
-        counter: u64,
-    ) -> Self {
-        Self {
-            version,
-            msg_type,
-            flags,
-            device_id,
-            counter,
-        }
-    }
-}
-
-impl FrameV1 {
-    fn encode(&self) -> Vec<u8> {
-        let mut out = self.header.encode();
-        out.extend_from_slice(&self.body);
-        out
-    }
-
-    fn decode(input: &[u8]) -> Result<Self, DecodeError> {
-        let header = FrameHeaderV1::decode(input)?;
-        let body = input[HEADER_LEN_V1..].to_vec();
-        Ok(FrameV1 { header, body })
-    }
-}
-
-fn main() {
-    let header = FrameHeaderV1 {
-        version: VERSION_V1,
-        msg_type: MsgType::Event,
-        flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
-        device_id: *b"DEV00001",
-        counter: 42,
-    };
-
-    let frame = FrameV1 {
-        header,
-        body: b"hello-body".to_vec(),
-    };
-
-    let bytes = frame.encode();
-    let parsed = FrameV1::decode(&bytes).unwrap();
-
-    println!("{parsed:?}");
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn roundtrip_header_v1() {
-        let h = FrameHeaderV1 {
-            version: VERSION_V1,
-            msg_type: MsgType::Command,
-            flags: Flags::new(0).unwrap(),
-            device_id: *b"ABCDEFGH",
-            counter: 123456,
-        };
-
-        let bytes = h.encode();
-        let parsed = FrameHeaderV1::decode(&bytes).unwrap();
-        assert_eq!(parsed, h);
-    }
-
-    #[test]
-    fn reject_too_short() {
-        let bytes = vec![0u8; HEADER_LEN_V1 - 1];
-        let err = FrameHeaderV1::decode(&bytes).unwrap_err();
-        assert_eq!(err, DecodeError::TooShort);
-    }
-
-    #[test]
-    fn reject_bad_magic() {
-        let mut bytes = vec![0u8; HEADER_LEN_V1];
-        bytes[0] = b'X';
-        bytes[1] = b'Y';
-        bytes[2] = VERSION_V1;
-        bytes[3] = MsgType::Event as u8;
-
-        let err = FrameHeaderV1::decode(&bytes).unwrap_err();
-        assert_eq!(err, DecodeError::BadMagic);
-    }
-
-    #[test]
-    fn reject_bad_version() {
-        let mut bytes = vec![0u8; HEADER_LEN_V1];
-        bytes[0] = b'P';
-        bytes[1] = b'P';
-        bytes[2] = 0x02;
-        bytes[3] = MsgType::Event as u8;
-
-        let err = FrameHeaderV1::decode(&bytes).unwrap_err();
-        assert_eq!(err, DecodeError::BadVersion(0x02));
-    }
-
-    #[test]
-    fn reject_unknown_msg_type() {
-        let mut bytes = vec![0u8; HEADER_LEN_V1];
-        bytes[0] = b'P';
-        bytes[1] = b'P';
-        bytes[2] = VERSION_V1;
-        bytes[3] = 0x99;
-
-        let err = FrameHeaderV1::decode(&bytes).unwrap_err();
-        assert_eq!(err, DecodeError::UnknownMsgType(0x99));
-    }
-
-    #[test]
-    fn reject_reserved_flags() {
-        let mut bytes = vec![0u8; HEADER_LEN_V1];
-        bytes[0] = b'P';
-        bytes[1] = b'P';
-        bytes[2] = VERSION_V1;
-        bytes[3] = MsgType::Event as u8;
-        bytes[4] = 0b0000_0010; // reserved bit1 set
-
-        let err = FrameHeaderV1::decode(&bytes).unwrap_err();
-        assert_eq!(err, DecodeError::ReservedFlags(0b0000_0010));
-    }
-
-    #[test]
-    fn flags_ack_required() {
-        let f = Flags::new(Flags::ACK_REQUIRED).unwrap();
-        assert!(f.ack_required());
-
-        let f2 = Flags::new(0).unwrap();
-        assert!(!f2.ack_required());
-    }
-
-    #[test]
-    fn roundtrip_frame_with_body() {
-        let header = FrameHeaderV1 {
-            version: VERSION_V1,
-            msg_type: MsgType::Event,
-            flags: Flags::new(0).unwrap(),
-            device_id: *b"ABCDEFGH",
-            counter: 999,
-        };
-
-        let f = FrameV1 {
-            header,
-            body: vec![1, 2, 3, 4, 5],
-        };
-
-        let bytes = f.encode();
-        let parsed = FrameV1::decode(&bytes).unwrap();
-        assert_eq!(parsed, f);
-    }
-}
+/// The tool needs sockets and files; on wasm only the library is built.
+#[cfg(target_family = "wasm")]
+fn main() {}
@@ -0,0 +1,804 @@
+//! Operational counters (feature `metrics`).
+//!
+//! The transports and [`FrameCodec`](crate::codec::FrameCodec) update the
+//! process-wide registry returned by [`global`]; every update is a relaxed
+//! atomic add. [`Metrics::render_prometheus_text`] produces the Prometheus
+//! text exposition format. Names and labels are stable:
+//!
+//! | metric                                    | type      | labels      |
+//! |-------------------------------------------|-----------|-------------|
+//! | `pipproto_frames_decoded_total`           | counter   |             |
+//! | `pipproto_decode_errors_total`            | counter   | `kind`      |
+//! | `pipproto_frames_encoded_total`           | counter   |             |
+//! | `pipproto_bytes_received_total`           | counter   |             |
+//! | `pipproto_bytes_sent_total`               | counter   |             |
+//! | `pipproto_frames_received_total`          | counter   | `msg_type`  |
+//! | `pipproto_frames_sent_total`              | counter   | `msg_type`  |
+//! | `pipproto_ack_rtt_seconds`                | histogram |             |
+//! | `pipproto_rate_limited_total`             | counter   | `outcome`   |
+//! | `pipproto_handler_panics_total`           | counter   |             |
+//! | `pipproto_queue_enqueued_total`           | counter   | `band`      |
+//! | `pipproto_queue_dequeued_total`           | counter   | `band`      |
+//! | `pipproto_queue_dropped_total`            | counter   | `band`      |
+//! | `pipproto_queue_device_depth`             | gauge     | `device`    |
+//! | `pipproto_queue_device_dequeued_total`    | counter   | `device`    |
+//! | `pipproto_authz_denied_total`             | counter   | `principal` |
+//! | `pipproto_ping_rtt_seconds`               | histogram |             |
+//! | `pipproto_pings_lost_total`               | counter   |             |
+//! | `pipproto_circuit_transitions_total`      | counter   | `to`        |
+//! | `pipproto_error_replies_suppressed_total` | counter   |             |
+//! | `pipproto_counter_anomalies_total`        | counter   | `kind`      |
+//! | `pipproto_buffer_pool_gets_total`         | counter   | `outcome`   |
+//! | `pipproto_buffer_pool_outstanding`        | gauge     |             |
+//! | `pipproto_pool_connections`               | gauge     | `state`     |
+//! | `pipproto_pool_connection_failures_total` | counter   |             |
+//!
+//! `kind` is [`DecodeError::kind`] or, for anomalies,
+//! [`AnomalyKind::as_str`](crate::anomaly::AnomalyKind::as_str);
+//! `msg_type` is [`MsgType::as_str`] and `outcome` is
+//! [`Admission::as_str`](crate::ratelimit::Admission::as_str) or, for
+//! buffer pools, `hit` or `miss`, and `band`
+//! is [`Priority::as_str`](crate::window::Priority::as_str). `device` is a
+//! device id in hex, with series only for devices with frames waiting: a
+//! device's are dropped once its last frame leaves, and its dequeued count
+//! starts again from zero if it queues more. The `band` totals count every
+//! frame.
+//! `principal` is whatever an [`Authorizer`](crate::acl::Authorizer) was
+//! asked about, so there is one series per principal ever denied. `to`
+//! is [`CircuitState::as_str`](crate::breaker::CircuitState::as_str).
+//! A pooled connection's `state` is `connecting`, `idle` or `busy`; the
+//! open ones are the last two.
+//! Byte counts include transport framing (length prefixes, packing).
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::window::Priority;
+use crate::{DecodeError, FrameV1, MsgType};
+
+const DECODE_ERROR_KINDS: [&str; 7] = [
+    "too_short",
+    "bad_magic",
+    "bad_version",
+    "unknown_msg_type",
+    "reserved_flags",
+    "bad_device_id_bytes",
+    "bad_counter_bytes",
+];
+
+const MSG_TYPES: [MsgType; 4] = [
+    MsgType::Event,
+    MsgType::Command,
+    MsgType::Ack,
+    MsgType::Error,
+];
+
+const RATE_OUTCOMES: [&str; 4] = ["allowed", "dropped", "deferred", "nacked"];
+
+const ANOMALY_KINDS: [&str; 2] = ["regression", "jump"];
+
+const CIRCUIT_STATES: [&str; 3] = ["closed", "open", "half_open"];
+
+/// Indexed by the states of [`crate::connpool`]'s connections.
+const CONNECTION_STATES: [&str; 3] = ["connecting", "idle", "busy"];
+
+const BANDS: [Priority; 2] = [Priority::Normal, Priority::Urgent];
+
+/// Upper bounds of the ack and ping round-trip histograms, in seconds.
+pub const ACK_RTT_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+fn error_index(e: &DecodeError) -> usize {
+    match e {
+        DecodeError::TooShort => 0,
+        DecodeError::BadMagic => 1,
+        DecodeError::BadVersion(_) => 2,
+        DecodeError::UnknownMsgType(_) => 3,
+        DecodeError::ReservedFlags(_) => 4,
+        DecodeError::BadDeviceIdBytes => 5,
+        DecodeError::BadCounterBytes => 6,
+    }
+}
+
+fn type_index(t: MsgType) -> usize {
+    MSG_TYPES.iter().position(|&m| m == t).unwrap()
+}
+
+/// `s` escaped for a Prometheus label value.
+fn label_value(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Non-cumulative; the last slot is `+Inf`.
+    buckets: [AtomicU64; ACK_RTT_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; ACK_RTT_BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, rtt: Duration) {
+        let secs = rtt.as_secs_f64();
+        let slot = ACK_RTT_BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(ACK_RTT_BUCKETS.len());
+        inc(&self.buckets[slot], 1);
+        inc(&self.sum_micros, rtt.as_micros() as u64);
+        inc(&self.count, 1);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (i, c) in self.buckets.iter().enumerate() {
+            cumulative += get(c);
+            let _ = match ACK_RTT_BUCKETS.get(i) {
+                Some(le) => writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}"),
+                None => writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {cumulative}"),
+            };
+        }
+        let sum = get(&self.sum_micros) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {}", get(&self.count));
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    frames_decoded: AtomicU64,
+    decode_errors: [AtomicU64; DECODE_ERROR_KINDS.len()],
+    frames_encoded: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    frames_received: [AtomicU64; MSG_TYPES.len()],
+    frames_sent: [AtomicU64; MSG_TYPES.len()],
+    ack_rtt: Histogram,
+    rate_limited: [AtomicU64; RATE_OUTCOMES.len()],
+    handler_panics: AtomicU64,
+    queue_enqueued: [AtomicU64; BANDS.len()],
+    queue_dequeued: [AtomicU64; BANDS.len()],
+    queue_dropped: [AtomicU64; BANDS.len()],
+    queue_devices: Mutex<BTreeMap<[u8; 8], DeviceQueue>>,
+    authz_denied: Mutex<BTreeMap<String, u64>>,
+    ping_rtt: Histogram,
+    pings_lost: AtomicU64,
+    circuit_transitions: [AtomicU64; CIRCUIT_STATES.len()],
+    error_replies_suppressed: AtomicU64,
+    counter_anomalies: [AtomicU64; ANOMALY_KINDS.len()],
+    pool_hits: AtomicU64,
+    pool_misses: AtomicU64,
+    pool_outstanding: AtomicU64,
+    connections: [AtomicU64; CONNECTION_STATES.len()],
+    connection_failures: AtomicU64,
+}
+
+/// One device's frames on outbound queues.
+#[derive(Debug, Clone, Copy, Default)]
+struct DeviceQueue {
+    depth: u64,
+    dequeued: u64,
+}
+
+static GLOBAL: Metrics = Metrics::new();
+
+/// The registry updated by this crate's transports and codec.
+pub fn global() -> &'static Metrics {
+    &GLOBAL
+}
+
+fn inc(c: &AtomicU64, n: u64) {
+    c.fetch_add(n, Ordering::Relaxed);
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            frames_decoded: AtomicU64::new(0),
+            decode_errors: [const { AtomicU64::new(0) }; DECODE_ERROR_KINDS.len()],
+            frames_encoded: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            frames_received: [const { AtomicU64::new(0) }; MSG_TYPES.len()],
+            frames_sent: [const { AtomicU64::new(0) }; MSG_TYPES.len()],
+            ack_rtt: Histogram::new(),
+            rate_limited: [const { AtomicU64::new(0) }; RATE_OUTCOMES.len()],
+            handler_panics: AtomicU64::new(0),
+            queue_enqueued: [const { AtomicU64::new(0) }; BANDS.len()],
+            queue_dequeued: [const { AtomicU64::new(0) }; BANDS.len()],
+            queue_dropped: [const { AtomicU64::new(0) }; BANDS.len()],
+            queue_devices: Mutex::new(BTreeMap::new()),
+            authz_denied: Mutex::new(BTreeMap::new()),
+            ping_rtt: Histogram::new(),
+            pings_lost: AtomicU64::new(0),
+            circuit_transitions: [const { AtomicU64::new(0) }; CIRCUIT_STATES.len()],
+            error_replies_suppressed: AtomicU64::new(0),
+            counter_anomalies: [const { AtomicU64::new(0) }; ANOMALY_KINDS.len()],
+            pool_hits: AtomicU64::new(0),
+            pool_misses: AtomicU64::new(0),
+            pool_outstanding: AtomicU64::new(0),
+            connections: [const { AtomicU64::new(0) }; CONNECTION_STATES.len()],
+            connection_failures: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn bytes_in(&self, n: usize) {
+        inc(&self.bytes_received, n as u64);
+    }
+
+    pub(crate) fn bytes_out(&self, n: usize) {
+        inc(&self.bytes_sent, n as u64);
+    }
+
+    pub(crate) fn received<B>(&self, frame: &FrameV1<B>) {
+        inc(&self.frames_decoded, 1);
+        inc(&self.frames_received[type_index(frame.header.msg_type)], 1);
+    }
+
+    pub(crate) fn decode_error(&self, e: &DecodeError) {
+        inc(&self.decode_errors[error_index(e)], 1);
+    }
+
+    pub(crate) fn decoded(&self, result: &Result<FrameV1, DecodeError>) {
+        match result {
+            Ok(f) => self.received(f),
+            Err(e) => self.decode_error(e),
+        }
+    }
+
+    pub(crate) fn encoded<B>(&self, frame: &FrameV1<B>) {
+        inc(&self.frames_encoded, 1);
+        inc(&self.frames_sent[type_index(frame.header.msg_type)], 1);
+    }
+
+    /// Record the time from sending a frame to receiving its ack.
+    pub fn observe_ack_rtt(&self, rtt: Duration) {
+        self.ack_rtt.observe(rtt);
+    }
+
+    pub(crate) fn ping_answered(&self, rtt: Duration) {
+        self.ping_rtt.observe(rtt);
+    }
+
+    pub(crate) fn ping_lost(&self) {
+        inc(&self.pings_lost, 1);
+    }
+
+    pub fn pings_lost(&self) -> u64 {
+        self.pings_lost.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn circuit_transition(&self, to: &str) {
+        if let Some(i) = CIRCUIT_STATES.iter().position(|&s| s == to) {
+            inc(&self.circuit_transitions[i], 1);
+        }
+    }
+
+    /// Circuits that have moved to state `to`.
+    pub fn circuit_transitions(&self, to: &str) -> u64 {
+        CIRCUIT_STATES
+            .iter()
+            .position(|&s| s == to)
+            .map_or(0, |i| self.circuit_transitions[i].load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn error_reply_suppressed(&self) {
+        inc(&self.error_replies_suppressed, 1);
+    }
+
+    pub fn error_replies_suppressed(&self) -> u64 {
+        self.error_replies_suppressed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn counter_anomaly(&self, kind: &str) {
+        if let Some(i) = ANOMALY_KINDS.iter().position(|&k| k == kind) {
+            inc(&self.counter_anomalies[i], 1);
+        }
+    }
+
+    pub fn counter_anomalies(&self, kind: &str) -> u64 {
+        ANOMALY_KINDS
+            .iter()
+            .position(|&k| k == kind)
+            .map_or(0, |i| self.counter_anomalies[i].load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn pool_taken(&self, hit: bool) {
+        inc(
+            if hit {
+                &self.pool_hits
+            } else {
+                &self.pool_misses
+            },
+            1,
+        );
+        inc(&self.pool_outstanding, 1);
+    }
+
+    pub(crate) fn pool_returned(&self) {
+        self.pool_outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Buffers taken from any [`BufferPool`](crate::pool::BufferPool),
+    /// served from its free buffers (`hit`) or allocated (`miss`).
+    pub fn pool_gets(&self, outcome: &str) -> u64 {
+        match outcome {
+            "hit" => self.pool_hits.load(Ordering::Relaxed),
+            "miss" => self.pool_misses.load(Ordering::Relaxed),
+            _ => 0,
+        }
+    }
+
+    pub fn pool_outstanding(&self) -> u64 {
+        self.pool_outstanding.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn connection_moved(&self, from: u8, to: u8) {
+        if let Some(c) = self.connections.get(usize::from(from)) {
+            c.fetch_sub(1, Ordering::Relaxed);
+        }
+        if let Some(c) = self.connections.get(usize::from(to)) {
+            inc(c, 1);
+        }
+    }
+
+    pub(crate) fn connection_failed(&self) {
+        inc(&self.connection_failures, 1);
+    }
+
+    /// Pooled connections in `state`, across
+    /// [`ConnectionPool`](crate::connpool::ConnectionPool)s.
+    pub fn connections(&self, state: &str) -> u64 {
+        CONNECTION_STATES
+            .iter()
+            .position(|&s| s == state)
+            .map_or(0, |i| self.connections[i].load(Ordering::Relaxed))
+    }
+
+    /// Pooled connections that couldn't be opened or broke.
+    pub fn connection_failures(&self) -> u64 {
+        self.connection_failures.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn rate_limited(&self, outcome: &str) {
+        if let Some(i) = RATE_OUTCOMES.iter().position(|&o| o == outcome) {
+            inc(&self.rate_limited[i], 1);
+        }
+    }
+
+    pub fn rate_limit_outcomes(&self, outcome: &str) -> u64 {
+        RATE_OUTCOMES
+            .iter()
+            .position(|&o| o == outcome)
+            .map_or(0, |i| self.rate_limited[i].load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn handler_panicked(&self) {
+        inc(&self.handler_panics, 1);
+    }
+
+    pub(crate) fn queue_enqueued(&self, band: Priority) {
+        inc(&self.queue_enqueued[band as usize], 1);
+    }
+
+    pub(crate) fn queue_dequeued(&self, band: Priority) {
+        inc(&self.queue_dequeued[band as usize], 1);
+    }
+
+    pub(crate) fn queue_dropped(&self, band: Priority) {
+        inc(&self.queue_dropped[band as usize], 1);
+    }
+
+    /// Forgets `device` once nothing of it is left waiting.
+    fn queue_device(&self, device: [u8; 8], f: impl FnOnce(&mut DeviceQueue)) {
+        let mut devices = self.queue_devices.lock().unwrap_or_else(|e| e.into_inner());
+        let d = devices.entry(device).or_default();
+        f(d);
+        if d.depth == 0 {
+            devices.remove(&device);
+        }
+    }
+
+    pub(crate) fn queue_device_depth(&self, device: [u8; 8], delta: i64) {
+        self.queue_device(device, |d| d.depth = d.depth.saturating_add_signed(delta));
+    }
+
+    pub(crate) fn queue_device_dequeued(&self, device: [u8; 8]) {
+        self.queue_device(device, |d| {
+            d.depth = d.depth.saturating_sub(1);
+            d.dequeued += 1;
+        });
+    }
+
+    /// `device`'s frames waiting, across outbound queues.
+    pub fn queue_depth(&self, device: [u8; 8]) -> u64 {
+        let devices = self.queue_devices.lock().unwrap_or_else(|e| e.into_inner());
+        devices.get(&device).map_or(0, |d| d.depth)
+    }
+
+    pub fn queue_dequeued_for(&self, device: [u8; 8]) -> u64 {
+        let devices = self.queue_devices.lock().unwrap_or_else(|e| e.into_inner());
+        devices.get(&device).map_or(0, |d| d.dequeued)
+    }
+
+    pub(crate) fn authz_denied(&self, principal: &str) {
+        let mut denied = self.authz_denied.lock().unwrap_or_else(|e| e.into_inner());
+        match denied.get_mut(principal) {
+            Some(n) => *n += 1,
+            None => {
+                denied.insert(principal.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn authz_denials(&self, principal: &str) -> u64 {
+        let denied = self.authz_denied.lock().unwrap_or_else(|e| e.into_inner());
+        denied.get(principal).copied().unwrap_or(0)
+    }
+
+    pub fn handler_panics(&self) -> u64 {
+        self.handler_panics.load(Ordering::Relaxed)
+    }
+
+    pub fn frames_decoded(&self) -> u64 {
+        self.frames_decoded.load(Ordering::Relaxed)
+    }
+
+    pub fn decode_errors(&self, kind: &str) -> u64 {
+        DECODE_ERROR_KINDS
+            .iter()
+            .position(|&k| k == kind)
+            .map_or(0, |i| self.decode_errors[i].load(Ordering::Relaxed))
+    }
+
+    pub fn frames_encoded(&self) -> u64 {
+        self.frames_encoded.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn frames_received(&self, msg_type: MsgType) -> u64 {
+        self.frames_received[type_index(msg_type)].load(Ordering::Relaxed)
+    }
+
+    pub fn frames_sent(&self, msg_type: MsgType) -> u64 {
+        self.frames_sent[type_index(msg_type)].load(Ordering::Relaxed)
+    }
+
+    pub fn render_prometheus_text(&self) -> String {
+        let mut out = String::new();
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+
+        let mut counter = |name: &str, help: &str, samples: &[(Option<(&str, &str)>, u64)]| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+            for (label, v) in samples {
+                let _ = match label {
+                    Some((k, l)) => writeln!(out, "{name}{{{k}=\"{l}\"}} {v}"),
+                    None => writeln!(out, "{name} {v}"),
+                };
+            }
+        };
+
+        counter(
+            "pipproto_frames_decoded_total",
+            "Frames decoded successfully.",
+            &[(None, get(&self.frames_decoded))],
+        );
+        let errors: Vec<_> = DECODE_ERROR_KINDS
+            .iter()
+            .zip(&self.decode_errors)
+            .map(|(k, c)| (Some(("kind", *k)), get(c)))
+            .collect();
+        counter(
+            "pipproto_decode_errors_total",
+            "Frames that failed to decode.",
+            &errors,
+        );
+        counter(
+            "pipproto_frames_encoded_total",
+            "Frames encoded for sending.",
+            &[(None, get(&self.frames_encoded))],
+        );
+        counter(
+            "pipproto_bytes_received_total",
+            "Bytes received by transports.",
+            &[(None, get(&self.bytes_received))],
+        );
+        counter(
+            "pipproto_bytes_sent_total",
+            "Bytes sent by transports.",
+            &[(None, get(&self.bytes_sent))],
+        );
+        let by_type = |cs: &[AtomicU64]| -> Vec<_> {
+            MSG_TYPES
+                .iter()
+                .zip(cs)
+                .map(|(t, c)| (Some(("msg_type", t.as_str())), get(c)))
+                .collect()
+        };
+        counter(
+            "pipproto_frames_received_total",
+            "Frames received, by type.",
+            &by_type(&self.frames_received),
+        );
+        counter(
+            "pipproto_frames_sent_total",
+            "Frames sent, by type.",
+            &by_type(&self.frames_sent),
+        );
+
+        let outcomes: Vec<_> = RATE_OUTCOMES
+            .iter()
+            .zip(&self.rate_limited)
+            .map(|(o, c)| (Some(("outcome", *o)), get(c)))
+            .collect();
+        counter(
+            "pipproto_rate_limited_total",
+            "Inbound frames checked against rate limits, by outcome.",
+            &outcomes,
+        );
+        counter(
+            "pipproto_handler_panics_total",
+            "Dispatcher handlers that panicked.",
+            &[(None, get(&self.handler_panics))],
+        );
+        let by_band = |cs: &[AtomicU64]| -> Vec<_> {
+            BANDS
+                .iter()
+                .zip(cs)
+                .map(|(b, c)| (Some(("band", b.as_str())), get(c)))
+                .collect()
+        };
+        counter(
+            "pipproto_queue_enqueued_total",
+            "Frames put on the outbound queue, by band.",
+            &by_band(&self.queue_enqueued),
+        );
+        counter(
+            "pipproto_queue_dequeued_total",
+            "Frames taken off the outbound queue to send, by band.",
+            &by_band(&self.queue_dequeued),
+        );
+        counter(
+            "pipproto_queue_dropped_total",
+            "Frames the full outbound queue rejected or dropped, by band.",
+            &by_band(&self.queue_dropped),
+        );
+        let devices: Vec<_> = self
+            .queue_devices
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(id, &d)| (crate::hex::encode(id), d))
+            .collect();
+        let dequeued: Vec<_> = devices
+            .iter()
+            .map(|(id, d)| (Some(("device", id.as_str())), d.dequeued))
+            .collect();
+        counter(
+            "pipproto_queue_device_dequeued_total",
+            "Frames taken off outbound queues to send, by device.",
+            &dequeued,
+        );
+
+        let denied: Vec<_> = self
+            .authz_denied
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(p, &n)| (label_value(p), n))
+            .collect();
+        let denied: Vec<_> = denied
+            .iter()
+            .map(|(p, n)| (Some(("principal", p.as_str())), *n))
+            .collect();
+        counter(
+            "pipproto_authz_denied_total",
+            "Operations an authorizer denied, by principal.",
+            &denied,
+        );
+
+        counter(
+            "pipproto_pings_lost_total",
+            "PINGs left unanswered.",
+            &[(None, get(&self.pings_lost))],
+        );
+        let transitions: Vec<_> = CIRCUIT_STATES
+            .iter()
+            .zip(&self.circuit_transitions)
+            .map(|(s, c)| (Some(("to", *s)), get(c)))
+            .collect();
+        counter(
+            "pipproto_circuit_transitions_total",
+            "Device circuit breaker changes of state, by new state.",
+            &transitions,
+        );
+        counter(
+            "pipproto_error_replies_suppressed_total",
+            "ERROR and NACK replies held back by a reply limiter.",
+            &[(None, get(&self.error_replies_suppressed))],
+        );
+        let anomalies: Vec<_> = ANOMALY_KINDS
+            .iter()
+            .zip(&self.counter_anomalies)
+            .map(|(k, c)| (Some(("kind", *k)), get(c)))
+            .collect();
+        counter(
+            "pipproto_counter_anomalies_total",
+            "Counters that moved too far back or ahead, by kind.",
+            &anomalies,
+        );
+
+        counter(
+            "pipproto_buffer_pool_gets_total",
+            "Buffers taken from pools, by whether a free one was there.",
+            &[
+                (Some(("outcome", "hit")), get(&self.pool_hits)),
+                (Some(("outcome", "miss")), get(&self.pool_misses)),
+            ],
+        );
+        counter(
+            "pipproto_pool_connection_failures_total",
+            "Pooled connections that couldn't be opened or broke.",
+            &[(None, get(&self.connection_failures))],
+        );
+        let _ = writeln!(
+            out,
+            "# HELP pipproto_buffer_pool_outstanding Pooled buffers in use.\n\
+             # TYPE pipproto_buffer_pool_outstanding gauge\n\
+             pipproto_buffer_pool_outstanding {}",
+            get(&self.pool_outstanding)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP pipproto_pool_connections Pooled connections, by state.\n\
+             # TYPE pipproto_pool_connections gauge"
+        );
+        for (state, c) in CONNECTION_STATES.iter().zip(&self.connections) {
+            let _ = writeln!(
+                out,
+                "pipproto_pool_connections{{state=\"{state}\"}} {}",
+                get(c)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP pipproto_queue_device_depth Frames waiting on outbound queues, by device.\n\
+             # TYPE pipproto_queue_device_depth gauge"
+        );
+        for (id, d) in &devices {
+            let _ = writeln!(
+                out,
+                "pipproto_queue_device_depth{{device=\"{id}\"}} {}",
+                d.depth
+            );
+        }
+
+        self.ack_rtt.render(
+            &mut out,
+            "pipproto_ack_rtt_seconds",
+            "Time from send to ack.",
+        );
+        self.ping_rtt.render(
+            &mut out,
+            "pipproto_ping_rtt_seconds",
+            "Time from PING to its answer.",
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::frame;
+
+    #[test]
+    fn every_kind_and_type_has_a_slot() {
+        let all = [
+            DecodeError::TooShort,
+            DecodeError::BadMagic,
+            DecodeError::BadVersion(0),
+            DecodeError::UnknownMsgType(0),
+            DecodeError::ReservedFlags(0),
+            DecodeError::BadDeviceIdBytes,
+            DecodeError::BadCounterBytes,
+        ];
+        for e in &all {
+            assert_eq!(DECODE_ERROR_KINDS[error_index(e)], e.kind());
+        }
+        for t in MSG_TYPES {
+            assert_eq!(MsgType::from_u8(t as u8), Some(t));
+        }
+    }
+
+    #[test]
+    fn renders_exposition_format() {
+        let m = Metrics::new();
+        m.decoded(&Ok(frame(1)));
+        m.decoded(&Err(DecodeError::BadMagic));
+        m.encoded(&frame(2));
+        m.bytes_in(24);
+        m.observe_ack_rtt(Duration::from_millis(20));
+        m.observe_ack_rtt(Duration::from_secs(60));
+        m.rate_limited("nacked");
+        m.handler_panicked();
+        m.queue_dropped(Priority::Urgent);
+        m.queue_device_depth(*b"DEV00001", 2);
+        m.queue_device_dequeued(*b"DEV00001");
+        m.queue_device_depth(*b"DEV00002", 1);
+        m.queue_device_dequeued(*b"DEV00002");
+        m.authz_denied("tenant \"a\"");
+        m.authz_denied("tenant \"a\"");
+        m.ping_answered(Duration::from_millis(7));
+        m.ping_lost();
+        m.circuit_transition("half_open");
+        m.error_reply_suppressed();
+        m.counter_anomaly("jump");
+        m.connection_moved(3, 0);
+        m.connection_moved(0, 1);
+        m.connection_failed();
+
+        let text = m.render_prometheus_text();
+        assert!(!text.contains("4445563030303032"));
+        for line in [
+            "# TYPE pipproto_frames_decoded_total counter",
+            "pipproto_frames_decoded_total 1",
+            "pipproto_decode_errors_total{kind=\"bad_magic\"} 1",
+            "pipproto_decode_errors_total{kind=\"too_short\"} 0",
+            "pipproto_frames_received_total{msg_type=\"event\"} 1",
+            "pipproto_frames_sent_total{msg_type=\"event\"} 1",
+            "pipproto_bytes_received_total 24",
+            "pipproto_ack_rtt_seconds_bucket{le=\"0.01\"} 0",
+            "pipproto_ack_rtt_seconds_bucket{le=\"0.025\"} 1",
+            "pipproto_ack_rtt_seconds_bucket{le=\"10\"} 1",
+            "pipproto_ack_rtt_seconds_bucket{le=\"+Inf\"} 2",
+            "pipproto_ack_rtt_seconds_sum 60.02",
+            "pipproto_ack_rtt_seconds_count 2",
+            "pipproto_rate_limited_total{outcome=\"nacked\"} 1",
+            "pipproto_rate_limited_total{outcome=\"allowed\"} 0",
+            "pipproto_handler_panics_total 1",
+            "pipproto_queue_dropped_total{band=\"urgent\"} 1",
+            "pipproto_queue_enqueued_total{band=\"normal\"} 0",
+            "# TYPE pipproto_queue_device_depth gauge",
+            "pipproto_queue_device_depth{device=\"4445563030303031\"} 1",
+            "pipproto_queue_device_dequeued_total{device=\"4445563030303031\"} 1",
+            "pipproto_authz_denied_total{principal=\"tenant \\\"a\\\"\"} 2",
+            "pipproto_ping_rtt_seconds_bucket{le=\"0.01\"} 1",
+            "pipproto_ping_rtt_seconds_count 1",
+            "pipproto_pings_lost_total 1",
+            "pipproto_circuit_transitions_total{to=\"half_open\"} 1",
+            "pipproto_circuit_transitions_total{to=\"open\"} 0",
+            "pipproto_error_replies_suppressed_total 1",
+            "pipproto_counter_anomalies_total{kind=\"jump\"} 1",
+            "# TYPE pipproto_pool_connections gauge",
+            "pipproto_pool_connections{state=\"connecting\"} 0",
+            "pipproto_pool_connections{state=\"idle\"} 1",
+            "pipproto_pool_connection_failures_total 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {line:?}");
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! MQTT bridge (feature `mqtt`).
+//!
+//! Outbound frames are published with the raw encoded frame as payload to a
+//! topic rendered from a template such as `pp/{device_id}/{msg_type}`.
+//! Payloads arriving on the command topic are decoded back into [`FrameV1`];
+//! malformed payloads are counted and dropped.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rumqttc::{AsyncClient, ClientError, ConnectionError, Event, EventLoop, Packet, QoS};
+
+use crate::{FrameHeaderV1, FrameV1};
+
+pub const DEFAULT_TOPIC_TEMPLATE: &str = "pp/{device_id}/{msg_type}";
+pub const DEFAULT_COMMAND_TOPIC: &str = "pp/commands/#";
+
+#[derive(Debug)]
+pub enum MqttError {
+    BadTemplate(String),
+    Client(ClientError),
+    Connection(Box<ConnectionError>),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::BadTemplate(t) => write!(f, "bad topic template: {t}"),
+            MqttError::Client(e) => write!(f, "mqtt client error: {e}"),
+            MqttError::Connection(e) => write!(f, "mqtt connection error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
+
+impl From<ClientError> for MqttError {
+    fn from(e: ClientError) -> Self {
+        MqttError::Client(e)
+    }
+}
+
+impl From<ConnectionError> for MqttError {
+    fn from(e: ConnectionError) -> Self {
+        MqttError::Connection(Box::new(e))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    DeviceId,
+    MsgType,
+}
+
+/// Outbound topic template.
+///
+/// Supported placeholders: `{device_id}` (16 lowercase hex digits) and
+/// `{msg_type}` (lowercase name, e.g. `event`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicTemplate {
+    parts: Vec<Part>,
+}
+
+impl TopicTemplate {
+    pub fn new(template: &str) -> Result<Self, MqttError> {
+        if template.is_empty() || template.contains(['+', '#']) {
+            return Err(MqttError::BadTemplate(template.to_string()));
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].to_string()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| MqttError::BadTemplate(template.to_string()))?;
+            let part = match &rest[open + 1..open + close] {
+                "device_id" => Part::DeviceId,
+                "msg_type" => Part::MsgType,
+                _ => return Err(MqttError::BadTemplate(template.to_string())),
+            };
+            parts.push(part);
+            rest = &rest[open + close + 1..];
+        }
+        if rest.contains('}') {
+            return Err(MqttError::BadTemplate(template.to_string()));
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(TopicTemplate { parts })
+    }
+
+    pub fn render(&self, header: &FrameHeaderV1) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(lit) => out.push_str(lit),
+                Part::DeviceId => out.push_str(&header.device_id_hex()),
+                Part::MsgType => out.push_str(header.msg_type.as_str()),
+            }
+        }
+        out
+    }
+}
+
+impl Default for TopicTemplate {
+    fn default() -> Self {
+        TopicTemplate::new(DEFAULT_TOPIC_TEMPLATE).unwrap()
+    }
+}
+
+/// ACK_REQUIRED frames go out at QoS1, everything else at QoS0.
+pub fn qos_for(header: &FrameHeaderV1) -> QoS {
+    if header.flags.ack_required() {
+        QoS::AtLeastOnce
+    } else {
+        QoS::AtMostOnce
+    }
+}
+
+/// MQTT topic filter matching (`+` single level, `#` multi level).
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut f = filter.split('/');
+    let mut t = topic.split('/');
+    loop {
+        match (f.next(), t.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(a), Some(b)) if a == b => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// The subset of an MQTT client the bridge needs; implemented for
+/// [`rumqttc::AsyncClient`] and easy to mock.
+pub trait MqttClient {
+    fn publish(
+        &self,
+        topic: String,
+        qos: QoS,
+        payload: Vec<u8>,
+    ) -> impl Future<Output = Result<(), ClientError>> + Send;
+
+    fn subscribe(
+        &self,
+        filter: String,
+        qos: QoS,
+    ) -> impl Future<Output = Result<(), ClientError>> + Send;
+}
+
+impl MqttClient for AsyncClient {
+    async fn publish(&self, topic: String, qos: QoS, payload: Vec<u8>) -> Result<(), ClientError> {
+        AsyncClient::publish(self, topic, qos, false, payload).await
+    }
+
+    async fn subscribe(&self, filter: String, qos: QoS) -> Result<(), ClientError> {
+        AsyncClient::subscribe(self, filter, qos).await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttBridgeConfig {
+    pub topic_template: TopicTemplate,
+    /// Topic filter inbound frames are read from.
+    pub command_topic: String,
+}
+
+impl Default for MqttBridgeConfig {
+    fn default() -> Self {
+        MqttBridgeConfig {
+            topic_template: TopicTemplate::default(),
+            command_topic: DEFAULT_COMMAND_TOPIC.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BridgeStats {
+    published: AtomicU64,
+    received: AtomicU64,
+    malformed: AtomicU64,
+}
+
+impl BridgeStats {
+    pub fn published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn malformed(&self) -> u64 {
+        self.malformed.load(Ordering::Relaxed)
+    }
+}
+
+pub struct MqttBridge<C> {
+    client: C,
+    config: MqttBridgeConfig,
+    stats: BridgeStats,
+}
+
+impl<C: MqttClient> MqttBridge<C> {
+    pub fn new(client: C, config: MqttBridgeConfig) -> Self {
+        MqttBridge {
+            client,
+            config,
+            stats: BridgeStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &BridgeStats {
+        &self.stats
+    }
+
+    pub async fn subscribe(&self) -> Result<(), MqttError> {
+        self.client
+            .subscribe(self.config.command_topic.clone(), QoS::AtLeastOnce)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn publish(&self, frame: &FrameV1) -> Result<(), MqttError> {
+        let topic = self.config.topic_template.render(&frame.header);
+        self.client
+            .publish(topic, qos_for(&frame.header), frame.encode())
+            .await?;
+        self.stats.published.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Decode one inbound publish. Returns `None` for topics outside the
+    /// command filter and for malformed payloads (which are counted).
+    pub fn handle_publish(&self, topic: &str, payload: &[u8]) -> Option<FrameV1> {
+        if !topic_matches(&self.config.command_topic, topic) {
+            return None;
+        }
+        match FrameV1::decode(payload) {
+            Ok(frame) => {
+                self.stats.received.fetch_add(1, Ordering::Relaxed);
+                Some(frame)
+            }
+            Err(_) => {
+                self.stats.malformed.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Drive the rumqttc event loop, handing every decoded command frame to
+    /// `on_frame`. Returns on connection errors; polling again reconnects.
+    pub async fn run<F: FnMut(FrameV1)>(
+        &self,
+        eventloop: &mut EventLoop,
+        mut on_frame: F,
+    ) -> Result<(), MqttError> {
+        loop {
+            if let Event::Incoming(Packet::Publish(p)) = eventloop.poll().await?
+                && let Some(frame) = self.handle_publish(&p.topic, &p.payload)
+            {
+                on_frame(frame);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Flags, MsgType, VERSION_V1};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockClient {
+        published: Mutex<Vec<(String, QoS, Vec<u8>)>>,
+        subscribed: Mutex<Vec<String>>,
+    }
+
+    impl MqttClient for MockClient {
+        async fn publish(
+            &self,
+            topic: String,
+            qos: QoS,
+            payload: Vec<u8>,
+        ) -> Result<(), ClientError> {
+            self.published.lock().unwrap().push((topic, qos, payload));
+            Ok(())
+        }
+
+        async fn subscribe(&self, filter: String, _qos: QoS) -> Result<(), ClientError> {
+            self.subscribed.lock().unwrap().push(filter);
+            Ok(())
+        }
+    }
+
+    fn frame(msg_type: MsgType, flags: u8) -> FrameV1 {
+        FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type,
+                flags: Flags::new(flags).unwrap(),
+                device_id: [0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 1],
+                counter: 7,
+            },
+            body: b"hi".into(),
+        }
+    }
+
+    #[test]
+    fn render_default_template() {
+        let t = TopicTemplate::default();
+        assert_eq!(
+            t.render(&frame(MsgType::Event, 0).header),
+            "pp/deadbeef00000001/event"
+        );
+    }
+
+    #[test]
+    fn reject_bad_templates() {
+        assert!(TopicTemplate::new("pp/{nope}").is_err());
+        assert!(TopicTemplate::new("pp/{device_id").is_err());
+        assert!(TopicTemplate::new("pp/+/x").is_err());
+        assert!(TopicTemplate::new("").is_err());
+    }
+
+    #[test]
+    fn filter_matching() {
+        assert!(topic_matches("pp/commands/#", "pp/commands/a/b"));
+        assert!(topic_matches("pp/+/command", "pp/dev/command"));
+        assert!(!topic_matches("pp/+/command", "pp/dev/event"));
+        assert!(!topic_matches("pp/a", "pp/a/b"));
+    }
+
+    #[tokio::test]
+    async fn publish_maps_qos() {
+        let bridge = MqttBridge::new(MockClient::default(), MqttBridgeConfig::default());
+        bridge.publish(&frame(MsgType::Event, 0)).await.unwrap();
+        bridge
+            .publish(&frame(MsgType::Command, Flags::ACK_REQUIRED))
+            .await
+            .unwrap();
+
+        let published = bridge.client.published.lock().unwrap();
+        assert_eq!(published[0].1, QoS::AtMostOnce);
+        assert_eq!(published[1].0, "pp/deadbeef00000001/command");
+        assert_eq!(published[1].1, QoS::AtLeastOnce);
+        assert_eq!(published[1].2, frame(MsgType::Command, 1).encode());
+        assert_eq!(bridge.stats().published(), 2);
+    }
+
+    #[tokio::test]
+    async fn subscribe_command_topic() {
+        let bridge = MqttBridge::new(MockClient::default(), MqttBridgeConfig::default());
+        bridge.subscribe().await.unwrap();
+        assert_eq!(*bridge.client.subscribed.lock().unwrap(), ["pp/commands/#"]);
+    }
+
+    #[test]
+    fn malformed_payloads_are_counted_and_dropped() {
+        let bridge = MqttBridge::new(MockClient::default(), MqttBridgeConfig::default());
+        let good = frame(MsgType::Command, 0);
+
+        assert_eq!(
+            bridge.handle_publish("pp/commands/x", &good.encode()),
+            Some(good)
+        );
+        assert_eq!(bridge.handle_publish("pp/commands/x", b"garbage"), None);
+        assert_eq!(bridge.handle_publish("other/topic", b"garbage"), None);
+
+        assert_eq!(bridge.stats().received(), 1);
+        assert_eq!(bridge.stats().malformed(), 1);
+    }
+}
@@ -0,0 +1,518 @@
+//! Deterministic simulation of sessions over a virtual network.
+//!
+//! A [`Simulation`] runs pairs of [`Session`]s, each with an
+//! [`AckTracker`] and, if it asks, a [`DedupCache`], against a network
+//! that loses, duplicates, delays and reorders frames as a seeded random
+//! stream draws. One [`ManualClock`] drives them all: each step jumps it
+//! to the next frame arrival or session deadline, so a run takes no real
+//! time and a seed always replays the same interleaving.
+//!
+//! After every step the run checks that no node sent two frames under one
+//! counter or let its counters go back, that no frame a sender sent
+//! [`Qos::ExactlyOnce`] reached its receiver's application twice, and that
+//! ack trackers and the network stay within bounds. At the end every
+//! message must have arrived or been given up on. A broken invariant ends
+//! the run with a [`Violation`] naming the seed.
+//!
+//! Sessions do not resend HELLO or BYE, so the network only delays
+//! control frames; it faults the rest.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::ack::{AckBody, AckConfig, AckTracker, ack_frame};
+use crate::clock::{Clock, ManualClock};
+use crate::dedup::{DedupCache, DedupConfig, Delivery};
+use crate::qos::Qos;
+use crate::session::{Control, Session, SessionConfig, SessionEvent, SessionState};
+use crate::sim::Rng;
+use crate::{FrameId, FrameV1, MsgType};
+
+#[derive(Debug, Clone)]
+pub struct NetConfig {
+    pub latency: Duration,
+    /// Up to this much more, drawn per frame.
+    pub jitter: Duration,
+    /// Chances, per application frame or ack, of losing it, of delivering
+    /// it twice and of holding it back a further `latency`.
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    /// Frames in flight at once before the run fails.
+    pub max_in_flight: usize,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        NetConfig {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(5),
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            max_in_flight: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub device_id: [u8; 8],
+    /// EVENTs to send the peer.
+    pub messages: u32,
+    pub qos: Qos,
+    pub ack: AckConfig,
+    /// Drop repeats of frames received before the application sees them.
+    pub dedup: bool,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            device_id: *b"NODE0000",
+            messages: 20,
+            qos: Qos::AtLeastOnce,
+            ack: AckConfig {
+                timeout: Duration::from_millis(100),
+                max_attempts: 10,
+                max_outstanding: 8,
+                ..AckConfig::default()
+            },
+            dedup: true,
+        }
+    }
+}
+
+/// A broken invariant, and where in which run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub seed: u64,
+    pub step: u64,
+    /// Simulated time into the run.
+    pub at: Duration,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "seed {}, step {} ({:?} in): {}",
+            self.seed, self.step, self.at, self.message
+        )
+    }
+}
+
+impl std::error::Error for Violation {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimReport {
+    pub steps: u64,
+    /// Simulated time the run took.
+    pub elapsed: Duration,
+    /// Messages that reached the application, the first time.
+    pub delivered: u64,
+    /// Repeats that reached it too.
+    pub duplicates: u64,
+    /// Messages their sender's tracker gave up on.
+    pub given_up: u64,
+    /// Frames the network lost.
+    pub lost: u64,
+}
+
+struct Node {
+    config: NodeConfig,
+    peer: usize,
+    session: Session,
+    dedup: Option<DedupCache>,
+    remaining: u32,
+    /// Frames sent, by counter, to tell resends from reuse.
+    sent: HashMap<u64, FrameV1>,
+    highest: Option<u64>,
+    /// Messages sent, and those of them given up on.
+    messages: Vec<FrameId>,
+    given_up: HashSet<FrameId>,
+    /// What reached the application, and how often.
+    received: HashMap<FrameId, u32>,
+}
+
+pub struct Simulation {
+    seed: u64,
+    config: NetConfig,
+    clock: ManualClock,
+    start: Instant,
+    rng: Rng,
+    nodes: Vec<Node>,
+    /// Frames on the way, by arrival and then send order, with the node
+    /// they are for.
+    in_flight: BTreeMap<(Instant, u64), (usize, FrameV1)>,
+    sends: u64,
+    max_steps: u64,
+    report: SimReport,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, config: NetConfig) -> Self {
+        let clock = ManualClock::new();
+        Simulation {
+            seed,
+            config,
+            start: clock.now(),
+            clock,
+            rng: Rng(seed),
+            nodes: Vec::new(),
+            in_flight: BTreeMap::new(),
+            sends: 0,
+            max_steps: 100_000,
+            report: SimReport::default(),
+        }
+    }
+
+    /// Steps after which a run that has not settled fails.
+    pub fn with_max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Add two nodes talking to each other; the first says HELLO.
+    pub fn pair(mut self, a: NodeConfig, b: NodeConfig) -> Self {
+        let first = self.nodes.len();
+        for (config, peer) in [(a, first + 1), (b, first)] {
+            let node = self.node(config, peer);
+            self.nodes.push(node);
+        }
+        self.nodes[first].session.connect().unwrap();
+        self
+    }
+
+    fn node(&self, config: NodeConfig, peer: usize) -> Node {
+        let clock: Arc<dyn Clock> = Arc::new(self.clock.clone());
+        let tracker = AckTracker::new(config.ack.clone(), clock.clone());
+        let session =
+            Session::with_clock(config.device_id, SessionConfig::default(), clock.clone())
+                .with_acks(tracker);
+        Node {
+            dedup: config
+                .dedup
+                .then(|| DedupCache::with_clock(DedupConfig::default(), clock)),
+            remaining: config.messages,
+            config,
+            peer,
+            session,
+            sent: HashMap::new(),
+            highest: None,
+            messages: Vec::new(),
+            given_up: HashSet::new(),
+            received: HashMap::new(),
+        }
+    }
+
+    fn violation(&self, message: String) -> Violation {
+        Violation {
+            seed: self.seed,
+            step: self.report.steps,
+            at: self.clock.now() - self.start,
+            message,
+        }
+    }
+
+    /// Run until nothing is left to send, deliver or wait for.
+    pub fn run(mut self) -> Result<SimReport, Violation> {
+        loop {
+            for i in 0..self.nodes.len() {
+                self.pump(i)?;
+            }
+            self.check_bounds()?;
+            let arrival = self.in_flight.keys().next().map(|&(at, _)| at);
+            let deadlines = self.nodes.iter().filter_map(|n| n.session.next_deadline());
+            let Some(next) = arrival.into_iter().chain(deadlines).min() else {
+                break;
+            };
+            self.report.steps += 1;
+            if self.report.steps > self.max_steps {
+                return Err(self.violation("did not settle".into()));
+            }
+            self.clock
+                .advance(next.saturating_duration_since(self.clock.now()));
+            let now = self.clock.now();
+            while let Some(entry) = self.in_flight.first_entry() {
+                if entry.key().0 > now {
+                    break;
+                }
+                let (to, frame) = entry.remove();
+                self.deliver(to, frame)?;
+            }
+            for i in 0..self.nodes.len() {
+                self.poll(i, now)?;
+            }
+        }
+        self.finish()
+    }
+
+    /// Send what node `i`'s application has room for, then put what its
+    /// session queued on the network.
+    fn pump(&mut self, i: usize) -> Result<(), Violation> {
+        loop {
+            let node = &mut self.nodes[i];
+            if node.remaining == 0 || node.session.state() != SessionState::Established {
+                break;
+            }
+            let tracked = node.config.qos != Qos::AtMostOnce;
+            let full = node.session.acks().unwrap().len() >= node.config.ack.max_outstanding;
+            if tracked && full {
+                break;
+            }
+            let index = node.config.messages - node.remaining;
+            let id = node
+                .session
+                .send(
+                    MsgType::Event,
+                    node.config.qos.flags(),
+                    index.to_be_bytes().to_vec(),
+                )
+                .map_err(|e| e.to_string());
+            let id = match id {
+                Ok(id) => id,
+                Err(e) => return Err(self.violation(format!("node {i} could not send: {e}"))),
+            };
+            let node = &mut self.nodes[i];
+            node.messages.push(id);
+            node.remaining -= 1;
+        }
+        while let Some(frame) = self.nodes[i].session.poll_transmit() {
+            self.check_counter(i, &frame)?;
+            self.transmit(i, frame);
+        }
+        Ok(())
+    }
+
+    /// Every counter is used for one frame only, and each new one is
+    /// above those before it.
+    fn check_counter(&mut self, i: usize, frame: &FrameV1) -> Result<(), Violation> {
+        let node = &mut self.nodes[i];
+        let counter = frame.header.counter;
+        let broken = match node.sent.get(&counter) {
+            Some(first) if first != frame => Some(format!("node {i} reused counter {counter}")),
+            Some(_) => None,
+            None if node.highest.is_some_and(|h| counter <= h) => {
+                Some(format!("node {i} went back to counter {counter}"))
+            }
+            None => {
+                node.sent.insert(counter, frame.clone());
+                node.highest = Some(counter);
+                None
+            }
+        };
+        match broken {
+            Some(message) => Err(self.violation(message)),
+            None => Ok(()),
+        }
+    }
+
+    fn check_bounds(&self) -> Result<(), Violation> {
+        if self.in_flight.len() > self.config.max_in_flight {
+            let n = self.in_flight.len();
+            return Err(self.violation(format!("{n} frames in flight")));
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            let waiting = node.session.acks().unwrap().len();
+            if waiting > node.config.ack.max_outstanding {
+                return Err(self.violation(format!("node {i} awaits {waiting} acks")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Put `frame` from node `from` on the way to its peer, faulted.
+    fn transmit(&mut self, from: usize, frame: FrameV1) {
+        let to = self.nodes[from].peer;
+        let faulted = Control::parse(&frame).is_none();
+        let chance = |rng: &mut Rng, p: f64| faulted && rng.next_f64() < p;
+        if chance(&mut self.rng, self.config.loss) {
+            self.report.lost += 1;
+            return;
+        }
+        let copies = if chance(&mut self.rng, self.config.duplicate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut delay = self.config.latency + self.config.jitter.mul_f64(self.rng.next_f64());
+            if chance(&mut self.rng, self.config.reorder) {
+                delay += self.config.latency;
+            }
+            self.sends += 1;
+            let at = self.clock.now() + delay;
+            self.in_flight.insert((at, self.sends), (to, frame.clone()));
+        }
+    }
+
+    fn deliver(&mut self, to: usize, frame: FrameV1) -> Result<(), Violation> {
+        let node = &mut self.nodes[to];
+        let frame = match node.session.on_frame(frame) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                if node.session.state() == SessionState::HelloReceived {
+                    node.session.accept().unwrap();
+                }
+                return Ok(());
+            }
+            // arrived before the handshake finished; as good as lost
+            Err(_) => return Ok(()),
+        };
+        if frame.header.msg_type == MsgType::Ack {
+            return Ok(());
+        }
+        let id = frame.header.id();
+        if frame.header.flags.ack_required() {
+            let ack = ack_frame(id.device_id, id.counter, AckBody::Single);
+            self.transmit(to, ack);
+        }
+        let node = &mut self.nodes[to];
+        if let Some(dedup) = &mut node.dedup
+            && dedup.check_and_record(id) == Delivery::Duplicate
+        {
+            return Ok(());
+        }
+        let times = node.received.entry(id).or_default();
+        *times += 1;
+        if *times == 1 {
+            self.report.delivered += 1;
+            return Ok(());
+        }
+        self.report.duplicates += 1;
+        let (times, peer) = (*times, node.peer);
+        if self.nodes[peer].config.qos == Qos::ExactlyOnce {
+            return Err(self.violation(format!(
+                "node {to} got counter {} {times} times, sent exactly once",
+                id.counter
+            )));
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self, i: usize, now: Instant) -> Result<(), Violation> {
+        let node = &mut self.nodes[i];
+        node.session.poll_timeout(now);
+        while let Some(event) = node.session.poll_event() {
+            match event {
+                SessionEvent::Unacked(id) => {
+                    node.given_up.insert(id);
+                    self.report.given_up += 1;
+                }
+                SessionEvent::TimedOut(state) => {
+                    return Err(self.violation(format!("node {i} timed out while {state}")));
+                }
+                SessionEvent::StateChanged { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<SimReport, Violation> {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.remaining > 0 {
+                let message = format!("node {i} never sent {} messages", node.remaining);
+                return Err(self.violation(message));
+            }
+            if node.config.qos == Qos::AtMostOnce {
+                continue;
+            }
+            let peer = &self.nodes[node.peer];
+            let missing = node
+                .messages
+                .iter()
+                .find(|id| !peer.received.contains_key(id) && !node.given_up.contains(id));
+            if let Some(id) = missing {
+                let message = format!("node {i}'s counter {} never arrived", id.counter);
+                return Err(self.violation(message));
+            }
+        }
+        self.report.elapsed = self.clock.now() - self.start;
+        Ok(std::mem::take(&mut self.report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(device_id: &[u8; 8], qos: Qos) -> NodeConfig {
+        NodeConfig {
+            device_id: *device_id,
+            qos,
+            ..NodeConfig::default()
+        }
+    }
+
+    fn hostile() -> NetConfig {
+        NetConfig {
+            loss: 0.2,
+            duplicate: 0.2,
+            reorder: 0.3,
+            ..NetConfig::default()
+        }
+    }
+
+    fn run(seed: u64, net: NetConfig, pairs: &[(Qos, Qos)]) -> SimReport {
+        let mut sim = Simulation::new(seed, net);
+        for (n, &(a, b)) in pairs.iter().enumerate() {
+            let a_id = format!("NODE{:02}A0", n).into_bytes().try_into().unwrap();
+            let b_id = format!("NODE{:02}B0", n).into_bytes().try_into().unwrap();
+            sim = sim.pair(node(&a_id, a), node(&b_id, b));
+        }
+        sim.run().unwrap_or_else(|v| panic!("{v}"))
+    }
+
+    #[test]
+    fn clean_network_delivers_everything_once() {
+        let report = run(
+            1,
+            NetConfig::default(),
+            &[(Qos::AtLeastOnce, Qos::ExactlyOnce)],
+        );
+        assert_eq!(report.delivered, 40);
+        assert_eq!((report.duplicates, report.given_up, report.lost), (0, 0, 0));
+    }
+
+    #[test]
+    fn hostile_networks_keep_the_invariants() {
+        let pairs = [
+            (Qos::ExactlyOnce, Qos::AtLeastOnce),
+            (Qos::AtMostOnce, Qos::ExactlyOnce),
+        ];
+        for seed in 0..8 {
+            let report = run(seed, hostile(), &pairs);
+            assert!(report.lost > 0, "seed {seed}: {report:?}");
+        }
+    }
+
+    #[test]
+    fn a_seed_replays_the_same_run() {
+        let pairs = [(Qos::ExactlyOnce, Qos::AtLeastOnce)];
+        assert_eq!(run(7, hostile(), &pairs), run(7, hostile(), &pairs));
+        assert_ne!(run(7, hostile(), &pairs), run(8, hostile(), &pairs));
+    }
+
+    #[test]
+    fn reports_the_seed_of_a_broken_run() {
+        let receiver = NodeConfig {
+            dedup: false,
+            ..node(b"NODE00B0", Qos::AtMostOnce)
+        };
+        let net = NetConfig {
+            duplicate: 0.5,
+            ..NetConfig::default()
+        };
+        let v = Simulation::new(3, net)
+            .pair(node(b"NODE00A0", Qos::ExactlyOnce), receiver)
+            .run()
+            .unwrap_err();
+        assert_eq!(v.seed, 3);
+        assert!(v.to_string().starts_with("seed 3, step "), "{v}");
+        assert!(v.message.contains("sent exactly once"), "{v}");
+    }
+}
@@ -0,0 +1,130 @@
+//! Hooks for watching frames go in and out without changing them.
+//!
+//! Wrap a transport in [`Observed`] to have a [`FrameObserver`] called on
+//! every frame it sends or receives. Unwrapped transports pay nothing.
+
+use std::sync::Arc;
+
+use crate::transport::{PeerAddr, ProtocolError, RxFrame, Transport, TransportError, TransportId};
+use crate::{DecodeError, FrameV1};
+
+pub trait FrameObserver: Send + Sync {
+    fn on_rx(&self, _frame: &FrameV1) {}
+
+    fn on_tx(&self, _frame: &FrameV1) {}
+
+    /// Received bytes failed to decode. `bytes` is empty when the
+    /// transport no longer has them, as with [`Observed`].
+    fn on_decode_error(&self, _bytes: &[u8], _error: &DecodeError) {}
+}
+
+impl<O: FrameObserver + ?Sized> FrameObserver for Arc<O> {
+    fn on_rx(&self, frame: &FrameV1) {
+        (**self).on_rx(frame)
+    }
+
+    fn on_tx(&self, frame: &FrameV1) {
+        (**self).on_tx(frame)
+    }
+
+    fn on_decode_error(&self, bytes: &[u8], error: &DecodeError) {
+        (**self).on_decode_error(bytes, error)
+    }
+}
+
+/// A transport that reports its traffic to an observer.
+pub struct Observed<T, O> {
+    inner: T,
+    observer: O,
+}
+
+impl<T: Transport, O: FrameObserver> Observed<T, O> {
+    pub fn new(inner: T, observer: O) -> Self {
+        Observed { inner, observer }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport, O: FrameObserver> Transport for Observed<T, O> {
+    async fn send(&mut self, frame: &FrameV1) -> Result<(), TransportError> {
+        self.inner.send(frame).await?;
+        self.observer.on_tx(frame);
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<FrameV1, TransportError> {
+        self.recv_rx().await.map(RxFrame::into_frame)
+    }
+
+    async fn recv_rx(&mut self) -> Result<RxFrame, TransportError> {
+        match self.inner.recv_rx().await {
+            Ok(rx) => {
+                self.observer.on_rx(&rx);
+                Ok(rx)
+            }
+            Err(TransportError::Protocol(ProtocolError::Decode(e))) => {
+                self.observer.on_decode_error(&[], &e);
+                Err(TransportError::Protocol(ProtocolError::Decode(e)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), TransportError> {
+        self.inner.close().await
+    }
+
+    fn peer(&self) -> &PeerAddr {
+        self.inner.peer()
+    }
+
+    fn id(&self) -> TransportId {
+        self.inner.id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::loopback_pair;
+    use crate::transport::tests::frame;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Seen(Mutex<Vec<String>>);
+
+    impl FrameObserver for Seen {
+        fn on_rx(&self, frame: &FrameV1) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("rx {}", frame.header.counter));
+        }
+
+        fn on_tx(&self, frame: &FrameV1) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("tx {}", frame.header.counter));
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_both_directions() {
+        let seen = Arc::new(Seen::default());
+        let (a, b) = loopback_pair();
+        let mut a = Observed::new(a, seen.clone());
+        let mut b = Observed::new(b, seen.clone());
+
+        a.send(&frame(1)).await.unwrap();
+        b.recv().await.unwrap();
+        assert_eq!(*seen.0.lock().unwrap(), ["tx 1", "rx 1"]);
+    }
+}
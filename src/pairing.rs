@@ -0,0 +1,854 @@
+//! Enrolling new devices over the protocol (feature `pairing`).
+//!
+//! An unprovisioned device, known only by its factory identifier, sends
+//! [`PairingMessage::Request`] with an ephemeral X25519 key. The gateway
+//! asks an [`Approver`], typically an operator, and on approval answers
+//! with [`PairingMessage::Grant`]: its own ephemeral key, the assigned
+//! device id, and the credential (e.g. the device's `auth` key) sealed with
+//! ChaCha20-Poly1305 under a key both sides derive from the exchange. The
+//! device proves it could open the grant with [`PairingMessage::Confirm`]
+//! and the gateway ends with [`PairingMessage::Done`]; a refusal is
+//! [`PairingMessage::Denied`].
+//!
+//! The key exchange is not authenticated: the approval, by someone who can
+//! check the factory identifier against the device in hand, is what
+//! decides whom to trust.
+//!
+//! Pairing messages travel as COMMANDs from [`UNPROVISIONED`], with bodies
+//! in the [`CONTROL_PREFIX`] space, and are matched to devices by factory
+//! identifier. Both roles are driven by the application, which sends what
+//! they return and retransmits its last message until answered.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use futures::future::BoxFuture;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand_core::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::clock::{Clock, SystemClock};
+use crate::session::CONTROL_PREFIX;
+use crate::{FrameV1, MsgType};
+
+/// The device id a device sends from until it is assigned one. It is
+/// [`UNASSIGNED`](crate::assign::UNASSIGNED), so a dispatcher's dedup
+/// stage lets pairing messages through too.
+pub const UNPROVISIONED: [u8; 8] = crate::assign::UNASSIGNED;
+
+const REQUEST: u8 = 0x06;
+const GRANT: u8 = 0x07;
+const CONFIRM: u8 = 0x08;
+const DONE: u8 = 0x09;
+const DENIED: u8 = 0x0a;
+
+const KEY_INFO: &[u8] = b"pipproto-pairing";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PairingMessage {
+    Request {
+        factory_id: [u8; 16],
+        public_key: [u8; 32],
+    },
+    Grant {
+        factory_id: [u8; 16],
+        public_key: [u8; 32],
+        device_id: [u8; 8],
+        /// The sealed credential.
+        credential: Vec<u8>,
+    },
+    Confirm {
+        factory_id: [u8; 16],
+        tag: [u8; 32],
+    },
+    Done {
+        factory_id: [u8; 16],
+        tag: [u8; 32],
+    },
+    Denied {
+        factory_id: [u8; 16],
+    },
+}
+
+impl PairingMessage {
+    /// The pairing message `frame` carries, if it is one.
+    pub fn parse(frame: &FrameV1) -> Option<PairingMessage> {
+        if frame.header.msg_type != MsgType::Command {
+            return None;
+        }
+        let (&kind, rest) = frame.body.strip_prefix(&CONTROL_PREFIX)?.split_first()?;
+        let (factory_id, rest) = rest.split_first_chunk::<16>()?;
+        let factory_id = *factory_id;
+        match (kind, rest.len()) {
+            (REQUEST, 32) => Some(PairingMessage::Request {
+                factory_id,
+                public_key: rest.try_into().ok()?,
+            }),
+            (GRANT, 40..) => Some(PairingMessage::Grant {
+                factory_id,
+                public_key: rest[..32].try_into().ok()?,
+                device_id: rest[32..40].try_into().ok()?,
+                credential: rest[40..].to_vec(),
+            }),
+            (CONFIRM, 32) => Some(PairingMessage::Confirm {
+                factory_id,
+                tag: rest.try_into().ok()?,
+            }),
+            (DONE, 32) => Some(PairingMessage::Done {
+                factory_id,
+                tag: rest.try_into().ok()?,
+            }),
+            (DENIED, 0) => Some(PairingMessage::Denied { factory_id }),
+            _ => None,
+        }
+    }
+
+    /// The COMMAND body carrying this message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = CONTROL_PREFIX.to_vec();
+        match self {
+            PairingMessage::Request {
+                factory_id,
+                public_key,
+            } => {
+                body.push(REQUEST);
+                body.extend(factory_id);
+                body.extend(public_key);
+            }
+            PairingMessage::Grant {
+                factory_id,
+                public_key,
+                device_id,
+                credential,
+            } => {
+                body.push(GRANT);
+                body.extend(factory_id);
+                body.extend(public_key);
+                body.extend(device_id);
+                body.extend(credential);
+            }
+            PairingMessage::Confirm { factory_id, tag } => {
+                body.push(CONFIRM);
+                body.extend(factory_id);
+                body.extend(tag);
+            }
+            PairingMessage::Done { factory_id, tag } => {
+                body.push(DONE);
+                body.extend(factory_id);
+                body.extend(tag);
+            }
+            PairingMessage::Denied { factory_id } => {
+                body.push(DENIED);
+                body.extend(factory_id);
+            }
+        }
+        body
+    }
+
+    pub fn factory_id(&self) -> [u8; 16] {
+        match self {
+            PairingMessage::Request { factory_id, .. }
+            | PairingMessage::Grant { factory_id, .. }
+            | PairingMessage::Confirm { factory_id, .. }
+            | PairingMessage::Done { factory_id, .. }
+            | PairingMessage::Denied { factory_id } => *factory_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingError {
+    Denied,
+    TimedOut,
+    Aborted,
+    /// A grant that would not open, or a confirmation tag that is wrong.
+    BadGrant,
+}
+
+impl fmt::Display for PairingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PairingError::Denied => write!(f, "pairing denied"),
+            PairingError::TimedOut => write!(f, "pairing timed out"),
+            PairingError::Aborted => write!(f, "pairing aborted"),
+            PairingError::BadGrant => write!(f, "pairing grant failed to verify"),
+        }
+    }
+}
+
+impl std::error::Error for PairingError {}
+
+/// Keys derived from one exchange.
+struct Keys {
+    seal: ChaCha20Poly1305,
+    device_confirm: [u8; 32],
+    gateway_confirm: [u8; 32],
+}
+
+impl Keys {
+    fn derive(
+        secret: EphemeralSecret,
+        peer: [u8; 32],
+        factory_id: &[u8; 16],
+        device_pub: &[u8; 32],
+        gateway_pub: &[u8; 32],
+    ) -> Option<Keys> {
+        let shared = secret.diffie_hellman(&PublicKey::from(peer));
+        if !shared.was_contributory() {
+            return None;
+        }
+        let hk = Hkdf::<Sha256>::new(None, shared.as_bytes());
+        let mut okm = [0u8; 96];
+        let info = [KEY_INFO, factory_id, device_pub, gateway_pub].concat();
+        hk.expand(&info, &mut okm).unwrap();
+        Some(Keys {
+            seal: ChaCha20Poly1305::new_from_slice(&okm[..32]).unwrap(),
+            device_confirm: okm[32..64].try_into().unwrap(),
+            gateway_confirm: okm[64..].try_into().unwrap(),
+        })
+    }
+
+    // each key seals one credential, so a fixed nonce is safe
+    fn seal(&self, device_id: &[u8; 8], credential: &[u8]) -> Vec<u8> {
+        let payload = Payload {
+            msg: credential,
+            aad: device_id,
+        };
+        self.seal.encrypt(&Nonce::default(), payload).unwrap()
+    }
+
+    fn open(&self, device_id: &[u8; 8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let payload = Payload {
+            msg: sealed,
+            aad: device_id,
+        };
+        self.seal.decrypt(&Nonce::default(), payload).ok()
+    }
+}
+
+fn tag(key: &[u8; 32], device_id: &[u8; 8]) -> Hmac<Sha256> {
+    let mut m = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+    m.update(device_id);
+    m
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceState {
+    Idle,
+    /// Waiting for a grant.
+    Requested,
+    /// Waiting for the gateway to confirm.
+    Confirming,
+    Paired {
+        device_id: [u8; 8],
+        credential: Vec<u8>,
+    },
+    Failed(PairingError),
+}
+
+#[derive(Debug, Clone)]
+pub struct PairingConfig {
+    /// How long the device waits for the whole exchange, approval included.
+    pub device_timeout: Duration,
+    /// How long the gateway waits for the approver.
+    pub approval_timeout: Duration,
+    /// How long the gateway waits for a granted device to confirm.
+    pub confirm_timeout: Duration,
+    /// How long the gateway answers a denied factory identifier's requests
+    /// with the denial, without asking the approver again.
+    pub denial_timeout: Duration,
+}
+
+impl Default for PairingConfig {
+    fn default() -> Self {
+        PairingConfig {
+            device_timeout: Duration::from_secs(300),
+            approval_timeout: Duration::from_secs(240),
+            confirm_timeout: Duration::from_secs(60),
+            denial_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The device's side of the exchange.
+pub struct DevicePairing {
+    clock: Arc<dyn Clock>,
+    timeout: Duration,
+    factory_id: [u8; 16],
+    secret: Option<EphemeralSecret>,
+    public_key: [u8; 32],
+    started: Option<Instant>,
+    state: DeviceState,
+    /// The assignment, until the gateway confirms it.
+    granted: Option<([u8; 8], Vec<u8>, [u8; 32])>,
+}
+
+impl DevicePairing {
+    pub fn new(factory_id: [u8; 16], config: &PairingConfig) -> Self {
+        Self::with_clock(factory_id, config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(factory_id: [u8; 16], config: &PairingConfig, clock: Arc<dyn Clock>) -> Self {
+        DevicePairing {
+            clock,
+            timeout: config.device_timeout,
+            factory_id,
+            secret: None,
+            public_key: [0; 32],
+            started: None,
+            state: DeviceState::Idle,
+            granted: None,
+        }
+    }
+
+    /// Start pairing with a fresh key; also the request to retransmit
+    /// while [`DeviceState::Requested`].
+    pub fn request(&mut self) -> PairingMessage {
+        if self.state != DeviceState::Requested {
+            let secret = EphemeralSecret::random_from_rng(OsRng);
+            self.public_key = PublicKey::from(&secret).to_bytes();
+            self.secret = Some(secret);
+            self.started = Some(self.clock.now());
+            self.granted = None;
+            self.state = DeviceState::Requested;
+        }
+        PairingMessage::Request {
+            factory_id: self.factory_id,
+            public_key: self.public_key,
+        }
+    }
+
+    /// Feed a received pairing message; returns the answer to send.
+    /// Messages for other devices, and ones that do not fit the state, are
+    /// ignored.
+    pub fn on_message(&mut self, message: &PairingMessage) -> Option<PairingMessage> {
+        if message.factory_id() != self.factory_id {
+            return None;
+        }
+        match (&self.state, message) {
+            (
+                DeviceState::Requested,
+                PairingMessage::Grant {
+                    public_key,
+                    device_id,
+                    credential,
+                    ..
+                },
+            ) => {
+                let secret = self.secret.take()?;
+                let opened = Keys::derive(
+                    secret,
+                    *public_key,
+                    &self.factory_id,
+                    &self.public_key,
+                    public_key,
+                )
+                .and_then(|keys| Some((keys.open(device_id, credential)?, keys)));
+                let Some((credential, keys)) = opened else {
+                    self.state = DeviceState::Failed(PairingError::BadGrant);
+                    return None;
+                };
+                let confirm = tag(&keys.device_confirm, device_id).finalize().into_bytes();
+                self.granted = Some((*device_id, credential, keys.gateway_confirm));
+                self.state = DeviceState::Confirming;
+                Some(PairingMessage::Confirm {
+                    factory_id: self.factory_id,
+                    tag: confirm.into(),
+                })
+            }
+            (DeviceState::Confirming, PairingMessage::Done { tag: done, .. }) => {
+                let (device_id, credential, key) = self.granted.take()?;
+                self.state = if tag(&key, &device_id).verify_slice(done).is_ok() {
+                    DeviceState::Paired {
+                        device_id,
+                        credential,
+                    }
+                } else {
+                    DeviceState::Failed(PairingError::BadGrant)
+                };
+                None
+            }
+            (DeviceState::Requested | DeviceState::Confirming, PairingMessage::Denied { .. }) => {
+                self.fail(PairingError::Denied);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Give up if the exchange has taken longer than `device_timeout`.
+    pub fn poll(&mut self, now: Instant) {
+        let in_progress = matches!(self.state, DeviceState::Requested | DeviceState::Confirming);
+        if in_progress && self.started.is_some_and(|t| now >= t + self.timeout) {
+            self.fail(PairingError::TimedOut);
+        }
+    }
+
+    pub fn abort(&mut self) {
+        if !matches!(self.state, DeviceState::Paired { .. }) {
+            self.fail(PairingError::Aborted);
+        }
+    }
+
+    pub fn state(&self) -> &DeviceState {
+        &self.state
+    }
+
+    fn fail(&mut self, error: PairingError) {
+        self.secret = None;
+        self.granted = None;
+        self.state = DeviceState::Failed(error);
+    }
+}
+
+/// What to give an approved device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub device_id: [u8; 8],
+    pub credential: Vec<u8>,
+}
+
+/// Decides on pairing requests, by factory identifier; `None` denies.
+pub trait Approver: Send + Sync {
+    fn approve(&self, factory_id: [u8; 16]) -> BoxFuture<'static, Option<Assignment>>;
+}
+
+impl<F, Fut> Approver for F
+where
+    F: Fn([u8; 16]) -> Fut + Send + Sync,
+    Fut: Future<Output = Option<Assignment>> + Send + 'static,
+{
+    fn approve(&self, factory_id: [u8; 16]) -> BoxFuture<'static, Option<Assignment>> {
+        Box::pin(self(factory_id))
+    }
+}
+
+/// What the gateway makes of a pairing message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GatewayStep {
+    Ignore,
+    Reply(PairingMessage),
+    /// The device confirmed its assignment; send it `reply`.
+    Enrolled {
+        device_id: [u8; 8],
+        reply: PairingMessage,
+    },
+}
+
+enum Pending {
+    Approving,
+    Granted {
+        grant: PairingMessage,
+        device_pub: [u8; 32],
+        device_id: [u8; 8],
+        device_confirm: [u8; 32],
+        gateway_confirm: [u8; 32],
+        at: Instant,
+    },
+    Denied {
+        at: Instant,
+    },
+    Enrolled {
+        device_id: [u8; 8],
+        done: PairingMessage,
+    },
+}
+
+/// The gateway's side, for any number of devices pairing at once.
+pub struct GatewayPairing {
+    clock: Arc<dyn Clock>,
+    config: PairingConfig,
+    approver: Arc<dyn Approver>,
+    pending: Mutex<HashMap<[u8; 16], Pending>>,
+}
+
+impl GatewayPairing {
+    pub fn new(approver: Arc<dyn Approver>, config: PairingConfig) -> Self {
+        Self::with_clock(approver, config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(
+        approver: Arc<dyn Approver>,
+        config: PairingConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        GatewayPairing {
+            clock,
+            config,
+            approver,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Feed a received pairing message. A request waits for the approver,
+    /// without holding up messages from other devices.
+    pub async fn on_message(&self, message: &PairingMessage) -> GatewayStep {
+        match message {
+            PairingMessage::Request {
+                factory_id,
+                public_key,
+            } => self.on_request(*factory_id, *public_key).await,
+            PairingMessage::Confirm {
+                factory_id,
+                tag: confirm,
+            } => self.on_confirm(*factory_id, confirm),
+            _ => GatewayStep::Ignore,
+        }
+    }
+
+    async fn on_request(&self, factory_id: [u8; 16], public_key: [u8; 32]) -> GatewayStep {
+        {
+            let mut pending = self.lock();
+            match pending.get(&factory_id) {
+                Some(Pending::Approving) => return GatewayStep::Ignore,
+                // a retransmission; the grant or denial was lost. A device
+                // that restarted has a new key and needs a new grant.
+                Some(Pending::Granted {
+                    grant, device_pub, ..
+                }) if *device_pub == public_key => {
+                    return GatewayStep::Reply(grant.clone());
+                }
+                Some(Pending::Denied { .. }) => {
+                    return GatewayStep::Reply(PairingMessage::Denied { factory_id });
+                }
+                _ => {}
+            }
+            pending.insert(factory_id, Pending::Approving);
+        }
+
+        let approval = self.approver.approve(factory_id);
+        let decision = tokio::time::timeout(self.config.approval_timeout, approval).await;
+
+        let mut pending = self.lock();
+        if !matches!(pending.get(&factory_id), Some(Pending::Approving)) {
+            // aborted meanwhile
+            return GatewayStep::Ignore;
+        }
+        let denied = PairingMessage::Denied { factory_id };
+        let assignment = match decision {
+            Ok(Some(assignment)) => assignment,
+            // only the approver's own no is remembered, and not for ever:
+            // anyone can send a request under a factory identifier
+            Ok(None) => {
+                let at = self.clock.now();
+                pending.insert(factory_id, Pending::Denied { at });
+                return GatewayStep::Reply(denied);
+            }
+            Err(_) => {
+                pending.remove(&factory_id);
+                return GatewayStep::Reply(denied);
+            }
+        };
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let gateway_pub = PublicKey::from(&secret).to_bytes();
+        let keys = Keys::derive(secret, public_key, &factory_id, &public_key, &gateway_pub);
+        let Some(keys) = keys else {
+            pending.remove(&factory_id);
+            return GatewayStep::Reply(denied);
+        };
+        let grant = PairingMessage::Grant {
+            factory_id,
+            public_key: gateway_pub,
+            device_id: assignment.device_id,
+            credential: keys.seal(&assignment.device_id, &assignment.credential),
+        };
+        pending.insert(
+            factory_id,
+            Pending::Granted {
+                grant: grant.clone(),
+                device_pub: public_key,
+                device_id: assignment.device_id,
+                device_confirm: keys.device_confirm,
+                gateway_confirm: keys.gateway_confirm,
+                at: self.clock.now(),
+            },
+        );
+        GatewayStep::Reply(grant)
+    }
+
+    fn on_confirm(&self, factory_id: [u8; 16], confirm: &[u8; 32]) -> GatewayStep {
+        let mut pending = self.lock();
+        match pending.get(&factory_id) {
+            Some(Pending::Granted {
+                device_id,
+                device_confirm,
+                gateway_confirm,
+                ..
+            }) => {
+                if tag(device_confirm, device_id)
+                    .verify_slice(confirm)
+                    .is_err()
+                {
+                    return GatewayStep::Ignore;
+                }
+                let device_id = *device_id;
+                let done = PairingMessage::Done {
+                    factory_id,
+                    tag: tag(gateway_confirm, &device_id)
+                        .finalize()
+                        .into_bytes()
+                        .into(),
+                };
+                pending.insert(
+                    factory_id,
+                    Pending::Enrolled {
+                        device_id,
+                        done: done.clone(),
+                    },
+                );
+                GatewayStep::Enrolled {
+                    device_id,
+                    reply: done,
+                }
+            }
+            // the device missed DONE
+            Some(Pending::Enrolled { done, .. }) => GatewayStep::Reply(done.clone()),
+            _ => GatewayStep::Ignore,
+        }
+    }
+
+    /// Forget grants not confirmed within `confirm_timeout`, returning
+    /// their factory identifiers, and denials older than `denial_timeout`.
+    pub fn poll(&self, now: Instant) -> Vec<[u8; 16]> {
+        let limit = self.config.confirm_timeout;
+        let mut expired = Vec::new();
+        self.lock().retain(|factory_id, p| match p {
+            Pending::Granted { at, .. } if now >= *at + limit => {
+                expired.push(*factory_id);
+                false
+            }
+            Pending::Denied { at } => now < *at + self.config.denial_timeout,
+            _ => true,
+        });
+        expired.sort();
+        expired
+    }
+
+    /// Drop whatever is under way for `factory_id`, so that its next
+    /// request starts afresh.
+    pub fn abort(&self, factory_id: [u8; 16]) {
+        self.lock().remove(&factory_id);
+    }
+
+    /// The device id `factory_id` was enrolled under.
+    pub fn enrolled(&self, factory_id: [u8; 16]) -> Option<[u8; 8]> {
+        match self.lock().get(&factory_id) {
+            Some(Pending::Enrolled { device_id, .. }) => Some(*device_id),
+            _ => None,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<[u8; 16], Pending>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use tokio::sync::oneshot;
+
+    const FACTORY_A: [u8; 16] = *b"factory-serial-A";
+    const FACTORY_B: [u8; 16] = *b"factory-serial-B";
+
+    fn approve_all() -> Arc<dyn Approver> {
+        Arc::new(|factory_id: [u8; 16]| async move {
+            Some(Assignment {
+                device_id: [b'D', b'E', b'V', 0, 0, 0, 0, factory_id[15]],
+                credential: b"secret key".to_vec(),
+            })
+        })
+    }
+
+    /// Runs one device through the gateway, over frames.
+    async fn pair(device: &mut DevicePairing, gateway: &GatewayPairing) -> Option<[u8; 8]> {
+        let over_the_air = |m: PairingMessage| {
+            let mut f = crate::transport::tests::frame(1);
+            f.header.msg_type = MsgType::Command;
+            f.header.device_id = UNPROVISIONED;
+            f.body = m.encode().into();
+            PairingMessage::parse(&f).unwrap()
+        };
+        let request = over_the_air(device.request());
+        let GatewayStep::Reply(grant) = gateway.on_message(&request).await else {
+            panic!("a request is always answered");
+        };
+        let confirm = device.on_message(&over_the_air(grant))?;
+        let GatewayStep::Enrolled { device_id, reply } =
+            gateway.on_message(&over_the_air(confirm)).await
+        else {
+            panic!("confirmation accepted");
+        };
+        assert_eq!(device.on_message(&over_the_air(reply)), None);
+        Some(device_id)
+    }
+
+    #[tokio::test]
+    async fn approved_device_gets_its_credential() {
+        let gateway = GatewayPairing::new(approve_all(), PairingConfig::default());
+        let mut device = DevicePairing::new(FACTORY_A, &PairingConfig::default());
+        let device_id = pair(&mut device, &gateway).await.unwrap();
+        assert_eq!(device_id, *b"DEV\0\0\0\0A");
+        assert_eq!(
+            device.state(),
+            &DeviceState::Paired {
+                device_id,
+                credential: b"secret key".to_vec()
+            }
+        );
+        assert_eq!(gateway.enrolled(FACTORY_A), Some(device_id));
+    }
+
+    #[tokio::test]
+    async fn rejection_and_eavesdropping() {
+        let deny: Arc<dyn Approver> = Arc::new(|_| async { None });
+        let gateway = GatewayPairing::new(deny, PairingConfig::default());
+        let mut device = DevicePairing::new(FACTORY_A, &PairingConfig::default());
+        let step = gateway.on_message(&device.request()).await;
+        let GatewayStep::Reply(denied) = step else {
+            panic!("denials are sent");
+        };
+        assert_eq!(
+            denied,
+            PairingMessage::Denied {
+                factory_id: FACTORY_A
+            }
+        );
+        assert_eq!(device.on_message(&denied), None);
+        assert_eq!(device.state(), &DeviceState::Failed(PairingError::Denied));
+
+        // a grant meant for someone else's key will not open
+        let gateway = GatewayPairing::new(approve_all(), PairingConfig::default());
+        let mut device = DevicePairing::new(FACTORY_A, &PairingConfig::default());
+        let mut eavesdropper = DevicePairing::new(FACTORY_A, &PairingConfig::default());
+        eavesdropper.request();
+        let GatewayStep::Reply(grant) = gateway.on_message(&device.request()).await else {
+            panic!("granted");
+        };
+        assert_eq!(eavesdropper.on_message(&grant), None);
+        assert_eq!(
+            eavesdropper.state(),
+            &DeviceState::Failed(PairingError::BadGrant)
+        );
+    }
+
+    #[tokio::test]
+    async fn denials_expire_and_timeouts_are_not_denials() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let clock = ManualClock::new();
+        let config = PairingConfig {
+            approval_timeout: Duration::from_millis(10),
+            ..PairingConfig::default()
+        };
+        let asked = Arc::new(AtomicUsize::new(0));
+        let approver: Arc<dyn Approver> = Arc::new({
+            let asked = asked.clone();
+            move |factory_id: [u8; 16]| {
+                asked.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    if factory_id == FACTORY_B {
+                        futures::future::pending::<()>().await;
+                    }
+                    None
+                }
+            }
+        });
+        let gateway = GatewayPairing::with_clock(approver, config.clone(), Arc::new(clock.clone()));
+        let request = |factory_id| PairingMessage::Request {
+            factory_id,
+            public_key: [9; 32],
+        };
+        let denied = |factory_id| GatewayStep::Reply(PairingMessage::Denied { factory_id });
+
+        // nobody answered for B: its next request is put to the approver
+        for n in 1..=2 {
+            assert_eq!(
+                gateway.on_message(&request(FACTORY_B)).await,
+                denied(FACTORY_B)
+            );
+            assert_eq!(asked.load(Ordering::Relaxed), n);
+        }
+
+        // A was denied: repeats get the denial until it expires
+        for _ in 0..2 {
+            assert_eq!(
+                gateway.on_message(&request(FACTORY_A)).await,
+                denied(FACTORY_A)
+            );
+        }
+        assert_eq!(asked.load(Ordering::Relaxed), 3);
+        clock.advance(config.denial_timeout);
+        assert_eq!(gateway.poll(clock.now()), Vec::<[u8; 16]>::new());
+        gateway.on_message(&request(FACTORY_A)).await;
+        assert_eq!(asked.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn devices_pair_concurrently() {
+        // the operator approves B first, while A's request is pending
+        let (approve_a, decision_a) = oneshot::channel::<()>();
+        let decision_a = Mutex::new(Some(decision_a));
+        let approver: Arc<dyn Approver> = Arc::new(move |factory_id: [u8; 16]| {
+            let wait =
+                (factory_id == FACTORY_A).then(|| decision_a.lock().unwrap().take().unwrap());
+            async move {
+                if let Some(wait) = wait {
+                    wait.await.ok()?;
+                }
+                Some(Assignment {
+                    device_id: [factory_id[15]; 8],
+                    credential: vec![factory_id[15]],
+                })
+            }
+        });
+        let gateway = GatewayPairing::new(approver, PairingConfig::default());
+        let mut a = DevicePairing::new(FACTORY_A, &PairingConfig::default());
+        let mut b = DevicePairing::new(FACTORY_B, &PairingConfig::default());
+
+        let pair_a = pair(&mut a, &gateway);
+        let pair_b = async {
+            let id = pair(&mut b, &gateway).await;
+            // A's retransmitted request is not approved twice
+            let again = PairingMessage::Request {
+                factory_id: FACTORY_A,
+                public_key: [9; 32],
+            };
+            assert_eq!(gateway.on_message(&again).await, GatewayStep::Ignore);
+            approve_a.send(()).unwrap();
+            id
+        };
+        let (id_a, id_b) = tokio::join!(pair_a, pair_b);
+        assert_eq!(id_a, Some([b'A'; 8]));
+        assert_eq!(id_b, Some([b'B'; 8]));
+        assert!(matches!(b.state(), DeviceState::Paired { credential, .. } if credential == b"B"));
+    }
+
+    #[tokio::test]
+    async fn timeouts_and_abort() {
+        let clock = ManualClock::new();
+        let config = PairingConfig::default();
+        let mut device = DevicePairing::with_clock(FACTORY_A, &config, Arc::new(clock.clone()));
+        device.request();
+        clock.advance(config.device_timeout);
+        device.poll(clock.now());
+        assert_eq!(device.state(), &DeviceState::Failed(PairingError::TimedOut));
+
+        let gateway =
+            GatewayPairing::with_clock(approve_all(), config.clone(), Arc::new(clock.clone()));
+        let mut device = DevicePairing::new(FACTORY_A, &config);
+        gateway.on_message(&device.request()).await;
+        assert_eq!(gateway.poll(clock.now()), Vec::<[u8; 16]>::new());
+        clock.advance(config.confirm_timeout);
+        assert_eq!(gateway.poll(clock.now()), [FACTORY_A]);
+
+        device.abort();
+        assert_eq!(device.state(), &DeviceState::Failed(PairingError::Aborted));
+    }
+}
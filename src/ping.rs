@@ -0,0 +1,360 @@
+//! Round-trip times to each device.
+//!
+//! A timed PING carries a sequence number and the sender's timestamp,
+//! `00 53 03 <seq u32> <micros u64>`, and its answer, the pong, is an ACK
+//! whose [`AckBody::Reply`] echoes those 12 bytes; [`pong`] builds it, and
+//! also answers a bare PING with a plain ACK. The round trip is measured
+//! on the sender's own clock from sending to the answer, so a peer's idea
+//! of the time never enters into it, and the echo lets anyone watching the
+//! link pair a pong with its PING.
+//!
+//! [`Pinger::ping`] pings one device; [`Pinger::run`] pings every device
+//! in a [`DeviceRegistry`] each `interval`. Each device's [`RttStats`] is
+//! kept in the registry. A PING left unanswered until the
+//! [`Requester`]'s timeout counts as lost and is not a sample. With the
+//! `metrics` feature, answered PINGs feed a histogram and lost ones a
+//! counter.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::ack::AckBody;
+use crate::clock::{Clock, SystemClock};
+use crate::registry::DeviceRegistry;
+use crate::request::{RequestError, Requester, ack_reply};
+use crate::sender::{CounterMap, CounterSource, SendError};
+use crate::session::{CONTROL_PREFIX, Control};
+use crate::transport::{Transport, TransportError};
+use crate::{FrameV1, MsgType};
+
+const PING: u8 = 0x03;
+
+/// Bytes after the kind in a timed PING.
+pub(crate) const ECHO_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingMessage {
+    pub seq: u32,
+    /// When it was sent, on the sender's clock; meaningless to anyone else.
+    pub sent_micros: u64,
+}
+
+impl PingMessage {
+    /// The timed PING `frame` carries, if it is one.
+    pub fn parse(frame: &FrameV1) -> Option<PingMessage> {
+        if frame.header.msg_type != MsgType::Command {
+            return None;
+        }
+        match frame.body.strip_prefix(&CONTROL_PREFIX)? {
+            [PING, echo @ ..] => Self::decode(echo),
+            _ => None,
+        }
+    }
+
+    fn decode(echo: &[u8]) -> Option<PingMessage> {
+        let echo: &[u8; ECHO_LEN] = echo.try_into().ok()?;
+        let (seq, micros) = echo.split_at(4);
+        Some(PingMessage {
+            seq: u32::from_be_bytes(seq.try_into().unwrap()),
+            sent_micros: u64::from_be_bytes(micros.try_into().unwrap()),
+        })
+    }
+
+    fn echo(&self) -> Vec<u8> {
+        [&self.seq.to_be_bytes()[..], &self.sent_micros.to_be_bytes()].concat()
+    }
+
+    /// The COMMAND body carrying this PING.
+    pub fn encode(&self) -> Vec<u8> {
+        [&CONTROL_PREFIX[..], &[PING], &self.echo()].concat()
+    }
+}
+
+/// The answer to `ping`, if it is a PING: the echo of a timed one, a plain
+/// ACK otherwise.
+pub fn pong(ping: &FrameV1) -> Option<FrameV1> {
+    if Control::parse(ping) != Some(Control::Ping) {
+        return None;
+    }
+    let body = match PingMessage::parse(ping) {
+        Some(p) => AckBody::Reply(p.echo()),
+        None => AckBody::Single,
+    };
+    Some(ack_reply(&ping.header, body))
+}
+
+/// The echoed PING in a pong's ACK body.
+pub fn parse_pong(ack: &FrameV1) -> Option<PingMessage> {
+    match AckBody::decode(&ack.body) {
+        Ok(AckBody::Reply(echo)) if ack.header.msg_type == MsgType::Ack => {
+            PingMessage::decode(&echo)
+        }
+        _ => None,
+    }
+}
+
+/// Round trips to one device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RttStats {
+    pub answered: u64,
+    pub lost: u64,
+    pub last: Option<Duration>,
+    pub min: Option<Duration>,
+    /// Of the answered PINGs.
+    pub mean: Option<Duration>,
+    /// Exponentially weighted, following recent changes.
+    pub ewma: Option<Duration>,
+}
+
+impl RttStats {
+    /// Add an answered PING; `weight` is the share of the EWMA given to
+    /// it.
+    pub fn sample(&mut self, rtt: Duration, weight: f64) {
+        self.answered += 1;
+        self.last = Some(rtt);
+        self.min = Some(self.min.map_or(rtt, |m| m.min(rtt)));
+        let mean = self.mean.map_or(0.0, |m| m.as_secs_f64());
+        let mean = mean + (rtt.as_secs_f64() - mean) / self.answered as f64;
+        self.mean = Some(Duration::from_secs_f64(mean.max(0.0)));
+        let w = weight.clamp(0.0, 1.0);
+        self.ewma = Some(match self.ewma {
+            None => rtt,
+            Some(e) => Duration::from_secs_f64((1.0 - w) * e.as_secs_f64() + w * rtt.as_secs_f64()),
+        });
+    }
+
+    pub fn lose(&mut self) {
+        self.lost += 1;
+    }
+
+    /// Lost PINGs as a fraction of those sent.
+    pub fn loss(&self) -> f64 {
+        match self.answered + self.lost {
+            0 => 0.0,
+            sent => self.lost as f64 / sent as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PingConfig {
+    /// Between rounds of [`Pinger::run`].
+    pub interval: Duration,
+    /// Share of the EWMA given to each new sample.
+    pub ewma_weight: f64,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        PingConfig {
+            interval: Duration::from_secs(30),
+            ewma_weight: 0.125,
+        }
+    }
+}
+
+/// PINGs sent through a [`Requester`], which must be fed the replies.
+pub struct Pinger<T, C = CounterMap> {
+    requester: Requester<T, C>,
+    registry: DeviceRegistry,
+    config: PingConfig,
+    clock: Arc<dyn Clock>,
+    epoch: Instant,
+    seq: AtomicU32,
+    /// Devices forgotten by the registry keep their history here.
+    stats: Mutex<HashMap<[u8; 8], RttStats>>,
+}
+
+impl<T: Transport, C: CounterSource> Pinger<T, C> {
+    pub fn new(requester: Requester<T, C>, registry: DeviceRegistry, config: PingConfig) -> Self {
+        Self::with_clock(requester, registry, config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(
+        requester: Requester<T, C>,
+        registry: DeviceRegistry,
+        config: PingConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Pinger {
+            requester,
+            registry,
+            config,
+            epoch: clock.now(),
+            clock,
+            seq: AtomicU32::new(0),
+            stats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Ping `device_id` and wait for the pong. Only an ACK is a sample;
+    /// a timeout is a lost PING, and a NACK or ERROR neither.
+    pub async fn ping(&self, device_id: [u8; 8]) -> Result<Duration, RequestError> {
+        let sent = self.clock.now();
+        let ping = PingMessage {
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            sent_micros: (sent - self.epoch).as_micros() as u64,
+        };
+        let result = self.requester.send_command(device_id, ping.encode()).await;
+        let rtt = self.clock.now().saturating_duration_since(sent);
+        let weight = self.config.ewma_weight;
+        let record = |update: &dyn Fn(&mut RttStats)| {
+            let mut all = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+            let stats = all.entry(device_id).or_default();
+            update(stats);
+            self.registry.set_rtt(device_id, *stats);
+        };
+        match result {
+            Ok(_) => {
+                record(&|s| s.sample(rtt, weight));
+                #[cfg(feature = "metrics")]
+                crate::metrics::global().ping_answered(rtt);
+                Ok(rtt)
+            }
+            Err(RequestError::Timeout) => {
+                record(&|s| s.lose());
+                #[cfg(feature = "metrics")]
+                crate::metrics::global().ping_lost();
+                Err(RequestError::Timeout)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn stats(&self, device_id: [u8; 8]) -> Option<RttStats> {
+        let all = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        all.get(&device_id).copied()
+    }
+
+    /// Ping every registered device at once, every `interval`, until a
+    /// send fails.
+    pub async fn run(&self) -> SendError<TransportError> {
+        loop {
+            let pings = self
+                .registry
+                .list()
+                .into_iter()
+                .map(|d| self.ping(d.device_id));
+            for result in futures::future::join_all(pings).await {
+                if let Err(RequestError::Send(e)) = result {
+                    return e;
+                }
+            }
+            tokio::time::sleep(self.config.interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::RegistryConfig;
+    use crate::request::RequestConfig;
+    use crate::sender::FrameSender;
+    use crate::transport::{PeerAddr, RxFrame, TransportId, loopback_pair};
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn stats_leave_losses_out_of_the_averages() {
+        let mut s = RttStats::default();
+        s.sample(10 * MS, 0.5);
+        s.lose();
+        s.sample(30 * MS, 0.5);
+        s.lose();
+        assert_eq!(s.answered, 2);
+        assert_eq!(s.last, Some(30 * MS));
+        assert_eq!(s.min, Some(10 * MS));
+        assert_eq!(s.mean, Some(20 * MS));
+        assert_eq!(s.ewma, Some(20 * MS));
+        assert_eq!(s.loss(), 0.5);
+    }
+
+    #[test]
+    fn pong_echoes_the_ping() {
+        let mut ping = crate::transport::tests::frame(9);
+        ping.header.msg_type = MsgType::Command;
+        let sent = PingMessage {
+            seq: 7,
+            sent_micros: u64::MAX,
+        };
+        ping.body = sent.encode().into();
+        assert_eq!(Control::parse(&ping), Some(Control::Ping));
+        let reply = pong(&ping).unwrap();
+        assert_eq!(reply.header.counter, 9);
+        assert_eq!(parse_pong(&reply), Some(sent));
+
+        ping.body = Control::Ping.encode().into();
+        assert_eq!(
+            AckBody::decode(&pong(&ping).unwrap().body),
+            Ok(AckBody::Single)
+        );
+        ping.body = vec![0x10].into();
+        assert_eq!(pong(&ping), None);
+    }
+
+    #[tokio::test]
+    async fn probes_registered_devices() {
+        let (gateway_out, mut devices_in) = loopback_pair();
+        let (mut devices_out, mut gateway_in) = loopback_pair();
+        let requester = Requester::new(
+            FrameSender::new(gateway_out, Arc::new(CounterMap::default())),
+            RequestConfig {
+                timeout: 30 * MS,
+                ..RequestConfig::default()
+            },
+        );
+        let registry = DeviceRegistry::new(RegistryConfig::default());
+        for id in [*b"DEV00001", *b"DEV00002"] {
+            let mut f = crate::transport::tests::frame(1);
+            f.header.device_id = id;
+            registry.observe(&RxFrame::new(
+                f,
+                PeerAddr::Opaque("test".into()),
+                TransportId::UNASSIGNED,
+            ));
+        }
+        let pinger = Pinger::new(
+            requester.clone(),
+            registry.clone(),
+            PingConfig {
+                interval: 20 * MS,
+                ..PingConfig::default()
+            },
+        );
+
+        // DEV00002 never answers, and DEV00001 only after a while
+        tokio::spawn(async move {
+            while let Ok(ping) = devices_in.recv().await {
+                if &ping.header.device_id == b"DEV00001" {
+                    tokio::time::sleep(5 * MS).await;
+                    devices_out.send(&pong(&ping).unwrap()).await.unwrap();
+                }
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok(frame) = gateway_in.recv().await {
+                requester.on_frame(&frame);
+            }
+        });
+
+        let rtt = pinger.ping(*b"DEV00001").await.unwrap();
+        assert!(rtt >= 5 * MS);
+        tokio::select! {
+            _ = pinger.run() => unreachable!(),
+            _ = tokio::time::sleep(100 * MS) => {}
+        }
+
+        let answered = registry.get(*b"DEV00001").unwrap().rtt;
+        assert!(answered.answered >= 3, "{answered:?}");
+        assert_eq!(answered.lost, 0);
+        assert!(answered.min.unwrap() >= 5 * MS);
+        let silent = pinger.stats(*b"DEV00002").unwrap();
+        assert_eq!(silent.answered, 0);
+        assert!(silent.lost >= 2);
+        assert_eq!((silent.mean, silent.ewma), (None, None));
+        assert_eq!(registry.get(*b"DEV00002").unwrap().rtt, silent);
+    }
+}
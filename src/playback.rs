@@ -0,0 +1,252 @@
+//! Sending recorded traffic again.
+//!
+//! [`play`] sends the frames of a journal's records over a transport,
+//! spaced out as they were recorded, or `speed` times faster, or as fast
+//! as the transport takes them. Records can be picked by direction and
+//! [`FrameFilter`], and their frames given other device ids or fresh
+//! counters ([`Rewrite`]) so a replay does not collide with the live
+//! devices it came from. The filter sees the frames as recorded.
+//!
+//! A failed send is counted and the next frame tried, except that a
+//! transport reporting [`TransportError::Disconnected`] ends the replay.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use tokio::time::Instant;
+
+use crate::FrameV1;
+use crate::filter::FrameFilter;
+use crate::sink::{Direction, FrameRecord, Payload};
+use crate::transport::{Transport, TransportError};
+
+/// What is done to frames before they are sent again.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rewrite {
+    /// Device ids replaced outright.
+    pub devices: HashMap<[u8; 8], [u8; 8]>,
+    /// XORed into every device id not in `devices`.
+    pub device_xor: [u8; 8],
+    /// Number each device's frames from 1 in the order they are sent; a
+    /// counter seen before gets the number it got then.
+    pub reset_counters: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackConfig {
+    /// How many times faster than recorded, above zero; `None` ignores
+    /// the recorded times.
+    pub speed: Option<f64>,
+    /// Records of the other direction are skipped; `None` sends both.
+    pub direction: Option<Direction>,
+    pub filter: Option<FrameFilter>,
+    pub rewrite: Rewrite,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        PlaybackConfig {
+            speed: Some(1.0),
+            direction: Some(Direction::Rx),
+            filter: None,
+            rewrite: Rewrite::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PlaybackSummary {
+    pub sent: u64,
+    /// Records passed over: the other direction, not matching the filter,
+    /// or not a frame.
+    pub skipped: u64,
+    pub failed: u64,
+    pub last_error: Option<TransportError>,
+    /// Whether the transport went away before the end.
+    pub disconnected: bool,
+    /// From the first frame sent to the last.
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Rewriter {
+    rewrite: Rewrite,
+    /// Per device, as rewritten: the next counter, and those handed out.
+    counters: HashMap<[u8; 8], (u64, HashMap<u64, u64>)>,
+}
+
+impl Rewriter {
+    fn apply(&mut self, frame: &mut FrameV1) {
+        let h = &mut frame.header;
+        h.device_id = match self.rewrite.devices.get(&h.device_id) {
+            Some(id) => *id,
+            None => {
+                let mut id = h.device_id;
+                for (b, x) in id.iter_mut().zip(self.rewrite.device_xor) {
+                    *b ^= x;
+                }
+                id
+            }
+        };
+        if self.rewrite.reset_counters {
+            let (next, given) = self
+                .counters
+                .entry(h.device_id)
+                .or_insert((1, HashMap::new()));
+            h.counter = *given.entry(h.counter).or_insert_with(|| {
+                *next += 1;
+                *next - 1
+            });
+        }
+    }
+}
+
+/// Send the frames of `records` over `transport`, calling `progress`
+/// after each record.
+pub async fn play<T, I>(
+    transport: &mut T,
+    records: I,
+    config: &PlaybackConfig,
+    mut progress: impl FnMut(&PlaybackSummary),
+) -> PlaybackSummary
+where
+    T: Transport,
+    I: IntoIterator<Item = FrameRecord>,
+{
+    let mut summary = PlaybackSummary::default();
+    let mut rewriter = Rewriter {
+        rewrite: config.rewrite.clone(),
+        ..Rewriter::default()
+    };
+    let mut origin: Option<(SystemTime, Instant)> = None;
+    for record in records {
+        let Payload::Frame(mut frame) = record.payload else {
+            summary.skipped += 1;
+            progress(&summary);
+            continue;
+        };
+        let wanted = config.direction.is_none_or(|d| d == record.direction)
+            && config.filter.as_ref().is_none_or(|f| f.matches(&frame));
+        if !wanted {
+            summary.skipped += 1;
+            progress(&summary);
+            continue;
+        }
+        let (recorded, started) = *origin.get_or_insert((record.timestamp, Instant::now()));
+        if let Some(speed) = config.speed {
+            let offset = record
+                .timestamp
+                .duration_since(recorded)
+                .unwrap_or_default();
+            tokio::time::sleep_until(started + offset.div_f64(speed)).await;
+        }
+        rewriter.apply(&mut frame);
+        match transport.send(&frame).await {
+            Ok(()) => summary.sent += 1,
+            Err(e) => {
+                summary.failed += 1;
+                let gone = matches!(e, TransportError::Disconnected);
+                summary.last_error = Some(e);
+                if gone {
+                    summary.disconnected = true;
+                }
+            }
+        }
+        summary.elapsed = started.elapsed();
+        progress(&summary);
+        if summary.disconnected {
+            break;
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::loopback_pair;
+    use crate::transport::tests::frame;
+
+    fn records(spacing_ms: u64, n: u64) -> Vec<FrameRecord> {
+        (1..=n)
+            .map(|c| {
+                let mut r = FrameRecord::rx(frame(c));
+                r.timestamp = SystemTime::UNIX_EPOCH + Duration::from_millis(spacing_ms * c);
+                r
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_the_recorded_spacing_scaled() {
+        let (mut a, mut b) = loopback_pair();
+        let config = PlaybackConfig {
+            speed: Some(2.0),
+            ..PlaybackConfig::default()
+        };
+        let start = std::time::Instant::now();
+        let summary = play(&mut a, records(100, 4), &config, |_| {}).await;
+        let took = start.elapsed();
+        assert_eq!(summary.sent, 4);
+        // 300ms recorded, at double speed
+        assert!(took >= Duration::from_millis(150), "{took:?}");
+        assert!(took < Duration::from_millis(300), "{took:?}");
+        for c in 1..=4 {
+            assert_eq!(b.recv().await.unwrap().header.counter, c);
+        }
+
+        let config = PlaybackConfig {
+            speed: None,
+            ..PlaybackConfig::default()
+        };
+        let start = std::time::Instant::now();
+        play(&mut a, records(1000, 4), &config, |_| {}).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn skips_and_rewrites() {
+        let (mut a, mut b) = loopback_pair();
+        let mut input = records(0, 6);
+        input[1].direction = Direction::Tx;
+        input[4] = input[2].clone(); // a retransmission of counter 3
+        let config = PlaybackConfig {
+            speed: None,
+            filter: Some("counter != 6".parse().unwrap()),
+            rewrite: Rewrite {
+                device_xor: [0, 0, 0, 0, 0, 0, 0, 0xff],
+                reset_counters: true,
+                ..Rewrite::default()
+            },
+            ..PlaybackConfig::default()
+        };
+        let mut calls = 0;
+        let summary = play(&mut a, input, &config, |_| calls += 1).await;
+        assert_eq!((summary.sent, summary.skipped, calls), (4, 2, 6));
+        let mut got = Vec::new();
+        for _ in 0..4 {
+            let f = b.recv().await.unwrap();
+            assert_eq!(f.header.device_id, *b"DEV0000\xce");
+            got.push(f.header.counter);
+        }
+        // counters 1, 3, 4, 3 renumbered in order of first sight
+        assert_eq!(got, [1, 2, 3, 2]);
+    }
+
+    #[tokio::test]
+    async fn stops_when_disconnected() {
+        let (mut a, b) = loopback_pair();
+        drop(b);
+        let config = PlaybackConfig {
+            speed: None,
+            ..PlaybackConfig::default()
+        };
+        let summary = play(&mut a, records(0, 5), &config, |_| {}).await;
+        assert_eq!((summary.sent, summary.failed), (0, 1));
+        assert!(summary.disconnected);
+        assert!(matches!(
+            summary.last_error,
+            Some(TransportError::Disconnected)
+        ));
+    }
+}
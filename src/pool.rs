@@ -0,0 +1,394 @@
+//! Reusable buffers, so a long-running gateway isn't allocating and
+//! freeing one per frame.
+//!
+//! A [`BufferPool`] keeps free buffers in a few size classes and hands them
+//! out as [`Pooled`] guards, which put them back when dropped. It is a
+//! handle: clones share the buffers, across tasks and threads. Hits,
+//! misses and the buffers handed out are counted in [`BufferPool::stats`]
+//! and, with the `metrics` feature, in the global registry.
+//!
+//! A buffer is only ever handed to one guard at a time: a `Vec` is owned by
+//! it outright, and a `BytesMut` comes back only once it has the room for
+//! its class to itself, so bytes split off it earlier are never written
+//! over.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+
+/// Size classes: 1 KiB holds a journal record or an encoded frame of the
+/// usual size, 8 KiB is what a `Framed` stream starts its buffers at, and
+/// 72 KiB holds a frame of [`DEFAULT_MAX_FRAME_LEN`] with its framing.
+///
+/// [`DEFAULT_MAX_FRAME_LEN`]: crate::codec::DEFAULT_MAX_FRAME_LEN
+pub const DEFAULT_CLASSES: [usize; 3] = [1 << 10, 8 << 10, 72 << 10];
+
+/// Free buffers kept per class by default.
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// A buffer a [`BufferPool`] can hold: `Vec<u8>` or `BytesMut`.
+pub trait Reusable: sealed::Sealed + Send + 'static {}
+
+impl Reusable for Vec<u8> {}
+impl Reusable for BytesMut {}
+
+mod sealed {
+    use bytes::BytesMut;
+
+    pub trait Sealed: Sized {
+        fn with_capacity(n: usize) -> Self;
+        fn capacity(&self) -> usize;
+        /// Empty, and able to take `n` bytes without allocating or writing
+        /// over bytes anyone else holds.
+        fn reclaim(&mut self, n: usize) -> bool;
+    }
+
+    impl Sealed for Vec<u8> {
+        fn with_capacity(n: usize) -> Self {
+            Vec::with_capacity(n)
+        }
+
+        fn capacity(&self) -> usize {
+            Vec::capacity(self)
+        }
+
+        fn reclaim(&mut self, n: usize) -> bool {
+            self.clear();
+            Vec::capacity(self) >= n
+        }
+    }
+
+    impl Sealed for BytesMut {
+        fn with_capacity(n: usize) -> Self {
+            BytesMut::with_capacity(n)
+        }
+
+        fn capacity(&self) -> usize {
+            BytesMut::capacity(self)
+        }
+
+        fn reclaim(&mut self, n: usize) -> bool {
+            self.clear();
+            self.try_reclaim(n)
+        }
+    }
+}
+
+/// Hits, misses, and the buffers handed out and not yet back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PoolStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub outstanding: u64,
+}
+
+struct Inner<T> {
+    classes: Vec<usize>,
+    capacity: usize,
+    free: Vec<Mutex<Vec<T>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    outstanding: AtomicU64,
+}
+
+/// Buffers of a few size classes, `Vec<u8>` unless given as
+/// `BufferPool<BytesMut>`.
+pub struct BufferPool<T: Reusable = Vec<u8>> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T: Reusable> Clone for BufferPool<T> {
+    fn clone(&self) -> Self {
+        BufferPool {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T: Reusable> fmt::Debug for BufferPool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("classes", &self.inner.classes)
+            .field("capacity", &self.inner.capacity)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl<T: Reusable> Default for BufferPool<T> {
+    fn default() -> Self {
+        BufferPool::new()
+    }
+}
+
+impl<T: Reusable> BufferPool<T> {
+    pub fn new() -> Self {
+        BufferPool::build(DEFAULT_CLASSES.to_vec(), DEFAULT_CAPACITY)
+    }
+
+    fn build(mut classes: Vec<usize>, capacity: usize) -> Self {
+        classes.sort_unstable();
+        classes.dedup();
+        BufferPool {
+            inner: Arc::new(Inner {
+                free: classes.iter().map(|_| Mutex::new(Vec::new())).collect(),
+                classes,
+                capacity,
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+                outstanding: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Size classes, in bytes. A request is served from the smallest class
+    /// that holds it; one bigger than them all is allocated to size and
+    /// not kept.
+    pub fn with_classes(self, classes: &[usize]) -> Self {
+        BufferPool::build(classes.to_vec(), self.inner.capacity)
+    }
+
+    /// Free buffers kept per class; more than that are freed on return.
+    pub fn with_capacity(self, buffers_per_class: usize) -> Self {
+        BufferPool::build(self.inner.classes.clone(), buffers_per_class)
+    }
+
+    /// An empty buffer with room for at least `len` bytes.
+    pub fn get(&self, len: usize) -> Pooled<T> {
+        Pooled {
+            buf: Some(self.take(len)),
+            pool: Some(self.clone()),
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        PoolStats {
+            hits: get(&self.inner.hits),
+            misses: get(&self.inner.misses),
+            outstanding: get(&self.inner.outstanding),
+        }
+    }
+
+    /// As [`get`](Self::get), the caller to hand the buffer back with
+    /// [`give_back`](Self::give_back).
+    pub(crate) fn take(&self, len: usize) -> T {
+        let inner = &*self.inner;
+        inner.outstanding.fetch_add(1, Ordering::Relaxed);
+        let class = inner.classes.iter().position(|&c| c >= len);
+        let reused = class.and_then(|i| lock(&inner.free[i]).pop());
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().pool_taken(reused.is_some());
+        match reused {
+            Some(buf) => {
+                inner.hits.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                inner.misses.fetch_add(1, Ordering::Relaxed);
+                T::with_capacity(class.map_or(len, |i| inner.classes[i]))
+            }
+        }
+    }
+
+    /// Keeps `buf` in the biggest class it can hold, if that class has room.
+    pub(crate) fn give_back(&self, mut buf: T) {
+        let inner = &*self.inner;
+        inner.outstanding.fetch_sub(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().pool_returned();
+        let Some(&largest) = inner.classes.last() else {
+            return;
+        };
+        if buf.capacity() > 2 * largest {
+            return;
+        }
+        for (i, &class) in inner.classes.iter().enumerate().rev() {
+            if buf.reclaim(class) {
+                let mut free = lock(&inner.free[i]);
+                if free.len() < inner.capacity {
+                    free.push(buf);
+                }
+                return;
+            }
+        }
+    }
+}
+
+fn lock<T>(m: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A buffer from a [`BufferPool`], back to it when dropped; or, made with
+/// [`Pooled::unpooled`], one of no pool's, freed when dropped.
+pub struct Pooled<T: Reusable = Vec<u8>> {
+    buf: Option<T>,
+    pool: Option<BufferPool<T>>,
+}
+
+impl<T: Reusable> Pooled<T> {
+    pub fn unpooled(buf: T) -> Self {
+        Pooled {
+            buf: Some(buf),
+            pool: None,
+        }
+    }
+
+    /// From `pool`, or freshly allocated without one.
+    pub fn new_in(pool: Option<&BufferPool<T>>, len: usize) -> Self {
+        match pool {
+            Some(pool) => pool.get(len),
+            None => Pooled::unpooled(T::with_capacity(len)),
+        }
+    }
+
+    /// The buffer, kept from going back to the pool.
+    pub fn into_inner(mut self) -> T {
+        let buf = self.buf.take().unwrap();
+        if let Some(pool) = &self.pool {
+            pool.inner.outstanding.fetch_sub(1, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            crate::metrics::global().pool_returned();
+        }
+        buf
+    }
+}
+
+impl<T: Reusable> Deref for Pooled<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.buf.as_ref().unwrap()
+    }
+}
+
+impl<T: Reusable> DerefMut for Pooled<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.buf.as_mut().unwrap()
+    }
+}
+
+impl<T: Reusable + fmt::Debug> fmt::Debug for Pooled<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.buf.as_ref().unwrap().fmt(f)
+    }
+}
+
+impl<T: Reusable> Drop for Pooled<T> {
+    fn drop(&mut self) {
+        if let (Some(buf), Some(pool)) = (self.buf.take(), &self.pool) {
+            pool.give_back(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn pools_and_guards_are_send_and_sync() {
+        assert_send_sync::<BufferPool>();
+        assert_send_sync::<BufferPool<BytesMut>>();
+        assert_send_sync::<Pooled>();
+        assert_send_sync::<Pooled<BytesMut>>();
+    }
+
+    #[test]
+    fn buffers_come_back_in_their_class() {
+        let pool: BufferPool = BufferPool::new().with_classes(&[64, 1024]);
+        let mut a = pool.get(10);
+        assert!(a.capacity() >= 64 && a.capacity() < 1024);
+        a.extend_from_slice(b"left over");
+        let at = a.as_ptr();
+        drop(a);
+        let b = pool.get(64);
+        assert!(b.is_empty());
+        assert_eq!(b.as_ptr(), at);
+        assert!(pool.get(1000).capacity() >= 1000);
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 1,
+                misses: 2,
+                outstanding: 1
+            }
+        );
+        drop(b);
+        assert_eq!(pool.stats().outstanding, 0);
+
+        // too big for any class: allocated to size, and not kept
+        let big = pool.get(5000);
+        assert!(big.capacity() >= 5000);
+        drop(big);
+        assert_eq!(pool.get(5000).capacity(), 5000);
+        assert_eq!(pool.stats().hits, 1);
+        assert_eq!(pool.stats().misses, 4);
+    }
+
+    #[test]
+    fn keeps_at_most_capacity_per_class() {
+        let pool: BufferPool = BufferPool::new().with_classes(&[64]).with_capacity(2);
+        let held: Vec<_> = (0..5).map(|_| pool.get(1)).collect();
+        drop(held);
+        let again: Vec<_> = (0..5).map(|_| pool.get(1)).collect();
+        assert_eq!(pool.stats().hits, 2);
+        drop(again);
+    }
+
+    #[test]
+    fn taken_buffers_leave_the_pool() {
+        let pool: BufferPool = BufferPool::new();
+        let mut v = pool.get(3).into_inner();
+        v.push(1);
+        assert_eq!(pool.stats().outstanding, 0);
+        let unpooled = Pooled::new_in(None::<&BufferPool>, 5);
+        assert!(unpooled.capacity() >= 5);
+        drop(unpooled);
+        assert_eq!(pool.stats().misses, 1);
+    }
+
+    #[test]
+    fn encode_pooled_matches_encode_and_reuses_buffer() {
+        let f = crate::transport::tests::frame(999);
+        let pool: BufferPool = BufferPool::new();
+        let first = f.encode_pooled(&pool);
+        assert_eq!(*first, f.encode());
+        let at = first.as_ptr();
+        drop(first);
+        let again = f.encode_pooled(&pool);
+        assert_eq!(*again, f.encode());
+        assert_eq!(again.as_ptr(), at);
+        assert_eq!(pool.stats().hits, 1);
+    }
+
+    #[test]
+    fn split_bytes_are_not_written_over() {
+        let pool: BufferPool<BytesMut> = BufferPool::new().with_classes(&[64]);
+        let mut a = pool.get(64);
+        let start = a.as_ptr();
+        a.extend_from_slice(&[1; 40]);
+        let frame = a.split_to(40).freeze();
+        drop(frame);
+        // Nothing else holds any of it: the whole of it comes back.
+        drop(a);
+        let mut b = pool.get(64);
+        assert_eq!(b.as_ptr(), start);
+        assert_eq!(pool.stats().hits, 1);
+
+        // Only 24 bytes are left next to a frame still held, too few for
+        // the class, so the buffer is freed rather than handed out again.
+        b.extend_from_slice(&[1; 40]);
+        let frame = b.split_to(40).freeze();
+        drop(b);
+        let mut c = pool.get(64);
+        c.extend_from_slice(&[2; 64]);
+        assert_eq!(frame, [1; 40][..]);
+        assert_eq!(pool.stats().hits, 1);
+    }
+}
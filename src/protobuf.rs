@@ -0,0 +1,138 @@
+//! Protobuf bodies (feature `protobuf`), for payloads described by a
+//! `.proto` file.
+//!
+//! [`FrameV1::from_protobuf_body`] encodes a [`prost::Message`] as a
+//! frame's body, and [`FrameV1::body_as_protobuf`] decodes it again, both
+//! within [`DEFAULT_MAX_PROTOBUF_BODY`]; a [`ProtobufConfig`] sets another
+//! limit. A message over the limit is refused before it is encoded, and a
+//! body over it before it is decoded. v1 frames have no content-type byte,
+//! so which bodies are protobuf is up to the application, as with CBOR.
+
+use prost::Message;
+
+use crate::{BodyError, Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+/// The largest body [`ProtobufConfig::new`] encodes or decodes.
+pub const DEFAULT_MAX_PROTOBUF_BODY: usize = 16 * 1024;
+
+/// How protobuf bodies are encoded and decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtobufConfig {
+    max_body: usize,
+}
+
+impl ProtobufConfig {
+    pub fn new() -> Self {
+        ProtobufConfig {
+            max_body: DEFAULT_MAX_PROTOBUF_BODY,
+        }
+    }
+
+    /// Refuse bodies over `max` bytes, either way.
+    pub fn with_max_body(mut self, max: usize) -> Self {
+        self.max_body = max;
+        self
+    }
+
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+
+    /// `message` in protobuf's wire format.
+    pub fn encode<M: Message>(&self, message: &M) -> Result<Vec<u8>, BodyError> {
+        self.check(message.encoded_len())?;
+        Ok(message.encode_to_vec())
+    }
+
+    /// The message `body` holds.
+    pub fn decode<M: Message + Default>(&self, body: &[u8]) -> Result<M, BodyError> {
+        self.check(body.len())?;
+        M::decode(body).map_err(BodyError::Protobuf)
+    }
+
+    fn check(&self, len: usize) -> Result<(), BodyError> {
+        if len > self.max_body {
+            return Err(BodyError::TooLarge {
+                len,
+                max: self.max_body,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for ProtobufConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameV1 {
+    /// A frame, without flags, whose body is `message` in protobuf's wire
+    /// format.
+    pub fn from_protobuf_body<M: Message>(
+        device_id: [u8; 8],
+        counter: u64,
+        msg_type: MsgType,
+        message: &M,
+    ) -> Result<FrameV1, BodyError> {
+        Ok(FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type,
+                flags: Flags::default(),
+                device_id,
+                counter,
+            },
+            body: ProtobufConfig::new().encode(message)?.into(),
+        })
+    }
+
+    /// The message the body holds in protobuf's wire format.
+    pub fn body_as_protobuf<M: Message + Default>(&self) -> Result<M, BodyError> {
+        ProtobufConfig::new().decode(&self.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_over_the_limit_are_refused() {
+        let text = "x".repeat(100);
+        let body = ProtobufConfig::new().encode(&text).unwrap();
+        let config = ProtobufConfig::new().with_max_body(8);
+        let too_large = BodyError::TooLarge {
+            len: body.len(),
+            max: 8,
+        };
+        assert_eq!(config.encode(&text), Err(too_large.clone()));
+        assert_eq!(config.decode::<String>(&body), Err(too_large));
+        assert_eq!(config.decode(&config.encode(&7u32).unwrap()), Ok(7u32));
+
+        let big = vec![0u8; DEFAULT_MAX_PROTOBUF_BODY];
+        let e = FrameV1::from_protobuf_body(*b"DEV00001", 1, MsgType::Event, &big).unwrap_err();
+        assert!(matches!(
+            e,
+            BodyError::TooLarge {
+                max: DEFAULT_MAX_PROTOBUF_BODY,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn truncated_bodies_wrap_the_prost_error() {
+        let frame =
+            FrameV1::from_protobuf_body(*b"DEV00001", 1, MsgType::Event, &"hello".to_string())
+                .unwrap();
+        let short = &frame.body[..frame.body.len() - 1];
+        let e = ProtobufConfig::new().decode::<String>(short).unwrap_err();
+        let BodyError::Protobuf(inner) = &e else {
+            panic!("{e:?}");
+        };
+        assert_eq!(e.to_string(), format!("cannot decode body: {inner}"));
+        assert!(std::error::Error::source(&e).is_some());
+    }
+}
@@ -0,0 +1,346 @@
+//! Python bindings (feature `python`), built by maturin into the
+//! `pipproto` extension module: `Frame`, `MsgType`, a streaming
+//! `Decoder`, and a `PipprotoError` subclass per [`DecodeError`] kind.
+//!
+//! Every error raised carries `kind` (as [`DecodeError::kind`]) and
+//! `offset` (the offending field's, or `None`).
+
+// pyo3's macros test a `gil-refs` feature of the crate using them, and
+// what they expand to predates the 2024 edition and this clippy
+#![allow(unexpected_cfgs, unsafe_op_in_unsafe_fn, clippy::useless_conversion)]
+
+use bytes::BytesMut;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyType};
+use tokio_util::codec::Decoder;
+
+use crate::codec::{DEFAULT_MAX_FRAME_LEN, FrameCodec};
+use crate::transport::{ProtocolError, TransportError};
+use crate::{DecodeError, Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+create_exception!(
+    pipproto,
+    PipprotoError,
+    PyException,
+    "Bytes that don't decode; `kind` and `offset` say why and where."
+);
+create_exception!(pipproto, TooShortError, PipprotoError);
+create_exception!(pipproto, BadMagicError, PipprotoError);
+create_exception!(pipproto, BadVersionError, PipprotoError);
+create_exception!(pipproto, UnknownMsgTypeError, PipprotoError);
+create_exception!(pipproto, ReservedFlagsError, PipprotoError);
+create_exception!(pipproto, BadDeviceIdBytesError, PipprotoError);
+create_exception!(pipproto, BadCounterBytesError, PipprotoError);
+create_exception!(
+    pipproto,
+    TruncatedError,
+    PipprotoError,
+    "A stream that ends inside a frame."
+);
+create_exception!(
+    pipproto,
+    FrameTooLargeError,
+    PipprotoError,
+    "A length prefix over the decoder's `max_frame_len`."
+);
+
+/// Bytes a [`PyDecoder`] asks its source for at a time.
+const READ_LEN: usize = 64 << 10;
+
+fn error(
+    py: Python<'_>,
+    new: fn(String) -> PyErr,
+    kind: &str,
+    offset: Option<usize>,
+    message: String,
+) -> PyErr {
+    let err = new(message);
+    let value = err.value_bound(py);
+    // setting attributes on a fresh exception can't fail
+    let _ = value.setattr("kind", kind);
+    let _ = value.setattr("offset", offset);
+    err
+}
+
+fn decode_error(py: Python<'_>, e: DecodeError) -> PyErr {
+    let new = match e {
+        DecodeError::TooShort => TooShortError::new_err::<String>,
+        DecodeError::BadMagic => BadMagicError::new_err,
+        DecodeError::BadVersion(_) => BadVersionError::new_err,
+        DecodeError::UnknownMsgType(_) => UnknownMsgTypeError::new_err,
+        DecodeError::ReservedFlags(_) => ReservedFlagsError::new_err,
+        DecodeError::BadDeviceIdBytes => BadDeviceIdBytesError::new_err,
+        DecodeError::BadCounterBytes => BadCounterBytesError::new_err,
+    };
+    error(py, new, e.kind(), e.offset(), e.to_string())
+}
+
+fn stream_error(py: Python<'_>, e: TransportError) -> PyErr {
+    match e {
+        TransportError::Protocol(ProtocolError::Decode(e)) => decode_error(py, e),
+        TransportError::Protocol(ProtocolError::Truncated) => error(
+            py,
+            TruncatedError::new_err,
+            "truncated",
+            None,
+            "stream ends inside a frame".to_string(),
+        ),
+        TransportError::Protocol(ProtocolError::TooLarge(n)) => error(
+            py,
+            FrameTooLargeError::new_err,
+            "too_large",
+            None,
+            format!("frame too large: {n} bytes"),
+        ),
+        e => PipprotoError::new_err(e.to_string()),
+    }
+}
+
+#[pyclass(name = "MsgType", module = "pipproto", eq, eq_int)]
+#[derive(Clone, Copy, PartialEq)]
+enum PyMsgType {
+    #[pyo3(name = "EVENT")]
+    Event = 0x01,
+    #[pyo3(name = "COMMAND")]
+    Command = 0x02,
+    #[pyo3(name = "ACK")]
+    Ack = 0x03,
+    #[pyo3(name = "ERROR")]
+    Error = 0x04,
+}
+
+impl From<MsgType> for PyMsgType {
+    fn from(t: MsgType) -> Self {
+        match t {
+            MsgType::Event => PyMsgType::Event,
+            MsgType::Command => PyMsgType::Command,
+            MsgType::Ack => PyMsgType::Ack,
+            MsgType::Error => PyMsgType::Error,
+        }
+    }
+}
+
+/// A `MsgType`, or its wire value.
+fn msg_type(py: Python<'_>, obj: &Bound<'_, PyAny>) -> PyResult<MsgType> {
+    let raw = match obj.extract::<PyMsgType>() {
+        Ok(t) => t as u8,
+        Err(_) => obj.extract::<u8>()?,
+    };
+    MsgType::from_u8(raw).ok_or_else(|| decode_error(py, DecodeError::UnknownMsgType(raw)))
+}
+
+/// A v1 frame.
+#[pyclass(name = "Frame", module = "pipproto", eq)]
+#[derive(Clone, PartialEq)]
+struct PyFrame(FrameV1);
+
+#[pymethods]
+impl PyFrame {
+    #[new]
+    #[pyo3(signature = (msg_type, device_id, counter, body = b"".as_slice(), flags = 0, version = VERSION_V1))]
+    fn new(
+        py: Python<'_>,
+        msg_type: &Bound<'_, PyAny>,
+        device_id: &[u8],
+        counter: u64,
+        body: &[u8],
+        flags: u8,
+        version: u8,
+    ) -> PyResult<Self> {
+        if version != VERSION_V1 {
+            return Err(decode_error(py, DecodeError::BadVersion(version)));
+        }
+        let device_id = device_id
+            .try_into()
+            .map_err(|_| PyValueError::new_err("device_id must be 8 bytes"))?;
+        Ok(PyFrame(FrameV1 {
+            header: FrameHeaderV1 {
+                version,
+                msg_type: self::msg_type(py, msg_type)?,
+                flags: Flags::new(flags).map_err(|e| decode_error(py, e))?,
+                device_id,
+                counter,
+            },
+            body: body.into(),
+        }))
+    }
+
+    /// Decode one frame, without a length prefix.
+    #[classmethod]
+    fn decode(_cls: &Bound<'_, PyType>, py: Python<'_>, data: &[u8]) -> PyResult<Self> {
+        FrameV1::decode(data)
+            .map(PyFrame)
+            .map_err(|e| decode_error(py, e))
+    }
+
+    fn encode<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.0.encode())
+    }
+
+    #[getter]
+    fn version(&self) -> u8 {
+        self.0.header.version
+    }
+
+    #[getter]
+    fn msg_type(&self) -> PyMsgType {
+        self.0.header.msg_type.into()
+    }
+
+    #[getter]
+    fn flags(&self) -> u8 {
+        self.0.header.flags.bits()
+    }
+
+    #[getter]
+    fn ack_required(&self) -> bool {
+        self.0.header.flags.ack_required()
+    }
+
+    #[getter]
+    fn device_id<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.0.header.device_id)
+    }
+
+    #[getter]
+    fn counter(&self) -> u64 {
+        self.0.header.counter
+    }
+
+    #[getter]
+    fn body<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.0.body)
+    }
+
+    fn __repr__(&self) -> String {
+        let h = &self.0.header;
+        format!(
+            "Frame(msg_type=MsgType.{}, device_id=bytes.fromhex('{}'), counter={}, \
+             body=<{} bytes>, flags={})",
+            h.msg_type.as_str().to_uppercase(),
+            h.device_id_hex(),
+            h.counter,
+            self.0.body.len(),
+            h.flags.bits(),
+        )
+    }
+}
+
+/// Length-prefixed frames out of a stream: fed with `feed`, or read from
+/// a binary file-like `source`, and iterated over.
+///
+/// A frame that doesn't decode raises its error and is skipped, so
+/// iterating again carries on; after a `FrameTooLargeError` the stream
+/// is out of step and the decoder should be dropped.
+#[pyclass(name = "Decoder", module = "pipproto")]
+struct PyDecoder {
+    codec: FrameCodec,
+    buf: BytesMut,
+    source: Option<PyObject>,
+    position: u64,
+}
+
+#[pymethods]
+impl PyDecoder {
+    #[new]
+    #[pyo3(signature = (source = None, max_frame_len = DEFAULT_MAX_FRAME_LEN))]
+    fn new(source: Option<PyObject>, max_frame_len: usize) -> Self {
+        PyDecoder {
+            codec: FrameCodec::new(max_frame_len),
+            buf: BytesMut::new(),
+            source,
+            position: 0,
+        }
+    }
+
+    /// Add bytes read from the stream.
+    fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Raise `TruncatedError` if the stream ended inside a frame.
+    fn finish(&mut self, py: Python<'_>) -> PyResult<()> {
+        if !self.buf.is_empty() {
+            return Err(stream_error(py, ProtocolError::Truncated.into()));
+        }
+        Ok(())
+    }
+
+    /// Stream offset of the next frame: the bytes decoded or skipped.
+    #[getter]
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Bytes fed or read but not yet decoded.
+    #[getter]
+    fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<PyFrame>> {
+        loop {
+            let before = self.buf.len();
+            let item = self.codec.decode(&mut self.buf);
+            self.position += (before - self.buf.len()) as u64;
+            match item.map_err(|e| stream_error(py, e))? {
+                Some(Ok(frame)) => return Ok(Some(PyFrame(frame))),
+                Some(Err(e)) => return Err(decode_error(py, e)),
+                None => {}
+            }
+            let Some(source) = &self.source else {
+                return Ok(None);
+            };
+            let chunk = source.bind(py).call_method1("read", (READ_LEN,))?;
+            let chunk = chunk.downcast::<PyBytes>()?.as_bytes();
+            if chunk.is_empty() {
+                self.source = None;
+                self.finish(py)?;
+                return Ok(None);
+            }
+            self.buf.extend_from_slice(chunk);
+        }
+    }
+}
+
+#[pymodule]
+fn pipproto(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_class::<PyFrame>()?;
+    m.add_class::<PyMsgType>()?;
+    m.add_class::<PyDecoder>()?;
+    m.add("HEADER_LEN", crate::HEADER_LEN_V1)?;
+    m.add("DEFAULT_MAX_FRAME_LEN", DEFAULT_MAX_FRAME_LEN)?;
+    m.add("PipprotoError", py.get_type_bound::<PipprotoError>())?;
+    m.add("TooShortError", py.get_type_bound::<TooShortError>())?;
+    m.add("BadMagicError", py.get_type_bound::<BadMagicError>())?;
+    m.add("BadVersionError", py.get_type_bound::<BadVersionError>())?;
+    m.add(
+        "UnknownMsgTypeError",
+        py.get_type_bound::<UnknownMsgTypeError>(),
+    )?;
+    m.add(
+        "ReservedFlagsError",
+        py.get_type_bound::<ReservedFlagsError>(),
+    )?;
+    m.add(
+        "BadDeviceIdBytesError",
+        py.get_type_bound::<BadDeviceIdBytesError>(),
+    )?;
+    m.add(
+        "BadCounterBytesError",
+        py.get_type_bound::<BadCounterBytesError>(),
+    )?;
+    m.add("TruncatedError", py.get_type_bound::<TruncatedError>())?;
+    m.add(
+        "FrameTooLargeError",
+        py.get_type_bound::<FrameTooLargeError>(),
+    )?;
+    Ok(())
+}
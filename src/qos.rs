@@ -0,0 +1,299 @@
+//! Delivery guarantees chosen per frame.
+//!
+//! [`Qos`] names what a frame needs, and a [`QosSender`] sets it up:
+//!
+//! - [`Qos::AtMostOnce`]: sent once without ACK_REQUIRED; lost if the link
+//!   loses it.
+//! - [`Qos::AtLeastOnce`]: sent with ACK_REQUIRED and resent by an
+//!   [`AckTracker`] until acked or given up on, so it may arrive more than
+//!   once.
+//! - [`Qos::ExactlyOnce`]: the same, and the receiver must drop the copies.
+//!   A resend is the frame as first sent, so its [`FrameId`] is the
+//!   idempotency key; a [`Dispatcher`] with a dedup cache drops repeats of
+//!   it while still acking them. [`Dispatcher::check_qos`] says whether a
+//!   dispatcher can keep the promise.
+//!
+//! Nothing on the wire tells the levels apart beyond ACK_REQUIRED, so the
+//! receiving side has to be set up for the most demanding level sent to
+//! it.
+//!
+//! [`Dispatcher`]: crate::dispatch::Dispatcher
+//! [`Dispatcher::check_qos`]: crate::dispatch::Dispatcher::check_qos
+
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::ack::{AckError, AckTracker, Outstanding};
+use crate::sender::{CounterMap, CounterSource, FrameSender, SendError, stamp};
+use crate::transport::{Transport, TransportError};
+use crate::window::Priority;
+use crate::{Flags, FrameId, FrameV1, MsgType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Qos {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl Qos {
+    pub fn flags(self) -> Flags {
+        match self {
+            Qos::AtMostOnce => Flags::new(0).unwrap(),
+            Qos::AtLeastOnce | Qos::ExactlyOnce => Flags::new(Flags::ACK_REQUIRED).unwrap(),
+        }
+    }
+
+    /// Whether the receiver has to drop repeats.
+    pub fn needs_dedup(self) -> bool {
+        self == Qos::ExactlyOnce
+    }
+}
+
+#[derive(Debug)]
+pub enum QosError {
+    Send(SendError<TransportError>),
+    /// The frame went out once, but the tracker would not take it, so it
+    /// will not be resent.
+    Untracked {
+        id: FrameId,
+        error: AckError,
+    },
+    /// The receiver has no dedup cache to deliver exactly once with.
+    NoDedup,
+}
+
+impl fmt::Display for QosError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QosError::Send(e) => write!(f, "{e}"),
+            QosError::Untracked { error, .. } => write!(f, "sent once, not resent: {error}"),
+            QosError::NoDedup => write!(f, "exactly-once delivery needs a dedup cache"),
+        }
+    }
+}
+
+impl std::error::Error for QosError {}
+
+/// A [`FrameSender`] that resends frames as their [`Qos`] asks. Feed it
+/// the acks received with [`QosSender::on_ack`] and call
+/// [`QosSender::resend_due`] at least by [`QosSender::next_deadline`].
+pub struct QosSender<T, C = CounterMap> {
+    sender: FrameSender<T, C>,
+    tracker: Mutex<AckTracker>,
+}
+
+impl<T: Transport, C: CounterSource> QosSender<T, C> {
+    pub fn new(sender: FrameSender<T, C>, tracker: AckTracker) -> Self {
+        QosSender {
+            sender,
+            tracker: Mutex::new(tracker),
+        }
+    }
+
+    fn tracker(&self) -> MutexGuard<'_, AckTracker> {
+        self.tracker.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Send a frame with `qos`. One that needs acking is tracked before it
+    /// goes out, so a failed first send is retried like a lost one.
+    pub async fn send(
+        &self,
+        qos: Qos,
+        msg_type: MsgType,
+        device_id: [u8; 8],
+        body: Vec<u8>,
+    ) -> Result<FrameId, QosError> {
+        let flags = qos.flags();
+        if qos == Qos::AtMostOnce {
+            return self
+                .sender
+                .send_raw(msg_type, flags, device_id, body)
+                .await
+                .map_err(QosError::Send);
+        }
+        let copy = body.clone();
+        let mut tracked = Ok(());
+        let id = self
+            .sender
+            .send_numbered(Priority::Normal, msg_type, flags, device_id, body, |id| {
+                let frame = stamp(msg_type, flags, id.device_id, id.counter, copy);
+                tracked = self.tracker().register(&frame);
+            })
+            .await
+            .map_err(QosError::Send)?;
+        tracked.map_err(|error| QosError::Untracked { id, error })?;
+        Ok(id)
+    }
+
+    /// Resolve the frames `ack` acknowledges; see [`AckTracker::on_ack`].
+    pub fn on_ack(&self, ack: &FrameV1) -> Vec<Outstanding> {
+        self.tracker().on_ack(ack)
+    }
+
+    /// Resend what is due at `now`. Returns the frames given up on.
+    pub async fn resend_due(
+        &self,
+        now: Instant,
+    ) -> Result<Vec<Outstanding>, SendError<TransportError>> {
+        let due = self.tracker().poll(now);
+        for frame in &due.retransmit {
+            self.sender.resend(frame).await?;
+        }
+        Ok(due.expired)
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.tracker().next_deadline()
+    }
+
+    /// Frames waiting for their ack.
+    pub fn pending(&self) -> usize {
+        self.tracker().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ack::AckConfig;
+    use crate::clock::{Clock, ManualClock};
+    use crate::dedup::{DedupCache, DedupConfig, SharedDedupCache};
+    use crate::dispatch::{Dispatcher, Route};
+    use crate::transport::{Fault, FaultyTransport, LoopbackTransport, RxFrame, loopback_pair};
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    const DEV: [u8; 8] = *b"DEV00001";
+
+    type Link = FaultyTransport<LoopbackTransport>;
+
+    /// Faults taken in turn, then delivery.
+    fn faulty(link: LoopbackTransport, faults: &[Fault]) -> Link {
+        let mut faults: VecDeque<Fault> = faults.iter().copied().collect();
+        FaultyTransport::new(link, move |_: &FrameV1| {
+            faults.pop_front().unwrap_or(Fault::Deliver)
+        })
+    }
+
+    struct Rig {
+        clock: ManualClock,
+        sender: QosSender<Link>,
+        device: LoopbackTransport,
+        dispatcher: Dispatcher,
+        handled: Arc<AtomicU32>,
+        acks_tx: Link,
+        acks_rx: LoopbackTransport,
+    }
+
+    fn rig(dedup: bool, out: &[Fault], back: &[Fault]) -> Rig {
+        let clock = ManualClock::new();
+        let (host, device) = loopback_pair();
+        let (acks_tx, acks_rx) = loopback_pair();
+        let config = AckConfig {
+            timeout: Duration::from_millis(100),
+            ..AckConfig::default()
+        };
+        let tracker = AckTracker::new(config, Arc::new(clock.clone()));
+        let sender = FrameSender::new(faulty(host, out), Arc::new(CounterMap::default()));
+        let handled = Arc::new(AtomicU32::new(0));
+        let mut dispatcher = Dispatcher::new().route(Route::new(MsgType::Command), {
+            let handled = handled.clone();
+            move |_: &RxFrame| {
+                handled.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+        });
+        if dedup {
+            let cache = DedupCache::new(DedupConfig::default());
+            dispatcher = dispatcher.with_dedup(SharedDedupCache::new(cache));
+        }
+        Rig {
+            clock,
+            sender: QosSender::new(sender, tracker),
+            device,
+            dispatcher,
+            handled,
+            acks_tx: faulty(acks_tx, back),
+            acks_rx,
+        }
+    }
+
+    async fn next(link: &mut LoopbackTransport) -> Option<RxFrame> {
+        tokio::time::timeout(Duration::from_millis(20), link.recv_rx())
+            .await
+            .ok()
+            .map(Result::unwrap)
+    }
+
+    impl Rig {
+        /// Deliver what reached the device and its acks what reached the
+        /// host, then move on to the next resend.
+        async fn exchange(&mut self) {
+            while let Some(frame) = next(&mut self.device).await {
+                if let Some(ack) = self.dispatcher.dispatch(frame).await {
+                    self.acks_tx.send(&ack).await.unwrap();
+                }
+            }
+            while let Some(ack) = next(&mut self.acks_rx).await {
+                self.sender.on_ack(&ack);
+            }
+            if let Some(deadline) = self.sender.next_deadline() {
+                self.clock.advance(deadline - self.clock.now());
+                self.sender.resend_due(self.clock.now()).await.unwrap();
+            }
+        }
+
+        async fn deliver(&mut self, qos: Qos) -> u32 {
+            self.sender
+                .send(qos, MsgType::Command, DEV, vec![1])
+                .await
+                .unwrap();
+            for _ in 0..4 {
+                self.exchange().await;
+            }
+            assert_eq!(self.sender.pending(), 0);
+            self.handled.load(Ordering::Relaxed)
+        }
+    }
+
+    #[tokio::test]
+    async fn at_most_once_is_never_resent() {
+        let mut lossy = rig(false, &[Fault::Drop], &[]);
+        assert_eq!(lossy.deliver(Qos::AtMostOnce).await, 0);
+        let mut clean = rig(false, &[], &[]);
+        let id = clean
+            .sender
+            .send(Qos::AtMostOnce, MsgType::Command, DEV, vec![1])
+            .await
+            .unwrap();
+        let got = next(&mut clean.device).await.unwrap();
+        assert_eq!(got.header.id(), id);
+        assert!(!got.header.flags.ack_required());
+    }
+
+    #[tokio::test]
+    async fn at_least_once_survives_loss_but_may_repeat() {
+        let mut rig = rig(false, &[Fault::Drop, Fault::Duplicate], &[]);
+        assert_eq!(rig.deliver(Qos::AtLeastOnce).await, 2);
+    }
+
+    #[tokio::test]
+    async fn exactly_once_needs_and_uses_the_dedup_cache() {
+        let without = rig(false, &[], &[]);
+        assert!(matches!(
+            without.dispatcher.check_qos(Qos::ExactlyOnce),
+            Err(QosError::NoDedup)
+        ));
+        assert!(without.dispatcher.check_qos(Qos::AtLeastOnce).is_ok());
+
+        // the first send and both acks of the duplicated resend are lost
+        let out = [Fault::Drop, Fault::Duplicate];
+        let mut rig = rig(true, &out, &[Fault::Drop, Fault::Drop]);
+        rig.dispatcher.check_qos(Qos::ExactlyOnce).unwrap();
+        assert_eq!(rig.deliver(Qos::ExactlyOnce).await, 1);
+    }
+}
@@ -0,0 +1,684 @@
+//! Outbound queueing by priority.
+//!
+//! A [`PriorityQueue`] holds frames waiting to be sent in one band per
+//! [`Priority`]. Urgent frames go first, except that after
+//! `starvation_limit` urgent frames in a row a waiting normal frame gets
+//! its turn. Within a band, devices take turns, one frame each, so a
+//! device with thousands of frames waiting sends no more often than one
+//! with three. The queue holds `capacity` frames in all and `per_device`
+//! for any one device; what a full queue, or a device at its limit, does
+//! with one more is up to the band's [`DropPolicy`], applied to that
+//! device's frames alone in the second case. [`PriorityQueue::device_stats`]
+//! counts a device's frames waiting, sent and dropped for as long as it has
+//! any waiting; once its last one leaves it is forgotten, so the queue keeps
+//! nothing for devices that come and go. [`PriorityQueue::stats`] counts
+//! every frame, by band.
+//!
+//! Frames are queued unnumbered and only get their counter as they leave:
+//! numbering at enqueue would let an urgent frame overtake an older,
+//! lower-numbered one from the same device, which the receiver would then
+//! reject as a replay. [`QueuedSender`] does the numbering, through a
+//! [`FrameSender`].
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use tokio::sync::Notify;
+
+use crate::sender::{CounterMap, CounterSource, FrameSender, SendError};
+use crate::transport::{Transport, TransportError};
+use crate::window::Priority;
+use crate::{Flags, MsgType};
+
+/// A frame yet to be numbered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outgoing {
+    pub msg_type: MsgType,
+    pub flags: Flags,
+    pub device_id: [u8; 8],
+    pub body: Vec<u8>,
+}
+
+/// What a full queue does with a frame for this band.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    RejectNew,
+    /// Drop the band's oldest frame.
+    DropOldest,
+    /// Drop the newest frame of a lower band, or reject if there is none.
+    DisplaceLower,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    /// Frames across all bands.
+    pub capacity: usize,
+    /// Frames across all bands for one device; `None` for no limit but
+    /// `capacity`.
+    pub per_device: Option<usize>,
+    /// Urgent frames in a row before a waiting normal frame goes;
+    /// `None` for strict priority.
+    pub starvation_limit: Option<u32>,
+    pub normal_when_full: DropPolicy,
+    pub urgent_when_full: DropPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            capacity: 1024,
+            per_device: Some(256),
+            starvation_limit: Some(8),
+            normal_when_full: DropPolicy::RejectNew,
+            urgent_when_full: DropPolicy::DisplaceLower,
+        }
+    }
+}
+
+/// The frame a full queue turned away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueFull(pub Outgoing);
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "outbound queue full")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandStats {
+    pub enqueued: u64,
+    pub dequeued: u64,
+    /// Rejected, or dropped to make room.
+    pub dropped: u64,
+}
+
+/// One device's frames, across bands, since it last had none waiting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    /// Waiting now.
+    pub queued: usize,
+    pub dequeued: u64,
+    /// Rejected, or dropped to make room.
+    pub dropped: u64,
+}
+
+#[derive(Default)]
+struct Band {
+    /// Per device, each frame with its arrival number.
+    frames: HashMap<[u8; 8], VecDeque<(u64, Outgoing)>>,
+    /// Devices with frames, next to send first.
+    turns: VecDeque<[u8; 8]>,
+    len: usize,
+    stats: BandStats,
+}
+
+impl Band {
+    fn push(&mut self, seq: u64, frame: Outgoing) {
+        let queue = self.frames.entry(frame.device_id).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(frame.device_id);
+        }
+        queue.push_back((seq, frame));
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Outgoing> {
+        let device = self.turns.pop_front()?;
+        let queue = self.frames.get_mut(&device)?;
+        let (_, frame) = queue.pop_front()?;
+        if queue.is_empty() {
+            self.frames.remove(&device);
+        } else {
+            self.turns.push_back(device);
+        }
+        self.len -= 1;
+        Some(frame)
+    }
+
+    /// Remove the oldest frame, or the newest.
+    fn remove_by_age(&mut self, oldest: bool) -> Option<Outgoing> {
+        let (&device, _) = self.frames.iter().min_by_key(|(_, q)| {
+            let seq = if oldest { q.front() } else { q.back() }.map_or(0, |(s, _)| *s);
+            if oldest { seq } else { u64::MAX - seq }
+        })?;
+        self.remove(device, oldest)
+    }
+
+    /// Remove `device`'s oldest frame, or its newest.
+    fn remove(&mut self, device: [u8; 8], oldest: bool) -> Option<Outgoing> {
+        let queue = self.frames.get_mut(&device)?;
+        let (_, frame) = if oldest {
+            queue.pop_front()
+        } else {
+            queue.pop_back()
+        }?;
+        if queue.is_empty() {
+            self.frames.remove(&device);
+            self.turns.retain(|d| *d != device);
+        }
+        self.len -= 1;
+        Some(frame)
+    }
+}
+
+pub struct PriorityQueue {
+    config: QueueConfig,
+    /// Indexed by `Priority as usize`.
+    bands: [Band; 2],
+    next_seq: u64,
+    /// Urgent frames popped in a row while normal ones waited.
+    urgent_run: u32,
+    /// Devices with frames waiting.
+    devices: HashMap<[u8; 8], DeviceStats>,
+}
+
+fn band_index(p: Priority) -> usize {
+    p as usize
+}
+
+impl PriorityQueue {
+    pub fn new(config: QueueConfig) -> Self {
+        PriorityQueue {
+            config: QueueConfig {
+                capacity: config.capacity.max(1),
+                ..config
+            },
+            bands: Default::default(),
+            next_seq: 0,
+            urgent_run: 0,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Queue `frame`. Returns a frame dropped to make room, if any.
+    pub fn push(
+        &mut self,
+        priority: Priority,
+        frame: Outgoing,
+    ) -> Result<Option<Outgoing>, QueueFull> {
+        let device = frame.device_id;
+        let mut dropped = None;
+        let device_full = self
+            .config
+            .per_device
+            .is_some_and(|n| self.device_stats(device).queued >= n);
+        if device_full || self.len() >= self.config.capacity {
+            let Some((from, victim)) = self.make_room(priority, device_full.then_some(device))
+            else {
+                self.count_drop(priority, device);
+                return Err(QueueFull(frame));
+            };
+            self.count_drop(from, victim.device_id);
+            self.device(victim.device_id).queued -= 1;
+            #[cfg(feature = "metrics")]
+            crate::metrics::global().queue_device_depth(victim.device_id, -1);
+            dropped = Some(victim);
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let band = self.band(priority);
+        band.push(seq, frame);
+        band.stats.enqueued += 1;
+        self.device(device).queued += 1;
+        if let Some(victim) = &dropped {
+            self.forget_if_idle(victim.device_id);
+        }
+        #[cfg(feature = "metrics")]
+        {
+            let m = crate::metrics::global();
+            m.queue_enqueued(priority);
+            m.queue_device_depth(device, 1);
+        }
+        Ok(dropped)
+    }
+
+    /// Take out a frame by `priority`'s policy, from `device`'s frames
+    /// only if given.
+    fn make_room(
+        &mut self,
+        priority: Priority,
+        device: Option<[u8; 8]>,
+    ) -> Option<(Priority, Outgoing)> {
+        let policy = match priority {
+            Priority::Normal => self.config.normal_when_full,
+            Priority::Urgent => self.config.urgent_when_full,
+        };
+        let remove = |band: &mut Band, oldest: bool| match device {
+            Some(device) => band.remove(device, oldest),
+            None => band.remove_by_age(oldest),
+        };
+        match policy {
+            DropPolicy::RejectNew => None,
+            DropPolicy::DropOldest => remove(self.band(priority), true).map(|f| (priority, f)),
+            DropPolicy::DisplaceLower => [Priority::Normal, Priority::Urgent]
+                .into_iter()
+                .filter(|&p| p < priority)
+                .find_map(|p| remove(self.band(p), false).map(|f| (p, f))),
+        }
+    }
+
+    fn band(&mut self, priority: Priority) -> &mut Band {
+        &mut self.bands[band_index(priority)]
+    }
+
+    fn device(&mut self, device: [u8; 8]) -> &mut DeviceStats {
+        self.devices.entry(device).or_default()
+    }
+
+    fn forget_if_idle(&mut self, device: [u8; 8]) {
+        if self.devices.get(&device).is_some_and(|d| d.queued == 0) {
+            self.devices.remove(&device);
+        }
+    }
+
+    /// A frame turned away from a device with none waiting only counts
+    /// for the band.
+    fn count_drop(&mut self, priority: Priority, device: [u8; 8]) {
+        self.band(priority).stats.dropped += 1;
+        if let Some(device) = self.devices.get_mut(&device) {
+            device.dropped += 1;
+        }
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().queue_dropped(priority);
+    }
+
+    /// The next frame to send.
+    pub fn pop(&mut self) -> Option<(Priority, Outgoing)> {
+        let normal_waiting = self.len_of(Priority::Normal) > 0;
+        let starved = self
+            .config
+            .starvation_limit
+            .is_some_and(|n| self.urgent_run >= n);
+        let priority = if self.len_of(Priority::Urgent) > 0 && !(starved && normal_waiting) {
+            Priority::Urgent
+        } else {
+            Priority::Normal
+        };
+        let frame = self.band(priority).pop()?;
+        self.urgent_run = match priority {
+            Priority::Urgent if normal_waiting => self.urgent_run + 1,
+            _ => 0,
+        };
+        self.band(priority).stats.dequeued += 1;
+        let device = self.device(frame.device_id);
+        device.queued -= 1;
+        device.dequeued += 1;
+        self.forget_if_idle(frame.device_id);
+        #[cfg(feature = "metrics")]
+        {
+            let m = crate::metrics::global();
+            m.queue_dequeued(priority);
+            m.queue_device_dequeued(frame.device_id);
+        }
+        Some((priority, frame))
+    }
+
+    pub fn len(&self) -> usize {
+        self.bands.iter().map(|b| b.len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len_of(&self, priority: Priority) -> usize {
+        self.bands[band_index(priority)].len
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.config.capacity
+    }
+
+    pub fn stats(&self, priority: Priority) -> BandStats {
+        self.bands[band_index(priority)].stats
+    }
+
+    /// Zero for a device with no frames waiting.
+    pub fn device_stats(&self, device: [u8; 8]) -> DeviceStats {
+        self.devices.get(&device).copied().unwrap_or_default()
+    }
+}
+
+struct Shared {
+    queue: Mutex<PriorityQueue>,
+    /// Wakes [`QueuedSender::run`] when a frame is queued.
+    queued: Notify,
+    /// Wakers of callers waiting for room.
+    room: Mutex<Vec<Waker>>,
+}
+
+/// A [`FrameSender`] fed through a [`PriorityQueue`]. Clones share the
+/// queue; one of them must be driving [`QueuedSender::run`].
+pub struct QueuedSender<T, C = CounterMap> {
+    sender: FrameSender<T, C>,
+    shared: Arc<Shared>,
+}
+
+impl<T, C> Clone for QueuedSender<T, C> {
+    fn clone(&self) -> Self {
+        QueuedSender {
+            sender: self.sender.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: Transport, C: CounterSource> QueuedSender<T, C> {
+    pub fn new(sender: FrameSender<T, C>, config: QueueConfig) -> Self {
+        QueuedSender {
+            sender,
+            shared: Arc::new(Shared {
+                queue: Mutex::new(PriorityQueue::new(config)),
+                queued: Notify::new(),
+                room: Mutex::default(),
+            }),
+        }
+    }
+
+    fn queue(&self) -> MutexGuard<'_, PriorityQueue> {
+        self.shared.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Ready while the queue has room; otherwise woken when a frame leaves.
+    pub fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let queue = self.queue();
+        if !queue.is_full() {
+            return Poll::Ready(());
+        }
+        let mut room = self.shared.room.lock().unwrap_or_else(|e| e.into_inner());
+        if !room.iter().any(|w| w.will_wake(cx.waker())) {
+            room.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    /// Queue a frame now, whether or not there is room; the queue's
+    /// [`DropPolicy`] decides. Returns a frame dropped to make room.
+    pub fn try_send(
+        &self,
+        priority: Priority,
+        frame: Outgoing,
+    ) -> Result<Option<Outgoing>, QueueFull> {
+        let dropped = self.queue().push(priority, frame)?;
+        self.shared.queued.notify_one();
+        Ok(dropped)
+    }
+
+    /// Wait for room, then queue a frame.
+    pub async fn send(&self, priority: Priority, frame: Outgoing) -> Result<(), QueueFull> {
+        poll_fn(|cx| self.poll_ready(cx)).await;
+        self.try_send(priority, frame).map(|_| ())
+    }
+
+    /// Send queued frames, in queue order, until a send fails. The frame
+    /// that failed is lost. Frames the sender's authorizer denies are
+    /// dropped without stopping.
+    pub async fn run(&self) -> SendError<TransportError> {
+        loop {
+            let next = self.queue().pop();
+            let Some((priority, f)) = next else {
+                self.shared.queued.notified().await;
+                continue;
+            };
+            for waker in self
+                .shared
+                .room
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .drain(..)
+            {
+                waker.wake();
+            }
+            let sent = self
+                .sender
+                .send_numbered(priority, f.msg_type, f.flags, f.device_id, f.body, |_| {})
+                .await;
+            match sent {
+                // dropped, like a frame the full queue rejects
+                Ok(_) | Err(SendError::Unauthorized(_) | SendError::CircuitOpen(_)) => {}
+                Err(e) => return e,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue().is_empty()
+    }
+
+    pub fn stats(&self, priority: Priority) -> BandStats {
+        self.queue().stats(priority)
+    }
+
+    pub fn device_stats(&self, device: [u8; 8]) -> DeviceStats {
+        self.queue().device_stats(device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::loopback_pair;
+    use futures::FutureExt;
+
+    fn out(device: u8, tag: u8) -> Outgoing {
+        Outgoing {
+            msg_type: MsgType::Event,
+            flags: Flags::new(0).unwrap(),
+            device_id: [b'D', b'E', b'V', 0, 0, 0, 0, device],
+            body: vec![tag],
+        }
+    }
+
+    fn queue(capacity: usize, starvation_limit: Option<u32>) -> PriorityQueue {
+        PriorityQueue::new(QueueConfig {
+            capacity,
+            per_device: None,
+            starvation_limit,
+            ..QueueConfig::default()
+        })
+    }
+
+    fn drain(q: &mut PriorityQueue) -> Vec<u8> {
+        std::iter::from_fn(|| q.pop())
+            .map(|(_, f)| f.body[0])
+            .collect()
+    }
+
+    #[test]
+    fn urgent_first_and_devices_take_turns() {
+        let mut q = queue(16, None);
+        for (device, tag) in [(1, 10), (1, 11), (1, 12), (2, 20), (3, 30)] {
+            q.push(Priority::Normal, out(device, tag)).unwrap();
+        }
+        q.push(Priority::Urgent, out(2, 99)).unwrap();
+        assert_eq!(drain(&mut q), [99, 10, 20, 30, 11, 12]);
+        assert_eq!(q.stats(Priority::Normal).dequeued, 5);
+    }
+
+    #[test]
+    fn sustained_urgent_load_does_not_starve_normal_frames() {
+        let mut q = queue(1024, Some(4));
+        for tag in 0..3 {
+            q.push(Priority::Normal, out(1, tag)).unwrap();
+        }
+        for _ in 0..100 {
+            q.push(Priority::Urgent, out(2, 0xff)).unwrap();
+        }
+        let order = drain(&mut q);
+        let normal: Vec<_> = order
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| **t != 0xff)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(normal, [4, 9, 14]);
+
+        // strict priority sends every urgent frame first
+        let mut strict = queue(1024, None);
+        strict.push(Priority::Normal, out(1, 0)).unwrap();
+        for _ in 0..100 {
+            strict.push(Priority::Urgent, out(2, 0xff)).unwrap();
+        }
+        assert_eq!(drain(&mut strict).last(), Some(&0));
+    }
+
+    #[test]
+    fn full_queue_follows_each_band_policy() {
+        let mut q = PriorityQueue::new(QueueConfig {
+            capacity: 3,
+            per_device: None,
+            starvation_limit: None,
+            normal_when_full: DropPolicy::DropOldest,
+            urgent_when_full: DropPolicy::DisplaceLower,
+        });
+        q.push(Priority::Normal, out(1, 1)).unwrap();
+        q.push(Priority::Normal, out(2, 2)).unwrap();
+        q.push(Priority::Urgent, out(1, 3)).unwrap();
+        assert_eq!(q.push(Priority::Normal, out(1, 4)), Ok(Some(out(1, 1))));
+        // the newest normal frame makes way for an urgent one
+        assert_eq!(q.push(Priority::Urgent, out(1, 5)), Ok(Some(out(1, 4))));
+        assert_eq!(q.push(Priority::Urgent, out(1, 6)), Ok(Some(out(2, 2))));
+        assert_eq!(
+            q.push(Priority::Urgent, out(1, 7)),
+            Err(QueueFull(out(1, 7)))
+        );
+        assert_eq!(q.stats(Priority::Normal).dropped, 3);
+        assert_eq!(q.stats(Priority::Urgent).dropped, 1);
+        assert_eq!(drain(&mut q), [3, 5, 6]);
+    }
+
+    #[test]
+    fn a_deep_backlog_does_not_block_small_ones() {
+        let mut q = queue(20_000, None);
+        for n in 0..10_000 {
+            q.push(Priority::Normal, out(1, (n % 200) as u8)).unwrap();
+        }
+        for tag in [201, 202, 203] {
+            q.push(Priority::Normal, out(2, tag)).unwrap();
+        }
+        let first: Vec<_> = (0..5).map(|_| q.pop().unwrap().1.body[0]).collect();
+        assert_eq!(first, [0, 201, 1, 202, 2]);
+        assert_eq!(
+            q.device_stats([b'D', b'E', b'V', 0, 0, 0, 0, 2]),
+            DeviceStats {
+                queued: 1,
+                dequeued: 2,
+                dropped: 0
+            }
+        );
+        assert_eq!(q.pop().unwrap().1.body, [203]);
+
+        // a device turning up later waits for one frame of the backlog
+        q.push(Priority::Normal, out(3, 250)).unwrap();
+        assert_eq!(drain(&mut q)[..2], [3, 250]);
+        assert_eq!(q.stats(Priority::Normal).dequeued, 10_004);
+        assert_eq!(
+            q.device_stats([b'D', b'E', b'V', 0, 0, 0, 0, 1]),
+            DeviceStats::default()
+        );
+    }
+
+    #[test]
+    fn devices_are_held_to_their_share() {
+        let mut q = PriorityQueue::new(QueueConfig {
+            capacity: 8,
+            per_device: Some(2),
+            starvation_limit: None,
+            normal_when_full: DropPolicy::RejectNew,
+            urgent_when_full: DropPolicy::DisplaceLower,
+        });
+        q.push(Priority::Normal, out(1, 1)).unwrap();
+        q.push(Priority::Normal, out(1, 2)).unwrap();
+        assert_eq!(
+            q.push(Priority::Normal, out(1, 3)),
+            Err(QueueFull(out(1, 3)))
+        );
+        // room for others all the same
+        q.push(Priority::Normal, out(2, 4)).unwrap();
+        // an urgent frame displaces the device's own newest normal one
+        assert_eq!(q.push(Priority::Urgent, out(1, 5)), Ok(Some(out(1, 2))));
+        assert_eq!(
+            q.device_stats([b'D', b'E', b'V', 0, 0, 0, 0, 1]),
+            DeviceStats {
+                queued: 2,
+                dequeued: 0,
+                dropped: 2
+            }
+        );
+        assert_eq!(drain(&mut q), [5, 1, 4]);
+    }
+
+    #[test]
+    fn devices_are_forgotten_once_drained() {
+        let mut q = PriorityQueue::new(QueueConfig {
+            capacity: 2,
+            per_device: Some(1),
+            starvation_limit: None,
+            normal_when_full: DropPolicy::RejectNew,
+            urgent_when_full: DropPolicy::DropOldest,
+        });
+        for device in 0..=255 {
+            q.push(Priority::Normal, out(device, 0)).unwrap();
+            q.pop().unwrap();
+        }
+        assert!(q.devices.is_empty());
+
+        // a frame displacing its device's only one keeps the device's count
+        q.push(Priority::Urgent, out(1, 1)).unwrap();
+        assert_eq!(q.push(Priority::Urgent, out(1, 2)), Ok(Some(out(1, 1))));
+        assert_eq!(
+            q.device_stats([b'D', b'E', b'V', 0, 0, 0, 0, 1]),
+            DeviceStats {
+                queued: 1,
+                dequeued: 0,
+                dropped: 1
+            }
+        );
+        // one turned away from a full queue is left to the band's count
+        q.push(Priority::Normal, out(2, 3)).unwrap();
+        assert!(q.push(Priority::Normal, out(3, 4)).is_err());
+        assert_eq!(q.devices.len(), 2);
+        assert_eq!(q.stats(Priority::Normal).dropped, 1);
+        assert_eq!(drain(&mut q), [2, 3]);
+        assert!(q.devices.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sender_numbers_frames_as_they_leave() {
+        let (a, mut b) = loopback_pair();
+        let sender = QueuedSender::new(
+            FrameSender::new(a, Arc::new(CounterMap::default())),
+            QueueConfig {
+                capacity: 2,
+                ..QueueConfig::default()
+            },
+        );
+        sender.send(Priority::Normal, out(1, 1)).await.unwrap();
+        sender.send(Priority::Urgent, out(1, 2)).await.unwrap();
+        let third = sender.send(Priority::Normal, out(1, 3));
+        let mut third = Box::pin(third);
+        assert!(third.as_mut().now_or_never().is_none(), "queue is full");
+
+        let runner = sender.clone();
+        let run = tokio::spawn(async move { runner.run().await });
+        third.await.unwrap();
+        let mut got = Vec::new();
+        for _ in 0..3 {
+            let f = b.recv().await.unwrap();
+            got.push((f.body[0], f.header.counter));
+        }
+        // the urgent frame overtook, yet counters still rise
+        assert_eq!(got, [(2, 1), (1, 2), (3, 3)]);
+        run.abort();
+    }
+}
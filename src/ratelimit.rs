@@ -0,0 +1,557 @@
+//! Per-device inbound rate limiting.
+//!
+//! [`RateLimiter`] keeps a token bucket per device id: `burst` tokens,
+//! refilled at `rate` per second, one spent per frame. Tokens are counted
+//! in billionths, so refill is exact integer arithmetic. A frame that finds
+//! its bucket empty is handled as [`OverLimit`] says.
+//!
+//! A bucket left alone long enough to refill completely is the same as a
+//! new one, so idle devices are forgotten without changing any outcome;
+//! the table never holds more than `max_devices`.
+//!
+//! [`ReplyLimiter`] limits the other way: the ERROR and NACK frames sent
+//! back to each source, so a peer sending a stream of frames that fail
+//! cannot have every one answered. Acks of frames that were handled are
+//! never held back.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::ack::{AckBody, ack_frame};
+use crate::clock::{Clock, SystemClock};
+use crate::request::error_reply;
+use crate::transport::PeerAddr;
+use crate::{FrameV1, MsgType};
+
+/// Billionths of a token per token.
+const SCALE: u128 = 1_000_000_000;
+
+/// What happens to a frame over its device's limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverLimit {
+    Drop,
+    /// Hold it in a bounded queue until the device has a token again;
+    /// dropped if the queue is full.
+    Defer,
+    /// Answer an ack-required frame with a NACK saying "Busy", and drop
+    /// anything else.
+    Nack,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Tokens added per second.
+    pub rate: u32,
+    pub burst: u32,
+    pub over_limit: OverLimit,
+    /// Frames held under [`OverLimit::Defer`], across all devices.
+    pub max_deferred: usize,
+    pub max_devices: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            rate: 10,
+            burst: 20,
+            over_limit: OverLimit::Drop,
+            max_deferred: 256,
+            max_devices: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    Allow(FrameV1),
+    Dropped,
+    Deferred,
+    /// Send this NACK back instead of handling the frame.
+    Nack(FrameV1),
+}
+
+impl Admission {
+    /// Metric label for the outcome.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Admission::Allow(_) => "allowed",
+            Admission::Dropped => "dropped",
+            Admission::Deferred => "deferred",
+            Admission::Nack(_) => "nacked",
+        }
+    }
+}
+
+struct Bucket {
+    /// In billionths of a token.
+    tokens: u128,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn full(burst: u32) -> u128 {
+        u128::from(burst) * SCALE
+    }
+
+    /// Refill for the time since the last refill, then take a token if
+    /// there is one.
+    fn take(&mut self, now: Instant, rate: u32, burst: u32) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_nanos();
+        self.tokens = (self.tokens + elapsed * u128::from(rate)).min(Self::full(burst));
+        self.refilled = now;
+        if self.tokens < SCALE {
+            return false;
+        }
+        self.tokens -= SCALE;
+        true
+    }
+}
+
+pub struct RateLimiter {
+    clock: Arc<dyn Clock>,
+    config: RateLimitConfig,
+    buckets: HashMap<[u8; 8], Bucket>,
+    deferred: VecDeque<FrameV1>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: RateLimitConfig, clock: Arc<dyn Clock>) -> Self {
+        RateLimiter {
+            clock,
+            config: RateLimitConfig {
+                max_devices: config.max_devices.max(1),
+                ..config
+            },
+            buckets: HashMap::new(),
+            deferred: VecDeque::new(),
+        }
+    }
+
+    fn full(&self) -> u128 {
+        Bucket::full(self.config.burst)
+    }
+
+    /// Take a token for `device_id` if it has one.
+    fn take(&mut self, device_id: [u8; 8], now: Instant) -> bool {
+        let full = self.full();
+        if !self.buckets.contains_key(&device_id) && self.buckets.len() >= self.config.max_devices {
+            self.evict_idle(now);
+            if self.buckets.len() >= self.config.max_devices
+                && let Some(oldest) = self
+                    .buckets
+                    .iter()
+                    .min_by_key(|(_, b)| b.refilled)
+                    .map(|(&d, _)| d)
+            {
+                self.buckets.remove(&oldest);
+            }
+        }
+        let bucket = self.buckets.entry(device_id).or_insert(Bucket {
+            tokens: full,
+            refilled: now,
+        });
+        bucket.take(now, self.config.rate, self.config.burst)
+    }
+
+    /// Decide what to do with a received frame.
+    pub fn check(&mut self, frame: FrameV1) -> Admission {
+        let now = self.clock.now();
+        let admission = if self.take(frame.header.device_id, now) {
+            Admission::Allow(frame)
+        } else {
+            match self.config.over_limit {
+                OverLimit::Drop => Admission::Dropped,
+                OverLimit::Defer if self.deferred.len() < self.config.max_deferred => {
+                    self.deferred.push_back(frame);
+                    Admission::Deferred
+                }
+                OverLimit::Defer => Admission::Dropped,
+                OverLimit::Nack if frame.header.flags.ack_required() => {
+                    let h = &frame.header;
+                    let busy = AckBody::Nack {
+                        reason: "Busy".into(),
+                    };
+                    Admission::Nack(ack_frame(h.device_id, h.counter, busy))
+                }
+                OverLimit::Nack => Admission::Dropped,
+            }
+        };
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().rate_limited(admission.as_str());
+        admission
+    }
+
+    /// Deferred frames whose devices have a token again, oldest first.
+    /// Each device's frames stay in order.
+    pub fn poll_deferred(&mut self) -> Vec<FrameV1> {
+        let now = self.clock.now();
+        let mut ready = Vec::new();
+        let mut still_waiting = VecDeque::new();
+        let mut blocked = Vec::new();
+        for frame in std::mem::take(&mut self.deferred) {
+            let device = frame.header.device_id;
+            if !blocked.contains(&device) && self.take(device, now) {
+                ready.push(frame);
+            } else {
+                blocked.push(device);
+                still_waiting.push_back(frame);
+            }
+        }
+        self.deferred = still_waiting;
+        ready
+    }
+
+    pub fn deferred(&self) -> usize {
+        self.deferred.len()
+    }
+
+    /// Forget devices whose buckets have refilled completely by `now`.
+    pub fn evict_idle(&mut self, now: Instant) {
+        let Some(refill) = self.refill_time() else {
+            return;
+        };
+        self.buckets
+            .retain(|_, b| now.saturating_duration_since(b.refilled) < refill);
+    }
+
+    /// How long an empty bucket takes to fill; `None` if it never does.
+    fn refill_time(&self) -> Option<Duration> {
+        let rate = u128::from(self.config.rate);
+        let nanos = self.full().checked_div(rate)?;
+        Some(Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX)))
+    }
+
+    /// Devices currently tracked.
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+/// Error code of the ERROR frame summing up replies a [`ReplyLimiter`]
+/// held back; the body carries their number as a big-endian u64.
+pub const ERRORS_SUPPRESSED: u16 = 0xfffe;
+
+/// What a [`ReplyLimiter`] counts a source by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LimitBy {
+    /// The device id the frame carries.
+    #[default]
+    Device,
+    /// The transport address it came from.
+    Peer,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplyLimitConfig {
+    /// ERROR and NACK replies allowed per second per source.
+    pub rate: u32,
+    pub burst: u32,
+    pub by: LimitBy,
+    /// Once a source may be answered again, send one
+    /// [`ERRORS_SUPPRESSED`] frame for the replies held back.
+    pub summarize: bool,
+    pub max_sources: usize,
+}
+
+impl Default for ReplyLimitConfig {
+    fn default() -> Self {
+        ReplyLimitConfig {
+            rate: 1,
+            burst: 5,
+            by: LimitBy::Device,
+            summarize: false,
+            max_sources: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Source {
+    Device([u8; 8]),
+    Peer(PeerAddr),
+}
+
+struct Held {
+    bucket: Bucket,
+    /// Replies held back since the last summary.
+    suppressed: u64,
+    /// The latest of them, addressed like the summary will be.
+    last: Option<FrameV1>,
+}
+
+pub struct ReplyLimiter {
+    clock: Arc<dyn Clock>,
+    config: ReplyLimitConfig,
+    sources: HashMap<Source, Held>,
+    suppressed: u64,
+}
+
+/// Whether `reply` is one a [`ReplyLimiter`] limits.
+fn is_error(reply: &FrameV1) -> bool {
+    match reply.header.msg_type {
+        MsgType::Error => true,
+        MsgType::Ack => matches!(AckBody::decode(&reply.body), Ok(AckBody::Nack { .. })),
+        _ => false,
+    }
+}
+
+impl ReplyLimiter {
+    pub fn new(config: ReplyLimitConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: ReplyLimitConfig, clock: Arc<dyn Clock>) -> Self {
+        ReplyLimiter {
+            clock,
+            config: ReplyLimitConfig {
+                max_sources: config.max_sources.max(1),
+                ..config
+            },
+            sources: HashMap::new(),
+            suppressed: 0,
+        }
+    }
+
+    /// Pass on `reply` to a frame from `peer`, unless it is an ERROR or
+    /// NACK and the frame's source has used up its allowance.
+    pub fn check(&mut self, peer: &PeerAddr, reply: FrameV1) -> Option<FrameV1> {
+        if !is_error(&reply) {
+            return Some(reply);
+        }
+        let source = match self.config.by {
+            LimitBy::Device => Source::Device(reply.header.device_id),
+            LimitBy::Peer => Source::Peer(peer.clone()),
+        };
+        let now = self.clock.now();
+        if !self.sources.contains_key(&source) && self.sources.len() >= self.config.max_sources {
+            let quietest = self
+                .sources
+                .iter()
+                .min_by_key(|(_, h)| h.bucket.refilled)
+                .map(|(s, _)| s.clone());
+            if let Some(quietest) = quietest {
+                self.sources.remove(&quietest);
+            }
+        }
+        let held = self.sources.entry(source).or_insert(Held {
+            bucket: Bucket {
+                tokens: Bucket::full(self.config.burst),
+                refilled: now,
+            },
+            suppressed: 0,
+            last: None,
+        });
+        if held.bucket.take(now, self.config.rate, self.config.burst) {
+            return Some(reply);
+        }
+        held.suppressed += 1;
+        held.last = Some(reply);
+        self.suppressed += 1;
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().error_reply_suppressed();
+        None
+    }
+
+    /// One [`ERRORS_SUPPRESSED`] frame per source that had replies held
+    /// back and may be answered again, if `summarize` is set.
+    pub fn summaries(&mut self) -> Vec<FrameV1> {
+        if !self.config.summarize {
+            return Vec::new();
+        }
+        let now = self.clock.now();
+        let (rate, burst) = (self.config.rate, self.config.burst);
+        let mut out = Vec::new();
+        for held in self.sources.values_mut() {
+            if held.suppressed == 0 || !held.bucket.take(now, rate, burst) {
+                continue;
+            }
+            let last = held.last.take().expect("held back a reply");
+            let count = std::mem::take(&mut held.suppressed);
+            out.push(error_reply(
+                &last.header,
+                ERRORS_SUPPRESSED,
+                &count.to_be_bytes(),
+            ));
+        }
+        out.sort_by_key(|f| (f.header.device_id, f.header.counter));
+        out
+    }
+
+    /// Replies held back so far.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Flags;
+    use crate::clock::ManualClock;
+    use crate::transport::tests::frame;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn limiter(rate: u32, burst: u32, over_limit: OverLimit) -> (RateLimiter, ManualClock) {
+        let clock = ManualClock::new();
+        let config = RateLimitConfig {
+            rate,
+            burst,
+            over_limit,
+            max_deferred: 2,
+            max_devices: 2,
+        };
+        (
+            RateLimiter::with_clock(config, Arc::new(clock.clone())),
+            clock,
+        )
+    }
+
+    fn allowed(l: &mut RateLimiter) -> bool {
+        matches!(l.check(frame(1)), Admission::Allow(_))
+    }
+
+    #[test]
+    fn refill_is_exact() {
+        let (mut l, clock) = limiter(3, 2, OverLimit::Drop);
+        assert!(allowed(&mut l));
+        assert!(allowed(&mut l));
+        assert!(!allowed(&mut l));
+
+        // a token takes 333.33.. ms; fractions carry over
+        clock.advance(333 * MS);
+        assert!(!allowed(&mut l));
+        clock.advance(MS);
+        assert!(allowed(&mut l));
+        clock.advance(332 * MS);
+        assert!(!allowed(&mut l));
+        clock.advance(MS);
+        assert!(allowed(&mut l));
+
+        // never more than the burst
+        clock.advance(Duration::from_secs(60));
+        assert!(allowed(&mut l));
+        assert!(allowed(&mut l));
+        assert!(!allowed(&mut l));
+    }
+
+    #[test]
+    fn devices_have_separate_buckets() {
+        let (mut l, _clock) = limiter(1, 1, OverLimit::Drop);
+        assert!(allowed(&mut l));
+        assert_eq!(l.check(frame(2)), Admission::Dropped);
+        let mut other = frame(1);
+        other.header.device_id = *b"DEV00002";
+        assert!(matches!(l.check(other), Admission::Allow(_)));
+    }
+
+    #[test]
+    fn deferral_is_bounded_and_ordered() {
+        let (mut l, clock) = limiter(10, 1, OverLimit::Defer);
+        assert!(allowed(&mut l));
+        assert_eq!(l.check(frame(2)), Admission::Deferred);
+        assert_eq!(l.check(frame(3)), Admission::Deferred);
+        assert_eq!(l.check(frame(4)), Admission::Dropped);
+        assert_eq!(l.poll_deferred(), []);
+
+        clock.advance(100 * MS);
+        assert_eq!(l.poll_deferred(), [frame(2)]);
+        clock.advance(100 * MS);
+        assert_eq!(l.poll_deferred(), [frame(3)]);
+        assert_eq!(l.deferred(), 0);
+    }
+
+    #[test]
+    fn nacks_only_frames_that_want_an_answer() {
+        let (mut l, _clock) = limiter(1, 1, OverLimit::Nack);
+        assert!(allowed(&mut l));
+        assert_eq!(l.check(frame(2)), Admission::Dropped);
+        let mut wants_ack = frame(3);
+        wants_ack.header.flags = Flags::new(Flags::ACK_REQUIRED).unwrap();
+        let Admission::Nack(nack) = l.check(wants_ack) else {
+            panic!("expected a nack");
+        };
+        assert_eq!(nack.header.counter, 3);
+        assert_eq!(
+            AckBody::decode(&nack.body),
+            Ok(AckBody::Nack {
+                reason: "Busy".into()
+            })
+        );
+    }
+
+    #[test]
+    fn table_is_bounded() {
+        let (mut l, clock) = limiter(10, 1, OverLimit::Drop);
+        let from = |d: &[u8; 8]| {
+            let mut f = frame(1);
+            f.header.device_id = *d;
+            f
+        };
+        l.check(from(b"DEV00001"));
+        clock.advance(50 * MS);
+        l.check(from(b"DEV00002"));
+        clock.advance(60 * MS);
+        // DEV00001 has refilled, so it goes first and nothing is lost
+        l.check(from(b"DEV00003"));
+        assert_eq!(l.len(), 2);
+        assert!(matches!(l.check(from(b"DEV00001")), Admission::Allow(_)));
+        assert_eq!(l.len(), 2);
+
+        clock.advance(Duration::from_secs(1));
+        l.evict_idle(clock.now());
+        assert!(l.is_empty());
+    }
+
+    #[test]
+    fn error_replies_are_limited_per_source() {
+        let clock = ManualClock::new();
+        let config = ReplyLimitConfig {
+            rate: 1,
+            burst: 2,
+            summarize: true,
+            ..ReplyLimitConfig::default()
+        };
+        let mut l = ReplyLimiter::with_clock(config, Arc::new(clock.clone()));
+        let peer = PeerAddr::Opaque("test".into());
+        let error = |counter| error_reply(&frame(counter).header, 0x0101, b"bad");
+        let nack = |counter| {
+            let reason = "Unhandled".into();
+            ack_frame(*b"DEV00001", counter, AckBody::Nack { reason })
+        };
+        assert!(l.check(&peer, error(1)).is_some());
+        assert!(l.check(&peer, nack(2)).is_some());
+        assert_eq!(l.check(&peer, error(3)), None);
+        assert_eq!(l.check(&peer, nack(4)), None);
+        let ack = ack_frame(*b"DEV00001", 5, AckBody::Single);
+        assert_eq!(l.check(&peer, ack.clone()), Some(ack), "acks pass");
+        let mut other = error(1);
+        other.header.device_id = *b"DEV00002";
+        assert!(l.check(&peer, other).is_some(), "another device");
+        assert_eq!(l.suppressed(), 2);
+        assert_eq!(l.summaries(), []);
+
+        clock.advance(Duration::from_secs(1));
+        let summary = l.summaries();
+        assert_eq!(
+            summary,
+            [error_reply(
+                &nack(4).header,
+                ERRORS_SUPPRESSED,
+                &2u64.to_be_bytes()
+            )]
+        );
+        assert_eq!(summary[0].header.msg_type, MsgType::Error);
+        assert_eq!(l.summaries(), [], "sent once");
+        assert_eq!(l.check(&peer, error(6)), None, "the summary took the token");
+    }
+}
@@ -0,0 +1,494 @@
+//! What is known about each device heard from.
+//!
+//! A [`DeviceRegistry`] is fed every received frame through
+//! [`DeviceRegistry::observe`] and keeps a [`DeviceInfo`] per device id:
+//! last counter and arrival, frame and error counts, the protocol version
+//! from its session HELLO or discovery ANNOUNCE, whatever capabilities
+//! the application says it announced, round-trip times from PINGs, the
+//! state of its circuit breaker and an estimate of its clock.
+//! Clones share one registry. It holds at most `max_devices`, forgetting
+//! the device heard from least recently, and announces devices it has not
+//! seen before on a broadcast channel.
+//!
+//! [`DeviceRegistry::save`] and [`DeviceRegistry::load`] keep it across
+//! restarts in a text file, one line per device.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::broadcast;
+
+use crate::breaker::CircuitState;
+use crate::ping::RttStats;
+use crate::session::Control;
+use crate::timesync::ClockEstimate;
+use crate::transport::RxFrame;
+use crate::{MsgType, VERSION_V1};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub device_id: [u8; 8],
+    /// Highest counter received.
+    pub last_counter: u64,
+    pub last_seen: SystemTime,
+    pub frames: u64,
+    /// ERROR frames received, plus failures reported with
+    /// [`DeviceRegistry::record_error`].
+    pub errors: u64,
+    /// The lower of the device's HELLO version and ours.
+    pub version: Option<u8>,
+    /// Opaque to the registry; v1 has no message announcing them.
+    pub capabilities: Vec<u8>,
+    /// Round trips measured by a [`Pinger`](crate::ping::Pinger); not
+    /// saved.
+    pub rtt: RttStats,
+    /// As a [`CircuitBreaker`](crate::breaker::CircuitBreaker) last
+    /// reported it; not saved.
+    pub circuit: CircuitState,
+    /// As a [`ClockEstimator`](crate::timesync::ClockEstimator) last
+    /// fitted it.
+    pub clock: ClockEstimate,
+}
+
+impl DeviceInfo {
+    fn new(device_id: [u8; 8], seen: SystemTime) -> Self {
+        DeviceInfo {
+            device_id,
+            last_counter: 0,
+            last_seen: seen,
+            frames: 0,
+            errors: 0,
+            version: None,
+            capabilities: Vec::new(),
+            rtt: RttStats::default(),
+            circuit: CircuitState::Closed,
+            clock: ClockEstimate::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RegistryConfig {
+    pub max_devices: usize,
+    /// New-device events a slow subscriber may fall behind by.
+    pub event_capacity: usize,
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        RegistryConfig {
+            max_devices: 10_000,
+            event_capacity: 64,
+        }
+    }
+}
+
+struct Entry {
+    info: DeviceInfo,
+    /// Recency rank, the key in `order`.
+    used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    devices: HashMap<[u8; 8], Entry>,
+    order: BTreeMap<u64, [u8; 8]>,
+    next_use: u64,
+}
+
+struct Inner {
+    max_devices: usize,
+    state: Mutex<State>,
+    new_devices: broadcast::Sender<[u8; 8]>,
+}
+
+#[derive(Clone)]
+pub struct DeviceRegistry {
+    inner: Arc<Inner>,
+}
+
+impl DeviceRegistry {
+    pub fn new(config: RegistryConfig) -> Self {
+        DeviceRegistry {
+            inner: Arc::new(Inner {
+                max_devices: config.max_devices.max(1),
+                state: Mutex::default(),
+                new_devices: broadcast::channel(config.event_capacity.max(1)).0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Apply `update` to the entry for `device_id`, creating it if needed,
+    /// and mark it most recently used.
+    fn touch(&self, device_id: [u8; 8], seen: SystemTime, update: impl FnOnce(&mut DeviceInfo)) {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let used = state.next_use;
+        state.next_use += 1;
+        let is_new = match state.devices.get_mut(&device_id) {
+            Some(e) => {
+                state.order.remove(&e.used);
+                e.used = used;
+                update(&mut e.info);
+                false
+            }
+            None => {
+                if state.devices.len() >= self.inner.max_devices
+                    && let Some((_, oldest)) = state.order.pop_first()
+                {
+                    state.devices.remove(&oldest);
+                }
+                let mut info = DeviceInfo::new(device_id, seen);
+                update(&mut info);
+                state.devices.insert(device_id, Entry { info, used });
+                true
+            }
+        };
+        state.order.insert(used, device_id);
+        if is_new {
+            // nobody listening is fine
+            let _ = self.inner.new_devices.send(device_id);
+        }
+    }
+
+    /// Record a received frame.
+    pub fn observe(&self, frame: &RxFrame) {
+        let h = &frame.header;
+        let hello = match Control::parse(frame) {
+            Some(Control::Hello { version }) => Some(version.min(VERSION_V1)),
+            _ => None,
+        };
+        self.touch(h.device_id, frame.received_at, |info| {
+            info.last_counter = info.last_counter.max(h.counter);
+            info.last_seen = info.last_seen.max(frame.received_at);
+            info.frames += 1;
+            if h.msg_type == MsgType::Error {
+                info.errors += 1;
+            }
+            if hello.is_some() {
+                info.version = hello;
+            }
+        });
+    }
+
+    /// Count a failure attributed to `device_id`, e.g. a rejected frame.
+    pub fn record_error(&self, device_id: [u8; 8]) {
+        self.update(device_id, |info| info.errors += 1);
+    }
+
+    /// Set the version the device says it speaks, capped at what this
+    /// crate knows.
+    pub fn set_version(&self, device_id: [u8; 8], version: u8) {
+        self.update(device_id, |info| {
+            info.version = Some(version.min(VERSION_V1))
+        });
+    }
+
+    pub fn set_capabilities(&self, device_id: [u8; 8], capabilities: Vec<u8>) {
+        self.update(device_id, |info| info.capabilities = capabilities);
+    }
+
+    pub fn set_rtt(&self, device_id: [u8; 8], rtt: RttStats) {
+        self.update(device_id, |info| info.rtt = rtt);
+    }
+
+    pub fn set_circuit(&self, device_id: [u8; 8], circuit: CircuitState) {
+        self.update(device_id, |info| info.circuit = circuit);
+    }
+
+    pub fn set_clock(&self, device_id: [u8; 8], clock: ClockEstimate) {
+        self.update(device_id, |info| info.clock = clock);
+    }
+
+    /// Change a known device; unknown ones are ignored.
+    fn update(&self, device_id: [u8; 8], update: impl FnOnce(&mut DeviceInfo)) {
+        if let Some(e) = self.lock().devices.get_mut(&device_id) {
+            update(&mut e.info);
+        }
+    }
+
+    pub fn get(&self, device_id: [u8; 8]) -> Option<DeviceInfo> {
+        self.lock().devices.get(&device_id).map(|e| e.info.clone())
+    }
+
+    /// Every device, by id.
+    pub fn list(&self) -> Vec<DeviceInfo> {
+        let mut all: Vec<_> = self
+            .lock()
+            .devices
+            .values()
+            .map(|e| e.info.clone())
+            .collect();
+        all.sort_by_key(|i| i.device_id);
+        all
+    }
+
+    /// Devices not heard from for at least `max_age` before `now`, by id.
+    pub fn stale(&self, now: SystemTime, max_age: Duration) -> Vec<DeviceInfo> {
+        let mut stale = self.list();
+        stale.retain(|i| {
+            now.duration_since(i.last_seen)
+                .is_ok_and(|age| age >= max_age)
+        });
+        stale
+    }
+
+    /// The ids of devices seen for the first time from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<[u8; 8]> {
+        self.inner.new_devices.subscribe()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().devices.is_empty()
+    }
+
+    /// Write every device to `path`, replacing it atomically.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        for info in self.list() {
+            text.push_str(&format_line(&info));
+            text.push('\n');
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(text.as_bytes())?;
+        tmp.sync_all()?;
+        drop(tmp);
+        fs::rename(&tmp_path, path)
+    }
+
+    /// A registry holding what [`DeviceRegistry::save`] wrote to `path`,
+    /// which need not exist. Loading announces no new devices.
+    pub fn load(path: impl AsRef<Path>, config: RegistryConfig) -> io::Result<Self> {
+        let registry = DeviceRegistry::new(config);
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(registry),
+            Err(e) => return Err(e),
+        };
+        registry.import(text.lines().filter(|l| !l.trim().is_empty()))?;
+        Ok(registry)
+    }
+
+    /// Every device as a line of the save file.
+    pub(crate) fn export(&self) -> Vec<String> {
+        self.list().iter().map(format_line).collect()
+    }
+
+    /// Take in devices from lines of a save file, replacing any held with
+    /// the same ids. Announces no new devices.
+    pub(crate) fn import<'a>(&self, lines: impl IntoIterator<Item = &'a str>) -> io::Result<()> {
+        let mut infos = lines
+            .into_iter()
+            .map(parse_line)
+            .collect::<io::Result<Vec<_>>>()?;
+        // least recently seen first, so they are the first evicted
+        infos.sort_by_key(|i| i.last_seen);
+        let mut state = self.lock();
+        let state = &mut *state;
+        let skip = infos.len().saturating_sub(self.inner.max_devices);
+        for info in infos.into_iter().skip(skip) {
+            if let Some(old) = state.devices.remove(&info.device_id) {
+                state.order.remove(&old.used);
+            } else if state.devices.len() >= self.inner.max_devices
+                && let Some((_, oldest)) = state.order.pop_first()
+            {
+                state.devices.remove(&oldest);
+            }
+            let used = state.next_use;
+            state.next_use += 1;
+            state.order.insert(used, info.device_id);
+            state.devices.insert(info.device_id, Entry { info, used });
+        }
+        Ok(())
+    }
+}
+
+/// `device_hex last_counter last_seen_ms frames errors version capabilities_hex`,
+/// with `-` for no version and no capabilities, then the two fields of
+/// [`ClockEstimate::format`]. Lines without those load with no estimate.
+fn format_line(i: &DeviceInfo) -> String {
+    let seen = i
+        .last_seen
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let version = i.version.map_or("-".to_string(), |v| v.to_string());
+    let caps = match i.capabilities.as_slice() {
+        [] => "-".to_string(),
+        c => crate::hex::encode(c),
+    };
+    format!(
+        "{} {} {seen} {} {} {version} {caps} {}",
+        crate::hex::encode(&i.device_id),
+        i.last_counter,
+        i.frames,
+        i.errors,
+        i.clock.format(),
+    )
+}
+
+fn parse_line(line: &str) -> io::Result<DeviceInfo> {
+    let bad = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad device line {line:?}"),
+        )
+    };
+    let fields: Vec<_> = line.split_whitespace().collect();
+    let (fields, clock) = match fields[..] {
+        [ref head @ .., fit, samples] if head.len() == 7 => (head, (fit, samples)),
+        ref head => (head, ("-", "-")),
+    };
+    let [device, counter, seen, frames, errors, version, caps] = fields[..] else {
+        return Err(bad());
+    };
+    let num = |s: &str| s.parse::<u64>().map_err(|_| bad());
+    let device_id = crate::hex::decode(device)
+        .and_then(|d| <[u8; 8]>::try_from(d).ok())
+        .ok_or_else(bad)?;
+    Ok(DeviceInfo {
+        device_id,
+        last_counter: num(counter)?,
+        last_seen: UNIX_EPOCH + Duration::from_millis(num(seen)?),
+        frames: num(frames)?,
+        errors: num(errors)?,
+        version: match version {
+            "-" => None,
+            v => Some(v.parse().map_err(|_| bad())?),
+        },
+        capabilities: match caps {
+            "-" => Vec::new(),
+            c => crate::hex::decode(c).ok_or_else(bad)?,
+        },
+        rtt: RttStats::default(),
+        circuit: CircuitState::Closed,
+        clock: ClockEstimate::parse(clock.0, clock.1).ok_or_else(bad)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::frame;
+    use crate::transport::{PeerAddr, TransportId};
+
+    const DEV: [u8; 8] = *b"DEV00001";
+
+    fn rx(device_id: [u8; 8], counter: u64, at_secs: u64) -> RxFrame {
+        let mut f = frame(counter);
+        f.header.device_id = device_id;
+        let mut rx = RxFrame::new(f, PeerAddr::Opaque("test".into()), TransportId::UNASSIGNED);
+        rx.received_at = UNIX_EPOCH + Duration::from_secs(at_secs);
+        rx
+    }
+
+    fn registry(max_devices: usize) -> DeviceRegistry {
+        DeviceRegistry::new(RegistryConfig {
+            max_devices,
+            ..RegistryConfig::default()
+        })
+    }
+
+    #[test]
+    fn tracks_what_each_device_sent() {
+        let r = registry(8);
+        r.observe(&rx(DEV, 5, 100));
+        // a late retransmission does not move anything back
+        r.observe(&rx(DEV, 3, 90));
+        let mut error = rx(DEV, 6, 110);
+        error.frame.header.msg_type = MsgType::Error;
+        r.observe(&error);
+        let mut hello = rx(DEV, 7, 120);
+        hello.frame.header.msg_type = MsgType::Command;
+        hello.frame.body = vec![0x00, 0x53, 0x01, 0x07].into();
+        r.observe(&hello);
+        r.record_error(DEV);
+        r.set_capabilities(DEV, vec![0xca, 0xfe]);
+        r.record_error(*b"UNKNOWN0");
+
+        let info = r.get(DEV).unwrap();
+        assert_eq!(info.last_counter, 7);
+        assert_eq!(info.last_seen, UNIX_EPOCH + Duration::from_secs(120));
+        assert_eq!(info.frames, 4);
+        assert_eq!(info.errors, 2);
+        assert_eq!(info.version, Some(VERSION_V1));
+        assert_eq!(info.capabilities, [0xca, 0xfe]);
+        assert_eq!(r.len(), 1);
+    }
+
+    #[test]
+    fn staleness_and_eviction() {
+        let r = registry(2);
+        r.observe(&rx(*b"DEV00001", 1, 100));
+        r.observe(&rx(*b"DEV00002", 1, 200));
+        r.observe(&rx(*b"DEV00001", 2, 300));
+        let now = UNIX_EPOCH + Duration::from_secs(400);
+        let stale = r.stale(now, Duration::from_secs(150));
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].device_id, *b"DEV00002");
+
+        // DEV00002 is the least recently heard from
+        r.observe(&rx(*b"DEV00003", 1, 400));
+        let ids: Vec<_> = r.list().iter().map(|i| i.device_id).collect();
+        assert_eq!(ids, [*b"DEV00001", *b"DEV00003"]);
+    }
+
+    #[tokio::test]
+    async fn announces_new_devices() {
+        let r = registry(8);
+        let mut events = r.subscribe();
+        r.observe(&rx(*b"DEV00001", 1, 1));
+        r.observe(&rx(*b"DEV00001", 2, 2));
+        let shared = r.clone();
+        tokio::spawn(async move { shared.observe(&rx(*b"DEV00002", 1, 3)) })
+            .await
+            .unwrap();
+        assert_eq!(events.recv().await.unwrap(), *b"DEV00001");
+        assert_eq!(events.recv().await.unwrap(), *b"DEV00002");
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("devices");
+        let r = registry(8);
+        r.observe(&rx(*b"DEV00001", 9, 100));
+        r.observe(&rx(*b"DEV00002", 4, 50));
+        r.set_capabilities(*b"DEV00002", vec![1]);
+        r.save(&path).unwrap();
+
+        let loaded = DeviceRegistry::load(&path, RegistryConfig::default()).unwrap();
+        assert_eq!(loaded.list(), r.list());
+
+        // only the most recently seen fit
+        let small = DeviceRegistry::load(
+            &path,
+            RegistryConfig {
+                max_devices: 1,
+                ..RegistryConfig::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(small.list(), [r.get(*b"DEV00001").unwrap()]);
+
+        let missing = DeviceRegistry::load(dir.path().join("none"), RegistryConfig::default());
+        assert!(missing.unwrap().is_empty());
+        fs::write(&path, "DEV00001 1 2\n").unwrap();
+        assert!(DeviceRegistry::load(&path, RegistryConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,387 @@
+//! Putting each device's frames back in counter order.
+//!
+//! A [`ReorderBuffer`] is pushed every received frame and hands back the
+//! frames that may now be delivered, in counter order per device. A frame
+//! ahead of the next expected counter waits until the ones before it
+//! arrive, for at most `timeout`; then the buffer gives up on them,
+//! releases what waited and reports the counters skipped as a
+//! [`MissingRange`]. It does no I/O.
+//!
+//! Only EVENTs and COMMANDs are reordered: an ACK or ERROR carries the
+//! counter of the frame it answers, not one of its sender's. The first
+//! frame from a device sets where its order starts. A frame behind the
+//! next expected counter, late or a duplicate of one delivered, is
+//! released straight away, so a dedup stage after this one can answer it;
+//! a duplicate of a frame still waiting is dropped.
+//!
+//! Memory is bounded by `window` frames per device, `max_frames` in all
+//! and `max_devices`. When a bound is hit, [`WhenFull`] says whether to
+//! give up waiting early or drop the frame that did not fit.
+//! [`Dispatcher::with_reorder`](crate::dispatch::Dispatcher::with_reorder)
+//! runs one in front of the handlers.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::MsgType;
+use crate::clock::{Clock, SystemClock};
+use crate::counter::CounterSpace;
+use crate::gaps::MissingRange;
+use crate::transport::RxFrame;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WhenFull {
+    /// Stop waiting for the device's earliest missing frame and release
+    /// what waited behind it.
+    #[default]
+    Release,
+    /// Drop the frame that did not fit; the sender is expected to resend.
+    DropNewest,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReorderConfig {
+    /// Frames waiting per device.
+    pub window: usize,
+    /// How long a frame waits for those before it.
+    pub timeout: Duration,
+    /// Frames waiting across all devices.
+    pub max_frames: usize,
+    /// Devices tracked at once; the least recently heard is released and
+    /// forgotten.
+    pub max_devices: usize,
+    pub when_full: WhenFull,
+    pub space: CounterSpace,
+}
+
+impl Default for ReorderConfig {
+    fn default() -> Self {
+        ReorderConfig {
+            window: 32,
+            timeout: Duration::from_millis(500),
+            max_frames: 4096,
+            max_devices: 10_000,
+            when_full: WhenFull::Release,
+            space: CounterSpace::Linear,
+        }
+    }
+}
+
+/// Frames to deliver, in order, and the counters given up on.
+#[derive(Debug, Default)]
+pub struct Release {
+    pub frames: Vec<RxFrame>,
+    pub gaps: Vec<MissingRange>,
+}
+
+struct Device {
+    /// The first counter heard; the rest are kept as offsets from it.
+    base: u64,
+    next: u64,
+    waiting: BTreeMap<u64, (RxFrame, Instant)>,
+    heard: Instant,
+}
+
+impl Device {
+    /// Release what can go now that `next` may have moved.
+    fn drain(&mut self, out: &mut Release) {
+        while let Some((frame, _)) = self.waiting.remove(&self.next) {
+            out.frames.push(frame);
+            self.next += 1;
+        }
+    }
+
+    /// Stop waiting for the frames before the earliest one waiting.
+    fn skip(&mut self, device_id: [u8; 8], out: &mut Release) {
+        let Some(&first) = self.waiting.keys().next() else {
+            return;
+        };
+        out.gaps.push(MissingRange {
+            device_id,
+            from: self.base.wrapping_add(self.next),
+            to: self.base.wrapping_add(first - 1),
+        });
+        self.next = first;
+        self.drain(out);
+    }
+}
+
+pub struct ReorderBuffer {
+    clock: Arc<dyn Clock>,
+    config: ReorderConfig,
+    devices: HashMap<[u8; 8], Device>,
+    waiting: usize,
+    dropped: u64,
+}
+
+impl ReorderBuffer {
+    pub fn new(config: ReorderConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: ReorderConfig, clock: Arc<dyn Clock>) -> Self {
+        ReorderBuffer {
+            clock,
+            config: ReorderConfig {
+                window: config.window.max(1),
+                max_frames: config.max_frames.max(1),
+                max_devices: config.max_devices.max(1),
+                ..config
+            },
+            devices: HashMap::new(),
+            waiting: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, frame: RxFrame) -> Release {
+        let mut out = Release::default();
+        if !matches!(frame.header.msg_type, MsgType::Event | MsgType::Command) {
+            out.frames.push(frame);
+            return out;
+        }
+        let now = self.clock.now();
+        let device_id = frame.header.device_id;
+        let counter = frame.header.counter;
+        if !self.devices.contains_key(&device_id) {
+            if self.devices.len() >= self.config.max_devices {
+                self.evict_quietest(&mut out);
+            }
+            let device = Device {
+                base: counter,
+                next: 0,
+                waiting: BTreeMap::new(),
+                heard: now,
+            };
+            self.devices.insert(device_id, device);
+        }
+        let d = self.devices.get_mut(&device_id).unwrap();
+        d.heard = now;
+        let offset = match self.config.space.behind(counter, d.base) {
+            Some(offset) if offset >= d.next => offset,
+            // late, or a duplicate of one delivered
+            _ => {
+                out.frames.push(frame);
+                return out;
+            }
+        };
+        if offset == d.next {
+            out.frames.push(frame);
+            d.next += 1;
+            let before = d.waiting.len();
+            d.drain(&mut out);
+            self.waiting -= before - d.waiting.len();
+            return out;
+        }
+        if d.waiting.contains_key(&offset) {
+            self.dropped += 1;
+            return out;
+        }
+        let full = d.waiting.len() >= self.config.window || self.waiting >= self.config.max_frames;
+        if full && self.config.when_full == WhenFull::DropNewest {
+            self.dropped += 1;
+            return out;
+        }
+        d.waiting.insert(offset, (frame, now));
+        self.waiting += 1;
+        if d.waiting.len() > self.config.window {
+            self.skip(device_id, &mut out);
+        }
+        if self.waiting > self.config.max_frames
+            && let Some(oldest) = self.longest_waiting()
+        {
+            self.skip(oldest, &mut out);
+        }
+        out
+    }
+
+    fn skip(&mut self, device_id: [u8; 8], out: &mut Release) {
+        let Some(d) = self.devices.get_mut(&device_id) else {
+            return;
+        };
+        let before = d.waiting.len();
+        d.skip(device_id, out);
+        self.waiting -= before - d.waiting.len();
+    }
+
+    /// The device whose earliest waiting frame has waited longest.
+    fn longest_waiting(&self) -> Option<[u8; 8]> {
+        self.devices
+            .iter()
+            .filter_map(|(id, d)| Some((d.waiting.values().next()?.1, *id)))
+            .min()
+            .map(|(_, id)| id)
+    }
+
+    fn evict_quietest(&mut self, out: &mut Release) {
+        let Some(id) = self
+            .devices
+            .iter()
+            .min_by_key(|(_, d)| d.heard)
+            .map(|(id, _)| *id)
+        else {
+            return;
+        };
+        while !self.devices[&id].waiting.is_empty() {
+            self.skip(id, out);
+        }
+        self.devices.remove(&id);
+    }
+
+    /// Give up on the frames that have held others up for `timeout`.
+    pub fn poll(&mut self) -> Release {
+        let now = self.clock.now();
+        let mut out = Release::default();
+        let mut ids: Vec<_> = self.devices.keys().copied().collect();
+        ids.sort();
+        for id in ids {
+            while self.devices[&id]
+                .waiting
+                .values()
+                .next()
+                .is_some_and(|(_, at)| now >= *at + self.config.timeout)
+            {
+                self.skip(id, &mut out);
+            }
+        }
+        out
+    }
+
+    /// When [`ReorderBuffer::poll`] next has something to release.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.devices
+            .values()
+            .filter_map(|d| d.waiting.values().next())
+            .map(|(_, at)| *at + self.config.timeout)
+            .min()
+    }
+
+    /// Frames waiting, across all devices.
+    pub fn len(&self) -> usize {
+        self.waiting
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting == 0
+    }
+
+    /// Duplicates of waiting frames and, with [`WhenFull::DropNewest`],
+    /// frames that did not fit.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::transport::{PeerAddr, TransportId};
+
+    const DEV: [u8; 8] = *b"DEV00001";
+    const MS: Duration = Duration::from_millis(1);
+
+    fn rx(device_id: [u8; 8], counter: u64) -> RxFrame {
+        let mut f = crate::transport::tests::frame(counter);
+        f.header.device_id = device_id;
+        RxFrame::new(f, PeerAddr::Opaque("test".into()), TransportId::UNASSIGNED)
+    }
+
+    fn counters(release: &Release) -> Vec<u64> {
+        release.frames.iter().map(|f| f.header.counter).collect()
+    }
+
+    fn gap(from: u64, to: u64) -> MissingRange {
+        MissingRange {
+            device_id: DEV,
+            from,
+            to,
+        }
+    }
+
+    fn buffer(config: ReorderConfig) -> (ReorderBuffer, ManualClock) {
+        let clock = ManualClock::new();
+        (
+            ReorderBuffer::with_clock(config, Arc::new(clock.clone())),
+            clock,
+        )
+    }
+
+    #[test]
+    fn releases_in_order_and_gives_up_after_the_timeout() {
+        let (mut b, clock) = buffer(ReorderConfig {
+            timeout: 100 * MS,
+            ..ReorderConfig::default()
+        });
+        assert_eq!(counters(&b.push(rx(DEV, 10))), [10]);
+        assert!(counters(&b.push(rx(DEV, 12))).is_empty());
+        assert!(counters(&b.push(rx(DEV, 13))).is_empty());
+        assert!(counters(&b.push(rx(DEV, 12))).is_empty(), "duplicate");
+        assert_eq!((b.len(), b.dropped()), (2, 1));
+        assert_eq!(counters(&b.push(rx(DEV, 11))), [11, 12, 13]);
+        assert_eq!(counters(&b.push(rx(DEV, 12))), [12], "behind: passed on");
+
+        // 14 never comes
+        b.push(rx(DEV, 15));
+        clock.advance(50 * MS);
+        b.push(rx(DEV, 16));
+        assert_eq!(b.next_deadline(), Some(clock.now() + 50 * MS));
+        assert!(b.poll().frames.is_empty());
+        clock.advance(50 * MS);
+        let release = b.poll();
+        assert_eq!(counters(&release), [15, 16]);
+        assert_eq!(release.gaps, [gap(14, 14)]);
+        assert!(b.is_empty());
+        assert_eq!(counters(&b.push(rx(DEV, 14))), [14], "too late");
+
+        let mut ack = rx(DEV, 99);
+        ack.frame.header.msg_type = MsgType::Ack;
+        assert_eq!(counters(&b.push(ack)), [99], "not reordered");
+    }
+
+    #[test]
+    fn bounds_per_device_and_overall() {
+        let (mut b, clock) = buffer(ReorderConfig {
+            window: 2,
+            max_frames: 2,
+            ..ReorderConfig::default()
+        });
+        let other = *b"DEV00002";
+        b.push(rx(DEV, 0));
+        b.push(rx(DEV, 2));
+        b.push(rx(DEV, 4));
+        let release = b.push(rx(DEV, 5));
+        assert_eq!(counters(&release), [2], "gave up waiting for 1");
+        assert_eq!(release.gaps, [gap(1, 1)]);
+        clock.advance(MS);
+        b.push(rx(other, 0));
+        let release = b.push(rx(other, 7));
+        assert_eq!(counters(&release), [4, 5], "DEV had waited longest");
+        assert_eq!(release.gaps, [gap(3, 3)]);
+        assert_eq!(b.len(), 1);
+
+        let (mut b, _clock) = buffer(ReorderConfig {
+            window: 1,
+            when_full: WhenFull::DropNewest,
+            ..ReorderConfig::default()
+        });
+        b.push(rx(DEV, 0));
+        b.push(rx(DEV, 2));
+        assert!(counters(&b.push(rx(DEV, 3))).is_empty());
+        assert_eq!(b.dropped(), 1);
+        assert_eq!(counters(&b.push(rx(DEV, 1))), [1, 2]);
+    }
+
+    #[test]
+    fn orders_across_the_wrap() {
+        let (mut b, _clock) = buffer(ReorderConfig {
+            space: CounterSpace::Serial,
+            ..ReorderConfig::default()
+        });
+        b.push(rx(DEV, u64::MAX - 1));
+        b.push(rx(DEV, 0));
+        b.push(rx(DEV, 1));
+        assert_eq!(counters(&b.push(rx(DEV, u64::MAX))), [u64::MAX, 0, 1]);
+    }
+}
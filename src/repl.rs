@@ -0,0 +1,412 @@
+//! The command language of `pipproto repl`.
+//!
+//! A session works on one frame, kept as bytes so that it can be made wrong
+//! on purpose. [`parse_line`] reads a line into a [`ReplCommand`], and a
+//! [`Workbench`] applies its [`Edit`]s. Header fields are written where
+//! [`annotate`](crate::annotate) says they are, a frame too short for the
+//! field first growing to fit, with the magic and then zeros. Showing the
+//! frame, sending it and the files behind `save` and `load` are left to
+//! the caller; templates are the frame's bytes as hex
+//! ([`Workbench::template`]).
+
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::annotate::FIELDS;
+use crate::{
+    DecodeError, Flags, FrameHeaderV1, FrameV1, HEADER_LEN_V1, MAGIC, MsgType, VERSION_V1,
+};
+
+/// What `help` prints.
+pub const HELP: &str = "\
+set type event|command|ack|error|N   message type, or any byte
+set version N
+set flags ack|none|N                 ACK_REQUIRED, nothing, or any byte
+set device HEX                       16 hex digits
+set counter N
+set body [HEX | \"TEXT\"]              empty without an argument
+set byte OFFSET N                    one byte, anywhere
+truncate LEN                         cut the frame to LEN bytes
+reset                                back to an empty COMMAND
+show                                 the frame, byte by byte
+send HOST:PORT                       send it and show the reply
+save PATH | load PATH                the frame as a hex template
+history                              the lines entered so far
+help | quit";
+
+/// A change to the frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    Version(u8),
+    /// The type byte, which need not be a [`MsgType`].
+    Type(u8),
+    Flags(u8),
+    Device([u8; 8]),
+    Counter(u64),
+    Body(Vec<u8>),
+    Byte {
+        offset: usize,
+        value: u8,
+    },
+    Truncate(usize),
+    Reset,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplCommand {
+    Edit(Edit),
+    Show,
+    Send(String),
+    Save(PathBuf),
+    Load(PathBuf),
+    History,
+    Help,
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplError {
+    Unknown(String),
+    /// Missing or extra arguments; holds how the command goes.
+    Usage(&'static str),
+    BadValue {
+        what: &'static str,
+        value: String,
+    },
+}
+
+impl fmt::Display for ReplError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplError::Unknown(word) => write!(f, "unknown command {word:?}, try help"),
+            ReplError::Usage(usage) => write!(f, "usage: {usage}"),
+            ReplError::BadValue { what, value } => write!(f, "bad {what}: {value:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ReplError {}
+
+/// Decimal, or hex after `0x`.
+fn number<T>(what: &'static str, s: &str) -> Result<T, ReplError>
+where
+    T: TryFrom<u64>,
+{
+    let n = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(digits) => u64::from_str_radix(digits, 16).ok(),
+        None => s.parse().ok(),
+    };
+    n.and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| ReplError::BadValue {
+            what,
+            value: s.to_string(),
+        })
+}
+
+/// Hex digits, with any whitespace between them.
+fn hex_bytes(what: &'static str, s: &str) -> Result<Vec<u8>, ReplError> {
+    let digits: String = s.split_whitespace().collect();
+    crate::hex::decode(&digits).ok_or_else(|| ReplError::BadValue {
+        what,
+        value: s.to_string(),
+    })
+}
+
+fn parse_set(field: &str, value: &str) -> Result<Edit, ReplError> {
+    let one = |usage| match value.split_whitespace().count() {
+        1 => Ok(value),
+        _ => Err(ReplError::Usage(usage)),
+    };
+    Ok(match field {
+        "version" => Edit::Version(number("version", one("set version N")?)?),
+        "type" => {
+            let value = one("set type event|command|ack|error|N")?;
+            match (0..=u8::MAX)
+                .filter_map(MsgType::from_u8)
+                .find(|t| t.as_str() == value.to_ascii_lowercase())
+            {
+                Some(t) => Edit::Type(t as u8),
+                None => Edit::Type(number("type", value)?),
+            }
+        }
+        "flags" => match one("set flags ack|none|N")? {
+            "ack" => Edit::Flags(Flags::ACK_REQUIRED),
+            "none" => Edit::Flags(0),
+            bits => Edit::Flags(number("flags", bits)?),
+        },
+        "device" => {
+            let value = one("set device HEX")?;
+            let id = hex_bytes("device id", value)?;
+            Edit::Device(id.try_into().map_err(|_| ReplError::BadValue {
+                what: "device id, 16 hex digits",
+                value: value.to_string(),
+            })?)
+        }
+        "counter" => Edit::Counter(number("counter", one("set counter N")?)?),
+        "body" => match value.strip_prefix('"') {
+            Some(text) => match text.strip_suffix('"') {
+                Some(text) => Edit::Body(text.as_bytes().to_vec()),
+                None => {
+                    return Err(ReplError::BadValue {
+                        what: "body, no closing quote",
+                        value: value.to_string(),
+                    });
+                }
+            },
+            None => Edit::Body(hex_bytes("body", value)?),
+        },
+        "byte" => match value.split_whitespace().collect::<Vec<_>>()[..] {
+            [offset, byte] => Edit::Byte {
+                offset: number("offset", offset)?,
+                value: number("byte", byte)?,
+            },
+            _ => return Err(ReplError::Usage("set byte OFFSET N")),
+        },
+        _ => return Err(ReplError::Unknown(format!("set {field}"))),
+    })
+}
+
+/// The command on `line`, or `None` for a blank line or a `#` comment.
+pub fn parse_line(line: &str) -> Result<Option<ReplCommand>, ReplError> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim();
+    let bare = |command, usage| match rest {
+        "" => Ok(command),
+        _ => Err(ReplError::Usage(usage)),
+    };
+    let one = |usage| match rest.split_whitespace().count() {
+        1 => Ok(rest.to_string()),
+        _ => Err(ReplError::Usage(usage)),
+    };
+    let command = match word {
+        "set" => {
+            let (field, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            if field.is_empty() {
+                return Err(ReplError::Usage("set FIELD VALUE"));
+            }
+            ReplCommand::Edit(parse_set(field, value.trim())?)
+        }
+        "truncate" => ReplCommand::Edit(Edit::Truncate(number("length", &one("truncate LEN")?)?)),
+        "reset" => bare(ReplCommand::Edit(Edit::Reset), "reset")?,
+        "show" => bare(ReplCommand::Show, "show")?,
+        "send" => ReplCommand::Send(one("send HOST:PORT")?),
+        "save" => ReplCommand::Save(one("save PATH")?.into()),
+        "load" => ReplCommand::Load(one("load PATH")?.into()),
+        "history" => bare(ReplCommand::History, "history")?,
+        "help" | "?" => ReplCommand::Help,
+        "quit" | "exit" => bare(ReplCommand::Quit, "quit")?,
+        _ => return Err(ReplError::Unknown(word.to_string())),
+    };
+    Ok(Some(command))
+}
+
+/// The frame being worked on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Workbench {
+    bytes: Vec<u8>,
+}
+
+impl Default for Workbench {
+    /// A COMMAND with ACK_REQUIRED from device zero, counter 1, no body.
+    fn default() -> Self {
+        let header = FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Command,
+            flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+            device_id: [0; 8],
+            counter: 1,
+        };
+        Workbench::new(
+            FrameV1 {
+                header,
+                body: Vec::new(),
+            }
+            .encode(),
+        )
+    }
+}
+
+impl Workbench {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Workbench { bytes }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The frame, if the bytes are one.
+    pub fn frame(&self) -> Result<FrameV1, DecodeError> {
+        FrameV1::decode(&self.bytes)
+    }
+
+    /// Make the frame at least `len` bytes long.
+    fn grow(&mut self, len: usize) {
+        let from = self.bytes.len();
+        if from < len {
+            let magic = MAGIC.get(from..len.min(MAGIC.len())).unwrap_or_default();
+            self.bytes.extend_from_slice(magic);
+            self.bytes.resize(len, 0);
+        }
+    }
+
+    /// Overwrite the header field called `name`.
+    fn write(&mut self, name: &str, value: &[u8]) {
+        let (_, range) = FIELDS.iter().find(|(n, _)| *n == name).unwrap();
+        self.grow(range.end);
+        self.bytes[range.clone()].copy_from_slice(value);
+    }
+
+    pub fn apply(&mut self, edit: &Edit) {
+        match edit {
+            Edit::Version(v) => self.write("version", &[*v]),
+            Edit::Type(t) => self.write("type", &[*t]),
+            Edit::Flags(bits) => self.write("flags", &[*bits]),
+            Edit::Device(id) => self.write("device_id", id),
+            Edit::Counter(c) => self.write("counter", &c.to_be_bytes()),
+            Edit::Body(body) => {
+                self.grow(HEADER_LEN_V1);
+                self.bytes.truncate(HEADER_LEN_V1);
+                self.bytes.extend_from_slice(body);
+            }
+            Edit::Byte { offset, value } => {
+                self.grow(offset + 1);
+                self.bytes[*offset] = *value;
+            }
+            Edit::Truncate(len) => self.bytes.truncate(*len),
+            Edit::Reset => *self = Workbench::default(),
+        }
+    }
+
+    /// The bytes as a template file: a comment, then hex.
+    pub fn template(&self) -> String {
+        format!(
+            "# pipproto frame template, {} bytes\n{}\n",
+            self.bytes.len(),
+            crate::hex::encode(&self.bytes)
+        )
+    }
+
+    /// Read a [`template`](Self::template): hex, ignoring whitespace and
+    /// `#` comment lines.
+    pub fn from_template(text: &str) -> Result<Self, ReplError> {
+        let hex: String = text
+            .lines()
+            .filter(|l| !l.trim_start().starts_with('#'))
+            .collect();
+        hex_bytes("template", &hex).map(Workbench::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(line: &str) -> Edit {
+        match parse_line(line) {
+            Ok(Some(ReplCommand::Edit(e))) => e,
+            other => panic!("{line:?} gave {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(edit("set type ack"), Edit::Type(MsgType::Ack as u8));
+        assert_eq!(edit("set type 0x7f"), Edit::Type(0x7f));
+        assert_eq!(edit("set flags ack"), Edit::Flags(1));
+        assert_eq!(edit("  set counter 42 "), Edit::Counter(42));
+        assert_eq!(
+            edit("set device 0011223344556677"),
+            Edit::Device([0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77])
+        );
+        assert_eq!(edit("set body de ad"), Edit::Body(vec![0xde, 0xad]));
+        assert_eq!(edit("set body \"a b\""), Edit::Body(b"a b".to_vec()));
+        assert_eq!(edit("set body"), Edit::Body(Vec::new()));
+        assert_eq!(
+            edit("set byte 3 0xff"),
+            Edit::Byte {
+                offset: 3,
+                value: 0xff
+            }
+        );
+        assert_eq!(
+            parse_line("send 127.0.0.1:4000"),
+            Ok(Some(ReplCommand::Send("127.0.0.1:4000".into())))
+        );
+        assert_eq!(parse_line("# note"), Ok(None));
+        assert_eq!(parse_line(""), Ok(None));
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(
+            parse_line("frobnicate"),
+            Err(ReplError::Unknown("frobnicate".into()))
+        );
+        assert_eq!(
+            parse_line("set counter"),
+            Err(ReplError::Usage("set counter N"))
+        );
+        assert!(matches!(
+            parse_line("set flags 256"),
+            Err(ReplError::BadValue { what: "flags", .. })
+        ));
+        assert!(matches!(
+            parse_line("set device 0011"),
+            Err(ReplError::BadValue { .. })
+        ));
+        assert_eq!(parse_line("show me"), Err(ReplError::Usage("show")));
+        assert!(parse_line("set colour red").is_err());
+    }
+
+    #[test]
+    fn edits_the_bytes() {
+        let mut bench = Workbench::default();
+        for line in [
+            "set type event",
+            "set device 0102030405060708",
+            "set counter 7",
+            "set flags none",
+            "set body \"hi\"",
+        ] {
+            bench.apply(&edit(line));
+        }
+        let frame = bench.frame().unwrap();
+        assert_eq!(frame.header.msg_type, MsgType::Event);
+        assert_eq!(frame.header.device_id, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(frame.header.counter, 7);
+        assert_eq!(frame.body, b"hi");
+
+        bench.apply(&edit("set byte 4 0x80"));
+        assert!(matches!(
+            bench.frame(),
+            Err(DecodeError::ReservedFlags(0x80))
+        ));
+
+        bench.apply(&edit("truncate 4"));
+        assert_eq!(bench.bytes().len(), 4);
+        bench.apply(&edit("set counter 1"));
+        assert_eq!(bench.bytes().len(), HEADER_LEN_V1);
+        assert_eq!(bench.bytes()[..5], [b'P', b'P', VERSION_V1, 1, 0]);
+
+        let mut empty = Workbench::new(Vec::new());
+        empty.apply(&edit("set version 2"));
+        assert_eq!(empty.bytes(), [b'P', b'P', 2]);
+
+        bench.apply(&Edit::Reset);
+        assert_eq!(bench, Workbench::default());
+    }
+
+    #[test]
+    fn template_roundtrip() {
+        let mut bench = Workbench::default();
+        bench.apply(&edit("set body 00ff"));
+        let back = Workbench::from_template(&bench.template()).unwrap();
+        assert_eq!(back, bench);
+        assert!(Workbench::from_template("# empty\nzz\n").is_err());
+    }
+}
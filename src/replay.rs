@@ -0,0 +1,290 @@
+//! Per-device replay protection (RFC section 8).
+//!
+//! Each device gets a fixed-size sliding window: counters above the
+//! highest seen are accepted, as are counters within `window` behind it
+//! that have not been seen yet. Duplicates and anything older are
+//! rejected. With `window` 0 this is the strict rule of RFC section 8.
+//!
+//! Counters are ordered in the configured [`CounterSpace`]; use
+//! [`CounterSpace::Serial`] for senders that wrap.
+//!
+//! v1 has no session id, so windows are keyed by device id alone.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::counter::CounterSpace;
+use crate::snapshot::{Rebase, ReplayEntry};
+
+/// Widest supported window; with the highest counter itself that makes
+/// 128 bits of state per device.
+pub const MAX_WINDOW: u32 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// Already accepted once.
+    Duplicate,
+    /// Older than the window, so it can't be told apart from a replay.
+    TooOld,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    /// Counters accepted behind the highest seen, at most [`MAX_WINDOW`].
+    pub window: u32,
+    /// Devices tracked at once; the least recently seen is evicted.
+    pub max_devices: usize,
+    pub space: CounterSpace,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig {
+            window: 64,
+            max_devices: 10_000,
+            space: CounterSpace::Linear,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Device {
+    top: u64,
+    /// Bit `i` is set if `top - i` has been seen.
+    seen: u128,
+    last_seen: Instant,
+}
+
+pub struct ReplayWindow {
+    clock: Arc<dyn Clock>,
+    window: u32,
+    max_devices: usize,
+    space: CounterSpace,
+    devices: HashMap<[u8; 8], Device>,
+}
+
+impl ReplayWindow {
+    pub fn new(config: ReplayConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: ReplayConfig, clock: Arc<dyn Clock>) -> Self {
+        ReplayWindow {
+            clock,
+            window: config.window.min(MAX_WINDOW),
+            max_devices: config.max_devices.max(1),
+            space: config.space,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Check `counter` from `device_id`, and remember it if accepted. The
+    /// first counter seen from a device is always accepted.
+    pub fn check(&mut self, device_id: [u8; 8], counter: u64) -> Verdict {
+        let now = self.clock.now();
+        let Some(d) = self.devices.get_mut(&device_id) else {
+            if self.devices.len() >= self.max_devices {
+                self.evict_oldest();
+            }
+            let device = Device {
+                top: counter,
+                seen: 1,
+                last_seen: now,
+            };
+            self.devices.insert(device_id, device);
+            return Verdict::Accept;
+        };
+
+        let verdict = if let Some(shift) = self.space.ahead(d.top, counter) {
+            d.seen = if shift >= u128::BITS as u64 {
+                0
+            } else {
+                d.seen << shift
+            };
+            d.seen |= 1;
+            d.top = counter;
+            Verdict::Accept
+        } else {
+            match self.space.behind(d.top, counter) {
+                Some(age) if age <= self.window as u64 => {
+                    if d.seen & (1 << age) != 0 {
+                        Verdict::Duplicate
+                    } else {
+                        d.seen |= 1 << age;
+                        Verdict::Accept
+                    }
+                }
+                _ => Verdict::TooOld,
+            }
+        };
+        if verdict == Verdict::Accept {
+            d.last_seen = now;
+        }
+        verdict
+    }
+
+    /// Highest counter accepted from `device_id`.
+    pub fn highest(&self, device_id: [u8; 8]) -> Option<u64> {
+        self.devices.get(&device_id).map(|d| d.top)
+    }
+
+    /// Drop a departed device; its next frame starts a new window.
+    pub fn forget(&mut self, device_id: [u8; 8]) -> bool {
+        self.devices.remove(&device_id).is_some()
+    }
+
+    /// Drop devices with nothing accepted for `max_idle`. Returns how many.
+    pub fn evict_idle(&mut self, max_idle: Duration) -> usize {
+        let now = self.clock.now();
+        let before = self.devices.len();
+        self.devices
+            .retain(|_, d| now.duration_since(d.last_seen) < max_idle);
+        before - self.devices.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub(crate) fn export(&self, now: Instant) -> Vec<ReplayEntry> {
+        let mut entries: Vec<_> = self
+            .devices
+            .iter()
+            .map(|(&device_id, d)| ReplayEntry {
+                device_id,
+                top: d.top,
+                seen: d.seen,
+                idle: now.saturating_duration_since(d.last_seen),
+            })
+            .collect();
+        entries.sort_by_key(|e| e.device_id);
+        entries
+    }
+
+    /// Take in windows from a snapshot, replacing any held for the same
+    /// devices.
+    pub(crate) fn import(&mut self, entries: Vec<ReplayEntry>, rebase: Rebase) {
+        for e in entries {
+            if !self.devices.contains_key(&e.device_id) && self.devices.len() >= self.max_devices {
+                self.evict_oldest();
+            }
+            let device = Device {
+                top: e.top,
+                seen: e.seen,
+                last_seen: rebase.ago(e.idle),
+            };
+            self.devices.insert(e.device_id, device);
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .devices
+            .iter()
+            .min_by_key(|(_, d)| d.last_seen)
+            .map(|(&id, _)| id);
+        if let Some(id) = oldest {
+            self.devices.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const DEV: [u8; 8] = *b"DEV00001";
+
+    fn window(window: u32) -> ReplayWindow {
+        ReplayWindow::new(ReplayConfig {
+            window,
+            ..ReplayConfig::default()
+        })
+    }
+
+    #[test]
+    fn out_of_order_inside_window() {
+        let mut w = window(8);
+        assert_eq!(w.check(DEV, 10), Verdict::Accept);
+        assert_eq!(w.check(DEV, 14), Verdict::Accept);
+        assert_eq!(w.check(DEV, 12), Verdict::Accept);
+        assert_eq!(w.check(DEV, 12), Verdict::Duplicate);
+        assert_eq!(w.check(DEV, 14), Verdict::Duplicate);
+        assert_eq!(w.check(DEV, 6), Verdict::Accept);
+        assert_eq!(w.check(DEV, 5), Verdict::TooOld);
+        assert_eq!(w.check(*b"DEV00002", 1), Verdict::Accept);
+        assert_eq!(w.highest(DEV), Some(14));
+    }
+
+    #[test]
+    fn strict_window_matches_rfc() {
+        let mut w = window(0);
+        assert_eq!(w.check(DEV, 3), Verdict::Accept);
+        assert_eq!(w.check(DEV, 3), Verdict::Duplicate);
+        assert_eq!(w.check(DEV, 2), Verdict::TooOld);
+        assert_eq!(w.check(DEV, 4), Verdict::Accept);
+    }
+
+    #[test]
+    fn u64_boundary() {
+        let mut w = window(MAX_WINDOW);
+        assert_eq!(w.check(DEV, u64::MAX - 200), Verdict::Accept);
+        assert_eq!(w.check(DEV, u64::MAX), Verdict::Accept);
+        assert_eq!(w.check(DEV, u64::MAX - 200), Verdict::TooOld);
+        assert_eq!(w.check(DEV, u64::MAX - 128), Verdict::TooOld);
+        assert_eq!(w.check(DEV, u64::MAX - 127), Verdict::Accept);
+        assert_eq!(w.check(DEV, u64::MAX - 1), Verdict::Accept);
+        assert_eq!(w.check(DEV, u64::MAX), Verdict::Duplicate);
+        // a counter that wrapped around is indistinguishable from a replay
+        assert_eq!(w.check(DEV, 0), Verdict::TooOld);
+    }
+
+    #[test]
+    fn serial_space_accepts_across_the_wrap() {
+        let mut w = ReplayWindow::new(ReplayConfig {
+            window: 8,
+            space: CounterSpace::Serial,
+            ..ReplayConfig::default()
+        });
+        assert_eq!(w.check(DEV, u64::MAX - 1), Verdict::Accept);
+        assert_eq!(w.check(DEV, 1), Verdict::Accept);
+        assert_eq!(w.check(DEV, u64::MAX), Verdict::Accept);
+        assert_eq!(w.check(DEV, 0), Verdict::Accept);
+        assert_eq!(w.check(DEV, u64::MAX - 1), Verdict::Duplicate);
+        assert_eq!(w.check(DEV, u64::MAX - 7), Verdict::TooOld);
+        assert_eq!(w.highest(DEV), Some(1));
+        // half the space away is unordered, so never accepted
+        assert_eq!(w.check(DEV, 1 + (1 << 63)), Verdict::TooOld);
+    }
+
+    #[test]
+    fn bounded_and_evictable() {
+        let clock = ManualClock::new();
+        let config = ReplayConfig {
+            window: 8,
+            max_devices: 2,
+            ..ReplayConfig::default()
+        };
+        let mut w = ReplayWindow::with_clock(config, Arc::new(clock.clone()));
+        w.check(*b"DEV00001", 1);
+        clock.advance(Duration::from_secs(1));
+        w.check(*b"DEV00002", 1);
+        w.check(*b"DEV00003", 1);
+        assert_eq!(w.len(), 2);
+        assert_eq!(w.highest(*b"DEV00001"), None);
+
+        clock.advance(Duration::from_secs(10));
+        w.check(*b"DEV00003", 2);
+        assert_eq!(w.evict_idle(Duration::from_secs(5)), 1);
+        assert!(w.forget(*b"DEV00003"));
+        assert!(w.is_empty());
+    }
+}
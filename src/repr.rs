@@ -0,0 +1,188 @@
+//! serde representation (feature `serde`).
+//!
+//! Frames serialize flat, with hex device ids and base64 bodies:
+//!
+//! ```json
+//! {"version":1,"msg_type":"event","flags":{"ack_required":true},
+//!  "device_id":"deadbeef00000001","counter":7,"body":"aGk="}
+//! ```
+//!
+//! A document may give the body as hex in `body_hex` instead. Deserializing
+//! enforces the same invariants as [`FrameV1::decode`], so a document that
+//! parses always encodes to a frame that decodes.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+use crate::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlagsRepr {
+    #[serde(default)]
+    ack_required: bool,
+}
+
+impl Serialize for Flags {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        FlagsRepr {
+            ack_required: self.ack_required(),
+        }
+        .serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for Flags {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let repr = FlagsRepr::deserialize(d)?;
+        let mut bits = 0;
+        if repr.ack_required {
+            bits |= Flags::ACK_REQUIRED;
+        }
+        Flags::new(bits).map_err(de::Error::custom)
+    }
+}
+
+fn default_version() -> u8 {
+    VERSION_V1
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FrameRepr {
+    #[serde(default = "default_version")]
+    version: u8,
+    msg_type: MsgType,
+    #[serde(default)]
+    flags: Flags,
+    device_id: String,
+    counter: u64,
+    #[serde(default)]
+    body: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_hex: Option<String>,
+}
+
+impl Serialize for FrameV1 {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        FrameRepr {
+            version: self.header.version,
+            msg_type: self.header.msg_type,
+            flags: self.header.flags,
+            device_id: self.header.device_id_hex(),
+            counter: self.header.counter,
+            body: BASE64.encode(&self.body),
+            body_hex: None,
+        }
+        .serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for FrameV1 {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let repr = FrameRepr::deserialize(d)?;
+        if repr.version != VERSION_V1 {
+            return Err(de::Error::custom(format_args!(
+                "unsupported version: {}",
+                repr.version
+            )));
+        }
+        let device_id = crate::hex::decode(&repr.device_id)
+            .and_then(|v| <[u8; 8]>::try_from(v).ok())
+            .ok_or_else(|| de::Error::custom("device_id must be 16 hex digits"))?;
+        let body = match repr.body_hex {
+            Some(_) if !repr.body.is_empty() => {
+                return Err(de::Error::custom("give body or body_hex, not both"));
+            }
+            Some(hex) => {
+                crate::hex::decode(&hex).ok_or_else(|| de::Error::custom("body_hex is not hex"))?
+            }
+            None => BASE64
+                .decode(&repr.body)
+                .map_err(|e| de::Error::custom(format_args!("body is not base64: {e}")))?,
+        };
+
+        Ok(FrameV1 {
+            header: FrameHeaderV1 {
+                version: repr.version,
+                msg_type: repr.msg_type,
+                flags: repr.flags,
+                device_id,
+                counter: repr.counter,
+            },
+            body: body.into(),
+        })
+    }
+}
+
+/// A device id as 16 hex digits, for `serialize_with`.
+pub(crate) fn device_id_hex<S: Serializer>(device_id: &[u8; 8], s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&crate::hex::encode(device_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> FrameV1 {
+        FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Event,
+                flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+                device_id: [0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 1],
+                counter: 7,
+            },
+            body: b"hi".into(),
+        }
+    }
+
+    #[test]
+    fn json_shape() {
+        let json = serde_json::to_string(&sample()).unwrap();
+        assert_eq!(
+            json,
+            r#"{"version":1,"msg_type":"event","flags":{"ack_required":true},"device_id":"deadbeef00000001","counter":7,"body":"aGk="}"#
+        );
+        let back: FrameV1 = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, sample());
+    }
+
+    #[test]
+    fn defaults_for_optional_fields() {
+        let f: FrameV1 = serde_json::from_str(
+            r#"{"msg_type":"ack","device_id":"0000000000000001","counter":1}"#,
+        )
+        .unwrap();
+        assert_eq!(f.header.version, VERSION_V1);
+        assert!(!f.header.flags.ack_required());
+        assert!(f.body.is_empty());
+    }
+
+    #[test]
+    fn body_as_hex() {
+        let f: FrameV1 = serde_json::from_str(
+            r#"{"msg_type":"event","flags":{"ack_required":true},"device_id":"deadbeef00000001","counter":7,"body_hex":"6869"}"#,
+        )
+        .unwrap();
+        assert_eq!(f, sample());
+    }
+
+    #[test]
+    fn reject_impossible_frames() {
+        for doc in [
+            r#"{"version":2,"msg_type":"event","device_id":"0000000000000001","counter":1}"#,
+            r#"{"msg_type":"nope","device_id":"0000000000000001","counter":1}"#,
+            r#"{"msg_type":"event","device_id":"0001","counter":1}"#,
+            r#"{"msg_type":"event","device_id":"0000000000000001","counter":1,"body":"!"}"#,
+            r#"{"msg_type":"event","device_id":"0000000000000001","counter":1,"body_hex":"6"}"#,
+            r#"{"msg_type":"event","device_id":"0000000000000001","counter":1,"body":"aGk=","body_hex":"6869"}"#,
+            r#"{"msg_type":"event","flags":{"urgent":true},"device_id":"0000000000000001","counter":1}"#,
+        ] {
+            assert!(serde_json::from_str::<FrameV1>(doc).is_err(), "{doc}");
+        }
+    }
+}
@@ -0,0 +1,428 @@
+//! Sending a COMMAND and waiting for its answer.
+//!
+//! [`Requester::send_command`] sends the command with ACK_REQUIRED and
+//! resolves when an ACK or ERROR frame repeating its device id and counter
+//! comes back, or when the timeout passes. Replies reach the requester
+//! through [`Requester::on_frame`]: feed it every frame received from the
+//! peer. Dropping a pending `send_command` forgets the request, and a
+//! reply that turns up later is ignored.
+//!
+//! An ERROR frame's body starts with a big-endian u16 error code; see
+//! [`error_reply`].
+//!
+//! If the sender has a [`CircuitBreaker`](crate::breaker::CircuitBreaker),
+//! each answer, nack, ERROR frame or timeout is reported to it. If it has
+//! a [`SendWindow`](crate::window::SendWindow), the command's slot goes
+//! back when it is answered, times out or is given up on.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+
+use crate::ack::{AckBody, ack_frame};
+use crate::breaker::Failure;
+use crate::sender::{CounterMap, CounterSource, FrameSender, SendError};
+use crate::transport::{Transport, TransportError};
+use crate::window::{Priority, SendWindow};
+use crate::{Flags, FrameHeaderV1, FrameId, FrameV1, MsgType, VERSION_V1};
+
+#[derive(Debug, Clone)]
+pub struct RequestConfig {
+    pub timeout: Duration,
+    /// Commands awaiting an answer across all devices.
+    pub max_in_flight: usize,
+}
+
+impl Default for RequestConfig {
+    fn default() -> Self {
+        RequestConfig {
+            timeout: Duration::from_secs(5),
+            max_in_flight: 64,
+        }
+    }
+}
+
+/// A successful answer to a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Ack,
+    /// An ACK carrying [`AckBody::Reply`] data.
+    Data(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// `max_in_flight` commands are already waiting.
+    Full,
+    Send(SendError<TransportError>),
+    Nacked(String),
+    /// The peer answered with an ERROR frame carrying this code.
+    ErrorFrame(u16),
+    Timeout,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Full => write!(f, "too many requests in flight"),
+            RequestError::Send(e) => write!(f, "{e}"),
+            RequestError::Nacked(reason) => write!(f, "nacked: {reason}"),
+            RequestError::ErrorFrame(code) => write!(f, "peer error {code:#06x}"),
+            RequestError::Timeout => write!(f, "no reply in time"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+type Answer = Result<Reply, RequestError>;
+
+#[derive(Default)]
+struct Pending {
+    waiting: HashMap<FrameId, oneshot::Sender<Answer>>,
+    /// Calls holding a [`Slot`], some of which may not be numbered yet.
+    in_flight: usize,
+}
+
+/// One request's claim on `max_in_flight`, released on drop however the
+/// call ends.
+struct Slot<'a> {
+    pending: &'a Mutex<Pending>,
+    id: Option<FrameId>,
+    /// The window the command took a slot of once it went out, to give
+    /// back unless an answer already did.
+    window: Option<&'a SendWindow>,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut p = lock(self.pending);
+        p.in_flight -= 1;
+        if let Some(id) = self.id
+            && p.waiting.remove(&id).is_some()
+            && let Some(w) = self.window
+        {
+            w.release(id.device_id);
+        }
+    }
+}
+
+fn lock(pending: &Mutex<Pending>) -> MutexGuard<'_, Pending> {
+    pending.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Commands over a [`FrameSender`], matched to their replies. Clones
+/// share the sender and the pending requests.
+pub struct Requester<T, C = CounterMap> {
+    sender: FrameSender<T, C>,
+    config: RequestConfig,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl<T, C> Clone for Requester<T, C> {
+    fn clone(&self) -> Self {
+        Requester {
+            sender: self.sender.clone(),
+            config: self.config.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<T: Transport, C: CounterSource> Requester<T, C> {
+    pub fn new(sender: FrameSender<T, C>, config: RequestConfig) -> Self {
+        Requester {
+            sender,
+            config,
+            pending: Arc::default(),
+        }
+    }
+
+    pub async fn send_command(&self, device_id: [u8; 8], body: Vec<u8>) -> Answer {
+        self.send_request(MsgType::Command, device_id, body).await
+    }
+
+    /// [`Requester::send_command`] for a frame of any type.
+    pub async fn send_request(
+        &self,
+        msg_type: MsgType,
+        device_id: [u8; 8],
+        body: Vec<u8>,
+    ) -> Answer {
+        let mut slot = self.reserve()?;
+        let (tx, rx) = oneshot::channel();
+        let pending = &*self.pending;
+        self.sender
+            .send_numbered(
+                Priority::Normal,
+                msg_type,
+                Flags::new(Flags::ACK_REQUIRED).unwrap(),
+                device_id,
+                body,
+                |id| {
+                    lock(pending).waiting.insert(id, tx);
+                    slot.id = Some(id);
+                },
+            )
+            .await
+            .map_err(RequestError::Send)?;
+        slot.window = self.sender.window();
+        let answer = match tokio::time::timeout(self.config.timeout, rx).await {
+            Ok(Ok(answer)) => answer,
+            _ => Err(RequestError::Timeout),
+        };
+        if let Some(breaker) = self.sender.breaker() {
+            match &answer {
+                Ok(_) => breaker.success(device_id),
+                Err(RequestError::Nacked(_)) => breaker.failure(device_id, Failure::Nack),
+                Err(RequestError::ErrorFrame(_)) => breaker.failure(device_id, Failure::ErrorFrame),
+                Err(RequestError::Timeout) => breaker.failure(device_id, Failure::Timeout),
+                Err(_) => {}
+            }
+        }
+        answer
+    }
+
+    fn reserve(&self) -> Result<Slot<'_>, RequestError> {
+        let mut p = lock(&self.pending);
+        if p.in_flight >= self.config.max_in_flight {
+            return Err(RequestError::Full);
+        }
+        p.in_flight += 1;
+        Ok(Slot {
+            pending: &self.pending,
+            id: None,
+            window: None,
+        })
+    }
+
+    /// Hand a received frame to the request it answers. Returns whether
+    /// it answered one; anything else is left to the caller.
+    pub fn on_frame(&self, frame: &FrameV1) -> bool {
+        let answer = match frame.header.msg_type {
+            MsgType::Ack => match AckBody::decode(&frame.body) {
+                Ok(AckBody::Single) => Ok(Reply::Ack),
+                Ok(AckBody::Reply(data)) => Ok(Reply::Data(data)),
+                Ok(AckBody::Nack { reason }) => Err(RequestError::Nacked(reason)),
+                _ => return false,
+            },
+            MsgType::Error => Err(RequestError::ErrorFrame(error_code(&frame.body))),
+            _ => return false,
+        };
+        let Some(tx) = lock(&self.pending).waiting.remove(&frame.header.id()) else {
+            return false;
+        };
+        if let Some(w) = self.sender.window() {
+            w.release(frame.header.device_id);
+        }
+        // the caller may have given up since
+        let _ = tx.send(answer);
+        true
+    }
+
+    /// Commands sent or being sent and not yet answered.
+    pub fn in_flight(&self) -> usize {
+        lock(&self.pending).in_flight
+    }
+}
+
+fn error_code(body: &[u8]) -> u16 {
+    match body {
+        [hi, lo, ..] => u16::from_be_bytes([*hi, *lo]),
+        _ => 0,
+    }
+}
+
+/// The answer to `command`, for the peer handling it.
+pub fn ack_reply(command: &FrameHeaderV1, body: AckBody) -> FrameV1 {
+    ack_frame(command.device_id, command.counter, body)
+}
+
+/// An ERROR frame answering `command` with `code`, then `detail`.
+pub fn error_reply(command: &FrameHeaderV1, code: u16, detail: &[u8]) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Error,
+            flags: Flags::new(0).unwrap(),
+            device_id: command.device_id,
+            counter: command.counter,
+        },
+        body: [&code.to_be_bytes(), detail].concat().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{LoopbackTransport, loopback_pair};
+
+    const DEV: [u8; 8] = *b"DEV00001";
+
+    struct Rig {
+        requester: Requester<LoopbackTransport>,
+        /// The device's end of the command link.
+        device: LoopbackTransport,
+        /// Replies travel back over a second link.
+        reply_tx: LoopbackTransport,
+        reply_rx: LoopbackTransport,
+    }
+
+    fn rig(config: RequestConfig) -> Rig {
+        let (host, device) = loopback_pair();
+        let (reply_tx, reply_rx) = loopback_pair();
+        let sender = FrameSender::new(host, Arc::new(CounterMap::default()));
+        Rig {
+            requester: Requester::new(sender, config),
+            device,
+            reply_tx,
+            reply_rx,
+        }
+    }
+
+    impl Rig {
+        /// Answer the next command with whatever `answer` builds from it.
+        async fn answer(&mut self, answer: impl FnOnce(&FrameHeaderV1) -> FrameV1) -> bool {
+            let command = self.device.recv().await.unwrap();
+            assert_eq!(command.header.msg_type, MsgType::Command);
+            assert!(command.header.flags.ack_required());
+            self.reply_tx.send(&answer(&command.header)).await.unwrap();
+            self.requester
+                .on_frame(&self.reply_rx.recv().await.unwrap())
+        }
+    }
+
+    async fn call(rig: &mut Rig, answer: impl FnOnce(&FrameHeaderV1) -> FrameV1) -> Answer {
+        let requester = rig.requester.clone();
+        let call = tokio::spawn(async move { requester.send_command(DEV, vec![7]).await });
+        assert!(rig.answer(answer).await);
+        let answer = call.await.unwrap();
+        assert_eq!(rig.requester.in_flight(), 0);
+        answer
+    }
+
+    #[tokio::test]
+    async fn every_kind_of_answer() {
+        let mut rig = rig(RequestConfig::default());
+        let ack = call(&mut rig, |c| ack_reply(c, AckBody::Single)).await;
+        assert_eq!(ack.unwrap(), Reply::Ack);
+
+        let data = call(&mut rig, |c| ack_reply(c, AckBody::Reply(vec![4, 2]))).await;
+        assert_eq!(data.unwrap(), Reply::Data(vec![4, 2]));
+
+        let nack = call(&mut rig, |c| {
+            ack_reply(
+                c,
+                AckBody::Nack {
+                    reason: "door open".into(),
+                },
+            )
+        })
+        .await;
+        assert!(matches!(nack, Err(RequestError::Nacked(r)) if r == "door open"));
+
+        let error = call(&mut rig, |c| error_reply(c, 0x0102, b"jammed")).await;
+        assert!(matches!(error, Err(RequestError::ErrorFrame(0x0102))));
+    }
+
+    #[tokio::test]
+    async fn events_can_be_requests_too() {
+        let Rig {
+            requester, device, ..
+        } = rig(RequestConfig::default());
+        let call = tokio::spawn({
+            let requester = requester.clone();
+            async move { requester.send_request(MsgType::Event, DEV, vec![1]).await }
+        });
+        let mut device = device;
+        let event = device.recv().await.unwrap();
+        assert_eq!(event.header.msg_type, MsgType::Event);
+        assert!(event.header.flags.ack_required());
+        assert!(requester.on_frame(&ack_reply(&event.header, AckBody::Single)));
+        assert_eq!(call.await.unwrap().unwrap(), Reply::Ack);
+    }
+
+    #[tokio::test]
+    async fn repeated_nacks_open_the_circuit() {
+        use crate::breaker::{BreakerConfig, CircuitBreaker, CircuitState};
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            threshold: 2,
+            ..BreakerConfig::default()
+        });
+        let mut rig = rig(RequestConfig::default());
+        rig.requester.sender = rig.requester.sender.with_breaker(breaker.clone());
+        let nack = |c: &FrameHeaderV1| {
+            ack_reply(
+                c,
+                AckBody::Nack {
+                    reason: "busy".into(),
+                },
+            )
+        };
+        assert!(call(&mut rig, nack).await.is_err());
+        assert_eq!(breaker.state(DEV), CircuitState::Closed);
+        assert!(call(&mut rig, nack).await.is_err());
+        assert_eq!(breaker.state(DEV), CircuitState::Open);
+        let refused = rig.requester.send_command(DEV, Vec::new()).await;
+        assert!(matches!(
+            refused,
+            Err(RequestError::Send(SendError::CircuitOpen(_)))
+        ));
+        assert_eq!(rig.requester.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn times_out_without_a_reply() {
+        let mut rig = rig(RequestConfig {
+            timeout: Duration::from_millis(20),
+            max_in_flight: 4,
+        });
+        let answer = rig.requester.send_command(DEV, Vec::new()).await;
+        assert!(matches!(answer, Err(RequestError::Timeout)));
+        assert_eq!(rig.requester.in_flight(), 0);
+
+        // the late reply finds nobody waiting
+        let command = rig.device.recv().await.unwrap();
+        let late = ack_reply(&command.header, AckBody::Single);
+        assert!(!rig.requester.on_frame(&late));
+    }
+
+    #[tokio::test]
+    async fn cancelled_request_is_forgotten() {
+        let mut rig = rig(RequestConfig::default());
+        let requester = rig.requester.clone();
+        let call = tokio::spawn(async move { requester.send_command(DEV, Vec::new()).await });
+        let command = rig.device.recv().await.unwrap();
+        assert_eq!(rig.requester.in_flight(), 1);
+
+        call.abort();
+        assert!(call.await.unwrap_err().is_cancelled());
+        assert_eq!(rig.requester.in_flight(), 0);
+        assert!(
+            !rig.requester
+                .on_frame(&ack_reply(&command.header, AckBody::Single))
+        );
+    }
+
+    #[tokio::test]
+    async fn caps_requests_in_flight() {
+        let mut rig = rig(RequestConfig {
+            timeout: Duration::from_secs(5),
+            max_in_flight: 1,
+        });
+        let requester = rig.requester.clone();
+        let first = tokio::spawn(async move { requester.send_command(DEV, Vec::new()).await });
+        let command = rig.device.recv().await.unwrap();
+        let second = rig.requester.send_command(DEV, Vec::new()).await;
+        assert!(matches!(second, Err(RequestError::Full)));
+
+        assert!(
+            rig.requester
+                .on_frame(&ack_reply(&command.header, AckBody::Single))
+        );
+        assert_eq!(first.await.unwrap().unwrap(), Reply::Ack);
+    }
+}
@@ -0,0 +1,197 @@
+//! When to resend a frame that has not been acked.
+//!
+//! A [`RetryPolicy`] gives the wait after each send of a frame before the
+//! next one, or says to stop resending. An
+//! [`AckTracker`](crate::ack::AckTracker) takes one for all frames, per
+//! message type or per frame; after the last send it waits its
+//! `timeout` before giving up.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::sim::{SPLITMIX_GAMMA, splitmix64};
+
+pub trait RetryPolicy: fmt::Debug + Send + Sync {
+    /// The wait after send number `attempt`, the first being 1, before
+    /// sending again; `None` to send no more.
+    fn next_delay(&self, attempt: u32) -> Option<Duration>;
+}
+
+/// The same wait every time, for `max_attempts` sends in all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixedInterval {
+    pub interval: Duration,
+    pub max_attempts: u32,
+}
+
+impl RetryPolicy for FixedInterval {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        (attempt < self.max_attempts).then_some(self.interval)
+    }
+}
+
+/// Never resend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NoRetry;
+
+impl RetryPolicy for NoRetry {
+    fn next_delay(&self, _attempt: u32) -> Option<Duration> {
+        None
+    }
+}
+
+/// `base`, then `factor` times the wait before, up to `max`, each wait
+/// spread by up to `jitter` of itself either way.
+#[derive(Debug)]
+pub struct ExponentialBackoff {
+    pub base: Duration,
+    pub factor: f64,
+    pub max: Duration,
+    /// Between 0 and 1.
+    pub jitter: f64,
+    /// Sends in all; unlimited by default.
+    pub max_attempts: Option<u32>,
+    rng: AtomicU64,
+}
+
+impl ExponentialBackoff {
+    pub fn new(base: Duration, factor: f64, max: Duration) -> Self {
+        ExponentialBackoff {
+            base,
+            factor,
+            max,
+            jitter: 0.0,
+            max_attempts: None,
+            rng: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Seed the jitter, for a schedule that repeats.
+    pub fn with_seed(self, seed: u64) -> Self {
+        self.rng.store(seed, Ordering::Relaxed);
+        self
+    }
+
+    /// Uniform in [-1, 1).
+    fn spread(&self) -> f64 {
+        let state = self
+            .rng
+            .fetch_add(SPLITMIX_GAMMA, Ordering::Relaxed)
+            .wrapping_add(SPLITMIX_GAMMA);
+        (splitmix64(state) >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+}
+
+impl Clone for ExponentialBackoff {
+    fn clone(&self) -> Self {
+        ExponentialBackoff {
+            rng: AtomicU64::new(self.rng.load(Ordering::Relaxed)),
+            ..*self
+        }
+    }
+}
+
+impl RetryPolicy for ExponentialBackoff {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| attempt >= max) {
+            return None;
+        }
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let wait = self.base.as_secs_f64() * self.factor.powi(exponent);
+        let wait = wait.min(self.max.as_secs_f64());
+        let wait = wait * (1.0 + self.jitter * self.spread());
+        Some(Duration::from_secs_f64(wait.max(0.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ack::{AckConfig, AckTracker};
+    use crate::clock::{Clock, ManualClock};
+    use crate::transport::tests::frame;
+    use std::sync::Arc;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// The times, from the first send, at which the tracker resends a
+    /// frame under `policy`, then when it gives up.
+    fn schedule(policy: impl RetryPolicy + 'static) -> (Vec<Duration>, Duration) {
+        let clock = ManualClock::new();
+        let config = AckConfig {
+            timeout: 50 * MS,
+            ..AckConfig::default()
+        };
+        let mut t = AckTracker::new(config, Arc::new(clock.clone())).with_policy(policy);
+        let start = clock.now();
+        t.register(&frame(1)).unwrap();
+        let mut resends = Vec::new();
+        while let Some(deadline) = t.next_deadline() {
+            clock.advance(deadline - clock.now());
+            let due = t.poll(clock.now());
+            if !due.expired.is_empty() {
+                return (resends, clock.now() - start);
+            }
+            assert_eq!(due.retransmit.len(), 1);
+            resends.push(clock.now() - start);
+        }
+        unreachable!("gave up without expiring")
+    }
+
+    #[test]
+    fn built_in_schedules() {
+        let fixed = FixedInterval {
+            interval: 100 * MS,
+            max_attempts: 3,
+        };
+        assert_eq!(schedule(fixed), (vec![100 * MS, 200 * MS], 250 * MS));
+        assert_eq!(schedule(NoRetry), (vec![], 50 * MS));
+
+        let backoff = ExponentialBackoff::new(100 * MS, 2.0, 500 * MS).with_max_attempts(6);
+        let resends = [100, 300, 700, 1200, 1700].map(|t| t * MS).to_vec();
+        assert_eq!(schedule(backoff), (resends, 1750 * MS));
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds_and_follows_the_seed() {
+        let policy = |seed| {
+            ExponentialBackoff::new(100 * MS, 2.0, 800 * MS)
+                .with_jitter(0.25)
+                .with_max_attempts(5)
+                .with_seed(seed)
+        };
+        let delays = |p: &ExponentialBackoff| -> Vec<Duration> {
+            (1..5).map(|a| p.next_delay(a).unwrap()).collect()
+        };
+        let a: Vec<Duration> = delays(&policy(7));
+        assert_eq!(a, delays(&policy(7)));
+        assert_ne!(a, delays(&policy(8)));
+        for (delay, nominal) in a.iter().zip([100, 200, 400, 800]) {
+            let nominal = nominal * MS;
+            assert!(*delay >= nominal.mul_f64(0.75) && *delay <= nominal.mul_f64(1.25));
+        }
+        assert_eq!(policy(7).next_delay(5), None);
+
+        let (resends, _) = schedule(policy(7));
+        let mut at = Duration::ZERO;
+        let expected: Vec<_> = a
+            .iter()
+            .map(|w| {
+                at += *w;
+                at
+            })
+            .collect();
+        assert_eq!(resends, expected);
+    }
+}
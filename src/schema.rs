@@ -0,0 +1,106 @@
+//! JSON Schema for the serde representation of frames (feature `schema`).
+//!
+//! [`frame_schema`] describes the documents that
+//! [`FrameV1`](crate::FrameV1)'s `Serialize` and `Deserialize` write and
+//! read: what the HTTP gateway takes and returns and `pipproto encode`
+//! reads. It is built from the crate's definitions
+//! (the [`MsgType`] names, [`VERSION_V1`]), and committed as
+//! `docs/frame-v1.schema.json` (`pipproto schema`). The `--json` lines of
+//! `listen` and `tail` have a shape of their own, and aren't described.
+
+use serde_json::{Value, json};
+
+use crate::{MsgType, VERSION_V1};
+
+/// Standard base64 with padding, as bodies are written.
+const BASE64_PATTERN: &str = "^(?:[A-Za-z0-9+/]{4})*(?:[A-Za-z0-9+/]{2}==|[A-Za-z0-9+/]{3}=)?$";
+
+/// The JSON Schema (draft 2020-12) of a serialized [`FrameV1`](crate::FrameV1).
+pub fn frame_schema() -> Value {
+    let msg_types: Vec<_> = (0..=u8::MAX)
+        .filter_map(MsgType::from_u8)
+        .map(MsgType::as_str)
+        .collect();
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "PiProto v1 frame",
+        "description": "A frame, as the HTTP gateway takes and returns it. The body is \
+                        base64 in `body`, or hex in `body_hex` instead; frames are \
+                        written with `body`.",
+        "type": "object",
+        "properties": {
+            "version": {
+                "description": "Protocol version.",
+                "const": VERSION_V1,
+                "default": VERSION_V1,
+            },
+            "msg_type": {
+                "description": "Message type.",
+                "enum": msg_types,
+            },
+            "flags": {
+                "description": "Flag bits, by name.",
+                "type": "object",
+                "properties": {
+                    "ack_required": {
+                        "description": "The sender wants an ACK.",
+                        "type": "boolean",
+                        "default": false,
+                    },
+                },
+                "additionalProperties": false,
+                "default": {"ack_required": false},
+            },
+            "device_id": {
+                "description": "The 8-byte device id as 16 hex digits, written lowercase.",
+                "type": "string",
+                "pattern": "^[0-9A-Fa-f]{16}$",
+            },
+            "counter": {
+                "description": "Per-device counter, a u64.",
+                "type": "integer",
+                "minimum": 0,
+                "maximum": u64::MAX,
+            },
+            "body": {
+                "description": "Body bytes as standard, padded base64.",
+                "type": "string",
+                "contentEncoding": "base64",
+                "pattern": BASE64_PATTERN,
+                "default": "",
+            },
+            "body_hex": {
+                "description": "Body bytes as hex, read instead of `body`.",
+                "type": "string",
+                "pattern": "^(?:[0-9A-Fa-f]{2})*$",
+            },
+        },
+        "required": ["msg_type", "device_id", "counter"],
+        "additionalProperties": false,
+        "not": {"required": ["body", "body_hex"]},
+    })
+}
+
+/// [`frame_schema`] as the committed file has it.
+pub fn to_json(schema: &Value) -> String {
+    serde_json::to_string_pretty(schema).unwrap() + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_every_msg_type() {
+        let schema = frame_schema();
+        assert_eq!(
+            schema["properties"]["msg_type"]["enum"],
+            json!(["event", "command", "ack", "error"])
+        );
+        assert_eq!(schema["properties"]["version"]["const"], json!(1));
+        assert_eq!(
+            schema["properties"]["counter"]["maximum"],
+            json!(18446744073709551615u64)
+        );
+    }
+}
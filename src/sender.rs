@@ -0,0 +1,748 @@
+//! Sending frames without tracking counters by hand.
+//!
+//! A [`CounterMap`] hands out the next counter per device under a lock,
+//! so concurrent senders never reuse one. [`FrameSender`] (async, over a
+//! [`Transport`]) and [`BlockingFrameSender`] (over a closure) take the
+//! next counter and send the frame under the same lock, so frames leave
+//! in counter order. A counter is used up even if its send fails.
+//! Either can be given a [`SendWindow`] to bound ack-required frames in
+//! flight, an [`Authorizer`] to refuse what its principal may not send and
+//! a [`CircuitBreaker`] to refuse sends to devices that keep failing.
+//!
+//! Give the map a [`CounterStore`] to carry counters across restarts.
+//! Senders that can keep no state at all can number frames with a
+//! [`TimestampCounter`] instead; both are [`CounterSource`]s.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::acl::{self, Authorizer, Operation, Unauthorized};
+use crate::breaker::{CircuitBreaker, CircuitOpen, Failure};
+use crate::counter::{CounterOverflow, OverflowPolicy, next_counter};
+use crate::counter_store::CounterStore;
+use crate::transport::{Transport, TransportError};
+use crate::window::{Priority, SendWindow, Slot, WindowError};
+use crate::{Flags, FrameHeaderV1, FrameId, FrameV1, MsgType, VERSION_V1};
+
+#[derive(Debug)]
+pub enum CounterError {
+    Overflow(CounterOverflow),
+    /// The counter was taken but could not be persisted.
+    Store(io::Error),
+}
+
+impl fmt::Display for CounterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CounterError::Overflow(e) => write!(f, "{e}"),
+            CounterError::Store(e) => write!(f, "counter store failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for CounterError {}
+
+/// Hands out frame counters, each strictly greater than the last one
+/// given to the same device.
+pub trait CounterSource: Send + Sync {
+    fn next(&self, device_id: [u8; 8]) -> Result<u64, CounterError>;
+}
+
+/// Last counter used per device; a device's first frame gets 1.
+#[derive(Default)]
+pub struct CounterMap {
+    last: Mutex<HashMap<[u8; 8], u64>>,
+    policy: OverflowPolicy,
+    store: Option<Arc<dyn CounterStore>>,
+}
+
+impl CounterMap {
+    pub fn new(policy: OverflowPolicy) -> Self {
+        CounterMap {
+            last: Mutex::default(),
+            policy,
+            store: None,
+        }
+    }
+
+    /// Load counters from `store`, each moved forward by `margin` to skip
+    /// any the store had not persisted before a crash, and persist every
+    /// counter taken from now on.
+    pub fn with_store(
+        policy: OverflowPolicy,
+        store: Arc<dyn CounterStore>,
+        margin: u64,
+    ) -> io::Result<Self> {
+        let mut last = HashMap::new();
+        for (device, counter) in store.load_all()? {
+            let bumped = counter.saturating_add(margin);
+            store.persist(device, bumped)?;
+            last.insert(device, bumped);
+        }
+        store.flush()?;
+        Ok(CounterMap {
+            last: Mutex::new(last),
+            policy,
+            store: Some(store),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<[u8; 8], u64>> {
+        self.last.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn next(&self, device_id: [u8; 8]) -> Result<u64, CounterError> {
+        let mut last = self.lock();
+        let next = match last.get(&device_id) {
+            Some(&c) => next_counter(c, self.policy).map_err(CounterError::Overflow)?,
+            None => 1,
+        };
+        last.insert(device_id, next);
+        if let Some(store) = &self.store {
+            store
+                .persist(device_id, next)
+                .map_err(CounterError::Store)?;
+        }
+        Ok(next)
+    }
+
+    /// Last counter used by each device.
+    pub fn snapshot(&self) -> BTreeMap<[u8; 8], u64> {
+        self.lock().iter().map(|(&d, &c)| (d, c)).collect()
+    }
+
+    /// Merge a snapshot in. Counters only move forward: a device already
+    /// past its snapshot value keeps its own.
+    pub fn restore(&self, snapshot: &BTreeMap<[u8; 8], u64>) {
+        let mut last = self.lock();
+        for (&device, &counter) in snapshot {
+            let c = last.entry(device).or_insert(counter);
+            *c = (*c).max(counter);
+        }
+    }
+}
+
+impl CounterSource for CounterMap {
+    fn next(&self, device_id: [u8; 8]) -> Result<u64, CounterError> {
+        CounterMap::next(self, device_id)
+    }
+}
+
+/// Low bits of a [`TimestampCounter`] value used for the sequence.
+pub const SEQUENCE_BITS: u32 = 16;
+
+type TimeFn = Box<dyn Fn() -> SystemTime + Send + Sync>;
+
+/// Counters derived from wall-clock time: milliseconds since the epoch
+/// shifted left by [`SEQUENCE_BITS`], plus a sequence for frames within
+/// the same millisecond.
+///
+/// Counters are strictly increasing across all devices in the process.
+/// If the clock steps backwards, or a burst uses up a millisecond's
+/// sequence space, counting carries on from the last value, so counters
+/// run ahead of the clock until it catches up. A restarted process starts
+/// again from the clock and so stays ahead of its predecessor as long as
+/// the clock did not step back further than that lead.
+pub struct TimestampCounter {
+    now: TimeFn,
+    last: Mutex<u64>,
+}
+
+impl TimestampCounter {
+    pub fn new() -> Self {
+        Self::with_time_source(SystemTime::now)
+    }
+
+    pub fn with_time_source(now: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        TimestampCounter {
+            now: Box::new(now),
+            last: Mutex::new(0),
+        }
+    }
+}
+
+impl Default for TimestampCounter {
+    fn default() -> Self {
+        TimestampCounter::new()
+    }
+}
+
+impl CounterSource for TimestampCounter {
+    fn next(&self, _device_id: [u8; 8]) -> Result<u64, CounterError> {
+        let millis = (self.now)()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let stamp = u64::try_from(millis << SEQUENCE_BITS).unwrap_or(u64::MAX);
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let next = match last.checked_add(1) {
+            Some(after) => stamp.max(after),
+            None => return Err(CounterError::Overflow(CounterOverflow)),
+        };
+        *last = next;
+        Ok(next)
+    }
+}
+
+#[derive(Debug)]
+pub enum SendError<E> {
+    Counter(CounterError),
+    /// No window slot was free, under a policy that does not wait.
+    Window(WindowError),
+    /// The sender's [`Authorizer`] denied the frame; nothing was sent.
+    Unauthorized(Unauthorized),
+    /// The device's circuit is open; nothing was sent.
+    CircuitOpen(CircuitOpen),
+    Send(E),
+}
+
+impl<E: fmt::Display> fmt::Display for SendError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Counter(e) => write!(f, "{e}"),
+            SendError::Window(e) => write!(f, "{e}"),
+            SendError::Unauthorized(e) => write!(f, "{e}"),
+            SendError::CircuitOpen(e) => write!(f, "{e}"),
+            SendError::Send(e) => write!(f, "send failed: {e}"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for SendError<E> {}
+
+pub(crate) fn stamp(
+    msg_type: MsgType,
+    flags: Flags,
+    device_id: [u8; 8],
+    counter: u64,
+    body: Vec<u8>,
+) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type,
+            flags,
+            device_id,
+            counter,
+        },
+        body: body.into(),
+    }
+}
+
+/// An authorizer and the principal a sender sends as.
+type Principal = (Arc<dyn Authorizer>, Arc<str>);
+
+fn authorize<E>(
+    authorizer: &Option<Principal>,
+    msg_type: MsgType,
+    device_id: [u8; 8],
+    body: &[u8],
+) -> Result<(), SendError<E>> {
+    let Some((authorizer, principal)) = authorizer else {
+        return Ok(());
+    };
+    let operation = Operation {
+        principal,
+        device_id,
+        msg_type,
+        body,
+    };
+    acl::check(authorizer.as_ref(), &operation).map_err(SendError::Unauthorized)
+}
+
+fn admit<E>(breaker: &Option<CircuitBreaker>, device_id: [u8; 8]) -> Result<(), SendError<E>> {
+    match breaker {
+        Some(b) => b.admit(device_id).map_err(SendError::CircuitOpen),
+        None => Ok(()),
+    }
+}
+
+fn failed<E>(breaker: &Option<CircuitBreaker>, device_id: [u8; 8], e: E) -> SendError<E> {
+    if let Some(b) = breaker {
+        b.failure(device_id, Failure::Send);
+    }
+    SendError::Send(e)
+}
+
+/// Numbers and sends frames over a shared transport. Clones share the
+/// transport and counters.
+pub struct FrameSender<T, C = CounterMap> {
+    transport: Arc<tokio::sync::Mutex<T>>,
+    counters: Arc<C>,
+    window: Option<SendWindow>,
+    authorizer: Option<Principal>,
+    breaker: Option<CircuitBreaker>,
+}
+
+impl<T, C> Clone for FrameSender<T, C> {
+    fn clone(&self) -> Self {
+        FrameSender {
+            transport: self.transport.clone(),
+            counters: self.counters.clone(),
+            window: self.window.clone(),
+            authorizer: self.authorizer.clone(),
+            breaker: self.breaker.clone(),
+        }
+    }
+}
+
+impl<T: Transport> FrameSender<T> {
+    /// [`FrameSender::new`] with counters kept in `store`; see
+    /// [`CounterMap::with_store`].
+    pub fn with_store(transport: T, store: Arc<dyn CounterStore>, margin: u64) -> io::Result<Self> {
+        let counters = CounterMap::with_store(OverflowPolicy::default(), store, margin)?;
+        Ok(FrameSender::new(transport, Arc::new(counters)))
+    }
+}
+
+impl<T: Transport, C: CounterSource> FrameSender<T, C> {
+    pub fn new(transport: T, counters: Arc<C>) -> Self {
+        FrameSender {
+            transport: Arc::new(tokio::sync::Mutex::new(transport)),
+            counters,
+            window: None,
+            authorizer: None,
+            breaker: None,
+        }
+    }
+
+    /// Take a slot of `window` before each ack-required send, waiting as
+    /// its policy says.
+    pub fn with_window(mut self, window: SendWindow) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Send only what `authorizer` allows `principal`.
+    pub fn with_authorizer(
+        mut self,
+        authorizer: Arc<dyn Authorizer>,
+        principal: impl Into<Arc<str>>,
+    ) -> Self {
+        self.authorizer = Some((authorizer, principal.into()));
+        self
+    }
+
+    /// Refuse sends to devices whose circuit in `breaker` is open, and
+    /// report transport failures to it.
+    pub fn with_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    pub fn counters(&self) -> &Arc<C> {
+        &self.counters
+    }
+
+    pub(crate) fn breaker(&self) -> Option<&CircuitBreaker> {
+        self.breaker.as_ref()
+    }
+
+    pub(crate) fn window(&self) -> Option<&SendWindow> {
+        self.window.as_ref()
+    }
+
+    pub async fn send_event(
+        &self,
+        device_id: [u8; 8],
+        body: Vec<u8>,
+    ) -> Result<FrameId, SendError<TransportError>> {
+        self.send_raw(MsgType::Event, Flags::new(0).unwrap(), device_id, body)
+            .await
+    }
+
+    pub async fn send_command(
+        &self,
+        device_id: [u8; 8],
+        body: Vec<u8>,
+    ) -> Result<FrameId, SendError<TransportError>> {
+        self.send_raw(MsgType::Command, Flags::new(0).unwrap(), device_id, body)
+            .await
+    }
+
+    pub async fn send_raw(
+        &self,
+        msg_type: MsgType,
+        flags: Flags,
+        device_id: [u8; 8],
+        body: Vec<u8>,
+    ) -> Result<FrameId, SendError<TransportError>> {
+        self.send_numbered(Priority::Normal, msg_type, flags, device_id, body, |_| {})
+            .await
+    }
+
+    /// [`FrameSender::send_raw`] allowed into the window's reserve.
+    pub async fn send_urgent(
+        &self,
+        msg_type: MsgType,
+        flags: Flags,
+        device_id: [u8; 8],
+        body: Vec<u8>,
+    ) -> Result<FrameId, SendError<TransportError>> {
+        self.send_numbered(Priority::Urgent, msg_type, flags, device_id, body, |_| {})
+            .await
+    }
+
+    /// Sends a frame, calling `numbered` with its id just before it goes
+    /// out.
+    pub(crate) async fn send_numbered(
+        &self,
+        priority: Priority,
+        msg_type: MsgType,
+        flags: Flags,
+        device_id: [u8; 8],
+        body: Vec<u8>,
+        numbered: impl FnOnce(FrameId),
+    ) -> Result<FrameId, SendError<TransportError>> {
+        authorize(&self.authorizer, msg_type, device_id, &body)?;
+        admit(&self.breaker, device_id)?;
+        let slot = match &self.window {
+            Some(w) if flags.ack_required() => {
+                w.acquire(device_id, priority)
+                    .await
+                    .map_err(SendError::Window)?;
+                Slot::taken(w, device_id)
+            }
+            _ => Slot::none(),
+        };
+        let mut transport = self.transport.lock().await;
+        let counter = self.counters.next(device_id).map_err(SendError::Counter)?;
+        let frame = stamp(msg_type, flags, device_id, counter, body);
+        numbered(frame.header.id());
+        transport
+            .send(&frame)
+            .await
+            .map_err(|e| failed(&self.breaker, device_id, e))?;
+        slot.keep();
+        Ok(frame.header.id())
+    }
+
+    /// Send an already numbered frame again, as it was.
+    pub(crate) async fn resend(&self, frame: &FrameV1) -> Result<(), SendError<TransportError>> {
+        let device_id = frame.header.device_id;
+        admit(&self.breaker, device_id)?;
+        let mut transport = self.transport.lock().await;
+        transport
+            .send(frame)
+            .await
+            .map_err(|e| failed(&self.breaker, device_id, e))
+    }
+}
+
+type SendFn<E> = Box<dyn FnMut(&FrameV1) -> Result<(), E> + Send>;
+
+/// [`FrameSender`] for blocking code, sending through a closure.
+pub struct BlockingFrameSender<E, C = CounterMap> {
+    send: Arc<Mutex<SendFn<E>>>,
+    counters: Arc<C>,
+    window: Option<SendWindow>,
+    authorizer: Option<Principal>,
+    breaker: Option<CircuitBreaker>,
+}
+
+impl<E, C> Clone for BlockingFrameSender<E, C> {
+    fn clone(&self) -> Self {
+        BlockingFrameSender {
+            send: self.send.clone(),
+            counters: self.counters.clone(),
+            window: self.window.clone(),
+            authorizer: self.authorizer.clone(),
+            breaker: self.breaker.clone(),
+        }
+    }
+}
+
+impl<E> BlockingFrameSender<E> {
+    /// [`BlockingFrameSender::new`] with counters kept in `store`.
+    pub fn with_store(
+        send: impl FnMut(&FrameV1) -> Result<(), E> + Send + 'static,
+        store: Arc<dyn CounterStore>,
+        margin: u64,
+    ) -> io::Result<Self> {
+        let counters = CounterMap::with_store(OverflowPolicy::default(), store, margin)?;
+        Ok(BlockingFrameSender::new(send, Arc::new(counters)))
+    }
+}
+
+impl<E, C: CounterSource> BlockingFrameSender<E, C> {
+    pub fn new(
+        send: impl FnMut(&FrameV1) -> Result<(), E> + Send + 'static,
+        counters: Arc<C>,
+    ) -> Self {
+        BlockingFrameSender {
+            send: Arc::new(Mutex::new(Box::new(send))),
+            counters,
+            window: None,
+            authorizer: None,
+            breaker: None,
+        }
+    }
+
+    /// See [`FrameSender::with_window`]; waiting blocks the thread.
+    pub fn with_window(mut self, window: SendWindow) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// See [`FrameSender::with_authorizer`].
+    pub fn with_authorizer(
+        mut self,
+        authorizer: Arc<dyn Authorizer>,
+        principal: impl Into<Arc<str>>,
+    ) -> Self {
+        self.authorizer = Some((authorizer, principal.into()));
+        self
+    }
+
+    /// See [`FrameSender::with_breaker`].
+    pub fn with_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    pub fn counters(&self) -> &Arc<C> {
+        &self.counters
+    }
+
+    pub fn send_event(&self, device_id: [u8; 8], body: Vec<u8>) -> Result<FrameId, SendError<E>> {
+        self.send_raw(MsgType::Event, Flags::new(0).unwrap(), device_id, body)
+    }
+
+    pub fn send_command(&self, device_id: [u8; 8], body: Vec<u8>) -> Result<FrameId, SendError<E>> {
+        self.send_raw(MsgType::Command, Flags::new(0).unwrap(), device_id, body)
+    }
+
+    pub fn send_raw(
+        &self,
+        msg_type: MsgType,
+        flags: Flags,
+        device_id: [u8; 8],
+        body: Vec<u8>,
+    ) -> Result<FrameId, SendError<E>> {
+        self.send_with(Priority::Normal, msg_type, flags, device_id, body)
+    }
+
+    /// [`BlockingFrameSender::send_raw`] allowed into the window's reserve.
+    pub fn send_urgent(
+        &self,
+        msg_type: MsgType,
+        flags: Flags,
+        device_id: [u8; 8],
+        body: Vec<u8>,
+    ) -> Result<FrameId, SendError<E>> {
+        self.send_with(Priority::Urgent, msg_type, flags, device_id, body)
+    }
+
+    fn send_with(
+        &self,
+        priority: Priority,
+        msg_type: MsgType,
+        flags: Flags,
+        device_id: [u8; 8],
+        body: Vec<u8>,
+    ) -> Result<FrameId, SendError<E>> {
+        authorize(&self.authorizer, msg_type, device_id, &body)?;
+        admit(&self.breaker, device_id)?;
+        let slot = match &self.window {
+            Some(w) if flags.ack_required() => {
+                w.acquire_blocking(device_id, priority)
+                    .map_err(SendError::Window)?;
+                Slot::taken(w, device_id)
+            }
+            _ => Slot::none(),
+        };
+        let mut send = self.send.lock().unwrap_or_else(|e| e.into_inner());
+        let counter = self.counters.next(device_id).map_err(SendError::Counter)?;
+        let frame = stamp(msg_type, flags, device_id, counter, body);
+        send(&frame).map_err(|e| failed(&self.breaker, device_id, e))?;
+        slot.keep();
+        Ok(frame.header.id())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counter_store::FileCounterStore;
+    use crate::transport::loopback_pair_with_capacity;
+
+    const DEV: [u8; 8] = *b"DEV00001";
+
+    #[tokio::test]
+    async fn concurrent_async_sends_never_share_a_counter() {
+        let (a, mut b) = loopback_pair_with_capacity(8);
+        let sender = FrameSender::new(a, Arc::new(CounterMap::default()));
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    for _ in 0..100 {
+                        sender.send_event(DEV, vec![1]).await.unwrap();
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+
+        let mut counters = Vec::new();
+        for _ in 0..1600 {
+            counters.push(b.recv().await.unwrap().header.counter);
+        }
+        for t in tasks {
+            t.await.unwrap();
+        }
+        // the lock covers numbering and sending, so the wire order is exact
+        assert_eq!(counters, (1..=1600).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn denied_sends_use_no_counter() {
+        use crate::acl::{AclTable, Rule};
+        let acl = AclTable::new().allow("tenant-a", Rule::any().with_device_prefix(b"DEV"));
+        let (a, mut b) = loopback_pair_with_capacity(8);
+        let sender = FrameSender::new(a, Arc::new(CounterMap::default()))
+            .with_authorizer(Arc::new(acl), "tenant-a");
+        let err = sender.send_command(*b"OTHER001", vec![]).await.unwrap_err();
+        assert!(matches!(err, SendError::Unauthorized(e) if e.device_id == *b"OTHER001"));
+        sender.send_command(DEV, vec![]).await.unwrap();
+        assert_eq!(b.recv().await.unwrap().header.device_id, DEV);
+        assert_eq!(sender.counters().snapshot().len(), 1);
+    }
+
+    #[test]
+    fn concurrent_blocking_sends_never_share_a_counter() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let out = seen.clone();
+        let sender = BlockingFrameSender::new(
+            move |f: &FrameV1| {
+                out.lock().unwrap().push(f.header.counter);
+                Ok::<_, ()>(())
+            },
+            Arc::new(CounterMap::default()),
+        );
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let sender = sender.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..500 {
+                        if i % 2 == 0 {
+                            sender.send_event(DEV, Vec::new()).unwrap();
+                        } else {
+                            sender.send_command(DEV, Vec::new()).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*seen.lock().unwrap(), (1..=4000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn snapshot_restore_and_overflow() {
+        let counters = CounterMap::new(OverflowPolicy::Error);
+        assert_eq!(counters.next(DEV).unwrap(), 1);
+        assert_eq!(counters.next(DEV).unwrap(), 2);
+        let snap = counters.snapshot();
+        assert_eq!(snap[&DEV], 2);
+
+        let restored = CounterMap::new(OverflowPolicy::Error);
+        restored.restore(&snap);
+        assert_eq!(restored.next(DEV).unwrap(), 3);
+        restored.restore(&snap);
+        assert_eq!(restored.next(DEV).unwrap(), 4, "restore never moves back");
+
+        restored.restore(&BTreeMap::from([(DEV, u64::MAX)]));
+        assert!(matches!(
+            restored.next(DEV),
+            Err(CounterError::Overflow(CounterOverflow))
+        ));
+    }
+
+    #[tokio::test]
+    async fn restart_never_reuses_counters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counters");
+        let open = || {
+            Arc::new(
+                FileCounterStore::open(&path)
+                    .unwrap()
+                    .with_persist_every(10),
+            )
+        };
+
+        let mut sent = Vec::new();
+        for _ in 0..3 {
+            let (a, mut b) = loopback_pair_with_capacity(64);
+            let store = open();
+            let sender = FrameSender::with_store(a, store.clone(), 10).unwrap();
+            for _ in 0..25 {
+                sender.send_event(DEV, Vec::new()).await.unwrap();
+                sent.push(b.recv().await.unwrap().header.counter);
+            }
+            // killed: no flush on drop, unwritten increments are lost
+            std::mem::forget(sender);
+            std::mem::forget(store);
+        }
+        let mut unique = sent.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), sent.len(), "{sent:?}");
+        assert!(sent.is_sorted());
+    }
+
+    fn at_ms(ms: u64) -> SystemTime {
+        UNIX_EPOCH + std::time::Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn timestamp_counter_survives_clock_stepping_back() {
+        let now = Arc::new(Mutex::new(at_ms(1_000)));
+        let time = now.clone();
+        let counter = TimestampCounter::with_time_source(move || *time.lock().unwrap());
+
+        let first = counter.next(DEV).unwrap();
+        assert_eq!(first, 1_000 << SEQUENCE_BITS);
+        assert_eq!(counter.next(DEV).unwrap(), first + 1);
+
+        *now.lock().unwrap() = at_ms(400);
+        assert_eq!(counter.next(DEV).unwrap(), first + 2);
+        assert_eq!(counter.next(*b"DEV00002").unwrap(), first + 3);
+
+        *now.lock().unwrap() = at_ms(1_001);
+        assert_eq!(counter.next(DEV).unwrap(), 1_001 << SEQUENCE_BITS);
+    }
+
+    #[test]
+    fn timestamp_counter_burst_borrows_from_the_next_millisecond() {
+        let counter = TimestampCounter::with_time_source(|| at_ms(7));
+        let burst = (1 << SEQUENCE_BITS) + 10;
+        let counters: Vec<u64> = (0..burst).map(|_| counter.next(DEV).unwrap()).collect();
+        assert!(counters.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(counters[0], 7 << SEQUENCE_BITS);
+        assert_eq!(*counters.last().unwrap(), (8 << SEQUENCE_BITS) + 9);
+    }
+
+    #[test]
+    fn blocking_sender_with_timestamp_counter() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let out = seen.clone();
+        let sender = BlockingFrameSender::new(
+            move |f: &FrameV1| {
+                out.lock().unwrap().push(f.header.counter);
+                Ok::<_, ()>(())
+            },
+            Arc::new(TimestampCounter::with_time_source(|| at_ms(5))),
+        );
+        sender.send_event(DEV, Vec::new()).unwrap();
+        sender.send_command(DEV, Vec::new()).unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [5 << SEQUENCE_BITS, (5 << SEQUENCE_BITS) + 1]
+        );
+    }
+}
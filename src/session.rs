@@ -0,0 +1,777 @@
+//! Session lifecycle between two peers, without any I/O.
+//!
+//! A [`Session`] numbers and queues the frames it wants sent, and is fed
+//! the frames received and the passage of time. Application frames only
+//! go out, and are only let in, once the session is
+//! [`SessionState::Established`].
+//!
+//! v1 has no control message types, so HELLO and BYE travel as COMMAND
+//! frames whose body starts with [`CONTROL_PREFIX`]: `00 53 01 <version>`
+//! for HELLO and `00 53 02` for BYE. PING, `00 53 03`, asks to be acked to
+//! show the peer is alive, and may carry an echo to time the round trip
+//! (see `ping`), and CHALLENGE and RESPONSE, `00 53 04` and
+//! `00 53 05`, authenticate a device and KEY-UPDATE, `00 53 0b`, rotates
+//! its key (see `auth`); `00 53 06` to `00 53 0a` enroll one (see
+//! `pairing`), DISCOVER and ANNOUNCE, `00 53 0c` and `00 53 0d`, find
+//! devices (see `discovery`), and `00 53 0e` to `00 53 11` give a new one
+//! its id (see `assign`). None of them mean anything to the session.
+//!
+//! ```text
+//!   Idle --connect--> HelloSent --HELLO--> Established --close--> Closing
+//!     \                                      ^    \                  |
+//!      HELLO--> HelloReceived --accept------/      BYE--> Closed <--BYE/timeout
+//! ```
+//!
+//! A session may be given an [`AckTracker`], which it registers its
+//! ACK_REQUIRED frames with, resolves with the ACKs let in and polls for
+//! resends; a [`ReplayWindow`], which the EVENTs and COMMANDs let in must
+//! pass; and a [`DeviceRegistry`], which it only carries. All three go in
+//! the session's [`SessionSnapshot`].
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::ack::{AckError, AckTracker};
+use crate::clock::{Clock, SystemClock};
+use crate::counter::{CounterOverflow, OverflowPolicy, next_counter};
+use crate::registry::DeviceRegistry;
+use crate::replay::{ReplayWindow, Verdict};
+use crate::snapshot::{Rebase, SessionSnapshot, SnapshotError};
+use crate::{Flags, FrameHeaderV1, FrameId, FrameV1, MsgType, VERSION_V1};
+
+/// First bytes of a session control COMMAND body.
+pub const CONTROL_PREFIX: [u8; 2] = [0x00, 0x53];
+const HELLO: u8 = 0x01;
+const BYE: u8 = 0x02;
+const PING: u8 = 0x03;
+const CHALLENGE: u8 = 0x04;
+const RESPONSE: u8 = 0x05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SessionState {
+    Idle,
+    HelloSent,
+    HelloReceived,
+    Established,
+    Closing,
+    Closed,
+}
+
+impl fmt::Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SessionState::Idle => "idle",
+            SessionState::HelloSent => "hello-sent",
+            SessionState::HelloReceived => "hello-received",
+            SessionState::Established => "established",
+            SessionState::Closing => "closing",
+            SessionState::Closed => "closed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A parsed session control message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Hello { version: u8 },
+    Bye,
+    Ping,
+    Challenge { nonce: [u8; 16] },
+    Response { nonce: [u8; 16], mac: [u8; 32] },
+}
+
+impl Control {
+    /// The control message `frame` carries, if it is one.
+    pub fn parse(frame: &FrameV1) -> Option<Control> {
+        if frame.header.msg_type != MsgType::Command {
+            return None;
+        }
+        match frame.body.strip_prefix(&CONTROL_PREFIX)? {
+            [HELLO, version] => Some(Control::Hello { version: *version }),
+            [BYE] => Some(Control::Bye),
+            [PING, echo @ ..] if echo.is_empty() || echo.len() == crate::ping::ECHO_LEN => {
+                Some(Control::Ping)
+            }
+            [CHALLENGE, nonce @ ..] => Some(Control::Challenge {
+                nonce: nonce.try_into().ok()?,
+            }),
+            [RESPONSE, rest @ ..] if rest.len() == 48 => Some(Control::Response {
+                nonce: rest[..16].try_into().ok()?,
+                mac: rest[16..].try_into().ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The COMMAND body carrying this message.
+    pub fn encode(self) -> Vec<u8> {
+        let mut body = CONTROL_PREFIX.to_vec();
+        match self {
+            Control::Hello { version } => body.extend([HELLO, version]),
+            Control::Bye => body.push(BYE),
+            Control::Ping => body.push(PING),
+            Control::Challenge { nonce } => {
+                body.push(CHALLENGE);
+                body.extend(nonce);
+            }
+            Control::Response { nonce, mac } => {
+                body.push(RESPONSE);
+                body.extend(nonce);
+                body.extend(mac);
+            }
+        }
+        body
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionError {
+    /// Application traffic outside [`SessionState::Established`].
+    NotEstablished(SessionState),
+    /// The local call makes no sense in this state.
+    InvalidTransition {
+        state: SessionState,
+        action: &'static str,
+    },
+    /// The peer sent a control message that makes no sense in this state.
+    /// The session is unchanged.
+    Unexpected {
+        state: SessionState,
+        control: Control,
+    },
+    Counter(CounterOverflow),
+    /// The ack tracker would not take an ACK_REQUIRED frame; it was not
+    /// queued.
+    Ack(AckError),
+    /// The replay window turned the frame away.
+    Replayed(Verdict),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::NotEstablished(s) => write!(f, "session is {s}, not established"),
+            SessionError::InvalidTransition { state, action } => {
+                write!(f, "cannot {action} a session that is {state}")
+            }
+            SessionError::Unexpected { state, control } => {
+                write!(f, "unexpected {control:?} while {state}")
+            }
+            SessionError::Counter(e) => write!(f, "{e}"),
+            SessionError::Ack(e) => write!(f, "{e}"),
+            SessionError::Replayed(v) => write!(f, "replayed frame: {v:?}"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    StateChanged {
+        from: SessionState,
+        to: SessionState,
+    },
+    /// A handshake or close took too long; the session is now closed.
+    TimedOut(SessionState),
+    /// The ack tracker gave up on a frame.
+    Unacked(FrameId),
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// How long HelloSent and HelloReceived may last.
+    pub handshake_timeout: Duration,
+    /// How long to wait for the peer's BYE after sending ours.
+    pub close_timeout: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            handshake_timeout: Duration::from_secs(5),
+            close_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+pub struct Session {
+    clock: Arc<dyn Clock>,
+    config: SessionConfig,
+    device_id: [u8; 8],
+    counter: u64,
+    state: SessionState,
+    deadline: Option<Instant>,
+    outbox: VecDeque<FrameV1>,
+    /// Frames kept back by [`Session::reconnect`] until re-established.
+    held: Option<Vec<FrameV1>>,
+    events: VecDeque<SessionEvent>,
+    acks: Option<AckTracker>,
+    replay: Option<ReplayWindow>,
+    registry: Option<DeviceRegistry>,
+}
+
+impl Session {
+    /// A session whose frames carry `device_id`.
+    pub fn new(device_id: [u8; 8], config: SessionConfig) -> Self {
+        Self::with_clock(device_id, config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(device_id: [u8; 8], config: SessionConfig, clock: Arc<dyn Clock>) -> Self {
+        Session {
+            clock,
+            config,
+            device_id,
+            counter: 0,
+            state: SessionState::Idle,
+            deadline: None,
+            outbox: VecDeque::new(),
+            held: None,
+            events: VecDeque::new(),
+            acks: None,
+            replay: None,
+            registry: None,
+        }
+    }
+
+    pub fn with_acks(mut self, acks: AckTracker) -> Self {
+        self.acks = Some(acks);
+        self
+    }
+
+    pub fn with_replay(mut self, replay: ReplayWindow) -> Self {
+        self.replay = Some(replay);
+        self
+    }
+
+    pub fn with_registry(mut self, registry: DeviceRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    pub fn acks(&self) -> Option<&AckTracker> {
+        self.acks.as_ref()
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Start the handshake by sending HELLO.
+    pub fn connect(&mut self) -> Result<(), SessionError> {
+        self.expect(SessionState::Idle, "connect")?;
+        self.send_control(Control::Hello {
+            version: VERSION_V1,
+        })?;
+        self.enter(SessionState::HelloSent);
+        Ok(())
+    }
+
+    /// Answer the peer's HELLO, establishing the session.
+    pub fn accept(&mut self) -> Result<(), SessionError> {
+        self.expect(SessionState::HelloReceived, "accept")?;
+        self.send_control(Control::Hello {
+            version: VERSION_V1,
+        })?;
+        self.enter(SessionState::Established);
+        Ok(())
+    }
+
+    /// The link dropped and another replaced it: start the handshake again
+    /// with a fresh HELLO, which the peer answers as for a new session.
+    /// Frames queued and not yet sent are kept back until the session is
+    /// established again, and then sent after every frame still awaiting
+    /// an ack, resent, in counter order.
+    pub fn reconnect(&mut self) -> Result<(), SessionError> {
+        if !matches!(
+            self.state,
+            SessionState::HelloSent | SessionState::Established
+        ) {
+            return Err(SessionError::InvalidTransition {
+                state: self.state,
+                action: "reconnect",
+            });
+        }
+        let acks = &self.acks;
+        let held = self.held.get_or_insert_with(Vec::new);
+        held.extend(self.outbox.drain(..).filter(|f| {
+            // resends are made afresh, and a control frame means nothing
+            // on the new link
+            let tracked = acks
+                .as_ref()
+                .is_some_and(|a| a.state(f.header.device_id, f.header.counter).is_some());
+            !tracked && Control::parse(f).is_none()
+        }));
+        self.send_control(Control::Hello {
+            version: VERSION_V1,
+        })?;
+        self.enter(SessionState::HelloSent);
+        Ok(())
+    }
+
+    /// Send BYE. An established session waits in Closing for the peer's
+    /// BYE; a half-open one closes at once.
+    pub fn close(&mut self) -> Result<(), SessionError> {
+        let next = match self.state {
+            SessionState::Established => SessionState::Closing,
+            SessionState::HelloSent | SessionState::HelloReceived => SessionState::Closed,
+            state => {
+                return Err(SessionError::InvalidTransition {
+                    state,
+                    action: "close",
+                });
+            }
+        };
+        self.send_control(Control::Bye)?;
+        self.enter(next);
+        Ok(())
+    }
+
+    /// Queue an application frame.
+    pub fn send(
+        &mut self,
+        msg_type: MsgType,
+        flags: Flags,
+        body: Vec<u8>,
+    ) -> Result<FrameId, SessionError> {
+        if self.state != SessionState::Established {
+            return Err(SessionError::NotEstablished(self.state));
+        }
+        self.queue(msg_type, flags, body)
+    }
+
+    /// Handle a received frame. Control messages are consumed; anything
+    /// else is handed back for the application, but only once
+    /// established.
+    pub fn on_frame(&mut self, frame: FrameV1) -> Result<Option<FrameV1>, SessionError> {
+        let Some(control) = Control::parse(&frame) else {
+            if self.state != SessionState::Established {
+                return Err(SessionError::NotEstablished(self.state));
+            }
+            let header = &frame.header;
+            if let Some(replay) = &mut self.replay
+                && matches!(header.msg_type, MsgType::Event | MsgType::Command)
+            {
+                match replay.check(header.device_id, header.counter) {
+                    Verdict::Accept => {}
+                    v => return Err(SessionError::Replayed(v)),
+                }
+            }
+            if let Some(acks) = &mut self.acks {
+                acks.on_ack(&frame);
+            }
+            return Ok(Some(frame));
+        };
+        use SessionState::*;
+        match (self.state, control) {
+            (_, Control::Ping | Control::Challenge { .. } | Control::Response { .. }) => {}
+            (Idle, Control::Hello { .. }) => self.enter(HelloReceived),
+            (HelloSent, Control::Hello { .. }) => {
+                self.enter(Established);
+                self.release_held();
+            }
+            // a repeat of a HELLO already handled
+            (HelloReceived | Established, Control::Hello { .. }) => {}
+            (HelloSent | HelloReceived | Closing, Control::Bye) => self.enter(Closed),
+            (Established, Control::Bye) => {
+                self.send_control(Control::Bye)?;
+                self.enter(Closed);
+            }
+            (state, control) => return Err(SessionError::Unexpected { state, control }),
+        }
+        Ok(None)
+    }
+
+    /// Close the session if its handshake or close has run out of time,
+    /// and queue the resends the ack tracker finds due.
+    pub fn poll_timeout(&mut self, now: Instant) {
+        if self.deadline.is_some_and(|d| now >= d) {
+            self.events.push_back(SessionEvent::TimedOut(self.state));
+            self.enter(SessionState::Closed);
+        }
+        if let Some(acks) = &mut self.acks {
+            let due = acks.poll(now);
+            self.outbox.extend(due.retransmit);
+            self.events.extend(
+                due.expired
+                    .iter()
+                    .map(|o| SessionEvent::Unacked(o.frame.header.id())),
+            );
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let acks = self.acks.as_ref().and_then(AckTracker::next_deadline);
+        self.deadline.into_iter().chain(acks).min()
+    }
+
+    /// The session's state and its parts' as of now.
+    pub fn snapshot(&self) -> SessionSnapshot {
+        let now = self.clock.now();
+        SessionSnapshot {
+            taken_at: SystemTime::now(),
+            device_id: self.device_id,
+            counter: self.counter,
+            state: self.state,
+            deadline_in: self.deadline.map(|d| d.saturating_duration_since(now)),
+            outbox: self
+                .outbox
+                .iter()
+                .chain(self.held.iter().flatten())
+                .map(FrameV1::encode)
+                .collect(),
+            replay: self.replay.as_ref().map_or(Vec::new(), |r| r.export(now)),
+            pending: self.acks.as_ref().map_or(Vec::new(), |a| a.export(now)),
+            devices: self.registry.as_ref().map_or(Vec::new(), |r| r.export()),
+        }
+    }
+
+    /// Pick up where `snapshot` left off, placing its times against
+    /// `now`. Parts of it this session was not given are left out; what
+    /// the parts it was given already hold is kept unless the snapshot
+    /// has the same device or frame. Queued events are dropped.
+    pub fn restore(
+        &mut self,
+        snapshot: SessionSnapshot,
+        now: Instant,
+    ) -> Result<(), SnapshotError> {
+        if snapshot.device_id != self.device_id {
+            return Err(SnapshotError::Device {
+                expected: self.device_id,
+                found: snapshot.device_id,
+            });
+        }
+        let outbox = snapshot
+            .outbox
+            .iter()
+            .map(|f| FrameV1::decode(f))
+            .collect::<Result<VecDeque<_>, _>>()
+            .map_err(SnapshotError::Frame)?;
+        let rebase = Rebase::new(snapshot.taken_at, now);
+        if let Some(acks) = &mut self.acks {
+            acks.import(snapshot.pending, rebase)
+                .map_err(SnapshotError::Frame)?;
+        }
+        if let Some(registry) = &self.registry {
+            registry
+                .import(snapshot.devices.iter().map(String::as_str))
+                .map_err(SnapshotError::Registry)?;
+        }
+        if let Some(replay) = &mut self.replay {
+            replay.import(snapshot.replay, rebase);
+        }
+        // never move back onto counters already sent
+        self.counter = self.counter.max(snapshot.counter);
+        self.state = snapshot.state;
+        self.deadline = snapshot.deadline_in.map(|d| rebase.after(d));
+        self.outbox = outbox;
+        self.held = None;
+        self.events.clear();
+        Ok(())
+    }
+
+    /// The next frame to put on the wire.
+    pub fn poll_transmit(&mut self) -> Option<FrameV1> {
+        self.outbox.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<SessionEvent> {
+        self.events.pop_front()
+    }
+
+    /// Queue what [`Session::reconnect`] kept back, with every frame
+    /// awaiting an ack.
+    fn release_held(&mut self) {
+        let Some(mut frames) = self.held.take() else {
+            return;
+        };
+        if let Some(acks) = &mut self.acks {
+            frames.extend(acks.resend_all(self.clock.now()));
+        }
+        frames.sort_by_key(|f| f.header.counter);
+        self.outbox.extend(frames);
+    }
+
+    fn expect(&self, state: SessionState, action: &'static str) -> Result<(), SessionError> {
+        if self.state != state {
+            return Err(SessionError::InvalidTransition {
+                state: self.state,
+                action,
+            });
+        }
+        Ok(())
+    }
+
+    fn enter(&mut self, to: SessionState) {
+        let from = self.state;
+        self.state = to;
+        let timeout = match to {
+            SessionState::HelloSent | SessionState::HelloReceived => {
+                Some(self.config.handshake_timeout)
+            }
+            SessionState::Closing => Some(self.config.close_timeout),
+            _ => None,
+        };
+        self.deadline = timeout.map(|t| self.clock.now() + t);
+        self.events
+            .push_back(SessionEvent::StateChanged { from, to });
+    }
+
+    fn send_control(&mut self, control: Control) -> Result<(), SessionError> {
+        self.queue(MsgType::Command, Flags::new(0).unwrap(), control.encode())
+            .map(drop)
+    }
+
+    fn queue(
+        &mut self,
+        msg_type: MsgType,
+        flags: Flags,
+        body: Vec<u8>,
+    ) -> Result<FrameId, SessionError> {
+        let counter =
+            next_counter(self.counter, OverflowPolicy::Error).map_err(SessionError::Counter)?;
+        self.counter = counter;
+        let frame = FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type,
+                flags,
+                device_id: self.device_id,
+                counter,
+            },
+            body: body.into(),
+        };
+        let id = frame.header.id();
+        if let Some(acks) = &mut self.acks
+            && flags.ack_required()
+        {
+            acks.register(&frame).map_err(SessionError::Ack)?;
+        }
+        self.outbox.push_back(frame);
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use SessionState::*;
+
+    const DEV: [u8; 8] = *b"DEV00001";
+
+    fn pair() -> (Session, Session, ManualClock) {
+        let clock = ManualClock::new();
+        let new = || Session::with_clock(DEV, SessionConfig::default(), Arc::new(clock.clone()));
+        (new(), new(), clock)
+    }
+
+    /// Deliver everything `from` has queued to `to`.
+    fn shuttle(from: &mut Session, to: &mut Session) {
+        while let Some(f) = from.poll_transmit() {
+            to.on_frame(f).unwrap();
+        }
+    }
+
+    fn states(s: &mut Session) -> Vec<SessionState> {
+        std::iter::from_fn(|| s.poll_event())
+            .filter_map(|e| match e {
+                SessionEvent::StateChanged { to, .. } => Some(to),
+                SessionEvent::TimedOut(_) | SessionEvent::Unacked(_) => None,
+            })
+            .collect()
+    }
+
+    fn event(body: &[u8]) -> FrameV1 {
+        let mut f = crate::transport::tests::frame(9);
+        f.body = body.into();
+        f
+    }
+
+    #[test]
+    fn handshake_traffic_and_close() {
+        let (mut a, mut b, _) = pair();
+        a.connect().unwrap();
+        shuttle(&mut a, &mut b);
+        assert_eq!(b.state(), HelloReceived);
+        b.accept().unwrap();
+        shuttle(&mut b, &mut a);
+        assert_eq!((a.state(), b.state()), (Established, Established));
+
+        a.send(MsgType::Event, Flags::new(0).unwrap(), vec![1])
+            .unwrap();
+        let f = a.poll_transmit().unwrap();
+        assert_eq!(b.on_frame(f.clone()).unwrap(), Some(f));
+
+        a.close().unwrap();
+        assert_eq!(a.state(), Closing);
+        shuttle(&mut a, &mut b);
+        assert_eq!(b.state(), Closed);
+        shuttle(&mut b, &mut a);
+        assert_eq!(a.state(), Closed);
+
+        assert_eq!(states(&mut a), [HelloSent, Established, Closing, Closed]);
+        assert_eq!(states(&mut b), [HelloReceived, Established, Closed]);
+    }
+
+    #[test]
+    fn reconnect_repeats_the_handshake_then_resends() {
+        let (a, mut b, clock) = pair();
+        let acks = AckTracker::new(Default::default(), Arc::new(clock.clone()));
+        let mut a = a.with_acks(acks);
+        a.connect().unwrap();
+        shuttle(&mut a, &mut b);
+        b.accept().unwrap();
+        shuttle(&mut b, &mut a);
+
+        let ack_required = Flags::new(Flags::ACK_REQUIRED).unwrap();
+        a.send(MsgType::Event, ack_required, vec![1]).unwrap();
+        // lost with the link
+        assert_eq!(a.poll_transmit().unwrap().header.counter, 2);
+        a.send(MsgType::Event, Flags::new(0).unwrap(), vec![2])
+            .unwrap();
+        states(&mut a);
+
+        a.reconnect().unwrap();
+        assert_eq!(a.state(), HelloSent);
+        let hello = a.poll_transmit().unwrap();
+        assert_eq!(Control::parse(&hello), Some(Control::Hello { version: 1 }));
+        assert_eq!(a.poll_transmit(), None);
+        assert_eq!(a.snapshot().outbox.len(), 1);
+
+        // the peer starts afresh on the new link
+        let mut b = Session::with_clock(DEV, SessionConfig::default(), Arc::new(clock.clone()));
+        b.on_frame(hello).unwrap();
+        b.accept().unwrap();
+        shuttle(&mut b, &mut a);
+        assert_eq!(states(&mut a), [HelloSent, Established]);
+        let sent: Vec<_> = std::iter::from_fn(|| a.poll_transmit())
+            .map(|f| (f.header.counter, f.body.to_vec()))
+            .collect();
+        assert_eq!(sent, [(2, vec![1]), (3, vec![2])]);
+        assert_eq!(a.acks().unwrap().state(DEV, 2).unwrap().attempts, 2);
+
+        let (mut idle, _, _) = pair();
+        assert!(matches!(
+            idle.reconnect(),
+            Err(SessionError::InvalidTransition {
+                state: Idle,
+                action: "reconnect"
+            })
+        ));
+    }
+
+    #[test]
+    fn simultaneous_open() {
+        let (mut a, mut b, _) = pair();
+        a.connect().unwrap();
+        b.connect().unwrap();
+        shuttle(&mut a, &mut b);
+        shuttle(&mut b, &mut a);
+        assert_eq!((a.state(), b.state()), (Established, Established));
+    }
+
+    #[test]
+    fn half_open_handshakes_time_out() {
+        let (mut a, mut b, clock) = pair();
+        a.connect().unwrap();
+        shuttle(&mut a, &mut b);
+        let deadline = a.next_deadline().unwrap();
+        assert_eq!(deadline, clock.now() + Duration::from_secs(5));
+
+        clock.advance(Duration::from_secs(5));
+        a.poll_timeout(clock.now());
+        b.poll_timeout(clock.now());
+        assert_eq!((a.state(), b.state()), (Closed, Closed));
+        assert!(
+            std::iter::from_fn(|| b.poll_event())
+                .any(|e| e == SessionEvent::TimedOut(HelloReceived))
+        );
+        assert_eq!(a.next_deadline(), None);
+    }
+
+    #[test]
+    fn close_times_out_without_peer_bye() {
+        let (mut a, mut b, clock) = pair();
+        a.connect().unwrap();
+        shuttle(&mut a, &mut b);
+        b.accept().unwrap();
+        shuttle(&mut b, &mut a);
+        a.close().unwrap();
+        clock.advance(Duration::from_secs(1));
+        a.poll_timeout(clock.now());
+        assert_eq!(a.state(), Closing);
+        clock.advance(Duration::from_secs(1));
+        a.poll_timeout(clock.now());
+        assert_eq!(a.state(), Closed);
+    }
+
+    #[test]
+    fn refuses_illegal_transitions() {
+        let (mut a, mut b, _) = pair();
+        let no_flags = Flags::new(0).unwrap();
+        assert_eq!(
+            a.send(MsgType::Command, no_flags, vec![1]),
+            Err(SessionError::NotEstablished(Idle))
+        );
+        assert_eq!(
+            a.on_frame(event(&[1])),
+            Err(SessionError::NotEstablished(Idle))
+        );
+        assert!(matches!(
+            a.accept(),
+            Err(SessionError::InvalidTransition { state: Idle, .. })
+        ));
+        assert!(matches!(
+            a.close(),
+            Err(SessionError::InvalidTransition { .. })
+        ));
+        let bye = event(&[0x00, 0x53, BYE]);
+        let mut bye_command = bye.clone();
+        bye_command.header.msg_type = MsgType::Command;
+        assert_eq!(
+            a.on_frame(bye_command.clone()),
+            Err(SessionError::Unexpected {
+                state: Idle,
+                control: Control::Bye
+            })
+        );
+        assert_eq!(Control::parse(&bye), None, "control travels as COMMAND");
+        let mut ping = bye_command.clone();
+        ping.body = Control::Ping.encode().into();
+        assert_eq!(a.on_frame(ping), Ok(None), "PING is fine in any state");
+
+        a.connect().unwrap();
+        assert!(matches!(
+            a.connect(),
+            Err(SessionError::InvalidTransition {
+                state: HelloSent,
+                ..
+            })
+        ));
+        assert_eq!(
+            a.send(MsgType::Event, no_flags, vec![]),
+            Err(SessionError::NotEstablished(HelloSent))
+        );
+        shuttle(&mut a, &mut b);
+        b.close().unwrap();
+        assert_eq!(b.state(), Closed);
+        shuttle(&mut b, &mut a);
+        assert_eq!(a.state(), Closed);
+
+        let mut hello = event(&[0x00, 0x53, HELLO, VERSION_V1]);
+        hello.header.msg_type = MsgType::Command;
+        assert!(matches!(
+            a.on_frame(hello),
+            Err(SessionError::Unexpected { state: Closed, .. })
+        ));
+        assert!(matches!(
+            a.connect(),
+            Err(SessionError::InvalidTransition { .. })
+        ));
+    }
+}
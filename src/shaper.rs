@@ -0,0 +1,378 @@
+//! Outbound traffic shaping.
+//!
+//! A [`ByteBucket`] holds up to `burst` bytes of budget, refilled at
+//! `bytes_per_second`. A frame may go out while some budget is left and
+//! then costs its encoded length, so the budget can run into debt by at
+//! most one frame; frames larger than the burst still get through, and
+//! over any long stretch the bytes sent converge to the configured rate.
+//! Because a frame's size is only needed after it is let through, a
+//! [`Sink`]'s `poll_ready` can wait for the budget before the frame exists.
+//!
+//! [`Priority::Urgent`] frames may also go out while the debt is under
+//! `reserve` bytes, so they are not stuck behind a backlog of normal ones.
+//!
+//! [`Shaped`] applies a bucket to a [`Transport`] or a [`Sink`] of frames.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::Sink;
+use tokio::time::Sleep;
+
+use crate::FrameV1;
+use crate::clock::{Clock, SystemClock};
+use crate::transport::{PeerAddr, RxFrame, Transport, TransportError, TransportId};
+use crate::window::Priority;
+
+/// Billionths of a byte per byte.
+const SCALE: i128 = 1_000_000_000;
+
+#[derive(Debug, Clone)]
+pub struct ShaperConfig {
+    pub bytes_per_second: u64,
+    pub burst: u64,
+    /// Debt only urgent frames may run up.
+    pub reserve: u64,
+}
+
+impl Default for ShaperConfig {
+    fn default() -> Self {
+        ShaperConfig {
+            bytes_per_second: 64 * 1024,
+            burst: 16 * 1024,
+            reserve: 1024,
+        }
+    }
+}
+
+/// A token bucket counted in bytes. Starts full.
+pub struct ByteBucket {
+    clock: Arc<dyn Clock>,
+    config: ShaperConfig,
+    /// In billionths of a byte; negative is debt.
+    tokens: i128,
+    refilled: Instant,
+}
+
+impl ByteBucket {
+    pub fn new(config: ShaperConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: ShaperConfig, clock: Arc<dyn Clock>) -> Self {
+        ByteBucket {
+            refilled: clock.now(),
+            tokens: i128::from(config.burst) * SCALE,
+            clock,
+            config,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.saturating_duration_since(self.refilled).as_nanos() as i128;
+        let full = i128::from(self.config.burst) * SCALE;
+        self.tokens = (self.tokens + elapsed * i128::from(self.config.bytes_per_second)).min(full);
+        self.refilled = now;
+    }
+
+    /// How long until a frame of `priority` may go out; zero if it may now.
+    /// `None` if it never will, because nothing refills the bucket.
+    pub fn ready_in(&mut self, priority: Priority) -> Option<Duration> {
+        self.refill();
+        let floor = match priority {
+            Priority::Normal => 0,
+            Priority::Urgent => -i128::from(self.config.reserve) * SCALE,
+        };
+        if self.tokens > floor {
+            return Some(Duration::ZERO);
+        }
+        let rate = i128::from(self.config.bytes_per_second);
+        let nanos = (floor - self.tokens + rate).checked_div(rate)?;
+        Some(Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX)))
+    }
+
+    /// Charge `bytes` that were let through.
+    pub fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as i128 * SCALE;
+    }
+
+    /// Whole bytes of budget left now; negative while in debt.
+    pub fn budget(&mut self) -> i64 {
+        self.refill();
+        (self.tokens.div_euclid(SCALE)).clamp(i64::MIN.into(), i64::MAX.into()) as i64
+    }
+}
+
+/// A transport or frame sink whose sends wait for a [`ByteBucket`].
+pub struct Shaped<T> {
+    inner: T,
+    bucket: ByteBucket,
+    sleep: Option<Pin<Box<Sleep>>>,
+    delayed: u64,
+}
+
+impl<T> Shaped<T> {
+    pub fn new(inner: T, config: ShaperConfig) -> Self {
+        Self::with_bucket(inner, ByteBucket::new(config))
+    }
+
+    pub fn with_bucket(inner: T, bucket: ByteBucket) -> Self {
+        Shaped {
+            inner,
+            bucket,
+            sleep: None,
+            delayed: 0,
+        }
+    }
+
+    /// Bytes of budget left; see [`ByteBucket::budget`].
+    pub fn budget(&mut self) -> i64 {
+        self.bucket.budget()
+    }
+
+    /// Frames that have had to wait for budget so far.
+    pub fn delayed(&self) -> u64 {
+        self.delayed
+    }
+
+    /// The wrapped transport, e.g. for its own queue depth.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Ready once a frame of `priority` may go out.
+    fn poll_budget(&mut self, priority: Priority, cx: &mut Context<'_>) -> Poll<()> {
+        let mut waited = false;
+        loop {
+            let wait = self.bucket.ready_in(priority).unwrap_or(Duration::MAX);
+            if wait.is_zero() {
+                self.sleep = None;
+                return Poll::Ready(());
+            }
+            if !waited && self.sleep.is_none() {
+                self.delayed += 1;
+            }
+            waited = true;
+            let deadline = tokio::time::Instant::now()
+                .checked_add(wait)
+                .unwrap_or_else(far_future);
+            let sleep = self
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+            sleep.as_mut().reset(deadline);
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+fn far_future() -> tokio::time::Instant {
+    tokio::time::Instant::now() + Duration::from_secs(86400 * 365)
+}
+
+impl<T: Transport> Shaped<T> {
+    /// Send a frame that may dip into the reserve.
+    pub async fn send_urgent(&mut self, frame: &FrameV1) -> Result<(), TransportError> {
+        self.send_with(frame, Priority::Urgent).await
+    }
+
+    async fn send_with(
+        &mut self,
+        frame: &FrameV1,
+        priority: Priority,
+    ) -> Result<(), TransportError> {
+        std::future::poll_fn(|cx| self.poll_budget(priority, cx)).await;
+        self.bucket.consume(frame.encoded_len());
+        self.inner.send(frame).await
+    }
+}
+
+impl<T: Transport> Transport for Shaped<T> {
+    async fn send(&mut self, frame: &FrameV1) -> Result<(), TransportError> {
+        self.send_with(frame, Priority::Normal).await
+    }
+
+    async fn recv(&mut self) -> Result<FrameV1, TransportError> {
+        self.inner.recv().await
+    }
+
+    async fn recv_rx(&mut self) -> Result<RxFrame, TransportError> {
+        self.inner.recv_rx().await
+    }
+
+    async fn close(&mut self) -> Result<(), TransportError> {
+        self.inner.close().await
+    }
+
+    fn peer(&self) -> &PeerAddr {
+        self.inner.peer()
+    }
+
+    fn id(&self) -> TransportId {
+        self.inner.id()
+    }
+}
+
+/// Backpressure: `poll_ready` is pending until there is budget and the
+/// inner sink is ready.
+impl<S: Sink<FrameV1> + Unpin> Sink<FrameV1> for Shaped<S> {
+    type Error = S::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.poll_budget(Priority::Normal, cx).is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: FrameV1) -> Result<(), Self::Error> {
+        self.bucket.consume(frame.encoded_len());
+        Pin::new(&mut self.inner).start_send(frame)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::transport::loopback_pair;
+    use crate::transport::tests::frame;
+    use futures::SinkExt;
+
+    fn bucket(bytes_per_second: u64, burst: u64, reserve: u64) -> (ByteBucket, ManualClock) {
+        let clock = ManualClock::new();
+        let config = ShaperConfig {
+            bytes_per_second,
+            burst,
+            reserve,
+        };
+        (
+            ByteBucket::with_clock(config, Arc::new(clock.clone())),
+            clock,
+        )
+    }
+
+    #[test]
+    fn long_run_rate_converges() {
+        let (mut b, clock) = bucket(1000, 300, 0);
+        let step = Duration::from_millis(7);
+        let mut sent = 0u64;
+        for _ in 0..20_000 {
+            while b.ready_in(Priority::Normal) == Some(Duration::ZERO) {
+                b.consume(113);
+                sent += 113;
+            }
+            clock.advance(step);
+        }
+        // 140 s at 1000 B/s, plus the initial burst, give or take a frame
+        let expected = 140 * 1000 + 300;
+        assert!(sent.abs_diff(expected) <= 113, "sent {sent}");
+    }
+
+    #[test]
+    fn waits_exactly_for_the_debt() {
+        let (mut b, clock) = bucket(1000, 100, 0);
+        b.consume(150);
+        assert_eq!(b.budget(), -50);
+        let ns = Duration::from_nanos(1);
+        assert_eq!(
+            b.ready_in(Priority::Normal),
+            Some(Duration::from_millis(50) + ns)
+        );
+        // an empty budget is not enough
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(b.budget(), 0);
+        assert_eq!(b.ready_in(Priority::Normal), Some(ns));
+        clock.advance(ns);
+        assert_eq!(b.ready_in(Priority::Normal), Some(Duration::ZERO));
+
+        // never more than the burst
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(b.budget(), 100);
+    }
+
+    #[test]
+    fn urgent_frames_dip_into_the_reserve() {
+        let (mut b, _clock) = bucket(1000, 100, 200);
+        b.consume(250);
+        assert_eq!(
+            b.ready_in(Priority::Normal),
+            Some(Duration::from_nanos(150_000_001))
+        );
+        assert_eq!(b.ready_in(Priority::Urgent), Some(Duration::ZERO));
+        b.consume(100);
+        assert_eq!(
+            b.ready_in(Priority::Urgent),
+            Some(Duration::from_nanos(50_000_001))
+        );
+
+        let (mut stuck, _clock) = bucket(0, 10, 0);
+        stuck.consume(11);
+        assert_eq!(stuck.ready_in(Priority::Normal), None);
+    }
+
+    #[tokio::test]
+    async fn transport_sends_are_spaced_out() {
+        let (a, mut b) = loopback_pair();
+        let len = frame(1).encoded_len() as u64;
+        // two frames at once, one into debt, then one every 5 ms
+        let mut a = Shaped::new(
+            a,
+            ShaperConfig {
+                bytes_per_second: len * 200,
+                burst: len + 1,
+                reserve: 0,
+            },
+        );
+        let start = tokio::time::Instant::now();
+        for counter in 1..=5 {
+            a.send(&frame(counter)).await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(14));
+        assert_eq!(a.delayed(), 3);
+        for counter in 1..=5 {
+            assert_eq!(b.recv().await.unwrap().header.counter, counter);
+        }
+    }
+
+    #[tokio::test]
+    async fn sink_backpressure_comes_from_poll_ready() {
+        let (tx, mut rx) = futures::channel::mpsc::channel::<FrameV1>(16);
+        let len = frame(1).encoded_len() as u64;
+        let mut shaped = Shaped::new(
+            tx,
+            ShaperConfig {
+                bytes_per_second: len * 200,
+                burst: len + 1,
+                reserve: 0,
+            },
+        );
+        shaped.send(frame(1)).await.unwrap();
+        shaped.send(frame(2)).await.unwrap();
+        let ready = std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut shaped).poll_ready(cx)));
+        assert!(ready.await.is_pending(), "the budget is spent");
+        let start = tokio::time::Instant::now();
+        shaped.send(frame(3)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(4));
+        assert_eq!(shaped.delayed(), 1);
+        for counter in 1..=3 {
+            assert_eq!(rx.try_recv().unwrap().header.counter, counter);
+        }
+    }
+}
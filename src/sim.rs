@@ -0,0 +1,602 @@
+//! Simulated devices, for load and end-to-end tests.
+//!
+//! A [`SimDevice`] plays one device over any [`Transport`]: it says HELLO
+//! and waits for the peer's, sends EVENTs at the pace its [`EventTiming`]
+//! draws, answers PINGs and answers COMMANDs as its [`CommandPolicy`]
+//! scripts, numbering its frames the way firmware does. It measures how
+//! long each acked EVENT took to be acked. A [`SimFleet`] runs many at
+//! once, each with its own id and a random stream derived from one seed,
+//! so a run can be repeated.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::time::{Instant, sleep_until};
+
+use crate::ack::AckBody;
+use crate::ping::pong;
+use crate::request::ack_reply;
+use crate::session::Control;
+use crate::transport::{Transport, TransportError};
+use crate::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+/// Time between one EVENT and the next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventTiming {
+    Fixed(Duration),
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    /// Exponentially distributed gaps: events arrive as a Poisson process.
+    Poisson {
+        mean: Duration,
+    },
+}
+
+impl EventTiming {
+    fn gap(&self, rng: &mut Rng) -> Duration {
+        match *self {
+            EventTiming::Fixed(gap) => gap,
+            EventTiming::Uniform { min, max } => {
+                let span = max.saturating_sub(min).as_nanos() as u64;
+                min + Duration::from_nanos(rng.next_u64() % (span + 1))
+            }
+            EventTiming::Poisson { mean } => mean.mul_f64(-(1.0 - rng.next_f64()).ln()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandReply {
+    Ack,
+    Nack(String),
+    /// Leave the command unanswered.
+    Ignore,
+}
+
+/// How a device answers the COMMANDs that ask to be acked: the replies in
+/// turn, starting over after the last.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandPolicy {
+    script: Vec<CommandReply>,
+}
+
+impl CommandPolicy {
+    pub fn always(reply: CommandReply) -> Self {
+        CommandPolicy {
+            script: vec![reply],
+        }
+    }
+
+    /// An empty script acks everything.
+    pub fn script(replies: impl IntoIterator<Item = CommandReply>) -> Self {
+        let script: Vec<_> = replies.into_iter().collect();
+        if script.is_empty() {
+            return Self::default();
+        }
+        CommandPolicy { script }
+    }
+
+    fn reply(&self, n: u64) -> &CommandReply {
+        &self.script[(n % self.script.len() as u64) as usize]
+    }
+}
+
+impl Default for CommandPolicy {
+    fn default() -> Self {
+        Self::always(CommandReply::Ack)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub device_id: [u8; 8],
+    pub timing: EventTiming,
+    /// Bytes of random body per EVENT.
+    pub body_len: usize,
+    /// EVENTs to send before lingering.
+    pub events: u64,
+    /// Send EVENTs with ACK_REQUIRED.
+    pub ack_events: bool,
+    pub commands: CommandPolicy,
+    /// Say HELLO first and BYE at the end.
+    pub handshake: bool,
+    /// Time to keep answering after the last EVENT.
+    pub linger: Duration,
+    /// Counter of the first frame sent.
+    pub first_counter: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            device_id: *b"SIM00000",
+            timing: EventTiming::Fixed(Duration::from_millis(100)),
+            body_len: 16,
+            events: 10,
+            ack_events: true,
+            commands: CommandPolicy::default(),
+            handshake: true,
+            linger: Duration::from_millis(100),
+            first_counter: 1,
+        }
+    }
+}
+
+/// What a run did.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimStats {
+    pub frames_sent: u64,
+    pub events_sent: u64,
+    pub acks_received: u64,
+    pub nacks_received: u64,
+    pub commands_received: u64,
+    pub commands_acked: u64,
+    pub commands_nacked: u64,
+    pub pings_answered: u64,
+    /// From sending each acked EVENT to its ack.
+    pub latencies: Vec<Duration>,
+}
+
+impl SimStats {
+    pub fn merge(&mut self, other: &SimStats) {
+        self.frames_sent += other.frames_sent;
+        self.events_sent += other.events_sent;
+        self.acks_received += other.acks_received;
+        self.nacks_received += other.nacks_received;
+        self.commands_received += other.commands_received;
+        self.commands_acked += other.commands_acked;
+        self.commands_nacked += other.commands_nacked;
+        self.pings_answered += other.pings_answered;
+        self.latencies.extend_from_slice(&other.latencies);
+    }
+
+    /// The latency below which a fraction `q` of them fall.
+    pub fn latency_quantile(&self, q: f64) -> Option<Duration> {
+        let mut sorted = self.latencies.clone();
+        sorted.sort();
+        let last = sorted.len().checked_sub(1)?;
+        Some(sorted[((last as f64 * q.clamp(0.0, 1.0)).round()) as usize])
+    }
+}
+
+/// Step of splitmix64's state.
+pub(crate) const SPLITMIX_GAMMA: u64 = 0x9e3779b97f4a7c15;
+
+/// splitmix64's output for an already stepped `state`.
+pub(crate) fn splitmix64(state: u64) -> u64 {
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// splitmix64: small, and the same everywhere for a seed.
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(SPLITMIX_GAMMA);
+        splitmix64(self.0)
+    }
+
+    /// Uniform in [0, 1).
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+pub struct SimDevice<T> {
+    transport: T,
+    config: SimConfig,
+    rng: Rng,
+    counter: u64,
+    established: bool,
+    closed: bool,
+    /// Acked EVENTs not yet acked, by counter.
+    pending: HashMap<u64, Instant>,
+    commands: u64,
+    stats: SimStats,
+}
+
+impl<T: Transport> SimDevice<T> {
+    pub fn new(transport: T, config: SimConfig) -> Self {
+        SimDevice {
+            transport,
+            counter: config.first_counter,
+            config,
+            rng: Rng(0),
+            established: false,
+            closed: false,
+            pending: HashMap::new(),
+            commands: 0,
+            stats: SimStats::default(),
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Rng(seed);
+        self
+    }
+
+    /// Play the device until its events are sent and it has lingered, or
+    /// the peer says BYE.
+    pub async fn run(mut self) -> Result<SimStats, TransportError> {
+        if self.config.handshake {
+            self.send_numbered(
+                MsgType::Command,
+                false,
+                Control::Hello {
+                    version: VERSION_V1,
+                }
+                .encode(),
+            )
+            .await?;
+            while !self.established && !self.closed {
+                self.receive().await?;
+            }
+        }
+        let mut next = Instant::now() + self.config.timing.gap(&mut self.rng);
+        let mut linger_until = None;
+        while !self.closed {
+            let wake = if self.stats.events_sent < self.config.events {
+                next
+            } else {
+                *linger_until.get_or_insert_with(|| Instant::now() + self.config.linger)
+            };
+            tokio::select! {
+                _ = sleep_until(wake) => {
+                    if linger_until.is_some() {
+                        break;
+                    }
+                    self.send_event().await?;
+                    next += self.config.timing.gap(&mut self.rng);
+                }
+                frame = self.transport.recv() => self.on_received(frame).await?,
+            }
+        }
+        if self.config.handshake && !self.closed {
+            self.send_numbered(MsgType::Command, false, Control::Bye.encode())
+                .await?;
+        }
+        Ok(self.stats)
+    }
+
+    async fn receive(&mut self) -> Result<(), TransportError> {
+        let frame = self.transport.recv().await;
+        self.on_received(frame).await
+    }
+
+    async fn on_received(
+        &mut self,
+        frame: Result<FrameV1, TransportError>,
+    ) -> Result<(), TransportError> {
+        match frame {
+            Ok(frame) => self.on_frame(frame).await,
+            // a garbled frame is lost, as on a real link
+            Err(TransportError::Protocol(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn on_frame(&mut self, frame: FrameV1) -> Result<(), TransportError> {
+        let header = &frame.header;
+        if header.device_id != self.config.device_id {
+            return Ok(());
+        }
+        match header.msg_type {
+            MsgType::Ack => self.on_ack(&frame),
+            MsgType::Command => match Control::parse(&frame) {
+                Some(Control::Hello { .. }) => self.established = true,
+                Some(Control::Bye) => self.closed = true,
+                Some(Control::Ping) => {
+                    self.reply(pong(&frame).unwrap()).await?;
+                    self.stats.pings_answered += 1;
+                }
+                Some(_) => {}
+                None => self.on_command(&frame).await?,
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn on_ack(&mut self, ack: &FrameV1) {
+        let Ok(body) = AckBody::decode(&ack.body) else {
+            return;
+        };
+        let named = ack.header.counter;
+        let now = Instant::now();
+        let covered: Vec<u64> = self
+            .pending
+            .keys()
+            .copied()
+            .filter(|c| body.covers(named, *c))
+            .collect();
+        for counter in covered {
+            let sent_at = self.pending.remove(&counter).unwrap();
+            if matches!(body, AckBody::Nack { .. }) {
+                self.stats.nacks_received += 1;
+            } else {
+                self.stats.acks_received += 1;
+                self.stats.latencies.push(now - sent_at);
+            }
+        }
+    }
+
+    async fn on_command(&mut self, command: &FrameV1) -> Result<(), TransportError> {
+        self.stats.commands_received += 1;
+        if !command.header.flags.ack_required() {
+            return Ok(());
+        }
+        let n = self.commands;
+        self.commands += 1;
+        let body = match self.config.commands.reply(n) {
+            CommandReply::Ack => AckBody::Single,
+            CommandReply::Nack(reason) => AckBody::Nack {
+                reason: reason.clone(),
+            },
+            CommandReply::Ignore => return Ok(()),
+        };
+        if matches!(body, AckBody::Nack { .. }) {
+            self.stats.commands_nacked += 1;
+        } else {
+            self.stats.commands_acked += 1;
+        }
+        self.reply(ack_reply(&command.header, body)).await
+    }
+
+    async fn send_event(&mut self) -> Result<(), TransportError> {
+        let body = (0..self.config.body_len)
+            .map(|_| self.rng.next_u64() as u8)
+            .collect();
+        let counter = self
+            .send_numbered(MsgType::Event, self.config.ack_events, body)
+            .await?;
+        if self.config.ack_events {
+            self.pending.insert(counter, Instant::now());
+        }
+        self.stats.events_sent += 1;
+        Ok(())
+    }
+
+    async fn send_numbered(
+        &mut self,
+        msg_type: MsgType,
+        ack_required: bool,
+        body: Vec<u8>,
+    ) -> Result<u64, TransportError> {
+        let counter = self.counter;
+        self.counter += 1;
+        let bits = if ack_required { Flags::ACK_REQUIRED } else { 0 };
+        let frame = FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type,
+                flags: Flags::new(bits).unwrap(),
+                device_id: self.config.device_id,
+                counter,
+            },
+            body: body.into(),
+        };
+        self.reply(frame).await?;
+        Ok(counter)
+    }
+
+    async fn reply(&mut self, frame: FrameV1) -> Result<(), TransportError> {
+        self.transport.send(&frame).await?;
+        self.stats.frames_sent += 1;
+        Ok(())
+    }
+}
+
+/// Many [`SimDevice`]s sharing one configuration, with ids `SIM00000`,
+/// `SIM00001` and so on.
+#[derive(Debug, Clone)]
+pub struct SimFleet {
+    base: SimConfig,
+    devices: usize,
+    seed: u64,
+}
+
+impl SimFleet {
+    /// `base.device_id` is replaced by each device's own.
+    pub fn new(base: SimConfig, devices: usize) -> Self {
+        SimFleet {
+            base,
+            devices: devices.min(100_000),
+            seed: 0,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn device_ids(&self) -> Vec<[u8; 8]> {
+        (0..self.devices).map(Self::device_id).collect()
+    }
+
+    fn device_id(index: usize) -> [u8; 8] {
+        let mut id = *b"SIM00000";
+        id[3..].copy_from_slice(format!("{index:05}").as_bytes());
+        id
+    }
+
+    /// Run a device over each transport, in the order of
+    /// [`SimFleet::device_ids`], all at once. Returns each one's outcome.
+    pub async fn run<T: Transport>(
+        &self,
+        transports: impl IntoIterator<Item = T>,
+    ) -> Vec<Result<SimStats, TransportError>> {
+        let runs = transports
+            .into_iter()
+            .take(self.devices)
+            .enumerate()
+            .map(|(i, transport)| {
+                let config = SimConfig {
+                    device_id: Self::device_id(i),
+                    ..self.base.clone()
+                };
+                let seed = Rng(self.seed ^ i as u64).next_u64();
+                SimDevice::new(transport, config).with_seed(seed).run()
+            });
+        futures::future::join_all(runs).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispatch::{Dispatcher, Route};
+    use crate::transport::{LoopbackTransport, loopback_pair};
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Acks EVENTs and answers HELLO, as a gateway would.
+    async fn gateway(mut transport: LoopbackTransport) {
+        let dispatcher = Dispatcher::new().route(Route::new(MsgType::Event), |_: &_| Ok(None));
+        while let Ok(frame) = transport.recv_rx().await {
+            let reply = match Control::parse(&frame) {
+                Some(Control::Hello { .. }) => {
+                    let mut hello = frame.into_frame();
+                    hello.body = Control::Hello {
+                        version: VERSION_V1,
+                    }
+                    .encode()
+                    .into();
+                    Some(hello)
+                }
+                Some(_) => None,
+                None => dispatcher.dispatch(frame).await,
+            };
+            if let Some(reply) = reply {
+                transport.send(&reply).await.unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn timing_is_seeded_and_distributed() {
+        let gaps = |timing: EventTiming, seed| {
+            let mut rng = Rng(seed);
+            (0..2000).map(|_| timing.gap(&mut rng)).collect::<Vec<_>>()
+        };
+        let uniform = EventTiming::Uniform {
+            min: 10 * MS,
+            max: 20 * MS,
+        };
+        assert_eq!(gaps(uniform, 7), gaps(uniform, 7));
+        assert_ne!(gaps(uniform, 7), gaps(uniform, 8));
+        assert!(
+            gaps(uniform, 7)
+                .iter()
+                .all(|g| (10 * MS..=20 * MS).contains(g))
+        );
+
+        let poisson = gaps(EventTiming::Poisson { mean: 10 * MS }, 1);
+        let mean = poisson.iter().sum::<Duration>() / poisson.len() as u32;
+        assert!((9 * MS..11 * MS).contains(&mean), "{mean:?}");
+        assert_eq!(gaps(EventTiming::Fixed(MS), 3), vec![MS; 2000]);
+    }
+
+    #[tokio::test]
+    async fn fleet_runs_end_to_end() {
+        let fleet = SimFleet::new(
+            SimConfig {
+                timing: EventTiming::Uniform {
+                    min: MS,
+                    max: 3 * MS,
+                },
+                events: 10,
+                linger: 20 * MS,
+                ..SimConfig::default()
+            },
+            5,
+        )
+        .with_seed(42);
+        let mut devices = Vec::new();
+        for _ in 0..5 {
+            let (device, gateway_end) = loopback_pair();
+            tokio::spawn(gateway(gateway_end));
+            devices.push(device);
+        }
+        let results = fleet.run(devices).await;
+        let mut total = SimStats::default();
+        for stats in results {
+            let stats = stats.unwrap();
+            assert_eq!(stats.events_sent, 10);
+            assert_eq!(stats.acks_received, 10);
+            assert_eq!(stats.frames_sent, 12, "with HELLO and BYE");
+            total.merge(&stats);
+        }
+        assert_eq!(total.latencies.len(), 50);
+        assert!(total.latency_quantile(1.0).unwrap() >= total.latency_quantile(0.5).unwrap());
+        assert_eq!(fleet.device_ids()[4], *b"SIM00004");
+    }
+
+    #[tokio::test]
+    async fn scripted_commands_and_pings() {
+        let (device, mut gateway) = loopback_pair();
+        let config = SimConfig {
+            device_id: *b"DEV00001",
+            events: 0,
+            handshake: false,
+            linger: 50 * MS,
+            commands: CommandPolicy::script([
+                CommandReply::Ack,
+                CommandReply::Nack("busy".into()),
+                CommandReply::Ignore,
+            ]),
+            ..SimConfig::default()
+        };
+        let run = tokio::spawn(SimDevice::new(device, config).run());
+
+        let command = |counter, body: Vec<u8>| FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Command,
+                flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+                device_id: *b"DEV00001",
+                counter,
+            },
+            body: body.into(),
+        };
+        for counter in 1..=4 {
+            gateway.send(&command(counter, vec![0x10])).await.unwrap();
+        }
+        gateway
+            .send(&command(5, Control::Ping.encode()))
+            .await
+            .unwrap();
+        let mut replies = Vec::new();
+        for _ in 0..4 {
+            let ack = gateway.recv().await.unwrap();
+            replies.push((ack.header.counter, AckBody::decode(&ack.body).unwrap()));
+        }
+        assert_eq!(
+            replies,
+            [
+                (1, AckBody::Single),
+                (
+                    2,
+                    AckBody::Nack {
+                        reason: "busy".into()
+                    }
+                ),
+                (4, AckBody::Single),
+                (5, AckBody::Single),
+            ]
+        );
+
+        let stats = run.await.unwrap().unwrap();
+        assert_eq!(stats.commands_received, 4);
+        assert_eq!(stats.commands_acked, 2);
+        assert_eq!(stats.commands_nacked, 1);
+        assert_eq!(stats.pings_answered, 1);
+        assert_eq!(stats.frames_sent, 4);
+    }
+}
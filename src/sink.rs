@@ -0,0 +1,99 @@
+//! Destinations for frame history (databases, journals, exports).
+//!
+//! A [`FrameSink`] is handed one [`FrameRecord`] per frame seen on a link,
+//! including bytes that failed to decode, and decides itself when to make
+//! them durable. [`FrameSink::flush`] forces that.
+
+use std::time::SystemTime;
+
+use crate::transport::{PeerAddr, RxFrame, TransportId};
+use crate::{DecodeError, FrameV1};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Rx => "rx",
+            Direction::Tx => "tx",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    Frame(FrameV1),
+    /// Bytes that did not decode, kept as received.
+    Undecodable {
+        bytes: Vec<u8>,
+        error: DecodeError,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameRecord {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub payload: Payload,
+    /// Where a received frame came from, when known.
+    pub source: Option<PeerAddr>,
+    pub transport_id: Option<TransportId>,
+}
+
+impl FrameRecord {
+    pub fn new(direction: Direction, payload: Payload) -> Self {
+        FrameRecord {
+            timestamp: SystemTime::now(),
+            direction,
+            payload,
+            source: None,
+            transport_id: None,
+        }
+    }
+
+    pub fn rx(frame: FrameV1) -> Self {
+        FrameRecord::new(Direction::Rx, Payload::Frame(frame))
+    }
+
+    pub fn tx(frame: FrameV1) -> Self {
+        FrameRecord::new(Direction::Tx, Payload::Frame(frame))
+    }
+
+    pub fn undecodable(direction: Direction, bytes: Vec<u8>, error: DecodeError) -> Self {
+        FrameRecord::new(direction, Payload::Undecodable { bytes, error })
+    }
+
+    pub fn frame(&self) -> Option<&FrameV1> {
+        match &self.payload {
+            Payload::Frame(f) => Some(f),
+            Payload::Undecodable { .. } => None,
+        }
+    }
+}
+
+/// Keeps the arrival time, source and transport.
+impl From<RxFrame> for FrameRecord {
+    fn from(rx: RxFrame) -> Self {
+        FrameRecord {
+            timestamp: rx.received_at,
+            direction: Direction::Rx,
+            payload: Payload::Frame(rx.frame),
+            source: Some(rx.source),
+            transport_id: Some(rx.transport_id).filter(|&id| id != TransportId::UNASSIGNED),
+        }
+    }
+}
+
+pub trait FrameSink {
+    type Error;
+
+    /// Accept a record. It may be buffered until the sink's own batching
+    /// rules or [`FrameSink::flush`] write it out.
+    fn record(&mut self, record: &FrameRecord) -> Result<(), Self::Error>;
+
+    fn flush(&mut self) -> Result<(), Self::Error>;
+}
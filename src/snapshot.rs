@@ -0,0 +1,269 @@
+//! Carrying a session's state across a restart.
+//!
+//! [`Session::snapshot`](crate::session::Session::snapshot) captures the
+//! session and the parts given to it: its handshake state, counter and
+//! unsent frames, its [`ReplayWindow`](crate::replay::ReplayWindow)'s
+//! windows, the frames its [`AckTracker`](crate::ack::AckTracker) awaits
+//! acks for and its [`DeviceRegistry`](crate::registry::DeviceRegistry)'s
+//! entries. Times are kept relative to when it was taken, and
+//! [`Session::restore`](crate::session::Session::restore) places them
+//! relative to a new `now`, less the wall time the snapshot lay unused;
+//! frames the tracker would have given up on meanwhile are dropped.
+//!
+//! Retry policies are not kept: restored frames resend by the restoring
+//! tracker's policy for their type. With the `serde` feature a snapshot
+//! serializes.
+
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::DecodeError;
+use crate::session::SessionState;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionSnapshot {
+    /// Wall time it was taken.
+    pub taken_at: SystemTime,
+    pub device_id: [u8; 8],
+    /// Last counter the session used.
+    pub counter: u64,
+    pub state: SessionState,
+    /// Time left before the handshake or close times out.
+    pub deadline_in: Option<Duration>,
+    /// Frames queued and not yet transmitted, encoded.
+    pub outbox: Vec<Vec<u8>>,
+    pub replay: Vec<ReplayEntry>,
+    pub pending: Vec<PendingAck>,
+    /// Registry entries, in the lines [`DeviceRegistry::save`] writes.
+    ///
+    /// [`DeviceRegistry::save`]: crate::registry::DeviceRegistry::save
+    pub devices: Vec<String>,
+}
+
+/// One device's replay window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReplayEntry {
+    pub device_id: [u8; 8],
+    /// Highest counter accepted.
+    pub top: u64,
+    /// Bit `i` is set if `top - i` has been accepted.
+    pub seen: u128,
+    /// Time since a counter was last accepted.
+    pub idle: Duration,
+}
+
+/// A frame awaiting its ack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PendingAck {
+    /// The frame, encoded.
+    pub frame: Vec<u8>,
+    pub attempts: u32,
+    pub first_sent_ago: Duration,
+    pub last_sent_ago: Duration,
+    /// Time until it is resent, zero if already due; `None` once its
+    /// policy has stopped.
+    pub retry_in: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The snapshot is of a session for another device.
+    Device { expected: [u8; 8], found: [u8; 8] },
+    /// A frame in it does not decode.
+    Frame(DecodeError),
+    /// A registry entry in it does not parse.
+    Registry(io::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Device { expected, found } => write!(
+                f,
+                "snapshot is of {}, not {}",
+                String::from_utf8_lossy(found),
+                String::from_utf8_lossy(expected)
+            ),
+            SnapshotError::Frame(e) => write!(f, "bad frame in snapshot: {e}"),
+            SnapshotError::Registry(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// Times in a snapshot being restored, placed against a new clock.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Rebase {
+    pub now: Instant,
+    /// Wall time since the snapshot was taken.
+    pub downtime: Duration,
+}
+
+impl Rebase {
+    pub fn new(taken_at: SystemTime, now: Instant) -> Self {
+        Rebase {
+            now,
+            downtime: SystemTime::now()
+                .duration_since(taken_at)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The instant `ago` before the snapshot was taken.
+    pub fn ago(self, ago: Duration) -> Instant {
+        let ago = ago.saturating_add(self.downtime);
+        self.now.checked_sub(ago).unwrap_or(self.now)
+    }
+
+    /// The instant `after` the snapshot was taken.
+    pub fn after(self, after: Duration) -> Instant {
+        match after.checked_sub(self.downtime) {
+            Some(left) => self.now + left,
+            None => {
+                let past = self.downtime - after;
+                self.now.checked_sub(past).unwrap_or(self.now)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ack::{AckBody, AckConfig, AckTracker, ack_frame};
+    use crate::clock::{Clock, ManualClock};
+    use crate::registry::{DeviceRegistry, RegistryConfig};
+    use crate::replay::{ReplayConfig, ReplayWindow, Verdict};
+    use crate::session::{Session, SessionConfig, SessionError};
+    use crate::transport::{PeerAddr, RxFrame, TransportId};
+    use crate::{Flags, FrameId, FrameV1, MsgType};
+    use std::sync::Arc;
+
+    const GW: [u8; 8] = *b"GATEWAY1";
+    const DEV: [u8; 8] = *b"DEV00001";
+
+    /// A gateway session with all its parts, and the registry it was given.
+    fn gateway(clock: &ManualClock) -> (Session, DeviceRegistry) {
+        let clock: Arc<dyn Clock> = Arc::new(clock.clone());
+        let registry = DeviceRegistry::new(RegistryConfig::default());
+        let session = Session::with_clock(GW, SessionConfig::default(), clock.clone())
+            .with_acks(AckTracker::new(AckConfig::default(), clock.clone()))
+            .with_replay(ReplayWindow::with_clock(ReplayConfig::default(), clock))
+            .with_registry(registry.clone());
+        (session, registry)
+    }
+
+    fn shuttle(from: &mut Session, to: &mut Session) {
+        while let Some(f) = from.poll_transmit() {
+            to.on_frame(f).unwrap();
+        }
+    }
+
+    fn ack(id: FrameId) -> FrameV1 {
+        ack_frame(id.device_id, id.counter, AckBody::Single)
+    }
+
+    /// Gateway and device established; the gateway has sent a command
+    /// whose ack was lost and let in one event, fed to its registry.
+    fn mid_conversation(clock: &ManualClock) -> (Session, Session, DeviceRegistry, FrameV1) {
+        let (mut gw, registry) = gateway(clock);
+        let mut dev = Session::with_clock(DEV, SessionConfig::default(), Arc::new(clock.clone()));
+        gw.connect().unwrap();
+        shuttle(&mut gw, &mut dev);
+        dev.accept().unwrap();
+        shuttle(&mut dev, &mut gw);
+
+        let acked = Flags::new(Flags::ACK_REQUIRED).unwrap();
+        let first = gw.send(MsgType::Command, acked, vec![1]).unwrap();
+        gw.send(MsgType::Command, acked, vec![2]).unwrap();
+        shuttle(&mut gw, &mut dev);
+        gw.on_frame(ack(first)).unwrap();
+
+        dev.send(MsgType::Event, Flags::new(0).unwrap(), vec![9])
+            .unwrap();
+        let event = dev.poll_transmit().unwrap();
+        gw.on_frame(event.clone()).unwrap();
+        let peer = PeerAddr::Opaque("test".into());
+        registry.observe(&RxFrame::new(event.clone(), peer, TransportId::UNASSIGNED));
+        (gw, dev, registry, event)
+    }
+
+    #[test]
+    fn a_restored_session_finishes_the_conversation() {
+        let clock = ManualClock::new();
+        let (gw, mut dev, registry, event) = mid_conversation(&clock);
+        let snapshot = gw.snapshot();
+        assert_eq!(snapshot.pending.len(), 1);
+        drop(gw);
+
+        let clock = ManualClock::new();
+        let (mut gw, restored) = gateway(&clock);
+        gw.restore(snapshot, clock.now()).unwrap();
+        assert_eq!(gw.state(), crate::session::SessionState::Established);
+        assert_eq!(
+            restored.export(),
+            registry.export(),
+            "to the saved precision"
+        );
+        assert_eq!(
+            gw.on_frame(event),
+            Err(SessionError::Replayed(Verdict::Duplicate))
+        );
+
+        // the unacked command is resent after the ack timeout
+        assert_eq!(gw.poll_transmit(), None);
+        clock.advance(gw.next_deadline().unwrap() - clock.now());
+        gw.poll_timeout(clock.now());
+        let resent = gw.poll_transmit().unwrap();
+        assert_eq!(resent.body, [2]);
+        gw.on_frame(ack(resent.header.id())).unwrap();
+        assert!(gw.acks().unwrap().is_empty());
+
+        let next = gw
+            .send(MsgType::Command, Flags::new(0).unwrap(), vec![3])
+            .unwrap();
+        assert_eq!(next.counter, resent.header.counter + 1);
+        gw.close().unwrap();
+        shuttle(&mut gw, &mut dev);
+        shuttle(&mut dev, &mut gw);
+        assert_eq!(gw.state(), crate::session::SessionState::Closed);
+    }
+
+    #[test]
+    fn a_stale_snapshot_drops_what_expired() {
+        let clock = ManualClock::new();
+        let (gw, _, _, event) = mid_conversation(&clock);
+        let mut snapshot = gw.snapshot();
+        snapshot.taken_at -= Duration::from_secs(3600);
+
+        let (mut other, _) = gateway(&clock);
+        let mut wrong = snapshot.clone();
+        wrong.device_id = DEV;
+        assert!(matches!(
+            other.restore(wrong, clock.now()),
+            Err(SnapshotError::Device { .. })
+        ));
+        other.restore(snapshot, clock.now()).unwrap();
+        assert!(other.acks().unwrap().is_empty(), "given up on long ago");
+        assert_eq!(other.next_deadline(), None);
+        assert!(other.on_frame(event).is_err(), "replay windows are kept");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serializes() {
+        let clock = ManualClock::new();
+        let (gw, ..) = mid_conversation(&clock);
+        let snapshot = gw.snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_str::<SessionSnapshot>(&json).unwrap(),
+            snapshot
+        );
+    }
+}
@@ -0,0 +1,335 @@
+//! SQLite frame history (feature `sqlite`).
+//!
+//! [`SqliteSink`] writes one row per [`FrameRecord`] into a `frames` table,
+//! grouping inserts into one transaction per `batch_frames` records or
+//! `batch_interval`, whichever comes first. The schema is versioned with
+//! `PRAGMA user_version` and migrated on open.
+//!
+//! Counters are stored as SQLite's signed 64-bit integer, so values above
+//! `i64::MAX` read back negative; cast to `u64` when querying.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use rusqlite::{Connection, params};
+
+use crate::clock::{Clock, SystemClock};
+use crate::sink::{FrameRecord, FrameSink, Payload};
+
+/// Each entry takes the schema from version `i` to `i + 1`.
+const MIGRATIONS: &[&str] = &["CREATE TABLE frames (
+        id INTEGER PRIMARY KEY,
+        ts_ms INTEGER NOT NULL,
+        direction TEXT NOT NULL,
+        device_id TEXT,
+        msg_type TEXT,
+        counter INTEGER,
+        flags INTEGER,
+        body BLOB NOT NULL,
+        decode_ok INTEGER NOT NULL,
+        error TEXT
+    );
+    CREATE INDEX frames_device_counter ON frames (device_id, counter);"];
+
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+const INSERT: &str = "INSERT INTO frames
+    (ts_ms, direction, device_id, msg_type, counter, flags, body, decode_ok, error)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)";
+
+#[derive(Debug)]
+pub enum SqliteError {
+    Sqlite(rusqlite::Error),
+    /// The database was written by a newer version of this crate.
+    SchemaTooNew {
+        found: i64,
+    },
+}
+
+impl fmt::Display for SqliteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SqliteError::Sqlite(e) => write!(f, "sqlite: {e}"),
+            SqliteError::SchemaTooNew { found } => write!(
+                f,
+                "schema version {found} is newer than supported {SCHEMA_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SqliteError {}
+
+impl From<rusqlite::Error> for SqliteError {
+    fn from(e: rusqlite::Error) -> Self {
+        SqliteError::Sqlite(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SqliteConfig {
+    pub batch_frames: usize,
+    pub batch_interval: Duration,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        SqliteConfig {
+            batch_frames: 500,
+            batch_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+pub struct SqliteSink {
+    conn: Connection,
+    config: SqliteConfig,
+    clock: Arc<dyn Clock>,
+    pending: usize,
+    batch_started: Option<Instant>,
+}
+
+impl SqliteSink {
+    pub fn open(path: impl AsRef<Path>, config: SqliteConfig) -> Result<Self, SqliteError> {
+        Self::from_connection(Connection::open(path)?, config, Arc::new(SystemClock))
+    }
+
+    pub fn from_connection(
+        conn: Connection,
+        config: SqliteConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, SqliteError> {
+        migrate(&conn)?;
+        Ok(SqliteSink {
+            conn,
+            config,
+            clock,
+            pending: 0,
+            batch_started: None,
+        })
+    }
+
+    /// The underlying connection, for queries. Rows of an open batch are
+    /// visible here but not to other connections.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Records inserted but not yet committed.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Commit the open batch if it has been open for `batch_interval`.
+    /// Returns whether it committed.
+    pub fn flush_if_due(&mut self) -> Result<bool, SqliteError> {
+        if !self.is_due() {
+            return Ok(false);
+        }
+        self.commit()?;
+        Ok(true)
+    }
+
+    fn is_due(&self) -> bool {
+        self.batch_started
+            .is_some_and(|t| self.clock.now() >= t + self.config.batch_interval)
+    }
+
+    fn insert(&mut self, record: &FrameRecord) -> Result<(), SqliteError> {
+        let ts_ms = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        let dir = record.direction.as_str();
+        let mut stmt = self.conn.prepare_cached(INSERT)?;
+        match &record.payload {
+            Payload::Frame(f) => stmt.execute(params![
+                ts_ms,
+                dir,
+                f.header.device_id_hex(),
+                f.header.msg_type.as_str(),
+                f.header.counter as i64,
+                f.header.flags.bits(),
+                f.body.as_slice(),
+                true,
+                None::<String>,
+            ])?,
+            Payload::Undecodable { bytes, error } => stmt.execute(params![
+                ts_ms,
+                dir,
+                None::<String>,
+                None::<String>,
+                None::<i64>,
+                None::<u8>,
+                bytes,
+                false,
+                error.to_string(),
+            ])?,
+        };
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), SqliteError> {
+        if self.batch_started.take().is_some() {
+            self.pending = 0;
+            self.conn.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
+}
+
+impl FrameSink for SqliteSink {
+    type Error = SqliteError;
+
+    fn record(&mut self, record: &FrameRecord) -> Result<(), SqliteError> {
+        if self.batch_started.is_none() {
+            self.conn.execute_batch("BEGIN")?;
+            self.batch_started = Some(self.clock.now());
+        }
+        self.insert(record)?;
+        self.pending += 1;
+        if self.pending >= self.config.batch_frames || self.is_due() {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SqliteError> {
+        self.commit()
+    }
+}
+
+impl Drop for SqliteSink {
+    fn drop(&mut self) {
+        let _ = self.commit();
+    }
+}
+
+fn migrate(conn: &Connection) -> Result<(), SqliteError> {
+    let found: i64 = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+    if found > SCHEMA_VERSION {
+        return Err(SqliteError::SchemaTooNew { found });
+    }
+    for (from, sql) in MIGRATIONS.iter().enumerate().skip(found as usize) {
+        conn.execute_batch(&format!(
+            "BEGIN; {sql}; PRAGMA user_version = {}; COMMIT;",
+            from + 1
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DecodeError;
+    use crate::clock::ManualClock;
+    use crate::sink::Direction;
+    use crate::transport::tests::frame;
+
+    fn count(conn: &Connection) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM frames", [], |r| r.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn batches_thousands_of_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("frames.db");
+        let config = SqliteConfig {
+            batch_frames: 1000,
+            batch_interval: Duration::from_secs(3600),
+        };
+        let mut sink = SqliteSink::open(&path, config).unwrap();
+        let reader = Connection::open(&path).unwrap();
+
+        for c in 0..2500 {
+            sink.record(&FrameRecord::rx(frame(c))).unwrap();
+        }
+        assert_eq!(sink.pending(), 500);
+        assert_eq!(count(&reader), 2000);
+        assert_eq!(count(sink.connection()), 2500);
+
+        sink.flush().unwrap();
+        assert_eq!(count(&reader), 2500);
+
+        let (msg_type, body): (String, Vec<u8>) = reader
+            .query_row(
+                "SELECT msg_type, body FROM frames WHERE device_id = ?1 AND counter = ?2",
+                params![frame(0).header.device_id_hex(), 1234],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(msg_type, "event");
+        assert_eq!(body, [1, 2, 3]);
+
+        let plan: String = reader
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT * FROM frames WHERE device_id = 'x' AND counter > 5",
+                [],
+                |r| r.get(3),
+            )
+            .unwrap();
+        assert!(plan.contains("frames_device_counter"), "{plan}");
+    }
+
+    #[test]
+    fn commits_after_interval() {
+        let clock = ManualClock::new();
+        let conn = Connection::open_in_memory().unwrap();
+        let mut sink =
+            SqliteSink::from_connection(conn, SqliteConfig::default(), Arc::new(clock.clone()))
+                .unwrap();
+
+        sink.record(&FrameRecord::tx(frame(1))).unwrap();
+        assert!(!sink.flush_if_due().unwrap());
+        clock.advance(Duration::from_secs(1));
+        assert!(sink.flush_if_due().unwrap());
+        assert_eq!(sink.pending(), 0);
+
+        // an overdue batch also commits on the next record
+        sink.record(&FrameRecord::tx(frame(2))).unwrap();
+        clock.advance(Duration::from_secs(2));
+        sink.record(&FrameRecord::tx(frame(3))).unwrap();
+        assert_eq!(sink.pending(), 0);
+    }
+
+    #[test]
+    fn stores_decode_failures() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut sink =
+            SqliteSink::from_connection(conn, SqliteConfig::default(), Arc::new(SystemClock))
+                .unwrap();
+        let rec = FrameRecord::undecodable(Direction::Rx, vec![0xff; 3], DecodeError::TooShort);
+        sink.record(&rec).unwrap();
+        sink.flush().unwrap();
+
+        let (ok, error, device): (bool, String, Option<String>) = sink
+            .connection()
+            .query_row("SELECT decode_ok, error, device_id FROM frames", [], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            })
+            .unwrap();
+        assert!(!ok);
+        assert_eq!(error, DecodeError::TooShort.to_string());
+        assert_eq!(device, None);
+    }
+
+    #[test]
+    fn reopen_keeps_schema_and_rejects_newer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("frames.db");
+        drop(SqliteSink::open(&path, SqliteConfig::default()).unwrap());
+        drop(SqliteSink::open(&path, SqliteConfig::default()).unwrap());
+
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch("PRAGMA user_version = 99").unwrap();
+        drop(conn);
+        assert!(matches!(
+            SqliteSink::open(&path, SqliteConfig::default()),
+            Err(SqliteError::SchemaTooNew { found: 99 })
+        ));
+    }
+}
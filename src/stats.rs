@@ -0,0 +1,528 @@
+//! What the traffic looks like: rates, sizes and types of frames.
+//!
+//! [`TrafficStats`] is a [`FrameObserver`]: install it on a transport with
+//! [`Observed`](crate::observer::Observed) and it counts every frame sent
+//! and received, and every frame that failed to decode. Counts are kept
+//! in rings of buckets covering the last minute, five minutes and hour
+//! ([`WINDOWS`]), so [`TrafficStats::snapshot`] can give rates over each.
+//! A window is its 60 latest complete buckets; the one being filled is
+//! left out, so rates lag by up to a bucket (1 s, 5 s, 1 min) but are
+//! never diluted by a partial one. Frames and bytes are also counted per
+//! device, for up to `max_devices` devices.
+//!
+//! Recording is a handful of relaxed atomic adds, plus a lock on one of
+//! 16 shards for the per-device counts. A frame recorded by one thread
+//! while another moves a bucket on to a new period may be lost; that is
+//! the price of not locking. With the `serde` feature the snapshot
+//! serializes, device ids as hex.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::observer::FrameObserver;
+use crate::{DecodeError, FrameV1, MsgType};
+
+/// Buckets per window; the ring has one more for the bucket being filled.
+const BUCKETS: u64 = 60;
+const SHARDS: usize = 16;
+
+/// The windows rates are given over; each is 60 buckets.
+pub const WINDOWS: [Duration; 3] = [
+    Duration::from_secs(60),
+    Duration::from_secs(5 * 60),
+    Duration::from_secs(60 * 60),
+];
+
+/// Upper bounds of the body size histogram, in bytes.
+pub const BODY_SIZE_BUCKETS: [usize; 6] = [0, 16, 64, 256, 1024, 4096];
+
+// slots in a direction's buckets
+const FRAMES: usize = 0;
+const BYTES: usize = 1;
+const DECODE_ERRORS: usize = 2;
+const TYPES: usize = 3;
+const DIRECTION_COUNTS: usize = TYPES + 4;
+
+fn type_slot(t: MsgType) -> usize {
+    TYPES
+        + match t {
+            MsgType::Event => 0,
+            MsgType::Command => 1,
+            MsgType::Ack => 2,
+            MsgType::Error => 3,
+        }
+}
+
+struct Bucket<const N: usize> {
+    /// The period counted, plus one; zero if never used.
+    period: AtomicU64,
+    counts: [AtomicU64; N],
+}
+
+/// One window: [`BUCKETS`] buckets of `width` each, and the one being
+/// filled.
+struct Ring<const N: usize> {
+    width: Duration,
+    buckets: Vec<Bucket<N>>,
+}
+
+impl<const N: usize> Ring<N> {
+    fn new(window: Duration) -> Self {
+        Ring {
+            width: window / BUCKETS as u32,
+            buckets: (0..=BUCKETS)
+                .map(|_| Bucket {
+                    period: AtomicU64::new(0),
+                    counts: [const { AtomicU64::new(0) }; N],
+                })
+                .collect(),
+        }
+    }
+
+    fn period(&self, elapsed: Duration) -> u64 {
+        (elapsed.as_nanos() / self.width.as_nanos()) as u64
+    }
+
+    fn add(&self, elapsed: Duration, counts: &[(usize, u64)]) {
+        let period = self.period(elapsed);
+        let bucket = &self.buckets[(period % (BUCKETS + 1)) as usize];
+        let tag = period + 1;
+        let seen = bucket.period.load(Ordering::Acquire);
+        if seen > tag {
+            return;
+        }
+        if seen < tag
+            && bucket
+                .period
+                .compare_exchange(seen, tag, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            for c in &bucket.counts {
+                c.store(0, Ordering::Relaxed);
+            }
+        }
+        for &(slot, n) in counts {
+            bucket.counts[slot].fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    /// Totals over the complete buckets of the window before `elapsed`.
+    fn sum(&self, elapsed: Duration) -> [u64; N] {
+        let current = self.period(elapsed) + 1;
+        let mut sum = [0; N];
+        for b in &self.buckets {
+            let tag = b.period.load(Ordering::Acquire);
+            if tag == 0 || tag >= current || current - tag > BUCKETS {
+                continue;
+            }
+            for (s, c) in sum.iter_mut().zip(&b.counts) {
+                *s += c.load(Ordering::Relaxed);
+            }
+        }
+        sum
+    }
+}
+
+/// Counts by message type.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ByType<T> {
+    pub event: T,
+    pub command: T,
+    pub ack: T,
+    pub error: T,
+}
+
+impl<T: Copy> ByType<T> {
+    fn from_slots(slots: &[T]) -> Self {
+        ByType {
+            event: slots[0],
+            command: slots[1],
+            ack: slots[2],
+            error: slots[3],
+        }
+    }
+}
+
+/// Traffic in one direction over one window, per second.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Rates {
+    pub window_secs: u64,
+    pub frames_per_sec: f64,
+    pub bytes_per_sec: f64,
+    pub by_type: ByType<f64>,
+    /// ERROR frames and frames that failed to decode, as a fraction of
+    /// all frames and failures.
+    pub error_rate: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Direction {
+    pub frames: u64,
+    pub bytes: u64,
+    pub decode_errors: u64,
+    pub by_type: ByType<u64>,
+    /// One per [`WINDOWS`] entry.
+    pub windows: Vec<Rates>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DeviceRates {
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::repr::device_id_hex")
+    )]
+    pub device_id: [u8; 8],
+    pub frames: u64,
+    pub bytes: u64,
+    /// Frames and bytes per second, both ways, one per [`WINDOWS`] entry.
+    pub windows: Vec<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SizeBucket {
+    /// `None` for the last, unbounded bucket.
+    pub le: Option<usize>,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StatsSnapshot {
+    pub uptime_secs: u64,
+    pub rx: Direction,
+    pub tx: Direction,
+    /// Bodies of frames both ways, not cumulative.
+    pub body_sizes: Vec<SizeBucket>,
+    /// By device id.
+    pub devices: Vec<DeviceRates>,
+}
+
+struct DirectionCounts {
+    totals: [AtomicU64; DIRECTION_COUNTS],
+    rings: [Ring<DIRECTION_COUNTS>; 3],
+}
+
+impl DirectionCounts {
+    fn new() -> Self {
+        DirectionCounts {
+            totals: [const { AtomicU64::new(0) }; DIRECTION_COUNTS],
+            rings: WINDOWS.map(Ring::new),
+        }
+    }
+
+    fn add(&self, elapsed: Duration, counts: &[(usize, u64)]) {
+        for &(slot, n) in counts {
+            self.totals[slot].fetch_add(n, Ordering::Relaxed);
+        }
+        for ring in &self.rings {
+            ring.add(elapsed, counts);
+        }
+    }
+
+    fn snapshot(&self, elapsed: Duration) -> Direction {
+        let totals = self.totals.each_ref().map(|c| c.load(Ordering::Relaxed));
+        let windows = self
+            .rings
+            .iter()
+            .zip(WINDOWS)
+            .map(|(ring, window)| {
+                let sum = ring.sum(elapsed);
+                let secs = window.as_secs_f64();
+                let seen = sum[FRAMES] + sum[DECODE_ERRORS];
+                let errors = sum[type_slot(MsgType::Error)] + sum[DECODE_ERRORS];
+                let per_type: Vec<f64> = sum[TYPES..].iter().map(|&n| n as f64 / secs).collect();
+                Rates {
+                    window_secs: window.as_secs(),
+                    frames_per_sec: sum[FRAMES] as f64 / secs,
+                    bytes_per_sec: sum[BYTES] as f64 / secs,
+                    by_type: ByType::from_slots(&per_type),
+                    error_rate: if seen == 0 {
+                        0.0
+                    } else {
+                        errors as f64 / seen as f64
+                    },
+                }
+            })
+            .collect();
+        Direction {
+            frames: totals[FRAMES],
+            bytes: totals[BYTES],
+            decode_errors: totals[DECODE_ERRORS],
+            by_type: ByType::from_slots(&totals[TYPES..]),
+            windows,
+        }
+    }
+}
+
+struct DeviceCounts {
+    frames: u64,
+    bytes: u64,
+    rings: [Ring<2>; 3],
+}
+
+#[derive(Debug, Clone)]
+pub struct StatsConfig {
+    /// Devices counted individually; later ones only count in the totals.
+    pub max_devices: usize,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        StatsConfig { max_devices: 1024 }
+    }
+}
+
+/// Counts frames as a [`FrameObserver`]. Clones share the counts.
+#[derive(Clone)]
+pub struct TrafficStats {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    clock: Arc<dyn Clock>,
+    started: Instant,
+    max_devices: usize,
+    tracked: AtomicUsize,
+    rx: DirectionCounts,
+    tx: DirectionCounts,
+    body_sizes: [AtomicU64; BODY_SIZE_BUCKETS.len() + 1],
+    devices: [Mutex<HashMap<[u8; 8], DeviceCounts>>; SHARDS],
+}
+
+impl TrafficStats {
+    pub fn new(config: StatsConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: StatsConfig, clock: Arc<dyn Clock>) -> Self {
+        TrafficStats {
+            inner: Arc::new(Inner {
+                started: clock.now(),
+                clock,
+                max_devices: config.max_devices,
+                tracked: AtomicUsize::new(0),
+                rx: DirectionCounts::new(),
+                tx: DirectionCounts::new(),
+                body_sizes: [const { AtomicU64::new(0) }; BODY_SIZE_BUCKETS.len() + 1],
+                devices: std::array::from_fn(|_| Mutex::new(HashMap::new())),
+            }),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.inner
+            .clock
+            .now()
+            .saturating_duration_since(self.inner.started)
+    }
+
+    fn record(&self, counts: &DirectionCounts, frame: &FrameV1) {
+        let inner = &*self.inner;
+        let elapsed = self.elapsed();
+        let bytes = frame.encoded_len() as u64;
+        counts.add(
+            elapsed,
+            &[
+                (FRAMES, 1),
+                (BYTES, bytes),
+                (type_slot(frame.header.msg_type), 1),
+            ],
+        );
+        let size = BODY_SIZE_BUCKETS
+            .iter()
+            .position(|&le| frame.body.len() <= le)
+            .unwrap_or(BODY_SIZE_BUCKETS.len());
+        inner.body_sizes[size].fetch_add(1, Ordering::Relaxed);
+
+        let device_id = frame.header.device_id;
+        let shard = device_id.iter().fold(0usize, |h, b| h * 31 + *b as usize) % SHARDS;
+        let mut devices = inner.devices[shard]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if !devices.contains_key(&device_id)
+            && inner.tracked.fetch_add(1, Ordering::Relaxed) >= inner.max_devices
+        {
+            inner.tracked.fetch_sub(1, Ordering::Relaxed);
+            return;
+        }
+        let device = devices.entry(device_id).or_insert_with(|| DeviceCounts {
+            frames: 0,
+            bytes: 0,
+            rings: WINDOWS.map(Ring::new),
+        });
+        device.frames += 1;
+        device.bytes += bytes;
+        for ring in &device.rings {
+            ring.add(elapsed, &[(0, 1), (1, bytes)]);
+        }
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let inner = &*self.inner;
+        let elapsed = self.elapsed();
+        let body_sizes = inner
+            .body_sizes
+            .iter()
+            .enumerate()
+            .map(|(i, c)| SizeBucket {
+                le: BODY_SIZE_BUCKETS.get(i).copied(),
+                count: c.load(Ordering::Relaxed),
+            })
+            .collect();
+        let mut devices: Vec<_> = inner
+            .devices
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.lock().unwrap_or_else(|e| e.into_inner());
+                shard
+                    .iter()
+                    .map(|(id, d)| DeviceRates {
+                        device_id: *id,
+                        frames: d.frames,
+                        bytes: d.bytes,
+                        windows: d
+                            .rings
+                            .iter()
+                            .zip(WINDOWS)
+                            .map(|(ring, window)| {
+                                let [frames, bytes] = ring.sum(elapsed);
+                                let secs = window.as_secs_f64();
+                                (frames as f64 / secs, bytes as f64 / secs)
+                            })
+                            .collect(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        devices.sort_by_key(|d| d.device_id);
+        StatsSnapshot {
+            uptime_secs: elapsed.as_secs(),
+            rx: inner.rx.snapshot(elapsed),
+            tx: inner.tx.snapshot(elapsed),
+            body_sizes,
+            devices,
+        }
+    }
+}
+
+impl FrameObserver for TrafficStats {
+    fn on_rx(&self, frame: &FrameV1) {
+        self.record(&self.inner.rx, frame);
+    }
+
+    fn on_tx(&self, frame: &FrameV1) {
+        self.record(&self.inner.tx, frame);
+    }
+
+    fn on_decode_error(&self, _bytes: &[u8], _error: &DecodeError) {
+        self.inner.rx.add(self.elapsed(), &[(DECODE_ERRORS, 1)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::transport::tests::frame;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    fn frame_from(device: &[u8; 8], msg_type: MsgType, body_len: usize) -> FrameV1 {
+        let mut f = frame(1);
+        f.header.device_id = *device;
+        f.header.msg_type = msg_type;
+        f.body = vec![0; body_len].into();
+        f
+    }
+
+    #[test]
+    fn windowed_rates_follow_the_traffic() {
+        let clock = ManualClock::new();
+        let stats = TrafficStats::with_clock(StatsConfig::default(), Arc::new(clock.clone()));
+        let event = frame_from(b"DEV00001", MsgType::Event, 10);
+        let bytes = event.encoded_len() as f64;
+
+        // two events a second for ten minutes, then a quiet minute
+        for _ in 0..600 {
+            stats.on_rx(&event);
+            stats.on_rx(&event);
+            clock.advance(SEC);
+        }
+        let rx = stats.snapshot().rx;
+        assert_eq!(rx.frames, 1200);
+        assert_eq!(rx.windows[0].frames_per_sec, 2.0);
+        assert_eq!(rx.windows[0].bytes_per_sec, 2.0 * bytes);
+        assert_eq!(rx.windows[1].frames_per_sec, 2.0);
+        assert_eq!(rx.windows[2].frames_per_sec, 1200.0 / 3600.0);
+        assert_eq!(rx.windows[0].by_type.event, 2.0);
+        assert_eq!(rx.windows[0].by_type.ack, 0.0);
+
+        clock.advance(60 * SEC);
+        let rx = stats.snapshot().rx;
+        assert_eq!(rx.windows[0].frames_per_sec, 0.0, "the minute is quiet");
+        assert_eq!(rx.windows[1].frames_per_sec, 4.0 * 60.0 * 2.0 / 300.0);
+        assert_eq!(rx.windows[2].frames_per_sec, 1200.0 / 3600.0);
+        assert_eq!(rx.frames, 1200, "totals are kept");
+
+        clock.advance(3600 * SEC);
+        assert_eq!(stats.snapshot().rx.windows[2].frames_per_sec, 0.0);
+    }
+
+    #[test]
+    fn breaks_traffic_down() {
+        let clock = ManualClock::new();
+        let stats =
+            TrafficStats::with_clock(StatsConfig { max_devices: 1 }, Arc::new(clock.clone()));
+        for _ in 0..6 {
+            stats.on_rx(&frame_from(b"DEV00001", MsgType::Event, 100));
+        }
+        stats.on_rx(&frame_from(b"DEV00001", MsgType::Error, 2));
+        stats.on_decode_error(&[], &DecodeError::BadMagic);
+        stats.on_tx(&frame_from(b"DEV00001", MsgType::Ack, 0));
+        stats.on_tx(&frame_from(b"DEV00002", MsgType::Command, 5000));
+        clock.advance(30 * SEC);
+
+        let s = stats.snapshot();
+        assert_eq!(s.rx.by_type.event, 6);
+        assert_eq!(s.rx.decode_errors, 1);
+        assert_eq!(s.rx.windows[0].error_rate, 2.0 / 8.0);
+        assert_eq!(
+            s.tx.by_type,
+            ByType {
+                event: 0,
+                command: 1,
+                ack: 1,
+                error: 0
+            }
+        );
+        let sizes: Vec<_> = s.body_sizes.iter().map(|b| b.count).collect();
+        assert_eq!(sizes, [1, 1, 0, 6, 0, 0, 1]);
+        assert_eq!(s.body_sizes[6].le, None);
+
+        // only one device fits
+        assert_eq!(s.devices.len(), 1);
+        let dev = &s.devices[0];
+        assert_eq!((dev.device_id, dev.frames), (*b"DEV00001", 8));
+        assert_eq!(dev.windows[0].0, 8.0 / 60.0);
+        assert_eq!(s.uptime_secs, 30);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn snapshot_serializes() {
+        let stats = TrafficStats::new(StatsConfig::default());
+        stats.on_rx(&frame_from(b"DEV00001", MsgType::Event, 3));
+        let json = serde_json::to_value(stats.snapshot()).unwrap();
+        assert_eq!(json["rx"]["by_type"]["event"], 1);
+        assert_eq!(json["devices"][0]["device_id"], "4445563030303031");
+        assert_eq!(json["rx"]["windows"][2]["window_secs"], 3600);
+    }
+}
@@ -0,0 +1,371 @@
+//! Estimating each device's clock offset and drift.
+//!
+//! A time-sync exchange gives one [`TimeSample`]: how far the device's
+//! clock is ahead of ours, give or take half the round trip it took to
+//! learn. [`ClockEstimator`] keeps each device's recent samples in its
+//! [`DeviceRegistry`] entry and refits a [`ClockEstimate`] after every new
+//! one, from the samples with the lowest round trips: those with a round
+//! trip more than `max_rtt_ratio` times the lowest are left out, so one
+//! slow exchange cannot pull the estimate. Drift is the slope of a least
+//! squares line through the offsets, once they span `min_span`.
+//!
+//! v1 defines no time-sync message; samples come from whatever exchange
+//! the application runs, e.g. via [`TimeSample::from_exchange`].
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::registry::DeviceRegistry;
+
+/// One exchange's view of a device's clock. Times are microseconds since
+/// the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSample {
+    /// Our time halfway through the exchange.
+    pub gateway_micros: u64,
+    /// Device time minus ours.
+    pub offset_micros: i64,
+    pub rtt: Duration,
+}
+
+impl TimeSample {
+    /// The sample from an NTP-style exchange: we sent at `t0`, the device
+    /// received at `t1` and answered at `t2`, and we received at `t3`.
+    pub fn from_exchange(t0: u64, t1: u64, t2: u64, t3: u64) -> Self {
+        let (t0, t1, t2, t3) = (t0 as i128, t1 as i128, t2 as i128, t3 as i128);
+        let offset = ((t1 - t0) + (t2 - t3)) / 2;
+        let rtt = ((t3 - t0) - (t2 - t1)).max(0);
+        TimeSample {
+            gateway_micros: ((t0 + t3) / 2) as u64,
+            offset_micros: offset as i64,
+            rtt: Duration::from_micros(rtt as u64),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClockConfig {
+    /// Recent samples kept per device.
+    pub history: usize,
+    /// Of those, how many with the lowest round trips are fitted.
+    pub best: usize,
+    /// Samples older than this before the newest are dropped.
+    pub max_age: Duration,
+    /// Samples with a round trip over this many times the lowest are not
+    /// fitted.
+    pub max_rtt_ratio: f64,
+    /// Time the fitted samples must span before drift is estimated.
+    pub min_span: Duration,
+    /// Drift, either way, above which a device is flagged.
+    pub max_drift_ppm: f64,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig {
+            history: 64,
+            best: 8,
+            max_age: Duration::from_secs(3600),
+            max_rtt_ratio: 3.0,
+            min_span: Duration::from_secs(60),
+            max_drift_ppm: 100.0,
+        }
+    }
+}
+
+/// What is known of a device's clock; the default knows nothing.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ClockEstimate {
+    /// Recent samples, oldest first.
+    pub samples: Vec<TimeSample>,
+    /// Our time the offset and drift refer to.
+    pub reference_micros: u64,
+    /// Device time minus ours at `reference_micros`.
+    pub offset_micros: i64,
+    /// How fast the offset grows, in microseconds per thousand seconds
+    /// (parts per billion).
+    pub drift_ppb: i64,
+    /// Drift over `max_drift_ppm`.
+    pub drifting: bool,
+}
+
+impl ClockEstimate {
+    pub fn is_known(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    pub fn drift_ppm(&self) -> f64 {
+        self.drift_ppb as f64 / 1000.0
+    }
+
+    /// Device time minus ours, at our time `gateway_micros`.
+    fn offset_at(&self, gateway_micros: i128) -> i128 {
+        let since = gateway_micros - self.reference_micros as i128;
+        self.offset_micros as i128 + since * self.drift_ppb as i128 / 1_000_000_000
+    }
+
+    /// Our time when the device's clock read `device`, if anything is
+    /// known of it.
+    pub fn device_time_to_gateway_time(&self, device: SystemTime) -> Option<SystemTime> {
+        if !self.is_known() {
+            return None;
+        }
+        let device = micros(device);
+        // drift is tiny, so the offset at the device time is close enough
+        // to find our time, and the offset there is exact
+        let guess = device - self.offset_micros as i128;
+        let gateway = device - self.offset_at(guess);
+        Some(from_micros(gateway))
+    }
+
+    fn add(&mut self, sample: TimeSample, config: &ClockConfig) {
+        self.samples.push(sample);
+        self.samples.sort_by_key(|s| s.gateway_micros);
+        let newest = self.samples.last().map_or(0, |s| s.gateway_micros);
+        let oldest = newest.saturating_sub(config.max_age.as_micros() as u64);
+        self.samples.retain(|s| s.gateway_micros >= oldest);
+        let excess = self.samples.len().saturating_sub(config.history.max(1));
+        self.samples.drain(..excess);
+        self.fit(config);
+    }
+
+    fn fit(&mut self, config: &ClockConfig) {
+        let mut best = self.samples.clone();
+        best.sort_by_key(|s| s.rtt);
+        best.truncate(config.best.max(1));
+        let lowest = best[0].rtt.as_secs_f64();
+        best.retain(|s| s.rtt.as_secs_f64() <= lowest * config.max_rtt_ratio);
+        let reference = best.iter().map(|s| s.gateway_micros).max().unwrap();
+        let n = best.len() as f64;
+        let x = |s: &TimeSample| (s.gateway_micros as f64 - reference as f64) / 1e6;
+        let y = |s: &TimeSample| s.offset_micros as f64;
+        let mean_x = best.iter().map(x).sum::<f64>() / n;
+        let mean_y = best.iter().map(y).sum::<f64>() / n;
+        let var: f64 = best.iter().map(|s| (x(s) - mean_x).powi(2)).sum();
+        let cov: f64 = best.iter().map(|s| (x(s) - mean_x) * (y(s) - mean_y)).sum();
+        let span = best.iter().map(x).fold(0.0, f64::min).abs();
+        // microseconds of offset per second is parts per million
+        let drift_ppm = if span >= config.min_span.as_secs_f64() && var > 0.0 {
+            cov / var
+        } else {
+            0.0
+        };
+        self.reference_micros = reference;
+        self.offset_micros = (mean_y - drift_ppm * mean_x).round() as i64;
+        self.drift_ppb = (drift_ppm * 1000.0).round() as i64;
+        self.drifting = drift_ppm.abs() > config.max_drift_ppm;
+    }
+
+    /// `-`, or `reference,offset,drift_ppb,drifting` then a space and the
+    /// samples as `gateway:offset:rtt_micros` joined by `;`.
+    pub(crate) fn format(&self) -> String {
+        if !self.is_known() {
+            return "- -".to_string();
+        }
+        let samples: Vec<_> = self
+            .samples
+            .iter()
+            .map(|s| {
+                format!(
+                    "{}:{}:{}",
+                    s.gateway_micros,
+                    s.offset_micros,
+                    s.rtt.as_micros()
+                )
+            })
+            .collect();
+        format!(
+            "{},{},{},{} {}",
+            self.reference_micros,
+            self.offset_micros,
+            self.drift_ppb,
+            u8::from(self.drifting),
+            samples.join(";")
+        )
+    }
+
+    /// The inverse of [`ClockEstimate::format`], from its two fields.
+    pub(crate) fn parse(fit: &str, samples: &str) -> Option<Self> {
+        if fit == "-" && samples == "-" {
+            return Some(ClockEstimate::default());
+        }
+        let fit: Vec<_> = fit.split(',').collect();
+        let [reference, offset, drift, drifting] = fit[..] else {
+            return None;
+        };
+        let samples = samples
+            .split(';')
+            .map(|s| {
+                let parts: Vec<_> = s.split(':').collect();
+                let [gateway, offset, rtt] = parts[..] else {
+                    return None;
+                };
+                Some(TimeSample {
+                    gateway_micros: gateway.parse().ok()?,
+                    offset_micros: offset.parse().ok()?,
+                    rtt: Duration::from_micros(rtt.parse().ok()?),
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(ClockEstimate {
+            samples,
+            reference_micros: reference.parse().ok()?,
+            offset_micros: offset.parse().ok()?,
+            drift_ppb: drift.parse().ok()?,
+            drifting: match drifting {
+                "0" => false,
+                "1" => true,
+                _ => return None,
+            },
+        })
+    }
+}
+
+fn micros(t: SystemTime) -> i128 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_micros() as i128,
+        Err(e) => -(e.duration().as_micros() as i128),
+    }
+}
+
+fn from_micros(micros: i128) -> SystemTime {
+    let magnitude = Duration::from_micros(micros.unsigned_abs() as u64);
+    if micros >= 0 {
+        UNIX_EPOCH + magnitude
+    } else {
+        UNIX_EPOCH - magnitude
+    }
+}
+
+/// Keeps each device's [`ClockEstimate`] in a registry, as
+/// [`DeviceInfo::clock`](crate::registry::DeviceInfo::clock).
+pub struct ClockEstimator {
+    registry: DeviceRegistry,
+    config: ClockConfig,
+}
+
+impl ClockEstimator {
+    pub fn new(registry: DeviceRegistry, config: ClockConfig) -> Self {
+        ClockEstimator { registry, config }
+    }
+
+    /// Take in a sample from `device_id` and return the new estimate.
+    /// Devices the registry does not know are not kept.
+    pub fn add(&self, device_id: [u8; 8], sample: TimeSample) -> ClockEstimate {
+        let mut estimate = self.estimate(device_id);
+        estimate.add(sample, &self.config);
+        self.registry.set_clock(device_id, estimate.clone());
+        estimate
+    }
+
+    pub fn estimate(&self, device_id: [u8; 8]) -> ClockEstimate {
+        self.registry
+            .get(device_id)
+            .map(|info| info.clock)
+            .unwrap_or_default()
+    }
+
+    /// Devices whose drift is over `max_drift_ppm`, by id.
+    pub fn drifting(&self) -> Vec<[u8; 8]> {
+        self.registry
+            .list()
+            .into_iter()
+            .filter(|info| info.clock.drifting)
+            .map(|info| info.device_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::RegistryConfig;
+    use crate::transport::tests::frame;
+    use crate::transport::{PeerAddr, RxFrame, TransportId};
+
+    const DEV: [u8; 8] = *b"DEV00001";
+    const START: u64 = 1_700_000_000_000_000;
+
+    fn estimator(config: ClockConfig) -> (ClockEstimator, DeviceRegistry) {
+        let registry = DeviceRegistry::new(RegistryConfig::default());
+        let rx = RxFrame::new(
+            frame(1),
+            PeerAddr::Opaque("test".into()),
+            TransportId::UNASSIGNED,
+        );
+        registry.observe(&rx);
+        (ClockEstimator::new(registry.clone(), config), registry)
+    }
+
+    /// A device `offset` µs ahead drifting by `ppm`, sampled every 10 s
+    /// with the given round trips, each sample off by half its round trip
+    /// the same way.
+    fn samples(offset: i64, ppm: i64, rtts_ms: &[u64]) -> Vec<TimeSample> {
+        rtts_ms
+            .iter()
+            .enumerate()
+            .map(|(i, &rtt)| {
+                let secs = 10 * i as i64;
+                let rtt = Duration::from_millis(rtt);
+                TimeSample {
+                    gateway_micros: START + secs as u64 * 1_000_000,
+                    offset_micros: offset + ppm * secs + rtt.as_micros() as i64 / 2,
+                    rtt,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn an_exchange_gives_offset_and_round_trip() {
+        // device 500 µs ahead, 100 µs each way, 30 µs to answer
+        let s = TimeSample::from_exchange(1_000, 1_600, 1_630, 1_230);
+        assert_eq!(s.offset_micros, 500);
+        assert_eq!(s.rtt, Duration::from_micros(200));
+        assert_eq!(s.gateway_micros, 1_115);
+    }
+
+    #[test]
+    fn a_slow_exchange_does_not_move_the_estimate() {
+        let (e, _) = estimator(ClockConfig::default());
+        for s in samples(250_000, 20, &[4, 4, 5, 40, 4, 4, 5, 4]) {
+            e.add(DEV, s);
+        }
+        let est = e.estimate(DEV);
+        assert!(est.is_known());
+        let now_offset = 250_000 + 20 * 70;
+        assert!((est.offset_micros - now_offset).abs() <= 2_500, "{est:?}");
+        assert!((est.drift_ppm() - 20.0).abs() < 5.0, "{est:?}");
+        assert!(!est.drifting);
+        assert!(e.drifting().is_empty());
+
+        let device = UNIX_EPOCH + Duration::from_micros(START + 70_000_000 + 250_000 + 1_400);
+        let gateway = est.device_time_to_gateway_time(device).unwrap();
+        let expected = UNIX_EPOCH + Duration::from_micros(START + 70_000_000);
+        let error = (micros(gateway) - micros(expected)).abs();
+        assert!(error <= 2_500, "{error} µs off");
+    }
+
+    #[test]
+    fn flags_drift_and_survives_a_restart() {
+        let config = ClockConfig {
+            max_drift_ppm: 50.0,
+            ..ClockConfig::default()
+        };
+        let (e, registry) = estimator(config.clone());
+        assert_eq!(e.estimate(DEV), ClockEstimate::default());
+        for s in samples(-1_000, 200, &[3; 8]) {
+            e.add(DEV, s);
+        }
+        let est = e.estimate(DEV);
+        assert_eq!(est.drift_ppb, 200_000);
+        assert_eq!(est.offset_micros, -1_000 + 200 * 70 + 1_500);
+        assert_eq!(e.drifting(), [DEV]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("devices");
+        registry.save(&path).unwrap();
+        let loaded = DeviceRegistry::load(&path, RegistryConfig::default()).unwrap();
+        assert_eq!(loaded.get(DEV).unwrap().clock, est);
+        assert_eq!(ClockEstimator::new(loaded, config).drifting(), [DEV]);
+    }
+}
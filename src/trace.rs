@@ -0,0 +1,164 @@
+//! tracing instrumentation (feature `tracing`).
+//!
+//! Per-frame spans and events are at `trace`, decode errors at `warn`,
+//! and transport failures at `error` (a clean disconnect is `debug`).
+//! Frame spans always carry `device_id` (hex) and `counter`; bodies are
+//! only ever described by `body_len`.
+
+use tracing::{debug, error, trace, trace_span, warn};
+
+use crate::transport::{PeerAddr, TransportError};
+use crate::{DecodeError, FrameV1};
+
+/// Span for handling one frame, named after the pipeline stage.
+macro_rules! frame_span {
+    ($name:literal, $header:expr) => {{
+        let h = $header;
+        tracing::trace_span!(
+            $name,
+            device_id = %h.device_id_hex(),
+            counter = h.counter,
+            msg_type = h.msg_type.as_str(),
+        )
+    }};
+}
+pub(crate) use frame_span;
+
+pub(crate) fn frame_decoded<B: AsRef<[u8]>>(frame: &FrameV1<B>, len: usize) {
+    let _span = frame_span!("decode frame", &frame.header).entered();
+    trace!(len, body_len = frame.body.as_ref().len(), "frame decoded");
+}
+
+pub(crate) fn decode_failed(e: &DecodeError, len: usize) {
+    let _span = trace_span!("decode frame", len).entered();
+    warn!(kind = e.kind(), offset = ?e.offset(), error = %e, "frame failed to decode");
+}
+
+/// A frame was decoded from `len` bytes of input, or failed to.
+pub(crate) fn decoded<B: AsRef<[u8]>>(result: &Result<FrameV1<B>, DecodeError>, len: usize) {
+    match result {
+        Ok(f) => frame_decoded(f, len),
+        Err(e) => decode_failed(e, len),
+    }
+}
+
+pub(crate) fn encoded<B: AsRef<[u8]>>(frame: &FrameV1<B>) {
+    let _span = frame_span!("encode frame", &frame.header).entered();
+    trace!(body_len = frame.body.as_ref().len(), "frame encoded");
+}
+
+pub(crate) fn transport_error(peer: &PeerAddr, e: &TransportError) {
+    match e {
+        TransportError::Disconnected => debug!(%peer, "peer disconnected"),
+        TransportError::Protocol(_) => warn!(%peer, error = %e, "protocol error"),
+        _ => error!(%peer, error = %e, "transport failure"),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::AsyncWriteExt;
+    use tracing_subscriber::fmt::MakeWriter;
+
+    use crate::bridge::Bridge;
+    use crate::transport::tests::frame;
+    use crate::transport::{StreamTransport, Transport, loopback_pair};
+
+    /// Formatted log output collected while the guard is alive.
+    #[derive(Clone, Default)]
+    pub(crate) struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        pub(crate) fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Captured {
+        type Writer = Captured;
+
+        fn make_writer(&'a self) -> Captured {
+            self.clone()
+        }
+    }
+
+    /// Capture everything at `trace` and above on this thread.
+    pub(crate) fn capture() -> (Captured, tracing::subscriber::DefaultGuard) {
+        let out = Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_writer(out.clone())
+            .with_ansi(false)
+            .finish();
+        (out, tracing::subscriber::set_default(subscriber))
+    }
+
+    #[tokio::test]
+    async fn decode_spans_carry_device_and_counter() {
+        let (out, _guard) = capture();
+        let (mut a, mut b) = loopback_pair();
+        a.send(&frame(7)).await.unwrap();
+        b.recv().await.unwrap();
+
+        let text = out.text();
+        let fields = "{device_id=4445563030303031 counter=7 msg_type=\"event\"}";
+        assert!(text.contains(&format!("encode frame{fields}")), "{text}");
+        assert!(text.contains(&format!("decode frame{fields}")), "{text}");
+        assert!(text.contains("TRACE"), "{text}");
+        assert!(!text.contains("body="), "bodies must not be logged: {text}");
+    }
+
+    #[tokio::test]
+    async fn decode_and_protocol_errors_warn() {
+        let (out, _guard) = capture();
+        let (mut raw, b) = tokio::io::duplex(256);
+        let mut b = StreamTransport::serial(b, "ttyS0").with_max_frame_len(100);
+
+        let mut bytes = 3u32.to_be_bytes().to_vec();
+        bytes.extend_from_slice(b"XX\x01");
+        bytes.extend_from_slice(&1000u32.to_be_bytes());
+        raw.write_all(&bytes).await.unwrap();
+        assert!(b.recv().await.is_err());
+        assert!(b.recv().await.is_err());
+
+        let text = out.text();
+        assert!(
+            text.contains("WARN decode frame{len=3}: pipproto::trace: frame failed to decode kind=\"too_short\""),
+            "{text}"
+        );
+        assert!(text.contains("peer=serial:ttyS0"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn bridge_forwards_inside_a_span() {
+        let (out, _guard) = capture();
+        let (a, mut radio) = loopback_pair();
+        let (b, mut cloud) = loopback_pair();
+        let mut bridge = Bridge::new(a, b);
+
+        radio.send(&frame(3)).await.unwrap();
+        tokio::select! {
+            _ = bridge.run() => unreachable!(),
+            r = cloud.recv() => assert_eq!(r.unwrap(), frame(3)),
+        }
+        let text = out.text();
+        assert!(
+            text.contains("forward frame{device_id=4445563030303031 counter=3"),
+            "{text}"
+        );
+    }
+}
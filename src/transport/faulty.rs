@@ -0,0 +1,100 @@
+//! A transport that loses or repeats frames on purpose, for testing
+//! retransmission and acknowledgement logic against a bad link.
+
+use super::{PeerAddr, RxFrame, Transport, TransportError, TransportId};
+use crate::FrameV1;
+
+/// What happens to one sent frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Deliver,
+    /// Report success without sending.
+    Drop,
+    /// Send the frame twice.
+    Duplicate,
+}
+
+type Plan = Box<dyn FnMut(&FrameV1) -> Fault + Send>;
+
+/// Wraps a transport and asks `plan` what to do with each frame it sends.
+/// Received frames pass through untouched; wrap the other end too to
+/// fault both directions.
+pub struct FaultyTransport<T> {
+    inner: T,
+    plan: Plan,
+}
+
+impl<T: Transport> FaultyTransport<T> {
+    pub fn new(inner: T, plan: impl FnMut(&FrameV1) -> Fault + Send + 'static) -> Self {
+        FaultyTransport {
+            inner,
+            plan: Box::new(plan),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+    async fn send(&mut self, frame: &FrameV1) -> Result<(), TransportError> {
+        match (self.plan)(frame) {
+            Fault::Deliver => self.inner.send(frame).await,
+            Fault::Drop => Ok(()),
+            Fault::Duplicate => {
+                self.inner.send(frame).await?;
+                self.inner.send(frame).await
+            }
+        }
+    }
+
+    async fn recv(&mut self) -> Result<FrameV1, TransportError> {
+        self.inner.recv().await
+    }
+
+    async fn recv_rx(&mut self) -> Result<RxFrame, TransportError> {
+        self.inner.recv_rx().await
+    }
+
+    async fn close(&mut self) -> Result<(), TransportError> {
+        self.inner.close().await
+    }
+
+    fn peer(&self) -> &PeerAddr {
+        self.inner.peer()
+    }
+
+    fn id(&self) -> TransportId {
+        self.inner.id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::loopback_pair;
+    use crate::transport::tests::frame;
+
+    #[tokio::test]
+    async fn drops_and_duplicates_by_plan() {
+        let (a, mut b) = loopback_pair();
+        let mut a = FaultyTransport::new(a, |f| match f.header.counter {
+            2 => Fault::Drop,
+            3 => Fault::Duplicate,
+            _ => Fault::Deliver,
+        });
+        for counter in 1..=4 {
+            a.send(&frame(counter)).await.unwrap();
+        }
+        let mut got = Vec::new();
+        for _ in 0..4 {
+            got.push(b.recv().await.unwrap().header.counter);
+        }
+        assert_eq!(got, [1, 3, 3, 4]);
+    }
+}
@@ -0,0 +1,219 @@
+//! Transports move frames between two peers.
+//!
+//! Session-layer code is generic over [`Transport`] so it doesn't care
+//! whether bytes travel over TCP, UDP, a UART, or an in-process channel.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::{DecodeError, FrameV1};
+
+mod faulty;
+mod loopback;
+mod rx;
+#[cfg(feature = "secure-udp")]
+pub mod secure_udp;
+mod stream;
+mod supervisor;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(not(target_family = "wasm"))]
+mod udp;
+
+pub use faulty::{Fault, FaultyTransport};
+pub use loopback::{LoopbackTransport, loopback_pair, loopback_pair_with_capacity};
+pub use rx::{RxFrame, TransportId};
+#[cfg(not(target_family = "wasm"))]
+pub use stream::TcpTransport;
+#[cfg(unix)]
+pub use stream::UnixTransport;
+pub use stream::{DEFAULT_MAX_FRAME_LEN, StreamTransport};
+pub use supervisor::{LinkEvent, ReconnectSchedule, Supervisor, SupervisorConfig};
+#[cfg(not(target_family = "wasm"))]
+pub use udp::UdpTransport;
+
+/// Who is on the other end of a transport.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Socket(SocketAddr),
+    Unix(PathBuf),
+    /// Serial port name, e.g. `/dev/ttyUSB0`.
+    Serial(String),
+    Opaque(String),
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Socket(a) => write!(f, "{a}"),
+            PeerAddr::Unix(p) => write!(f, "unix:{}", p.display()),
+            PeerAddr::Serial(s) => write!(f, "serial:{s}"),
+            PeerAddr::Opaque(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+/// Received bytes that don't form a valid frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    Decode(DecodeError),
+    /// A length prefix or packed entry ran past the available bytes.
+    Truncated,
+    /// A length prefix exceeded the configured maximum.
+    TooLarge(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Decode(e) => write!(f, "{e}"),
+            ProtocolError::Truncated => write!(f, "truncated frame"),
+            ProtocolError::TooLarge(n) => write!(f, "frame too large: {n} bytes"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    /// Nothing could be sent or received in time; retrying may succeed.
+    Timeout,
+    /// The peer or link went away.
+    Disconnected,
+    /// Bytes arrived but were not a valid frame. On datagram transports
+    /// the next recv is unaffected; stream transports lose sync on
+    /// [`ProtocolError::TooLarge`] and should be closed.
+    Protocol(ProtocolError),
+    /// The secure channel could not be set up: bad or untrusted
+    /// certificate, name mismatch, no common protocol version.
+    Handshake(io::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Timeout => write!(f, "timed out"),
+            TransportError::Disconnected => write!(f, "disconnected"),
+            TransportError::Protocol(e) => write!(f, "protocol error: {e}"),
+            TransportError::Handshake(e) => write!(f, "handshake failed: {e}"),
+            TransportError::Io(e) => write!(f, "io error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        use io::ErrorKind::*;
+        match e.kind() {
+            WouldBlock | TimedOut => TransportError::Timeout,
+            ConnectionReset | ConnectionAborted | BrokenPipe | NotConnected | UnexpectedEof => {
+                TransportError::Disconnected
+            }
+            _ => TransportError::Io(e),
+        }
+    }
+}
+
+impl From<ProtocolError> for TransportError {
+    fn from(e: ProtocolError) -> Self {
+        TransportError::Protocol(e)
+    }
+}
+
+impl From<DecodeError> for TransportError {
+    fn from(e: DecodeError) -> Self {
+        TransportError::Protocol(ProtocolError::Decode(e))
+    }
+}
+
+pub trait Transport: Send {
+    fn send(&mut self, frame: &FrameV1) -> impl Future<Output = Result<(), TransportError>> + Send;
+
+    /// Must be cancel-safe: dropping a pending `recv` loses no frame, so
+    /// callers can race it against timers and other transports.
+    fn recv(&mut self) -> impl Future<Output = Result<FrameV1, TransportError>> + Send;
+
+    /// [`Transport::recv`] plus where and when the frame arrived. Equally
+    /// cancel-safe.
+    fn recv_rx(&mut self) -> impl Future<Output = Result<RxFrame, TransportError>> + Send {
+        async move {
+            let frame = self.recv().await?;
+            Ok(RxFrame::new(frame, self.peer().clone(), self.id()))
+        }
+    }
+
+    /// Flush anything pending and shut the link down.
+    fn close(&mut self) -> impl Future<Output = Result<(), TransportError>> + Send;
+
+    fn peer(&self) -> &PeerAddr;
+
+    /// The built-in transports take a fresh [`TransportId::next`] when
+    /// created.
+    fn id(&self) -> TransportId {
+        TransportId::UNASSIGNED
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{Flags, FrameHeaderV1, MsgType, VERSION_V1};
+
+    pub(crate) fn frame(counter: u64) -> FrameV1 {
+        FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Event,
+                flags: Flags::new(0).unwrap(),
+                device_id: *b"DEV00001",
+                counter,
+            },
+            body: vec![1, 2, 3].into(),
+        }
+    }
+
+    /// Exercised against every implementation.
+    pub(crate) async fn roundtrip<A: Transport, B: Transport>(a: &mut A, b: &mut B) {
+        a.send(&frame(1)).await.unwrap();
+        a.send(&frame(2)).await.unwrap();
+        assert_eq!(b.recv().await.unwrap(), frame(1));
+        assert_eq!(b.recv().await.unwrap(), frame(2));
+
+        b.send(&frame(3)).await.unwrap();
+        let rx = a.recv_rx().await.unwrap();
+        assert_eq!(*rx, frame(3));
+        assert_eq!(rx.source, *a.peer());
+        assert_eq!(rx.transport_id, a.id());
+        assert_ne!(a.id(), b.id());
+    }
+
+    #[test]
+    fn io_error_classification() {
+        let e = |k| TransportError::from(io::Error::from(k));
+        assert!(matches!(
+            e(io::ErrorKind::TimedOut),
+            TransportError::Timeout
+        ));
+        assert!(matches!(
+            e(io::ErrorKind::WouldBlock),
+            TransportError::Timeout
+        ));
+        assert!(matches!(
+            e(io::ErrorKind::UnexpectedEof),
+            TransportError::Disconnected
+        ));
+        assert!(matches!(
+            e(io::ErrorKind::BrokenPipe),
+            TransportError::Disconnected
+        ));
+        assert!(matches!(
+            e(io::ErrorKind::PermissionDenied),
+            TransportError::Io(_)
+        ));
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use super::PeerAddr;
+use crate::FrameV1;
+
+/// Names one transport instance within the process, so frames from two
+/// links to the same peer can be told apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TransportId(pub u64);
+
+impl TransportId {
+    /// Reported by transports that don't assign themselves an id.
+    pub const UNASSIGNED: TransportId = TransportId(0);
+
+    /// A fresh id, unique for the life of the process.
+    pub fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        TransportId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for TransportId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// A received frame with where and when it arrived. Derefs to the frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RxFrame {
+    pub frame: FrameV1,
+    pub received_at: SystemTime,
+    /// The sender, which on datagram transports may differ per frame.
+    pub source: PeerAddr,
+    pub transport_id: TransportId,
+}
+
+impl RxFrame {
+    /// Stamped with the current time.
+    pub fn new(frame: FrameV1, source: PeerAddr, transport_id: TransportId) -> Self {
+        RxFrame {
+            frame,
+            received_at: SystemTime::now(),
+            source,
+            transport_id,
+        }
+    }
+
+    pub fn frame(&self) -> &FrameV1 {
+        &self.frame
+    }
+
+    pub fn into_frame(self) -> FrameV1 {
+        self.frame
+    }
+}
+
+impl Deref for RxFrame {
+    type Target = FrameV1;
+
+    fn deref(&self) -> &FrameV1 {
+        &self.frame
+    }
+}
+
+impl From<RxFrame> for FrameV1 {
+    fn from(rx: RxFrame) -> Self {
+        rx.frame
+    }
+}
@@ -0,0 +1,659 @@
+//! Encrypted UDP (feature `secure-udp`), as specified in RFC section 12.
+//!
+//! Both ends hold a 32-byte pre-shared key. A two-message handshake
+//! authenticated with the PSK exchanges ephemeral X25519 keys; HKDF then
+//! derives one ChaCha20-Poly1305 key per direction for the session.
+//!
+//! Sessions are named by a random 8-byte id rather than by address, so a
+//! client whose address changes (NAT rebinding, cellular handover) keeps
+//! its session: the server follows the source of the latest authentic
+//! record. A client that is told its session is unknown, e.g. after a
+//! server restart, handshakes again on its own.
+//!
+//! One [`SecureUdpTransport`] carries one session. Servers with many
+//! clients need one socket per client or their own demultiplexing.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::{PeerAddr, ProtocolError, Transport, TransportError, TransportId};
+use crate::FrameV1;
+use crate::datagram::{MAX_DATAGRAM, UnpackError, unpack};
+
+pub const SD_MAGIC: [u8; 2] = *b"SD";
+
+const HELLO: u8 = 0x01;
+const HELLO_ACK: u8 = 0x02;
+const DATA: u8 = 0x03;
+const UNKNOWN_SESSION: u8 = 0x04;
+
+const ID_LEN: usize = 8;
+const PUB_LEN: usize = 32;
+const MAC_LEN: usize = 16;
+/// Magic, type and session id.
+const PREFIX_LEN: usize = 2 + 1 + ID_LEN;
+const HANDSHAKE_LEN: usize = PREFIX_LEN + PUB_LEN + MAC_LEN;
+const DATA_HEADER_LEN: usize = PREFIX_LEN + 8;
+
+const KEY_INFO: &[u8] = b"pipproto secure datagram v1";
+
+/// Handshake again before a direction's sequence numbers get this high.
+const REKEY_AFTER: u64 = 1 << 48;
+
+const REPLAY_WINDOW: u64 = 64;
+
+type SessionId = [u8; ID_LEN];
+
+#[derive(Debug, Clone)]
+pub struct SecureConfig {
+    /// How long a client waits for each handshake reply.
+    pub handshake_timeout: Duration,
+    pub handshake_attempts: u32,
+}
+
+impl Default for SecureConfig {
+    fn default() -> Self {
+        SecureConfig {
+            handshake_timeout: Duration::from_millis(500),
+            handshake_attempts: 5,
+        }
+    }
+}
+
+/// Truncated HMAC-SHA256 under the PSK.
+fn mac(psk: &[u8; 32], parts: &[&[u8]]) -> [u8; MAC_LEN] {
+    let mut m = <Hmac<Sha256> as Mac>::new_from_slice(psk).unwrap();
+    for p in parts {
+        m.update(p);
+    }
+    let full = m.finalize().into_bytes();
+    full[..MAC_LEN].try_into().unwrap()
+}
+
+fn verify_mac(psk: &[u8; 32], parts: &[&[u8]], tag: &[u8]) -> bool {
+    let mut m = <Hmac<Sha256> as Mac>::new_from_slice(psk).unwrap();
+    for p in parts {
+        m.update(p);
+    }
+    m.verify_truncated_left(tag).is_ok()
+}
+
+fn nonce(seq: u64) -> Nonce {
+    let mut n = [0u8; 12];
+    n[4..].copy_from_slice(&seq.to_be_bytes());
+    n.into()
+}
+
+fn handshake_error(msg: &str) -> TransportError {
+    TransportError::Handshake(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Sliding window over the highest sequence numbers seen.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    top: u64,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, seq: u64) -> bool {
+        if seq > self.top {
+            return true;
+        }
+        let age = self.top - seq;
+        age < REPLAY_WINDOW && self.seen & (1 << age) == 0 && seq != 0
+    }
+
+    fn mark(&mut self, seq: u64) {
+        if seq > self.top {
+            let shift = seq - self.top;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.top = seq;
+        }
+        self.seen |= 1 << (self.top - seq);
+    }
+}
+
+struct Session {
+    id: SessionId,
+    seal: ChaCha20Poly1305,
+    open: ChaCha20Poly1305,
+    next_seq: u64,
+    replay: ReplayWindow,
+}
+
+impl Session {
+    fn derive(
+        psk: &[u8; 32],
+        id: SessionId,
+        shared: &x25519_dalek::SharedSecret,
+        client_pub: &[u8],
+        server_pub: &[u8],
+        is_client: bool,
+    ) -> Result<Self, TransportError> {
+        if !shared.was_contributory() {
+            return Err(handshake_error("non-contributory key exchange"));
+        }
+        let hk = Hkdf::<Sha256>::new(Some(psk), shared.as_bytes());
+        let mut okm = [0u8; 64];
+        let info = [KEY_INFO, &id, client_pub, server_pub].concat();
+        hk.expand(&info, &mut okm).unwrap();
+        let c2s = ChaCha20Poly1305::new_from_slice(&okm[..32]).unwrap();
+        let s2c = ChaCha20Poly1305::new_from_slice(&okm[32..]).unwrap();
+        let (seal, open) = if is_client { (c2s, s2c) } else { (s2c, c2s) };
+        Ok(Session {
+            id,
+            seal,
+            open,
+            next_seq: 1,
+            replay: ReplayWindow::default(),
+        })
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let mut out = Vec::with_capacity(DATA_HEADER_LEN + plaintext.len() + 16);
+        out.extend_from_slice(&SD_MAGIC);
+        out.push(DATA);
+        out.extend_from_slice(&self.id);
+        out.extend_from_slice(&seq.to_be_bytes());
+        let sealed = self
+            .seal
+            .encrypt(
+                &nonce(seq),
+                Payload {
+                    msg: plaintext,
+                    aad: &out,
+                },
+            )
+            .unwrap();
+        out.extend_from_slice(&sealed);
+        out
+    }
+
+    /// Decrypt a DATA record addressed to this session, rejecting forgeries
+    /// and replays.
+    fn open(&mut self, record: &[u8]) -> Option<Vec<u8>> {
+        let (header, sealed) = record.split_at_checked(DATA_HEADER_LEN)?;
+        let seq = u64::from_be_bytes(header[PREFIX_LEN..].try_into().unwrap());
+        if !self.replay.is_fresh(seq) {
+            return None;
+        }
+        let plain = self
+            .open
+            .decrypt(
+                &nonce(seq),
+                Payload {
+                    msg: sealed,
+                    aad: header,
+                },
+            )
+            .ok()?;
+        self.replay.mark(seq);
+        Some(plain)
+    }
+}
+
+fn unknown_session(id: &SessionId) -> Vec<u8> {
+    [&SD_MAGIC[..], &[UNKNOWN_SESSION], id].concat()
+}
+
+enum Record {
+    Opened,
+    /// Forged, corrupted or replayed.
+    Rejected,
+    Unknown(SessionId),
+}
+
+/// A server-side handshake that no DATA record has confirmed yet.
+struct Pending {
+    session: Session,
+    ack: Vec<u8>,
+    from: SocketAddr,
+}
+
+enum Role {
+    Client,
+    Server { pending: Option<Pending> },
+}
+
+pub struct SecureUdpTransport {
+    socket: UdpSocket,
+    psk: [u8; 32],
+    config: SecureConfig,
+    role: Role,
+    peer: SocketAddr,
+    peer_addr: PeerAddr,
+    session: Option<Session>,
+    buf: Vec<u8>,
+    inbox: VecDeque<Result<FrameV1, UnpackError>>,
+    id: TransportId,
+}
+
+impl SecureUdpTransport {
+    /// Handshake with `server` and return the established transport.
+    pub async fn connect(
+        socket: UdpSocket,
+        server: SocketAddr,
+        psk: [u8; 32],
+        config: SecureConfig,
+    ) -> Result<Self, TransportError> {
+        let mut t = Self::new(socket, server, psk, config, Role::Client);
+        t.handshake().await?;
+        Ok(t)
+    }
+
+    /// Wait for a client's handshake and answer it. The session is
+    /// confirmed by the client's first record.
+    pub async fn accept(
+        socket: UdpSocket,
+        psk: [u8; 32],
+        config: SecureConfig,
+    ) -> Result<Self, TransportError> {
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        let role = Role::Server { pending: None };
+        let mut t = Self::new(socket, unspecified, psk, config, role);
+        loop {
+            let (n, from) = t.socket.recv_from(&mut t.buf).await?;
+            let dgram = t.buf[..n].to_vec();
+            if t.server_hello(&dgram, from).await? {
+                t.set_peer(from);
+                return Ok(t);
+            }
+            // a client of an earlier run; tell it to handshake again
+            if dgram.len() > DATA_HEADER_LEN && dgram.starts_with(&SD_MAGIC) && dgram[2] == DATA {
+                let id = dgram[3..PREFIX_LEN].try_into().unwrap();
+                t.socket.send_to(&unknown_session(&id), from).await?;
+            }
+        }
+    }
+
+    fn new(
+        socket: UdpSocket,
+        peer: SocketAddr,
+        psk: [u8; 32],
+        config: SecureConfig,
+        role: Role,
+    ) -> Self {
+        SecureUdpTransport {
+            socket,
+            psk,
+            config,
+            role,
+            peer,
+            peer_addr: PeerAddr::Socket(peer),
+            session: None,
+            buf: vec![0; MAX_DATAGRAM],
+            inbox: VecDeque::new(),
+            id: TransportId::next(),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn set_peer(&mut self, addr: SocketAddr) {
+        self.peer = addr;
+        self.peer_addr = PeerAddr::Socket(addr);
+    }
+
+    /// Client side: run a fresh handshake, replacing any current session
+    /// only once it succeeds.
+    async fn handshake(&mut self) -> Result<(), TransportError> {
+        for _ in 0..self.config.handshake_attempts {
+            let mut id = [0u8; ID_LEN];
+            OsRng.fill_bytes(&mut id);
+            let secret = EphemeralSecret::random_from_rng(OsRng);
+            let client_pub = PublicKey::from(&secret);
+
+            let mut hello = Vec::with_capacity(HANDSHAKE_LEN);
+            hello.extend_from_slice(&SD_MAGIC);
+            hello.push(HELLO);
+            hello.extend_from_slice(&id);
+            hello.extend_from_slice(client_pub.as_bytes());
+            let tag = mac(&self.psk, &[&hello]);
+            hello.extend_from_slice(&tag);
+            self.socket.send_to(&hello, self.peer).await?;
+
+            let reply = timeout(self.config.handshake_timeout, self.await_ack(&hello)).await;
+            let Ok(ack) = reply else { continue };
+            let server_pub: [u8; PUB_LEN] = ack?;
+            let shared = secret.diffie_hellman(&PublicKey::from(server_pub));
+            self.session = Some(Session::derive(
+                &self.psk,
+                id,
+                &shared,
+                client_pub.as_bytes(),
+                &server_pub,
+                true,
+            )?);
+            return Ok(());
+        }
+        Err(TransportError::Handshake(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no handshake reply",
+        )))
+    }
+
+    /// Read until an authentic HELLO_ACK for `hello` arrives. Records for
+    /// the current session are still delivered meanwhile.
+    async fn await_ack(&mut self, hello: &[u8]) -> Result<[u8; PUB_LEN], TransportError> {
+        loop {
+            let (n, _) = self.socket.recv_from(&mut self.buf).await?;
+            let d = &self.buf[..n];
+            if d.len() == HANDSHAKE_LEN
+                && d.starts_with(&SD_MAGIC)
+                && d[2] == HELLO_ACK
+                && d[3..PREFIX_LEN] == hello[3..PREFIX_LEN]
+            {
+                let (body, tag) = d.split_at(HANDSHAKE_LEN - MAC_LEN);
+                if verify_mac(&self.psk, &[hello, body], tag) {
+                    return Ok(body[PREFIX_LEN..].try_into().unwrap());
+                }
+            } else if d.len() > DATA_HEADER_LEN && d.starts_with(&SD_MAGIC) && d[2] == DATA {
+                let d = d.to_vec();
+                let _ = self.open_data(&d);
+            }
+        }
+    }
+
+    /// Server side: answer an authentic HELLO. Returns whether `dgram`
+    /// was one.
+    async fn server_hello(
+        &mut self,
+        dgram: &[u8],
+        from: SocketAddr,
+    ) -> Result<bool, TransportError> {
+        if dgram.len() != HANDSHAKE_LEN || !dgram.starts_with(&SD_MAGIC) || dgram[2] != HELLO {
+            return Ok(false);
+        }
+        let (hello_body, tag) = dgram.split_at(HANDSHAKE_LEN - MAC_LEN);
+        if !verify_mac(&self.psk, &[hello_body], tag) {
+            return Ok(false);
+        }
+        let Role::Server { pending } = &mut self.role else {
+            return Ok(false);
+        };
+        let id: SessionId = dgram[3..PREFIX_LEN].try_into().unwrap();
+
+        // a retransmitted HELLO gets the same answer
+        if let Some(p) = pending.as_ref().filter(|p| p.session.id == id) {
+            self.socket.send_to(&p.ack, from).await?;
+            return Ok(true);
+        }
+
+        let client_pub: [u8; PUB_LEN] = hello_body[PREFIX_LEN..].try_into().unwrap();
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let server_pub = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(&PublicKey::from(client_pub));
+        let session = Session::derive(
+            &self.psk,
+            id,
+            &shared,
+            &client_pub,
+            server_pub.as_bytes(),
+            false,
+        )?;
+
+        let mut ack = Vec::with_capacity(HANDSHAKE_LEN);
+        ack.extend_from_slice(&SD_MAGIC);
+        ack.push(HELLO_ACK);
+        ack.extend_from_slice(&id);
+        ack.extend_from_slice(server_pub.as_bytes());
+        let tag = mac(&self.psk, &[dgram, &ack]);
+        ack.extend_from_slice(&tag);
+
+        self.socket.send_to(&ack, from).await?;
+        *pending = Some(Pending { session, ack, from });
+        Ok(true)
+    }
+
+    /// Decrypt a DATA record into the inbox. A record for the pending
+    /// server-side session confirms it.
+    fn open_data(&mut self, d: &[u8]) -> Record {
+        let id: SessionId = d[3..PREFIX_LEN].try_into().unwrap();
+        let plain = if let Some(s) = self.session.as_mut().filter(|s| s.id == id) {
+            s.open(d)
+        } else if let Role::Server { pending } = &mut self.role
+            && let Some(p) = pending.as_mut().filter(|p| p.session.id == id)
+        {
+            let plain = p.session.open(d);
+            if plain.is_some() {
+                self.session = pending.take().map(|p| p.session);
+            }
+            plain
+        } else {
+            return Record::Unknown(id);
+        };
+        match plain {
+            Some(plain) => {
+                self.inbox.extend(unpack(&plain));
+                Record::Opened
+            }
+            None => Record::Rejected,
+        }
+    }
+
+    /// Next frame from the peer. May answer handshakes, or on a client
+    /// handshake again, while waiting.
+    pub async fn recv_frame(&mut self) -> Result<Result<FrameV1, UnpackError>, TransportError> {
+        loop {
+            if let Some(item) = self.inbox.pop_front() {
+                return Ok(item);
+            }
+            let (n, from) = self.socket.recv_from(&mut self.buf).await?;
+            let d = self.buf[..n].to_vec();
+            if d.len() < PREFIX_LEN || !d.starts_with(&SD_MAGIC) {
+                continue;
+            }
+            let is_server = matches!(self.role, Role::Server { .. });
+            match (d[2], &self.role) {
+                (HELLO, Role::Server { .. }) => {
+                    self.server_hello(&d, from).await?;
+                }
+                (DATA, _) if d.len() > DATA_HEADER_LEN => match self.open_data(&d) {
+                    // follow the client to its latest address
+                    Record::Opened if is_server && from != self.peer => self.set_peer(from),
+                    Record::Unknown(id) if is_server => {
+                        self.socket.send_to(&unknown_session(&id), from).await?;
+                    }
+                    _ => {}
+                },
+                (UNKNOWN_SESSION, Role::Client)
+                    if self.session.as_ref().is_some_and(|s| d[3..] == s.id) =>
+                {
+                    self.handshake().await?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub async fn send_frame(&mut self, frame: &FrameV1) -> Result<(), TransportError> {
+        let needs_handshake = self
+            .session
+            .as_ref()
+            .is_none_or(|s| s.next_seq >= REKEY_AFTER);
+        if needs_handshake && matches!(self.role, Role::Client) {
+            self.handshake().await?;
+        }
+        let dest = self.peer;
+        let session = match (&mut self.session, &mut self.role) {
+            (Some(s), _) => s,
+            (None, Role::Server { pending: Some(p) }) => {
+                // the client holds these keys already; its first record
+                // will confirm them
+                let dest = p.from;
+                let record = p.session.seal(&frame.encode());
+                self.socket.send_to(&record, dest).await?;
+                return Ok(());
+            }
+            _ => return Err(TransportError::Disconnected),
+        };
+        let record = session.seal(&frame.encode());
+        self.socket.send_to(&record, dest).await?;
+        Ok(())
+    }
+}
+
+impl Transport for SecureUdpTransport {
+    async fn send(&mut self, frame: &FrameV1) -> Result<(), TransportError> {
+        self.send_frame(frame).await
+    }
+
+    async fn recv(&mut self) -> Result<FrameV1, TransportError> {
+        self.recv_frame().await?.map_err(|e| match e {
+            UnpackError::Truncated { .. } => ProtocolError::Truncated.into(),
+            UnpackError::Frame { error, .. } => error.into(),
+        })
+    }
+
+    async fn close(&mut self) -> Result<(), TransportError> {
+        self.session = None;
+        Ok(())
+    }
+
+    fn peer(&self) -> &PeerAddr {
+        &self.peer_addr
+    }
+
+    fn id(&self) -> TransportId {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::{frame, roundtrip};
+
+    const PSK: [u8; 32] = [7; 32];
+
+    fn fast() -> SecureConfig {
+        SecureConfig {
+            handshake_timeout: Duration::from_millis(100),
+            handshake_attempts: 3,
+        }
+    }
+
+    async fn bind() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    async fn pair() -> (SecureUdpTransport, SecureUdpTransport) {
+        let server_sock = bind().await;
+        let addr = server_sock.local_addr().unwrap();
+        let (client, server) = tokio::join!(
+            SecureUdpTransport::connect(bind().await, addr, PSK, fast()),
+            SecureUdpTransport::accept(server_sock, PSK, fast()),
+        );
+        (client.unwrap(), server.unwrap())
+    }
+
+    #[tokio::test]
+    async fn frames_roundtrip_encrypted() {
+        let (mut client, mut server) = pair().await;
+        roundtrip(&mut client, &mut server).await;
+    }
+
+    #[test]
+    fn records_do_not_carry_plaintext() {
+        let mut session = Session {
+            id: [1; ID_LEN],
+            seal: ChaCha20Poly1305::new_from_slice(&[9; 32]).unwrap(),
+            open: ChaCha20Poly1305::new_from_slice(&[9; 32]).unwrap(),
+            next_seq: 1,
+            replay: ReplayWindow::default(),
+        };
+        let plain = frame(1).encode();
+        let record = session.seal(&plain);
+        assert!(!record.windows(plain.len()).any(|w| w == plain));
+
+        // replayed and tampered records are dropped
+        assert_eq!(session.open(&record), Some(plain));
+        assert_eq!(session.open(&record), None);
+        let mut forged = session.seal(&frame(2).encode());
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(session.open(&forged), None);
+    }
+
+    #[tokio::test]
+    async fn wrong_psk_fails_handshake() {
+        let server_sock = bind().await;
+        let addr = server_sock.local_addr().unwrap();
+        let server = tokio::spawn(SecureUdpTransport::accept(server_sock, PSK, fast()));
+        let client = SecureUdpTransport::connect(bind().await, addr, [8; 32], fast()).await;
+        assert!(matches!(client, Err(TransportError::Handshake(_))));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn session_survives_client_address_change() {
+        let (client, mut server) = pair().await;
+        // same session, new socket: what a NAT rebinding looks like
+        let moved = SecureUdpTransport {
+            socket: bind().await,
+            ..client
+        };
+        let mut client = moved;
+        let new_addr = client.local_addr().unwrap();
+
+        client.send(&frame(1)).await.unwrap();
+        assert_eq!(server.recv().await.unwrap(), frame(1));
+        assert_eq!(*server.peer(), PeerAddr::Socket(new_addr));
+        server.send(&frame(2)).await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), frame(2));
+    }
+
+    #[tokio::test]
+    async fn client_rehandshakes_after_server_restart() {
+        let (mut client, server) = pair().await;
+        let addr = server.local_addr().unwrap();
+        drop(server);
+
+        // the restarted server answers the stale session with
+        // UNKNOWN_SESSION and the client handshakes again by itself
+        let sock = UdpSocket::bind(addr).await.unwrap();
+        let server = tokio::spawn(async move {
+            let mut server = SecureUdpTransport::accept(sock, PSK, fast()).await.unwrap();
+            server.send(&frame(2)).await.unwrap();
+            server.recv().await.unwrap()
+        });
+
+        client.send(&frame(1)).await.unwrap();
+        let reply = timeout(Duration::from_secs(2), client.recv()).await;
+        assert_eq!(reply.unwrap().unwrap(), frame(2));
+        client.send(&frame(3)).await.unwrap();
+        assert_eq!(server.await.unwrap(), frame(3));
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut w = ReplayWindow::default();
+        for seq in [1, 3, 2, 70] {
+            assert!(w.is_fresh(seq));
+            w.mark(seq);
+        }
+        assert!(!w.is_fresh(3));
+        assert!(!w.is_fresh(6), "older than the window");
+        assert!(w.is_fresh(69));
+    }
+}
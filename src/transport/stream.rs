@@ -0,0 +1,406 @@
+//! Stream transports (TCP, Unix sockets, serial links), framed with
+//! [`FrameCodec`].
+//!
+//! Besides [`Transport`], [`StreamTransport`] implements `futures`'
+//! `Stream` and `Sink`. The sink buffers at most the write-buffer size
+//! (see [`StreamTransport::with_write_buffer`]) before `poll_ready` waits
+//! for the peer, so a slow reader applies backpressure rather than growing
+//! memory. With [`StreamTransport::with_pool`], its read and write buffers
+//! come from a [`BufferPool`] and go back to it when the transport is
+//! dropped, for the next connection.
+
+#[cfg(not(target_family = "wasm"))]
+use std::io;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::BytesMut;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(not(target_family = "wasm"))]
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::codec::Framed;
+
+use super::{PeerAddr, Transport, TransportError, TransportId};
+use crate::FrameV1;
+use crate::codec::FrameCodec;
+use crate::pool::BufferPool;
+
+pub use crate::codec::DEFAULT_MAX_FRAME_LEN;
+
+#[cfg(not(target_family = "wasm"))]
+pub type TcpTransport = StreamTransport<TcpStream>;
+#[cfg(unix)]
+pub type UnixTransport = StreamTransport<tokio::net::UnixStream>;
+
+pub struct StreamTransport<S> {
+    framed: Buffers<S>,
+    peer: PeerAddr,
+    id: TransportId,
+}
+
+/// What a `Framed` starts its buffers at.
+const INITIAL_BUFFER: usize = 8 << 10;
+
+/// The framed stream, its buffers put back in `pool` when dropped.
+struct Buffers<S> {
+    /// Only taken by `into_inner`.
+    framed: Option<Framed<S, FrameCodec>>,
+    pool: Option<BufferPool<BytesMut>>,
+}
+
+impl<S> Buffers<S> {
+    fn release(&mut self) {
+        if let (Some(framed), Some(pool)) = (&mut self.framed, self.pool.take()) {
+            pool.give_back(mem::take(framed.read_buffer_mut()));
+            pool.give_back(mem::take(framed.write_buffer_mut()));
+        }
+    }
+
+    fn into_inner(mut self) -> S {
+        self.release();
+        self.framed.take().unwrap().into_inner()
+    }
+}
+
+impl<S> Deref for Buffers<S> {
+    type Target = Framed<S, FrameCodec>;
+
+    fn deref(&self) -> &Self::Target {
+        self.framed.as_ref().unwrap()
+    }
+}
+
+impl<S> DerefMut for Buffers<S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.framed.as_mut().unwrap()
+    }
+}
+
+impl<S> Drop for Buffers<S> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> StreamTransport<S> {
+    pub fn new(stream: S, peer: PeerAddr) -> Self {
+        StreamTransport {
+            framed: Buffers {
+                framed: Some(Framed::new(stream, FrameCodec::default())),
+                pool: None,
+            },
+            peer,
+            id: TransportId::next(),
+        }
+    }
+
+    /// Any byte stream to a serial port (e.g. a `tokio-serial` stream).
+    pub fn serial(stream: S, port: impl Into<String>) -> Self {
+        StreamTransport::new(stream, PeerAddr::Serial(port.into()))
+    }
+
+    pub fn with_max_frame_len(mut self, max: usize) -> Self {
+        *self.framed.codec_mut() = FrameCodec::new(max);
+        self
+    }
+
+    /// Bytes the sink buffers before `poll_ready` waits for a flush.
+    pub fn with_write_buffer(mut self, bytes: usize) -> Self {
+        self.framed.set_backpressure_boundary(bytes);
+        self
+    }
+
+    /// Read and write buffers from `pool`, put back when the transport is
+    /// dropped.
+    pub fn with_pool(mut self, pool: BufferPool<BytesMut>) -> Self {
+        self.framed.release();
+        let swap = |buf: &mut BytesMut| {
+            let old = mem::replace(buf, pool.take(INITIAL_BUFFER));
+            buf.extend_from_slice(&old);
+        };
+        swap(self.framed.read_buffer_mut());
+        swap(self.framed.write_buffer_mut());
+        self.framed.pool = Some(pool);
+        self
+    }
+
+    /// Encoded bytes not yet written to the stream.
+    pub fn pending_write_bytes(&self) -> usize {
+        self.framed.write_buffer().len()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.framed.get_ref()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.framed.into_inner()
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl StreamTransport<TcpStream> {
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::from_tcp(TcpStream::connect(addr).await?)
+    }
+
+    pub fn from_tcp(stream: TcpStream) -> io::Result<Self> {
+        let peer = PeerAddr::Socket(stream.peer_addr()?);
+        Ok(StreamTransport::new(stream, peer))
+    }
+}
+
+#[cfg(unix)]
+impl StreamTransport<tokio::net::UnixStream> {
+    pub async fn connect_unix(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        Ok(StreamTransport::new(
+            stream,
+            PeerAddr::Unix(path.to_path_buf()),
+        ))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for StreamTransport<S> {
+    type Item = Result<FrameV1, TransportError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.framed.poll_next_unpin(cx).map(|item| match item? {
+            Ok(Ok(frame)) => Some(Ok(frame)),
+            Ok(Err(e)) => Some(Err(e.into())),
+            Err(e) => {
+                #[cfg(feature = "tracing")]
+                crate::trace::transport_error(&self.peer, &e);
+                Some(Err(e))
+            }
+        })
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<FrameV1> for StreamTransport<S> {
+    type Error = TransportError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        SinkExt::<&FrameV1>::poll_ready_unpin(&mut *self.framed, cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: FrameV1) -> Result<(), Self::Error> {
+        self.framed.start_send_unpin(frame)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        SinkExt::<&FrameV1>::poll_flush_unpin(&mut *self.framed, cx)
+    }
+
+    /// Flushes, then shuts down the write side.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        SinkExt::<&FrameV1>::poll_close_unpin(&mut *self.framed, cx)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Transport for StreamTransport<S> {
+    async fn send(&mut self, frame: &FrameV1) -> Result<(), TransportError> {
+        let result = self.framed.send(frame).await;
+        #[cfg(feature = "tracing")]
+        if let Err(e) = &result {
+            crate::trace::transport_error(&self.peer, e);
+        }
+        result
+    }
+
+    async fn recv(&mut self) -> Result<FrameV1, TransportError> {
+        self.next()
+            .await
+            .unwrap_or(Err(TransportError::Disconnected))
+    }
+
+    async fn close(&mut self) -> Result<(), TransportError> {
+        SinkExt::<&FrameV1>::close(&mut *self.framed).await
+    }
+
+    fn peer(&self) -> &PeerAddr {
+        &self.peer
+    }
+
+    fn id(&self) -> TransportId {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DecodeError;
+    use crate::transport::ProtocolError;
+    use crate::transport::tests::{frame, roundtrip};
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    #[cfg(not(target_family = "wasm"))]
+    use tokio::net::TcpListener;
+
+    #[cfg(not(target_family = "wasm"))]
+    async fn tcp_pair() -> (TcpTransport, TcpTransport) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpTransport::connect(addr), listener.accept());
+        let server = TcpTransport::from_tcp(accepted.unwrap().0).unwrap();
+        (client.unwrap(), server)
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn tcp_roundtrip() {
+        let (mut a, mut b) = tcp_pair().await;
+        assert_eq!(
+            *a.peer(),
+            PeerAddr::Socket(b.get_ref().local_addr().unwrap())
+        );
+        roundtrip(&mut a, &mut b).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_roundtrip() {
+        let (a, b) = tokio::net::UnixStream::pair().unwrap();
+        let mut a = UnixTransport::new(a, PeerAddr::Opaque("b".into()));
+        let mut b = UnixTransport::new(b, PeerAddr::Opaque("a".into()));
+        roundtrip(&mut a, &mut b).await;
+    }
+
+    #[tokio::test]
+    async fn serial_over_any_byte_stream() {
+        let (a, b) = tokio::io::duplex(256);
+        let mut a = StreamTransport::serial(a, "/dev/ttyUSB0");
+        let mut b = StreamTransport::serial(b, "/dev/ttyUSB1");
+        assert_eq!(*a.peer(), PeerAddr::Serial("/dev/ttyUSB0".into()));
+        roundtrip(&mut a, &mut b).await;
+    }
+
+    #[tokio::test]
+    async fn recv_is_cancel_safe() {
+        let (mut raw, b) = tokio::io::duplex(256);
+        let mut b = StreamTransport::serial(b, "x");
+
+        let mut bytes = 24u32.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&frame(7).encode());
+        raw.write_all(&bytes[..10]).await.unwrap();
+        let pending = tokio::time::timeout(Duration::from_millis(10), b.recv()).await;
+        assert!(pending.is_err());
+
+        raw.write_all(&bytes[10..]).await.unwrap();
+        assert_eq!(b.recv().await.unwrap(), frame(7));
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn pooled_buffers_go_back_on_drop() {
+        let pool = BufferPool::new();
+        for round in 0..2 {
+            let (a, b) = tcp_pair().await;
+            let mut a = a.with_pool(pool.clone());
+            let mut b = b.with_pool(pool.clone());
+            roundtrip(&mut a, &mut b).await;
+            assert_eq!(pool.stats().outstanding, 4);
+            drop((a, b));
+            let stats = pool.stats();
+            assert_eq!(stats.outstanding, 0);
+            assert_eq!(stats.hits, 4 * round);
+        }
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn eof_is_disconnected() {
+        let (mut a, mut b) = tcp_pair().await;
+        Transport::close(&mut a).await.unwrap();
+        assert!(matches!(b.recv().await, Err(TransportError::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn bad_bytes_are_protocol_errors() {
+        let (mut raw, b) = tokio::io::duplex(256);
+        let mut b = StreamTransport::serial(b, "x").with_max_frame_len(100);
+
+        let mut garbage = 21u32.to_be_bytes().to_vec();
+        garbage.extend_from_slice(&[0u8; 21]);
+        raw.write_all(&garbage).await.unwrap();
+        assert!(matches!(
+            b.recv().await,
+            Err(TransportError::Protocol(ProtocolError::Decode(
+                DecodeError::BadMagic
+            )))
+        ));
+
+        // a bad frame doesn't desync the stream
+        raw.write_all(&4u32.to_be_bytes()).await.unwrap();
+        raw.write_all(b"PPPP").await.unwrap();
+        let mut good = 24u32.to_be_bytes().to_vec();
+        good.extend_from_slice(&frame(5).encode());
+        raw.write_all(&good).await.unwrap();
+        assert!(matches!(
+            b.recv().await,
+            Err(TransportError::Protocol(ProtocolError::Decode(
+                DecodeError::TooShort
+            )))
+        ));
+        assert_eq!(b.recv().await.unwrap(), frame(5));
+
+        raw.write_all(&1000u32.to_be_bytes()).await.unwrap();
+        assert!(matches!(
+            b.recv().await,
+            Err(TransportError::Protocol(ProtocolError::TooLarge(1000)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_send_rejected() {
+        let (a, _b) = tokio::io::duplex(256);
+        let mut a = StreamTransport::serial(a, "x").with_max_frame_len(10);
+        assert!(matches!(
+            Transport::send(&mut a, &frame(1)).await,
+            Err(TransportError::Protocol(ProtocolError::TooLarge(24)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stream_and_sink() {
+        let (a, b) = tokio::io::duplex(256);
+        let mut a = StreamTransport::serial(a, "a");
+        let mut b = StreamTransport::serial(b, "b");
+
+        a.feed(frame(1)).await.unwrap();
+        a.feed(frame(2)).await.unwrap();
+        a.flush().await.unwrap();
+        assert_eq!(a.pending_write_bytes(), 0);
+
+        let got: Vec<_> = (&mut b).take(2).map(Result::unwrap).collect().await;
+        assert_eq!(got, [frame(1), frame(2)]);
+
+        SinkExt::close(&mut a).await.unwrap();
+        assert!(b.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn slow_reader_backpressures_sink() {
+        // nobody reads `_b`, so the pipe fills and then the sink's buffer
+        let (a, _b) = tokio::io::duplex(64);
+        let mut a = StreamTransport::serial(a, "a").with_write_buffer(256);
+
+        let mut sent = 0;
+        loop {
+            let ready = tokio::time::timeout(Duration::from_millis(20), a.feed(frame(sent))).await;
+            if ready.is_err() {
+                break;
+            }
+            sent += 1;
+            assert!(sent < 1000, "sink never applied backpressure");
+        }
+
+        assert!(sent > 0);
+        assert!(a.pending_write_bytes() <= 256 + 28);
+    }
+}
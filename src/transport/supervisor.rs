@@ -0,0 +1,666 @@
+//! A transport that opens its link again whenever it drops.
+//!
+//! A [`Supervisor`] gets its links from a factory closure and is itself a
+//! [`Transport`]. When the link fails on send or recv it is dropped, and
+//! the next one is asked for at once; after each failed attempt the
+//! supervisor waits as long as its [`RetryPolicy`] says, exponential
+//! backoff with jitter unless told otherwise, and a link that comes up
+//! resets the schedule. The schedule itself is a [`ReconnectSchedule`],
+//! usable without the transport.
+//!
+//! `recv` waits out the schedule; `send` only makes an attempt that is
+//! due, and otherwise queues the frame, up to
+//! [`SupervisorConfig::queue_while_down`] of them, or fails at once with
+//! [`TransportError::Disconnected`]. Queued frames go out on the next link
+//! before anything else. Only failures of the link itself (disconnects,
+//! i/o errors, a stream that lost sync) reconnect; other errors are
+//! returned as they are.
+//!
+//! Given a [`Session`] with [`Supervisor::with_session`], every link after
+//! the first starts with [`Session::reconnect`]: the HELLO goes out first,
+//! and once the peer answers the session resends what is still unacked,
+//! then what it held back. The peer must take the HELLO as the start of a
+//! new session, as it would on a new connection. Frames queued here go
+//! out right after the HELLO, before the peer is established again, so
+//! with a session leave the queue off and let the session resend.
+//! [`Supervisor::subscribe`] reports every change of the link.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::broadcast;
+
+use super::{PeerAddr, ProtocolError, RxFrame, Transport, TransportError, TransportId};
+use crate::FrameV1;
+use crate::clock::{Clock, SystemClock};
+use crate::retry::{ExponentialBackoff, RetryPolicy};
+use crate::session::{Session, SessionError};
+
+/// Events a [`Supervisor::subscribe`] receiver keeps before it lags.
+const EVENT_CAPACITY: usize = 32;
+
+/// When to try for a link again, against a clock.
+///
+/// The first attempt is due at once, and so is the first after a link
+/// was up. Failed attempt `n` puts the next off by the policy's delay for
+/// attempt `n`; when the policy has none left the schedule gives up.
+pub struct ReconnectSchedule {
+    policy: Arc<dyn RetryPolicy>,
+    clock: Arc<dyn Clock>,
+    failures: u32,
+    next: Option<Instant>,
+}
+
+impl ReconnectSchedule {
+    pub fn new(policy: impl RetryPolicy + 'static, clock: Arc<dyn Clock>) -> Self {
+        let next = Some(clock.now());
+        ReconnectSchedule {
+            policy: Arc::new(policy),
+            clock,
+            failures: 0,
+            next,
+        }
+    }
+
+    /// When the next attempt is due; `None` once given up.
+    pub fn next_attempt(&self) -> Option<Instant> {
+        self.next
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.next.is_some_and(|at| at <= self.clock.now())
+    }
+
+    pub fn gave_up(&self) -> bool {
+        self.next.is_none()
+    }
+
+    /// Attempts failed since the last link.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// An attempt failed: how long until the next, or `None` to give up.
+    pub fn on_failure(&mut self) -> Option<Duration> {
+        self.failures += 1;
+        let wait = self.policy.next_delay(self.failures);
+        self.next = wait.map(|w| self.clock.now() + w);
+        wait
+    }
+
+    /// A link came up; the next attempt, once it drops, is due at once.
+    pub fn on_connected(&mut self) {
+        self.failures = 0;
+        self.next = Some(self.clock.now());
+    }
+}
+
+impl fmt::Debug for ReconnectSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectSchedule")
+            .field("policy", &self.policy)
+            .field("failures", &self.failures)
+            .field("next", &self.next)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A change to a [`Supervisor`]'s link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkEvent {
+    /// A link is up, on the `attempts`th try.
+    Connected { attempts: u32 },
+    /// The link failed and was dropped.
+    Disconnected,
+    /// Attempt `attempt` failed; the next is due in `retry_in`.
+    ConnectFailed { attempt: u32, retry_in: Duration },
+    /// Attempt `attempts` failed and the policy has no more.
+    GaveUp { attempts: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    /// Frames sent while there is no link that are kept for the next one;
+    /// 0 fails such sends at once.
+    pub queue_while_down: usize,
+    /// An attempt taking longer than this fails with
+    /// [`TransportError::Timeout`].
+    pub connect_timeout: Duration,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            queue_while_down: 0,
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Keeps a link from `connect` up; see the [module docs](self).
+pub struct Supervisor<F, T> {
+    connect: F,
+    config: SupervisorConfig,
+    link: Option<T>,
+    schedule: ReconnectSchedule,
+    queue: VecDeque<FrameV1>,
+    session: Option<Arc<Mutex<Session>>>,
+    events: broadcast::Sender<LinkEvent>,
+    peer: PeerAddr,
+    id: TransportId,
+    connected_before: bool,
+    closed: bool,
+}
+
+impl<F, Fut, T> Supervisor<F, T>
+where
+    F: FnMut() -> Fut + Send,
+    Fut: Future<Output = Result<T, TransportError>> + Send,
+    T: Transport,
+{
+    /// Nothing is connected until the first send or recv. Retries back
+    /// off from 100ms, doubling up to 30s, each spread by up to a fifth.
+    pub fn new(connect: F, config: SupervisorConfig) -> Self {
+        let policy =
+            ExponentialBackoff::new(Duration::from_millis(100), 2.0, Duration::from_secs(30))
+                .with_jitter(0.2);
+        Supervisor {
+            connect,
+            config,
+            link: None,
+            schedule: ReconnectSchedule::new(policy, Arc::new(SystemClock)),
+            queue: VecDeque::new(),
+            session: None,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            peer: PeerAddr::Opaque("supervisor:down".into()),
+            id: TransportId::next(),
+            connected_before: false,
+            closed: false,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: impl RetryPolicy + 'static) -> Self {
+        self.schedule = ReconnectSchedule::new(policy, self.schedule.clock.clone());
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.schedule.next = Some(clock.now());
+        self.schedule.clock = clock;
+        self
+    }
+
+    /// Replay `session`'s handshake on every new link.
+    pub fn with_session(mut self, session: Arc<Mutex<Session>>) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Every [`LinkEvent`] from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<LinkEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.link.is_some()
+    }
+
+    pub fn schedule(&self) -> &ReconnectSchedule {
+        &self.schedule
+    }
+
+    /// Frames waiting for the next link.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// The link, if one is up.
+    pub fn get_ref(&self) -> Option<&T> {
+        self.link.as_ref()
+    }
+
+    /// One attempt at a link, then the handshake and queued frames on it.
+    async fn try_connect(&mut self) -> Result<(), TransportError> {
+        let attempt = self.schedule.failures() + 1;
+        let result = tokio::time::timeout(self.config.connect_timeout, (self.connect)())
+            .await
+            .unwrap_or(Err(TransportError::Timeout));
+        let link = match result {
+            Ok(link) => link,
+            Err(e) => {
+                let event = match self.schedule.on_failure() {
+                    Some(retry_in) => LinkEvent::ConnectFailed { attempt, retry_in },
+                    None => LinkEvent::GaveUp { attempts: attempt },
+                };
+                let _ = self.events.send(event);
+                return Err(e);
+            }
+        };
+        self.schedule.on_connected();
+        self.peer = link.peer().clone();
+        self.link = Some(link);
+        let _ = self.events.send(LinkEvent::Connected { attempts: attempt });
+        if self.connected_before {
+            self.replay_handshake();
+        }
+        self.connected_before = true;
+        self.flush().await
+    }
+
+    /// Put the session's HELLO ahead of everything queued.
+    fn replay_handshake(&mut self) {
+        let Some(session) = &self.session else {
+            return;
+        };
+        let mut session = session.lock().unwrap_or_else(|e| e.into_inner());
+        match session.reconnect() {
+            // nothing to replay before connecting or after closing
+            Ok(()) | Err(SessionError::InvalidTransition { .. }) => {}
+            Err(_) => return,
+        }
+        let frames: Vec<_> = std::iter::from_fn(|| session.poll_transmit()).collect();
+        for frame in frames.into_iter().rev() {
+            self.queue.push_front(frame);
+        }
+    }
+
+    /// Send what is queued, oldest first. A frame leaves the queue once
+    /// sent, so a cancelled flush loses nothing.
+    async fn flush(&mut self) -> Result<(), TransportError> {
+        while let (Some(link), Some(frame)) = (&mut self.link, self.queue.front()) {
+            if let Err(e) = link.send(frame).await {
+                if is_link_error(&e) {
+                    self.lost();
+                }
+                return Err(e);
+            }
+            self.queue.pop_front();
+        }
+        Ok(())
+    }
+
+    fn lost(&mut self) {
+        self.link = None;
+        let _ = self.events.send(LinkEvent::Disconnected);
+    }
+
+    /// Keep `frame` for the next link, if there will be one and there is
+    /// room.
+    fn hold(&mut self, frame: &FrameV1) -> Result<(), TransportError> {
+        if self.schedule.gave_up() || self.queue.len() >= self.config.queue_while_down {
+            return Err(TransportError::Disconnected);
+        }
+        self.queue.push_back(frame.clone());
+        Ok(())
+    }
+}
+
+/// Errors after which the link is no use.
+fn is_link_error(e: &TransportError) -> bool {
+    matches!(
+        e,
+        TransportError::Disconnected
+            | TransportError::Io(_)
+            | TransportError::Protocol(ProtocolError::TooLarge(_))
+    )
+}
+
+impl<F, Fut, T> Transport for Supervisor<F, T>
+where
+    F: FnMut() -> Fut + Send,
+    Fut: Future<Output = Result<T, TransportError>> + Send,
+    T: Transport,
+{
+    async fn send(&mut self, frame: &FrameV1) -> Result<(), TransportError> {
+        if self.closed {
+            return Err(TransportError::Disconnected);
+        }
+        // once on the link there is, once on a fresh one if it failed
+        for _ in 0..2 {
+            if self.link.is_none() {
+                if !self.schedule.is_due() || self.try_connect().await.is_err() {
+                    break;
+                }
+            } else if let Err(e) = self.flush().await {
+                if self.link.is_some() {
+                    return Err(e);
+                }
+                continue;
+            }
+            let link = self.link.as_mut().expect("connected");
+            match link.send(frame).await {
+                Err(e) if is_link_error(&e) => self.lost(),
+                r => return r,
+            }
+        }
+        self.hold(frame)
+    }
+
+    async fn recv(&mut self) -> Result<FrameV1, TransportError> {
+        Ok(self.recv_rx().await?.frame)
+    }
+
+    async fn recv_rx(&mut self) -> Result<RxFrame, TransportError> {
+        loop {
+            if self.closed {
+                return Err(TransportError::Disconnected);
+            }
+            if self.link.is_none() {
+                let Some(at) = self.schedule.next_attempt() else {
+                    return Err(TransportError::Disconnected);
+                };
+                let wait = at.saturating_duration_since(self.schedule.clock.now());
+                tokio::time::sleep(wait).await;
+                let _ = self.try_connect().await;
+                continue;
+            }
+            if let Err(e) = self.flush().await {
+                if self.link.is_some() {
+                    return Err(e);
+                }
+                continue;
+            }
+            let link = self.link.as_mut().expect("connected");
+            match link.recv_rx().await {
+                Ok(mut rx) => {
+                    rx.transport_id = self.id;
+                    return Ok(rx);
+                }
+                Err(e) if is_link_error(&e) => self.lost(),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Close the link, if any, and stop reconnecting. Queued frames are
+    /// dropped.
+    async fn close(&mut self) -> Result<(), TransportError> {
+        self.closed = true;
+        self.queue.clear();
+        match self.link.take() {
+            Some(mut link) => link.close().await,
+            None => Ok(()),
+        }
+    }
+
+    /// The current link's peer, or the last one's while down.
+    fn peer(&self) -> &PeerAddr {
+        &self.peer
+    }
+
+    /// The supervisor's own, the same across links.
+    fn id(&self) -> TransportId {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::session::SessionConfig;
+    use crate::transport::tests::frame;
+    use crate::transport::{LoopbackTransport, loopback_pair};
+    use crate::{Flags, MsgType};
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Links that come up while `up` is set, their far ends sent to `far`.
+    struct Links {
+        up: Arc<AtomicBool>,
+        attempts: Arc<AtomicU32>,
+        far: mpsc::UnboundedSender<LoopbackTransport>,
+    }
+
+    impl Links {
+        fn new() -> (Links, mpsc::UnboundedReceiver<LoopbackTransport>) {
+            let (far, ends) = mpsc::unbounded_channel();
+            let links = Links {
+                up: Arc::new(AtomicBool::new(true)),
+                attempts: Arc::new(AtomicU32::new(0)),
+                far,
+            };
+            (links, ends)
+        }
+
+        fn connector(
+            &self,
+        ) -> impl FnMut() -> std::future::Ready<Result<LoopbackTransport, TransportError>> + Send + 'static
+        {
+            let (up, attempts, far) = (self.up.clone(), self.attempts.clone(), self.far.clone());
+            move || {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                if !up.load(Ordering::SeqCst) {
+                    return std::future::ready(Err(TransportError::Disconnected));
+                }
+                let (near, end) = loopback_pair();
+                let _ = far.send(end);
+                std::future::ready(Ok(near))
+            }
+        }
+
+        fn attempts(&self) -> u32 {
+            self.attempts.load(Ordering::SeqCst)
+        }
+    }
+
+    fn events(rx: &mut broadcast::Receiver<LinkEvent>) -> Vec<LinkEvent> {
+        std::iter::from_fn(|| rx.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn schedule_backs_off_and_resets() {
+        let clock = ManualClock::new();
+        let policy = ExponentialBackoff::new(10 * MS, 2.0, 50 * MS).with_max_attempts(7);
+        let mut schedule = ReconnectSchedule::new(policy, Arc::new(clock.clone()));
+        assert!(schedule.is_due());
+
+        let waits: Vec<_> = (0..4).map(|_| schedule.on_failure().unwrap()).collect();
+        assert_eq!(waits, [10 * MS, 20 * MS, 40 * MS, 50 * MS]);
+        assert_eq!(schedule.next_attempt(), Some(clock.now() + 50 * MS));
+        clock.advance(49 * MS);
+        assert!(!schedule.is_due());
+        clock.advance(MS);
+        assert!(schedule.is_due());
+
+        schedule.on_connected();
+        assert!(schedule.is_due());
+        assert_eq!(schedule.failures(), 0);
+        assert_eq!(schedule.on_failure(), Some(10 * MS));
+
+        for _ in 2..7 {
+            assert!(schedule.on_failure().is_some());
+        }
+        assert_eq!(schedule.on_failure(), None);
+        assert!(schedule.gave_up());
+        assert_eq!(schedule.next_attempt(), None);
+    }
+
+    #[tokio::test]
+    async fn sends_while_down_wait_for_the_schedule() {
+        let (links, mut far) = Links::new();
+        links.up.store(false, Ordering::SeqCst);
+        let clock = ManualClock::new();
+        let config = SupervisorConfig {
+            queue_while_down: 2,
+            ..Default::default()
+        };
+        let mut sup = Supervisor::new(links.connector(), config)
+            .with_policy(ExponentialBackoff::new(
+                10 * MS,
+                2.0,
+                Duration::from_secs(1),
+            ))
+            .with_clock(Arc::new(clock.clone()));
+        let mut rx = sup.subscribe();
+
+        sup.send(&frame(1)).await.unwrap();
+        sup.send(&frame(2)).await.unwrap();
+        assert!(matches!(
+            sup.send(&frame(3)).await,
+            Err(TransportError::Disconnected)
+        ));
+        assert_eq!((links.attempts(), sup.queued()), (1, 2));
+
+        clock.advance(10 * MS);
+        assert!(sup.send(&frame(3)).await.is_err());
+        clock.advance(19 * MS);
+        assert!(sup.send(&frame(3)).await.is_err());
+        assert_eq!(links.attempts(), 2);
+        clock.advance(MS);
+        assert!(sup.send(&frame(3)).await.is_err());
+        assert_eq!(
+            events(&mut rx),
+            [
+                LinkEvent::ConnectFailed {
+                    attempt: 1,
+                    retry_in: 10 * MS
+                },
+                LinkEvent::ConnectFailed {
+                    attempt: 2,
+                    retry_in: 20 * MS
+                },
+                LinkEvent::ConnectFailed {
+                    attempt: 3,
+                    retry_in: 40 * MS
+                },
+            ]
+        );
+
+        links.up.store(true, Ordering::SeqCst);
+        clock.advance(40 * MS);
+        sup.send(&frame(3)).await.unwrap();
+        assert!(sup.is_connected());
+        let mut end = far.recv().await.unwrap();
+        for counter in 1..=3 {
+            assert_eq!(end.recv().await.unwrap().header.counter, counter);
+        }
+
+        // a working link resets the backoff
+        links.up.store(false, Ordering::SeqCst);
+        drop(end);
+        sup.send(&frame(4)).await.unwrap();
+        assert_eq!(sup.queued(), 1);
+        assert_eq!(
+            events(&mut rx),
+            [
+                LinkEvent::Connected { attempts: 4 },
+                LinkEvent::Disconnected,
+                LinkEvent::ConnectFailed {
+                    attempt: 1,
+                    retry_in: 10 * MS
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_fast_and_gives_up() {
+        let (links, _far) = Links::new();
+        links.up.store(false, Ordering::SeqCst);
+        let clock = ManualClock::new();
+        let policy = ExponentialBackoff::new(10 * MS, 1.0, 10 * MS).with_max_attempts(2);
+        let mut sup = Supervisor::new(links.connector(), SupervisorConfig::default())
+            .with_policy(policy)
+            .with_clock(Arc::new(clock.clone()));
+        let mut rx = sup.subscribe();
+
+        assert!(sup.send(&frame(1)).await.is_err());
+        assert_eq!(sup.queued(), 0);
+        clock.advance(10 * MS);
+        assert!(sup.send(&frame(1)).await.is_err());
+        assert!(sup.schedule().gave_up());
+        clock.advance(Duration::from_secs(60));
+        assert!(matches!(
+            sup.recv().await,
+            Err(TransportError::Disconnected)
+        ));
+        assert_eq!(links.attempts(), 2);
+        assert_eq!(
+            events(&mut rx).last(),
+            Some(&LinkEvent::GaveUp { attempts: 2 })
+        );
+    }
+
+    #[tokio::test]
+    async fn recv_reconnects_and_keeps_one_id() {
+        let (links, mut far) = Links::new();
+        let mut sup = Supervisor::new(links.connector(), SupervisorConfig::default())
+            .with_policy(ExponentialBackoff::new(MS, 2.0, 10 * MS));
+        sup.send(&frame(1)).await.unwrap();
+        let end = far.recv().await.unwrap();
+        drop(end);
+
+        let (got, _end) = tokio::join!(sup.recv_rx(), async {
+            let mut end = far.recv().await.unwrap();
+            end.send(&frame(2)).await.unwrap();
+            end
+        });
+        let got = got.unwrap();
+        assert_eq!(got.header.counter, 2);
+        assert_eq!(got.transport_id, sup.id());
+        assert_eq!(got.source, *sup.peer());
+        assert_eq!(links.attempts(), 2);
+
+        sup.close().await.unwrap();
+        assert!(sup.send(&frame(3)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn new_links_replay_the_session_handshake() {
+        const DEV: [u8; 8] = *b"DEV00001";
+        let (links, mut far) = Links::new();
+        let clock = ManualClock::new();
+        let acks = crate::ack::AckTracker::new(Default::default(), Arc::new(clock.clone()));
+        let session = Session::with_clock(DEV, SessionConfig::default(), Arc::new(clock.clone()))
+            .with_acks(acks);
+        let session = Arc::new(Mutex::new(session));
+        let mut sup = Supervisor::new(links.connector(), SupervisorConfig::default())
+            .with_session(session.clone());
+        let transmit = || session.lock().unwrap().poll_transmit();
+
+        // a peer that starts a session of its own on every link
+        async fn answer(end: &mut LoopbackTransport, clock: &ManualClock) -> Session {
+            let mut peer =
+                Session::with_clock(DEV, SessionConfig::default(), Arc::new(clock.clone()));
+            peer.on_frame(end.recv().await.unwrap()).unwrap();
+            peer.accept().unwrap();
+            end.send(&peer.poll_transmit().unwrap()).await.unwrap();
+            peer
+        }
+
+        session.lock().unwrap().connect().unwrap();
+        sup.send(&transmit().unwrap()).await.unwrap();
+        let mut end = far.recv().await.unwrap();
+        let (hello, _peer) = tokio::join!(sup.recv(), answer(&mut end, &clock));
+        session.lock().unwrap().on_frame(hello.unwrap()).unwrap();
+
+        let ack_required = Flags::new(Flags::ACK_REQUIRED).unwrap();
+        session
+            .lock()
+            .unwrap()
+            .send(MsgType::Event, ack_required, vec![7])
+            .unwrap();
+        sup.send(&transmit().unwrap()).await.unwrap();
+        drop(end);
+
+        let reconnect = async {
+            let mut end = far.recv().await.unwrap();
+            let peer = answer(&mut end, &clock).await;
+            (end, peer)
+        };
+        let (hello, (mut end, mut peer)) = tokio::join!(sup.recv(), reconnect);
+        session.lock().unwrap().on_frame(hello.unwrap()).unwrap();
+        sup.send(&transmit().unwrap()).await.unwrap();
+        let resent = peer.on_frame(end.recv().await.unwrap()).unwrap().unwrap();
+        assert_eq!((resent.header.counter, &resent.body[..]), (2, &[7][..]));
+        assert_eq!(transmit(), None);
+    }
+}
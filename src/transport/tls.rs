@@ -0,0 +1,180 @@
+//! TLS over TCP (feature `tls`, rustls with the ring provider).
+//!
+//! [`TlsConnector`] and [`TlsAcceptor`] wrap an established TCP stream and
+//! yield a [`StreamTransport`] speaking the same length-prefixed framing as
+//! plain TCP. Certificate, name and protocol failures during the handshake
+//! surface as [`TransportError::Handshake`].
+//!
+//! For the platform's trust store, or any other verifier, build a rustls
+//! config yourself and pass it to `from_config`.
+
+use std::io;
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use super::{PeerAddr, StreamTransport, TransportError};
+
+/// The rustls version this module is built against.
+pub use tokio_rustls::rustls;
+
+pub type TlsClientTransport = StreamTransport<tokio_rustls::client::TlsStream<TcpStream>>;
+pub type TlsServerTransport = StreamTransport<tokio_rustls::server::TlsStream<TcpStream>>;
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn handshake_error(e: io::Error) -> TransportError {
+    match TransportError::from(e) {
+        TransportError::Io(e) => TransportError::Handshake(e),
+        other => other,
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsConnector {
+    inner: tokio_rustls::TlsConnector,
+}
+
+impl TlsConnector {
+    /// Trust servers whose chain ends in `roots`.
+    pub fn new(roots: RootCertStore) -> Result<Self, rustls::Error> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self::from_config(Arc::new(config)))
+    }
+
+    pub fn from_config(config: Arc<ClientConfig>) -> Self {
+        TlsConnector {
+            inner: config.into(),
+        }
+    }
+
+    pub async fn connect(
+        &self,
+        addr: impl ToSocketAddrs,
+        server_name: ServerName<'static>,
+    ) -> Result<TlsClientTransport, TransportError> {
+        self.connect_stream(TcpStream::connect(addr).await?, server_name)
+            .await
+    }
+
+    pub async fn connect_stream(
+        &self,
+        tcp: TcpStream,
+        server_name: ServerName<'static>,
+    ) -> Result<TlsClientTransport, TransportError> {
+        let peer = PeerAddr::Socket(tcp.peer_addr()?);
+        let tls = self
+            .inner
+            .connect(server_name, tcp)
+            .await
+            .map_err(handshake_error)?;
+        Ok(StreamTransport::new(tls, peer))
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    inner: tokio_rustls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    /// Present `cert_chain` (leaf first) without asking for client certs.
+    pub fn new(
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<Self, rustls::Error> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key)?;
+        Ok(Self::from_config(Arc::new(config)))
+    }
+
+    pub fn from_config(config: Arc<ServerConfig>) -> Self {
+        TlsAcceptor {
+            inner: config.into(),
+        }
+    }
+
+    pub async fn accept(&self, tcp: TcpStream) -> Result<TlsServerTransport, TransportError> {
+        let peer = PeerAddr::Socket(tcp.peer_addr()?);
+        let tls = self.inner.accept(tcp).await.map_err(handshake_error)?;
+        Ok(StreamTransport::new(tls, peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::roundtrip;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use tokio::net::TcpListener;
+
+    struct Identity {
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+    }
+
+    fn self_signed(name: &str) -> Identity {
+        let ck = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        Identity {
+            cert: ck.cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(ck.signing_key.serialize_der()).into(),
+        }
+    }
+
+    async fn connect(
+        server: Identity,
+        trusted: CertificateDer<'static>,
+        name: &'static str,
+    ) -> (
+        Result<TlsClientTransport, TransportError>,
+        Result<TlsServerTransport, TransportError>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::new(vec![server.cert], server.key).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted).unwrap();
+        let connector = TlsConnector::new(roots).unwrap();
+
+        let name = ServerName::try_from(name).unwrap();
+        let server = async {
+            let (tcp, _) = listener.accept().await.unwrap();
+            acceptor.accept(tcp).await
+        };
+        tokio::join!(connector.connect(addr, name), server)
+    }
+
+    #[tokio::test]
+    async fn frames_roundtrip_over_tls() {
+        let id = self_signed("gateway.local");
+        let cert = id.cert.clone();
+        let (client, server) = connect(id, cert, "gateway.local").await;
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        roundtrip(&mut client, &mut server).await;
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate_is_a_handshake_error() {
+        let id = self_signed("gateway.local");
+        let other = self_signed("gateway.local").cert;
+        let (client, _) = connect(id, other, "gateway.local").await;
+        assert!(matches!(client, Err(TransportError::Handshake(_))));
+    }
+
+    #[tokio::test]
+    async fn name_mismatch_is_a_handshake_error() {
+        let id = self_signed("gateway.local");
+        let cert = id.cert.clone();
+        let (client, _) = connect(id, cert, "other.local").await;
+        assert!(matches!(client, Err(TransportError::Handshake(_))));
+    }
+}
@@ -0,0 +1,338 @@
+//! UDP transport, optionally coalescing outbound frames into packed
+//! datagrams (see [`crate::datagram`]).
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, sleep_until};
+
+use super::{PeerAddr, ProtocolError, RxFrame, Transport, TransportError, TransportId};
+use crate::FrameV1;
+use crate::datagram::{MAX_DATAGRAM, PACK_MAGIC, Packer, UnpackError, unpack};
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    peer_addr: PeerAddr,
+    coalesce: Option<Duration>,
+    packer: Packer,
+    queue: VecDeque<FrameV1>,
+    queued_bytes: usize,
+    deadline: Option<Instant>,
+    /// Packed from `queue` but not yet sent, by a flush cancelled midway.
+    unsent: Option<Vec<u8>>,
+    inbox: VecDeque<Result<FrameV1, UnpackError>>,
+    inbox_from: SocketAddr,
+    buf: Vec<u8>,
+    id: TransportId,
+}
+
+impl UdpTransport {
+    pub fn new(socket: UdpSocket, peer: SocketAddr) -> Self {
+        UdpTransport {
+            socket,
+            peer,
+            peer_addr: PeerAddr::Socket(peer),
+            coalesce: None,
+            packer: Packer::default(),
+            queue: VecDeque::new(),
+            queued_bytes: PACK_MAGIC.len(),
+            deadline: None,
+            unsent: None,
+            inbox: VecDeque::new(),
+            inbox_from: peer,
+            buf: vec![0; MAX_DATAGRAM],
+            id: TransportId::next(),
+        }
+    }
+
+    /// Hold outbound frames for up to `max_delay`, or until `mtu` bytes are
+    /// queued, and send them packed. Frames held past `max_delay` go with
+    /// the next [`UdpTransport::send`], or while [`UdpTransport::recv_from`]
+    /// waits; a caller that stops sending and never receives must call
+    /// [`UdpTransport::flush`] itself. Flush before dropping the transport.
+    pub fn with_coalescing(mut self, max_delay: Duration, mtu: usize) -> Self {
+        self.coalesce = Some(max_delay);
+        self.packer = Packer::new(mtu);
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn send(&mut self, frame: &FrameV1) -> io::Result<()> {
+        #[cfg(feature = "metrics")]
+        crate::metrics::global().encoded(frame);
+        #[cfg(feature = "tracing")]
+        crate::trace::encoded(frame);
+        let Some(max_delay) = self.coalesce else {
+            let bytes = frame.encode();
+            self.socket.send_to(&bytes, self.peer).await?;
+            #[cfg(feature = "metrics")]
+            crate::metrics::global().bytes_out(bytes.len());
+            return Ok(());
+        };
+
+        // frames held too long go first, so this one gets its own wait
+        if self.deadline.is_some_and(|d| d <= Instant::now()) {
+            self.flush().await?;
+        }
+        self.queued_bytes += 2 + frame.encoded_len();
+        self.queue.push_back(frame.clone());
+        self.deadline
+            .get_or_insert_with(|| Instant::now() + max_delay);
+
+        if self.queued_bytes >= self.packer.mtu() {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Send everything queued. Never sends an empty datagram.
+    ///
+    /// Cancel-safe: a datagram packed but not sent when the future is
+    /// dropped is kept, and sent first by the next flush. One whose send
+    /// fails is dropped, with its frames.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        loop {
+            if self.unsent.is_none() {
+                self.unsent = self.packer.pack(&mut self.queue);
+            }
+            let Some(dgram) = &self.unsent else { break };
+            let sent = self.socket.send_to(dgram, self.peer).await;
+            self.unsent = None;
+            #[cfg(feature = "metrics")]
+            if let Ok(n) = sent {
+                crate::metrics::global().bytes_out(n);
+            }
+            sent?;
+        }
+        self.queued_bytes = PACK_MAGIC.len();
+        self.deadline = None;
+        Ok(())
+    }
+
+    /// Next received frame and the address it came from. Frames from one
+    /// packed datagram are returned one per call, each with its own result.
+    pub async fn recv_from(&mut self) -> io::Result<(SocketAddr, Result<FrameV1, UnpackError>)> {
+        loop {
+            if let Some(item) = self.inbox.pop_front() {
+                #[cfg(feature = "metrics")]
+                match &item {
+                    Ok(frame) => crate::metrics::global().received(frame),
+                    Err(UnpackError::Frame { error, .. }) => {
+                        crate::metrics::global().decode_error(error)
+                    }
+                    Err(UnpackError::Truncated { .. }) => {}
+                }
+                #[cfg(feature = "tracing")]
+                match &item {
+                    Ok(frame) => crate::trace::frame_decoded(frame, frame.encoded_len()),
+                    Err(UnpackError::Frame { error, .. }) => crate::trace::decode_failed(error, 0),
+                    Err(UnpackError::Truncated { .. }) => {}
+                }
+                return Ok((self.inbox_from, item));
+            }
+
+            let deadline = self.deadline;
+            tokio::select! {
+                r = self.socket.recv_from(&mut self.buf) => {
+                    let (n, from) = r?;
+                    #[cfg(feature = "metrics")]
+                    crate::metrics::global().bytes_in(n);
+                    self.inbox.extend(unpack(&self.buf[..n]));
+                    self.inbox_from = from;
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.flush().await?;
+                }
+            }
+        }
+    }
+
+    async fn recv_transport(&mut self) -> Result<(SocketAddr, FrameV1), TransportError> {
+        let (from, item) = match self.recv_from().await {
+            Ok(r) => r,
+            Err(e) => {
+                let e = TransportError::from(e);
+                #[cfg(feature = "tracing")]
+                crate::trace::transport_error(&self.peer_addr, &e);
+                return Err(e);
+            }
+        };
+        let frame = item.map_err(|e| match e {
+            UnpackError::Truncated { .. } => TransportError::from(ProtocolError::Truncated),
+            UnpackError::Frame { error, .. } => error.into(),
+        })?;
+        Ok((from, frame))
+    }
+}
+
+impl Transport for UdpTransport {
+    async fn send(&mut self, frame: &FrameV1) -> Result<(), TransportError> {
+        let result = UdpTransport::send(self, frame).await.map_err(Into::into);
+        #[cfg(feature = "tracing")]
+        if let Err(e) = &result {
+            crate::trace::transport_error(&self.peer_addr, e);
+        }
+        result
+    }
+
+    async fn recv(&mut self) -> Result<FrameV1, TransportError> {
+        Ok(self.recv_transport().await?.1)
+    }
+
+    /// The source is the datagram's sender, not necessarily the peer.
+    async fn recv_rx(&mut self) -> Result<RxFrame, TransportError> {
+        let (from, frame) = self.recv_transport().await?;
+        Ok(RxFrame::new(frame, PeerAddr::Socket(from), self.id))
+    }
+
+    async fn close(&mut self) -> Result<(), TransportError> {
+        Ok(self.flush().await?)
+    }
+
+    fn peer(&self) -> &PeerAddr {
+        &self.peer_addr
+    }
+
+    fn id(&self) -> TransportId {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::{frame, roundtrip};
+
+    async fn pair() -> (UdpTransport, UdpSocket) {
+        let rx = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer = rx.local_addr().unwrap();
+        (UdpTransport::new(tx, peer), rx)
+    }
+
+    async fn recv_datagram(sock: &UdpSocket) -> Vec<u8> {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let n = tokio::time::timeout(Duration::from_secs(2), sock.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        buf.truncate(n);
+        buf
+    }
+
+    #[tokio::test]
+    async fn plain_send_recv() {
+        let (mut a, rx) = pair().await;
+        let mut b = UdpTransport::new(rx, a.local_addr().unwrap());
+
+        a.send(&frame(1)).await.unwrap();
+        let (from, got) = b.recv_from().await.unwrap();
+        assert_eq!(from, a.local_addr().unwrap());
+        assert_eq!(got, Ok(frame(1)));
+
+        roundtrip(&mut a, &mut b).await;
+    }
+
+    #[tokio::test]
+    async fn bad_datagram_is_a_protocol_error() {
+        let (a, rx) = pair().await;
+        let mut b = UdpTransport::new(rx, a.local_addr().unwrap());
+        let raw = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        raw.send_to(b"not a frame at all, honest!", b.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            Transport::recv(&mut b).await,
+            Err(TransportError::Protocol(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn coalesce_until_delay() {
+        let (a, rx) = pair().await;
+        let mut a = a.with_coalescing(Duration::from_millis(20), 1200);
+
+        for c in 0..3 {
+            a.send(&frame(c)).await.unwrap();
+        }
+        // recv drives the coalescing timer
+        let _ = tokio::time::timeout(Duration::from_millis(60), a.recv_from()).await;
+
+        let dgram = recv_datagram(&rx).await;
+        let counters: Vec<u64> = unpack(&dgram).map(|f| f.unwrap().header.counter).collect();
+        assert_eq!(counters, [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn a_later_send_takes_overdue_frames_along() {
+        let (a, rx) = pair().await;
+        let mut a = a.with_coalescing(Duration::from_millis(20), 1200);
+
+        a.send(&frame(0)).await.unwrap();
+        a.send(&frame(1)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        // no receive drives the timer; the next send finds the frames overdue
+        a.send(&frame(2)).await.unwrap();
+
+        let dgram = recv_datagram(&rx).await;
+        let counters: Vec<u64> = unpack(&dgram).map(|f| f.unwrap().header.counter).collect();
+        assert_eq!(counters, [0, 1]);
+        a.flush().await.unwrap();
+        assert_eq!(recv_datagram(&rx).await, frame(2).encode());
+    }
+
+    #[tokio::test]
+    async fn a_datagram_packed_by_a_cancelled_flush_is_sent_later() {
+        let (a, rx) = pair().await;
+        let mut a = a.with_coalescing(Duration::from_millis(20), 1200);
+        for c in 0..3 {
+            a.send(&frame(c)).await.unwrap();
+        }
+        // where a flush dropped while its send was pending leaves off: the
+        // frames packed and off the queue, but not sent
+        a.unsent = a.packer.pack(&mut a.queue);
+        assert!(a.queue.is_empty());
+        a.send(&frame(3)).await.unwrap();
+
+        // the next receive's flush sends them ahead of the later frame
+        let _ = tokio::time::timeout(Duration::from_millis(60), a.recv_from()).await;
+        let dgram = recv_datagram(&rx).await;
+        let counters: Vec<u64> = unpack(&dgram).map(|f| f.unwrap().header.counter).collect();
+        assert_eq!(counters, [0, 1, 2]);
+        assert_eq!(recv_datagram(&rx).await, frame(3).encode());
+    }
+
+    #[tokio::test]
+    async fn coalesce_until_full() {
+        let (a, rx) = pair().await;
+        // 2 + 2 * (2 + 24) = 54
+        let mut a = a.with_coalescing(Duration::from_secs(60), 54);
+
+        a.send(&frame(0)).await.unwrap();
+        a.send(&frame(1)).await.unwrap();
+
+        let dgram = recv_datagram(&rx).await;
+        assert_eq!(dgram.len(), 54);
+        assert_eq!(unpack(&dgram).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn empty_flush_sends_nothing() {
+        let (a, rx) = pair().await;
+        let mut a = a.with_coalescing(Duration::from_millis(1), 1200);
+        a.flush().await.unwrap();
+        a.send(&frame(9)).await.unwrap();
+        a.flush().await.unwrap();
+
+        // the only datagram is the single frame, sent plain
+        assert_eq!(recv_datagram(&rx).await, frame(9).encode());
+    }
+}
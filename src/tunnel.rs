@@ -0,0 +1,324 @@
+//! Carry a plain byte stream over frames, for code that only knows
+//! [`io::Write`] and [`io::Read`].
+//!
+//! [`FrameWriter`] cuts written bytes into frame bodies; [`FrameReader`]
+//! joins the bodies back up. Both are blocking and take closures for the
+//! frame I/O, so they can sit on a channel, a transport driven with
+//! `block_on`, or a file.
+
+use std::io::{self, Read, Write};
+
+use crate::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+pub const DEFAULT_MAX_BODY: usize = 1024;
+
+type SendFrame = Box<dyn FnMut(FrameV1) -> io::Result<()> + Send>;
+type NextCounter = Box<dyn FnMut() -> u64 + Send>;
+type RecvFrame = Box<dyn FnMut() -> io::Result<Option<FrameV1>> + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Only emit full frames; the rest waits for [`Write::flush`].
+    WhenFull,
+    /// Emit whatever is buffered at the end of every `write`.
+    EveryWrite,
+}
+
+/// Buffers written bytes and sends them as frames of at most `max_body`
+/// bytes. Dropping the writer flushes it, ignoring errors.
+///
+/// A body whose send failed stays buffered and is sent first next time.
+/// `write` only fails if it accepted none of its input, so `write_all`
+/// neither sends bytes twice nor loses them.
+pub struct FrameWriter {
+    device_id: [u8; 8],
+    msg_type: MsgType,
+    max_body: usize,
+    policy: FlushPolicy,
+    buf: Vec<u8>,
+    send: SendFrame,
+    next_counter: NextCounter,
+}
+
+impl FrameWriter {
+    /// Event frames from `device_id`, numbered by `next_counter` and handed
+    /// to `send`.
+    pub fn new(
+        device_id: [u8; 8],
+        send: impl FnMut(FrameV1) -> io::Result<()> + Send + 'static,
+        next_counter: impl FnMut() -> u64 + Send + 'static,
+    ) -> Self {
+        FrameWriter {
+            device_id,
+            msg_type: MsgType::Event,
+            max_body: DEFAULT_MAX_BODY,
+            policy: FlushPolicy::WhenFull,
+            buf: Vec::new(),
+            send: Box::new(send),
+            next_counter: Box::new(next_counter),
+        }
+    }
+
+    pub fn with_msg_type(mut self, msg_type: MsgType) -> Self {
+        self.msg_type = msg_type;
+        self
+    }
+
+    /// Largest body per frame (at least 1).
+    pub fn with_max_body(mut self, max: usize) -> Self {
+        self.max_body = max.max(1);
+        self
+    }
+
+    pub fn with_flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Bytes written but not yet sent.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Send the buffer as one frame, keeping it if that fails.
+    fn send_buffered(&mut self) -> io::Result<()> {
+        let frame = FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: self.msg_type,
+                flags: Flags::new(0).unwrap(),
+                device_id: self.device_id,
+                counter: (self.next_counter)(),
+            },
+            body: self.buf[..].into(),
+        };
+        (self.send)(frame)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl Write for FrameWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut consumed = 0;
+        loop {
+            if self.buf.len() == self.max_body
+                && let Err(e) = self.send_buffered()
+            {
+                return if consumed == 0 { Err(e) } else { Ok(consumed) };
+            }
+            if consumed == data.len() {
+                break;
+            }
+            let take = (self.max_body - self.buf.len()).min(data.len() - consumed);
+            self.buf.extend_from_slice(&data[consumed..consumed + take]);
+            consumed += take;
+        }
+        if self.policy == FlushPolicy::EveryWrite
+            && let Err(e) = self.flush()
+            && consumed == 0
+        {
+            return Err(e);
+        }
+        Ok(consumed)
+    }
+
+    /// Sends any partial buffer as a final, shorter frame.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.send_buffered()
+    }
+}
+
+impl Drop for FrameWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Reads the bodies of frames of one type back as a byte stream. Frames
+/// of other types are skipped; the stream ends when `recv` returns `None`.
+pub struct FrameReader {
+    msg_type: MsgType,
+    recv: RecvFrame,
+    body: Vec<u8>,
+    pos: usize,
+}
+
+impl FrameReader {
+    pub fn new(recv: impl FnMut() -> io::Result<Option<FrameV1>> + Send + 'static) -> Self {
+        FrameReader {
+            msg_type: MsgType::Event,
+            recv: Box::new(recv),
+            body: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn with_msg_type(mut self, msg_type: MsgType) -> Self {
+        self.msg_type = msg_type;
+        self
+    }
+}
+
+impl Read for FrameReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.body.len() {
+            match (self.recv)()? {
+                None => return Ok(0),
+                Some(f) if f.header.msg_type == self.msg_type => {
+                    self.body = f.body.into_vec();
+                    self.pos = 0;
+                }
+                Some(_) => {}
+            }
+        }
+        let n = out.len().min(self.body.len() - self.pos);
+        out[..n].copy_from_slice(&self.body[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{Transport, TransportError, loopback_pair};
+    use std::sync::{Arc, Mutex};
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+    }
+
+    /// xorshift64, enough to make incompressible test data.
+    fn random_bytes(n: usize) -> Vec<u8> {
+        let mut x = 0x9e37_79b9_7f4a_7c15u64;
+        (0..n)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tunnels_megabytes_over_loopback() {
+        let data = random_bytes(3 * 1024 * 1024 + 123);
+        let (mut a, mut b) = loopback_pair();
+
+        let sent = data.clone();
+        let writer = std::thread::spawn(move || {
+            let rt = runtime();
+            let mut counter = 0;
+            let mut w = FrameWriter::new(
+                *b"DEV00001",
+                move |f| rt.block_on(a.send(&f)).map_err(io::Error::other),
+                move || {
+                    counter += 1;
+                    counter
+                },
+            )
+            .with_max_body(4000);
+            for chunk in sent.chunks(777) {
+                w.write_all(chunk).unwrap();
+            }
+        });
+
+        let rt = runtime();
+        let mut last = 0;
+        let mut r = FrameReader::new(move || match rt.block_on(b.recv()) {
+            Ok(f) => {
+                assert_eq!(f.header.counter, last + 1);
+                last = f.header.counter;
+                Ok(Some(f))
+            }
+            Err(TransportError::Disconnected) => Ok(None),
+            Err(e) => Err(io::Error::other(e)),
+        });
+        let mut received = Vec::new();
+        r.read_to_end(&mut received).unwrap();
+        writer.join().unwrap();
+        assert!(received == data, "tunnelled bytes differ");
+    }
+
+    #[test]
+    fn flush_emits_partial_frame() {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let out = frames.clone();
+        let mut w = FrameWriter::new(
+            *b"DEV00001",
+            move |f| {
+                out.lock().unwrap().push(f);
+                Ok(())
+            },
+            || 7,
+        )
+        .with_max_body(4)
+        .with_msg_type(MsgType::Command);
+
+        w.write_all(b"abcdef").unwrap();
+        assert_eq!(w.buffered(), 2);
+        w.flush().unwrap();
+        w.write_all(b"g").unwrap();
+        drop(w);
+
+        let frames = frames.lock().unwrap();
+        let bodies: Vec<_> = frames.iter().map(|f| f.body.as_slice()).collect();
+        assert_eq!(bodies, [&b"abcd"[..], b"ef", b"g"]);
+        assert!(frames.iter().all(|f| f.header.msg_type == MsgType::Command));
+        assert!(frames.iter().all(|f| f.header.counter == 7));
+    }
+
+    #[test]
+    fn failed_sends_neither_lose_nor_repeat_bytes() {
+        let data = random_bytes(100);
+        for policy in [FlushPolicy::WhenFull, FlushPolicy::EveryWrite] {
+            let bodies = Arc::new(Mutex::new(Vec::new()));
+            let out = bodies.clone();
+            let mut sends = 0;
+            let mut w = FrameWriter::new(
+                [0; 8],
+                move |f: FrameV1| {
+                    sends += 1;
+                    if sends == 2 {
+                        return Err(io::Error::other("link down"));
+                    }
+                    out.lock().unwrap().push(f.body.into_vec());
+                    Ok(())
+                },
+                || 0,
+            )
+            .with_max_body(16)
+            .with_flush_policy(policy);
+            w.write_all(&data[..70]).unwrap();
+            w.write_all(&data[70..]).unwrap();
+            w.flush().unwrap();
+            drop(w);
+            assert_eq!(bodies.lock().unwrap().concat(), data, "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn every_write_policy_sends_each_write() {
+        let count = Arc::new(Mutex::new(0));
+        let c = count.clone();
+        let mut w = FrameWriter::new(
+            [0; 8],
+            move |_| {
+                *c.lock().unwrap() += 1;
+                Ok(())
+            },
+            || 0,
+        )
+        .with_flush_policy(FlushPolicy::EveryWrite);
+        w.write_all(b"a").unwrap();
+        w.write_all(b"b").unwrap();
+        assert_eq!(*count.lock().unwrap(), 2);
+    }
+}
@@ -0,0 +1,162 @@
+//! Writing a frame as a few separate buffers: a header, the body as it
+//! is, and room for a trailer, without copying them together first.
+
+use std::io::{self, IoSlice, Write};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Write all of `parts`, in order, as vectored writes. A writer without
+/// vectored writes takes them one at a time, as the default
+/// `write_vectored` writes only the first buffer that isn't empty.
+pub(crate) fn write_all<const N: usize>(out: &mut impl Write, parts: [&[u8]; N]) -> io::Result<()> {
+    let mut bufs = parts.map(IoSlice::new);
+    let mut bufs = &mut bufs[..];
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match out.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// As [`write_all`], for an async writer; one that says it has no
+/// vectored writes is given each part with `write_all`.
+pub(crate) async fn write_all_async<const N: usize>(
+    out: &mut (impl AsyncWrite + Unpin),
+    parts: [&[u8]; N],
+) -> io::Result<()> {
+    if !out.is_write_vectored() {
+        for part in parts {
+            out.write_all(part).await?;
+        }
+        return Ok(());
+    }
+    let mut bufs = parts.map(IoSlice::new);
+    let mut bufs = &mut bufs[..];
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match out.write_vectored(bufs).await {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Takes at most 5 bytes per write, and of one buffer at a time unless
+    /// `vectored`, so writes are resumed mid-buffer.
+    struct Trickle {
+        out: Vec<u8>,
+        vectored: bool,
+        calls: usize,
+    }
+
+    impl Trickle {
+        fn new(vectored: bool) -> Self {
+            Trickle {
+                out: Vec::new(),
+                vectored,
+                calls: 0,
+            }
+        }
+
+        fn take(&mut self, bufs: &[IoSlice<'_>]) -> usize {
+            self.calls += 1;
+            let mut n = 0;
+            for buf in bufs {
+                let k = buf.len().min(5 - n);
+                self.out.extend_from_slice(&buf[..k]);
+                n += k;
+                if n == 5 || !self.vectored {
+                    break;
+                }
+            }
+            n
+        }
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(self.take(&[IoSlice::new(buf)]))
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            Ok(self.take(bufs))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(self.get_mut().take(&[IoSlice::new(buf)])))
+        }
+
+        fn poll_write_vectored(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(self.get_mut().take(bufs)))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            self.vectored
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    const PARTS: [&[u8]; 3] = [b"header", b"", b"the body, then a"];
+
+    #[test]
+    fn writes_every_part_in_order() {
+        for vectored in [true, false] {
+            let mut out = Trickle::new(vectored);
+            write_all(&mut out, PARTS).unwrap();
+            assert_eq!(out.out, PARTS.concat());
+        }
+        let mut out = Trickle::new(true);
+        write_all(&mut out, [&b""[..], b""]).unwrap();
+        assert_eq!(out.calls, 0);
+        let mut out = [0u8; 8];
+        let err = write_all(&mut &mut out[..], PARTS).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[tokio::test]
+    async fn async_writes_every_part_in_order() {
+        for vectored in [true, false] {
+            let mut out = Trickle::new(vectored);
+            write_all_async(&mut out, PARTS).await.unwrap();
+            assert_eq!(out.out, PARTS.concat());
+        }
+        let mut out = Vec::new();
+        write_all_async(&mut out, PARTS).await.unwrap();
+        assert_eq!(out, PARTS.concat());
+    }
+}
@@ -0,0 +1,475 @@
+//! Test vectors: the wire format as data, for other implementations.
+//!
+//! [`generate`] lists named cases, always the same ones in the same
+//! order, and [`to_json`] writes them as the file committed at
+//! `docs/test-vectors-v1.json`. Each valid case gives the header fields
+//! and body of a frame with its encoding; each invalid case gives input
+//! bytes with the error category ([`DecodeError::kind`]) and offset
+//! ([`DecodeError::offset`]) a decoder must report. Between them they
+//! cover every message type and flag, the boundaries of every field and
+//! every error a decoder can reach, including which wins when input has
+//! more than one fault. `bad_device_id_bytes` and `bad_counter_bytes`
+//! have no cases: once the length is checked no input can produce them.
+//!
+//! The file is
+//!
+//! ```text
+//! {"format":"pipproto-test-vectors","version":1,"protocol_version":1,
+//!  "valid":[{"name":..,"msg_type":"event","flags":1,"device_id":HEX,
+//!            "counter":N,"body":HEX,"encoded":HEX},..],
+//!  "invalid":[{"name":..,"input":HEX,"error":KIND,"offset":N|null},..]}
+//! ```
+//!
+//! with lowercase hex and one case per line. [`VECTORS_VERSION`] changes
+//! only when existing cases would change meaning; adding cases does not.
+//! [`check`] runs one case against this crate, and with the `serde`
+//! feature [`parse`] reads a file back.
+
+use std::fmt::Write as _;
+
+use crate::{DecodeError, Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+/// The `version` of the file [`to_json`] writes.
+pub const VECTORS_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expect {
+    /// The bytes are the encoding of this frame.
+    Frame(FrameV1),
+    /// The bytes fail to decode, with this [`DecodeError::kind`] and
+    /// [`DecodeError::offset`].
+    Error { kind: String, offset: Option<usize> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vector {
+    pub name: String,
+    pub bytes: Vec<u8>,
+    pub expect: Expect,
+}
+
+const DEVICE: [u8; 8] = *b"DEV00001";
+
+pub(crate) fn frame(
+    msg_type: MsgType,
+    flags: u8,
+    device_id: [u8; 8],
+    counter: u64,
+    body: &[u8],
+) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type,
+            flags: Flags::new(flags).unwrap(),
+            device_id,
+            counter,
+        },
+        body: body.into(),
+    }
+}
+
+fn valid(name: &str, frame: FrameV1) -> Vector {
+    Vector {
+        name: name.to_string(),
+        bytes: frame.encode(),
+        expect: Expect::Frame(frame),
+    }
+}
+
+fn invalid(name: &str, bytes: Vec<u8>, error: DecodeError) -> Vector {
+    Vector {
+        name: name.to_string(),
+        bytes,
+        expect: Expect::Error {
+            kind: error.kind().to_string(),
+            offset: error.offset(),
+        },
+    }
+}
+
+/// Every case, in file order.
+pub fn generate() -> Vec<Vector> {
+    use MsgType::*;
+    let mut out = Vec::new();
+    for t in [Event, Command, Ack, Error] {
+        let name = format!("type_{}", t.as_str());
+        out.push(valid(&name, frame(t, 0, DEVICE, 1, b"")));
+    }
+    out.push(valid("flags_none", frame(Event, 0, DEVICE, 1, b"")));
+    out.push(valid(
+        "flags_ack_required",
+        frame(Event, Flags::ACK_REQUIRED, DEVICE, 1, b""),
+    ));
+    for (name, id) in [
+        ("device_id_zero", [0; 8]),
+        ("device_id_ones", [0xff; 8]),
+        ("device_id_ascii", DEVICE),
+        ("device_id_byte_order", [1, 2, 3, 4, 5, 6, 7, 8]),
+    ] {
+        out.push(valid(name, frame(Event, 0, id, 1, b"")));
+    }
+    for (name, counter) in [
+        ("counter_zero", 0),
+        ("counter_one", 1),
+        ("counter_u8_max", 0xff),
+        ("counter_u8_max_plus_one", 0x100),
+        ("counter_u32_max", u32::MAX as u64),
+        ("counter_u32_max_plus_one", u32::MAX as u64 + 1),
+        ("counter_i64_max", i64::MAX as u64),
+        ("counter_i64_max_plus_one", i64::MAX as u64 + 1),
+        ("counter_u64_max", u64::MAX),
+        ("counter_byte_order", 0x0102_0304_0506_0708),
+    ] {
+        out.push(valid(name, frame(Event, 0, DEVICE, counter, b"")));
+    }
+    let every_byte: Vec<u8> = (0..=255).collect();
+    let header_like = frame(Event, 0, DEVICE, 2, b"").encode();
+    for (name, body) in [
+        ("body_empty", &b""[..]),
+        ("body_one_zero_byte", &[0x00]),
+        ("body_one_ff_byte", &[0xff]),
+        ("body_every_byte_value", &every_byte),
+        ("body_that_looks_like_a_header", &header_like),
+    ] {
+        out.push(valid(name, frame(Event, 0, DEVICE, 1, body)));
+    }
+    out.push(valid(
+        "body_4096_bytes",
+        frame(Command, Flags::ACK_REQUIRED, DEVICE, 9, &[0x5a; 4096]),
+    ));
+
+    let good = frame(Event, 0, DEVICE, 1, b"hi").encode();
+    let with = |at: usize, byte: u8| {
+        let mut bytes = good.clone();
+        bytes[at] = byte;
+        bytes
+    };
+    for len in [0, 1, 2, 5, 13, 20] {
+        let name = format!("too_short_{len}_bytes");
+        out.push(invalid(&name, good[..len].to_vec(), DecodeError::TooShort));
+    }
+    out.push(invalid(
+        "bad_magic_first_byte",
+        with(0, b'Q'),
+        DecodeError::BadMagic,
+    ));
+    out.push(invalid(
+        "bad_magic_second_byte",
+        with(1, b'Q'),
+        DecodeError::BadMagic,
+    ));
+    out.push(invalid(
+        "bad_magic_lowercase",
+        [&b"pp"[..], &good[2..]].concat(),
+        DecodeError::BadMagic,
+    ));
+    for v in [0x00, 0x02, 0xff] {
+        let name = format!("bad_version_{v:02x}");
+        out.push(invalid(&name, with(2, v), DecodeError::BadVersion(v)));
+    }
+    for t in [0x00, 0x05, 0x80, 0xff] {
+        let name = format!("unknown_msg_type_{t:02x}");
+        out.push(invalid(&name, with(3, t), DecodeError::UnknownMsgType(t)));
+    }
+    for bit in 1..8 {
+        let flags = 1 << bit;
+        let name = format!("reserved_flag_bit_{bit}");
+        out.push(invalid(
+            &name,
+            with(4, flags),
+            DecodeError::ReservedFlags(flags),
+        ));
+    }
+    out.push(invalid(
+        "reserved_flags_all",
+        with(4, 0xff),
+        DecodeError::ReservedFlags(0xff),
+    ));
+    // checked in header order, the length first
+    let mut all_wrong = with(0, b'Q');
+    all_wrong[2..5].copy_from_slice(&[0x02, 0x09, 0x80]);
+    out.push(invalid(
+        "first_fault_length",
+        all_wrong[..20].to_vec(),
+        DecodeError::TooShort,
+    ));
+    out.push(invalid(
+        "first_fault_magic",
+        all_wrong.clone(),
+        DecodeError::BadMagic,
+    ));
+    all_wrong[..2].copy_from_slice(b"PP");
+    out.push(invalid(
+        "first_fault_version",
+        all_wrong.clone(),
+        DecodeError::BadVersion(0x02),
+    ));
+    all_wrong[2] = VERSION_V1;
+    out.push(invalid(
+        "first_fault_msg_type",
+        all_wrong.clone(),
+        DecodeError::UnknownMsgType(0x09),
+    ));
+    all_wrong[3] = Event as u8;
+    out.push(invalid(
+        "first_fault_flags",
+        all_wrong,
+        DecodeError::ReservedFlags(0x80),
+    ));
+    out
+}
+
+/// The vectors file, as committed.
+pub fn to_json(vectors: &[Vector]) -> String {
+    let hex = crate::hex::encode;
+    let mut out = format!(
+        "{{\"format\":\"pipproto-test-vectors\",\"version\":{VECTORS_VERSION},\"protocol_version\":{VERSION_V1},\n"
+    );
+    let (frames, errors): (Vec<_>, Vec<_>) = vectors
+        .iter()
+        .partition(|v| matches!(v.expect, Expect::Frame(_)));
+    out.push_str("\"valid\":[\n");
+    for (i, v) in frames.iter().enumerate() {
+        let Expect::Frame(f) = &v.expect else {
+            unreachable!()
+        };
+        let h = &f.header;
+        let _ = write!(
+            out,
+            "{{\"name\":\"{}\",\"msg_type\":\"{}\",\"flags\":{},\"device_id\":\"{}\",\"counter\":{},\"body\":\"{}\",\"encoded\":\"{}\"}}",
+            v.name,
+            h.msg_type.as_str(),
+            h.flags.bits(),
+            hex(&h.device_id),
+            h.counter,
+            hex(&f.body),
+            hex(&v.bytes)
+        );
+        out.push_str(if i + 1 < frames.len() { ",\n" } else { "\n" });
+    }
+    out.push_str("],\n\"invalid\":[\n");
+    for (i, v) in errors.iter().enumerate() {
+        let Expect::Error { kind, offset } = &v.expect else {
+            unreachable!()
+        };
+        let offset = offset.map_or("null".to_string(), |o| o.to_string());
+        let _ = write!(
+            out,
+            "{{\"name\":\"{}\",\"input\":\"{}\",\"error\":\"{kind}\",\"offset\":{offset}}}",
+            v.name,
+            hex(&v.bytes)
+        );
+        out.push_str(if i + 1 < errors.len() { ",\n" } else { "\n" });
+    }
+    out.push_str("]}\n");
+    out
+}
+
+/// Whether this crate encodes and decodes `vector` as it says; the
+/// complaint if not.
+pub fn check(vector: &Vector) -> Result<(), String> {
+    let decoded = FrameV1::decode(&vector.bytes);
+    match (&vector.expect, decoded) {
+        (Expect::Frame(frame), Ok(decoded)) => {
+            if decoded != *frame {
+                return Err(format!("decodes to {decoded}, not {frame}"));
+            }
+            if frame.encode() != vector.bytes {
+                return Err(format!(
+                    "encodes to {}",
+                    crate::hex::encode(&frame.encode())
+                ));
+            }
+            Ok(())
+        }
+        (Expect::Frame(_), Err(e)) => Err(format!("does not decode: {e}")),
+        (Expect::Error { kind, offset }, Err(e)) => {
+            if e.kind() != kind || e.offset() != *offset {
+                return Err(format!(
+                    "fails with {} at {:?}, not {kind} at {offset:?}",
+                    e.kind(),
+                    e.offset()
+                ));
+            }
+            Ok(())
+        }
+        (Expect::Error { kind, .. }, Ok(frame)) => {
+            Err(format!("decodes to {frame}, not failing with {kind}"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VectorError {
+    Json(String),
+    /// The file's `format` or `version` is not one this crate reads.
+    Unsupported(String),
+    /// A case that is missing a field or has a bad one.
+    BadCase {
+        name: String,
+        field: &'static str,
+    },
+}
+
+impl std::fmt::Display for VectorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VectorError::Json(e) => write!(f, "not JSON: {e}"),
+            VectorError::Unsupported(what) => write!(f, "unsupported vectors file: {what}"),
+            VectorError::BadCase { name, field } => write!(f, "case {name:?}: bad {field}"),
+        }
+    }
+}
+
+impl std::error::Error for VectorError {}
+
+/// Read a vectors file of any [`VECTORS_VERSION`] up to this one.
+#[cfg(feature = "serde")]
+pub fn parse(json: &str) -> Result<Vec<Vector>, VectorError> {
+    use serde_json::Value;
+
+    let file: Value = serde_json::from_str(json).map_err(|e| VectorError::Json(e.to_string()))?;
+    if file["format"] != "pipproto-test-vectors" {
+        return Err(VectorError::Unsupported(format!(
+            "format {}",
+            file["format"]
+        )));
+    }
+    match file["version"].as_u64() {
+        Some(v) if (1..=VECTORS_VERSION as u64).contains(&v) => {}
+        _ => {
+            return Err(VectorError::Unsupported(format!(
+                "version {}",
+                file["version"]
+            )));
+        }
+    }
+    let cases = |key: &str| file[key].as_array().cloned().unwrap_or_default();
+    let mut vectors = Vec::new();
+    for case in cases("valid").iter().chain(&cases("invalid")) {
+        let name = case["name"].as_str().unwrap_or_default().to_string();
+        let bad = |field| VectorError::BadCase {
+            name: name.clone(),
+            field,
+        };
+        let hex = |field: &'static str| {
+            case[field]
+                .as_str()
+                .and_then(crate::hex::decode)
+                .ok_or_else(|| bad(field))
+        };
+        let vector = if case.get("encoded").is_some() {
+            let msg_type = [
+                MsgType::Event,
+                MsgType::Command,
+                MsgType::Ack,
+                MsgType::Error,
+            ]
+            .into_iter()
+            .find(|t| case["msg_type"] == t.as_str())
+            .ok_or_else(|| bad("msg_type"))?;
+            let flags = case["flags"]
+                .as_u64()
+                .and_then(|f| u8::try_from(f).ok())
+                .and_then(|f| Flags::new(f).ok())
+                .ok_or_else(|| bad("flags"))?;
+            let device_id = hex("device_id")?.try_into().map_err(|_| bad("device_id"))?;
+            let counter = case["counter"].as_u64().ok_or_else(|| bad("counter"))?;
+            let header = FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type,
+                flags,
+                device_id,
+                counter,
+            };
+            Vector {
+                bytes: hex("encoded")?,
+                expect: Expect::Frame(FrameV1 {
+                    header,
+                    body: hex("body")?.into(),
+                }),
+                name,
+            }
+        } else {
+            let kind = case["error"].as_str().ok_or_else(|| bad("error"))?;
+            let offset = match &case["offset"] {
+                Value::Null => None,
+                o => Some(o.as_u64().ok_or_else(|| bad("offset"))? as usize),
+            };
+            Vector {
+                bytes: hex("input")?,
+                expect: Expect::Error {
+                    kind: kind.to_string(),
+                    offset,
+                },
+                name,
+            }
+        };
+        vectors.push(vector);
+    }
+    Ok(vectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_unique_and_every_reachable_error_is_covered() {
+        let vectors = generate();
+        let mut names: Vec<_> = vectors.iter().map(|v| v.name.as_str()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), vectors.len());
+        let kinds: std::collections::BTreeSet<_> = vectors
+            .iter()
+            .filter_map(|v| match &v.expect {
+                Expect::Error { kind, .. } => Some(kind.as_str()),
+                Expect::Frame(_) => None,
+            })
+            .collect();
+        assert_eq!(
+            kinds.into_iter().collect::<Vec<_>>(),
+            [
+                "bad_magic",
+                "bad_version",
+                "reserved_flags",
+                "too_short",
+                "unknown_msg_type"
+            ]
+        );
+    }
+
+    #[test]
+    fn check_catches_a_wrong_case() {
+        let mut vectors = generate();
+        assert!(vectors.iter().all(|v| check(v).is_ok()));
+        vectors[0].bytes[5] ^= 1;
+        assert!(
+            check(&vectors[0])
+                .unwrap_err()
+                .starts_with("decodes to event dev=4545")
+        );
+        let last = vectors.last_mut().unwrap();
+        last.expect = Expect::Error {
+            kind: "reserved_flags".into(),
+            offset: Some(3),
+        };
+        assert_eq!(
+            check(last).unwrap_err(),
+            "fails with reserved_flags at Some(4), not reserved_flags at Some(3)"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn the_file_reads_back() {
+        let vectors = generate();
+        assert_eq!(parse(&to_json(&vectors)).unwrap(), vectors);
+        assert!(matches!(
+            parse(r#"{"format":"pipproto-test-vectors","version":99}"#),
+            Err(VectorError::Unsupported(_))
+        ));
+    }
+}
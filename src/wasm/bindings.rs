@@ -0,0 +1,42 @@
+//! The wasm-bindgen glue: JSON values in and out of JS objects, and
+//! [`WasmError`]s thrown as `Error`s.
+
+use js_sys::{Error, JSON, Reflect};
+use wasm_bindgen::prelude::*;
+
+use super::{WasmError, annotate_json, decode_json, encode_json};
+
+fn to_js(value: serde_json::Value) -> JsValue {
+    JSON::parse(&value.to_string()).expect("serde_json writes valid JSON")
+}
+
+fn throw(e: WasmError) -> JsValue {
+    let error = Error::new(&e.message);
+    let offset = e.offset.map_or(JsValue::NULL, |o| JsValue::from(o as f64));
+    let _ = Reflect::set(&error, &"kind".into(), &e.kind.into());
+    let _ = Reflect::set(&error, &"offset".into(), &offset);
+    error.into()
+}
+
+/// Decode a frame into an object, or throw its [`DecodeError`](crate::DecodeError).
+#[wasm_bindgen(js_name = decodeFrame)]
+pub fn decode_frame(bytes: &[u8]) -> Result<JsValue, JsValue> {
+    decode_json(bytes).map(to_js).map_err(throw)
+}
+
+/// Encode a frame object, as [`decode_frame`] returns them.
+#[wasm_bindgen(js_name = encodeFrame)]
+pub fn encode_frame(frame: JsValue) -> Result<Vec<u8>, JsValue> {
+    let json = JSON::stringify(&frame)
+        .ok()
+        .and_then(|s| s.as_string())
+        .ok_or_else(|| throw(WasmError::invalid("expected a frame object")))?;
+    let value = serde_json::from_str(&json).map_err(|e| throw(WasmError::invalid(e)))?;
+    encode_json(value).map_err(throw)
+}
+
+/// The fields `bytes` holds, as `{start, end, name, value, ok}` objects.
+#[wasm_bindgen]
+pub fn annotate(bytes: &[u8]) -> JsValue {
+    to_js(annotate_json(bytes))
+}
@@ -0,0 +1,268 @@
+//! WebAssembly bindings (feature `wasm`), for tools in the browser.
+//!
+//! On a wasm target, `decodeFrame(Uint8Array)` returns, and
+//! `encodeFrame(object)` takes, an object such as:
+//!
+//! ```json
+//! {"version":1,"msgType":"event","flags":{"ackRequired":true},
+//!  "deviceId":"deadbeef00000001","counter":"7","body":"6869"}
+//! ```
+//!
+//! `deviceId` and `body` are hex. `counter` is a decimal string, as a
+//! u64 doesn't fit a JS number; `encodeFrame` takes a number as well.
+//! `annotate(Uint8Array)` returns the
+//! [field spans](crate::annotate::annotate) as
+//! `{start, end, name, value, ok}` objects. Errors are thrown as `Error`s
+//! with the `kind` and `offset` of a [`WasmError`].
+//!
+//! The JS glue is in `bindings`, built only for wasm; what it converts
+//! is [`decode_json`], [`encode_json`] and [`annotate_json`], which build
+//! everywhere.
+
+#[cfg(target_family = "wasm")]
+mod bindings;
+
+#[cfg(target_family = "wasm")]
+pub use bindings::{annotate, decode_frame, encode_frame};
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{DecodeError, Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+/// Why a call failed: a [`DecodeError`], with its kind and offset, or an
+/// object `encodeFrame` can't make a frame of (kind `invalid_frame`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WasmError {
+    pub kind: &'static str,
+    pub offset: Option<usize>,
+    pub message: String,
+}
+
+impl WasmError {
+    fn invalid(message: impl fmt::Display) -> Self {
+        WasmError {
+            kind: "invalid_frame",
+            offset: None,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<DecodeError> for WasmError {
+    fn from(e: DecodeError) -> Self {
+        WasmError {
+            kind: e.kind(),
+            offset: e.offset(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for WasmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for WasmError {}
+
+fn default_version() -> u8 {
+    VERSION_V1
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct FlagsObject {
+    #[serde(default)]
+    ack_required: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Counter {
+    Number(u64),
+    String(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct FrameObject {
+    #[serde(default = "default_version")]
+    version: u8,
+    msg_type: MsgType,
+    #[serde(default)]
+    flags: FlagsObject,
+    device_id: String,
+    counter: Counter,
+    #[serde(default)]
+    body: String,
+}
+
+/// `decodeFrame`'s object for `input`.
+pub fn decode_json(input: &[u8]) -> Result<Value, WasmError> {
+    let frame = FrameV1::decode(input)?;
+    let object = FrameObject {
+        version: frame.header.version,
+        msg_type: frame.header.msg_type,
+        flags: FlagsObject {
+            ack_required: frame.header.flags.ack_required(),
+        },
+        device_id: frame.header.device_id_hex(),
+        counter: Counter::String(frame.header.counter.to_string()),
+        body: crate::hex::encode(&frame.body),
+    };
+    Ok(serde_json::to_value(object).unwrap())
+}
+
+/// The frame `encodeFrame` encodes for `object`.
+pub fn encode_json(object: Value) -> Result<Vec<u8>, WasmError> {
+    let object: FrameObject = serde_json::from_value(object).map_err(WasmError::invalid)?;
+    if object.version != VERSION_V1 {
+        return Err(WasmError::invalid(format_args!(
+            "unsupported version: {}",
+            object.version
+        )));
+    }
+    let device_id = crate::hex::decode(&object.device_id)
+        .and_then(|v| <[u8; 8]>::try_from(v).ok())
+        .ok_or_else(|| WasmError::invalid("deviceId must be 16 hex digits"))?;
+    let counter = match object.counter {
+        Counter::Number(n) => n,
+        Counter::String(s) => s
+            .parse()
+            .map_err(|_| WasmError::invalid("counter must be a u64"))?,
+    };
+    let body =
+        crate::hex::decode(&object.body).ok_or_else(|| WasmError::invalid("body is not hex"))?;
+    let flags = if object.flags.ack_required {
+        Flags::ACK_REQUIRED
+    } else {
+        0
+    };
+    let frame: FrameV1 = FrameV1 {
+        header: FrameHeaderV1 {
+            version: object.version,
+            msg_type: object.msg_type,
+            flags: Flags::new(flags).unwrap(),
+            device_id,
+            counter,
+        },
+        body: body.into(),
+    };
+    Ok(frame.encode())
+}
+
+/// `annotate`'s spans for `input`.
+pub fn annotate_json(input: &[u8]) -> Value {
+    crate::annotate::annotate(input)
+        .into_iter()
+        .map(|span| {
+            serde_json::json!({
+                "start": span.range.start,
+                "end": span.range.end,
+                "name": span.name,
+                "value": span.value,
+                "ok": span.ok,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn sample() -> FrameV1 {
+        FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: MsgType::Event,
+                flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+                device_id: [0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 1],
+                counter: u64::MAX,
+            },
+            body: b"hi".into(),
+        }
+    }
+
+    #[test]
+    fn frames_round_trip_through_objects() {
+        let bytes = sample().encode();
+        let object = decode_json(&bytes).unwrap();
+        assert_eq!(
+            object,
+            json!({
+                "version": 1,
+                "msgType": "event",
+                "flags": {"ackRequired": true},
+                "deviceId": "deadbeef00000001",
+                "counter": "18446744073709551615",
+                "body": "6869",
+            })
+        );
+        assert_eq!(encode_json(object).unwrap(), bytes);
+
+        // the defaults, and a counter as a number
+        let bytes = encode_json(json!({
+            "msgType": "ack",
+            "deviceId": "0000000000000002",
+            "counter": 5,
+        }))
+        .unwrap();
+        let frame = FrameV1::decode(&bytes).unwrap();
+        assert_eq!(frame.header.msg_type, MsgType::Ack);
+        assert!(!frame.header.flags.ack_required());
+        assert_eq!(frame.header.counter, 5);
+        assert!(frame.body.is_empty());
+    }
+
+    #[test]
+    fn errors_carry_kind_and_offset() {
+        let mut bytes = sample().encode();
+        bytes[3] = 0x7f;
+        let e = decode_json(&bytes).unwrap_err();
+        assert_eq!((e.kind, e.offset), ("unknown_msg_type", Some(3)));
+        assert_eq!(e.message, "unknown msg_type: 0x7f");
+        let e = decode_json(&bytes[..4]).unwrap_err();
+        assert_eq!((e.kind, e.offset), ("too_short", None));
+
+        let good = decode_json(&sample().encode()).unwrap();
+        for (key, value, message) in [
+            ("version", json!(2), "unsupported version: 2"),
+            ("deviceId", json!("dead"), "deviceId must be 16 hex digits"),
+            ("counter", json!("-1"), "counter must be a u64"),
+            ("body", json!("xyz"), "body is not hex"),
+        ] {
+            let mut object = good.clone();
+            object[key] = value;
+            let e = encode_json(object).unwrap_err();
+            assert_eq!((e.kind, e.offset), ("invalid_frame", None));
+            assert_eq!(e.message, message);
+        }
+        let mut object = good.clone();
+        object["extra"] = json!(1);
+        assert_eq!(encode_json(object).unwrap_err().kind, "invalid_frame");
+        let e = encode_json(json!({"msgType": "nope"})).unwrap_err();
+        assert_eq!(e.kind, "invalid_frame");
+    }
+
+    #[test]
+    fn spans_are_objects() {
+        let spans = annotate_json(&sample().encode()[..18]);
+        let spans = spans.as_array().unwrap();
+        assert_eq!(spans.len(), 6);
+        assert_eq!(
+            spans[0],
+            json!({"start": 0, "end": 2, "name": "magic", "value": "PP", "ok": true})
+        );
+        assert_eq!(
+            spans[5],
+            json!({"start": 13, "end": 18, "name": "counter", "value": "truncated", "ok": false})
+        );
+    }
+}
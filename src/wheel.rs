@@ -0,0 +1,312 @@
+//! A hierarchical timer wheel.
+//!
+//! [`TimerWheel`] keeps deadlines for many keys at once: inserting and
+//! cancelling a timer take constant time, and [`TimerWheel::tick`] only
+//! touches the timers that fall due and the slots passed on the way. Time
+//! is cut into ticks of `resolution` from an origin; [`LEVELS`] levels of
+//! [`SLOTS`] slots each cover `SLOTS ^ LEVELS` ticks ahead, and timers
+//! further out wait in an overflow list until they come into range.
+//!
+//! Timers fire at their deadline, not rounded to a tick: a tick call
+//! returns exactly the timers whose deadline is at or before its `now`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Slots per level.
+pub const SLOTS: usize = 64;
+/// Levels; with 1 ms ticks the wheel covers about 4.6 hours.
+pub const LEVELS: usize = 4;
+
+const BITS: u32 = SLOTS.trailing_zeros();
+/// Index of the overflow list among the buckets.
+const OVERFLOW: usize = LEVELS * SLOTS;
+
+#[derive(Debug)]
+struct Timer {
+    at: Instant,
+    bucket: usize,
+    /// Index in the bucket.
+    pos: usize,
+}
+
+#[derive(Debug)]
+pub struct TimerWheel<K> {
+    origin: Instant,
+    resolution: u128,
+    /// Ticks processed; every timer is due at or after it.
+    elapsed: u64,
+    timers: HashMap<K, Timer>,
+    /// Keys per slot, level by level, then the overflow list.
+    buckets: Vec<Vec<K>>,
+    /// Bit `s` of level `l` is set if its slot `s` holds timers.
+    occupied: [u64; LEVELS],
+}
+
+impl<K: Copy + Eq + Hash + Ord> TimerWheel<K> {
+    /// A wheel counting ticks of `resolution` from `origin`.
+    pub fn new(origin: Instant, resolution: Duration) -> Self {
+        TimerWheel {
+            origin,
+            resolution: resolution.as_nanos().max(1),
+            elapsed: 0,
+            timers: HashMap::new(),
+            buckets: (0..=OVERFLOW).map(|_| Vec::new()).collect(),
+            occupied: [0; LEVELS],
+        }
+    }
+
+    fn tick_of(&self, at: Instant) -> u64 {
+        let nanos = at.saturating_duration_since(self.origin).as_nanos();
+        u64::try_from(nanos / self.resolution).unwrap_or(u64::MAX)
+    }
+
+    /// The bucket for a timer due in tick `tick`: the level is that of the
+    /// highest tick digit it differs from `elapsed` in.
+    fn bucket_for(&self, tick: u64) -> usize {
+        let tick = tick.max(self.elapsed);
+        let differs = tick ^ self.elapsed;
+        let level = if differs == 0 {
+            0
+        } else {
+            ((u64::BITS - 1 - differs.leading_zeros()) / BITS) as usize
+        };
+        if level >= LEVELS {
+            return OVERFLOW;
+        }
+        level * SLOTS + ((tick >> (level as u32 * BITS)) as usize & (SLOTS - 1))
+    }
+
+    fn place(&mut self, key: K, at: Instant) {
+        let bucket = self.bucket_for(self.tick_of(at));
+        if bucket < OVERFLOW {
+            self.occupied[bucket / SLOTS] |= 1 << (bucket % SLOTS);
+        }
+        let pos = self.buckets[bucket].len();
+        self.buckets[bucket].push(key);
+        self.timers.insert(key, Timer { at, bucket, pos });
+    }
+
+    fn unplace(&mut self, key: &K) -> Option<Instant> {
+        let timer = self.timers.remove(key)?;
+        let bucket = &mut self.buckets[timer.bucket];
+        bucket.swap_remove(timer.pos);
+        if let Some(moved) = bucket.get(timer.pos) {
+            self.timers.get_mut(moved).unwrap().pos = timer.pos;
+        }
+        if bucket.is_empty() && timer.bucket < OVERFLOW {
+            self.occupied[timer.bucket / SLOTS] &= !(1 << (timer.bucket % SLOTS));
+        }
+        Some(timer.at)
+    }
+
+    /// Set `key`'s timer for `at`, returning the deadline it replaces.
+    pub fn insert(&mut self, key: K, at: Instant) -> Option<Instant> {
+        let old = self.unplace(&key);
+        self.place(key, at);
+        old
+    }
+
+    /// Stop `key`'s timer, returning its deadline.
+    pub fn cancel(&mut self, key: &K) -> Option<Instant> {
+        self.unplace(key)
+    }
+
+    pub fn deadline(&self, key: &K) -> Option<Instant> {
+        self.timers.get(key).map(|t| t.at)
+    }
+
+    /// The first occupied slot at or after `elapsed`, and the tick it
+    /// starts at. Timers on a lower level are all due before those on a
+    /// higher one.
+    fn next_slot(&self) -> Option<(usize, u64)> {
+        (0..LEVELS).find_map(|level| {
+            let shift = level as u32 * BITS;
+            let here = (self.elapsed >> shift) as usize & (SLOTS - 1);
+            let ahead = self.occupied[level] & (u64::MAX << here);
+            if ahead == 0 {
+                return None;
+            }
+            let slot = ahead.trailing_zeros() as u64;
+            let block = self.elapsed >> (shift + BITS) << (shift + BITS);
+            let start = (block | slot << shift).max(self.elapsed);
+            Some((level * SLOTS + slot as usize, start))
+        })
+    }
+
+    fn overflow_start(&self) -> Option<u64> {
+        self.buckets[OVERFLOW]
+            .iter()
+            .map(|k| self.tick_of(self.timers[k].at))
+            .min()
+    }
+
+    /// Remove and return the timers due at or before `now`, by deadline
+    /// and then key.
+    pub fn tick(&mut self, now: Instant) -> Vec<(K, Instant)> {
+        let target = self.tick_of(now).max(self.elapsed);
+        let mut fired = Vec::new();
+        loop {
+            let slot = self.next_slot();
+            let overflow = self.overflow_start();
+            let (bucket, start) = match (slot, overflow) {
+                (Some((_, start)), Some(o)) if o < start => (OVERFLOW, o),
+                (Some(next), _) => next,
+                (None, Some(o)) => (OVERFLOW, o),
+                (None, None) => break,
+            };
+            if start > target {
+                break;
+            }
+            self.elapsed = start;
+            let keys = std::mem::take(&mut self.buckets[bucket]);
+            if bucket < OVERFLOW {
+                self.occupied[bucket / SLOTS] &= !(1 << (bucket % SLOTS));
+            }
+            let last = bucket < SLOTS && start == target;
+            for key in keys {
+                let at = self.timers.remove(&key).unwrap().at;
+                if bucket < SLOTS && (start < target || at <= now) {
+                    fired.push((key, at));
+                } else {
+                    // a slot further out coming into range, or one not yet
+                    // due within the current tick
+                    self.place(key, at);
+                }
+            }
+            if last {
+                break;
+            }
+        }
+        self.elapsed = target;
+        fired.sort_by_key(|&(key, at)| (at, key));
+        fired
+    }
+
+    /// The earliest deadline, if any timer is set.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let slot = self
+            .next_slot()
+            .and_then(|(bucket, _)| self.buckets[bucket].iter().map(|k| self.timers[k].at).min());
+        let overflow = self.buckets[OVERFLOW]
+            .iter()
+            .map(|k| self.timers[k].at)
+            .min();
+        slot.into_iter().chain(overflow).min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SPLITMIX_GAMMA, splitmix64};
+    use std::collections::BTreeMap;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Every timer in a map, scanned on each tick.
+    #[derive(Default)]
+    struct Naive(BTreeMap<u32, Instant>);
+
+    impl Naive {
+        fn tick(&mut self, now: Instant) -> Vec<(u32, Instant)> {
+            let mut fired: Vec<_> = self
+                .0
+                .iter()
+                .filter(|&(_, &at)| at <= now)
+                .map(|(&k, &at)| (k, at))
+                .collect();
+            fired.sort_by_key(|&(k, at)| (at, k));
+            for (k, _) in &fired {
+                self.0.remove(k);
+            }
+            fired
+        }
+    }
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 = self.0.wrapping_add(SPLITMIX_GAMMA);
+            splitmix64(self.0) % n
+        }
+    }
+
+    #[test]
+    fn fires_at_the_deadline_not_the_tick() {
+        let start = Instant::now();
+        let mut w = TimerWheel::new(start, 10 * MS);
+        w.insert(1, start + 15 * MS);
+        w.insert(2, start + 12 * MS);
+        assert_eq!(w.next_deadline(), Some(start + 12 * MS));
+        assert_eq!(w.tick(start + 11 * MS), []);
+        assert_eq!(w.tick(start + 12 * MS), [(2, start + 12 * MS)]);
+        assert_eq!(w.insert(1, start + 40 * MS), Some(start + 15 * MS));
+        assert_eq!(w.tick(start + 39 * MS), []);
+        assert_eq!(w.cancel(&1), Some(start + 40 * MS));
+        assert!(w.is_empty());
+        assert_eq!(w.next_deadline(), None);
+    }
+
+    #[test]
+    fn holds_timers_beyond_the_horizon() {
+        let start = Instant::now();
+        let mut w = TimerWheel::new(start, MS);
+        let far = start + Duration::from_secs(24 * 3600);
+        w.insert(1, far);
+        w.insert(2, start + 5 * MS);
+        assert_eq!(w.next_deadline(), Some(start + 5 * MS));
+        assert_eq!(w.tick(start + 10 * MS), [(2, start + 5 * MS)]);
+        assert_eq!(w.next_deadline(), Some(far));
+        w.insert(3, far - MS);
+        assert_eq!(w.tick(far - 2 * MS), []);
+        assert_eq!(w.tick(far), [(3, far - MS), (1, far)]);
+    }
+
+    /// Random inserts, cancels and ticks, many far apart in time, must
+    /// fire exactly what scanning every timer does, in the same order.
+    #[test]
+    fn matches_a_naive_scan() {
+        for seed in 0..40 {
+            let mut rng = Rng(seed);
+            let start = Instant::now();
+            let resolution = Duration::from_micros(1 + rng.below(2000));
+            let mut wheel = TimerWheel::new(start, resolution);
+            let mut naive = Naive::default();
+            let mut now = start;
+            for _ in 0..2000 {
+                match rng.below(10) {
+                    0..=4 => {
+                        let key = rng.below(300) as u32;
+                        // mostly near, sometimes hours or days out
+                        let range = [50, 5_000, 5_000_000, 200_000_000_000][rng.below(4) as usize];
+                        let at = now + Duration::from_micros(rng.below(range));
+                        let at = if rng.below(20) == 0 { now - MS } else { at };
+                        assert_eq!(wheel.insert(key, at), naive.0.insert(key, at));
+                    }
+                    5 => {
+                        let key = rng.below(300) as u32;
+                        assert_eq!(wheel.cancel(&key), naive.0.remove(&key));
+                    }
+                    _ => {
+                        let step = [10, 1_000, 100_000, 50_000_000_000][rng.below(4) as usize];
+                        now += Duration::from_micros(rng.below(step));
+                        assert_eq!(wheel.tick(now), naive.tick(now), "seed {seed}");
+                    }
+                }
+                assert_eq!(wheel.next_deadline(), naive.0.values().min().copied());
+                assert_eq!(wheel.len(), naive.0.len());
+            }
+        }
+    }
+}
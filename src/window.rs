@@ -0,0 +1,428 @@
+//! Flow control: a bound on ack-required frames in flight per device.
+//!
+//! A [`SendWindow`] hands out one slot per ack-required frame and gets it
+//! back through [`SendWindow::release`] when the frame is acked, nacked
+//! or expires. Senders given a window take a slot before sending such a
+//! frame; what they do when none is free is [`WhenFull`]. A
+//! [`Requester`](crate::request::Requester) over such a sender gives the
+//! slot back when the command is answered or times out, and an
+//! [`AckTracker`](crate::ack::AckTracker) given the window with
+//! [`AckTracker::with_window`](crate::ack::AckTracker::with_window) when
+//! it resolves or gives up on the frame; let only one of them see each
+//! frame.
+//!
+//! The last `reserved` slots of each device's window are for
+//! [`Priority::Urgent`] frames, so an urgent frame can still go out when
+//! normal traffic has filled the rest.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use tokio::sync::Notify;
+
+use crate::ack::Outstanding;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    #[default]
+    Normal,
+    Urgent,
+}
+
+impl Priority {
+    /// Lowercase name, as used in metric labels.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Normal => "normal",
+            Priority::Urgent => "urgent",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Wait for a slot.
+    Block,
+    /// Fail with [`WindowError::WouldBlock`].
+    WouldBlock,
+    /// Wait for a slot if fewer than this many senders already are, and
+    /// fail with [`WindowError::QueueFull`] otherwise.
+    Queue(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct WindowConfig {
+    /// Slots per device.
+    pub size: usize,
+    /// Slots only urgent frames may take.
+    pub reserved: usize,
+    pub when_full: WhenFull,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        WindowConfig {
+            size: 16,
+            reserved: 2,
+            when_full: WhenFull::Block,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowError {
+    WouldBlock,
+    QueueFull,
+}
+
+impl fmt::Display for WindowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowError::WouldBlock => write!(f, "send window full"),
+            WindowError::QueueFull => write!(f, "send window queue full"),
+        }
+    }
+}
+
+impl std::error::Error for WindowError {}
+
+#[derive(Default)]
+struct State {
+    in_flight: HashMap<[u8; 8], usize>,
+    waiting: usize,
+}
+
+struct Inner {
+    config: WindowConfig,
+    state: Mutex<State>,
+    /// Wakes async waiters on release.
+    notify: Notify,
+    /// Wakes blocking waiters on release.
+    freed: Condvar,
+}
+
+/// Per-device send window. Clones share the same slots.
+#[derive(Clone)]
+pub struct SendWindow {
+    inner: Arc<Inner>,
+}
+
+/// Counts a sender as waiting until dropped, cancelled or not.
+struct Waiting<'a>(&'a SendWindow);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.lock().waiting -= 1;
+    }
+}
+
+impl SendWindow {
+    pub fn new(config: WindowConfig) -> Self {
+        SendWindow {
+            inner: Arc::new(Inner {
+                config,
+                state: Mutex::default(),
+                notify: Notify::new(),
+                freed: Condvar::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn take(&self, state: &mut State, device_id: [u8; 8], priority: Priority) -> bool {
+        let config = &self.inner.config;
+        let limit = match priority {
+            Priority::Urgent => config.size,
+            Priority::Normal => config.size.saturating_sub(config.reserved),
+        };
+        let used = state.in_flight.entry(device_id).or_default();
+        if *used >= limit {
+            if *used == 0 {
+                state.in_flight.remove(&device_id);
+            }
+            return false;
+        }
+        *used += 1;
+        true
+    }
+
+    /// Take a slot if one is free, whatever the [`WhenFull`] policy.
+    pub fn try_acquire(&self, device_id: [u8; 8], priority: Priority) -> Result<(), WindowError> {
+        let mut state = self.lock();
+        if self.take(&mut state, device_id, priority) {
+            Ok(())
+        } else {
+            Err(WindowError::WouldBlock)
+        }
+    }
+
+    /// Whether a full window should make the caller wait.
+    fn start_waiting(&self, state: &mut State) -> Result<(), WindowError> {
+        match self.inner.config.when_full {
+            WhenFull::WouldBlock => return Err(WindowError::WouldBlock),
+            WhenFull::Queue(depth) if state.waiting >= depth => {
+                return Err(WindowError::QueueFull);
+            }
+            _ => {}
+        }
+        state.waiting += 1;
+        Ok(())
+    }
+
+    /// Take a slot, waiting for one as the policy allows. Cancel-safe.
+    pub async fn acquire(&self, device_id: [u8; 8], priority: Priority) -> Result<(), WindowError> {
+        let _waiting = {
+            let mut state = self.lock();
+            if self.take(&mut state, device_id, priority) {
+                return Ok(());
+            }
+            self.start_waiting(&mut state)?;
+            Waiting(self)
+        };
+        loop {
+            let freed = self.inner.notify.notified();
+            tokio::pin!(freed);
+            freed.as_mut().enable();
+            if self.take(&mut self.lock(), device_id, priority) {
+                return Ok(());
+            }
+            freed.await;
+        }
+    }
+
+    /// [`SendWindow::acquire`] for blocking code.
+    pub fn acquire_blocking(
+        &self,
+        device_id: [u8; 8],
+        priority: Priority,
+    ) -> Result<(), WindowError> {
+        let mut state = self.lock();
+        if self.take(&mut state, device_id, priority) {
+            return Ok(());
+        }
+        self.start_waiting(&mut state)?;
+        loop {
+            state = self
+                .inner
+                .freed
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+            if self.take(&mut state, device_id, priority) {
+                state.waiting -= 1;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Give back a slot of `device_id`.
+    pub fn release(&self, device_id: [u8; 8]) {
+        {
+            let mut state = self.lock();
+            let Some(used) = state.in_flight.get_mut(&device_id) else {
+                return;
+            };
+            *used -= 1;
+            if *used == 0 {
+                state.in_flight.remove(&device_id);
+            }
+        }
+        self.inner.notify.notify_waiters();
+        self.inner.freed.notify_all();
+    }
+
+    /// Give back the slots of frames an [`AckTracker`](crate::ack::AckTracker)
+    /// resolved or expired.
+    pub fn release_all<'a>(&self, done: impl IntoIterator<Item = &'a Outstanding>) {
+        for o in done {
+            self.release(o.frame.header.device_id);
+        }
+    }
+
+    pub fn in_flight(&self, device_id: [u8; 8]) -> usize {
+        self.lock().in_flight.get(&device_id).copied().unwrap_or(0)
+    }
+}
+
+/// A taken slot that goes back to the window on drop unless kept, so a
+/// send that fails or is cancelled does not leak it.
+pub(crate) struct Slot<'a> {
+    window: Option<&'a SendWindow>,
+    device_id: [u8; 8],
+}
+
+impl<'a> Slot<'a> {
+    pub(crate) fn none() -> Self {
+        Slot {
+            window: None,
+            device_id: [0; 8],
+        }
+    }
+
+    pub(crate) fn taken(window: &'a SendWindow, device_id: [u8; 8]) -> Self {
+        Slot {
+            window: Some(window),
+            device_id,
+        }
+    }
+
+    /// The frame went out; its ack will release the slot.
+    pub(crate) fn keep(mut self) {
+        self.window = None;
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        if let Some(w) = self.window {
+            w.release(self.device_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sender::{CounterMap, FrameSender, SendError};
+    use crate::transport::{Transport, loopback_pair};
+    use crate::{Flags, MsgType};
+    use futures::FutureExt;
+
+    const DEV: [u8; 8] = *b"DEV00001";
+
+    fn window(when_full: WhenFull) -> SendWindow {
+        SendWindow::new(WindowConfig {
+            size: 3,
+            reserved: 1,
+            when_full,
+        })
+    }
+
+    #[test]
+    fn urgent_frames_use_the_reserve() {
+        let w = window(WhenFull::WouldBlock);
+        w.try_acquire(DEV, Priority::Normal).unwrap();
+        w.try_acquire(DEV, Priority::Normal).unwrap();
+        assert_eq!(
+            w.try_acquire(DEV, Priority::Normal),
+            Err(WindowError::WouldBlock)
+        );
+        w.try_acquire(DEV, Priority::Urgent).unwrap();
+        assert_eq!(
+            w.try_acquire(DEV, Priority::Urgent),
+            Err(WindowError::WouldBlock)
+        );
+        w.try_acquire(*b"DEV00002", Priority::Normal).unwrap();
+
+        // one release leaves the window at the normal limit
+        w.release(DEV);
+        assert_eq!(
+            w.try_acquire(DEV, Priority::Normal),
+            Err(WindowError::WouldBlock)
+        );
+        w.release(DEV);
+        w.try_acquire(DEV, Priority::Normal).unwrap();
+        assert_eq!(w.in_flight(DEV), 2);
+    }
+
+    #[tokio::test]
+    async fn bounded_queue_of_waiters() {
+        let w = window(WhenFull::Queue(1));
+        w.try_acquire(DEV, Priority::Normal).unwrap();
+        w.try_acquire(DEV, Priority::Normal).unwrap();
+
+        let mut first = Box::pin(w.acquire(DEV, Priority::Normal));
+        assert!(first.as_mut().now_or_never().is_none());
+        assert_eq!(
+            w.acquire(DEV, Priority::Normal).await,
+            Err(WindowError::QueueFull)
+        );
+        w.release(DEV);
+        assert_eq!(first.await, Ok(()));
+        assert_eq!(w.lock().waiting, 0);
+    }
+
+    #[tokio::test]
+    async fn stalled_receiver_blocks_the_sender() {
+        let (a, mut b) = loopback_pair();
+        let w = window(WhenFull::Block);
+        let sender = FrameSender::new(a, Arc::new(CounterMap::default())).with_window(w.clone());
+        let acked = Flags::new(Flags::ACK_REQUIRED).unwrap();
+        let task = {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                for _ in 0..10 {
+                    sender
+                        .send_raw(MsgType::Event, acked, DEV, vec![0; 64])
+                        .await
+                        .unwrap();
+                }
+            })
+        };
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        // frames without ACK_REQUIRED take no slot
+        sender
+            .send_raw(MsgType::Event, Flags::new(0).unwrap(), DEV, Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(w.in_flight(DEV), 2);
+        for _ in 0..3 {
+            b.recv().await.unwrap();
+        }
+        assert!(b.recv().now_or_never().is_none(), "nothing else was sent");
+
+        for sent in 2..10 {
+            w.release(DEV);
+            let f = b.recv().await.unwrap();
+            assert_eq!(f.header.counter, sent + 2);
+        }
+        task.await.unwrap();
+    }
+
+    #[test]
+    fn blocking_sender_would_block() {
+        let w = window(WhenFull::WouldBlock);
+        let sender = crate::sender::BlockingFrameSender::new(
+            |_: &crate::FrameV1| Ok::<_, ()>(()),
+            Arc::new(CounterMap::default()),
+        )
+        .with_window(w.clone());
+        let acked = Flags::new(Flags::ACK_REQUIRED).unwrap();
+        sender
+            .send_raw(MsgType::Event, acked, DEV, Vec::new())
+            .unwrap();
+        sender
+            .send_raw(MsgType::Event, acked, DEV, Vec::new())
+            .unwrap();
+        assert!(matches!(
+            sender.send_raw(MsgType::Event, acked, DEV, Vec::new()),
+            Err(SendError::Window(WindowError::WouldBlock))
+        ));
+        sender
+            .send_urgent(MsgType::Event, acked, DEV, Vec::new())
+            .unwrap();
+        assert_eq!(w.in_flight(DEV), 3);
+    }
+
+    #[test]
+    fn blocking_acquire_wakes_on_release() {
+        let w = window(WhenFull::Block);
+        w.try_acquire(DEV, Priority::Normal).unwrap();
+        w.try_acquire(DEV, Priority::Normal).unwrap();
+        let waiter = {
+            let w = w.clone();
+            std::thread::spawn(move || w.acquire_blocking(DEV, Priority::Normal))
+        };
+        while w.lock().waiting == 0 {
+            std::thread::yield_now();
+        }
+        w.release(DEV);
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+        assert_eq!(w.in_flight(DEV), 2);
+    }
+}
@@ -0,0 +1,61 @@
+//! Heap allocations on the owned decode path, counted per thread by the
+//! global allocator in `benches/common/alloc.rs`, so tests running
+//! alongside don't move each other's counts.
+
+use bytes::BytesMut;
+use pipproto::codec::FrameCodec;
+use pipproto::workload::frame;
+use pipproto::{FrameV1, INLINE_BODY};
+use tokio_util::codec::{Decoder, Encoder};
+
+#[path = "../benches/common/alloc.rs"]
+mod alloc;
+
+fn allocations<T>(run: impl FnOnce() -> T) -> (T, u64) {
+    let (out, counts) = alloc::counted(run);
+    (out, counts.allocations)
+}
+
+#[test]
+fn small_bodies_decode_without_allocating() {
+    for body_len in [0, 1, 16, INLINE_BODY] {
+        let bytes = frame(7, body_len).encode();
+        let (decoded, n) = allocations(|| FrameV1::decode(&bytes).unwrap());
+        assert_eq!(n, 0, "{body_len}B body");
+        assert!(decoded.body.is_inline());
+        assert_eq!(decoded, frame(7, body_len));
+    }
+
+    let bytes = frame(7, INLINE_BODY + 1).encode();
+    let (decoded, n) = allocations(|| FrameV1::decode(&bytes).unwrap());
+    assert_eq!(n, 1);
+    assert!(!decoded.body.is_inline());
+}
+
+#[test]
+fn small_bodies_stream_without_allocating() {
+    const FRAMES: u64 = 1_000;
+    // With no subscriber set, `tracing` hands each event to `log` when its
+    // `log` feature is on (axum turns it on), which formats it.
+    #[cfg(feature = "tracing")]
+    let _ = tracing::subscriber::set_global_default(tracing::subscriber::NoSubscriber::default());
+    let mut codec = FrameCodec::new(usize::MAX);
+    let mut buf = BytesMut::new();
+    for c in 0..FRAMES {
+        codec.encode(frame(c, 32), &mut buf).unwrap();
+    }
+    // The first split turns the buffer into one shared by the frames
+    // split off it, which allocates once; and the metrics, if compiled in,
+    // are set up on first use.
+    codec.decode(&mut buf).unwrap().unwrap().unwrap();
+    let (decoded, n) = allocations(|| {
+        let mut decoded = 0;
+        while let Some(frame) = codec.decode(&mut buf).unwrap() {
+            assert_eq!(frame.unwrap().body.len(), 32);
+            decoded += 1;
+        }
+        decoded
+    });
+    assert_eq!(decoded, FRAMES - 1);
+    assert_eq!(n, 0);
+}
@@ -0,0 +1,166 @@
+//! `pipproto analyze` over a journal written by the library.
+
+use std::io::Write;
+use std::process::{Command, Output};
+use std::time::{Duration, UNIX_EPOCH};
+
+use pipproto::journal::JournalWriter;
+use pipproto::sink::{Direction, FrameRecord, FrameSink};
+use pipproto::{DecodeError, Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+fn analyze(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pipproto"))
+        .arg("analyze")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn frame(msg_type: MsgType, device_id: &[u8; 8], counter: u64, body_len: usize) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type,
+            flags: Flags::new(0).unwrap(),
+            device_id: *device_id,
+            counter,
+        },
+        body: vec![0xab; body_len].into(),
+    }
+}
+
+/// Device A sends 3000 events less 15 lost in two runs; device B 1500
+/// commands, each acked; ten records did not decode. A record with a bad
+/// CRC ends the file.
+fn write_journal(path: &std::path::Path) {
+    let mut records = Vec::new();
+    for counter in 1..=3000 {
+        if !(100..110).contains(&counter) && !(2000..2005).contains(&counter) {
+            records.push(FrameRecord::rx(frame(
+                MsgType::Event,
+                b"DEVA0001",
+                counter,
+                8,
+            )));
+        }
+        if counter <= 1500 {
+            records.push(FrameRecord::rx(frame(
+                MsgType::Command,
+                b"DEVB0001",
+                counter,
+                32,
+            )));
+            records.push(FrameRecord::tx(frame(
+                MsgType::Ack,
+                b"DEVB0001",
+                counter,
+                0,
+            )));
+        }
+        if counter % 300 == 0 {
+            let (bytes, error) = if counter % 900 == 0 {
+                ([b"XX".as_slice(), &[0; 19]].concat(), DecodeError::BadMagic)
+            } else {
+                (b"PP".to_vec(), DecodeError::TooShort)
+            };
+            records.push(FrameRecord::undecodable(Direction::Rx, bytes, error));
+        }
+    }
+    let mut journal = JournalWriter::open(path).unwrap();
+    for (i, mut record) in records.into_iter().enumerate() {
+        record.timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000 + 1000 * i as u64);
+        journal.record(&record).unwrap();
+    }
+    journal.flush().unwrap();
+    drop(journal);
+    let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+    file.write_all(&[0, 0, 0, 4, 1, 2, 3, 4, 0xde, 0xad, 0xbe, 0xef])
+        .unwrap();
+}
+
+#[test]
+fn reports_a_large_journal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("journal.log");
+    write_journal(&path);
+    let path = path.to_str().unwrap();
+
+    let out = analyze(&["--json", "--top", "1", path]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let report: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(report["records"], 5995);
+    assert_eq!(report["frames"], 5985);
+    assert_eq!(report["rx"], 4485);
+    assert_eq!(report["tx"], 1500);
+    assert_eq!(report["devices"], 2);
+    assert_eq!(report["span_secs"], 5994.0);
+    assert_eq!(report["first"], "2023-11-14T22:13:20.000Z");
+    assert_eq!(
+        report["decode_errors"],
+        serde_json::json!({"total": 10, "bad_magic": 3, "too_short": 7})
+    );
+    assert_eq!(
+        report["by_type"],
+        serde_json::json!({"event": 2985, "command": 1500, "ack": 1500, "error": 0})
+    );
+    assert_eq!(
+        report["body_len"],
+        serde_json::json!({"p50": 8, "p90": 32, "p99": 32, "max": 32})
+    );
+    assert_eq!(
+        report["top_devices"],
+        serde_json::json!([{"device_id": "4445564230303031", "frames": 3000, "bytes": 111000}])
+    );
+    assert_eq!(
+        report["gaps"],
+        serde_json::json!({"devices": 1, "ranges": 2, "missing": 15,
+            "top": [{"device_id": "4445564130303031", "ranges": 2, "missing": 15}]})
+    );
+    assert!(
+        report["stopped"]
+            .as_str()
+            .unwrap()
+            .starts_with("corrupt record at offset")
+    );
+    assert_eq!(report["unread_bytes"], 12);
+
+    let out = analyze(&[
+        "--json",
+        "--filter",
+        "type == command && counter <= 1000",
+        path,
+    ]);
+    let report: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(report["frames"], 1000);
+    assert_eq!(
+        report["records"], 1010,
+        "records that did not decode still count"
+    );
+
+    let out = analyze(&[path]);
+    assert!(out.status.success());
+    let text = String::from_utf8(out.stdout).unwrap();
+    assert!(
+        text.contains("frames   5985 (rx 4485, tx 1500) from 2 devices"),
+        "{text}"
+    );
+    assert!(
+        text.contains("  4445564130303031  15 missing in 2 ranges"),
+        "{text}"
+    );
+    assert!(text.ends_with("12 bytes not read\n"), "{text}");
+}
+
+#[test]
+fn rejects_what_is_not_a_journal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("notes.txt");
+    std::fs::write(&path, "hello").unwrap();
+    let out = analyze(&[path.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("not a journal file"));
+}
@@ -0,0 +1,140 @@
+//! `pipproto audit` over journals with planted duplicates and regressions.
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+use pipproto::journal::JournalWriter;
+use pipproto::sink::{FrameRecord, FrameSink};
+use pipproto::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+fn audit(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pipproto"))
+        .arg("audit")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn frame(device: &[u8; 8], counter: u64, body: &[u8]) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Event,
+            flags: Flags::new(0).unwrap(),
+            device_id: *device,
+            counter,
+        },
+        body: body.into(),
+    }
+}
+
+fn write_journal(path: &Path, frames: &[FrameV1]) {
+    let mut journal = JournalWriter::open(path).unwrap();
+    for f in frames {
+        journal.record(&FrameRecord::tx(f.clone())).unwrap();
+    }
+    journal.flush().unwrap();
+}
+
+/// Counters 1 to 1000 from two devices, the second journal carrying on
+/// from the first.
+fn write_clean(dir: &Path) -> (String, String) {
+    let (a, b) = (dir.join("a.log"), dir.join("b.log"));
+    let frames = |range: std::ops::RangeInclusive<u64>| {
+        range
+            .flat_map(|c| [frame(b"DEV00001", c, b"ok"), frame(b"DEV00002", c, b"ok")])
+            .collect::<Vec<_>>()
+    };
+    write_journal(&a, &frames(1..=500));
+    write_journal(&b, &frames(501..=1000));
+    (a.display().to_string(), b.display().to_string())
+}
+
+#[test]
+fn clean_journals_pass() {
+    let dir = tempfile::tempdir().unwrap();
+    let (a, b) = write_clean(dir.path());
+    let spill = dir.path().display().to_string();
+    let out = audit(&["--buckets", "4", "--spill", &spill, &a, &b]);
+    assert_eq!(out.status.code(), Some(0));
+    let text = String::from_utf8(out.stdout).unwrap();
+    assert!(
+        text.starts_with("2000 records, 0 skipped, 2 devices: clean\n"),
+        "{text}"
+    );
+    assert!(text.contains("\n4445563030303031        1000"), "{text}");
+}
+
+#[test]
+fn reports_planted_findings() {
+    let dir = tempfile::tempdir().unwrap();
+    let (a, _) = write_clean(dir.path());
+    let c = dir.path().join("c.log");
+    write_journal(
+        &c,
+        &[
+            frame(b"DEV00001", 1001, b"ok"),
+            frame(b"DEV00001", 1001, b"ok"), // retransmitted
+            frame(b"DEV00001", 250, b"reused"),
+            frame(b"DEV00002", 1500, b"ok"),
+        ],
+    );
+    let c = c.display().to_string();
+
+    let out = audit(&["--json", &a, &c]);
+    assert_eq!(out.status.code(), Some(1));
+    let lines: Vec<serde_json::Value> = String::from_utf8(out.stdout)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3, "{lines:?}");
+    let regression = &lines[0];
+    assert_eq!(regression["kind"], "regression");
+    assert_eq!(regression["device_id"], "4445563030303031");
+    assert_eq!(regression["previous"], 1001);
+    assert_eq!(regression["counter"], 250);
+    assert_eq!(regression["at"]["journal"], c.as_str());
+    let duplicate = &lines[1];
+    assert_eq!(duplicate["kind"], "duplicate");
+    assert_eq!(duplicate["counter"], 250);
+    assert_eq!(duplicate["first"]["journal"], a.as_str());
+    assert_eq!(duplicate["second"], regression["at"]);
+
+    let report = &lines[2];
+    assert_eq!(report["kind"], "report");
+    assert_eq!(report["clean"], false);
+    let dev1 = &report["devices"][0];
+    assert_eq!(dev1["frames"], 503);
+    assert_eq!(dev1["retransmissions"], 1);
+    assert_eq!(dev1["duplicates"], 1);
+    assert_eq!(dev1["regressions"], 1);
+    let dev2 = &report["devices"][1];
+    assert_eq!(dev2["largest_gap"]["after"], 500);
+    assert_eq!(dev2["largest_gap"]["missing"], 999);
+
+    let out = audit(&[&a, &c]);
+    let text = String::from_utf8(out.stdout).unwrap();
+    assert!(
+        text.starts_with(&format!(
+            "regression device=4445563030303031 counter=250 at {c}@"
+        )),
+        "{text}"
+    );
+    let duplicate = text.lines().nth(1).unwrap();
+    assert!(
+        duplicate.starts_with(&format!(
+            "duplicate device=4445563030303031 counter=250 at {c}@"
+        )),
+        "{text}"
+    );
+    assert!(duplicate.contains(&format!(", first at {a}@")), "{text}");
+}
+
+#[test]
+fn unreadable_journal() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing.log");
+    let out = audit(&[missing.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(2));
+}
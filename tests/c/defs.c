@@ -0,0 +1,42 @@
+/* include/pipproto_defs.h as firmware builds it, in any C dialect, and
+ * beside the C bindings' header (C99), whose constants it must agree
+ * with. */
+
+#include "pipproto_defs.h"
+#include "pipproto_defs.h"
+
+#define CHECK(name, cond) typedef char check_##name[(cond) ? 1 : -1]
+
+CHECK(fields_fill_the_header, PP_COUNTER_OFFSET + PP_COUNTER_SIZE == PP_HEADER_LEN_V1);
+CHECK(fields_are_contiguous,
+      PP_MAGIC_OFFSET + PP_MAGIC_SIZE == PP_VERSION_OFFSET &&
+          PP_VERSION_OFFSET + PP_VERSION_SIZE == PP_MSG_TYPE_OFFSET &&
+          PP_MSG_TYPE_OFFSET + PP_MSG_TYPE_SIZE == PP_FLAGS_OFFSET &&
+          PP_FLAGS_OFFSET + PP_FLAGS_SIZE == PP_DEVICE_ID_OFFSET &&
+          PP_DEVICE_ID_OFFSET + PP_DEVICE_ID_SIZE == PP_COUNTER_OFFSET);
+CHECK(flags, (PP_FLAG_ACK_REQUIRED & PP_FLAGS_RESERVED) == 0 &&
+                 (PP_FLAG_ACK_REQUIRED | PP_FLAGS_RESERVED) == 0xffu);
+#if defined(__STDC_VERSION__) && __STDC_VERSION__ >= 199901L
+#include "pipproto.h"
+
+CHECK(header_len, PP_HEADER_LEN_V1 == PP_HEADER_LEN);
+CHECK(errors, PP_ERR_OK == PP_ERROR_OK && PP_ERR_TOO_SHORT == PP_ERROR_TOO_SHORT &&
+                  PP_ERR_BAD_MAGIC == PP_ERROR_BAD_MAGIC &&
+                  PP_ERR_BAD_VERSION == PP_ERROR_BAD_VERSION &&
+                  PP_ERR_UNKNOWN_MSG_TYPE == PP_ERROR_UNKNOWN_MSG_TYPE &&
+                  PP_ERR_RESERVED_FLAGS == PP_ERROR_RESERVED_FLAGS &&
+                  PP_ERR_BAD_DEVICE_ID_BYTES == PP_ERROR_BAD_DEVICE_ID_BYTES &&
+                  PP_ERR_BAD_COUNTER_BYTES == PP_ERROR_BAD_COUNTER_BYTES);
+#endif
+
+static const unsigned char magic[PP_MAGIC_LEN] = {PP_MAGIC_0, PP_MAGIC_1};
+
+int main(void) {
+    unsigned char header[PP_HEADER_LEN_V1] = {0};
+    header[PP_MAGIC_OFFSET] = magic[0];
+    header[PP_MAGIC_OFFSET + 1] = magic[1];
+    header[PP_VERSION_OFFSET] = PP_VERSION_V1;
+    header[PP_MSG_TYPE_OFFSET] = PP_MSG_TYPE_EVENT;
+    header[PP_FLAGS_OFFSET] = PP_FLAG_ACK_REQUIRED;
+    return header[PP_FLAGS_OFFSET] & PP_FLAGS_RESERVED ? PP_ERR_RESERVED_FLAGS : PP_ERR_OK;
+}
@@ -0,0 +1,151 @@
+/* Exercises include/pipproto.h against the static library: frames
+ * round-trip, every decode error comes back as its code, and the output
+ * buffer is sized the documented way. Exits non-zero on a failure. */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "pipproto.h"
+
+static int failures;
+
+#define CHECK(cond)                                                      \
+  do {                                                                   \
+    if (!(cond)) {                                                       \
+      fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, \
+              #cond);                                                    \
+      failures++;                                                        \
+    }                                                                    \
+  } while (0)
+
+static PpFrame sample(uint8_t *body, size_t body_len) {
+  PpFrame frame = {0};
+  frame.version = 1;
+  frame.msg_type = 2; /* COMMAND */
+  frame.flags = 1;    /* ACK_REQUIRED */
+  memcpy(frame.device_id, "DEV00042", 8);
+  frame.counter = 0x0102030405060708ull;
+  frame.body = body;
+  frame.body_len = body_len;
+  return frame;
+}
+
+static void round_trip(size_t body_len) {
+  uint8_t body[1024];
+  uint8_t encoded[PP_HEADER_LEN + sizeof body];
+  size_t written = 0;
+  size_t i;
+  PpFrame frame;
+  PpFrame decoded;
+
+  for (i = 0; i < body_len; i++) {
+    body[i] = (uint8_t)(i * 7);
+  }
+  frame = sample(body_len ? body : NULL, body_len);
+
+  /* ask for the length first */
+  CHECK(pp_frame_encode(&frame, NULL, 0, &written) ==
+        PP_ERROR_BUFFER_TOO_SMALL);
+  CHECK(written == PP_HEADER_LEN + body_len);
+
+  CHECK(pp_frame_encode(&frame, encoded, sizeof encoded, &written) ==
+        PP_ERROR_OK);
+  CHECK(written == PP_HEADER_LEN + body_len);
+  CHECK(memcmp(encoded, "PP", 2) == 0);
+
+  CHECK(pp_frame_decode(encoded, written, &decoded) == PP_ERROR_OK);
+  CHECK(decoded.version == 1);
+  CHECK(decoded.msg_type == 2);
+  CHECK(decoded.flags == 1);
+  CHECK(memcmp(decoded.device_id, "DEV00042", 8) == 0);
+  CHECK(decoded.counter == 0x0102030405060708ull);
+  CHECK(decoded.body_len == body_len);
+  CHECK(body_len == 0 ? decoded.body == NULL
+                      : memcmp(decoded.body, body, body_len) == 0);
+  /* the decoded body is the library's own copy */
+  CHECK(body_len == 0 || decoded.body != body);
+
+  pp_frame_free(&decoded);
+  CHECK(decoded.body == NULL && decoded.body_len == 0);
+  pp_frame_free(&decoded);
+}
+
+static void decode_errors(void) {
+  uint8_t body[4] = {1, 2, 3, 4};
+  uint8_t good[PP_HEADER_LEN + sizeof body];
+  uint8_t bad[sizeof good];
+  size_t written;
+  PpFrame frame = sample(body, sizeof body);
+  PpFrame decoded;
+  struct {
+    size_t at;
+    uint8_t byte;
+    PpError want;
+  } cases[] = {
+      {0, 'X', PP_ERROR_BAD_MAGIC},
+      {2, 9, PP_ERROR_BAD_VERSION},
+      {3, 0x7f, PP_ERROR_UNKNOWN_MSG_TYPE},
+      {4, 0x02, PP_ERROR_RESERVED_FLAGS},
+  };
+  size_t i;
+
+  CHECK(pp_frame_encode(&frame, good, sizeof good, &written) == PP_ERROR_OK);
+  CHECK(pp_frame_decode(good, PP_HEADER_LEN - 1, &decoded) ==
+        PP_ERROR_TOO_SHORT);
+  CHECK(decoded.body == NULL);
+  for (i = 0; i < sizeof cases / sizeof cases[0]; i++) {
+    memcpy(bad, good, sizeof good);
+    bad[cases[i].at] = cases[i].byte;
+    CHECK(pp_frame_decode(bad, sizeof bad, &decoded) == cases[i].want);
+    CHECK(decoded.body == NULL);
+  }
+  CHECK(pp_frame_decode(NULL, 4, &decoded) == PP_ERROR_NULL_POINTER);
+  CHECK(pp_frame_decode(good, sizeof good, NULL) == PP_ERROR_NULL_POINTER);
+}
+
+static void encode_errors(void) {
+  uint8_t body[4] = {1, 2, 3, 4};
+  uint8_t out[PP_HEADER_LEN + sizeof body];
+  size_t written = 0;
+  PpFrame frame;
+
+  frame = sample(body, sizeof body);
+  memset(out, 0xee, sizeof out);
+  CHECK(pp_frame_encode(&frame, out, sizeof out - 1, &written) ==
+        PP_ERROR_BUFFER_TOO_SMALL);
+  CHECK(written == sizeof out);
+  CHECK(out[0] == 0xee);
+
+  frame.msg_type = 0;
+  CHECK(pp_frame_encode(&frame, out, sizeof out, &written) ==
+        PP_ERROR_UNKNOWN_MSG_TYPE);
+  frame = sample(body, sizeof body);
+  frame.flags = 0x80;
+  CHECK(pp_frame_encode(&frame, out, sizeof out, &written) ==
+        PP_ERROR_RESERVED_FLAGS);
+  frame = sample(NULL, sizeof body);
+  CHECK(pp_frame_encode(&frame, out, sizeof out, &written) ==
+        PP_ERROR_NULL_POINTER);
+  CHECK(pp_frame_encode(NULL, out, sizeof out, &written) ==
+        PP_ERROR_NULL_POINTER);
+}
+
+int main(void) {
+  size_t lens[] = {0, 1, 54, 55, 1000};
+  size_t i;
+
+  for (i = 0; i < sizeof lens / sizeof lens[0]; i++) {
+    round_trip(lens[i]);
+  }
+  decode_errors();
+  encode_errors();
+  CHECK(strcmp(pp_error_message(PP_ERROR_BAD_MAGIC), "bad magic") == 0);
+  CHECK(strcmp(pp_error_message(-1), "unknown error code") == 0);
+
+  if (failures) {
+    fprintf(stderr, "%d checks failed\n", failures);
+    return 1;
+  }
+  printf("ok\n");
+  return 0;
+}
@@ -0,0 +1,41 @@
+//! The committed `include/pipproto_defs.h` is what the generator writes,
+//! and compiles as strict C.
+
+use pipproto::cdefs::header;
+
+const FILE: &str = include_str!("../include/pipproto_defs.h");
+
+#[test]
+fn committed_header_is_up_to_date() {
+    assert!(
+        FILE == header(),
+        "include/pipproto_defs.h is stale: regenerate it with \
+         `cargo run -- c-defs --out include/pipproto_defs.h`"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn header_compiles_under_wall() {
+    use std::path::Path;
+    use std::process::Command;
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dir = tempfile::tempdir().unwrap();
+    let cc = std::env::var("CC").unwrap_or_else(|_| "cc".into());
+    for std in ["-std=c89", "-std=c99", "-std=c11"] {
+        let build = Command::new(&cc)
+            .args([std, "-pedantic", "-Wall", "-Wextra", "-Werror", "-I"])
+            .arg(root.join("include"))
+            .arg(root.join("tests/c/defs.c"))
+            .arg("-o")
+            .arg(dir.path().join("defs"))
+            .output()
+            .unwrap();
+        assert!(
+            build.status.success(),
+            "{std}: {}",
+            String::from_utf8_lossy(&build.stderr)
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! The `pipproto` binary, run as a process.
+#![cfg(feature = "serde")]
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn pipproto(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pipproto"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+const FRAME: &str = r#"{"msg_type":"event","flags":{"ack_required":true},
+    "device_id":"deadbeef00000001","counter":7,"body_hex":"6869"}"#;
+
+#[test]
+fn encode_then_decode() {
+    let encoded = pipproto(&["encode"], FRAME.as_bytes());
+    assert!(encoded.status.success());
+    let hex = String::from_utf8(encoded.stdout).unwrap();
+    assert_eq!(
+        hex,
+        "50500101 01deadbeef00000001 0000000000000007 6869\n".replace(' ', "")
+    );
+
+    let decoded = pipproto(&["decode", "--json"], hex.as_bytes());
+    assert!(decoded.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&decoded.stdout).unwrap();
+    assert_eq!(json["device_id"], "deadbeef00000001");
+    assert_eq!(json["counter"], 7);
+    assert_eq!(json["flags"], serde_json::json!(["ACK_REQUIRED"]));
+    assert_eq!(json["body"], "6869");
+}
+
+#[test]
+fn encode_to_a_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("frame.bin");
+    let out = out.to_str().unwrap();
+    let encoded = pipproto(&["encode", "--out", out], FRAME.as_bytes());
+    assert!(encoded.status.success());
+    assert!(encoded.stdout.is_empty());
+    let decoded = pipproto(&["decode", "--binary", "--file", out], b"");
+    assert!(decoded.status.success());
+    let text = String::from_utf8(decoded.stdout).unwrap();
+    assert!(text.contains("counter:   7\n"), "{text}");
+}
+
+#[test]
+fn rejects_impossible_frames() {
+    let doc = r#"{"version":2,"msg_type":"event","device_id":"deadbeef00000001","counter":7}"#;
+    let encoded = pipproto(&["encode"], doc.as_bytes());
+    assert_eq!(encoded.status.code(), Some(1));
+    let err = String::from_utf8(encoded.stderr).unwrap();
+    assert!(err.contains("unsupported version: 2"), "{err}");
+
+    let decoded = pipproto(&["decode"], b"5050 02");
+    assert_eq!(decoded.status.code(), Some(1));
+}
+
+#[test]
+fn verify_vectors_reports_failing_cases() {
+    let file = std::fs::read_to_string("docs/test-vectors-v1.json").unwrap();
+    let passed = pipproto(&["verify-vectors"], file.as_bytes());
+    assert!(passed.status.success());
+    assert!(
+        String::from_utf8(passed.stdout)
+            .unwrap()
+            .ends_with(" vectors checked, 0 failed\n")
+    );
+
+    let tampered = file.replacen(
+        r#""error":"bad_magic","offset":0"#,
+        r#""error":"bad_version","offset":0"#,
+        1,
+    );
+    let failed = pipproto(&["verify-vectors"], tampered.as_bytes());
+    assert_eq!(failed.status.code(), Some(1));
+    let report = String::from_utf8(failed.stderr).unwrap();
+    assert!(
+        report.starts_with("FAIL bad_magic_first_byte: fails with bad_magic at Some(0), not bad_version at Some(0)\n"),
+        "{report}"
+    );
+    assert!(report.ends_with(" vectors checked, 1 failed\n"));
+}
+
+#[test]
+fn gen_corpus_is_reproducible_and_decodes_as_described() {
+    let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    for dir in &dirs {
+        let out = dir.path().to_str().unwrap();
+        let run = pipproto(
+            &["gen-corpus", "--seed", "9", "--count", "50", "--out", out],
+            b"",
+        );
+        assert!(run.status.success());
+        assert_eq!(
+            String::from_utf8(run.stdout).unwrap(),
+            format!("wrote 50 cases to {out} (2 decode, 48 do not)\n")
+        );
+    }
+    let mut names: Vec<_> = std::fs::read_dir(dirs[0].path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names.len(), 100);
+    for name in &names {
+        let read = |i: usize| std::fs::read(dirs[i].path().join(name)).unwrap();
+        assert_eq!(read(0), read(1), "{name:?}");
+    }
+
+    for (stem, decodes) in [
+        ("00001-type_event_flags_01", true),
+        ("00002-type_event_flags_02", false),
+    ] {
+        let path = dirs[0].path().join(format!("{stem}.bin"));
+        let decoded = pipproto(
+            &["decode", "--binary", "--file", path.to_str().unwrap()],
+            b"",
+        );
+        assert_eq!(decoded.status.success(), decodes);
+        let manifest =
+            std::fs::read_to_string(dirs[0].path().join(format!("{stem}.json"))).unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["decodes"], decodes);
+    }
+}
@@ -0,0 +1,150 @@
+//! `pipproto diff` over two journals of the same traffic.
+
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::{Duration, UNIX_EPOCH};
+
+use pipproto::journal::JournalWriter;
+use pipproto::sink::{FrameRecord, FrameSink};
+use pipproto::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+fn diff(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pipproto"))
+        .arg("diff")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn frame(device: &[u8; 8], counter: u64) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Event,
+            flags: Flags::new(0).unwrap(),
+            device_id: *device,
+            counter,
+        },
+        body: counter.to_be_bytes().into(),
+    }
+}
+
+/// Records `frames`, each `(ms after the start, frame)`, starting at
+/// `start_ms`.
+fn write(path: &Path, start_ms: u64, frames: &[(u64, FrameV1)]) {
+    let mut journal = JournalWriter::open(path).unwrap();
+    for (ms, frame) in frames {
+        let mut record = FrameRecord::rx(frame.clone());
+        record.timestamp = UNIX_EPOCH + Duration::from_millis(start_ms + ms);
+        journal.record(&record).unwrap();
+    }
+    journal.flush().unwrap();
+}
+
+/// Two devices taking turns, 1000 frames 10ms apart.
+fn traffic() -> Vec<(u64, FrameV1)> {
+    (0..1000)
+        .map(|i| {
+            let device = if i % 2 == 0 { b"DEVA0001" } else { b"DEVB0001" };
+            (10 * i, frame(device, 1 + i / 2))
+        })
+        .collect()
+}
+
+#[test]
+fn identical_traffic_reordered_and_retransmitted() {
+    let dir = tempfile::tempdir().unwrap();
+    let (left, right) = (dir.path().join("left.log"), dir.path().join("right.log"));
+    let frames = traffic();
+    write(&left, 1_700_000_000_000, &frames);
+    let mut other = frames.clone();
+    for chunk in other.chunks_mut(7) {
+        chunk.reverse();
+    }
+    let again: Vec<_> = other.iter().step_by(50).cloned().collect();
+    for (i, f) in again.into_iter().enumerate() {
+        other.insert(i * 51 + 3, f);
+    }
+    write(&right, 1_800_000_000_000, &other);
+
+    let out = diff(&[left.to_str().unwrap(), right.to_str().unwrap()]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stdout)
+    );
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "left 1000 frames (0 retransmitted, 0 only there), right 1020 frames \
+         (20 retransmitted, 0 only there); 1000 paired, 1000 identical, 0 changed, \
+         0 apart in time\n"
+    );
+}
+
+#[test]
+fn reports_what_changed() {
+    let dir = tempfile::tempdir().unwrap();
+    let (left, right) = (dir.path().join("left.log"), dir.path().join("right.log"));
+    let frames = traffic();
+    write(&left, 1_700_000_000_000, &frames);
+    let mut other = frames.clone();
+    other.remove(100); // DEVA0001 51
+    other[200].1.body[7] ^= 0xff; // DEVB0001 101
+    other[301].1.header.flags = Flags::new(Flags::ACK_REQUIRED).unwrap(); // DEVA0001 152
+    other[500].0 += 5000; // DEVB0001 251
+    other.push((10_000, frame(b"DEVC0001", 1)));
+    write(&right, 1_800_000_000_000, &other);
+    let (left, right) = (left.to_str().unwrap(), right.to_str().unwrap());
+
+    let out = diff(&[left, right]);
+    assert_eq!(out.status.code(), Some(1));
+    let text = String::from_utf8(out.stdout).unwrap();
+    let lines: Vec<_> = text.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "changed dev=4445564230303031 ctr=101: body @7: 65 -> 9a",
+            "changed dev=4445564130303031 ctr=152: flags 0b00000000 -> 0b00000001",
+            "timing dev=4445564230303031 ctr=251: left at 5.010s, right at 10.010s",
+            "2023-11-14T22:13:21.000Z only left: event dev=4445564130303031 ctr=51 body=8B",
+            "2027-01-15T08:00:10.000Z only right: event dev=4445564330303031 ctr=1 body=8B",
+            "left 1000 frames (0 retransmitted, 1 only there), right 1000 frames \
+             (0 retransmitted, 1 only there); 999 paired, 997 identical, 2 changed, \
+             1 apart in time",
+        ]
+    );
+
+    let out = diff(&["--json", "--timing", "off", left, right]);
+    let objects: Vec<serde_json::Value> = out
+        .stdout
+        .split(|&b| b == b'\n')
+        .filter(|l| !l.is_empty())
+        .map(|l| serde_json::from_slice(l).unwrap())
+        .collect();
+    assert_eq!(objects.len(), 5);
+    assert_eq!(objects[0]["kind"], "changed");
+    assert_eq!(
+        objects[0]["body"],
+        serde_json::json!([{"offset": 7, "left": "65", "right": "9a"}])
+    );
+    assert_eq!(
+        objects[1]["right"]["flags"],
+        serde_json::json!(["ACK_REQUIRED"])
+    );
+    assert_eq!(objects[2]["side"], "left");
+    assert_eq!(objects[2]["frame"]["counter"], 51);
+    assert_eq!(objects[4]["kind"], "summary");
+    assert_eq!(objects[4]["timing"], 0);
+    assert_eq!(objects[4]["identical"], 997);
+}
+
+#[test]
+fn unreadable_journal_exits_2() {
+    let dir = tempfile::tempdir().unwrap();
+    let left = dir.path().join("left.log");
+    write(&left, 0, &traffic());
+    let missing = dir.path().join("missing.log");
+    let out = diff(&[left.to_str().unwrap(), missing.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).starts_with("error: "));
+}
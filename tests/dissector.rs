@@ -0,0 +1,14 @@
+//! The committed Wireshark plugin is what the generator writes.
+
+use pipproto::dissector::{DEFAULT_PORT, lua};
+
+const FILE: &str = include_str!("../docs/pipproto-dissector.lua");
+
+#[test]
+fn committed_plugin_is_up_to_date() {
+    assert!(
+        FILE == lua(DEFAULT_PORT),
+        "docs/pipproto-dissector.lua is stale: regenerate it with \
+         `cargo run -- dissector --out docs/pipproto-dissector.lua`"
+    );
+}
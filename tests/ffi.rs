@@ -0,0 +1,84 @@
+//! The C bindings as a C program sees them: `tests/c/roundtrip.c`,
+//! compiled against `include/pipproto.h` and linked with the static
+//! library.
+
+#![cfg(all(feature = "ffi", target_os = "linux"))]
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const HEADER: &str = include_str!("../include/pipproto.h");
+
+/// Build the static library, which `cargo test` doesn't, returning its
+/// path.
+fn static_lib() -> PathBuf {
+    // this test is target/{profile}/deps/ffi-*
+    let exe = std::env::current_exe().unwrap();
+    let dir = exe.parent().unwrap().parent().unwrap();
+    let profile = match dir.file_name().unwrap().to_str().unwrap() {
+        "debug" => "dev",
+        other => other,
+    };
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--features", "ffi", "--profile", profile])
+        .arg("--manifest-path")
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    dir.join("libpipproto.a")
+}
+
+#[test]
+fn c_program_round_trips_frames() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let lib = static_lib();
+    let dir = tempfile::tempdir().unwrap();
+    let exe = dir.path().join("roundtrip");
+
+    let cc = std::env::var("CC").unwrap_or_else(|_| "cc".into());
+    let build = Command::new(cc)
+        .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-I"])
+        .arg(root.join("include"))
+        .arg(root.join("tests/c/roundtrip.c"))
+        .arg(&lib)
+        // what `rustc --print native-static-libs` lists on Linux
+        .args(["-lgcc_s", "-lutil", "-lrt", "-lpthread", "-lm", "-ldl"])
+        .arg("-o")
+        .arg(&exe)
+        .output()
+        .unwrap();
+    assert!(
+        build.status.success(),
+        "{}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+
+    let run = Command::new(&exe).output().unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    assert_eq!(run.stdout, b"ok\n");
+}
+
+/// cbindgen isn't run by the build, so check the committed header is
+/// what it makes of `src/ffi.rs` now.
+#[test]
+fn header_is_up_to_date() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let config = cbindgen::Config::from_file(root.join("cbindgen.toml")).unwrap();
+    let mut generated = Vec::new();
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(root.join("src/ffi.rs"))
+        .generate()
+        .unwrap()
+        .write(&mut generated);
+    assert!(
+        String::from_utf8(generated).unwrap() == HEADER,
+        "include/pipproto.h is stale: regenerate it with \
+         `cbindgen --config cbindgen.toml --output include/pipproto.h src/ffi.rs`"
+    );
+}
@@ -0,0 +1,43 @@
+//! Counters of the global registry, in a binary of their own so no other
+//! test moves them.
+#![cfg(feature = "metrics")]
+
+use pipproto::metrics;
+use pipproto::transport::{Transport, loopback_pair};
+use pipproto::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+fn frame(msg_type: MsgType, counter: u64) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type,
+            flags: Flags::new(0).unwrap(),
+            device_id: *b"DEV00001",
+            counter,
+        },
+        body: vec![1, 2, 3].into(),
+    }
+}
+
+#[tokio::test]
+async fn loopback_session_updates_counters() {
+    let (mut a, mut b) = loopback_pair();
+    for c in 0..3 {
+        a.send(&frame(MsgType::Event, c)).await.unwrap();
+        b.recv().await.unwrap();
+    }
+    b.send(&frame(MsgType::Ack, 0)).await.unwrap();
+    a.recv().await.unwrap();
+
+    let m = metrics::global();
+    assert_eq!(m.frames_encoded(), 4);
+    assert_eq!(m.frames_decoded(), 4);
+    assert_eq!(m.frames_sent(MsgType::Event), 3);
+    assert_eq!(m.frames_received(MsgType::Ack), 1);
+    assert_eq!(m.bytes_sent(), 4 * 24);
+    assert_eq!(m.bytes_received(), 4 * 24);
+    assert_eq!(m.decode_errors("bad_magic"), 0);
+
+    let text = m.render_prometheus_text();
+    assert!(text.contains("pipproto_frames_received_total{msg_type=\"event\"} 3\n"));
+}
@@ -0,0 +1,155 @@
+//! A journal of millions of records read on several threads, against the
+//! same journal read on one.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::Command;
+
+use pipproto::journal::{JournalReader, JournalWriter, ParallelReader};
+use pipproto::sink::{FrameRecord, FrameSink, Payload};
+use pipproto::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+const RECORDS: u64 = 2_000_000;
+
+fn frame(device: u8, counter: u64, body: Vec<u8>) -> FrameV1 {
+    let mut device_id = *b"DEV00000";
+    device_id[7] += device;
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Event,
+            flags: Flags::new(0).unwrap(),
+            device_id,
+            counter,
+        },
+        body: body.into(),
+    }
+}
+
+/// Small bodies from eight devices, a counter skipped or repeated here
+/// and there, some bodies holding a whole journal record, and a few
+/// places written over.
+fn write_journal(path: &Path) {
+    let nested = path.with_extension("nested");
+    let mut journal = JournalWriter::open(&nested).unwrap();
+    journal
+        .record(&FrameRecord::tx(frame(9, 1, vec![1; 8])))
+        .unwrap();
+    journal.flush().unwrap();
+    let nested = std::fs::read(&nested).unwrap()[4..].to_vec();
+
+    let mut journal = JournalWriter::open(path).unwrap();
+    for n in 0..RECORDS {
+        let device = (n % 8) as u8;
+        let counter = n / 8 + if n % 10_007 == 0 { 3 } else { 0 };
+        let body = match n % 1000 {
+            0 => nested.clone(),
+            k => vec![k as u8; (k % 17) as usize],
+        };
+        journal
+            .record(&FrameRecord::tx(frame(device, counter, body)))
+            .unwrap();
+    }
+    journal.flush().unwrap();
+    drop(journal);
+
+    let len = std::fs::metadata(path).unwrap().len();
+    let mut file = OpenOptions::new().write(true).open(path).unwrap();
+    for k in 1..20 {
+        file.seek(SeekFrom::Start(len / 20 * k + k * 7)).unwrap();
+        file.write_all(&[0x5a; 13]).unwrap();
+    }
+}
+
+#[test]
+fn parallel_reads_match_sequential_ones() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("big.log");
+    write_journal(&path);
+
+    // record by record, errors and offsets included
+    let mut one = JournalReader::open(&path).unwrap();
+    let mut many = ParallelReader::open(&path)
+        .unwrap()
+        .with_threads(8)
+        .with_chunk_len(1 << 20);
+    let (mut records, mut errors) = (0, 0);
+    loop {
+        let (a, b) = (one.next(), many.next());
+        match (&a, &b) {
+            (Some(Ok(a)), Some(Ok(b))) => {
+                assert_eq!(a, b);
+                records += 1;
+            }
+            (Some(Err(a)), Some(Err(b))) => {
+                assert_eq!(a.to_string(), b.to_string());
+                errors += 1;
+            }
+            (None, None) => break,
+            _ => panic!("{a:?} vs {b:?} at {}", one.offset()),
+        }
+        assert_eq!(one.offset(), many.offset());
+    }
+    assert!(records > RECORDS - 100, "{records}");
+    assert!(errors >= 19, "{errors}");
+
+    // an aggregate: frames and the sum of their counters, by device
+    type Tally = BTreeMap<[u8; 8], (u64, u64)>;
+    let add = |out: &mut Tally, item| {
+        if let Ok(FrameRecord {
+            payload: Payload::Frame(f),
+            ..
+        }) = item
+        {
+            let e = out.entry(f.header.device_id).or_default();
+            e.0 += 1;
+            e.1 += f.header.counter;
+        }
+    };
+    let mut want = Tally::new();
+    for item in JournalReader::open(&path).unwrap() {
+        add(&mut want, item);
+    }
+    let tally = ParallelReader::open(&path)
+        .unwrap()
+        .with_threads(8)
+        .with_chunk_len(1 << 20)
+        .fold_unordered(
+            Tally::new,
+            |out, _, item| add(out, item),
+            |mut a, b| {
+                for (device, (n, sum)) in b {
+                    let e = a.entry(device).or_default();
+                    e.0 += n;
+                    e.1 += sum;
+                }
+                a
+            },
+        );
+    assert_eq!(tally, want);
+
+    // and the commands that read journals in order
+    let path = path.display().to_string();
+    for command in [
+        &["analyze", "--json", &path][..],
+        &["audit", "--json", "--direction", "both", &path],
+    ] {
+        let run = |threads: &str| {
+            Command::new(env!("CARGO_BIN_EXE_pipproto"))
+                .args(command)
+                .args(["--threads", threads])
+                .output()
+                .unwrap()
+        };
+        let (one, many) = (run("1"), run("8"));
+        assert!(
+            !one.stdout.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&one.stderr)
+        );
+        assert_eq!(one.stdout, many.stdout, "{command:?}");
+        assert_eq!(one.status, many.status);
+    }
+}
@@ -0,0 +1,81 @@
+//! A buffer pool shared by many threads and tasks: no buffer is handed to
+//! two of them at once, and every one handed out comes back.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use bytes::BytesMut;
+use pipproto::pool::BufferPool;
+
+const THREADS: u64 = 16;
+const ROUNDS: u64 = 2_000;
+
+/// Fill each buffer with a pattern of its holder's, let the others run,
+/// and check nobody else wrote to it.
+#[test]
+fn threads_never_share_a_buffer() {
+    let pool: BufferPool = BufferPool::new().with_classes(&[64, 1024]).with_capacity(4);
+    let gets = Arc::new(AtomicU64::new(0));
+    thread::scope(|s| {
+        for t in 0..THREADS {
+            let pool = pool.clone();
+            let gets = Arc::clone(&gets);
+            s.spawn(move || {
+                for round in 0..ROUNDS {
+                    let len = 1 + ((t * 31 + round * 7) % 1500) as usize;
+                    let mark = (t as u8) ^ (round as u8);
+                    // two at a time, so the pool runs dry now and then
+                    let mut a = pool.get(len);
+                    let mut b = pool.get(len / 2);
+                    gets.fetch_add(2, Ordering::Relaxed);
+                    assert!(a.is_empty() && b.is_empty());
+                    a.resize(len, mark);
+                    b.resize(len / 2, !mark);
+                    thread::yield_now();
+                    assert!(a.iter().all(|&x| x == mark));
+                    assert!(b.iter().all(|&x| x == !mark));
+                }
+            });
+        }
+    });
+    let stats = pool.stats();
+    assert_eq!(stats.outstanding, 0);
+    assert_eq!(stats.hits + stats.misses, gets.load(Ordering::Relaxed));
+    assert!(stats.hits > stats.misses, "{stats:?}");
+}
+
+/// As above, for `BytesMut` buffers with frames split off them and held
+/// past the guard.
+#[tokio::test]
+async fn tasks_never_share_a_buffer() {
+    let pool: BufferPool<BytesMut> = BufferPool::new().with_classes(&[256]);
+    let tasks: Vec<_> = (0..64u8)
+        .map(|t| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let mut held = Vec::new();
+                for round in 0..200u8 {
+                    let mark = t ^ round;
+                    let mut buf = pool.get(256);
+                    buf.resize(200, mark);
+                    tokio::task::yield_now().await;
+                    if round % 3 == 0 {
+                        held.push((mark, buf.split_to(100).freeze()));
+                    }
+                    assert!(buf.iter().all(|&x| x == mark));
+                    if held.len() > 4 {
+                        held.remove(0);
+                    }
+                    for (mark, frame) in &held {
+                        assert!(frame.iter().all(|x| x == mark));
+                    }
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(pool.stats().outstanding, 0);
+}
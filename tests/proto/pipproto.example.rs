@@ -0,0 +1,52 @@
+// This file is @generated by prost-build.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Reading {
+    #[prost(string, tag = "1")]
+    pub sensor: ::prost::alloc::string::String,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+    #[prost(message, optional, tag = "3")]
+    pub position: ::core::option::Option<Position>,
+    #[prost(string, repeated, tag = "4")]
+    pub tags: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(map = "uint32, sint64", tag = "5")]
+    pub calibration: ::std::collections::HashMap<u32, i64>,
+    #[prost(enumeration = "Unit", tag = "6")]
+    pub unit: i32,
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Position {
+    #[prost(float, tag = "1")]
+    pub lat: f32,
+    #[prost(float, tag = "2")]
+    pub lon: f32,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Unit {
+    Unspecified = 0,
+    Celsius = 1,
+    Pascal = 2,
+}
+impl Unit {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Unspecified => "UNIT_UNSPECIFIED",
+            Self::Celsius => "UNIT_CELSIUS",
+            Self::Pascal => "UNIT_PASCAL",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "UNIT_UNSPECIFIED" => Some(Self::Unspecified),
+            "UNIT_CELSIUS" => Some(Self::Celsius),
+            "UNIT_PASCAL" => Some(Self::Pascal),
+            _ => None,
+        }
+    }
+}
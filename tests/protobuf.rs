@@ -0,0 +1,66 @@
+//! Protobuf bodies with a message generated from `tests/proto/reading.proto`.
+#![cfg(feature = "protobuf")]
+
+use std::collections::HashMap;
+
+use pipproto::protobuf::ProtobufConfig;
+use pipproto::{BodyError, FrameV1, MsgType};
+
+mod example {
+    include!("proto/pipproto.example.rs");
+}
+
+use example::{Position, Reading, Unit};
+
+fn reading() -> Reading {
+    Reading {
+        sensor: "t1".to_string(),
+        value: 21.5,
+        position: Some(Position {
+            lat: 45.5,
+            lon: 9.25,
+        }),
+        tags: vec!["roof".to_string(), "north".to_string()],
+        calibration: HashMap::from([(1, -3), (300, 70_000)]),
+        unit: Unit::Celsius as i32,
+    }
+}
+
+#[test]
+fn generated_messages_round_trip() {
+    let frame = FrameV1::from_protobuf_body(*b"DEV00001", 7, MsgType::Event, &reading()).unwrap();
+    assert_eq!(frame.header.msg_type, MsgType::Event);
+    assert_eq!(frame.header.counter, 7);
+    assert!(!frame.header.flags.ack_required());
+    assert_eq!(frame.body.len(), prost::Message::encoded_len(&reading()));
+
+    let back = FrameV1::decode(&frame.encode()).unwrap();
+    let got = back.body_as_protobuf::<Reading>().unwrap();
+    assert_eq!(got, reading());
+    assert_eq!(got.unit(), Unit::Celsius);
+}
+
+#[test]
+fn defaults_encode_as_an_empty_body() {
+    let frame = FrameV1::from_protobuf_body(*b"DEV00001", 1, MsgType::Command, &Reading::default())
+        .unwrap();
+    assert!(frame.body.is_empty());
+    assert_eq!(frame.body_as_protobuf::<Reading>(), Ok(Reading::default()));
+}
+
+#[test]
+fn truncated_and_oversized_bodies_are_errors() {
+    let body = ProtobufConfig::new().encode(&reading()).unwrap();
+    let e = ProtobufConfig::new()
+        .decode::<Reading>(&body[..body.len() - 3])
+        .unwrap_err();
+    assert!(matches!(e, BodyError::Protobuf(_)), "{e:?}");
+
+    let config = ProtobufConfig::new().with_max_body(body.len() - 1);
+    let too_large = BodyError::TooLarge {
+        len: body.len(),
+        max: body.len() - 1,
+    };
+    assert_eq!(config.encode(&reading()), Err(too_large.clone()));
+    assert_eq!(config.decode::<Reading>(&body), Err(too_large));
+}
@@ -0,0 +1,51 @@
+//! The Python tests in `python/tests`, against the extension module as
+//! maturin would build it.
+
+#![cfg(all(feature = "python", target_os = "linux"))]
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn python_tests_pass() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // this test is target/{profile}/deps/python-*
+    let exe = std::env::current_exe().unwrap();
+    let dir = exe.parent().unwrap().parent().unwrap();
+    let profile = match dir.file_name().unwrap().to_str().unwrap() {
+        "debug" => "dev",
+        other => other,
+    };
+    let features = "python,pyo3/extension-module";
+    let status = Command::new(env!("CARGO"))
+        .args([
+            "build",
+            "--lib",
+            "--features",
+            features,
+            "--profile",
+            profile,
+        ])
+        .arg("--manifest-path")
+        .arg(root.join("Cargo.toml"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // where `maturin develop` would put it
+    let site = tempfile::tempdir().unwrap();
+    std::fs::copy(dir.join("libpipproto.so"), site.path().join("pipproto.so")).unwrap();
+
+    let python = std::env::var("PYO3_PYTHON").unwrap_or_else(|_| "python3".into());
+    let run = Command::new(python)
+        .args(["-m", "unittest", "discover", "-s"])
+        .arg(root.join("python/tests"))
+        .env("PYTHONPATH", site.path())
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+}
@@ -0,0 +1,95 @@
+//! `pipproto repl` driven through stdin, sending to a socket on this host.
+
+use std::io::Write;
+use std::net::UdpSocket;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+use pipproto::FrameV1;
+use pipproto::ack::AckBody;
+use pipproto::request::ack_reply;
+
+fn repl(script: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pipproto"))
+        .args(["repl", "--timeout", "1s"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Acks frames that decode and sends back the bytes of those that do not,
+/// `n` times.
+fn responder(n: usize) -> (String, thread::JoinHandle<Vec<Vec<u8>>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    let handle = thread::spawn(move || {
+        let mut seen = Vec::new();
+        let mut buf = [0; 2048];
+        for _ in 0..n {
+            let (len, from) = socket.recv_from(&mut buf).unwrap();
+            let reply = match FrameV1::decode(&buf[..len]) {
+                Ok(frame) => ack_reply(&frame.header, AckBody::Single).encode(),
+                Err(_) => buf[..len].to_vec(),
+            };
+            socket.send_to(&reply, from).unwrap();
+            seen.push(buf[..len].to_vec());
+        }
+        seen
+    });
+    (addr, handle)
+}
+
+#[test]
+fn builds_sends_and_shows_replies() {
+    let (addr, responder) = responder(2);
+    let script = format!(
+        "set device 0102030405060708\n\
+         set counter 9\n\
+         set body \"hi\"\n\
+         send {addr}\n\
+         set byte 3 0x7f\n\
+         send {addr}\n\
+         frobnicate\n\
+         quit\n\
+         show\n"
+    );
+    let out = repl(&script);
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert!(lines[0].starts_with("ack for ctr=9 in "), "{stdout}");
+    assert_eq!(lines[1], "sent 23 bytes");
+    assert!(
+        lines[2].ends_with("error: unknown msg_type: 0x7f at offset 3"),
+        "{stdout}"
+    );
+    // nothing after quit
+    assert_eq!(lines.len(), 3, "{stdout}");
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert_eq!(stderr, "error: unknown command \"frobnicate\", try help\n");
+
+    let seen = responder.join().unwrap();
+    let frame = FrameV1::decode(&seen[0]).unwrap();
+    assert_eq!(frame.header.device_id, [1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(frame.body, b"hi");
+    assert_eq!(seen[1][3], 0x7f);
+}
+
+#[test]
+fn reports_silence() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let out = repl(&format!("set flags none\nsend {addr}\n"));
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(stdout, "sent 21 bytes, no reply in 1000ms\n");
+}
@@ -0,0 +1,155 @@
+//! `pipproto replay` sending a small journal to a socket on this host.
+
+use std::io::Read;
+use std::net::{TcpListener, UdpSocket};
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use pipproto::journal::JournalWriter;
+use pipproto::sink::{Direction, FrameRecord, FrameSink};
+use pipproto::{DecodeError, Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+fn replay(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pipproto"))
+        .arg("replay")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn frame(counter: u64) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Event,
+            flags: Flags::new(0).unwrap(),
+            device_id: *b"DEV00001",
+            counter,
+        },
+        body: vec![counter as u8; 3].into(),
+    }
+}
+
+/// Five received frames 100ms apart, counters 101 to 105, with an ack sent
+/// and a record that did not decode among them.
+fn write_journal(path: &Path) {
+    let mut records: Vec<_> = (101..=105).map(|c| FrameRecord::rx(frame(c))).collect();
+    records.insert(2, FrameRecord::tx(frame(1)));
+    records.insert(
+        4,
+        FrameRecord::undecodable(Direction::Rx, b"PP".to_vec(), DecodeError::TooShort),
+    );
+    let mut journal = JournalWriter::open(path).unwrap();
+    let mut ms = 0;
+    for mut record in records {
+        if matches!(record.direction, Direction::Rx) {
+            ms += 100;
+        }
+        record.timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000 + ms);
+        journal.record(&record).unwrap();
+    }
+    journal.flush().unwrap();
+}
+
+#[test]
+fn replays_over_udp_with_the_recorded_spacing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("journal.log");
+    write_journal(&path);
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let target = socket.local_addr().unwrap().to_string();
+
+    let start = Instant::now();
+    let out = replay(&[
+        &target,
+        path.to_str().unwrap(),
+        "--speed",
+        "2",
+        "--map-device",
+        "4445563030303031=00000000000000aa",
+        "--reset-counters",
+    ]);
+    let took = start.elapsed();
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let summary = String::from_utf8(out.stdout).unwrap();
+    assert!(summary.starts_with("5 sent in 0.2"), "{summary}");
+    assert!(summary.ends_with(", 2 skipped, 0 failed\n"), "{summary}");
+    // 400ms recorded, at double speed
+    assert!(took >= Duration::from_millis(200), "{took:?}");
+
+    let mut buf = [0; 1500];
+    for counter in 1..=5 {
+        let n = socket.recv(&mut buf).unwrap();
+        let got = FrameV1::decode(&buf[..n]).unwrap();
+        assert_eq!(got.header.device_id, [0, 0, 0, 0, 0, 0, 0, 0xaa]);
+        assert_eq!(got.header.counter, counter);
+        assert_eq!(got.body, frame(100 + counter).body);
+    }
+}
+
+#[test]
+fn replays_over_tcp_as_fast_as_it_can() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("journal.log");
+    write_journal(&path);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = listener.local_addr().unwrap().to_string();
+    let receiver = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes).unwrap();
+        bytes
+    });
+
+    let out = replay(&[
+        "--tcp",
+        &target,
+        "--no-timing",
+        "--direction",
+        "both",
+        "--filter",
+        "counter != 103",
+        path.to_str().unwrap(),
+    ]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let summary = String::from_utf8(out.stdout).unwrap();
+    assert!(summary.ends_with(", 2 skipped, 0 failed\n"), "{summary}");
+
+    let bytes = receiver.join().unwrap();
+    let mut counters = Vec::new();
+    let mut rest = &bytes[..];
+    while !rest.is_empty() {
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        counters.push(FrameV1::decode(&rest[4..4 + len]).unwrap().header.counter);
+        rest = &rest[4 + len..];
+    }
+    assert_eq!(counters, [101, 102, 1, 104, 105]);
+}
+
+#[test]
+fn fails_when_nothing_listens() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("journal.log");
+    write_journal(&path);
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let target = format!("127.0.0.1:{port}");
+    let out = replay(&["--tcp", &target, path.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).starts_with(&format!("error: {target}: ")));
+}
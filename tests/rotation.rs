@@ -0,0 +1,116 @@
+//! A journal directory written by a process killed part way through,
+//! rotating every few records.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use pipproto::journal::{JournalDirReader, JournalWriter, Retention, RotationConfig, SegmentCodec};
+use pipproto::sink::{FrameRecord, FrameSink};
+use pipproto::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+
+const CHILD: &str = "PIPPROTO_ROTATION_DIR";
+
+#[derive(Debug)]
+struct Reverse;
+
+impl SegmentCodec for Reverse {
+    fn extension(&self) -> &str {
+        "rev"
+    }
+
+    fn compress(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        bytes.reverse();
+        output.write_all(&bytes)
+    }
+
+    fn decompress(&self, mut input: File) -> io::Result<Box<dyn Read + Send>> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        bytes.reverse();
+        Ok(Box::new(io::Cursor::new(bytes)))
+    }
+}
+
+fn config() -> RotationConfig {
+    RotationConfig {
+        max_bytes: Some(200),
+        retention: Retention {
+            max_segments: Some(50),
+            ..Retention::default()
+        },
+        codec: Some(Arc::new(Reverse)),
+        ..RotationConfig::default()
+    }
+}
+
+fn frame(counter: u64) -> FrameV1 {
+    FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Event,
+            flags: Flags::new(0).unwrap(),
+            device_id: *b"DEV00001",
+            counter,
+        },
+        body: vec![counter as u8; 8].into(),
+    }
+}
+
+/// Carries on from the last counter in `dir` until killed.
+fn write_forever(dir: &Path) -> ! {
+    let next = counters(dir).last().map_or(1, |c| c + 1);
+    let mut journal = JournalWriter::open_dir(dir, config()).unwrap();
+    for counter in next.. {
+        journal.record(&FrameRecord::tx(frame(counter))).unwrap();
+        journal.flush().unwrap();
+    }
+    unreachable!()
+}
+
+fn counters(dir: &Path) -> Vec<u64> {
+    JournalDirReader::open(dir)
+        .unwrap()
+        .with_codec(Arc::new(Reverse))
+        .map(|r| r.unwrap().frame().unwrap().header.counter)
+        .collect()
+}
+
+#[test]
+fn killed_mid_rotation_leaves_a_readable_journal() {
+    if let Some(dir) = std::env::var_os(CHILD) {
+        write_forever(Path::new(&dir));
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let mut written = 0;
+    for round in 0..8u64 {
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "killed_mid_rotation_leaves_a_readable_journal"])
+            .env(CHILD, dir.path())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_millis(40 + round * 13));
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // every record flushed is there, in order, from the oldest kept
+        let counters = counters(dir.path());
+        let (first, last) = (counters[0], *counters.last().unwrap());
+        assert_eq!(counters, (first..=last).collect::<Vec<_>>());
+        assert!(last >= written, "round {round}: {last} < {written}");
+        written = last;
+    }
+    let compressed = std::fs::read_dir(dir.path())
+        .unwrap()
+        .filter(|e| e.as_ref().unwrap().path().extension().unwrap() == "rev")
+        .count();
+    assert!(compressed > 0, "{written} records, none rotated");
+}
@@ -0,0 +1,120 @@
+//! Serialized frames are what the published JSON Schema says they are,
+//! and documents it rejects don't deserialize either.
+
+#![cfg(feature = "schema")]
+
+use jsonschema::Validator;
+use pipproto::schema::{frame_schema, to_json};
+use pipproto::vectors::{self, Expect};
+use pipproto::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+use serde_json::{Value, json};
+
+const FILE: &str = include_str!("../docs/frame-v1.schema.json");
+
+fn validator() -> Validator {
+    jsonschema::draft202012::new(&frame_schema()).unwrap()
+}
+
+fn frames() -> Vec<FrameV1> {
+    let mut frames = pipproto::workload::mixed(200, &[0..=0, 1..=3, 4..=300]);
+    for t in [
+        MsgType::Event,
+        MsgType::Command,
+        MsgType::Ack,
+        MsgType::Error,
+    ] {
+        frames.push(FrameV1 {
+            header: FrameHeaderV1 {
+                version: VERSION_V1,
+                msg_type: t,
+                flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+                device_id: [0xff; 8],
+                counter: u64::MAX,
+            },
+            body: b"\x00\xff".into(),
+        });
+    }
+    // every frame the test vectors expect to decode
+    for v in vectors::generate() {
+        if let Expect::Frame(frame) = v.expect {
+            frames.push(frame);
+        }
+    }
+    frames
+}
+
+#[test]
+fn committed_schema_is_up_to_date() {
+    assert!(
+        FILE == to_json(&frame_schema()),
+        "docs/frame-v1.schema.json is stale: regenerate it with \
+         `cargo run --features schema -- schema --out docs/frame-v1.schema.json`"
+    );
+}
+
+#[test]
+fn serialized_frames_match_the_schema() {
+    let validator = validator();
+    for frame in frames() {
+        let doc = serde_json::to_value(&frame).unwrap();
+        if let Err(e) = validator.validate(&doc) {
+            panic!("{doc}: {e}");
+        }
+        assert_eq!(serde_json::from_value::<FrameV1>(doc).unwrap(), frame);
+    }
+}
+
+#[test]
+fn documents_the_schema_allows_deserialize() {
+    let validator = validator();
+    for doc in [
+        json!({"msg_type": "ack", "device_id": "0000000000000001", "counter": 0}),
+        json!({"msg_type": "event", "device_id": "DEADBEEF00000001", "counter": 1,
+               "body_hex": "C0ffee", "flags": {}}),
+        json!({"version": 1, "msg_type": "error", "device_id": "0000000000000001",
+               "counter": 2, "body": "aGk=", "flags": {"ack_required": true}}),
+    ] {
+        assert!(validator.is_valid(&doc), "{doc}");
+        serde_json::from_value::<FrameV1>(doc).unwrap();
+    }
+}
+
+#[test]
+fn documents_the_schema_rejects_do_not_deserialize() {
+    let validator = validator();
+    let good = json!({"msg_type": "event", "device_id": "0000000000000001", "counter": 1});
+    let changes: [(&str, Value); 12] = [
+        ("version", json!(2)),
+        ("msg_type", json!("nope")),
+        ("msg_type", json!(1)),
+        ("device_id", json!("0001")),
+        ("device_id", json!("000000000000000g")),
+        ("counter", json!(-1)),
+        ("counter", json!(1.5)),
+        ("body", json!("!")),
+        ("body", json!("aGk")),
+        ("body_hex", json!("6")),
+        ("flags", json!({"urgent": true})),
+        ("extra", json!(1)),
+    ];
+    for (key, value) in changes {
+        let mut doc = good.clone();
+        doc[key] = value;
+        assert!(!validator.is_valid(&doc), "{doc}");
+        assert!(
+            serde_json::from_value::<FrameV1>(doc.clone()).is_err(),
+            "{doc}"
+        );
+    }
+    for key in ["msg_type", "device_id", "counter"] {
+        let mut doc = good.clone();
+        doc.as_object_mut().unwrap().remove(key);
+        assert!(!validator.is_valid(&doc), "{doc}");
+        assert!(serde_json::from_value::<FrameV1>(doc).is_err());
+    }
+    let mut doc = good.clone();
+    doc["body"] = json!("aGk=");
+    doc["body_hex"] = json!("6869");
+    assert!(!validator.is_valid(&doc));
+    assert!(serde_json::from_value::<FrameV1>(doc).is_err());
+}
@@ -0,0 +1,25 @@
+//! The committed test vectors are what the generator writes, and this crate
+//! passes them.
+
+use pipproto::vectors::{check, generate, to_json};
+
+const FILE: &str = include_str!("../docs/test-vectors-v1.json");
+
+#[test]
+fn committed_file_is_up_to_date() {
+    assert!(
+        FILE == to_json(&generate()),
+        "docs/test-vectors-v1.json is stale: regenerate it with \
+         `cargo run -- vectors --out docs/test-vectors-v1.json`, and bump \
+         VECTORS_VERSION if an existing case changed"
+    );
+}
+
+#[test]
+fn this_crate_conforms() {
+    let failures: Vec<_> = generate()
+        .iter()
+        .filter_map(|v| check(v).err().map(|e| format!("{}: {e}", v.name)))
+        .collect();
+    assert!(failures.is_empty(), "{failures:#?}");
+}
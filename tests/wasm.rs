@@ -0,0 +1,80 @@
+//! The WebAssembly bindings as JS sees them. Run with
+//! `wasm-pack test --node -- --features wasm`.
+
+#![cfg(all(feature = "wasm", target_family = "wasm"))]
+
+use js_sys::{Error, JSON, Reflect};
+use pipproto::wasm::{annotate, decode_frame, encode_frame};
+use pipproto::{Flags, FrameHeaderV1, FrameV1, MsgType, VERSION_V1};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_test::wasm_bindgen_test;
+
+fn get(value: &JsValue, key: &str) -> JsValue {
+    Reflect::get(value, &key.into()).unwrap()
+}
+
+fn sample() -> Vec<u8> {
+    let frame: FrameV1 = FrameV1 {
+        header: FrameHeaderV1 {
+            version: VERSION_V1,
+            msg_type: MsgType::Command,
+            flags: Flags::new(Flags::ACK_REQUIRED).unwrap(),
+            device_id: [0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 1],
+            counter: 7,
+        },
+        body: b"hi".into(),
+    };
+    frame.encode()
+}
+
+#[wasm_bindgen_test]
+fn frames_round_trip() {
+    let bytes = sample();
+    let frame = decode_frame(&bytes).unwrap();
+    assert_eq!(get(&frame, "msgType").as_string().unwrap(), "command");
+    assert_eq!(
+        get(&frame, "deviceId").as_string().unwrap(),
+        "deadbeef00000001"
+    );
+    assert_eq!(get(&frame, "counter").as_string().unwrap(), "7");
+    assert_eq!(get(&frame, "body").as_string().unwrap(), "6869");
+    assert_eq!(get(&get(&frame, "flags"), "ackRequired"), JsValue::TRUE);
+    assert_eq!(encode_frame(frame).unwrap(), bytes);
+
+    let frame = JSON::parse(
+        r#"{"msgType":"command","flags":{"ackRequired":true},"deviceId":"deadbeef00000001","counter":7,"body":"6869"}"#,
+    )
+    .unwrap();
+    assert_eq!(encode_frame(frame).unwrap(), bytes);
+}
+
+#[wasm_bindgen_test]
+fn errors_are_thrown_with_kind_and_offset() {
+    let mut bytes = sample();
+    bytes[4] = 0b10;
+    let e = decode_frame(&bytes).unwrap_err();
+    assert!(e.is_instance_of::<Error>());
+    assert_eq!(get(&e, "kind").as_string().unwrap(), "reserved_flags");
+    assert_eq!(get(&e, "offset").as_f64(), Some(4.0));
+    let e = decode_frame(&bytes[..3]).unwrap_err();
+    assert_eq!(get(&e, "kind").as_string().unwrap(), "too_short");
+    assert!(get(&e, "offset").is_null());
+
+    let frame = JSON::parse(r#"{"msgType":"event","deviceId":"beef","counter":1}"#).unwrap();
+    let e = encode_frame(frame).unwrap_err();
+    assert_eq!(get(&e, "kind").as_string().unwrap(), "invalid_frame");
+    let message = String::from(e.dyn_into::<Error>().unwrap().message());
+    assert_eq!(message, "deviceId must be 16 hex digits");
+    let e = encode_frame(JsValue::UNDEFINED).unwrap_err();
+    assert_eq!(get(&e, "kind").as_string().unwrap(), "invalid_frame");
+}
+
+#[wasm_bindgen_test]
+fn annotate_returns_spans() {
+    let spans = annotate(&sample());
+    assert_eq!(get(&spans, "length").as_f64(), Some(7.0));
+    let body = get(&spans, "6");
+    assert_eq!(get(&body, "name").as_string().unwrap(), "body");
+    assert_eq!(get(&body, "start").as_f64(), Some(21.0));
+    assert_eq!(get(&body, "ok"), JsValue::TRUE);
+}
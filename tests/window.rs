@@ -0,0 +1,80 @@
+//! More requests than a send window holds, through a windowed sender:
+//! each answer, nack, ERROR frame or timeout gives its slot back, so the
+//! rest go out instead of waiting forever.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::join_all;
+use pipproto::ack::AckBody;
+use pipproto::request::{RequestConfig, RequestError, Requester, ack_reply, error_reply};
+use pipproto::sender::{CounterMap, FrameSender};
+use pipproto::transport::{Transport, loopback_pair};
+use pipproto::window::{SendWindow, WhenFull, WindowConfig};
+
+const DEV: [u8; 8] = *b"DEV00001";
+const SIZE: usize = 2;
+const REQUESTS: usize = 4 * SIZE + 1;
+
+#[tokio::test]
+async fn requests_beyond_the_window_all_go_out() {
+    let (gateway_end, mut device) = loopback_pair();
+    let (mut device_end, mut gateway) = loopback_pair();
+    let window = SendWindow::new(WindowConfig {
+        size: SIZE,
+        reserved: 0,
+        when_full: WhenFull::Block,
+    });
+    let sender =
+        FrameSender::new(gateway_end, Arc::new(CounterMap::default())).with_window(window.clone());
+    let requester = Requester::new(
+        sender,
+        RequestConfig {
+            timeout: Duration::from_millis(100),
+            ..RequestConfig::default()
+        },
+    );
+
+    // acks, nacks, fails or ignores each command by its counter
+    let device_task = tokio::spawn(async move {
+        while let Ok(command) = device.recv().await {
+            let reply = match command.header.counter % 4 {
+                0 => ack_reply(&command.header, AckBody::Single),
+                1 => ack_reply(
+                    &command.header,
+                    AckBody::Nack {
+                        reason: "no".into(),
+                    },
+                ),
+                2 => error_reply(&command.header, 0x0102, b""),
+                _ => continue,
+            };
+            if device_end.send(&reply).await.is_err() {
+                break;
+            }
+        }
+    });
+    let replies = tokio::spawn({
+        let requester = requester.clone();
+        async move {
+            while let Ok(reply) = gateway.recv().await {
+                requester.on_frame(&reply);
+            }
+        }
+    });
+
+    let calls = (0..REQUESTS).map(|i| requester.send_command(DEV, vec![i as u8]));
+    let answers = tokio::time::timeout(Duration::from_secs(10), join_all(calls))
+        .await
+        .expect("every request gets a slot");
+    let count = |f: fn(&Result<_, RequestError>) -> bool| answers.iter().filter(|a| f(a)).count();
+    assert_eq!(count(|a| a.is_ok()), 2);
+    assert_eq!(count(|a| matches!(a, Err(RequestError::Nacked(_)))), 3);
+    assert_eq!(count(|a| matches!(a, Err(RequestError::ErrorFrame(_)))), 2);
+    assert_eq!(count(|a| matches!(a, Err(RequestError::Timeout))), 2);
+    assert_eq!(window.in_flight(DEV), 0);
+    assert_eq!(requester.in_flight(), 0);
+
+    device_task.abort();
+    replies.abort();
+}